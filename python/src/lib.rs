@@ -0,0 +1,147 @@
+//! Python bindings of the library, module `rtlib` is built with maturin (`python` feature).
+//!
+//! Scene description is created from text or loaded from file, its settings can be changed
+//! and scene is rendered to NumPy array of shape (height, width, 3) and type uint8.
+//!
+//! ```python
+//! import rtlib
+//! desc = rtlib.SceneDescription.load("scene.pbrt")
+//! for spp in [16, 64, 256]:
+//!     desc.spp = spp
+//!     image = desc.render()
+//! ```
+#![cfg(feature = "python")]
+
+use std::path::PathBuf;
+
+use numpy::{PyArray1, PyArray3};
+use pyo3::exceptions::{PyIOError, PyValueError};
+use pyo3::prelude::*;
+
+use rtlib::{ImageSize, SceneFormat, parse_scene_description, render_scene};
+use rtlib::{load_scene_description_from_json, parse_pbrt_v4_input_file};
+use rtlib::scene::{Scene, SceneDescription};
+
+fn format_from_name(name: &str) -> PyResult<SceneFormat> {
+    match name {
+        "pbrt" => Ok(SceneFormat::Pbrt),
+        "json" => Ok(SceneFormat::Json),
+        _ => Err(PyValueError::new_err(format!("Unknown scene format: {}", name)))
+    }
+}
+
+// Files are parsed again with their path, so relative paths of included files and textures are resolved
+enum SceneSource {
+    Text(String, SceneFormat),
+    File(PathBuf, SceneFormat),
+}
+
+/// Scene is parsed again for every render, so settings can be changed between renders (e.g. parameter sweeps).
+#[pyclass(name = "SceneDescription")]
+struct PySceneDescription {
+    source: SceneSource,
+    resolution: Option<ImageSize>,
+    spp: Option<usize>,
+    nthreads: Option<usize>,
+}
+
+impl PySceneDescription {
+    fn with_source(source: SceneSource) -> PyResult<Self> {
+        let desc = Self { source, resolution: None, spp: None, nthreads: None };
+        // errors of scene are reported when it is created and not when it is rendered
+        desc.description()?;
+        Ok(desc)
+    }
+
+    fn description(&self) -> PyResult<SceneDescription> {
+        let result = match &self.source {
+            SceneSource::Text(text, format) => parse_scene_description(text.as_bytes(), *format),
+            SceneSource::File(path, SceneFormat::Json) => load_scene_description_from_json(path),
+            SceneSource::File(path, SceneFormat::Pbrt) => parse_pbrt_v4_input_file(path),
+        };
+        let mut desc = result.map_err(|e| PyValueError::new_err(e.to_string()))?;
+        if let Some(resolution) = self.resolution {
+            desc.set_resolution(resolution);
+        }
+        if let Some(spp) = self.spp {
+            desc.settings.spp = spp;
+        }
+        if let Some(nthreads) = self.nthreads {
+            desc.settings.nthreads = nthreads;
+        }
+        Ok(desc)
+    }
+}
+
+#[pymethods]
+impl PySceneDescription {
+    /// Scene given as text, format is "pbrt" or "json". Relative paths are resolved against working directory.
+    #[new]
+    #[pyo3(signature = (text, format = "pbrt"))]
+    fn new(text: String, format: &str) -> PyResult<Self> {
+        Self::with_source(SceneSource::Text(text, format_from_name(format)?))
+    }
+
+    /// Load scene from file, files with .json extension are json scenes, other files are pbrt-v4 scenes.
+    #[staticmethod]
+    fn load(path: PathBuf) -> PyResult<Self> {
+        if !path.is_file() {
+            return Err(PyIOError::new_err(format!("Scene file {} doesn't exist!", path.display())))
+        }
+        let format = match path.extension().and_then(|ext| ext.to_str()) {
+            Some("json") => SceneFormat::Json,
+            _ => SceneFormat::Pbrt
+        };
+        Self::with_source(SceneSource::File(path, format))
+    }
+
+    /// Resolution of the image as (width, height)
+    #[getter]
+    fn resolution(&self) -> PyResult<(usize, usize)> {
+        let resolution = self.description()?.settings.resolution;
+        Ok((resolution.width, resolution.height))
+    }
+
+    #[setter]
+    fn set_resolution(&mut self, resolution: (usize, usize)) {
+        self.resolution = Some(ImageSize::new(resolution.0, resolution.1));
+    }
+
+    /// Samples per pixel
+    #[getter]
+    fn spp(&self) -> PyResult<usize> {
+        Ok(self.description()?.settings.spp)
+    }
+
+    #[setter]
+    fn set_spp(&mut self, spp: usize) {
+        self.spp = Some(spp);
+    }
+
+    /// Number of rendering threads
+    #[getter]
+    fn nthreads(&self) -> PyResult<usize> {
+        Ok(self.description()?.settings.nthreads)
+    }
+
+    #[setter]
+    fn set_nthreads(&mut self, nthreads: usize) {
+        self.nthreads = Some(nthreads);
+    }
+
+    /// Render scene, GIL is released while rendering. Image is array of shape (height, width, 3) and type uint8.
+    fn render<'py>(&self, py: Python<'py>) -> PyResult<&'py PyArray3<u8>> {
+        let desc = self.description()?;
+        let scene = Scene::try_from(desc).map_err(|e| PyValueError::new_err(format!("Scene creation failed: {}", e)))?;
+        let image = py.allow_threads(|| render_scene(&scene));
+        let size = image.size();
+        PyArray1::from_vec(py, image.to_rgb_bytes()).reshape([size.height, size.width, 3])
+    }
+}
+
+#[pymodule]
+#[pyo3(name = "rtlib")]
+fn rtlib_py(_py: Python, m: &PyModule) -> PyResult<()> {
+    m.add_class::<PySceneDescription>()?;
+    Ok(())
+}
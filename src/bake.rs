@@ -0,0 +1,297 @@
+//! Baking of lighting into texture of a mesh
+//!
+//! UV layout of the mesh is rasterized to the texture and every covered texel gets point and normal
+//! of the surface. Radiance leaving the point in direction of the normal is estimated by tracing ray
+//! from just above the surface, so integrators that render camera rays bake texels without changes.
+
+use crate::color::RGB;
+use crate::integrators::{render_tiles, TileOutputs, ambient_occlusion, radiance_direct_lgt, radiance_random_walk};
+use crate::postprocess::finish_image;
+use crate::ray::{Ray, spawn_new_ray};
+use crate::rgb::{ImageSize, RGB8uffer};
+use crate::scene::{Scene, RenderingAlgorithm};
+use crate::shapes::MeshDescription;
+use crate::vec::{Normal, Point3, Vec3};
+
+/// Texture of mesh is rendered instead of camera image, resolution of the film is resolution of texture.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BakeProperties {
+    /// Index of the mesh in shapes of the scene
+    pub mesh: usize,
+}
+
+/// Point on the surface of the mesh and its shading normal, normal is interpolated from vertex normals
+/// or it is normal of the triangle when mesh doesn't have them.
+#[derive(Debug, Clone, Copy)]
+pub struct BakeTexel {
+    pub position: Point3,
+    pub normal: Normal,
+}
+
+impl BakeTexel {
+    /// Ray that hits the texel point in direction opposite to the normal. Ray starts at point spawned
+    /// from the surface (see spawn_new_ray) that is moved further by minimal hit distance tmin,
+    /// so the hit is never rejected as self intersection.
+    pub fn ray(&self, tmin: f32) -> Ray {
+        let normal = Vec3::from(self.normal);
+        let spawned = spawn_new_ray(self.position, self.normal, normal);
+        Ray::new(spawned.origin + normal * tmin, -normal)
+    }
+}
+
+// Triangle of the mesh with vertices in world space and in texture space
+#[derive(Debug, Clone, Copy)]
+struct BakeTriangle {
+    positions: [Point3; 3],
+    normals: Option<[Normal; 3]>,
+    // normal of the triangle that agrees with vertex normals
+    normal: Normal,
+    texels: [(f32, f32); 3],
+    area: f32,
+}
+
+impl BakeTriangle {
+    // Barycentric coordinates of point of texture, they are ratios of signed areas, so winding doesn't matter
+    fn barycentrics(&self, px: f32, py: f32) -> [f32; 3] {
+        let [t0, t1, t2] = self.texels;
+        let b1 = ((px - t0.0) * (t2.1 - t0.1) - (t2.0 - t0.0) * (py - t0.1)) / self.area;
+        let b2 = ((t1.0 - t0.0) * (py - t0.1) - (px - t0.0) * (t1.1 - t0.1)) / self.area;
+        [1.0 - b1 - b2, b1, b2]
+    }
+
+    // Point of the triangle at point of texture, points outside of triangle are clamped to its border
+    fn texel(&self, px: f32, py: f32) -> BakeTexel {
+        let [b0, b1, b2] = self.barycentrics(px, py).map(|b| b.max(0.0));
+        let sum = b0 + b1 + b2;
+        let [b0, b1, b2] = [b0 / sum, b1 / sum, b2 / sum];
+        let [p0, p1, p2] = self.positions;
+        let position = p0 * b0 + p1 * b1 + p2 * b2;
+        let normal = match self.normals {
+            Some([n0, n1, n2]) => n0 * b0 + n1 * b1 + n2 * b2,
+            None => self.normal
+        };
+        let normal = if normal.length_sqr() > 0.0 { normal.normalize() } else { self.normal };
+        BakeTexel { position, normal }
+    }
+}
+
+/// Texels of the texture that are covered by UV layout of the mesh. Texture rows go from top to bottom,
+/// so v coordinate is flipped.
+pub struct BakeMap {
+    size: ImageSize,
+    triangles: Vec<BakeTriangle>,
+    // triangle that covers center of the texel
+    texels: Vec<Option<u32>>,
+}
+
+impl BakeMap {
+    /// Triangles are rasterized in world space of the mesh, texel is covered when its center is inside
+    /// triangle in UV space. Overlapping triangles keep texel of the last one.
+    pub fn new(desc: &MeshDescription, size: ImageSize) -> Result<Self, String> {
+        let (vertices, indices) = match (&desc.vertices, &desc.indices) {
+            (Some(vertices), Some(indices)) => (vertices, indices),
+            _ => return Err("Bake - mesh doesn't have vertices!".to_string())
+        };
+        let uvs = match &desc.uvs {
+            Some(uvs) if uvs.len() == vertices.len() => uvs,
+            _ => return Err("Bake - mesh doesn't have texture coordinates!".to_string())
+        };
+        let vertex = |index: u32| -> Point3 {
+            match &desc.transform {
+                Some(transform) => vertices[index as usize] * *transform,
+                None => vertices[index as usize]
+            }
+        };
+        let normals = desc.normals.as_ref().filter(|normals| normals.len() == vertices.len());
+        let vertex_normal = |index: u32| -> Option<Normal> {
+            let normal = normals?[index as usize];
+            match &desc.transform {
+                Some(transform) => Some(*transform * normal),
+                None => Some(normal)
+            }
+        };
+
+        let (width, height) = (size.width as f32, size.height as f32);
+        let mut triangles = Vec::new();
+        let mut texels = vec![None; size.width * size.height];
+        for triangle in indices.chunks_exact(3) {
+            let [i0, i1, i2] = [triangle[0], triangle[1], triangle[2]];
+            let (p0, p1, p2) = (vertex(i0), vertex(i1), vertex(i2));
+            let cross = (p1 - p0).cross(p2 - p0);
+            if cross.length_sqr() == 0.0 {
+                continue
+            }
+            let normals = match (vertex_normal(i0), vertex_normal(i1), vertex_normal(i2)) {
+                (Some(n0), Some(n1), Some(n2)) => Some([n0, n1, n2]),
+                _ => None
+            };
+            let mut normal = Normal::from(cross.normalize());
+            if let Some([n0, n1, n2]) = normals {
+                if (n0 + n1 + n2) * Vec3::from(normal) < 0.0 {
+                    normal = -normal;
+                }
+            }
+
+            let texel = |index: u32| {
+                let uv = uvs[index as usize];
+                (uv.x * width, (1.0 - uv.y) * height)
+            };
+            let (t0, t1, t2) = (texel(i0), texel(i1), texel(i2));
+            let area = (t1.0 - t0.0) * (t2.1 - t0.1) - (t2.0 - t0.0) * (t1.1 - t0.1);
+            if area == 0.0 {
+                continue
+            }
+            let triangle = BakeTriangle { positions: [p0, p1, p2], normals, normal, texels: [t0, t1, t2], area };
+            let xmin = t0.0.min(t1.0).min(t2.0).floor().max(0.0) as usize;
+            let ymin = t0.1.min(t1.1).min(t2.1).floor().max(0.0) as usize;
+            let xmax = (t0.0.max(t1.0).max(t2.0).ceil().max(0.0) as usize).min(size.width);
+            let ymax = (t0.1.max(t1.1).max(t2.1).ceil().max(0.0) as usize).min(size.height);
+            for y in ymin..ymax {
+                for x in xmin..xmax {
+                    if triangle.barycentrics(x as f32 + 0.5, y as f32 + 0.5).iter().all(|b| *b >= 0.0) {
+                        texels[y * size.width + x] = Some(triangles.len() as u32);
+                    }
+                }
+            }
+            triangles.push(triangle);
+        }
+        Ok(Self { size, triangles, texels })
+    }
+
+    /// Point at center of the texel
+    pub fn texel(&self, x: usize, y: usize) -> Option<BakeTexel> {
+        self.sample(x, y, 0.5, 0.5)
+    }
+
+    /// Point at offset (sx, sy) inside of the texel, point stays on triangle that covers center of the
+    /// texel, so samples near border of UV layout don't leave the surface.
+    pub fn sample(&self, x: usize, y: usize, sx: f32, sy: f32) -> Option<BakeTexel> {
+        if x >= self.size.width || y >= self.size.height {
+            return None
+        }
+        let triangle = &self.triangles[self.texels[y * self.size.width + x]? as usize];
+        Some(triangle.texel(x as f32 + sx, y as f32 + sy))
+    }
+
+    /// Number of texels covered by UV layout
+    pub fn coverage(&self) -> usize {
+        self.texels.iter().filter(|texel| texel.is_some()).count()
+    }
+}
+
+/// Every covered texel gets spp samples of integrator of the scene jittered inside of the texel,
+/// texels outside of UV layout stay black.
+pub fn bake_integrator(scene: &Scene, map: &BakeMap) -> RGB8uffer {
+    let tmin = scene.geometry.tmin();
+    // NOTE: texels are not filtered, filter would spread texels over seams of UV layout
+    let outputs = TileOutputs { unfiltered: true, ..Default::default() };
+    let film = render_tiles(scene, outputs, |buffers, sampler| {
+        for i in buffers.passes() {
+            if scene.cancel_token.is_cancelled() {
+                break;
+            }
+            for (x, y) in buffers.tile {
+                if map.texel(x, y).is_none() {
+                    continue
+                }
+                let (sx, sy) = sampler.sample_pixel(x, y, i);
+                let texel = match map.sample(x, y, sx, sy) {
+                    Some(texel) => texel,
+                    None => continue
+                };
+                let ray = texel.ray(tmin);
+                let rgb = match &scene.settings.rendering_algorithm {
+                    RenderingAlgorithm::AmbientOcclusion(settings) => ambient_occlusion(&ray, &scene.geometry, sampler, settings),
+                    RenderingAlgorithm::DirectLighting => radiance_direct_lgt(&ray, scene, sampler),
+                    RenderingAlgorithm::RandomWalk(settings) => radiance_random_walk(&ray, scene, sampler, settings),
+                    _ => RGB::zero()
+                };
+                buffers.add(x, y, x as f32 + 0.5, y as f32 + 0.5, &rgb);
+            }
+        }
+    });
+    finish_image(scene, &film.radiance, film.depth())
+}
+
+/// Integrators that can be baked, they estimate radiance of camera rays.
+pub fn is_bakeable(algorithm: &RenderingAlgorithm) -> bool {
+    matches!(algorithm, RenderingAlgorithm::AmbientOcclusion(_) | RenderingAlgorithm::DirectLighting |
+                        RenderingAlgorithm::RandomWalk(_))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vec::Point2;
+    use crate::scene::{SceneDescription, SceneFormat, parse_scene_description};
+    use crate::shapes::ShapeDescription;
+
+    #[test]
+    fn bake_map_rasterization() {
+        // quad in plane z = 0 whose UV layout covers left half of the texture
+        let desc = MeshDescription {
+            vertices: Some(vec![Point3::new(0.0, 0.0, 0.0), Point3::new(2.0, 0.0, 0.0),
+                                Point3::new(2.0, 2.0, 0.0), Point3::new(0.0, 2.0, 0.0)]),
+            indices: Some(vec![0, 1, 2, 0, 2, 3]),
+            uvs: Some(vec![Point2::new(0.0, 0.0), Point2::new(0.5, 0.0), Point2::new(0.5, 1.0), Point2::new(0.0, 1.0)]),
+            ..Default::default()
+        };
+        let map = BakeMap::new(&desc, ImageSize::new(8, 4)).unwrap();
+        assert_eq!(map.coverage(), 16);
+        assert!(map.texel(4, 0).is_none() && map.texel(8, 0).is_none());
+        // v is flipped, bottom row of texture is v = 0
+        let texel = map.texel(0, 3).unwrap();
+        assert!((texel.position.x - 0.25).abs() < 1e-5 && (texel.position.y - 0.25).abs() < 1e-5);
+        assert!((texel.normal.z - 1.0).abs() < 1e-5);
+        let ray = texel.ray(0.01);
+        assert!(ray.origin.z > 0.01 && ray.direction.z < 0.0);
+        // jittered sample moves inside of texel and stays on the triangle at border of UV layout
+        let jittered = map.sample(0, 3, 0.0, 1.0).unwrap();
+        assert!(jittered.position.x.abs() < 1e-5 && jittered.position.y.abs() < 1e-5);
+        let border = map.sample(3, 3, 1.0, 0.5).unwrap();
+        assert!((border.position.x - 2.0).abs() < 1e-5);
+
+        // normal is interpolated from vertex normals
+        let normals = vec![Normal::new(-1.0, 0.0, 1.0).normalize(), Normal::new(1.0, 0.0, 1.0).normalize(),
+                           Normal::new(1.0, 0.0, 1.0).normalize(), Normal::new(-1.0, 0.0, 1.0).normalize()];
+        let smooth = MeshDescription { vertices: desc.vertices.clone(), indices: desc.indices.clone(), uvs: desc.uvs.clone(),
+                                       normals: Some(normals), ..Default::default() };
+        let map = BakeMap::new(&smooth, ImageSize::new(8, 4)).unwrap();
+        let (left, right) = (map.texel(0, 1).unwrap().normal, map.texel(3, 1).unwrap().normal);
+        assert!(left.x < 0.0 && right.x > 0.0 && (left.length_sqr() - 1.0).abs() < 1e-5);
+
+        let no_uvs = MeshDescription { vertices: desc.vertices.clone(), indices: desc.indices.clone(), ..Default::default() };
+        assert!(BakeMap::new(&no_uvs, ImageSize::new(8, 4)).is_err());
+    }
+
+    #[test]
+    fn bake_ambient_occlusion() {
+        // floor with sphere above its right half
+        let text = r#"
+            Film "rgb" "integer xresolution" 16 "integer yresolution" 16 "integer bakemesh" 0
+            Sampler "independent" "integer pixelsamples" 16
+            Integrator "ambientocclusion"
+            WorldBegin
+            Material "diffuse"
+            Shape "trianglemesh" "point3 P" [-2 0 -2  2 0 -2  2 0 2  -2 0 2] "integer indices" [0 3 2 0 2 1]
+                "point2 uv" [0 0 1 0 1 1 0 1]
+            Translate 1 0.6 0
+            Shape "sphere" "float radius" 0.5
+        "#;
+        let desc = parse_scene_description(text.as_bytes(), SceneFormat::Pbrt).unwrap();
+        assert_eq!(desc.settings.bake, Some(BakeProperties { mesh: 0 }));
+        let scene = Scene::try_from(desc).unwrap();
+        let map = scene.bake_map.as_ref().unwrap();
+        assert_eq!(map.coverage(), 256);
+        let texture = bake_integrator(&scene, map);
+        let occluded = texture.get(12, 8).unwrap().red;
+        let open = texture.get(2, 8).unwrap().red;
+        assert!(open > 200 && occluded < open);
+
+        let mut desc = SceneDescription::default();
+        desc.settings.bake = Some(BakeProperties { mesh: 1 });
+        desc.shapes.push(ShapeDescription::Mesh(MeshDescription::default()));
+        assert!(Scene::try_from(desc).is_err());
+    }
+}
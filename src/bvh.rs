@@ -0,0 +1,695 @@
+use crate::ray::Ray;
+use crate::shapes::{AABB, ShapeIntersection};
+use crate::vec::{Point3, Vec3};
+use crate::epsilon::INFINITE_DISTANCE;
+use smallvec::SmallVec;
+
+const MAX_PRIMITIVES_IN_LEAF: usize = 4;
+const SAH_BUCKETS: usize = 12;
+// Cost of traversal step relative to cost of primitive intersection
+const SAH_TRAVERSAL_COST: f32 = 0.125;
+
+/// Entries of traversal stack that are kept on the call stack, stack of deeper hierarchies is
+/// allocated on the heap.
+pub const TRAVERSAL_STACK_SIZE: usize = 64;
+
+// Nodes are stored in depth first order, first child of interior node is next node in the array.
+#[derive(Debug, Clone, Copy)]
+struct BVHNode {
+    bbox: AABB,
+    // NOTE: for interior node index of second child, for leaf node offset of first primitive
+    offset: u32,
+    n_primitives: u32,
+    axis: u8,
+}
+
+impl BVHNode {
+    fn leaf(bbox: AABB, offset: usize, n_primitives: usize) -> Self {
+        Self { bbox, offset: offset as u32, n_primitives: n_primitives as u32, axis: 0 }
+    }
+
+    fn interior(bbox: AABB, second_child: usize, axis: usize) -> Self {
+        Self { bbox, offset: second_child as u32, n_primitives: 0, axis: axis as u8 }
+    }
+}
+
+fn point_axis(p: Point3, axis: usize) -> f32 {
+    match axis {
+        0 => p.x,
+        1 => p.y,
+        _ => p.z
+    }
+}
+
+fn max_extent(v: Vec3) -> usize {
+    if v.x > v.y && v.x > v.z {
+        0
+    } else if v.y > v.z {
+        1
+    } else {
+        2
+    }
+}
+
+// Traversal stack never holds more than max_depth - 1 nodes, root has depth one.
+fn intersect_nodes(nodes: &[BVHNode], primitives: &[u32], max_depth: usize, ray: &Ray,
+                   isect_fn: &dyn Fn(usize, &Ray) -> Option<f32>) -> Option<ShapeIntersection> {
+    if nodes.is_empty() {
+        return None
+    }
+    let mut primitive_id = 0;
+    let mut current_t = INFINITE_DISTANCE;
+    let rd = ray.direction;
+    let inv_rd = Vec3::new(1.0 / rd.x, 1.0 / rd.y, 1.0 / rd.z);
+    let dir_is_neg = [inv_rd.x < 0.0, inv_rd.y < 0.0, inv_rd.z < 0.0];
+
+    let mut stack: SmallVec<[usize; TRAVERSAL_STACK_SIZE]> = SmallVec::with_capacity(max_depth);
+    let mut node_idx = 0;
+    loop {
+        let node = &nodes[node_idx];
+        let hit = match node.bbox.intersect(ray.origin, inv_rd) {
+            Some(entry_t) => entry_t <= current_t,
+            None => false
+        };
+        if hit {
+            if node.n_primitives > 0 {
+                let offset = node.offset as usize;
+                for idx in primitives[offset..offset + node.n_primitives as usize].iter() {
+                    if let Some(t) = isect_fn(*idx as usize, ray) {
+                        if t < current_t {
+                            current_t = t;
+                            primitive_id = *idx as usize;
+                        }
+                    }
+                }
+            } else {
+                debug_assert!(stack.len() + 1 < max_depth, "BVH traversal is deeper than hierarchy");
+                // NOTE: closer child is visited first
+                if dir_is_neg[node.axis as usize] {
+                    stack.push(node_idx + 1);
+                    node_idx = node.offset as usize;
+                } else {
+                    stack.push(node.offset as usize);
+                    node_idx += 1;
+                }
+                continue;
+            }
+        }
+        match stack.pop() {
+            Some(idx) => node_idx = idx,
+            None => break
+        }
+    }
+    if current_t < INFINITE_DISTANCE {
+        Some(ShapeIntersection { t: current_t, shape_id: primitive_id })
+    } else {
+        None
+    }
+}
+
+/// Quality statistics of built hierarchy
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct BVHMetrics {
+    /// Expected cost of random ray (surface area heuristic), relative to cost of one primitive intersection
+    pub sah_cost: f32,
+    pub node_count: usize,
+    pub leaf_count: usize,
+    pub max_depth: usize,
+    pub average_leaf_size: f32,
+    /// Surface area of overlap of sibling nodes relative to surface area of their parent
+    pub average_overlap: f32,
+    pub max_overlap: f32,
+}
+
+fn overlap_area(a: &AABB, b: &AABB) -> f32 {
+    let (amin, amax, bmin, bmax) = (a.min(), a.max(), b.min(), b.max());
+    let min = Point3::new(amin.x.max(bmin.x), amin.y.max(bmin.y), amin.z.max(bmin.z));
+    let max = Point3::new(amax.x.min(bmax.x), amax.y.min(bmax.y), amax.z.min(bmax.z));
+    if min.x > max.x || min.y > max.y || min.z > max.z {
+        return 0.0;
+    }
+    AABB::new(min, max).surface_area()
+}
+
+fn calculate_metrics(nodes: &[BVHNode]) -> BVHMetrics {
+    let mut metrics = BVHMetrics { node_count: nodes.len(), ..Default::default() };
+    if nodes.is_empty() {
+        return metrics;
+    }
+    let root_area = nodes[0].bbox.surface_area();
+    let relative_area = |node: &BVHNode| -> f32 {
+        if root_area > 0.0 { node.bbox.surface_area() / root_area } else { 1.0 }
+    };
+    let mut primitives = 0;
+    let mut interior_count = 0;
+    let mut overlap_sum = 0.0;
+    let mut stack = vec![(0usize, 1usize)];
+    while let Some((idx, depth)) = stack.pop() {
+        let node = &nodes[idx];
+        metrics.max_depth = metrics.max_depth.max(depth);
+        if node.n_primitives > 0 {
+            metrics.leaf_count += 1;
+            primitives += node.n_primitives as usize;
+            metrics.sah_cost += node.n_primitives as f32 * relative_area(node);
+        } else {
+            let (first, second) = (idx + 1, node.offset as usize);
+            interior_count += 1;
+            metrics.sah_cost += SAH_TRAVERSAL_COST * relative_area(node);
+            let area = node.bbox.surface_area();
+            let overlap = if area > 0.0 { overlap_area(&nodes[first].bbox, &nodes[second].bbox) / area } else { 0.0 };
+            overlap_sum += overlap;
+            metrics.max_overlap = metrics.max_overlap.max(overlap);
+            stack.push((first, depth + 1));
+            stack.push((second, depth + 1));
+        }
+    }
+    metrics.average_leaf_size = primitives as f32 / metrics.leaf_count as f32;
+    if interior_count > 0 {
+        metrics.average_overlap = overlap_sum / interior_count as f32;
+    }
+    metrics
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BVHBuildMethod {
+    Midpoint,
+    SAH
+}
+
+impl BVHBuildMethod {
+    /// Names of split methods are same as in pbrt
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "middle" => Some(BVHBuildMethod::Midpoint),
+            "sah" => Some(BVHBuildMethod::SAH),
+            _ => None
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+struct BuildPrimitive {
+    bbox: AABB,
+    centroid: Point3,
+    index: u32
+}
+
+fn partition<T, F: Fn(&T) -> bool>(items: &mut [T], pred: F) -> usize {
+    let mut first = 0;
+    for i in 0..items.len() {
+        if pred(&items[i]) {
+            items.swap(first, i);
+            first += 1;
+        }
+    }
+    first
+}
+
+/// Top-down bounding volume hierarchy
+pub struct BVH {
+    method: BVHBuildMethod,
+    nodes: Vec<BVHNode>,
+    primitives: Vec<u32>,
+    max_depth: usize
+}
+
+impl BVH {
+    pub fn new(method: BVHBuildMethod) -> Self {
+        Self { method, nodes: Vec::new(), primitives: Vec::new(), max_depth: 0 }
+    }
+
+    pub fn build(&mut self, n_primitives: usize, calculate_bbox_fn: &dyn Fn(usize) -> AABB) {
+        self.nodes.clear();
+        self.primitives.clear();
+        self.max_depth = 0;
+        if n_primitives == 0 {
+            return;
+        }
+        let mut build_primitives: Vec<BuildPrimitive> = (0..n_primitives).map(|i| {
+            let bbox = calculate_bbox_fn(i);
+            BuildPrimitive { bbox, centroid: bbox.centroid(), index: i as u32 }
+        }).collect();
+        self.build_recursive(&mut build_primitives, 1);
+        debug_assert_eq!(self.max_depth, calculate_metrics(&self.nodes).max_depth);
+    }
+
+    fn build_recursive(&mut self, prims: &mut [BuildPrimitive], depth: usize) -> usize {
+        let node_idx = self.nodes.len();
+        self.max_depth = self.max_depth.max(depth);
+        let mut bbox = prims[0].bbox;
+        let mut centroid_bbox = AABB::new(prims[0].centroid, prims[0].centroid);
+        for prim in prims.iter() {
+            bbox = bbox.union(&prim.bbox);
+            centroid_bbox = centroid_bbox.union(&AABB::new(prim.centroid, prim.centroid));
+        }
+        let axis = max_extent(centroid_bbox.max() - centroid_bbox.min());
+        let cmin = point_axis(centroid_bbox.min(), axis);
+        let cmax = point_axis(centroid_bbox.max(), axis);
+
+        if prims.len() <= MAX_PRIMITIVES_IN_LEAF || cmin == cmax {
+            self.nodes.push(BVHNode::leaf(bbox, self.primitives.len(), prims.len()));
+            self.primitives.extend(prims.iter().map(|p| p.index));
+            return node_idx;
+        }
+
+        let mid = match self.method {
+            BVHBuildMethod::Midpoint => {
+                let pmid = 0.5 * (cmin + cmax);
+                partition(prims, |p| point_axis(p.centroid, axis) < pmid)
+            }
+            BVHBuildMethod::SAH => Self::sah_split(prims, &bbox, axis, cmin, cmax)
+        };
+        // NOTE: fallback to equal counts if partition failed
+        let mid = if mid == 0 || mid == prims.len() {
+            let mid = prims.len() / 2;
+            prims.select_nth_unstable_by(mid, |a, b| {
+                point_axis(a.centroid, axis).total_cmp(&point_axis(b.centroid, axis))
+            });
+            mid
+        } else {
+            mid
+        };
+
+        self.nodes.push(BVHNode::leaf(bbox, 0, 0));
+        let (left, right) = prims.split_at_mut(mid);
+        self.build_recursive(left, depth + 1);
+        let second_child = self.build_recursive(right, depth + 1);
+        self.nodes[node_idx] = BVHNode::interior(bbox, second_child, axis);
+        node_idx
+    }
+
+    fn sah_split(prims: &mut [BuildPrimitive], bbox: &AABB, axis: usize, cmin: f32, cmax: f32) -> usize {
+        let bucket_index = |p: &BuildPrimitive| -> usize {
+            let b = (SAH_BUCKETS as f32 * (point_axis(p.centroid, axis) - cmin) / (cmax - cmin)) as usize;
+            b.min(SAH_BUCKETS - 1)
+        };
+        let mut counts = [0usize; SAH_BUCKETS];
+        let mut bounds: [Option<AABB>; SAH_BUCKETS] = [None; SAH_BUCKETS];
+        for prim in prims.iter() {
+            let b = bucket_index(prim);
+            counts[b] += 1;
+            bounds[b] = Some(match bounds[b] {
+                Some(bb) => bb.union(&prim.bbox),
+                None => prim.bbox
+            });
+        }
+
+        let area = |range: &[Option<AABB>]| -> f32 {
+            let mut result: Option<AABB> = None;
+            for bb in range.iter().flatten() {
+                result = Some(match result {
+                    Some(r) => r.union(bb),
+                    None => *bb
+                });
+            }
+            result.map_or(0.0, |r| r.surface_area())
+        };
+
+        let mut best_cost = f32::MAX;
+        let mut best_split = 0;
+        for split in 0..SAH_BUCKETS - 1 {
+            let area_left = area(&bounds[..=split]);
+            let area_right = area(&bounds[split + 1..]);
+            let count_left: usize = counts[..=split].iter().sum();
+            let count_right: usize = counts[split + 1..].iter().sum();
+            let cost = SAH_TRAVERSAL_COST + (count_left as f32 * area_left + count_right as f32 * area_right) / bbox.surface_area();
+            if cost < best_cost {
+                best_cost = cost;
+                best_split = split;
+            }
+        }
+        partition(prims, |p| bucket_index(p) <= best_split)
+    }
+
+    pub fn intersect(&self, ray: &Ray, isect_fn: &dyn Fn(usize, &Ray) -> Option<f32>) -> Option<ShapeIntersection> {
+        intersect_nodes(&self.nodes, &self.primitives, self.max_depth, ray, isect_fn)
+    }
+
+    pub fn metrics(&self) -> BVHMetrics {
+        calculate_metrics(&self.nodes)
+    }
+
+    /// Number of levels of hierarchy, root is at depth one
+    pub fn max_depth(&self) -> usize {
+        self.max_depth
+    }
+
+    /// Bytes used by nodes and primitive indices
+    pub fn memory_usage(&self) -> usize {
+        self.nodes.capacity() * std::mem::size_of::<BVHNode>() + self.primitives.capacity() * std::mem::size_of::<u32>()
+    }
+}
+
+// Approximate agglomerative clustering parameters (AAC-Fast)
+// Gu et al. - Efficient BVH Construction via Approximate Agglomerative Clustering
+const AAC_DELTA: usize = 4;
+const AAC_EPSILON: f32 = 0.2;
+
+fn aac_reduction(n: usize) -> usize {
+    let alpha = 0.5 - AAC_EPSILON;
+    let c = (AAC_DELTA as f32).powf(0.5 + AAC_EPSILON) * 0.5;
+    ((c * (n as f32).powf(alpha)) as usize).max(1)
+}
+
+// Spread lower 10 bits of value so there are two zero bits between each bit
+fn left_shift3(x: u32) -> u32 {
+    let mut x = x & 0x3ff;
+    x = (x | (x << 16)) & 0x30000ff;
+    x = (x | (x << 8)) & 0x300f00f;
+    x = (x | (x << 4)) & 0x30c30c3;
+    x = (x | (x << 2)) & 0x9249249;
+    x
+}
+
+fn morton_code(p: Vec3) -> u32 {
+    let quantize = |v: f32| -> u32 { (v * 1024.0).clamp(0.0, 1023.0) as u32 };
+    (left_shift3(quantize(p.z)) << 2) | (left_shift3(quantize(p.y)) << 1) | left_shift3(quantize(p.x))
+}
+
+enum BuildNode {
+    Leaf { bbox: AABB, primitive: u32 },
+    Interior { bbox: AABB, left: usize, right: usize }
+}
+
+impl BuildNode {
+    fn bbox(&self) -> &AABB {
+        match self {
+            BuildNode::Leaf { bbox, .. } => bbox,
+            BuildNode::Interior { bbox, .. } => bbox
+        }
+    }
+}
+
+/// Bottom-up bounding volume hierarchy built with approximate agglomerative clustering.
+/// Primitives are sorted along Morton curve and clusters are merged only inside
+/// of small neighbourhoods, that keeps build time close to linear.
+pub struct BVHUp {
+    nodes: Vec<BVHNode>,
+    primitives: Vec<u32>,
+    max_depth: usize
+}
+
+impl Default for BVHUp {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl BVHUp {
+    pub fn new() -> Self {
+        Self { nodes: Vec::new(), primitives: Vec::new(), max_depth: 0 }
+    }
+
+    pub fn build(&mut self, n_primitives: usize, calculate_bbox_fn: &dyn Fn(usize) -> AABB) {
+        self.nodes.clear();
+        self.primitives.clear();
+        self.max_depth = 0;
+        if n_primitives == 0 {
+            return;
+        }
+        let bboxes: Vec<AABB> = (0..n_primitives).map(calculate_bbox_fn).collect();
+        let mut centroid_bbox = AABB::new(bboxes[0].centroid(), bboxes[0].centroid());
+        for bbox in bboxes.iter() {
+            centroid_bbox = centroid_bbox.union(&AABB::new(bbox.centroid(), bbox.centroid()));
+        }
+        let extent = centroid_bbox.max() - centroid_bbox.min();
+        let inv = |v: f32| if v > 0.0 { 1.0 / v } else { 0.0 };
+        let scale = Vec3::new(inv(extent.x), inv(extent.y), inv(extent.z));
+
+        let mut codes: Vec<(u32, u32)> = bboxes.iter().enumerate().map(|(i, bbox)| {
+            let d = bbox.centroid() - centroid_bbox.min();
+            (morton_code(Vec3::new(d.x * scale.x, d.y * scale.y, d.z * scale.z)), i as u32)
+        }).collect();
+        codes.sort_unstable();
+
+        let mut arena: Vec<BuildNode> = codes.iter().map(|(_, idx)| {
+            BuildNode::Leaf { bbox: bboxes[*idx as usize], primitive: *idx }
+        }).collect();
+
+        let clusters = Self::build_tree(&mut arena, &codes, 0, n_primitives, 29);
+        let clusters = Self::combine_clusters(&mut arena, clusters, 1);
+        self.flatten(&arena, clusters[0], 1);
+        debug_assert_eq!(self.max_depth, calculate_metrics(&self.nodes).max_depth);
+    }
+
+    fn build_tree(arena: &mut Vec<BuildNode>, codes: &[(u32, u32)], start: usize, end: usize, bit: i32) -> Vec<usize> {
+        let n = end - start;
+        if n <= AAC_DELTA {
+            let clusters = (start..end).collect();
+            return Self::combine_clusters(arena, clusters, aac_reduction(AAC_DELTA));
+        }
+        // NOTE: codes are sorted so all codes with given bit set are at the end of the range
+        let mut bit = bit;
+        let mut split = start + n / 2;
+        while bit >= 0 {
+            let mask = 1u32 << bit;
+            let pos = start + codes[start..end].partition_point(|(code, _)| code & mask == 0);
+            bit -= 1;
+            if pos != start && pos != end {
+                split = pos;
+                break;
+            }
+        }
+        let mut clusters = Self::build_tree(arena, codes, start, split, bit);
+        clusters.extend(Self::build_tree(arena, codes, split, end, bit));
+        Self::combine_clusters(arena, clusters, aac_reduction(n))
+    }
+
+    fn find_best_match(arena: &[BuildNode], clusters: &[usize], i: usize) -> usize {
+        let bbox = arena[clusters[i]].bbox();
+        let mut best_cost = f32::MAX;
+        let mut best = i;
+        for (j, cluster) in clusters.iter().enumerate() {
+            if i == j {
+                continue;
+            }
+            let cost = bbox.union(arena[*cluster].bbox()).surface_area();
+            if cost < best_cost {
+                best_cost = cost;
+                best = j;
+            }
+        }
+        best
+    }
+
+    fn combine_clusters(arena: &mut Vec<BuildNode>, mut clusters: Vec<usize>, n: usize) -> Vec<usize> {
+        if clusters.len() <= n {
+            return clusters;
+        }
+        let mut closest: Vec<usize> = (0..clusters.len()).map(|i| {
+            Self::find_best_match(arena, &clusters, i)
+        }).collect();
+
+        while clusters.len() > n {
+            let mut best_cost = f32::MAX;
+            let (mut left, mut right) = (0, 0);
+            for (i, c) in closest.iter().enumerate() {
+                let cost = arena[clusters[i]].bbox().union(arena[clusters[*c]].bbox()).surface_area();
+                if cost < best_cost {
+                    best_cost = cost;
+                    left = i;
+                    right = *c;
+                }
+            }
+
+            let bbox = arena[clusters[left]].bbox().union(arena[clusters[right]].bbox());
+            arena.push(BuildNode::Interior { bbox, left: clusters[left], right: clusters[right] });
+            let last = clusters.len() - 1;
+            clusters[left] = arena.len() - 1;
+            clusters.swap_remove(right);
+            closest.swap_remove(right);
+            let merged = if left == last { right } else { left };
+
+            // NOTE: update references to merged, removed and moved clusters
+            let mut stale = Vec::new();
+            for (i, c) in closest.iter_mut().enumerate() {
+                if *c == left || *c == right || i == merged {
+                    stale.push(i);
+                } else if *c == last {
+                    *c = right;
+                }
+            }
+            for i in stale {
+                closest[i] = Self::find_best_match(arena, &clusters, i);
+            }
+        }
+        clusters
+    }
+
+    fn flatten(&mut self, arena: &[BuildNode], node: usize, depth: usize) -> usize {
+        let node_idx = self.nodes.len();
+        self.max_depth = self.max_depth.max(depth);
+        match arena[node] {
+            BuildNode::Leaf { bbox, primitive } => {
+                self.nodes.push(BVHNode::leaf(bbox, self.primitives.len(), 1));
+                self.primitives.push(primitive);
+            }
+            BuildNode::Interior { bbox, left, right } => {
+                // NOTE: child with smaller centroid along split axis must be first
+                let delta = arena[right].bbox().centroid() - arena[left].bbox().centroid();
+                let axis = max_extent(Vec3::new(delta.x.abs(), delta.y.abs(), delta.z.abs()));
+                let (first, second) = if point_axis(Point3::new(delta.x, delta.y, delta.z), axis) >= 0.0 {
+                    (left, right)
+                } else {
+                    (right, left)
+                };
+                self.nodes.push(BVHNode::leaf(bbox, 0, 0));
+                self.flatten(arena, first, depth + 1);
+                let second_child = self.flatten(arena, second, depth + 1);
+                self.nodes[node_idx] = BVHNode::interior(bbox, second_child, axis);
+            }
+        }
+        node_idx
+    }
+
+    pub fn intersect(&self, ray: &Ray, isect_fn: &dyn Fn(usize, &Ray) -> Option<f32>) -> Option<ShapeIntersection> {
+        intersect_nodes(&self.nodes, &self.primitives, self.max_depth, ray, isect_fn)
+    }
+
+    pub fn metrics(&self) -> BVHMetrics {
+        calculate_metrics(&self.nodes)
+    }
+
+    pub fn max_depth(&self) -> usize {
+        self.max_depth
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rng::{PCGRng, Rng};
+    use crate::shapes::LinearIntersector;
+    use crate::isect::isect_ray_sphere;
+
+    fn random_spheres(n: usize) -> Vec<(Point3, f32)> {
+        let mut rng = PCGRng::new(123, 0);
+        (0..n).map(|_| {
+            let p = Point3::new(rng.rand_f32() * 20.0 - 10.0, rng.rand_f32() * 20.0 - 10.0, rng.rand_f32() * 20.0 - 10.0);
+            (p, 0.1 + rng.rand_f32() * 0.5)
+        }).collect()
+    }
+
+    #[test]
+    fn bvh_matches_linear_intersector() {
+        let spheres = random_spheres(500);
+        let bbox_fn = |i: usize| -> AABB {
+            let (p, r) = spheres[i];
+            AABB::new(p + Vec3::new(-r, -r, -r), p + Vec3::new(r, r, r))
+        };
+        let isect_fn = |i: usize, ray: &Ray| -> Option<f32> {
+            let (p, r) = spheres[i];
+            isect_ray_sphere(ray, p, r, 0.0, 1e38)
+        };
+        let mut linear = LinearIntersector::new();
+        linear.prepare_for_rendering(spheres.len(), &bbox_fn);
+        let mut midpoint = BVH::new(BVHBuildMethod::Midpoint);
+        midpoint.build(spheres.len(), &bbox_fn);
+        let mut sah = BVH::new(BVHBuildMethod::SAH);
+        sah.build(spheres.len(), &bbox_fn);
+        let mut bvh_up = BVHUp::new();
+        bvh_up.build(spheres.len(), &bbox_fn);
+
+        let mut rng = PCGRng::new(7, 0);
+        let mut nhits = 0;
+        for _ in 0..1000 {
+            let origin = Point3::new(rng.rand_f32() * 30.0 - 15.0, rng.rand_f32() * 30.0 - 15.0, rng.rand_f32() * 30.0 - 15.0);
+            let direction = Vec3::new(rng.rand_f32() - 0.5, rng.rand_f32() - 0.5, rng.rand_f32() - 0.5).normalize();
+            let ray = Ray::new(origin, direction);
+            let expected = linear.intersect(&ray, &isect_fn);
+            for result in [midpoint.intersect(&ray, &isect_fn), sah.intersect(&ray, &isect_fn), bvh_up.intersect(&ray, &isect_fn)] {
+                match (&expected, result) {
+                    (Some(e), Some(r)) => {
+                        assert_eq!(e.shape_id, r.shape_id);
+                        assert_eq!(e.t, r.t);
+                    }
+                    (None, None) => {}
+                    _ => panic!("BVH and linear intersector results differ!")
+                }
+            }
+            if expected.is_some() {
+                nhits += 1;
+            }
+        }
+        assert!(nhits > 0);
+    }
+
+    #[test]
+    fn bvh_up_large_build() {
+        let spheres = random_spheres(50000);
+        let bbox_fn = |i: usize| -> AABB {
+            let (p, r) = spheres[i];
+            AABB::new(p + Vec3::new(-r, -r, -r), p + Vec3::new(r, r, r))
+        };
+        let mut bvh_up = BVHUp::new();
+        bvh_up.build(spheres.len(), &bbox_fn);
+        let mut primitives = bvh_up.primitives.clone();
+        primitives.sort_unstable();
+        assert!(primitives.iter().enumerate().all(|(i, p)| i == *p as usize));
+        assert_eq!(bvh_up.nodes.len(), 2 * spheres.len() - 1);
+    }
+
+    #[test]
+    fn bvh_metrics() {
+        let spheres = random_spheres(2000);
+        let bbox_fn = |i: usize| -> AABB {
+            let (p, r) = spheres[i];
+            AABB::new(p + Vec3::new(-r, -r, -r), p + Vec3::new(r, r, r))
+        };
+        let mut midpoint = BVH::new(BVHBuildMethod::Midpoint);
+        midpoint.build(spheres.len(), &bbox_fn);
+        let mut sah = BVH::new(BVHBuildMethod::SAH);
+        sah.build(spheres.len(), &bbox_fn);
+        let mut bvh_up = BVHUp::new();
+        bvh_up.build(spheres.len(), &bbox_fn);
+
+        for metrics in [midpoint.metrics(), sah.metrics(), bvh_up.metrics()] {
+            assert_eq!(metrics.node_count, 2 * metrics.leaf_count - 1);
+            assert!(metrics.max_depth > 1 && metrics.max_depth < 64);
+            assert!(metrics.sah_cost > 0.0);
+            assert!(metrics.average_overlap >= 0.0 && metrics.average_overlap <= metrics.max_overlap);
+            assert!(metrics.max_overlap <= 1.0);
+        }
+        let metrics = bvh_up.metrics();
+        assert_eq!(metrics.average_leaf_size, 1.0);
+        assert!(sah.metrics().sah_cost <= midpoint.metrics().sah_cost * 1.1);
+        assert_eq!(sah.max_depth(), sah.metrics().max_depth);
+        assert_eq!(bvh_up.max_depth(), metrics.max_depth);
+        assert_eq!(BVH::new(BVHBuildMethod::SAH).metrics(), BVHMetrics::default());
+    }
+
+    // Chain of interior nodes whose second children are leaves
+    fn push_chain(bvh: &mut BVH, bbox: AABB, depth: usize) -> usize {
+        let node_idx = bvh.nodes.len();
+        let primitive = bvh.primitives.len();
+        bvh.max_depth = bvh.max_depth.max(depth);
+        bvh.nodes.push(BVHNode::leaf(bbox, primitive, 1));
+        bvh.primitives.push(primitive as u32);
+        if depth < 100 {
+            push_chain(bvh, bbox, depth + 1);
+            let second_child = bvh.nodes.len();
+            bvh.nodes.push(BVHNode::leaf(bbox, bvh.primitives.len(), 1));
+            bvh.primitives.push(bvh.primitives.len() as u32);
+            bvh.nodes[node_idx] = BVHNode::interior(bbox, second_child, 0);
+        }
+        node_idx
+    }
+
+    #[test]
+    fn deep_bvh_traversal() {
+        let bbox = AABB::new(Point3::new(-1.0, -1.0, -1.0), Point3::new(1.0, 1.0, 1.0));
+        let mut bvh = BVH::new(BVHBuildMethod::SAH);
+        push_chain(&mut bvh, bbox, 1);
+        assert_eq!(bvh.max_depth(), 100);
+        assert!(bvh.max_depth() > TRAVERSAL_STACK_SIZE);
+        assert_eq!(bvh.metrics().max_depth, 100);
+
+        // every primitive is hit, the one with largest index is closest
+        let n = bvh.primitives.len();
+        let isect_fn = |i: usize, _ray: &Ray| -> Option<f32> { Some(5.0 + (n - i) as f32) };
+        let ray = Ray::new(Point3::new(0.0, 0.0, -5.0), Vec3::new(0.0, 0.0, 1.0));
+        let result = bvh.intersect(&ray, &isect_fn).unwrap();
+        assert_eq!((result.shape_id, result.t), (n - 1, 6.0));
+    }
+}
@@ -8,20 +8,43 @@ pub fn create_raster_to_ndc_transformation(resolution_x: usize, resolution_y: us
     ndc_to_raster.inverse()
 }
 
-pub fn create_ndc_to_screen_transformation(resolution_x: usize, resolution_y: usize) -> Transformation {
-    let aspect_ratio = resolution_x as f32 / resolution_y as f32;
-    // screen, shorter axis [-1, 1]
-    let (pmin_x, pmin_y, pmax_x, pmax_y) = if aspect_ratio > 1.0 {
-        (-aspect_ratio, -1.0, aspect_ratio, 1.0)
-    } else {
-        (-1.0, -1.0 / aspect_ratio, 1.0, 1.0 / aspect_ratio)
-    };
-
-    let scale_x = (pmax_x - pmin_x).recip();
-    let scale_y = (pmax_y - pmin_y).recip();
+/// Extent of the image on the screen plane
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ScreenWindow {
+    pub min_x: f32,
+    pub max_x: f32,
+    pub min_y: f32,
+    pub max_y: f32,
+}
+
+impl ScreenWindow {
+    pub fn new(min_x: f32, max_x: f32, min_y: f32, max_y: f32) -> Self {
+        Self { min_x, max_x, min_y, max_y }
+    }
+
+    /// Default window where shorter axis of the frame is [-1, 1]
+    pub fn from_frame_aspect_ratio(aspect_ratio: f32) -> Self {
+        if aspect_ratio > 1.0 {
+            Self::new(-aspect_ratio, aspect_ratio, -1.0, 1.0)
+        } else {
+            Self::new(-1.0, 1.0, -1.0 / aspect_ratio, 1.0 / aspect_ratio)
+        }
+    }
+
+    /// Offset window by fraction of its width and height
+    pub fn shift(&self, shift_x: f32, shift_y: f32) -> Self {
+        let dx = shift_x * (self.max_x - self.min_x);
+        let dy = shift_y * (self.max_y - self.min_y);
+        Self::new(self.min_x + dx, self.max_x + dx, self.min_y + dy, self.max_y + dy)
+    }
+}
+
+pub fn create_ndc_to_screen_transformation(window: &ScreenWindow) -> Transformation {
+    let scale_x = (window.max_x - window.min_x).recip();
+    let scale_y = (window.max_y - window.min_y).recip();
     let screen_to_ndc =
     Transformation::scale(scale_x, scale_y, 1.0) * 
-    Transformation::translate(&Vec3::new(-pmin_x, -pmax_y, 0.0));
+    Transformation::translate(&Vec3::new(-window.min_x, -window.max_y, 0.0));
     screen_to_ndc.inverse()
 }
 
@@ -30,32 +53,204 @@ pub fn create_screen_to_perspective_transformation(fov: f32, z_near: f32, z_far:
 }
 
 pub fn create_raster_to_perspective_transformation(
-    resolution_x: usize, resolution_y: usize, fov: f32, z_near: f32, z_far: f32) -> Transformation {
+    resolution_x: usize, resolution_y: usize, window: &ScreenWindow, fov: f32, z_near: f32, z_far: f32) -> Transformation {
     let raster_to_ndc = create_raster_to_ndc_transformation(resolution_x, resolution_y);
-    let ndc_to_screen = create_ndc_to_screen_transformation(resolution_x, resolution_y);
+    let ndc_to_screen = create_ndc_to_screen_transformation(window);
     let screen_to_camera = create_screen_to_perspective_transformation(fov, z_near, z_far);
     screen_to_camera * ndc_to_screen * raster_to_ndc
 }
 
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StereoLayout {
+    SideBySide,
+    TopBottom
+}
+
+/// Stereo rendering, left and right eye are rendered into one image.
+/// Eyes use parallel cameras with off-axis frustums that converge at convergence distance.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct StereoSettings {
+    pub interocular_distance: f32,
+    pub convergence_distance: f32,
+    pub layout: StereoLayout
+}
+
+impl Default for StereoSettings {
+    fn default() -> Self {
+        Self { interocular_distance: 0.065, convergence_distance: 10.0, layout: StereoLayout::SideBySide }
+    }
+}
+
+/// How much light passes through shutter during exposure, box shutter is fully open all the time,
+/// triangle shutter opens linearly until middle of the interval and then closes.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ShutterCurve {
+    Box,
+    Triangle
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Shutter {
+    pub open: f32,
+    pub close: f32,
+    pub curve: ShutterCurve
+}
+
+impl Default for Shutter {
+    fn default() -> Self {
+        Self { open: 0.0, close: 1.0, curve: ShutterCurve::Box }
+    }
+}
+
+impl Shutter {
+    /// Map uniform sample to time inside of shutter interval distributed by shutter curve.
+    pub fn sample(&self, u: f32) -> f32 {
+        let t = match self.curve {
+            ShutterCurve::Box => u,
+            ShutterCurve::Triangle if u < 0.5 => (0.5 * u).sqrt(),
+            ShutterCurve::Triangle => 1.0 - (0.5 * (1.0 - u)).sqrt()
+        };
+        self.open + t * (self.close - self.open)
+    }
+}
+
+struct StereoEyes {
+    layout: StereoLayout,
+    eye_size: ImageSize,
+    eye_offset: f32,
+    left_raster_to_camera: Transformation,
+    right_raster_to_camera: Transformation,
+}
+
+/// Connection from point in the scene to the camera, see PerspectiveCamera::sample_wi
+pub struct CameraWiSample {
+    /// Emitted importance along direction from camera to the point
+    pub importance: f32,
+    /// Direction from point to the camera
+    pub wi: Vec3,
+    pub pdfw: f32,
+    pub position: Point3,
+    /// Position on the image where the point is visible
+    pub raster: (f32, f32)
+}
+
 pub struct PerspectiveCamera {
     raster_to_camera: Transformation,
     camera_to_world: Transformation,
+    stereo: Option<StereoEyes>,
+    shutter: Shutter,
+    size: ImageSize,
+    // NOTE: raster points are mapped to plane z = image_plane_z in camera space
+    image_plane_z: f32,
+    // Area of image on plane at distance 1 from the camera
+    image_area: f32,
 }
 
 impl PerspectiveCamera {
-    fn new(size: ImageSize, fov: f32, near_plane: f32, far_plane: f32, camera_to_world: Transformation) -> PerspectiveCamera {
-        let raster_to_camera = create_raster_to_perspective_transformation(size.width, size.height, fov, near_plane, far_plane);
-        PerspectiveCamera { raster_to_camera, camera_to_world }
+    fn new(size: ImageSize, window: &ScreenWindow, fov: f32, near_plane: f32, far_plane: f32,
+           camera_to_world: Transformation) -> PerspectiveCamera {
+        let raster_to_camera = create_raster_to_perspective_transformation(
+            size.width, size.height, window, fov, near_plane, far_plane);
+        let p_min = Point3::new(0.0, 0.0, 0.0) * raster_to_camera;
+        let p_max = Point3::new(size.width as f32, size.height as f32, 0.0) * raster_to_camera;
+        let image_area = ((p_max.x - p_min.x) * (p_max.y - p_min.y) / (p_min.z * p_min.z)).abs();
+        PerspectiveCamera { raster_to_camera, camera_to_world, stereo: None, shutter: Shutter::default(),
+                            size, image_plane_z: p_min.z, image_area }
+    }
+
+    fn forward(&self) -> Vec3 {
+        (self.camera_to_world * Vec3::new(0.0, 0.0, 1.0)).normalize()
+    }
+
+    pub fn position(&self) -> Point3 {
+        self.camera_to_world * Point3::new(0.0, 0.0, 0.0)
+    }
+
+    /// Raster position where direction leaving the camera crosses the image, None if it misses the image.
+    /// Importance functions are defined only for mono camera, stereo camera always returns None.
+    pub fn raster_position(&self, direction: Vec3) -> Option<(f32, f32)> {
+        if self.stereo.is_some() {
+            return None
+        }
+        let local = self.camera_to_world.inverse() * direction;
+        if local.z <= 0.0 {
+            return None
+        }
+        let scale = self.image_plane_z / local.z;
+        let raster = Point3::new(local.x * scale, local.y * scale, local.z * scale) * self.raster_to_camera.inverse();
+        let inside = raster.x >= 0.0 && raster.x < self.size.width as f32 && raster.y >= 0.0 && raster.y < self.size.height as f32;
+        inside.then_some((raster.x, raster.y))
+    }
+
+    /// Importance emitted along ray leaving the camera and raster position of the ray.
+    /// Importance is normalized so that its integral over the image is one.
+    pub fn we(&self, ray: &Ray) -> Option<(f32, (f32, f32))> {
+        let cos_theta = ray.direction * self.forward();
+        if cos_theta <= 0.0 {
+            return None
+        }
+        let raster = self.raster_position(ray.direction)?;
+        let cos2_theta = cos_theta * cos_theta;
+        Some(((self.image_area * cos2_theta * cos2_theta).recip(), raster))
+    }
+
+    /// Position and direction densities of ray leaving the camera, position of pinhole is delta so
+    /// its density is one.
+    pub fn pdf_we(&self, ray: &Ray) -> (f32, f32) {
+        let cos_theta = ray.direction * self.forward();
+        if cos_theta <= 0.0 || self.raster_position(ray.direction).is_none() {
+            return (0.0, 0.0)
+        }
+        (1.0, (self.image_area * cos_theta * cos_theta * cos_theta).recip())
+    }
+
+    /// Connect point in the scene to the camera, None if point is not visible on the image.
+    pub fn sample_wi(&self, point: Point3) -> Option<CameraWiSample> {
+        let position = self.position();
+        let to_camera = position - point;
+        let dist_sqr = to_camera.length_sqr();
+        if dist_sqr == 0.0 {
+            return None
+        }
+        let wi = to_camera * dist_sqr.sqrt().recip();
+        let (importance, raster) = self.we(&Ray::new(position, -wi))?;
+        let pdfw = dist_sqr / (wi * self.forward()).abs();
+        Some(CameraWiSample { importance, wi, pdfw, position, raster })
+    }
+
+    /// Generate ray at raster position, time of the ray is warped from u_time by shutter curve.
+    pub fn generate_ray_at_time(&self, x: f32, y: f32, u_time: f32) -> Ray {
+        self.generate_ray(x, y).with_time(self.shutter.sample(u_time))
     }
 
     pub fn generate_ray(&self, x: f32, y: f32) -> Ray {
-        let local_origin = Point3::new(0.0, 0.0, 0.0);
-        let point_on_camera = Point3::new(x, y, 0.0) * self.raster_to_camera;
+        let stereo = match &self.stereo {
+            Some(stereo) => stereo,
+            None => {
+                let local_origin = Point3::new(0.0, 0.0, 0.0);
+                let point_on_camera = Point3::new(x, y, 0.0) * self.raster_to_camera;
+                let local_direction = Vec3::from(point_on_camera);
+                return Ray::new(local_origin, local_direction) * self.camera_to_world
+            }
+        };
+        let (right_eye, x, y) = match stereo.layout {
+            StereoLayout::SideBySide if x >= stereo.eye_size.width as f32 => (true, x - stereo.eye_size.width as f32, y),
+            StereoLayout::TopBottom if y >= stereo.eye_size.height as f32 => (true, x, y - stereo.eye_size.height as f32),
+            _ => (false, x, y)
+        };
+        let (raster_to_camera, offset) = if right_eye {
+            (stereo.right_raster_to_camera, stereo.eye_offset)
+        } else {
+            (stereo.left_raster_to_camera, -stereo.eye_offset)
+        };
+        let local_origin = Point3::new(offset, 0.0, 0.0);
+        let point_on_camera = Point3::new(x, y, 0.0) * raster_to_camera;
         let local_direction = Vec3::from(point_on_camera);
         Ray::new(local_origin, local_direction) * self.camera_to_world
     }
 }
 
+#[derive(Debug)]
 pub struct PerspectiveCameraDescriptor {
     pub resolution: ImageSize,
     pub fov: f32,
@@ -65,15 +260,70 @@ pub struct PerspectiveCameraDescriptor {
     pub near_plane: Option<f32>,
     pub far_plane: Option<f32>,
     pub camera_to_world: Option<Transformation>,
+    /// Width of pixel divided by its height
+    pub pixel_aspect_ratio: f32,
+    pub screen_window: Option<ScreenWindow>,
+    /// Horizontal and vertical lens shift as fraction of screen window size
+    pub lens_shift: (f32, f32),
+    pub stereo: Option<StereoSettings>,
+    pub shutter: Shutter,
 }
 
 impl PerspectiveCameraDescriptor {
-    pub fn create(&self) -> PerspectiveCamera {
+    /// Error when camera to world transformation isn't given and camera can't be oriented by look at.
+    pub fn create(&self) -> Result<PerspectiveCamera, String> {
         let near_plane = self.near_plane.unwrap_or(0.01);
         let far_plane = self.far_plane.unwrap_or(1000.0);
         let up = self.up.unwrap_or(Vec3::new(0.0, 1.0, 0.0));
-        let camera_to_world = self.camera_to_world.unwrap_or(Transformation::look_at(self.position, self.look_at, up).inverse());
-        PerspectiveCamera::new(self.resolution, self.fov, near_plane, far_plane, camera_to_world)
+        let camera_to_world = match self.camera_to_world {
+            Some(camera_to_world) => camera_to_world,
+            None => Transformation::look_at(self.position, self.look_at, up)?.inverse()
+        };
+        let mut camera = PerspectiveCamera::new(self.resolution, &self.screen_window(), self.fov, near_plane, far_plane, camera_to_world);
+        camera.shutter = self.shutter;
+        if let Some(stereo) = &self.stereo {
+            let eye_size = self.eye_size();
+            let window = self.window_for(eye_size);
+            // NOTE: shift of the screen window so that eye frustums overlap at convergence distance
+            let eye_offset = 0.5 * stereo.interocular_distance;
+            let tan_angle = (0.5 * self.fov.to_radians()).tan();
+            let shift = eye_offset / (stereo.convergence_distance * tan_angle * (window.max_x - window.min_x));
+            let raster_to_camera = |window: &ScreenWindow| {
+                create_raster_to_perspective_transformation(eye_size.width, eye_size.height, window, self.fov, near_plane, far_plane)
+            };
+            camera.stereo = Some(StereoEyes {
+                layout: stereo.layout,
+                eye_size,
+                eye_offset,
+                left_raster_to_camera: raster_to_camera(&window.shift(shift, 0.0)),
+                right_raster_to_camera: raster_to_camera(&window.shift(-shift, 0.0)),
+            });
+        }
+        Ok(camera)
+    }
+
+    pub fn screen_window(&self) -> ScreenWindow {
+        self.window_for(self.resolution)
+    }
+
+    fn window_for(&self, resolution: ImageSize) -> ScreenWindow {
+        let window = match self.screen_window {
+            Some(window) => window,
+            None => {
+                let frame_aspect_ratio = self.pixel_aspect_ratio * resolution.width as f32 / resolution.height as f32;
+                ScreenWindow::from_frame_aspect_ratio(frame_aspect_ratio)
+            }
+        };
+        window.shift(self.lens_shift.0, self.lens_shift.1)
+    }
+
+    /// Resolution of one eye, in stereo mode image resolution contains both eyes
+    pub fn eye_size(&self) -> ImageSize {
+        match self.stereo.map(|stereo| stereo.layout) {
+            Some(StereoLayout::SideBySide) => ImageSize::new(self.resolution.width / 2, self.resolution.height),
+            Some(StereoLayout::TopBottom) => ImageSize::new(self.resolution.width, self.resolution.height / 2),
+            None => self.resolution
+        }
     }
 }
 
@@ -87,7 +337,12 @@ impl Default for PerspectiveCameraDescriptor {
             up: None,
             near_plane: None,
             far_plane: None,
-            camera_to_world: None
+            camera_to_world: None,
+            pixel_aspect_ratio: 1.0,
+            screen_window: None,
+            lens_shift: (0.0, 0.0),
+            stereo: None,
+            shutter: Shutter::default()
         }
     }
 }
@@ -97,6 +352,8 @@ impl Default for PerspectiveCameraDescriptor {
 mod tests {
     use super::*;
     use crate::vec::Point3;
+    use crate::rng::{PCGRng, Rng};
+    use crate::samplings::sample_uniform_sphere;
 
     #[test]
     fn test_create_matrix() {
@@ -112,4 +369,103 @@ mod tests {
         // Assert that the matrix is correctly created
         //assert_eq!(matrix, Transformation::scale(800.0, -600.0, 1.0));
     }
+
+    #[test]
+    fn screen_window_mapping() {
+        let mut desc = PerspectiveCameraDescriptor { resolution: ImageSize::new(200, 100), ..Default::default() };
+        assert_eq!(desc.screen_window(), ScreenWindow::new(-2.0, 2.0, -1.0, 1.0));
+        desc.pixel_aspect_ratio = 0.5;
+        assert_eq!(desc.screen_window(), ScreenWindow::new(-1.0, 1.0, -1.0, 1.0));
+
+        let window = ScreenWindow::new(-1.0, 3.0, -2.0, 0.0);
+        let raster_to_screen = create_ndc_to_screen_transformation(&window) * create_raster_to_ndc_transformation(200, 100);
+        let p = Point3::new(0.0, 0.0, 0.0) * raster_to_screen;
+        assert!((p.x + 1.0).abs() < 1e-5 && p.y.abs() < 1e-5);
+        let p = Point3::new(200.0, 100.0, 0.0) * raster_to_screen;
+        assert!((p.x - 3.0).abs() < 1e-5 && (p.y + 2.0).abs() < 1e-5);
+    }
+
+    #[test]
+    fn lens_shift() {
+        let desc = PerspectiveCameraDescriptor { lens_shift: (0.0, 0.25), ..Default::default() };
+        assert_eq!(desc.screen_window(), ScreenWindow::new(-1.0, 1.0, -0.5, 1.5));
+
+        // NOTE: shift moves image center but keeps direction of camera
+        let camera = desc.create().unwrap();
+        let ray = camera.generate_ray(128.0, 128.0);
+        assert!(ray.direction.x.abs() < 1e-5);
+        assert!(ray.direction.y > 0.0);
+    }
+
+    #[test]
+    fn stereo_eyes_converge() {
+        let stereo = StereoSettings { interocular_distance: 0.5, convergence_distance: 4.0, layout: StereoLayout::SideBySide };
+        let desc = PerspectiveCameraDescriptor {
+            resolution: ImageSize::new(512, 256),
+            position: Point3::new(0.0, 0.0, 0.0),
+            look_at: Point3::new(0.0, 0.0, -1.0),
+            stereo: Some(stereo),
+            ..Default::default()
+        };
+        let camera = desc.create().unwrap();
+        let left = camera.generate_ray(128.0, 128.0);
+        let right = camera.generate_ray(384.0, 128.0);
+        assert!(((left.origin.x - right.origin.x).abs() - 0.5).abs() < 1e-4);
+
+        let t = 4.0 / left.direction.z.abs();
+        let p_left = left.point_at(t);
+        let t = 4.0 / right.direction.z.abs();
+        let p_right = right.point_at(t);
+        assert!(p_left.distance(p_right) < 1e-3);
+        assert!((p_left.z + 4.0).abs() < 1e-3);
+    }
+
+    #[test]
+    fn shutter_curves() {
+        let shutter = Shutter { open: 1.0, close: 3.0, curve: ShutterCurve::Box };
+        assert_eq!((shutter.sample(0.0), shutter.sample(0.25), shutter.sample(1.0)), (1.0, 1.5, 3.0));
+
+        let shutter = Shutter { curve: ShutterCurve::Triangle, ..shutter };
+        assert_eq!((shutter.sample(0.0), shutter.sample(0.5), shutter.sample(1.0)), (1.0, 2.0, 3.0));
+        // quarter of samples falls into first quarter of triangle area
+        assert!((shutter.sample(0.125) - 1.5).abs() < 1e-6);
+        assert!((shutter.sample(0.875) - 2.5).abs() < 1e-6);
+
+        let camera = PerspectiveCameraDescriptor { shutter, ..Default::default() }.create().unwrap();
+        assert_eq!(camera.generate_ray_at_time(128.0, 128.0, 0.5).time, 2.0);
+    }
+
+    #[test]
+    fn camera_importance() {
+        let desc = PerspectiveCameraDescriptor {
+            resolution: ImageSize::new(200, 100),
+            position: Point3::new(1.0, 2.0, 3.0),
+            look_at: Point3::new(0.0, 0.0, -1.0),
+            fov: 60.0,
+            ..Default::default()
+        };
+        let camera = desc.create().unwrap();
+        let ray = camera.generate_ray(30.5, 70.25);
+        let (importance, (x, y)) = camera.we(&ray).unwrap();
+        assert!((x - 30.5).abs() < 1e-2 && (y - 70.25).abs() < 1e-2);
+        let (pdf_pos, pdf_dir) = camera.pdf_we(&ray);
+        let cos_theta = ray.direction * camera.forward();
+        assert!((importance * cos_theta - pdf_dir).abs() < 1e-4 * pdf_dir && pdf_pos == 1.0);
+        assert!(camera.we(&Ray::new(ray.origin, -ray.direction)).is_none());
+
+        // directional density integrates to one over the image
+        let mut rng = PCGRng::new(3, 0);
+        let n = 200000;
+        let mut sum = 0.0;
+        for _ in 0..n {
+            let sample = sample_uniform_sphere(rng.rand_f32(), rng.rand_f32());
+            sum += camera.pdf_we(&Ray::new(camera.position(), sample.direction)).1 / sample.pdfw;
+        }
+        assert!((sum / n as f32 - 1.0).abs() < 0.03);
+
+        let point = ray.point_at(5.0);
+        let sample = camera.sample_wi(point).unwrap();
+        assert!((sample.raster.0 - 30.5).abs() < 1e-2 && (sample.raster.1 - 70.25).abs() < 1e-2);
+        assert!((sample.pdfw - 25.0 / cos_theta).abs() < 1e-2);
+    }
 }
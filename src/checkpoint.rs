@@ -0,0 +1,204 @@
+//! Checkpoints of tiled rendering
+//!
+//! Sampler of every pass of tile is initialized only from the tile and the pass, so state of rendering
+//! is number of finished passes of every tile and its accumulation buffers. Checkpoint is written
+//! periodically while tiles are rendered and resumed rendering renders only missing passes, so resumed
+//! image is same as image rendered at once.
+
+use std::collections::BTreeMap;
+use std::error::Error;
+
+use crate::color::{PixelSample, RGB};
+use crate::rgb::ImageSize;
+
+const MAGIC: [u8; 4] = *b"RTCP";
+const VERSION: u32 = 3;
+
+/// File name of checkpoint, minimal number of seconds between writes and whether rendering is resumed
+/// from existing checkpoint.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CheckpointOutput {
+    pub output_fname: String,
+    pub interval: f32,
+    pub resume: bool,
+}
+
+/// Samples of accumulation buffers of tile
+pub type TileSamples = Vec<Vec<PixelSample<RGB>>>;
+
+/// Finished passes of tiles and their accumulation buffers, buffers of tile are radiance, light layers,
+/// light path expressions and bent normals in that order. Fingerprint is hash of scene description
+/// (see SceneDescription::fingerprint).
+#[derive(Debug, Clone)]
+pub struct Checkpoint {
+    size: ImageSize,
+    spp: usize,
+    ntiles: usize,
+    fingerprint: u64,
+    tiles: BTreeMap<usize, (usize, TileSamples)>,
+}
+
+fn read_u32(data: &[u8], pos: &mut usize) -> Result<u32, Box<dyn Error>> {
+    let bytes = data.get(*pos..*pos + 4).ok_or("Checkpoint is truncated!")?;
+    *pos += 4;
+    Ok(u32::from_le_bytes(bytes.try_into()?))
+}
+
+fn read_u64(data: &[u8], pos: &mut usize) -> Result<u64, Box<dyn Error>> {
+    let low = read_u32(data, pos)? as u64;
+    let high = read_u32(data, pos)? as u64;
+    Ok(low | (high << 32))
+}
+
+fn read_f32(data: &[u8], pos: &mut usize) -> Result<f32, Box<dyn Error>> {
+    Ok(f32::from_bits(read_u32(data, pos)?))
+}
+
+impl Checkpoint {
+    pub fn new(size: ImageSize, spp: usize, ntiles: usize, fingerprint: u64) -> Self {
+        Self { size, spp, ntiles, fingerprint, tiles: BTreeMap::new() }
+    }
+
+    /// Checkpoint can be resumed only by rendering of same scene with same resolution, samples and tiles.
+    pub fn is_compatible(&self, size: ImageSize, spp: usize, ntiles: usize, fingerprint: u64) -> bool {
+        (self.size.width, self.size.height, self.spp, self.ntiles, self.fingerprint) ==
+            (size.width, size.height, spp, ntiles, fingerprint)
+    }
+
+    /// Buffers of tile after given number of finished passes, they replace previously stored buffers.
+    pub fn add_tile(&mut self, index: usize, passes: usize, buffers: TileSamples) {
+        self.tiles.insert(index, (passes, buffers));
+    }
+
+    /// Number of finished passes of tile and its buffers
+    pub fn tile(&self, index: usize) -> Option<(usize, &[Vec<PixelSample<RGB>>])> {
+        self.tiles.get(&index).map(|(passes, buffers)| (*passes, buffers.as_slice()))
+    }
+
+    /// Number of tiles with at least one finished pass
+    pub fn stored_tiles(&self) -> usize {
+        self.tiles.len()
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut data = Vec::new();
+        data.extend_from_slice(&MAGIC);
+        for value in [VERSION, self.size.width as u32, self.size.height as u32, self.spp as u32,
+                      self.ntiles as u32] {
+            data.extend_from_slice(&value.to_le_bytes());
+        }
+        data.extend_from_slice(&self.fingerprint.to_le_bytes());
+        data.extend_from_slice(&(self.tiles.len() as u32).to_le_bytes());
+        for (index, (passes, buffers)) in self.tiles.iter() {
+            data.extend_from_slice(&(*index as u32).to_le_bytes());
+            data.extend_from_slice(&(*passes as u32).to_le_bytes());
+            data.extend_from_slice(&(buffers.len() as u32).to_le_bytes());
+            for buffer in buffers.iter() {
+                data.extend_from_slice(&(buffer.len() as u32).to_le_bytes());
+                for sample in buffer.iter() {
+                    let s = sample.spectrum;
+                    for value in [s.r, s.g, s.b, sample.weight] {
+                        data.extend_from_slice(&value.to_le_bytes());
+                    }
+                }
+            }
+        }
+        data
+    }
+
+    pub fn from_bytes(data: &[u8]) -> Result<Self, Box<dyn Error>> {
+        if data.len() < 4 || data[0..4] != MAGIC {
+            return Err("Checkpoint has unknown format!".into())
+        }
+        let mut pos = 4;
+        let version = read_u32(data, &mut pos)?;
+        if version != VERSION {
+            return Err(format!("Unsupported version of checkpoint: {}", version).into())
+        }
+        let width = read_u32(data, &mut pos)? as usize;
+        let height = read_u32(data, &mut pos)? as usize;
+        let spp = read_u32(data, &mut pos)? as usize;
+        let ntiles = read_u32(data, &mut pos)? as usize;
+        let fingerprint = read_u64(data, &mut pos)?;
+        let mut checkpoint = Checkpoint::new(ImageSize::new(width, height), spp, ntiles, fingerprint);
+        for _ in 0..read_u32(data, &mut pos)? {
+            let index = read_u32(data, &mut pos)? as usize;
+            if index >= ntiles {
+                return Err(format!("Checkpoint tile {} is outside of image!", index).into())
+            }
+            let passes = read_u32(data, &mut pos)? as usize;
+            let nbuffers = read_u32(data, &mut pos)?;
+            let mut buffers = Vec::new();
+            for _ in 0..nbuffers {
+                let nsamples = read_u32(data, &mut pos)? as usize;
+                if data.len() < pos + nsamples * 16 {
+                    return Err("Checkpoint is truncated!".into())
+                }
+                let mut buffer = Vec::with_capacity(nsamples);
+                for _ in 0..nsamples {
+                    let r = read_f32(data, &mut pos)?;
+                    let g = read_f32(data, &mut pos)?;
+                    let b = read_f32(data, &mut pos)?;
+                    let weight = read_f32(data, &mut pos)?;
+                    buffer.push(PixelSample { spectrum: RGB::new(r, g, b), weight });
+                }
+                buffers.push(buffer);
+            }
+            checkpoint.add_tile(index, passes, buffers);
+        }
+        Ok(checkpoint)
+    }
+
+    /// Checkpoint is written to temporary file that replaces old checkpoint, so crash during
+    /// writing doesn't destroy previous checkpoint.
+    #[cfg(feature = "fs")]
+    pub fn save(&self, fname: &str) -> Result<(), Box<dyn Error>> {
+        let tmp_fname = format!("{}.tmp", fname);
+        std::fs::write(&tmp_fname, self.to_bytes())?;
+        std::fs::rename(&tmp_fname, fname)?;
+        Ok(())
+    }
+
+    #[cfg(not(feature = "fs"))]
+    pub fn save(&self, fname: &str) -> Result<(), Box<dyn Error>> {
+        Err(format!("Checkpoint {} can't be saved, writing files requires fs feature!", fname).into())
+    }
+
+    #[cfg(feature = "fs")]
+    pub fn load(fname: &str) -> Result<Self, Box<dyn Error>> {
+        Self::from_bytes(&std::fs::read(fname)?)
+    }
+
+    #[cfg(not(feature = "fs"))]
+    pub fn load(fname: &str) -> Result<Self, Box<dyn Error>> {
+        Err(format!("Checkpoint {} can't be loaded, reading files requires fs feature!", fname).into())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn checkpoint_round_trip() {
+        let mut checkpoint = Checkpoint::new(ImageSize::new(40, 20), 16, 2, 0x1234_5678_9abc_def0);
+        let sample = PixelSample { spectrum: RGB::new(0.5, 1.0, 2.0), weight: 3.0 };
+        checkpoint.add_tile(1, 5, vec![vec![sample; 6], Vec::new()]);
+        let data = checkpoint.to_bytes();
+
+        let loaded = Checkpoint::from_bytes(&data).unwrap();
+        assert!(loaded.is_compatible(ImageSize::new(40, 20), 16, 2, 0x1234_5678_9abc_def0));
+        assert!(!loaded.is_compatible(ImageSize::new(40, 20), 32, 2, 0x1234_5678_9abc_def0));
+        assert!(!loaded.is_compatible(ImageSize::new(40, 20), 16, 2, 0x1234_5678));
+        assert_eq!(loaded.stored_tiles(), 1);
+        assert!(loaded.tile(0).is_none());
+        let (passes, buffers) = loaded.tile(1).unwrap();
+        assert_eq!(passes, 5);
+        assert_eq!((buffers.len(), buffers[0].len(), buffers[1].len()), (2, 6, 0));
+        assert_eq!((buffers[0][5].spectrum.b, buffers[0][5].weight), (2.0, 3.0));
+
+        assert!(Checkpoint::from_bytes(&data[..data.len() - 4]).is_err());
+        assert!(Checkpoint::from_bytes(b"RTCK").is_err());
+    }
+}
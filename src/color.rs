@@ -1,5 +1,9 @@
+use std::hash::{Hash, Hasher};
 use std::ops::{Add, AddAssign, Mul};
 
+use half::f16;
+use rayon::prelude::*;
+
 use crate::rgb::ImageSize;
 use crate::tile::Tile;
 use crate::rgb::{RGB8uffer, RGB8};
@@ -19,6 +23,13 @@ impl RGB {
     pub fn zero() -> Self {
         Self { r: 0.0, g: 0.0, b: 0.0 }
     }
+
+    pub fn luminance(&self) -> f32 {
+        0.2126 * self.r + 0.7152 * self.g + 0.0722 * self.b
+    }
+    pub fn is_black(&self) -> bool {
+        self.r <= 0.0 && self.g <= 0.0 && self.b <= 0.0
+    }
 }
 
 impl Mul<f32> for RGB {
@@ -82,6 +93,67 @@ impl From<RGB> for RGB8 {
 }
 
 
+/// RGB color stored as half floats, arithmetic is done in f32.
+#[derive(Debug, Copy, Clone)]
+pub struct RGBHalf {
+    pub r: f16,
+    pub g: f16,
+    pub b: f16,
+}
+
+impl From<RGB> for RGBHalf {
+    fn from(rgb: RGB) -> Self {
+        Self { r: f16::from_f32(rgb.r), g: f16::from_f32(rgb.g), b: f16::from_f32(rgb.b) }
+    }
+}
+
+impl From<RGBHalf> for RGB {
+    fn from(rgb: RGBHalf) -> Self {
+        RGB::new(rgb.r.to_f32(), rgb.g.to_f32(), rgb.b.to_f32())
+    }
+}
+
+impl Default for RGBHalf {
+    fn default() -> Self {
+        RGBHalf::from(RGB::zero())
+    }
+}
+
+/// Weighted average of samples stored in half floats, it takes 3/4 of the memory of PixelSample<RGB>.
+/// Average is stored instead of sum so it doesn't saturate at high sample counts, merging
+/// is done in f32 and result is quantized only for storage. Weight is kept in f32, in f16
+/// unit weight samples would be rounded away once the weight reaches 2048.
+#[derive(Debug, Copy, Clone, Default)]
+pub struct HalfPixelSample {
+    pub mean: RGBHalf,
+    pub weight: f32,
+}
+
+impl HalfPixelSample {
+    #[inline(always)]
+    pub fn merge(&mut self, spectrum: RGB, weight: f32) {
+        let total = self.weight + weight;
+        if total == 0.0 {
+            return;
+        }
+        self.mean = RGBHalf::from((RGB::from(self.mean) * self.weight + spectrum) * total.recip());
+        self.weight = total;
+    }
+}
+
+impl From<HalfPixelSample> for PixelSample<RGB> {
+    fn from(sample: HalfPixelSample) -> Self {
+        PixelSample{spectrum: RGB::from(sample.mean) * sample.weight, weight: sample.weight}
+    }
+}
+
+impl From<HalfPixelSample> for RGB {
+    fn from(sample: HalfPixelSample) -> Self {
+        RGB::from(sample.mean)
+    }
+}
+
+
 #[derive(Debug, Copy, Clone)]
 pub struct RGBPixelSample {
     pub spectrum: RGB,
@@ -154,6 +226,12 @@ impl<T: Mul<f32, Output = T> + Into<RGB>> From<PixelSample<T>> for RGB {
     }
 }
 
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BufferPrecision {
+    Full,
+    Half,
+}
+
 pub enum TMOType {
     Linear,
     Gamma,
@@ -161,7 +239,7 @@ pub enum TMOType {
 }
 
 // http://filmicworlds.com/blog/filmic-tonemapping-operators/
-fn tone_map(tmo_type: &TMOType, spec: &RGB) -> RGB {
+pub fn tone_map(tmo_type: &TMOType, spec: &RGB) -> RGB {
     const INV_GAMMA: f32 = 1.0/2.2;
 
     fn gamma_correct(value: f32) -> f32 {
@@ -182,42 +260,121 @@ fn tone_map(tmo_type: &TMOType, spec: &RGB) -> RGB {
     }
 }
 
+/// Encoding of stored texture values. Color maps are usually authored in sRGB, while data maps
+/// (roughness, metallic) are linear and must not be decoded.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ColorEncoding {
+    Linear,
+    SRGB,
+    /// Stored value is linear value raised to 1 / gamma
+    Gamma(f32),
+}
+
+// NOTE: encodings are keys of cache of texture images, gamma is never NaN
+impl Eq for ColorEncoding {}
+
+impl Hash for ColorEncoding {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        std::mem::discriminant(self).hash(state);
+        if let ColorEncoding::Gamma(gamma) = self {
+            gamma.to_bits().hash(state);
+        }
+    }
+}
+
+impl ColorEncoding {
+    /// Encoding given by name as in pbrt-v4 scenes: "linear", "sRGB" or "gamma <value>"
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.split_once(' ') {
+            Some(("gamma", gamma)) => gamma.trim().parse().ok().filter(|gamma: &f32| *gamma > 0.0).map(ColorEncoding::Gamma),
+            _ => match name {
+                "linear" => Some(ColorEncoding::Linear),
+                "sRGB" | "srgb" => Some(ColorEncoding::SRGB),
+                _ => None
+            }
+        }
+    }
+
+    /// Linear value of stored value in [0, 1]
+    pub fn to_linear(&self, value: f32) -> f32 {
+        match self {
+            ColorEncoding::Linear => value,
+            ColorEncoding::SRGB => {
+                if value <= 0.04045 {
+                    value / 12.92
+                } else {
+                    ((value + 0.055) / 1.055).powf(2.4)
+                }
+            }
+            ColorEncoding::Gamma(gamma) => value.max(0.0).powf(*gamma),
+        }
+    }
+
+    /// Linear value of 8-bit stored value
+    pub fn to_linear_u8(&self, value: u8) -> f32 {
+        self.to_linear(value as f32 / 255.0)
+    }
+}
+
+// Pixels are stored in square blocks of BLOCK_SIZE x BLOCK_SIZE pixels, blocks are in row-major order.
+// Filter splats and tile merges touch neighbouring pixels so this keeps them in the same cache lines.
+const BLOCK_SHIFT: usize = 3;
+const BLOCK_SIZE: usize = 1 << BLOCK_SHIFT;
+const BLOCK_MASK: usize = BLOCK_SIZE - 1;
 
 pub struct AccumlationBuffer<PixelSample> {
     size: ImageSize,
+    xblocks: usize,
     buffer: Vec<PixelSample>,
 }
 
-impl<T: Default + Clone + Copy + AddAssign + Into<RGB> + Mul<f32, Output = T>> AccumlationBuffer<PixelSample<T>> {
+impl<P: Default + Clone + Copy> AccumlationBuffer<P> {
     pub fn new(size: ImageSize) -> Self {
-        let buffer = vec![PixelSample::default(); size.width * size.height];
-        Self { size, buffer}
+        let xblocks = size.width.div_ceil(BLOCK_SIZE);
+        let yblocks = size.height.div_ceil(BLOCK_SIZE);
+        let buffer = vec![P::default(); xblocks * yblocks * BLOCK_SIZE * BLOCK_SIZE];
+        Self { size, xblocks, buffer}
     }
 
-    pub fn add(&mut self, x: usize, y: usize, value: &T) {
-        let index = y * self.size.width + x;
-        let sample = PixelSample{spectrum: *value, weight: 1.0};
-        self.buffer[index] += sample;
+    #[inline(always)]
+    fn index(&self, x: usize, y: usize) -> usize {
+        let block = (y >> BLOCK_SHIFT) * self.xblocks + (x >> BLOCK_SHIFT);
+        (block << (2 * BLOCK_SHIFT)) + ((y & BLOCK_MASK) << BLOCK_SHIFT) + (x & BLOCK_MASK)
     }
 
-    pub fn set(&mut self, x: usize, y: usize, value: &T) {
-        let index = y * self.size.width + x;
-        let sample = PixelSample{spectrum: *value, weight: 1.0};
-        self.buffer[index] = sample;
+    pub fn get(&self, x: usize, y: usize) -> Option<&P> {
+        if x >= self.size.width || y >= self.size.height {
+            return None;
+        }
+        self.buffer.get(self.index(x, y))
     }
 
-    pub fn get(&self, x: usize, y: usize) -> Option<&PixelSample<T>> {
-        let index = y * self.size.width + x;
-        self.buffer.get(index)
+    pub fn size(&self) -> ImageSize {
+        self.size
     }
 
-    pub fn to_rgb8_buffer(&self, tmo_type: &TMOType) -> RGB8uffer {
-        let vals: Vec<RGB8> = self.buffer.iter().map(
-            |sample| tone_map(tmo_type,&(*sample).into()).into()).collect();
+    /// Weighted average of every pixel, pixels are in row-major order.
+    pub fn resolve(&self) -> Vec<RGB>
+    where P: Into<RGB> {
+        (0..self.size.height).flat_map(|y| {
+            (0..self.size.width).map(move |x| self.buffer[self.index(x, y)].into())
+        }).collect()
+    }
+
+    /// Tone mapping and quantization of rows is done in parallel.
+    pub fn to_rgb8_buffer(&self, tmo_type: &TMOType) -> RGB8uffer
+    where P: Into<RGB> + Send + Sync {
+        let vals: Vec<RGB8> = (0..self.size.height).into_par_iter().flat_map_iter(|y| {
+            (0..self.size.width).map(move |x| {
+                let sample = self.buffer[self.index(x, y)];
+                tone_map(tmo_type, &sample.into()).into()
+            })
+        }).collect();
         RGB8uffer::from((self.size.width, vals))
     }
 
-    pub fn add_accumulation_tile_buffer(&mut self, tile_buffer: &AccumlationTileBuffer<PixelSample<T>>) {
+    // Every pixel of tile buffer (with padding of filter) is merged into pixel of this buffer.
+    fn merge_tile_buffer<U: Copy>(&mut self, tile_buffer: &AccumlationTileBuffer<U>, merge: impl Fn(&mut P, U)) {
         let tile = tile_buffer.tile;
         let padding = tile_buffer.padding;
         let left = (tile.x1 as i32 - padding).max(0) as usize;
@@ -230,8 +387,8 @@ impl<T: Default + Clone + Copy + AddAssign + Into<RGB> + Mul<f32, Output = T>> A
         for y in top..bottom {
             for x in left..right {
                 let src_index = curx + cury * tile_buffer.width;
-                let dst_index = x + y * self.size.width;
-                self.buffer[dst_index] += tile_buffer.buffer[src_index];
+                let dst_index = self.index(x, y);
+                merge(&mut self.buffer[dst_index], tile_buffer.buffer[src_index]);
                 curx += 1;
             }
             curx = 0;
@@ -240,6 +397,151 @@ impl<T: Default + Clone + Copy + AddAssign + Into<RGB> + Mul<f32, Output = T>> A
     }
 }
 
+impl<T: Default + Clone + Copy + AddAssign + Into<RGB> + Mul<f32, Output = T>> AccumlationBuffer<PixelSample<T>> {
+    pub fn add(&mut self, x: usize, y: usize, value: &T) {
+        let index = self.index(x, y);
+        let sample = PixelSample{spectrum: *value, weight: 1.0};
+        self.buffer[index] += sample;
+    }
+
+    /// Add value that stands for weight samples (e.g. pixel of previous render with weight spp).
+    pub fn add_weighted(&mut self, x: usize, y: usize, value: &T, weight: f32) {
+        let index = self.index(x, y);
+        let sample = PixelSample{spectrum: *value * weight, weight};
+        self.buffer[index] += sample;
+    }
+
+    pub fn set(&mut self, x: usize, y: usize, value: &T) {
+        let index = self.index(x, y);
+        let sample = PixelSample{spectrum: *value, weight: 1.0};
+        self.buffer[index] = sample;
+    }
+
+    /// Add samples of smaller buffer whose upper left corner is at pixel (x, y) of this buffer.
+    pub fn add_buffer(&mut self, other: &Self, x: usize, y: usize) {
+        for oy in 0..other.size.height {
+            for ox in 0..other.size.width {
+                let dst_index = self.index(x + ox, y + oy);
+                self.buffer[dst_index] += other.buffer[other.index(ox, oy)];
+            }
+        }
+    }
+
+    pub fn add_accumulation_tile_buffer<U: Copy>(&mut self, tile_buffer: &AccumlationTileBuffer<PixelSample<U>>)
+    where T: From<U> {
+        self.merge_tile_buffer(tile_buffer, |dst, sample| {
+            *dst += PixelSample{spectrum: T::from(sample.spectrum), weight: sample.weight};
+        });
+    }
+}
+
+impl AccumlationBuffer<HalfPixelSample> {
+    pub fn add_weighted(&mut self, x: usize, y: usize, value: &RGB, weight: f32) {
+        let index = self.index(x, y);
+        self.buffer[index].merge(*value * weight, weight);
+    }
+
+    /// Tile is accumulated in f32, sums of its pixels are merged into stored averages.
+    pub fn add_accumulation_tile_buffer(&mut self, tile_buffer: &AccumlationTileBuffer<PixelSample<RGB>>) {
+        self.merge_tile_buffer(tile_buffer, |dst, sample| dst.merge(sample.spectrum, sample.weight));
+    }
+}
+
+/// Accumulation buffer of RGB values with selectable storage precision.
+pub enum RGBAccumlationBuffer {
+    Full(AccumlationBuffer<PixelSample<RGB>>),
+    Half(AccumlationBuffer<HalfPixelSample>),
+}
+
+impl RGBAccumlationBuffer {
+    pub fn new(size: ImageSize, precision: BufferPrecision) -> Self {
+        match precision {
+            BufferPrecision::Full => RGBAccumlationBuffer::Full(AccumlationBuffer::new(size)),
+            BufferPrecision::Half => RGBAccumlationBuffer::Half(AccumlationBuffer::new(size)),
+        }
+    }
+
+    pub fn add(&mut self, x: usize, y: usize, value: &RGB) {
+        match self {
+            RGBAccumlationBuffer::Full(buffer) => buffer.add(x, y, value),
+            RGBAccumlationBuffer::Half(buffer) => buffer.add_weighted(x, y, value, 1.0),
+        }
+    }
+
+    /// Add every pixel of image of same size (row-major order) as weight samples, see add_weighted.
+    pub fn add_image(&mut self, pixels: &[RGB], weight: f32) {
+        let width = self.size().width;
+        for (index, rgb) in pixels.iter().enumerate().take(width * self.size().height) {
+            let (x, y) = (index % width, index / width);
+            match self {
+                RGBAccumlationBuffer::Full(buffer) => buffer.add_weighted(x, y, rgb, weight),
+                RGBAccumlationBuffer::Half(buffer) => buffer.add_weighted(x, y, rgb, weight),
+            }
+        }
+    }
+
+    /// Add samples of smaller buffer whose upper left corner is at pixel (x, y) of this buffer.
+    pub fn add_buffer(&mut self, other: &RGBAccumlationBuffer, x: usize, y: usize) {
+        if let (RGBAccumlationBuffer::Full(buffer), RGBAccumlationBuffer::Full(other)) = (&mut *self, other) {
+            return buffer.add_buffer(other, x, y);
+        }
+        let size = other.size();
+        for oy in 0..size.height {
+            for ox in 0..size.width {
+                let sample = match other.get(ox, oy) {
+                    Some(sample) => sample,
+                    None => continue
+                };
+                match self {
+                    RGBAccumlationBuffer::Full(buffer) => {
+                        let index = buffer.index(x + ox, y + oy);
+                        buffer.buffer[index] += sample;
+                    }
+                    RGBAccumlationBuffer::Half(buffer) => {
+                        let index = buffer.index(x + ox, y + oy);
+                        buffer.buffer[index].merge(sample.spectrum, sample.weight);
+                    }
+                }
+            }
+        }
+    }
+
+    pub fn get(&self, x: usize, y: usize) -> Option<PixelSample<RGB>> {
+        match self {
+            RGBAccumlationBuffer::Full(buffer) => buffer.get(x, y).copied(),
+            RGBAccumlationBuffer::Half(buffer) => buffer.get(x, y).map(|sample| PixelSample::from(*sample)),
+        }
+    }
+
+    pub fn add_accumulation_tile_buffer(&mut self, tile_buffer: &AccumlationTileBuffer<PixelSample<RGB>>) {
+        match self {
+            RGBAccumlationBuffer::Full(buffer) => buffer.add_accumulation_tile_buffer(tile_buffer),
+            RGBAccumlationBuffer::Half(buffer) => buffer.add_accumulation_tile_buffer(tile_buffer),
+        }
+    }
+
+    pub fn size(&self) -> ImageSize {
+        match self {
+            RGBAccumlationBuffer::Full(buffer) => buffer.size(),
+            RGBAccumlationBuffer::Half(buffer) => buffer.size(),
+        }
+    }
+
+    pub fn resolve(&self) -> Vec<RGB> {
+        match self {
+            RGBAccumlationBuffer::Full(buffer) => buffer.resolve(),
+            RGBAccumlationBuffer::Half(buffer) => buffer.resolve(),
+        }
+    }
+
+    pub fn to_rgb8_buffer(&self, tmo_type: &TMOType) -> RGB8uffer {
+        match self {
+            RGBAccumlationBuffer::Full(buffer) => buffer.to_rgb8_buffer(tmo_type),
+            RGBAccumlationBuffer::Half(buffer) => buffer.to_rgb8_buffer(tmo_type),
+        }
+    }
+}
+
 pub struct AccumlationTileBuffer<PixelSample> {
     tile: Tile,
     width: usize,
@@ -254,7 +556,7 @@ impl<T: Default + Clone + Copy + AddAssign + Into<RGB> + Mul<f32, Output = T>> A
     pub fn new(tile: Tile, filter_radius: Option<f32>, max_width: usize, max_height: usize) -> Self {
         match filter_radius {
             Some(radius) => {
-                let padding = (0.5 + radius) as i32;
+                let padding = Self::padding(filter_radius) as i32;
                 let left = (tile.x1 as i32 - padding).max(0) as usize;
                 let right = (tile.x2 as i32 + padding).min(max_width as i32) as usize;
                 let top = (tile.y1 as i32 - padding).max(0) as usize;
@@ -273,6 +575,32 @@ impl<T: Default + Clone + Copy + AddAssign + Into<RGB> + Mul<f32, Output = T>> A
         }
     }
 
+    /// Pixels around the tile that samples of the tile are splatted into by filter of given radius
+    pub fn padding(filter_radius: Option<f32>) -> usize {
+        filter_radius.map_or(0, |radius| (0.5 + radius) as usize)
+    }
+
+    // Upper left corner of the buffer (tile with padding) in the image
+    fn origin(&self) -> (usize, usize) {
+        ((self.tile.x1 as i32 - self.padding).max(0) as usize, (self.tile.y1 as i32 - self.padding).max(0) as usize)
+    }
+
+    /// Samples of other tile buffer that overlap this buffer are added to it, so samples that neighbouring
+    /// tile splatted into this tile (or its padding) are merged without buffer of whole image.
+    pub fn add_tile_buffer(&mut self, other: &Self) {
+        let (left, top) = self.origin();
+        let (other_left, other_top) = other.origin();
+        let (x1, y1) = (left.max(other_left), top.max(other_top));
+        let x2 = (left + self.width).min(other_left + other.width);
+        let y2 = (top + self.height).min(other_top + other.height);
+        for y in y1..y2 {
+            for x in x1..x2 {
+                let sample = other.buffer[(y - other_top) * other.width + x - other_left];
+                self.buffer[(y - top) * self.width + x - left] += sample;
+            }
+        }
+    }
+
     pub fn add(&mut self, ix: usize, iy: usize, x: f32, y: f32, value: &T,
                calculate_weight_fn: &dyn Fn(f32, f32) -> f32) {
         
@@ -289,9 +617,9 @@ impl<T: Default + Clone + Copy + AddAssign + Into<RGB> + Mul<f32, Output = T>> A
             }
         };
 
-        // Convert to local tile coordinates
-        let local_x = x - self.tile.x1 as f32;
-        let local_y = y - self.tile.y1 as f32;
+        // Convert to local coordinates of the buffer, buffer starts at padding before the tile
+        let (left, top) = self.origin();
+        let (local_x, local_y) = (x - left as f32, y - top as f32);
 
         // Calculate pixel extent for the filter
         let x_min = ((local_x - radius).floor() as i32).max(0);
@@ -314,4 +642,292 @@ impl<T: Default + Clone + Copy + AddAssign + Into<RGB> + Mul<f32, Output = T>> A
             }
         }
     }
+
+    /// Weighted average of pixels of the tile (without padding), pixels are in row-major order.
+    /// Samples that neighbouring tiles splatted into this tile are not included.
+    pub fn resolve(&self) -> Vec<RGB> {
+        let (left, top) = self.origin();
+        let (left, top) = (self.tile.x1 - left, self.tile.y1 - top);
+        (0..self.tile.height()).flat_map(|y| {
+            (0..self.tile.width()).map(move |x| RGB::from(self.buffer[(y + top) * self.width + x + left]))
+        }).collect()
+    }
+
+    /// Samples of the tile including padding, they are in row-major order.
+    pub fn samples(&self) -> &[PixelSample<T>] {
+        &self.buffer
+    }
+
+    pub fn samples_mut(&mut self) -> &mut [PixelSample<T>] {
+        &mut self.buffer
+    }
+}
+
+/// Auxiliary output that is rendered next to beauty image.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AovType {
+    GeometricNormal,
+    ShadingNormal,
+    Depth,
+    Albedo,
+    MaterialId
+}
+
+impl AovType {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "normal" | "geometricnormal" => Some(AovType::GeometricNormal),
+            "shadingnormal" => Some(AovType::ShadingNormal),
+            "depth" => Some(AovType::Depth),
+            "albedo" => Some(AovType::Albedo),
+            "materialid" => Some(AovType::MaterialId),
+            _ => None
+        }
+    }
+}
+
+/// Values of auxiliary outputs at first hit of camera ray.
+pub struct AovSample {
+    pub geometric_normal: RGB,
+    pub shading_normal: RGB,
+    pub depth: f32,
+    pub albedo: RGB,
+    pub material_id: u32
+}
+
+/// Accumulation buffers of auxiliary outputs, samples of rays that missed geometry are not
+/// added so background has zero weight.
+pub struct AovBuffers {
+    types: Vec<AovType>,
+    buffers: Vec<RGBAccumlationBuffer>
+}
+
+impl AovBuffers {
+    pub fn new(size: ImageSize, types: &[AovType], precision: BufferPrecision) -> Self {
+        let buffers = types.iter().map(|_| RGBAccumlationBuffer::new(size, precision)).collect();
+        Self { types: types.to_vec(), buffers }
+    }
+
+    pub fn types(&self) -> &[AovType] {
+        &self.types
+    }
+
+    pub fn add(&mut self, x: usize, y: usize, sample: &AovSample) {
+        for (typ, buffer) in self.types.iter().zip(self.buffers.iter_mut()) {
+            let value = match typ {
+                AovType::GeometricNormal => sample.geometric_normal,
+                AovType::ShadingNormal => sample.shading_normal,
+                AovType::Depth => RGB::new(sample.depth, sample.depth, sample.depth),
+                AovType::Albedo => sample.albedo,
+                AovType::MaterialId => material_id_color(sample.material_id)
+            };
+            buffer.add(x, y, &value);
+        }
+    }
+
+    /// Add buffers of tile, they have size of the tile.
+    pub fn add_tile(&mut self, tile_aovs: &AovBuffers, tile: &Tile) {
+        for (buffer, tile_buffer) in self.buffers.iter_mut().zip(tile_aovs.buffers.iter()) {
+            buffer.add_buffer(tile_buffer, tile.x1, tile.y1);
+        }
+    }
+
+    pub fn get(&self, index: usize, x: usize, y: usize) -> Option<RGB> {
+        match self.buffers[index].get(x, y) {
+            Some(sample) if sample.weight > 0.0 => Some(sample.spectrum * sample.weight.recip()),
+            _ => None
+        }
+    }
+
+    /// Depth of every pixel in row-major order when depth is accumulated, pixels without hits have infinite depth.
+    pub fn depth(&self) -> Option<Vec<f32>> {
+        let index = self.types.iter().position(|typ| *typ == AovType::Depth)?;
+        let size = self.buffers[index].size();
+        Some((0..size.height).flat_map(|y| (0..size.width).map(move |x| (x, y)))
+            .map(|(x, y)| self.get(index, x, y).map_or(f32::INFINITY, |depth| depth.r)).collect())
+    }
+
+    /// Image of auxiliary output, normals are stored as n * 0.5 + 0.5 and depth is divided
+    /// by largest depth in the image.
+    pub fn to_rgb8_buffer(&self, index: usize) -> RGB8uffer {
+        let size = self.buffers[index].size();
+        let max_depth = (0..size.height).flat_map(|y| (0..size.width).map(move |x| (x, y)))
+            .filter_map(|(x, y)| self.get(index, x, y)).fold(0.0f32, |acc, v| acc.max(v.r));
+        let mut image = RGB8uffer::new(size);
+        for y in 0..size.height {
+            for x in 0..size.width {
+                let value = match self.get(index, x, y) {
+                    Some(value) => value,
+                    None => continue
+                };
+                let rgb = match self.types[index] {
+                    AovType::GeometricNormal | AovType::ShadingNormal => {
+                        let len = (value.r * value.r + value.g * value.g + value.b * value.b).sqrt().max(1e-8);
+                        RGB::new(value.r / len * 0.5 + 0.5, value.g / len * 0.5 + 0.5, value.b / len * 0.5 + 0.5)
+                    }
+                    AovType::Depth if max_depth > 0.0 => value * max_depth.recip(),
+                    _ => value
+                };
+                image.set(x, y, &rgb.into());
+            }
+        }
+        image
+    }
+}
+
+// Every material id gets its own color so neighbouring materials can be distinguished
+fn material_id_color(id: u32) -> RGB {
+    let h = crate::hash::hash64(id as u64 + 1);
+    RGB::new((h & 0xff) as f32 / 255.0, ((h >> 8) & 0xff) as f32 / 255.0, ((h >> 16) & 0xff) as f32 / 255.0)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Instant;
+
+    #[test]
+    fn color_encoding() {
+        assert_eq!(ColorEncoding::Linear.to_linear_u8(51), 0.2);
+        assert_eq!(ColorEncoding::SRGB.to_linear(0.0), 0.0);
+        assert!((ColorEncoding::SRGB.to_linear(1.0) - 1.0).abs() < 1e-6);
+        assert!((ColorEncoding::SRGB.to_linear_u8(128) - 0.2158).abs() < 1e-3);
+        assert_eq!(ColorEncoding::from_name("sRGB"), Some(ColorEncoding::SRGB));
+        assert!(ColorEncoding::from_name("gamma").is_none());
+        assert_eq!(ColorEncoding::from_name("gamma 2.2"), Some(ColorEncoding::Gamma(2.2)));
+        assert!((ColorEncoding::Gamma(2.0).to_linear(0.5) - 0.25).abs() < 1e-6);
+    }
+
+    #[test]
+    fn accumulation_buffer_layout() {
+        let size = ImageSize::new(13, 21);
+        let mut accum = AccumlationBuffer::<PixelSample<RGB>>::new(size);
+        for y in 0..size.height {
+            for x in 0..size.width {
+                accum.set(x, y, &RGB::new(x as f32, y as f32, 0.0));
+            }
+        }
+        accum.add(12, 20, &RGB::new(1.0, 1.0, 1.0));
+        for y in 0..size.height {
+            for x in 0..size.width {
+                let sample = accum.get(x, y).unwrap();
+                if x == 12 && y == 20 {
+                    assert_eq!(sample.weight, 2.0);
+                    assert_eq!(sample.spectrum.r, 13.0);
+                } else {
+                    assert_eq!(sample.weight, 1.0);
+                    assert_eq!(sample.spectrum.r, x as f32);
+                    assert_eq!(sample.spectrum.g, y as f32);
+                }
+            }
+        }
+        assert!(accum.get(13, 0).is_none());
+        assert!(accum.get(0, 21).is_none());
+    }
+
+    #[test]
+    fn accumulation_buffer_to_rgb8() {
+        let size = ImageSize::new(10, 3);
+        let mut accum = AccumlationBuffer::<PixelSample<RGB>>::new(size);
+        accum.set(9, 2, &RGB::new(0.5, 0.0, 0.0));
+        let image = accum.to_rgb8_buffer(&TMOType::Linear);
+        assert_eq!(image.get(9, 2).unwrap().red, 128);
+        assert_eq!(image.get(8, 2).unwrap().red, 0);
+    }
+
+    #[test]
+    fn half_accumulation_buffer() {
+        let size = ImageSize::new(4, 4);
+        let mut accum = RGBAccumlationBuffer::new(size, BufferPrecision::Half);
+        accum.add(1, 2, &RGB::new(0.25, 0.5, 1.0));
+        accum.add(1, 2, &RGB::new(0.25, 0.5, 1.0));
+        let sample = accum.get(1, 2).unwrap();
+        assert_eq!(sample.weight, 2.0);
+        assert_eq!(sample.spectrum.r, 0.5);
+        assert_eq!(sample.spectrum.g, 1.0);
+        assert_eq!(sample.spectrum.b, 2.0);
+
+        let tile = Tile::new(0, 0, 4, 4);
+        let mut tile_buffer = AccumlationTileBuffer::<PixelSample<RGB>>::new(tile, None, 4, 4);
+        tile_buffer.add(3, 3, 3.5, 3.5, &RGB::new(1.0, 1.0, 1.0), &|_x, _y| 1.0);
+        accum.add_accumulation_tile_buffer(&tile_buffer);
+        assert_eq!(accum.get(3, 3).unwrap().spectrum.g, 1.0);
+
+        // average doesn't saturate at high sample counts
+        for _ in 0..20000 {
+            accum.add(0, 0, &RGB::new(0.75, 2.0, 0.0));
+        }
+        let rgb = accum.resolve()[0];
+        assert!((rgb.r - 0.75).abs() < 1e-2 && (rgb.g - 2.0).abs() < 2e-2);
+        // weight still grows after 2048 unit weight samples
+        let mut sample = HalfPixelSample::default();
+        for _ in 0..4096 {
+            sample.merge(RGB::new(0.75, 0.0, 0.0), 1.0);
+        }
+        sample.merge(RGB::new(0.25, 0.0, 0.0) * 4096.0, 4096.0);
+        assert_eq!(sample.weight, 8192.0);
+        assert!((RGB::from(sample).r - 0.5).abs() < 1e-3);
+        assert_eq!(std::mem::size_of::<HalfPixelSample>() * 4, std::mem::size_of::<PixelSample<RGB>>() * 3);
+    }
+
+    #[test]
+    fn filtered_tile_buffer() {
+        // sample in the middle of pixel (5, 6) of tile that doesn't start at the image border
+        let tile = Tile::new(4, 4, 8, 8);
+        let mut tile_buffer = AccumlationTileBuffer::<PixelSample<RGB>>::new(tile, Some(1.5), 16, 16);
+        let weight_fn = |x: f32, y: f32| (1.5 - x.abs()).max(0.0) * (1.5 - y.abs()).max(0.0);
+        tile_buffer.add(5, 6, 5.5, 6.5, &RGB::new(1.0, 1.0, 1.0), &weight_fn);
+        let mut accum = AccumlationBuffer::<PixelSample<RGB>>::new(ImageSize::new(16, 16));
+        accum.add_accumulation_tile_buffer(&tile_buffer);
+        assert_eq!(accum.get(5, 6).unwrap().weight, 2.25);
+        assert_eq!(accum.get(4, 6).unwrap().weight, 0.75);
+        assert_eq!(accum.get(3, 6).unwrap().weight, 0.0);
+    }
+
+    #[test]
+    fn merge_neighbouring_tile_buffers() {
+        let weight_fn = |x: f32, y: f32| (1.5 - x.abs()).max(0.0) * (1.5 - y.abs()).max(0.0);
+        let mut left = AccumlationTileBuffer::<PixelSample<RGB>>::new(Tile::new(0, 0, 4, 4), Some(1.5), 8, 4);
+        let mut right = AccumlationTileBuffer::<PixelSample<RGB>>::new(Tile::new(4, 0, 8, 4), Some(1.5), 8, 4);
+        left.add(3, 1, 3.5, 1.5, &RGB::new(1.0, 1.0, 1.0), &weight_fn);
+        right.add(4, 1, 4.5, 1.5, &RGB::new(1.0, 1.0, 1.0), &weight_fn);
+        left.add_tile_buffer(&right);
+        let mut accum = AccumlationBuffer::<PixelSample<RGB>>::new(ImageSize::new(8, 4));
+        accum.add_accumulation_tile_buffer(&left);
+        assert_eq!(accum.get(3, 1).unwrap().weight, 3.0);
+        assert_eq!(accum.get(2, 1).unwrap().weight, 0.75);
+        // padding of the left tile gets also splats of the right tile
+        assert_eq!(accum.get(4, 1).unwrap().weight, 3.0);
+        assert_eq!(accum.get(5, 1).unwrap().weight, 0.75);
+    }
+
+    // Constant radiance splatted by tiles stays constant after merge, also across block boundaries
+    fn splat_constant_image(resolution: usize, tile_size: usize) -> AccumlationBuffer<PixelSample<RGB>> {
+        let mut accum = AccumlationBuffer::<PixelSample<RGB>>::new(ImageSize::new(resolution, resolution));
+        let tile = Tile::new(0, 0, resolution, resolution);
+        let weight_fn = |x: f32, y: f32| (2.0 - x.abs()).max(0.0) * (2.0 - y.abs()).max(0.0);
+        for sub_tile in tile.split(tile_size, tile_size) {
+            let mut tile_buffer = AccumlationTileBuffer::<PixelSample<RGB>>::new(sub_tile, Some(2.0), resolution, resolution);
+            for (x, y) in sub_tile {
+                tile_buffer.add(x, y, x as f32 + 0.5, y as f32 + 0.5, &RGB::new(1.0, 1.0, 1.0), &weight_fn);
+            }
+            accum.add_accumulation_tile_buffer(&tile_buffer);
+        }
+        accum
+    }
+
+    #[test]
+    fn accumulation_buffer_splat() {
+        let accum = splat_constant_image(37, 10);
+        assert!(accum.resolve().iter().all(|rgb| (rgb.r - 1.0).abs() < 1e-5 && (rgb.b - 1.0).abs() < 1e-5));
+    }
+
+    #[test]
+    #[ignore = "timing of splatting, run with --ignored --nocapture"]
+    fn accumulation_buffer_splat_timing() {
+        let start = Instant::now();
+        splat_constant_image(1024, 64);
+        println!("Splat and merge of 1024x1024 image: {:.2?}", start.elapsed());
+    }
 }
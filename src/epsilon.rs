@@ -0,0 +1,49 @@
+//! Distances used to reject self intersections of rays spawned from surfaces.
+
+/// Distance larger than any hit distance, used as upper bound of ray segments and as "no hit" sentinel.
+pub const INFINITE_DISTANCE: f32 = 1e38;
+
+/// Minimal hit distances of the scene. Floating point error of hit points grows with magnitude
+/// of coordinates, so minimal hit distance is scaled by extent of the scene.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct EpsilonPolicy {
+    /// Minimal hit distance as fraction of scene extent
+    pub relative_tmin: f32,
+    /// Minimal hit distance regardless of scene extent
+    pub absolute_tmin: f32,
+    /// Fraction of distance to light point that shadow ray doesn't test, so that light geometry
+    /// doesn't occlude itself
+    pub shadow_epsilon: f32,
+}
+
+impl Default for EpsilonPolicy {
+    fn default() -> Self {
+        Self { relative_tmin: 1e-7, absolute_tmin: 1e-6, shadow_epsilon: 1e-4 }
+    }
+}
+
+impl EpsilonPolicy {
+    /// Minimal hit distance for scene where largest absolute coordinate is scene_extent.
+    pub fn tmin(&self, scene_extent: f32) -> f32 {
+        self.absolute_tmin.max(self.relative_tmin * scene_extent)
+    }
+
+    /// Maximal hit distance of shadow ray that tests visibility of point at given distance.
+    pub fn shadow_tmax(&self, distance: f32) -> f32 {
+        distance * (1.0 - self.shadow_epsilon)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn scale_aware_tmin() {
+        let policy = EpsilonPolicy::default();
+        assert_eq!(policy.tmin(1.0), policy.absolute_tmin);
+        assert_eq!(policy.tmin(1e5), 1e5 * policy.relative_tmin);
+        assert!(policy.shadow_tmax(10.0) < 10.0 && policy.shadow_tmax(10.0) > 9.99);
+    }
+}
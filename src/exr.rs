@@ -0,0 +1,169 @@
+//! Writing of tiled OpenEXR images
+//!
+//! Tiles are stored uncompressed with 32-bit float channels, so size of every tile is known in
+//! advance and offset table can be written before any tile. Tiles can then be written in any
+//! order as soon as they are finished and the whole image never has to be kept in memory.
+
+use std::error::Error;
+use std::io::{Seek, SeekFrom, Write};
+
+use crate::color::RGB;
+use crate::rgb::ImageSize;
+use crate::tile::Tile;
+
+const MAGIC: [u8; 4] = [0x76, 0x2f, 0x31, 0x01];
+// Version 2 with single part tiled flag
+const VERSION: u32 = 2 | 0x200;
+const FLOAT_CHANNEL: i32 = 2;
+// Size of tile coordinates, level and data size that precede data of tile
+const TILE_HEADER_SIZE: u64 = 5 * 4;
+
+pub struct TiledExrWriter<W: Write + Seek> {
+    writer: W,
+    size: ImageSize,
+    tile_size: usize,
+    xtiles: usize,
+    offsets: Vec<u64>,
+}
+
+fn attribute(header: &mut Vec<u8>, name: &str, typ: &str, value: &[u8]) {
+    header.extend_from_slice(name.as_bytes());
+    header.push(0);
+    header.extend_from_slice(typ.as_bytes());
+    header.push(0);
+    header.extend_from_slice(&(value.len() as i32).to_le_bytes());
+    header.extend_from_slice(value);
+}
+
+fn box2i(width: usize, height: usize) -> Vec<u8> {
+    [0, 0, width as i32 - 1, height as i32 - 1].iter().flat_map(|v| v.to_le_bytes()).collect()
+}
+
+impl<W: Write + Seek> TiledExrWriter<W> {
+    /// Header and offset table are written, tiles are then written with write_tile.
+    pub fn new(mut writer: W, size: ImageSize, tile_size: usize) -> Result<Self, Box<dyn Error>> {
+        if size.width == 0 || size.height == 0 || tile_size == 0 {
+            return Err("Tiled EXR image and its tiles must not be empty!".into())
+        }
+        let mut header = Vec::new();
+        header.extend_from_slice(&MAGIC);
+        header.extend_from_slice(&VERSION.to_le_bytes());
+
+        // Channels are sorted by name
+        let mut channels = Vec::new();
+        for name in ["B", "G", "R"] {
+            channels.extend_from_slice(name.as_bytes());
+            channels.push(0);
+            channels.extend_from_slice(&FLOAT_CHANNEL.to_le_bytes());
+            channels.extend_from_slice(&[0, 0, 0, 0]);
+            channels.extend_from_slice(&1i32.to_le_bytes());
+            channels.extend_from_slice(&1i32.to_le_bytes());
+        }
+        channels.push(0);
+        attribute(&mut header, "channels", "chlist", &channels);
+        attribute(&mut header, "compression", "compression", &[0]);
+        attribute(&mut header, "dataWindow", "box2i", &box2i(size.width, size.height));
+        attribute(&mut header, "displayWindow", "box2i", &box2i(size.width, size.height));
+        attribute(&mut header, "lineOrder", "lineOrder", &[0]);
+        attribute(&mut header, "pixelAspectRatio", "float", &1.0f32.to_le_bytes());
+        attribute(&mut header, "screenWindowCenter", "v2f", &[0u8; 8]);
+        attribute(&mut header, "screenWindowWidth", "float", &1.0f32.to_le_bytes());
+        let mut tiles = Vec::new();
+        tiles.extend_from_slice(&(tile_size as u32).to_le_bytes());
+        tiles.extend_from_slice(&(tile_size as u32).to_le_bytes());
+        tiles.push(0);
+        attribute(&mut header, "tiles", "tiledesc", &tiles);
+        header.push(0);
+
+        let image_tiles = Tile::new(0, 0, size.width, size.height).split(tile_size, tile_size);
+        let mut offset = (header.len() + image_tiles.len() * 8) as u64;
+        let mut offsets = Vec::with_capacity(image_tiles.len());
+        for tile in image_tiles.iter() {
+            offsets.push(offset);
+            offset += TILE_HEADER_SIZE + (tile.width() * tile.height() * 3 * 4) as u64;
+        }
+        writer.write_all(&header)?;
+        for offset in offsets.iter() {
+            writer.write_all(&offset.to_le_bytes())?;
+        }
+        let xtiles = size.width.div_ceil(tile_size);
+        Ok(Self { writer, size, tile_size, xtiles, offsets })
+    }
+
+    /// Write pixels of tile, they are in row-major order. Tile must be one of the tiles of
+    /// image split to tiles of tile_size.
+    pub fn write_tile(&mut self, tile: &Tile, pixels: &[RGB]) -> Result<(), Box<dyn Error>> {
+        let (tx, ty) = (tile.x1 / self.tile_size, tile.y1 / self.tile_size);
+        let expected = Tile::new(tile.x1, tile.y1, (tile.x1 + self.tile_size).min(self.size.width),
+                                 (tile.y1 + self.tile_size).min(self.size.height));
+        let aligned = tile.x1.is_multiple_of(self.tile_size) && tile.y1.is_multiple_of(self.tile_size);
+        if !aligned || tile.x2 != expected.x2 || tile.y2 != expected.y2 {
+            return Err(format!("Tile {:?} doesn't match tiles of EXR image!", tile).into())
+        }
+        if pixels.len() != tile.width() * tile.height() {
+            return Err(format!("Tile {:?} has {} pixels!", tile, pixels.len()).into())
+        }
+        let data_size = tile.width() * tile.height() * 3 * 4;
+        let mut chunk = Vec::with_capacity(TILE_HEADER_SIZE as usize + data_size);
+        for value in [tx as i32, ty as i32, 0, 0, data_size as i32] {
+            chunk.extend_from_slice(&value.to_le_bytes());
+        }
+        for row in pixels.chunks(tile.width()) {
+            for channel in [|p: &RGB| p.b, |p: &RGB| p.g, |p: &RGB| p.r] {
+                for pixel in row {
+                    chunk.extend_from_slice(&channel(pixel).to_le_bytes());
+                }
+            }
+        }
+        let offset = self.offsets[ty * self.xtiles + tx];
+        self.writer.seek(SeekFrom::Start(offset))?;
+        self.writer.write_all(&chunk)?;
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> Result<W, Box<dyn Error>> {
+        self.writer.flush()?;
+        Ok(self.writer)
+    }
+}
+
+#[cfg(feature = "fs")]
+impl TiledExrWriter<std::io::BufWriter<std::fs::File>> {
+    pub fn create(fname: &str, size: ImageSize, tile_size: usize) -> Result<Self, Box<dyn Error>> {
+        let file = std::fs::File::create(fname)?;
+        Self::new(std::io::BufWriter::new(file), size, tile_size)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn tiled_exr_layout() {
+        let size = ImageSize::new(5, 3);
+        let mut writer = TiledExrWriter::new(Cursor::new(Vec::new()), size, 4).unwrap();
+        let tiles = Tile::new(0, 0, 5, 3).split(4, 4);
+        // tiles are written in reverse order, offsets are fixed in advance
+        for tile in tiles.iter().rev() {
+            let pixels: Vec<_> = tile.into_iter().map(|(x, y)| RGB::new(x as f32, y as f32, 0.5)).collect();
+            writer.write_tile(tile, &pixels).unwrap();
+        }
+        assert!(writer.write_tile(&Tile::new(1, 0, 5, 3), &[]).is_err());
+        let data = writer.finish().unwrap().into_inner();
+        assert_eq!(data[0..4], MAGIC);
+
+        let read_u64 = |pos: usize| u64::from_le_bytes(data[pos..pos + 8].try_into().unwrap());
+        let read_i32 = |pos: usize| i32::from_le_bytes(data[pos..pos + 4].try_into().unwrap());
+        let read_f32 = |pos: usize| f32::from_le_bytes(data[pos..pos + 4].try_into().unwrap());
+        let table = data.len() - (20 + 4 * 3 * 3 * 4) - (20 + 3 * 3 * 4) - 2 * 8;
+        let (first, second) = (read_u64(table) as usize, read_u64(table + 8) as usize);
+        assert_eq!(first, table + 16);
+        assert_eq!((read_i32(second), read_i32(second + 4), read_i32(second + 16)), (1, 0, 36));
+        // second tile has one pixel per row, channels B, G, R follow each other
+        assert_eq!((read_f32(second + 20), read_f32(second + 24), read_f32(second + 28)), (0.5, 0.0, 4.0));
+        assert_eq!(read_f32(second + 20 + 2 * 12 + 4), 2.0);
+    }
+}
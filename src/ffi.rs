@@ -0,0 +1,301 @@
+//! C interface for embedding of the library in other languages.
+//!
+//! Scene is loaded from text, settings can be changed and scene is rendered
+//! synchronously in buffer that is provided by caller. Functions return
+//! RTLIB_OK on success, description of last error is returned by rtlib_last_error.
+//! To get shared or static library build crate with `--features ffi` and
+//! `--crate-type cdylib` or `staticlib`.
+
+use std::cell::RefCell;
+use std::ffi::{CStr, CString};
+use std::os::raw::{c_char, c_int};
+use std::panic::{self, AssertUnwindSafe};
+use std::ptr;
+
+use crate::integrators::render_scene;
+use crate::rgb::{ImageSize, RGB8uffer};
+use crate::scene::{Scene, SceneDescription, SceneFormat, parse_scene_description};
+
+pub const RTLIB_OK: c_int = 0;
+pub const RTLIB_ERROR: c_int = -1;
+
+pub const RTLIB_FORMAT_PBRT: c_int = 0;
+pub const RTLIB_FORMAT_JSON: c_int = 1;
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+fn set_last_error(msg: &str) {
+    let msg = CString::new(msg.replace('\0', " ")).expect("Null byte in error message!");
+    LAST_ERROR.with(|err| *err.borrow_mut() = Some(msg));
+}
+
+/// Scene handle, text of the scene is kept so that it can be rebuilt when settings change.
+pub struct RtlibScene {
+    text: String,
+    format: SceneFormat,
+    resolution: Option<ImageSize>,
+    spp: Option<usize>,
+    nthreads: Option<usize>,
+    scene: Option<Scene>
+}
+
+impl RtlibScene {
+    // Parsing runs inside of catch_unwind, so panic in parser doesn't unwind across C boundary
+    fn description(&self) -> Result<SceneDescription, String> {
+        let parse = || parse_scene_description(self.text.as_bytes(), self.format).map_err(|e| e.to_string());
+        let mut desc = catch_panic(parse, "Scene parsing failed")??;
+        if let Some(resolution) = self.resolution {
+            desc.set_resolution(resolution);
+        }
+        if let Some(spp) = self.spp {
+            desc.settings.spp = spp;
+        }
+        if let Some(nthreads) = self.nthreads {
+            desc.settings.nthreads = nthreads;
+        }
+        Ok(desc)
+    }
+
+    fn build(&mut self) -> Result<&Scene, String> {
+        if self.scene.is_none() {
+            let desc = self.description()?;
+            let scene = catch_panic(|| Scene::try_from(desc), "Scene creation failed")?
+                .map_err(|e| format!("Scene creation failed: {}", e))?;
+            self.scene = Some(scene);
+        }
+        Ok(self.scene.as_ref().expect("Scene not built!"))
+    }
+}
+
+fn panic_message(payload: &(dyn std::any::Any + Send)) -> String {
+    if let Some(msg) = payload.downcast_ref::<&str>() {
+        msg.to_string()
+    } else if let Some(msg) = payload.downcast_ref::<String>() {
+        msg.clone()
+    } else {
+        "unknown error".to_string()
+    }
+}
+
+fn catch_panic<R>(f: impl FnOnce() -> R, context: &str) -> Result<R, String> {
+    panic::catch_unwind(AssertUnwindSafe(f)).map_err(|e| format!("{}: {}", context, panic_message(e.as_ref())))
+}
+
+fn copy_to_rgba(image: &RGB8uffer, buffer: &mut [u8]) {
+    let size = image.size();
+    for y in 0..size.height {
+        for x in 0..size.width {
+            let p = image.get(x, y).expect("Pixel out of range!");
+            let index = (y * size.width + x) * 4;
+            buffer[index..index + 4].copy_from_slice(&[p.red, p.green, p.blue, 255]);
+        }
+    }
+}
+
+/// Load scene from null terminated text, format is RTLIB_FORMAT_PBRT or RTLIB_FORMAT_JSON.
+/// Returns null on error.
+///
+/// # Safety
+/// `text` must be valid null terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn rtlib_scene_load(text: *const c_char, format: c_int) -> *mut RtlibScene {
+    if text.is_null() {
+        set_last_error("Scene text is null!");
+        return ptr::null_mut();
+    }
+    let format = match format {
+        RTLIB_FORMAT_PBRT => SceneFormat::Pbrt,
+        RTLIB_FORMAT_JSON => SceneFormat::Json,
+        _ => {
+            set_last_error(&format!("Unknown scene format {}", format));
+            return ptr::null_mut();
+        }
+    };
+    let text = match CStr::from_ptr(text).to_str() {
+        Ok(text) => text.to_string(),
+        Err(e) => {
+            set_last_error(&format!("Scene text is not valid utf-8: {}", e));
+            return ptr::null_mut();
+        }
+    };
+    let scene = RtlibScene { text, format, resolution: None, spp: None, nthreads: None, scene: None };
+    if let Err(e) = scene.description() {
+        set_last_error(&e);
+        return ptr::null_mut();
+    }
+    Box::into_raw(Box::new(scene))
+}
+
+/// Release scene returned by rtlib_scene_load.
+///
+/// # Safety
+/// `scene` must be null or pointer returned by rtlib_scene_load that is not yet freed.
+#[no_mangle]
+pub unsafe extern "C" fn rtlib_scene_free(scene: *mut RtlibScene) {
+    if !scene.is_null() {
+        drop(Box::from_raw(scene));
+    }
+}
+
+/// # Safety
+/// `scene` must be valid pointer returned by rtlib_scene_load.
+#[no_mangle]
+pub unsafe extern "C" fn rtlib_scene_set_resolution(scene: *mut RtlibScene, width: usize, height: usize) -> c_int {
+    let scene = match scene.as_mut() {
+        Some(scene) => scene,
+        None => { set_last_error("Scene is null!"); return RTLIB_ERROR }
+    };
+    if width == 0 || height == 0 {
+        set_last_error("Resolution must be positive!");
+        return RTLIB_ERROR;
+    }
+    scene.resolution = Some(ImageSize::new(width, height));
+    scene.scene = None;
+    RTLIB_OK
+}
+
+/// # Safety
+/// `scene` must be valid pointer returned by rtlib_scene_load.
+#[no_mangle]
+pub unsafe extern "C" fn rtlib_scene_set_spp(scene: *mut RtlibScene, spp: usize) -> c_int {
+    let scene = match scene.as_mut() {
+        Some(scene) => scene,
+        None => { set_last_error("Scene is null!"); return RTLIB_ERROR }
+    };
+    if spp == 0 {
+        set_last_error("Samples per pixel must be positive!");
+        return RTLIB_ERROR;
+    }
+    scene.spp = Some(spp);
+    scene.scene = None;
+    RTLIB_OK
+}
+
+/// Number of rendering threads, 0 means one thread per logical core.
+///
+/// # Safety
+/// `scene` must be valid pointer returned by rtlib_scene_load.
+#[no_mangle]
+pub unsafe extern "C" fn rtlib_scene_set_nthreads(scene: *mut RtlibScene, nthreads: usize) -> c_int {
+    let scene = match scene.as_mut() {
+        Some(scene) => scene,
+        None => { set_last_error("Scene is null!"); return RTLIB_ERROR }
+    };
+    scene.nthreads = Some(nthreads);
+    scene.scene = None;
+    RTLIB_OK
+}
+
+/// Resolution of the image that rtlib_render_rgba produces.
+///
+/// # Safety
+/// `scene` must be valid pointer returned by rtlib_scene_load, `width` and `height` valid pointers.
+#[no_mangle]
+pub unsafe extern "C" fn rtlib_scene_resolution(scene: *mut RtlibScene, width: *mut usize, height: *mut usize) -> c_int {
+    let scene = match scene.as_mut() {
+        Some(scene) => scene,
+        None => { set_last_error("Scene is null!"); return RTLIB_ERROR }
+    };
+    if width.is_null() || height.is_null() {
+        set_last_error("Width or height is null!");
+        return RTLIB_ERROR;
+    }
+    let resolution = match scene.build() {
+        Ok(scene) => scene.settings.resolution,
+        Err(e) => { set_last_error(&e); return RTLIB_ERROR }
+    };
+    *width = resolution.width;
+    *height = resolution.height;
+    RTLIB_OK
+}
+
+/// Render scene into RGBA buffer (8 bits per channel, rows from top to bottom),
+/// buffer must hold at least width * height * 4 bytes.
+///
+/// # Safety
+/// `scene` must be valid pointer returned by rtlib_scene_load and `buffer` must point to `len` writable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn rtlib_render_rgba(scene: *mut RtlibScene, buffer: *mut u8, len: usize) -> c_int {
+    let scene = match scene.as_mut() {
+        Some(scene) => scene,
+        None => { set_last_error("Scene is null!"); return RTLIB_ERROR }
+    };
+    if buffer.is_null() {
+        set_last_error("Buffer is null!");
+        return RTLIB_ERROR;
+    }
+    let scene = match scene.build() {
+        Ok(scene) => scene,
+        Err(e) => { set_last_error(&e); return RTLIB_ERROR }
+    };
+    let resolution = scene.settings.resolution;
+    let required = resolution.width * resolution.height * 4;
+    if len < required {
+        set_last_error(&format!("Buffer too small, {} bytes required!", required));
+        return RTLIB_ERROR;
+    }
+    let image = match catch_panic(|| render_scene(scene), "Rendering failed") {
+        Ok(image) => image,
+        Err(e) => { set_last_error(&e); return RTLIB_ERROR }
+    };
+    copy_to_rgba(&image, std::slice::from_raw_parts_mut(buffer, required));
+    RTLIB_OK
+}
+
+/// Description of the last error on calling thread, null if there was no error.
+/// Pointer is valid until next failing call on the same thread.
+#[no_mangle]
+pub extern "C" fn rtlib_last_error() -> *const c_char {
+    LAST_ERROR.with(|err| match err.borrow().as_ref() {
+        Some(msg) => msg.as_ptr(),
+        None => ptr::null()
+    })
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn render_through_c_interface() {
+        let text = CString::new(r#"
+            LookAt 0 0 3  0 0 0  0 1 0
+            Camera "perspective" "float fov" 45
+            Film "rgb" "integer xresolution" 8 "integer yresolution" 8
+            Integrator "ambientocclusion"
+            WorldBegin
+            Material "diffuse"
+            Shape "sphere" "float radius" 1
+        "#).unwrap();
+        unsafe {
+            let scene = rtlib_scene_load(text.as_ptr(), RTLIB_FORMAT_PBRT);
+            assert!(!scene.is_null());
+            assert_eq!(rtlib_scene_set_resolution(scene, 4, 2), RTLIB_OK);
+            assert_eq!(rtlib_scene_set_spp(scene, 2), RTLIB_OK);
+            assert_eq!(rtlib_scene_set_nthreads(scene, 2), RTLIB_OK);
+            let (mut width, mut height) = (0, 0);
+            assert_eq!(rtlib_scene_resolution(scene, &mut width, &mut height), RTLIB_OK);
+            assert_eq!((width, height), (4, 2));
+
+            let mut buffer = vec![0u8; 4 * 2 * 4];
+            assert_eq!(rtlib_render_rgba(scene, buffer.as_mut_ptr(), 8), RTLIB_ERROR);
+            assert!(!rtlib_last_error().is_null());
+            assert_eq!(rtlib_render_rgba(scene, buffer.as_mut_ptr(), buffer.len()), RTLIB_OK);
+            assert!(buffer.chunks(4).all(|p| p[3] == 255));
+            rtlib_scene_free(scene);
+
+            let invalid = CString::new("{").unwrap();
+            assert!(rtlib_scene_load(invalid.as_ptr(), RTLIB_FORMAT_JSON).is_null());
+        }
+    }
+
+    #[test]
+    fn panic_is_reported_as_error() {
+        let result: Result<(), String> = catch_panic(|| panic!("invalid scene"), "Scene parsing failed");
+        assert_eq!(result.unwrap_err(), "Scene parsing failed: invalid scene");
+        assert_eq!(catch_panic(|| 1, "Scene parsing failed"), Ok(1));
+    }
+}
@@ -117,6 +117,7 @@ impl Filter {
     }
 }
 
+#[derive(Debug)]
 pub enum FilterType {
     Box,
     Triangle,
@@ -125,6 +126,7 @@ pub enum FilterType {
     LanczosSinc,
 }
 
+#[derive(Debug)]
 pub struct FilterDescriptor {
     pub filter_type: FilterType,
     pub xradius: f32,
@@ -18,6 +18,7 @@ impl Frame {
     }
 }
 
+/// Orthonormal basis around the normal (Duff et al. 2017), tangents depend only on the normal.
 impl From<Vec3> for Frame {
     fn from(normal: Vec3) -> Self {
         let sign = 1.0f32.copysign(normal.z);
@@ -0,0 +1,151 @@
+use crate::color::{RGB, RGBAccumlationBuffer};
+use crate::integrators::{TileOutputs, in_render_pool, render_tiles};
+use crate::ray::{Ray, spawn_new_ray};
+use crate::rgb::RGB8uffer;
+use crate::samplers::SamplerInterface;
+use crate::scene::{Scene, FurnaceProperties};
+use crate::textures::TextureContext;
+
+// Path is surrounded by uniform white environment, emission of materials is ignored.
+// For energy conserving BSDF with albedo one every pixel converges to radiance of environment.
+fn furnace_radiance(ray: &Ray, scene: &Scene, sampler: &mut Box<dyn SamplerInterface>,
+                    settings: &FurnaceProperties, material_id: Option<usize>) -> RGB {
+    let environment = RGB::new(settings.radiance, settings.radiance, settings.radiance);
+    let mut ray = *ray;
+    let mut throughput = RGB::new(1.0, 1.0, 1.0);
+    for depth in 0..=settings.maxdepth {
+        let isect_p = match scene.geometry.intersect(&ray) {
+            Some(isect_p) => isect_p,
+            None => return throughput * environment
+        };
+        let material_id = material_id.unwrap_or(isect_p.material_id as usize);
+        let material = scene.material(material_id, &TextureContext::new(&isect_p, &scene.geometry));
+        let wo = -ray.direction;
+        sampler.start_bounce(depth as u32);
+        let bs = match material.sample(wo, isect_p.normal, sampler) {
+            Some(bs) => bs,
+            None => break
+        };
+        throughput = throughput * bs.color * ((isect_p.normal * bs.wi).abs() / bs.pdfw);
+        ray = spawn_new_ray(isect_p.hit_point, isect_p.normal, bs.wi).with_time(ray.time);
+    }
+    RGB::zero()
+}
+
+fn render_furnace(scene: &Scene, settings: &FurnaceProperties) -> Result<RGBAccumlationBuffer, String> {
+    let material_id = match &settings.material {
+        Some(name) => match scene.material_names.get(name) {
+            Some(id) => Some(*id),
+            None => return Err(format!("Furnace: material {} doesn't exist!", name))
+        },
+        None => None
+    };
+    let film = render_tiles(scene, TileOutputs::default(), |buffers, sampler| {
+        for i in buffers.passes() {
+            if scene.cancel_token.is_cancelled() {
+                break;
+            }
+            for (x, y) in buffers.tile {
+                let (sx, sy) = sampler.sample_pixel(x, y, i);
+                buffers.add_sample_count(x, y);
+                let px = x as f32 + sx;
+                let py = y as f32 + sy;
+                let ray = scene.camera.generate_ray_at_time(px, py, sampler.sample_time());
+                let rgb = furnace_radiance(&ray, scene, sampler, settings, material_id);
+                buffers.add(x, y, px, py, &rgb);
+            }
+        }
+    });
+    Ok(film.radiance)
+}
+
+pub fn furnace_integrator(scene: &Scene, settings: &FurnaceProperties) -> RGB8uffer {
+    match render_furnace(scene, settings) {
+        Ok(accum) => accum.to_rgb8_buffer(&scene.settings.tonemap),
+        Err(err) => panic!("{}", err)
+    }
+}
+
+/// Render scene in white furnace and check that every pixel is within tolerance of
+/// environment radiance. Returns maximum relative deviation found.
+pub fn furnace_check(scene: &Scene, settings: &FurnaceProperties, tolerance: f32) -> Result<f32, String> {
+    let accum = in_render_pool(scene, || render_furnace(scene, settings))?;
+    let resolution = scene.settings.resolution;
+    let mut max_deviation = 0.0f32;
+    for y in 0..resolution.height {
+        for x in 0..resolution.width {
+            let sample = match accum.get(x, y) {
+                Some(sample) if sample.weight > 0.0 => sample,
+                _ => continue
+            };
+            let value = sample.spectrum * sample.weight.recip();
+            for channel in [value.r, value.g, value.b] {
+                let deviation = (channel - settings.radiance).abs() / settings.radiance;
+                if deviation > tolerance {
+                    return Err(format!("Furnace: pixel ({}, {}) has value {} expected {}", x, y, channel, settings.radiance));
+                }
+                max_deviation = max_deviation.max(deviation);
+            }
+        }
+    }
+    Ok(max_deviation)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hair::HairProperties;
+    use crate::materials::{MaterialDescription, MaterialType};
+    use crate::rgb::ImageSize;
+    use crate::scene::SceneDescription;
+    use crate::shapes::{ShapeDescription, SphereDescription};
+    use crate::vec::Point3;
+
+    fn furnace_scene(diffuse: RGB) -> Scene {
+        let mut desc = SceneDescription::default();
+        desc.set_resolution(ImageSize::new(16, 16));
+        desc.settings.spp = 4;
+        desc.settings.nthreads = 2;
+        desc.camera_desc.position = Point3::new(0.0, 0.0, 3.0);
+        desc.camera_desc.look_at = Point3::new(0.0, 0.0, 0.0);
+        desc.materials.push(MaterialDescription { name: "matte".to_string(), ..Default::default() });
+        desc.materials.push(MaterialDescription { name: "white".to_string(), diffuse, ..Default::default() });
+        desc.shapes.push(ShapeDescription::Sphere(SphereDescription { radius: 1.0, material: "matte".to_string(), ..Default::default() }));
+        Scene::try_from(desc).unwrap()
+    }
+
+    #[test]
+    fn white_furnace() {
+        let settings = FurnaceProperties { material: Some("white".to_string()), ..Default::default() };
+        let scene = furnace_scene(RGB::new(1.0, 1.0, 1.0));
+        let deviation = furnace_check(&scene, &settings, 1e-3).unwrap();
+        assert!(deviation < 1e-3);
+
+        // NOTE: material that loses energy must fail the check
+        let settings = FurnaceProperties::default();
+        assert!(furnace_check(&scene, &settings, 1e-3).is_err());
+
+        let settings = FurnaceProperties { material: Some("missing".to_string()), ..Default::default() };
+        assert!(furnace_check(&scene, &settings, 1e-3).is_err());
+    }
+
+    #[test]
+    fn hair_furnace() {
+        let hair_scene = |hair: HairProperties| {
+            let mut desc = SceneDescription::default();
+            desc.set_resolution(ImageSize::new(8, 8));
+            desc.settings.spp = 64;
+            desc.camera_desc.position = Point3::new(0.0, 0.0, 3.0);
+            desc.camera_desc.look_at = Point3::new(0.0, 0.0, 0.0);
+            desc.materials.push(MaterialDescription { name: "hair".to_string(), typ: MaterialType::Hair, hair, ..Default::default() });
+            desc.shapes.push(ShapeDescription::Sphere(SphereDescription { radius: 1.0, material: "hair".to_string(), ..Default::default() }));
+            Scene::try_from(desc).unwrap()
+        };
+        // hair without absorption scatters all light
+        let scene = hair_scene(HairProperties { sigma_a: RGB::zero(), ..Default::default() });
+        let deviation = furnace_check(&scene, &FurnaceProperties::default(), 1e-2).unwrap();
+        assert!(deviation < 1e-2);
+        assert!(furnace_check(&hair_scene(HairProperties::default()), &FurnaceProperties::default(), 1e-2).is_err());
+    }
+}
@@ -0,0 +1,133 @@
+#[cfg(feature = "fs")]
+use std::env;
+use std::error::Error;
+#[cfg(feature = "fs")]
+use std::path::Path;
+
+#[cfg(feature = "fs")]
+use crate::image_diff::{compare_images, load_image, FloatImage, ImageDiff};
+use crate::integrators::render_scene;
+use crate::rgb::RGB8uffer;
+use crate::scene::{Scene, SceneDescription, SceneFormat, parse_scene_description};
+
+/// When this environment variable is set reference images are (re)written instead of compared.
+#[cfg(feature = "fs")]
+pub const UPDATE_GOLDEN_ENV: &str = "RTLIB_UPDATE_GOLDEN";
+
+/// Small scene that is rendered with fixed sampler seed and compared against stored reference.
+#[derive(Debug, Clone)]
+pub struct GoldenScene {
+    /// Reference image is stored as <name>.png in reference directory
+    pub name: String,
+    pub format: SceneFormat,
+    pub text: String,
+    /// Maximum allowed root mean squared error
+    pub tolerance: f32
+}
+
+impl GoldenScene {
+    pub fn new(name: &str, format: SceneFormat, text: &str, tolerance: f32) -> Self {
+        Self { name: name.to_string(), format, text: text.to_string(), tolerance }
+    }
+
+    pub fn scene_description(&self) -> Result<SceneDescription, Box<dyn Error>> {
+        parse_scene_description(self.text.as_bytes(), self.format)
+    }
+
+    pub fn render(&self) -> Result<RGB8uffer, Box<dyn Error>> {
+        let desc = self.scene_description()?;
+        let scene = Scene::try_from(desc)?;
+        Ok(render_scene(&scene))
+    }
+}
+
+/// Render golden scene and compare it with <reference_dir>/<name>.png. If environment
+/// variable RTLIB_UPDATE_GOLDEN is set reference image is written instead.
+#[cfg(feature = "fs")]
+pub fn check_golden_scene<P: AsRef<Path>>(golden: &GoldenScene, reference_dir: P) -> Result<ImageDiff, Box<dyn Error>> {
+    let image = golden.render()?;
+    let path = reference_dir.as_ref().join(format!("{}.png", golden.name));
+    if env::var_os(UPDATE_GOLDEN_ENV).is_some() {
+        image.save(&path)?;
+    }
+    if !path.exists() {
+        return Err(format!("Golden: reference image {} doesn't exist, set {} to create it!",
+                           path.display(), UPDATE_GOLDEN_ENV).into());
+    }
+    let reference = load_image(&path)?;
+    let diff = compare_images(&FloatImage::from(&image), &reference)?;
+    if diff.rmse > golden.tolerance {
+        return Err(format!("Golden: scene {} differs from reference, rmse {} tolerance {}",
+                           golden.name, diff.rmse, golden.tolerance).into());
+    }
+    Ok(diff)
+}
+
+/// Check all scenes and report every scene that failed.
+#[cfg(feature = "fs")]
+pub fn check_golden_scenes<P: AsRef<Path>>(scenes: &[GoldenScene], reference_dir: P) -> Result<(), Box<dyn Error>> {
+    let errors: Vec<String> = scenes.iter()
+        .filter_map(|golden| check_golden_scene(golden, reference_dir.as_ref()).err())
+        .map(|err| err.to_string())
+        .collect();
+    if !errors.is_empty() {
+        return Err(errors.join("\n").into());
+    }
+    Ok(())
+}
+
+const GOLDEN_CAMERA: &str = r#"
+    LookAt 0 1 4  0 0.5 0  0 1 0
+    Camera "perspective" "float fov" 45
+    Film "rgb" "integer xresolution" 16 "integer yresolution" 16
+"#;
+
+const GOLDEN_WORLD: &str = r#"
+    Material "diffuse" "rgb reflectance" [0.7 0.7 0.7]
+    Shape "trianglemesh" "point3 P" [-5 0 -5  5 0 -5  5 0 5  -5 0 5] "integer indices" [0 2 1  0 3 2]
+    Material "diffuse" "rgb reflectance" [0.8 0.3 0.2]
+    AttributeBegin
+    Translate 0 0.5 0
+    Shape "sphere" "float radius" 0.5
+    AttributeEnd
+"#;
+
+fn golden_pbrt_scene(name: &str, integrator: &str, lights: &str) -> GoldenScene {
+    let text = format!("{}\n{}\nSampler \"independent\" \"integer seed\" 7 \"integer pixelsamples\" 16\nWorldBegin\n{}\n{}",
+                       GOLDEN_CAMERA, integrator, GOLDEN_WORLD, lights);
+    GoldenScene::new(name, SceneFormat::Pbrt, &text, 0.02)
+}
+
+/// Scenes that cover integrators of this crate, references are stored in testdata/golden.
+pub fn builtin_golden_scenes() -> Vec<GoldenScene> {
+    vec![
+        golden_pbrt_scene("ambient_occlusion", "Integrator \"ambientocclusion\" \"float maxdistance\" 2", ""),
+        golden_pbrt_scene("direct_lighting", "Integrator \"direct_lighting\"",
+                          "LightSource \"point\" \"point3 from\" [1 3 2] \"rgb I\" [200 200 200]"),
+        golden_pbrt_scene("random_walk", "Integrator \"randomwalk\" \"integer maxdepth\" 3",
+                          "AttributeBegin\nAreaLightSource \"diffuse\" \"rgb L\" [3 3 3]\nTranslate 0 4 0\nShape \"sphere\" \"float radius\" 2\nAttributeEnd"),
+    ]
+}
+
+
+#[cfg(all(test, feature = "fs"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn golden_images() {
+        let reference_dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("testdata").join("golden");
+        if let Err(err) = check_golden_scenes(&builtin_golden_scenes(), reference_dir) {
+            panic!("{}", err);
+        }
+    }
+
+    #[test]
+    fn golden_missing_reference() {
+        let mut golden = builtin_golden_scenes().remove(0);
+        golden.name = "missing".to_string();
+        if env::var_os(UPDATE_GOLDEN_ENV).is_none() {
+            assert!(check_golden_scene(&golden, env::temp_dir().join("rtlib_no_golden")).is_err());
+        }
+    }
+}
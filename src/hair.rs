@@ -0,0 +1,371 @@
+//! Hair scattering model of Chiang et al. 2016 (A Practical and Controllable Hair and Fur Model
+//! for Production Path Tracing). Directions are in local frame of the curve, x axis goes along
+//! the hair and h is offset across the hair width in [-1, 1].
+
+use std::f32::consts::PI;
+
+use crate::color::RGB;
+use crate::frame::Frame;
+use crate::materials::{fr_dielectric, BSDFEvalSample, BSDFInterface, BSDFSample, ScatteringType};
+use crate::samplers::SamplerInterface;
+use crate::vec::{Normal, Vec3};
+
+const P_MAX: usize = 3;
+
+/// Parameters of hair material, absorption is given directly or by melanin concentrations.
+#[derive(Debug, Clone, Copy)]
+pub struct HairProperties {
+    pub sigma_a: RGB,
+    pub eta: f32,
+    /// Longitudinal roughness in [0, 1]
+    pub beta_m: f32,
+    /// Azimuthal roughness in [0, 1]
+    pub beta_n: f32,
+    /// Angle of cuticle scales in degrees
+    pub alpha: f32,
+}
+
+impl Default for HairProperties {
+    fn default() -> Self {
+        Self { sigma_a: sigma_a_from_melanin(1.3, 0.0), eta: 1.55, beta_m: 0.3, beta_n: 0.3, alpha: 2.0 }
+    }
+}
+
+/// Absorption coefficient from concentration of eumelanin (brown/black) and pheomelanin (red).
+pub fn sigma_a_from_melanin(eumelanin: f32, pheomelanin: f32) -> RGB {
+    let eumelanin_sigma_a = RGB::new(0.419, 0.697, 1.37);
+    let pheomelanin_sigma_a = RGB::new(0.187, 0.4, 1.05);
+    eumelanin_sigma_a * eumelanin + pheomelanin_sigma_a * pheomelanin
+}
+
+/// Absorption coefficient that gives approximately desired color of hair for given azimuthal roughness.
+pub fn sigma_a_from_reflectance(color: RGB, beta_n: f32) -> RGB {
+    let denom = 5.969 - 0.215 * beta_n + 2.532 * beta_n.powi(2) - 10.73 * beta_n.powi(3) +
+        5.574 * beta_n.powi(4) + 0.245 * beta_n.powi(5);
+    let sigma = |c: f32| (c.ln() / denom).powi(2);
+    RGB::new(sigma(color.r), sigma(color.g), sigma(color.b))
+}
+
+fn safe_sqrt(x: f32) -> f32 {
+    x.max(0.0).sqrt()
+}
+
+fn safe_asin(x: f32) -> f32 {
+    x.clamp(-1.0, 1.0).asin()
+}
+
+fn exp_rgb(c: RGB) -> RGB {
+    RGB::new(c.r.exp(), c.g.exp(), c.b.exp())
+}
+
+// Modified Bessel function of the first kind
+fn i0(x: f32) -> f32 {
+    let (mut val, mut x2i, mut ifact, mut i4) = (0.0, 1.0, 1.0, 1.0);
+    for i in 0..10 {
+        if i > 1 {
+            ifact *= i as f32;
+        }
+        val += x2i / (i4 * ifact * ifact);
+        x2i *= x * x;
+        i4 *= 4.0;
+    }
+    val
+}
+
+fn log_i0(x: f32) -> f32 {
+    if x > 12.0 {
+        x + 0.5 * (-(2.0 * PI).ln() + x.recip().ln() + (8.0 * x).recip())
+    } else {
+        i0(x).ln()
+    }
+}
+
+// Longitudinal scattering
+fn mp(cos_theta_i: f32, cos_theta_o: f32, sin_theta_i: f32, sin_theta_o: f32, v: f32) -> f32 {
+    let a = cos_theta_i * cos_theta_o / v;
+    let b = sin_theta_i * sin_theta_o / v;
+    if v <= 0.1 {
+        (log_i0(a) - b - v.recip() + std::f32::consts::LN_2 + (0.5 / v).ln()).exp()
+    } else {
+        (-b).exp() * i0(a) / ((v.recip()).sinh() * 2.0 * v)
+    }
+}
+
+// Attenuation of R, TT, TRT and sum of higher order lobes
+fn ap(cos_theta_o: f32, eta: f32, h: f32, t: RGB) -> [RGB; P_MAX + 1] {
+    let cos_gamma_o = safe_sqrt(1.0 - h * h);
+    let f = fr_dielectric(cos_theta_o * cos_gamma_o, eta);
+    let a0 = RGB::new(f, f, f);
+    let a1 = t * (1.0 - f) * (1.0 - f);
+    let a2 = a1 * t * f;
+    // NOTE: geometric series of remaining bounces, there is nothing left when all light is reflected
+    let rest = |a: f32, tf: f32| if tf < 1.0 { a * tf / (1.0 - tf) } else { 0.0 };
+    let tf = t * f;
+    let a3 = RGB::new(rest(a2.r, tf.r), rest(a2.g, tf.g), rest(a2.b, tf.b));
+    [a0, a1, a2, a3]
+}
+
+fn phi(p: usize, gamma_o: f32, gamma_t: f32) -> f32 {
+    2.0 * p as f32 * gamma_t - 2.0 * gamma_o + p as f32 * PI
+}
+
+fn logistic(x: f32, s: f32) -> f32 {
+    let x = x.abs();
+    (-x / s).exp() / (s * (1.0 + (-x / s).exp()).powi(2))
+}
+
+fn logistic_cdf(x: f32, s: f32) -> f32 {
+    1.0 / (1.0 + (-x / s).exp())
+}
+
+fn trimmed_logistic(x: f32, s: f32, a: f32, b: f32) -> f32 {
+    logistic(x, s) / (logistic_cdf(b, s) - logistic_cdf(a, s))
+}
+
+fn sample_trimmed_logistic(u: f32, s: f32, a: f32, b: f32) -> f32 {
+    let k = logistic_cdf(b, s) - logistic_cdf(a, s);
+    let x = -s * ((u * k + logistic_cdf(a, s)).recip() - 1.0).ln();
+    x.clamp(a, b)
+}
+
+// Azimuthal scattering
+fn np(phi_value: f32, p: usize, s: f32, gamma_o: f32, gamma_t: f32) -> f32 {
+    let mut dphi = phi_value - phi(p, gamma_o, gamma_t);
+    while dphi > PI {
+        dphi -= 2.0 * PI;
+    }
+    while dphi < -PI {
+        dphi += 2.0 * PI;
+    }
+    trimmed_logistic(dphi, s, -PI, PI)
+}
+
+pub struct HairBSDF {
+    h: f32,
+    gamma_o: f32,
+    eta: f32,
+    sigma_a: RGB,
+    v: [f32; P_MAX + 1],
+    s: f32,
+    sin_2k_alpha: [f32; 3],
+    cos_2k_alpha: [f32; 3],
+}
+
+impl HairBSDF {
+    pub fn new(props: &HairProperties, h: f32) -> Self {
+        let h = h.clamp(-1.0, 1.0);
+        let beta_m = props.beta_m;
+        let v0 = (0.726 * beta_m + 0.812 * beta_m.powi(2) + 3.7 * beta_m.powi(20)).powi(2);
+        let v = [v0, 0.25 * v0, 4.0 * v0, 4.0 * v0];
+        let beta_n = props.beta_n;
+        let sqrt_pi_over_8 = (PI / 8.0).sqrt();
+        let s = sqrt_pi_over_8 * (0.265 * beta_n + 1.194 * beta_n.powi(2) + 5.372 * beta_n.powi(22));
+        let mut sin_2k_alpha = [props.alpha.to_radians().sin(), 0.0, 0.0];
+        let mut cos_2k_alpha = [safe_sqrt(1.0 - sin_2k_alpha[0] * sin_2k_alpha[0]), 0.0, 0.0];
+        for i in 1..3 {
+            sin_2k_alpha[i] = 2.0 * cos_2k_alpha[i - 1] * sin_2k_alpha[i - 1];
+            cos_2k_alpha[i] = cos_2k_alpha[i - 1].powi(2) - sin_2k_alpha[i - 1].powi(2);
+        }
+        Self { h, gamma_o: safe_asin(h), eta: props.eta, sigma_a: props.sigma_a, v, s, sin_2k_alpha, cos_2k_alpha }
+    }
+
+    // Longitudinal angle of outgoing direction tilted by cuticle scales for given lobe
+    fn tilted(&self, p: usize, sin_theta_o: f32, cos_theta_o: f32) -> (f32, f32) {
+        let (sin_theta, cos_theta) = match p {
+            0 => (sin_theta_o * self.cos_2k_alpha[1] - cos_theta_o * self.sin_2k_alpha[1],
+                  cos_theta_o * self.cos_2k_alpha[1] + sin_theta_o * self.sin_2k_alpha[1]),
+            1 => (sin_theta_o * self.cos_2k_alpha[0] + cos_theta_o * self.sin_2k_alpha[0],
+                  cos_theta_o * self.cos_2k_alpha[0] - sin_theta_o * self.sin_2k_alpha[0]),
+            2 => (sin_theta_o * self.cos_2k_alpha[2] + cos_theta_o * self.sin_2k_alpha[2],
+                  cos_theta_o * self.cos_2k_alpha[2] - sin_theta_o * self.sin_2k_alpha[2]),
+            _ => (sin_theta_o, cos_theta_o)
+        };
+        (sin_theta, cos_theta.abs())
+    }
+
+    // Transmittance through the hair and refracted azimuthal angle
+    fn transmittance(&self, sin_theta_o: f32, cos_theta_o: f32) -> (RGB, f32) {
+        let sin_theta_t = sin_theta_o / self.eta;
+        let cos_theta_t = safe_sqrt(1.0 - sin_theta_t * sin_theta_t);
+        let etap = safe_sqrt(self.eta * self.eta - sin_theta_o * sin_theta_o) / cos_theta_o;
+        let sin_gamma_t = self.h / etap;
+        let cos_gamma_t = safe_sqrt(1.0 - sin_gamma_t * sin_gamma_t);
+        let t = exp_rgb(self.sigma_a * (-2.0 * cos_gamma_t / cos_theta_t));
+        (t, safe_asin(sin_gamma_t))
+    }
+
+    fn ap_pdf(&self, sin_theta_o: f32, cos_theta_o: f32) -> [f32; P_MAX + 1] {
+        let (t, _) = self.transmittance(sin_theta_o, cos_theta_o);
+        let ap = ap(cos_theta_o, self.eta, self.h, t);
+        let luminance = ap.map(|a| a.luminance());
+        let sum: f32 = luminance.iter().sum();
+        luminance.map(|l| l / sum)
+    }
+
+    pub fn eval(&self, wo: Vec3, wi: Vec3) -> RGB {
+        let sin_theta_o = wo.x;
+        let cos_theta_o = safe_sqrt(1.0 - sin_theta_o * sin_theta_o);
+        let phi_o = wo.z.atan2(wo.y);
+        let sin_theta_i = wi.x;
+        let cos_theta_i = safe_sqrt(1.0 - sin_theta_i * sin_theta_i);
+        let phi_i = wi.z.atan2(wi.y);
+
+        let (t, gamma_t) = self.transmittance(sin_theta_o, cos_theta_o);
+        let phi_value = phi_i - phi_o;
+        let ap = ap(cos_theta_o, self.eta, self.h, t);
+        let mut sum = RGB::zero();
+        for (p, a) in ap.iter().enumerate().take(P_MAX) {
+            let (sin_thetap_o, cos_thetap_o) = self.tilted(p, sin_theta_o, cos_theta_o);
+            sum += *a * (mp(cos_theta_i, cos_thetap_o, sin_theta_i, sin_thetap_o, self.v[p]) *
+                np(phi_value, p, self.s, self.gamma_o, gamma_t));
+        }
+        sum += ap[P_MAX] * (mp(cos_theta_i, cos_theta_o, sin_theta_i, sin_theta_o, self.v[P_MAX]) / (2.0 * PI));
+        if wi.z.abs() > 0.0 {
+            sum = sum * wi.z.abs().recip();
+        }
+        sum
+    }
+
+    pub fn pdf(&self, wo: Vec3, wi: Vec3) -> f32 {
+        let sin_theta_o = wo.x;
+        let cos_theta_o = safe_sqrt(1.0 - sin_theta_o * sin_theta_o);
+        let phi_o = wo.z.atan2(wo.y);
+        let sin_theta_i = wi.x;
+        let cos_theta_i = safe_sqrt(1.0 - sin_theta_i * sin_theta_i);
+        let phi_i = wi.z.atan2(wi.y);
+
+        let (_, gamma_t) = self.transmittance(sin_theta_o, cos_theta_o);
+        let ap_pdf = self.ap_pdf(sin_theta_o, cos_theta_o);
+        let phi_value = phi_i - phi_o;
+        let mut pdf = 0.0;
+        for (p, a) in ap_pdf.iter().enumerate().take(P_MAX) {
+            let (sin_thetap_o, cos_thetap_o) = self.tilted(p, sin_theta_o, cos_theta_o);
+            pdf += mp(cos_theta_i, cos_thetap_o, sin_theta_i, sin_thetap_o, self.v[p]) * a *
+                np(phi_value, p, self.s, self.gamma_o, gamma_t);
+        }
+        pdf += mp(cos_theta_i, cos_theta_o, sin_theta_i, sin_theta_o, self.v[P_MAX]) * ap_pdf[P_MAX] / (2.0 * PI);
+        pdf
+    }
+
+    /// Sample incoming direction, uc selects lobe and u samples longitudinal and azimuthal angle.
+    /// Returns direction, value of BSDF and pdf.
+    pub fn sample(&self, wo: Vec3, uc: f32, u: (f32, f32)) -> Option<(Vec3, RGB, f32)> {
+        let sin_theta_o = wo.x;
+        let cos_theta_o = safe_sqrt(1.0 - sin_theta_o * sin_theta_o);
+        let phi_o = wo.z.atan2(wo.y);
+
+        let ap_pdf = self.ap_pdf(sin_theta_o, cos_theta_o);
+        let mut p = 0;
+        let mut uc = uc;
+        while p < P_MAX && uc >= ap_pdf[p] {
+            uc -= ap_pdf[p];
+            p += 1;
+        }
+        uc = (uc / ap_pdf[p]).min(1.0 - f32::EPSILON);
+
+        let (sin_thetap_o, cos_thetap_o) = self.tilted(p, sin_theta_o, cos_theta_o);
+        let u0 = u.0.max(1e-5);
+        let cos_theta = 1.0 + self.v[p] * (u0 + (1.0 - u0) * (-2.0 / self.v[p]).exp()).ln();
+        let sin_theta = safe_sqrt(1.0 - cos_theta * cos_theta);
+        let cos_phi = (2.0 * PI * u.1).cos();
+        let sin_theta_i = -cos_theta * sin_thetap_o + sin_theta * cos_phi * cos_thetap_o;
+        let cos_theta_i = safe_sqrt(1.0 - sin_theta_i * sin_theta_i);
+
+        let (_, gamma_t) = self.transmittance(sin_theta_o, cos_theta_o);
+        let dphi = if p < P_MAX {
+            phi(p, self.gamma_o, gamma_t) + sample_trimmed_logistic(uc, self.s, -PI, PI)
+        } else {
+            2.0 * PI * uc
+        };
+        let phi_i = phi_o + dphi;
+        let wi = Vec3::new(sin_theta_i, cos_theta_i * phi_i.cos(), cos_theta_i * phi_i.sin());
+        let pdf = self.pdf(wo, wi);
+        if pdf <= 0.0 {
+            return None
+        }
+        Some((wi, self.eval(wo, wi), pdf))
+    }
+}
+
+/// Hair material of surfaces. Surface has no tangents, so hair goes along tangent of the frame of the normal
+/// and offset h across the hair follows from angle between normal and outgoing direction as on a cylinder.
+pub struct HairMaterial {
+    props: HairProperties
+}
+
+impl HairMaterial {
+    pub fn new(props: HairProperties) -> Self {
+        Self { props }
+    }
+
+    fn local_bsdf(&self, wo: Vec3, normal: Normal) -> (Frame, HairBSDF) {
+        let frame = Frame::from(normal);
+        let wo = frame.to_local(wo);
+        let across = (wo.y * wo.y + wo.z * wo.z).sqrt();
+        let h = if across > 0.0 { -wo.y / across } else { 0.0 };
+        (frame, HairBSDF::new(&self.props, h))
+    }
+}
+
+impl BSDFInterface for HairMaterial {
+    fn eval(&self, wo: Vec3, normal: Normal, wi: Vec3) -> Option<BSDFEvalSample> {
+        let (frame, bsdf) = self.local_bsdf(wo, normal);
+        let (wo, wi) = (frame.to_local(wo), frame.to_local(wi));
+        let pdfw = bsdf.pdf(wo, wi);
+        if pdfw <= 0.0 || !pdfw.is_finite() {
+            return None
+        }
+        Some(BSDFEvalSample { color: bsdf.eval(wo, wi), pdfw })
+    }
+
+    fn sample(&self, wo: Vec3, normal: Normal, sampler: &mut Box<dyn SamplerInterface>) -> Option<BSDFSample> {
+        let (frame, bsdf) = self.local_bsdf(wo, normal);
+        let uc = sampler.next_1d();
+        let (wi, color, pdfw) = bsdf.sample(frame.to_local(wo), uc, sampler.next_2d())?;
+        Some(BSDFSample { wi: frame.to_world(wi).normalize(), color, pdfw })
+    }
+
+    fn scattering_type(&self) -> ScatteringType {
+        ScatteringType::Glossy
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rng::{PCGRng, Rng};
+    use crate::samplings::sample_uniform_sphere;
+
+    #[test]
+    fn hair_white_furnace() {
+        let mut rng = PCGRng::new(7, 0);
+        for beta in [0.2, 0.5, 0.9] {
+            let props = HairProperties { sigma_a: RGB::zero(), beta_m: beta, beta_n: beta, ..Default::default() };
+            let count = 50000;
+            let mut uniform = 0.0;
+            let mut sampled = 0.0;
+            for _ in 0..count {
+                let bsdf = HairBSDF::new(&props, -1.0 + 2.0 * rng.rand_f32());
+                let wo = sample_uniform_sphere(rng.rand_f32(), rng.rand_f32()).direction;
+                let sample = sample_uniform_sphere(rng.rand_f32(), rng.rand_f32());
+                uniform += bsdf.eval(wo, sample.direction).g * sample.direction.z.abs() / sample.pdfw;
+                if let Some((wi, f, pdf)) = bsdf.sample(wo, rng.rand_f32(), (rng.rand_f32(), rng.rand_f32())) {
+                    sampled += f.g * wi.z.abs() / pdf;
+                    assert!(pdf.is_finite() && f.g.is_finite());
+                }
+            }
+            // without absorption all light is scattered
+            assert!((uniform / count as f32 - 1.0).abs() < 0.05, "beta {} uniform {}", beta, uniform / count as f32);
+            assert!((sampled / count as f32 - 1.0).abs() < 0.02, "beta {} sampled {}", beta, sampled / count as f32);
+        }
+    }
+
+    #[test]
+    fn melanin_absorption() {
+        let sigma = sigma_a_from_melanin(1.0, 0.5);
+        assert!((sigma.r - (0.419 + 0.5 * 0.187)).abs() < 1e-6);
+        let sigma = sigma_a_from_reflectance(RGB::new(0.8, 0.5, 0.2), 0.3);
+        assert!(sigma.r < sigma.g && sigma.g < sigma.b);
+    }
+}
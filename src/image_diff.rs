@@ -0,0 +1,266 @@
+#[cfg(feature = "fs")]
+use std::error::Error;
+#[cfg(feature = "fs")]
+use std::path::Path;
+
+#[cfg(feature = "fs")]
+use image::DynamicImage;
+
+use crate::color::{ColorEncoding, RGB, RGBAccumlationBuffer, TMOType, tone_map};
+use crate::rgb::{ImageSize, RGB8uffer, RGB8};
+
+/// Image with float pixels, values of 8-bit images are in range [0, 1] and keep encoding
+/// of the image (usually sRGB), float images (EXR, HDR) are linear.
+pub struct FloatImage {
+    size: ImageSize,
+    pixels: Vec<RGB>,
+    encoding: ColorEncoding
+}
+
+impl FloatImage {
+    /// Image with linear pixels
+    pub fn new(size: ImageSize, pixels: Vec<RGB>) -> Self {
+        Self::with_encoding(size, pixels, ColorEncoding::Linear)
+    }
+
+    pub fn with_encoding(size: ImageSize, pixels: Vec<RGB>, encoding: ColorEncoding) -> Self {
+        assert_eq!(size.width * size.height, pixels.len());
+        Self { size, pixels, encoding }
+    }
+
+    pub fn encoding(&self) -> ColorEncoding {
+        self.encoding
+    }
+
+    pub fn size(&self) -> ImageSize {
+        self.size
+    }
+
+    pub fn get(&self, x: usize, y: usize) -> Option<&RGB> {
+        if x >= self.size.width {
+            return None
+        }
+        self.pixels.get(y * self.size.width + x)
+    }
+
+    /// Pixels in row-major order
+    pub fn pixels(&self) -> &[RGB] {
+        &self.pixels
+    }
+
+    pub fn pixels_mut(&mut self) -> &mut [RGB] {
+        &mut self.pixels
+    }
+
+    fn linear_pixel(&self, index: usize) -> RGB {
+        let p = self.pixels[index];
+        let decode = |value: f32| self.encoding.to_linear(value);
+        RGB::new(decode(p.r), decode(p.g), decode(p.b))
+    }
+
+    pub fn to_rgb8_buffer(&self, tmo_type: &TMOType) -> RGB8uffer {
+        let pixels = (0..self.pixels.len()).map(|index| tone_map(tmo_type, &self.linear_pixel(index)).into()).collect();
+        RGB8uffer::from((self.size.width, pixels))
+    }
+}
+
+impl From<&RGBAccumlationBuffer> for FloatImage {
+    fn from(accum: &RGBAccumlationBuffer) -> Self {
+        Self::new(accum.size(), accum.resolve())
+    }
+}
+
+impl From<&RGB8uffer> for FloatImage {
+    fn from(buffer: &RGB8uffer) -> Self {
+        let size = buffer.size();
+        let mut pixels = Vec::with_capacity(size.width * size.height);
+        for y in 0..size.height {
+            for x in 0..size.width {
+                let p = buffer.get(x, y).expect("Pixel out of range!");
+                pixels.push(RGB::new(p.red as f32 / 255.0, p.green as f32 / 255.0, p.blue as f32 / 255.0));
+            }
+        }
+        Self::with_encoding(size, pixels, ColorEncoding::SRGB)
+    }
+}
+
+#[cfg(feature = "fs")]
+pub fn load_image<P: AsRef<Path>>(path: P) -> Result<FloatImage, Box<dyn Error>> {
+    let img = image::open(path)?;
+    let encoding = match img {
+        DynamicImage::ImageRgb32F(_) | DynamicImage::ImageRgba32F(_) => ColorEncoding::Linear,
+        _ => ColorEncoding::SRGB
+    };
+    let img = img.to_rgb32f();
+    let size = ImageSize::new(img.width() as usize, img.height() as usize);
+    let pixels = img.pixels().map(|p| RGB::new(p[0], p[1], p[2])).collect();
+    Ok(FloatImage::with_encoding(size, pixels, encoding))
+}
+
+pub struct ImageDiff {
+    pub mse: f32,
+    pub rmse: f32,
+    /// Relative mean squared error, (a - b)^2 / (b^2 + 0.01)
+    pub relative_mse: f32,
+    /// Mean value of FLIP-style error map
+    pub mean_flip: f32
+}
+
+fn check_sizes(test: &FloatImage, reference: &FloatImage) -> Result<(), String> {
+    if test.size.width != reference.size.width || test.size.height != reference.size.height {
+        return Err(format!("Image sizes differ: {}x{} and {}x{}", test.size.width, test.size.height,
+                           reference.size.width, reference.size.height));
+    }
+    Ok(())
+}
+
+pub fn compare_images(test: &FloatImage, reference: &FloatImage) -> Result<ImageDiff, String> {
+    check_sizes(test, reference)?;
+    let mut se = 0.0f64;
+    let mut rel_se = 0.0f64;
+    for (a, b) in test.pixels.iter().zip(reference.pixels.iter()) {
+        for (va, vb) in [(a.r, b.r), (a.g, b.g), (a.b, b.b)] {
+            let d = (va - vb) as f64;
+            se += d * d;
+            rel_se += d * d / ((vb * vb) as f64 + 0.01);
+        }
+    }
+    let n = (test.pixels.len() * 3).max(1) as f64;
+    let mse = (se / n) as f32;
+    let flip = flip_error_map(test, reference)?;
+    let mean_flip = flip.iter().map(|v| *v as f64).sum::<f64>() / flip.len().max(1) as f64;
+    Ok(ImageDiff { mse, rmse: mse.sqrt(), relative_mse: (rel_se / n) as f32, mean_flip: mean_flip as f32 })
+}
+
+/// Load two images and compare them
+#[cfg(feature = "fs")]
+pub fn diff_images<P: AsRef<Path>>(test: P, reference: P) -> Result<ImageDiff, Box<dyn Error>> {
+    let test = load_image(test)?;
+    let reference = load_image(reference)?;
+    Ok(compare_images(&test, &reference)?)
+}
+
+// Linear sRGB to CIELAB with D65 white point
+fn linear_rgb_to_lab(c: &RGB) -> (f32, f32, f32) {
+    let x = 0.4124564 * c.r + 0.3575761 * c.g + 0.1804375 * c.b;
+    let y = 0.2126729 * c.r + 0.7151522 * c.g + 0.0721750 * c.b;
+    let z = 0.0193339 * c.r + 0.119192 * c.g + 0.9503041 * c.b;
+    fn f(t: f32) -> f32 {
+        const DELTA: f32 = 6.0 / 29.0;
+        if t > DELTA * DELTA * DELTA {
+            t.cbrt()
+        } else {
+            t / (3.0 * DELTA * DELTA) + 4.0 / 29.0
+        }
+    }
+    let (fx, fy, fz) = (f(x / 0.950489), f(y), f(z / 1.08884));
+    (116.0 * fy - 16.0, 500.0 * (fx - fy), 200.0 * (fy - fz))
+}
+
+fn hyab(c1: (f32, f32, f32), c2: (f32, f32, f32)) -> f32 {
+    let (dl, da, db) = (c1.0 - c2.0, c1.1 - c2.1, c1.2 - c2.2);
+    dl.abs() + (da * da + db * db).sqrt()
+}
+
+// 3x3 gaussian prefilter, approximates spatial filtering of human visual system
+fn prefilter(image: &FloatImage) -> Vec<RGB> {
+    const WEIGHTS: [f32; 3] = [0.25, 0.5, 0.25];
+    let (width, height) = (image.size.width as i32, image.size.height as i32);
+    let mut result = Vec::with_capacity(image.pixels.len());
+    for y in 0..height {
+        for x in 0..width {
+            let mut sum = RGB::zero();
+            for (j, wy) in WEIGHTS.iter().enumerate() {
+                for (i, wx) in WEIGHTS.iter().enumerate() {
+                    let sx = (x + i as i32 - 1).clamp(0, width - 1) as usize;
+                    let sy = (y + j as i32 - 1).clamp(0, height - 1) as usize;
+                    sum += image.linear_pixel(sy * width as usize + sx) * (wx * wy);
+                }
+            }
+            result.push(sum);
+        }
+    }
+    result
+}
+
+/// Per pixel perceptual error in range [0, 1]. This follows color pipeline of FLIP
+/// (prefiltering, HyAB distance in CIELAB and error compression), edge and point
+/// features of FLIP are not evaluated.
+pub fn flip_error_map(test: &FloatImage, reference: &FloatImage) -> Result<Vec<f32>, String> {
+    check_sizes(test, reference)?;
+    const QC: f32 = 0.7;
+    const PC: f32 = 0.4;
+    const PT: f32 = 0.95;
+    let green = linear_rgb_to_lab(&RGB::new(0.0, 1.0, 0.0));
+    let blue = linear_rgb_to_lab(&RGB::new(0.0, 0.0, 1.0));
+    let cmax = hyab(green, blue).powf(QC);
+
+    let test = prefilter(test);
+    let reference = prefilter(reference);
+    let errors = test.iter().zip(reference.iter()).map(|(a, b)| {
+        let d = hyab(linear_rgb_to_lab(a), linear_rgb_to_lab(b)).powf(QC);
+        let e = if d < PC * cmax {
+            d * PT / (PC * cmax)
+        } else {
+            PT + (d - PC * cmax) / (cmax - PC * cmax) * (1.0 - PT)
+        };
+        e.min(1.0)
+    }).collect();
+    Ok(errors)
+}
+
+/// Error map stored as grayscale image
+pub fn flip_error_image(test: &FloatImage, reference: &FloatImage) -> Result<RGB8uffer, String> {
+    let errors = flip_error_map(test, reference)?;
+    let pixels = errors.iter().map(|e| {
+        let v = (e * 255.0 + 0.5) as u8;
+        RGB8 { red: v, green: v, blue: v }
+    }).collect();
+    Ok(RGB8uffer::from((test.size.width, pixels)))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn constant_image(value: f32) -> FloatImage {
+        let size = ImageSize::new(4, 4);
+        FloatImage::new(size, vec![RGB::new(value, value, value); 16])
+    }
+
+    #[test]
+    fn image_difference() {
+        let a = constant_image(0.5);
+        let b = constant_image(0.25);
+        let diff = compare_images(&a, &a).unwrap();
+        assert_eq!(diff.mse, 0.0);
+        assert_eq!(diff.mean_flip, 0.0);
+
+        let diff = compare_images(&a, &b).unwrap();
+        assert!((diff.mse - 0.0625).abs() < 1e-6);
+        assert!((diff.rmse - 0.25).abs() < 1e-6);
+        assert!((diff.relative_mse - 0.0625 / 0.0725).abs() < 1e-4);
+        assert!(diff.mean_flip > 0.0 && diff.mean_flip <= 1.0);
+
+        let black = constant_image(0.0);
+        let white = constant_image(1.0);
+        let flip = compare_images(&black, &white).unwrap().mean_flip;
+        assert!(flip > diff.mean_flip);
+
+        let small = FloatImage::new(ImageSize::new(1, 1), vec![RGB::zero()]);
+        assert!(compare_images(&a, &small).is_err());
+    }
+
+    #[test]
+    fn flip_decodes_by_encoding() {
+        let gray = RGB8 { red: 128, green: 128, blue: 128 };
+        let srgb = FloatImage::from(&RGB8uffer::from((4, vec![gray; 16])));
+        assert_eq!(srgb.encoding(), ColorEncoding::SRGB);
+        let linear = constant_image(ColorEncoding::SRGB.to_linear_u8(128));
+        assert!(flip_error_map(&srgb, &linear).unwrap().iter().all(|e| *e < 1e-4));
+        // same values are brighter when they are linear
+        let linear = constant_image(128.0 / 255.0);
+        assert!(flip_error_map(&srgb, &linear).unwrap().iter().all(|e| *e > 0.01));
+    }
+}
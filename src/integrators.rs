@@ -1,62 +1,859 @@
 use crate::vec::{Vec3, Normal};
-use crate::color::{RGB, AccumlationBuffer, PixelSample, AccumlationTileBuffer};
-use crate::shapes::Geometry;
+use crate::color::{RGB, RGBAccumlationBuffer, PixelSample, AccumlationTileBuffer};
+use crate::shapes::{Geometry, SurfaceInteraction};
 use crate::frame::Frame;
-use crate::scene::Scene;
+use crate::scene::{Scene, SceneFormat, parse_scene_description};
 use crate::rgb::RGB8uffer;
 use crate::vec::Point3;
+use crate::filter::Filter;
 use crate::tile::Tile;
 use crate::ray::{Ray, spawn_new_ray};
-use crate::scene::RenderingAlgorithm;
-use crate::scene::AmbientOcclusionProperties;
-use crate::samplings::{sample_cos_hemisphere, sample_uniform_hemisphere};
-use crate::samplers::SamplerInterface;
+use crate::scene::{RenderingAlgorithm, LightStrategy, ShadingNormalSettings, light_layer_fname};
+use crate::scene::{AmbientOcclusionProperties, AmbientOcclusionOutput, IntersectorProperties, PreviewShading, RestirProperties};
+use crate::color::{BufferPrecision, AovBuffers, AovSample, AovType};
+use crate::rgb::ImageSize;
+use std::error::Error;
+use crate::samplings::{sample_cos_hemisphere, sample_uniform_hemisphere, sample_equi_angular};
+use crate::samplers::{SamplerInterface, RandomPathSampler};
 use crate::scene::RandomWalkProperties;
 use crate::samplings::sample_uniform_sphere;
+use crate::wavefront::random_walk_wavefront_integrator;
+use crate::furnace::furnace_integrator;
+use crate::sppm::sppm_integrator;
+use crate::lpe::{Lpe, LpeState, LpeEvent};
+use crate::materials::{BSDFInterface, ScatteringType, HitMaterial};
+use crate::postprocess::finish_image;
+use crate::epsilon::INFINITE_DISTANCE;
+use crate::media::Medium;
+use rayon::{ThreadPool, ThreadPoolBuilder};
+use rayon::prelude::*;
+use crate::exr::TiledExrWriter;
+use std::io::{Seek, Write};
+use std::ops::Range;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use crate::raylog::{RayLog, RayKind};
+use crate::telemetry::SampleOutcome;
+use crate::checkpoint::{Checkpoint, CheckpointOutput};
+use crate::restir::Reservoir;
+use crate::lights::LightSample;
+use crate::path_guiding::PathGuide;
+use crate::bake::bake_integrator;
+use crate::irradiance_cache::{IrradianceCacheProperties, IrradianceRecord, GatherSample, hemisphere_strata, stratum_direction, gather_irradiance};
+use std::time::Instant;
+#[cfg(feature = "fs")]
+use std::fs::File;
+#[cfg(feature = "fs")]
+use std::io::BufWriter;
 
 // AO(p) = 1/pi * integral_{w} V(p, w) * dot(n, w) dw
 pub fn ambient_occlusion_integrator(scene: &Scene, ao_settings: &AmbientOcclusionProperties) -> RGB8uffer {
-    let spp = scene.settings.spp;
+    let (accum, bent_normals, depth) = render_ambient_occlusion(scene, ao_settings);
+    if let (Some(bent_normals), Some(fname)) = (bent_normals, &ao_settings.bent_normal_output) {
+        if let Err(e) = save_image(&bent_normals_to_rgb8_buffer(&bent_normals, scene.settings.resolution), fname) {
+            println!("Error saving bent normals image {}: {:?}", fname, e);
+        }
+    }
+    finish_image(scene, &accum, depth)
+}
+
+/// Render ambient occlusion (and bent normals) as auxiliary pass and save it, it is used only by
+/// integrators that don't accumulate ambient occlusion output in their own tile pass.
+pub fn ambient_occlusion_pass(scene: &Scene, ao_output: &AmbientOcclusionOutput) -> Result<(), Box<dyn Error>> {
+    let image = ambient_occlusion_integrator(scene, &ao_output.settings);
+    save_image(&image, &ao_output.output_fname)
+}
+
+#[cfg(feature = "fs")]
+fn save_image(image: &RGB8uffer, fname: &str) -> Result<(), Box<dyn Error>> {
+    image.save(fname)
+}
+
+#[cfg(not(feature = "fs"))]
+fn save_image(_image: &RGB8uffer, fname: &str) -> Result<(), Box<dyn Error>> {
+    Err(format!("Image {} can't be saved, writing files requires fs feature!", fname).into())
+}
+
+/// Number of camera samples that every pixel received (sample count AOV).
+pub struct SampleCounts {
+    size: ImageSize,
+    counts: Vec<u32>
+}
+
+impl SampleCounts {
+    pub fn new(size: ImageSize) -> Self {
+        Self { size, counts: vec![0; size.width * size.height] }
+    }
+
+    pub fn add(&mut self, x: usize, y: usize) {
+        self.counts[y * self.size.width + x] += 1;
+    }
+
+    pub fn get(&self, x: usize, y: usize) -> u32 {
+        self.counts[y * self.size.width + x]
+    }
+
+    /// Add counts of tile, they have size of the tile.
+    pub fn add_tile(&mut self, tile_counts: &SampleCounts, tile: &Tile) {
+        for (x, y) in *tile {
+            self.counts[y * self.size.width + x] += tile_counts.get(x - tile.x1, y - tile.y1);
+        }
+    }
+
+    /// Total number of samples in pixels of tile
+    pub fn tile_total(&self, tile: &Tile) -> u64 {
+        tile.into_iter().map(|(x, y)| self.get(x, y) as u64).sum()
+    }
+
+    /// Counts are divided by maximum count, so pixel that received most samples is white.
+    pub fn to_rgb8_buffer(&self) -> RGB8uffer {
+        let max = self.counts.iter().copied().max().unwrap_or(0).max(1) as f32;
+        let pixels = self.counts.iter().map(|count| {
+            let value = *count as f32 / max;
+            RGB::new(value, value, value).into()
+        }).collect();
+        RGB8uffer::from((self.size.width, pixels))
+    }
+}
+
+pub fn create_sample_counts(scene: &Scene) -> Option<SampleCounts> {
+    scene.settings.sample_count_output.as_ref().map(|_| SampleCounts::new(scene.settings.resolution))
+}
+
+pub fn save_sample_counts(scene: &Scene, counts: &Option<SampleCounts>) {
+    if let (Some(counts), Some(fname)) = (counts, &scene.settings.sample_count_output) {
+        if let Err(e) = save_image(&counts.to_rgb8_buffer(), fname) {
+            println!("Error saving sample count image {}: {:?}", fname, e);
+        }
+    }
+}
+
+// Saved auxiliary outputs come first, depth is also accumulated for fog even when it isn't saved.
+fn aov_types(scene: &Scene) -> Vec<AovType> {
+    let mut types: Vec<_> = scene.settings.aovs.iter().map(|aov| aov.typ).collect();
+    if scene.settings.fog.is_some() && !types.contains(&AovType::Depth) {
+        types.push(AovType::Depth);
+    }
+    types
+}
+
+pub fn create_aov_buffers(scene: &Scene) -> Option<AovBuffers> {
+    let types = aov_types(scene);
+    if types.is_empty() {
+        return None
+    }
+    Some(AovBuffers::new(scene.settings.resolution, &types, scene.settings.buffer_precision))
+}
+
+/// Ray passes through at most this many medium interfaces, crossings don't count as bounces
+/// of random walk and auxiliary outputs are recorded at first hit behind them
+const MAX_INTERFACE_CROSSINGS: usize = 32;
+
+// First hit of camera ray that is visible in auxiliary outputs and its distance along the ray. isect_p is
+// first hit that integrator found for the ray, ray is intersected again only to pass through medium interfaces.
+fn aov_hit(scene: &Scene, ray: &Ray, isect_p: Option<&SurfaceInteraction>) -> Option<(SurfaceInteraction, f32)> {
+    let mut isect_p = isect_p?.clone();
+    let mut depth = isect_p.t;
+    for _ in 0..MAX_INTERFACE_CROSSINGS {
+        if !scene.material_at(&isect_p).is_interface() {
+            return Some((isect_p, depth))
+        }
+        let next = spawn_new_ray(isect_p.hit_point, isect_p.normal, ray.direction).with_time(ray.time);
+        isect_p = scene.geometry.intersect(&next)?;
+        depth += isect_p.t;
+    }
+    None
+}
+
+fn add_aov_hit(scene: &Scene, aovs: &mut AovBuffers, x: usize, y: usize, isect_p: &SurfaceInteraction, depth: f32) {
+    let outward = |n: Normal| if isect_p.back_side { -n } else { n };
+    let (ng, ns) = (outward(isect_p.normal), outward(isect_p.shading_normal));
+    let sample = AovSample {
+        geometric_normal: RGB::new(ng.x, ng.y, ng.z),
+        shading_normal: RGB::new(ns.x, ns.y, ns.z),
+        depth,
+        albedo: scene.material_at(isect_p).albedo(),
+        material_id: isect_p.material_id
+    };
+    aovs.add(x, y, &sample);
+}
+
+/// Values of auxiliary outputs at first hit of camera ray, isect_p is the hit that integrator found for the ray.
+/// Medium interfaces are invisible in auxiliary outputs, values are taken from first surface behind them.
+pub fn add_aov_sample(scene: &Scene, aovs: &mut Option<AovBuffers>, x: usize, y: usize, ray: &Ray,
+                      isect_p: Option<&SurfaceInteraction>) {
+    let aovs = match aovs.as_mut() {
+        Some(aovs) => aovs,
+        None => return
+    };
+    if let Some((isect_p, depth)) = aov_hit(scene, ray, isect_p) {
+        add_aov_hit(scene, aovs, x, y, &isect_p, depth);
+    }
+}
+
+pub fn save_aovs(scene: &Scene, aovs: &Option<AovBuffers>) {
+    if let Some(aovs) = aovs {
+        for (index, aov) in scene.settings.aovs.iter().enumerate() {
+            if let Err(e) = save_image(&aovs.to_rgb8_buffer(index), &aov.output_fname) {
+                println!("Error saving {:?} image {}: {:?}", aov.typ, aov.output_fname, e);
+            }
+        }
+    }
+}
+
+/// Size of tiles that are rendered in parallel.
+pub const TILE_SIZE: usize = 32;
+
+/// Buffers of image that integrator renders next to the radiance.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TileOutputs {
+    /// Samples are added only to their pixel, reconstruction filter of the scene is not used
+    pub unfiltered: bool,
+    pub aovs: bool,
+    pub light_layers: bool,
+    pub lpes: bool,
+    pub bent_normals: bool,
+    /// Luminances of samples of every pixel are summed, so variance of pixels can be estimated
+    pub variance: bool,
+    /// Rays of pixel of ray log output are recorded by tile that contains the pixel
+    pub ray_log: bool,
+}
+
+/// Sums of luminances of samples of one pixel.
+#[derive(Debug, Clone, Copy, Default)]
+struct LuminanceMoments {
+    count: u32,
+    sum: f32,
+    sum_sqr: f32
+}
+
+impl LuminanceMoments {
+    fn add(&mut self, y: f32) {
+        self.count += 1;
+        self.sum += y;
+        self.sum_sqr += y * y;
+    }
+
+    /// Unbiased sample variance, zero for pixels with less than two samples
+    fn variance(&self) -> f32 {
+        if self.count < 2 {
+            return 0.0
+        }
+        let n = self.count as f32;
+        ((self.sum_sqr - self.sum * self.sum / n) / (n - 1.0)).max(0.0)
+    }
+}
+
+/// Buffers of one tile, pixel coordinates are coordinates of the image. Samples are splatted with
+/// reconstruction filter of the scene, buffers are padded so samples reach neighbouring tiles.
+pub struct TileBuffers<'a> {
+    pub tile: Tile,
+    filter: Option<&'a Filter>,
+    /// Samples per pixel that tile gets, it differs from spp of scene only when tile importance is used
+    pub spp: usize,
+    passes: Range<usize>,
+    pub radiance: AccumlationTileBuffer<PixelSample<RGB>>,
+    pub layers: Vec<AccumlationTileBuffer<PixelSample<RGB>>>,
+    pub lpes: Vec<AccumlationTileBuffer<PixelSample<RGB>>>,
+    pub bent_normals: Option<AccumlationTileBuffer<PixelSample<RGB>>>,
+    sample_counts: Option<SampleCounts>,
+    aovs: Option<AovBuffers>,
+    ao_output: Option<AmbientOcclusionBuffers>,
+    moments: Option<Vec<LuminanceMoments>>,
+    /// Logged pixel and its rays
+    ray_log: Option<(usize, usize, RayLog)>,
+}
+
+// Sampler of ambient occlusion output has its own sequence, so dimensions of integrator don't change.
+const AO_OUTPUT_ITERATION: u32 = u32::MAX;
+
+/// Ambient occlusion output (and its bent normals) that is accumulated next to auxiliary outputs.
+struct AmbientOcclusionBuffers {
+    occlusion: AccumlationTileBuffer<PixelSample<RGB>>,
+    bent_normals: Option<AccumlationTileBuffer<PixelSample<RGB>>>,
+    sampler: RandomPathSampler,
+}
+
+impl<'a> TileBuffers<'a> {
+    pub fn new(scene: &'a Scene, tile: Tile, outputs: &TileOutputs) -> Self {
+        let resolution = scene.settings.resolution;
+        let filter = scene.filter.as_ref().filter(|_| !outputs.unfiltered);
+        let filter_radius = filter.map(|filter| filter.max_radius());
+        let buffer = || AccumlationTileBuffer::new(tile, filter_radius, resolution.width, resolution.height);
+        let layers = if outputs.light_layers { scene.light_layers.names.iter().map(|_| buffer()).collect() } else { Vec::new() };
+        let lpes = if outputs.lpes { scene.lpes.iter().map(|_| buffer()).collect() } else { Vec::new() };
+        let aov_types = aov_types(scene);
+        let aovs = (outputs.aovs && !aov_types.is_empty()).then(|| AovBuffers::new(tile.size(), &aov_types, BufferPrecision::Full));
+        let ao_output = scene.settings.ao_output.as_ref().filter(|_| outputs.aovs).map(|ao_output| {
+            let mut sampler = RandomPathSampler::new(1234567890);
+            sampler.initialize(&tile, AO_OUTPUT_ITERATION);
+            let bent_normals = ao_output.settings.bent_normal_output.as_ref().map(|_| buffer());
+            AmbientOcclusionBuffers { occlusion: buffer(), bent_normals, sampler }
+        });
+        Self {
+            tile,
+            filter,
+            spp: scene.settings.spp,
+            passes: 0..scene.settings.spp,
+            radiance: buffer(),
+            layers,
+            lpes,
+            bent_normals: outputs.bent_normals.then(buffer),
+            sample_counts: scene.settings.sample_count_output.as_ref().map(|_| SampleCounts::new(tile.size())),
+            aovs,
+            ao_output,
+            moments: outputs.variance.then(|| vec![LuminanceMoments::default(); tile.width() * tile.height()]),
+            ray_log: scene.settings.ray_log.as_ref()
+                .filter(|output| outputs.ray_log && (tile.x1..tile.x2).contains(&output.x) && (tile.y1..tile.y2).contains(&output.y))
+                .map(|output| (output.x, output.y, RayLog::new(&output.kinds, ray_log_miss_length(scene))))
+        }
+    }
+
+    /// Ray log when pixel is pixel of ray log output, so logged rays are rays of the rendered samples.
+    pub fn ray_log(&mut self, x: usize, y: usize) -> Option<&mut RayLog> {
+        self.ray_log.as_mut().filter(|(lx, ly, _)| (*lx, *ly) == (x, y)).map(|(_, _, log)| log)
+    }
+
+    /// Weight of sample at offset from center of pixel, every pixel is box of its own samples without filter.
+    pub fn filter_weight(&self) -> impl Fn(f32, f32) -> f32 + 'a {
+        let filter = self.filter;
+        move |x, y| filter.map_or(1.0, |filter| filter.evaluate(x, y))
+    }
+
+    pub fn add(&mut self, x: usize, y: usize, px: f32, py: f32, rgb: &RGB) {
+        let weight = self.filter_weight();
+        self.radiance.add(x, y, px, py, rgb, &weight);
+        if let Some(moments) = self.moments.as_mut() {
+            moments[(y - self.tile.y1) * self.tile.width() + x - self.tile.x1].add(rgb.luminance());
+        }
+    }
+
+    pub fn add_layer(&mut self, index: usize, x: usize, y: usize, px: f32, py: f32, rgb: &RGB) {
+        let weight = self.filter_weight();
+        self.layers[index].add(x, y, px, py, rgb, &weight);
+    }
+
+    pub fn add_lpe(&mut self, index: usize, x: usize, y: usize, px: f32, py: f32, rgb: &RGB) {
+        let weight = self.filter_weight();
+        self.lpes[index].add(x, y, px, py, rgb, &weight);
+    }
+
+    pub fn add_bent_normal(&mut self, x: usize, y: usize, px: f32, py: f32, direction: Vec3) {
+        let weight = self.filter_weight();
+        if let Some(bent_normals) = self.bent_normals.as_mut() {
+            bent_normals.add(x, y, px, py, &RGB::new(direction.x, direction.y, direction.z), &weight);
+        }
+    }
+
+    pub fn add_sample_count(&mut self, x: usize, y: usize) {
+        if let Some(counts) = self.sample_counts.as_mut() {
+            counts.add(x - self.tile.x1, y - self.tile.y1);
+        }
+    }
+
+    /// Auxiliary outputs and ambient occlusion output of camera sample at (px, py), isect_p is first hit of
+    /// the camera ray found by integrator.
+    #[allow(clippy::too_many_arguments)]
+    pub fn add_aov_sample(&mut self, scene: &Scene, x: usize, y: usize, px: f32, py: f32, ray: &Ray,
+                          isect_p: Option<&SurfaceInteraction>) {
+        if self.aovs.is_none() && self.ao_output.is_none() {
+            return
+        }
+        let hit = aov_hit(scene, ray, isect_p);
+        if let (Some(aovs), Some((isect_p, depth))) = (self.aovs.as_mut(), hit.as_ref()) {
+            add_aov_hit(scene, aovs, x - self.tile.x1, y - self.tile.y1, isect_p, *depth);
+        }
+        let weight = self.filter_weight();
+        let (ao_output, ao_buffers) = match (&scene.settings.ao_output, self.ao_output.as_mut()) {
+            (Some(ao_output), Some(ao_buffers)) => (ao_output, ao_buffers),
+            _ => return
+        };
+        let (rgb, direction) = ambient_occlusion_sample(hit.as_ref().map(|(isect_p, _)| isect_p), &scene.geometry,
+                                                        &mut ao_buffers.sampler, &ao_output.settings);
+        ao_buffers.occlusion.add(x, y, px, py, &rgb, &weight);
+        if let Some(bent_normals) = ao_buffers.bent_normals.as_mut() {
+            bent_normals.add(x, y, px, py, &RGB::new(direction.x, direction.y, direction.z), &weight);
+        }
+    }
+
+    fn accumulation_buffers(&self) -> impl Iterator<Item = &AccumlationTileBuffer<PixelSample<RGB>>> {
+        let ao_output = self.ao_output.iter().flat_map(|ao| std::iter::once(&ao.occlusion).chain(ao.bent_normals.iter()));
+        std::iter::once(&self.radiance).chain(self.layers.iter()).chain(self.lpes.iter()).chain(self.bent_normals.iter())
+            .chain(ao_output)
+    }
+
+    fn accumulation_buffers_mut(&mut self) -> impl Iterator<Item = &mut AccumlationTileBuffer<PixelSample<RGB>>> {
+        let ao_output = self.ao_output.iter_mut().flat_map(|ao| std::iter::once(&mut ao.occlusion).chain(ao.bent_normals.iter_mut()));
+        std::iter::once(&mut self.radiance).chain(self.layers.iter_mut()).chain(self.lpes.iter_mut()).chain(self.bent_normals.iter_mut())
+            .chain(ao_output)
+    }
+
+    /// Passes (sample indices of pixels) that integrator renders in current call of tile rendering,
+    /// all samples of the tile are rendered at once unless checkpoint stores passes one by one.
+    pub fn passes(&self) -> Range<usize> {
+        self.passes.clone()
+    }
+
+    /// Samples of accumulation buffers that are stored in checkpoint.
+    fn checkpoint_samples(&self) -> Vec<Vec<PixelSample<RGB>>> {
+        self.accumulation_buffers().map(|buffer| buffer.samples().to_vec()).collect()
+    }
+
+    /// Samples are restored only when checkpoint has same buffers as tile, false is returned otherwise.
+    fn restore_samples(&mut self, samples: &[Vec<PixelSample<RGB>>]) -> bool {
+        let matches = self.accumulation_buffers().count() == samples.len() &&
+            self.accumulation_buffers().zip(samples.iter()).all(|(buffer, s)| buffer.samples().len() == s.len());
+        if matches {
+            for (buffer, s) in self.accumulation_buffers_mut().zip(samples.iter()) {
+                buffer.samples_mut().copy_from_slice(s);
+            }
+        }
+        matches
+    }
+}
+
+/// Buffers of whole image, finished tiles are merged into them.
+pub struct FilmBuffers {
+    pub radiance: RGBAccumlationBuffer,
+    pub layers: Vec<RGBAccumlationBuffer>,
+    pub lpes: Vec<RGBAccumlationBuffer>,
+    pub bent_normals: Option<RGBAccumlationBuffer>,
+    sample_counts: Option<SampleCounts>,
+    aovs: Option<AovBuffers>,
+    /// Ambient occlusion output and its bent normals
+    ao_output: Option<(RGBAccumlationBuffer, Option<RGBAccumlationBuffer>)>,
+    ray_log: Option<RayLog>,
+}
+
+impl FilmBuffers {
+    /// Radiance starts from warm start image of the scene when it is set.
+    pub fn new(scene: &Scene, outputs: &TileOutputs) -> Self {
+        let (resolution, precision) = (scene.settings.resolution, scene.settings.buffer_precision);
+        let layers = if outputs.light_layers { scene.light_layers.names.len() } else { 0 };
+        let lpes = if outputs.lpes { scene.lpes.len() } else { 0 };
+        if let Some(output) = scene.settings.ray_log.as_ref().filter(|_| outputs.ray_log) {
+            if output.x >= resolution.width || output.y >= resolution.height {
+                println!("Ray log pixel ({}, {}) is outside of image!", output.x, output.y);
+            }
+        }
+        let mut radiance = RGBAccumlationBuffer::new(resolution, precision);
+        if let Some(warm_start) = &scene.warm_start {
+            radiance.add_image(&warm_start.pixels, warm_start.weight);
+        }
+        Self {
+            radiance,
+            layers: (0..layers).map(|_| RGBAccumlationBuffer::new(resolution, precision)).collect(),
+            lpes: (0..lpes).map(|_| RGBAccumlationBuffer::new(resolution, precision)).collect(),
+            bent_normals: outputs.bent_normals.then(|| RGBAccumlationBuffer::new(resolution, BufferPrecision::Full)),
+            sample_counts: create_sample_counts(scene),
+            aovs: create_aov_buffers(scene).filter(|_| outputs.aovs),
+            ao_output: scene.settings.ao_output.as_ref().filter(|_| outputs.aovs).map(|ao_output| {
+                let bent_normals = ao_output.settings.bent_normal_output.as_ref()
+                    .map(|_| RGBAccumlationBuffer::new(resolution, BufferPrecision::Full));
+                (RGBAccumlationBuffer::new(resolution, precision), bent_normals)
+            }),
+            ray_log: None
+        }
+    }
+
+    pub fn add_tile(&mut self, buffers: &TileBuffers) {
+        self.radiance.add_accumulation_tile_buffer(&buffers.radiance);
+        for (accum, buffer) in self.layers.iter_mut().zip(buffers.layers.iter()) {
+            accum.add_accumulation_tile_buffer(buffer);
+        }
+        for (accum, buffer) in self.lpes.iter_mut().zip(buffers.lpes.iter()) {
+            accum.add_accumulation_tile_buffer(buffer);
+        }
+        if let (Some(accum), Some(buffer)) = (self.bent_normals.as_mut(), buffers.bent_normals.as_ref()) {
+            accum.add_accumulation_tile_buffer(buffer);
+        }
+        if let (Some(counts), Some(tile_counts)) = (self.sample_counts.as_mut(), buffers.sample_counts.as_ref()) {
+            counts.add_tile(tile_counts, &buffers.tile);
+        }
+        if let (Some(aovs), Some(tile_aovs)) = (self.aovs.as_mut(), buffers.aovs.as_ref()) {
+            aovs.add_tile(tile_aovs, &buffers.tile);
+        }
+        if let (Some((occlusion, bent_normals)), Some(tile_ao)) = (self.ao_output.as_mut(), buffers.ao_output.as_ref()) {
+            occlusion.add_accumulation_tile_buffer(&tile_ao.occlusion);
+            if let (Some(accum), Some(buffer)) = (bent_normals.as_mut(), tile_ao.bent_normals.as_ref()) {
+                accum.add_accumulation_tile_buffer(buffer);
+            }
+        }
+        if let Some((_, _, log)) = &buffers.ray_log {
+            self.ray_log = Some(log.clone());
+        }
+    }
+
+    /// Depth of pixels when it is accumulated with auxiliary outputs, see AovBuffers::depth.
+    pub fn depth(&self) -> Option<Vec<f32>> {
+        self.aovs.as_ref().and_then(|aovs| aovs.depth())
+    }
+
+    /// Save sample counts, auxiliary outputs, light layers, light path expressions and ray log.
+    pub fn save_outputs(&self, scene: &Scene) {
+        save_sample_counts(scene, &self.sample_counts);
+        if let (Some(log), Some(output)) = (&self.ray_log, &scene.settings.ray_log) {
+            if let Err(e) = log.save(&output.output_fname) {
+                println!("Error saving ray log {}: {:?}", output.output_fname, e);
+            }
+        }
+        save_aovs(scene, &self.aovs);
+        if let (Some((occlusion, bent_normals)), Some(ao_output)) = (&self.ao_output, &scene.settings.ao_output) {
+            if let Err(e) = save_image(&occlusion.to_rgb8_buffer(&scene.settings.tonemap), &ao_output.output_fname) {
+                println!("Error saving ambient occlusion image {}: {:?}", ao_output.output_fname, e);
+            }
+            if let (Some(bent_normals), Some(fname)) = (bent_normals, &ao_output.settings.bent_normal_output) {
+                if let Err(e) = save_image(&bent_normals_to_rgb8_buffer(bent_normals, scene.settings.resolution), fname) {
+                    println!("Error saving bent normals image {}: {:?}", fname, e);
+                }
+            }
+        }
+        for (accum, lpe_output) in self.lpes.iter().zip(scene.settings.lpes.iter()) {
+            if let Err(e) = save_image(&accum.to_rgb8_buffer(&scene.settings.tonemap), &lpe_output.output_fname) {
+                println!("Error saving light path expression {} image: {:?}", lpe_output.expression, e);
+            }
+        }
+        for (accum, name) in self.layers.iter().zip(scene.light_layers.names.iter()) {
+            let fname = light_layer_fname(&scene.settings.output_fname, name);
+            if let Err(e) = save_image(&accum.to_rgb8_buffer(&scene.settings.tonemap), &fname) {
+                println!("Error saving light layer {} image {}: {:?}", name, fname, e);
+            }
+        }
+    }
+}
+
+fn render_tile_buffers<'a, F>(scene: &'a Scene, tile: Tile, spp: usize, outputs: &TileOutputs, render_tile: &F) -> TileBuffers<'a>
+where F: Fn(&mut TileBuffers, &mut Box<dyn SamplerInterface>) + Sync {
+    let mut buffers = TileBuffers::new(scene, tile, outputs);
+    buffers.spp = spp;
+    if scene.cancel_token.is_cancelled() {
+        return buffers
+    }
+    render_tile_passes(&mut buffers, 0..spp, scene, render_tile);
+    buffers
+}
+
+// Sampler of the tile starts from the first pass, so passes can be rendered separately.
+fn render_tile_passes<F>(buffers: &mut TileBuffers, passes: Range<usize>, scene: &Scene, render_tile: &F)
+where F: Fn(&mut TileBuffers, &mut Box<dyn SamplerInterface>) + Sync {
+    let mut sampler = scene.sampler.clone_for_tile(&buffers.tile, passes.start as u32);
+    buffers.passes = passes;
+    render_tile(buffers, &mut sampler);
+}
+
+/// Average standard deviation of luminance of samples of pixels of the tile. It is large for noisy tiles
+/// and zero for tiles whose pixels have constant value, also for sharp edges that aren't noisy.
+fn tile_deviation(buffers: &TileBuffers) -> f32 {
+    let moments = match &buffers.moments {
+        Some(moments) if !moments.is_empty() => moments,
+        _ => return 0.0
+    };
+    moments.iter().map(|m| m.variance().sqrt()).sum::<f32>() / moments.len() as f32
+}
+
+/// Samples per pixel of tiles, every tile gets min_spp (at least one) and rest of the budget (spp in every
+/// pixel) is distributed proportionally to standard deviations of tiles, that is optimal allocation of
+/// stratified sampling. Without any variance every tile gets spp.
+pub fn allocate_tile_samples(tiles: &[Tile], deviations: &[f32], spp: usize, min_spp: usize) -> Vec<usize> {
+    let min_spp = min_spp.max(1).min(spp);
+    let pixels = |tile: &Tile| (tile.width() * tile.height()) as f32;
+    let weighted: f32 = tiles.iter().zip(deviations.iter()).map(|(tile, d)| pixels(tile) * d).sum();
+    if weighted <= 0.0 || !weighted.is_finite() {
+        return vec![spp; tiles.len()]
+    }
+    let total: f32 = tiles.iter().map(pixels).sum();
+    let budget = (spp - min_spp) as f32 * total;
+    deviations.iter().map(|d| min_spp + (budget * d / weighted).round() as usize).collect()
+}
+
+// Pre-pass is rendered with its own sampler sequence and without auxiliary outputs and filter, only
+// variance of samples of pixels is used. Irradiance cache records of pre-pass are dropped, so they
+// don't end in the image.
+fn tile_samples<F>(scene: &Scene, tiles: &[Tile], render_tile: &F) -> Vec<usize>
+where F: Fn(&mut TileBuffers, &mut Box<dyn SamplerInterface>) + Sync {
+    let settings = match &scene.settings.tile_importance {
+        Some(settings) => settings,
+        None => return vec![scene.settings.spp; tiles.len()]
+    };
+    let deviations: Vec<_> = tiles.par_iter().map(|tile| {
+        let mut buffers = TileBuffers::new(scene, *tile, &TileOutputs { unfiltered: true, variance: true, ..Default::default() });
+        buffers.spp = settings.prepass_spp.max(2);
+        buffers.passes = 0..buffers.spp;
+        let mut sampler = scene.sampler.clone_for_tile(tile, 1);
+        render_tile(&mut buffers, &mut sampler);
+        tile_deviation(&buffers)
+    }).collect();
+    if let Some(cache) = &scene.irradiance_cache {
+        cache.clear();
+    }
+    if scene.cancel_token.is_cancelled() {
+        return vec![scene.settings.spp; tiles.len()]
+    }
+    allocate_tile_samples(tiles, &deviations, scene.settings.spp, settings.min_spp)
+}
+
+/// Image is split to tiles of TILE_SIZE that are rendered in parallel in current rayon thread pool,
+/// every tile has its own sampler initialized for the tile. Finished tiles are merged in order of tiles,
+/// so image doesn't depend on number of threads. Tiles are written to bucket output instead when it is set.
+/// When rendering is cancelled remaining tiles are skipped and rendering of started tiles stops after
+/// current pass, so pixels keep samples of finished passes. When checkpoint is set tiles are rendered
+/// pass by pass and finished passes are stored in it, passes of resumed checkpoint are not rendered
+/// again. Sampler of the tile then starts from every pass, so image differs from image rendered
+/// without checkpoint by random numbers, not by expected value. With tile importance
+/// samples per pixel of tiles are estimated by pre-pass (see TileImportanceProperties).
+pub fn render_tiles<F>(scene: &Scene, outputs: TileOutputs, render_tile: F) -> FilmBuffers
+where F: Fn(&mut TileBuffers, &mut Box<dyn SamplerInterface>) + Sync {
+    let resolution = scene.settings.resolution;
+    let tiles = Tile::new(0, 0, resolution.width, resolution.height).split(TILE_SIZE, TILE_SIZE);
+    let tile_spp = tile_samples(scene, &tiles, &render_tile);
+    if let Some(fname) = &scene.settings.bucket_output {
+        match create_bucket_writer(fname, resolution) {
+            Ok(writer) => return render_buckets(scene, &outputs, tiles, &tile_spp, writer, &render_tile),
+            Err(e) => println!("Error creating bucket image {}, whole image is rendered: {:?}", fname, e)
+        }
+    }
+    let output = match &scene.settings.checkpoint {
+        Some(output) => output,
+        None => {
+            let finished: Vec<_> = tiles.into_par_iter().zip(tile_spp).map(|(tile, spp)| {
+                render_tile_buffers(scene, tile, spp, &outputs, &render_tile)
+            }).collect();
+            return merge_tiles(scene, &outputs, &finished)
+        }
+    };
+    let resumed = resume_checkpoint(scene, output, tiles.len());
+    // checkpoint, time of last write and number of snapshots taken for writing
+    let checkpoint = Mutex::new((resumed.clone(), Instant::now(), 0));
+    let written = Mutex::new(0);
+    let finished: Vec<_> = tiles.into_par_iter().enumerate().map(|(index, tile)| {
+        let mut buffers = TileBuffers::new(scene, tile, &outputs);
+        buffers.spp = tile_spp[index];
+        let mut first_pass = 0;
+        if let Some((passes, samples)) = resumed.tile(index) {
+            if buffers.restore_samples(samples) {
+                first_pass = passes.min(buffers.spp);
+            }
+        }
+        for pass in first_pass..buffers.spp {
+            if scene.cancel_token.is_cancelled() {
+                break;
+            }
+            render_tile_passes(&mut buffers, pass..pass + 1, scene, &render_tile);
+            // pass that was rendered while rendering was cancelled may be unfinished
+            if scene.cancel_token.is_cancelled() {
+                break;
+            }
+            let snapshot = {
+                let mut checkpoint = checkpoint.lock().unwrap();
+                checkpoint.0.add_tile(index, pass + 1, buffers.checkpoint_samples());
+                if checkpoint.1.elapsed().as_secs_f32() >= output.interval {
+                    checkpoint.1 = Instant::now();
+                    checkpoint.2 += 1;
+                    Some((checkpoint.2, checkpoint.0.clone()))
+                } else {
+                    None
+                }
+            };
+            // NOTE: file is written outside of lock of checkpoint, snapshot older than written one is dropped
+            if let Some((number, snapshot)) = snapshot {
+                let mut written = written.lock().unwrap();
+                if number > *written {
+                    save_checkpoint(&snapshot, &output.output_fname);
+                    *written = number;
+                }
+            }
+        }
+        buffers
+    }).collect();
+    save_checkpoint(&checkpoint.into_inner().unwrap().0, &output.output_fname);
+    merge_tiles(scene, &outputs, &finished)
+}
+
+fn merge_tiles(scene: &Scene, outputs: &TileOutputs, finished: &[TileBuffers]) -> FilmBuffers {
+    let mut film = FilmBuffers::new(scene, outputs);
+    for buffers in finished.iter() {
+        film.add_tile(buffers);
+    }
+    film
+}
+
+fn resume_checkpoint(scene: &Scene, output: &CheckpointOutput, ntiles: usize) -> Checkpoint {
+    let (resolution, spp) = (scene.settings.resolution, scene.settings.spp);
+    if output.resume {
+        match Checkpoint::load(&output.output_fname) {
+            Ok(checkpoint) if checkpoint.is_compatible(resolution, spp, ntiles, scene.fingerprint) => return checkpoint,
+            Ok(_) => println!("Checkpoint {} doesn't match rendering, whole image is rendered!", output.output_fname),
+            Err(e) => println!("Error loading checkpoint {}, whole image is rendered: {:?}", output.output_fname, e)
+        }
+    }
+    Checkpoint::new(resolution, spp, ntiles, scene.fingerprint)
+}
+
+fn save_checkpoint(checkpoint: &Checkpoint, fname: &str) {
+    if let Err(e) = checkpoint.save(fname) {
+        println!("Error saving checkpoint {}: {:?}", fname, e);
+    }
+}
+
+#[cfg(feature = "fs")]
+fn create_bucket_writer(fname: &str, size: ImageSize) -> Result<TiledExrWriter<BufWriter<File>>, Box<dyn Error>> {
+    TiledExrWriter::create(fname, size, TILE_SIZE)
+}
+
+#[cfg(not(feature = "fs"))]
+fn create_bucket_writer(fname: &str, _size: ImageSize) -> Result<TiledExrWriter<std::io::Cursor<Vec<u8>>>, Box<dyn Error>> {
+    Err(format!("Image {} can't be saved, writing files requires fs feature!", fname).into())
+}
+
+// Every finished tile is written and dropped, only radiance is rendered. Filter splats samples also
+// to neighbouring tiles, so tile is merged into pending buffers of its neighbours (and itself) and
+// tile is written when all tiles that splat into it are finished.
+fn render_buckets<F, W>(scene: &Scene, outputs: &TileOutputs, tiles: Vec<Tile>, tile_spp: &[usize],
+                        writer: TiledExrWriter<W>, render_tile: &F) -> FilmBuffers
+where F: Fn(&mut TileBuffers, &mut Box<dyn SamplerInterface>) + Sync, W: Write + Seek + Send {
     let resolution = scene.settings.resolution;
-    let camera = &scene.camera;
-    let geometry = &scene.geometry;
-    let tile = Tile::new(0, 0, resolution.width, resolution.height);
-    let mut accum = AccumlationBuffer::<PixelSample<RGB>>::new(tile.size());
-    let cossample = ao_settings.cossample;
-    let maxdistance = ao_settings.maxdistance;
-    let mut sampler = scene.sampler.create_sampler();
-    sampler.initialize(&tile, 0);
-
-    for i in 0..spp {
-        for (x, y) in tile {
-            let (sx, sy) = sampler.sample_pixel(x, y, i);
-            let px = x as f32 + sx;
-            let py = y as f32 + sy;
-            let ray = camera.generate_ray(px, py);
-            let rgb = ambient_occlusion(&ray, geometry, &mut sampler, cossample, maxdistance);
-            accum.add(x, y, &rgb);
-        } 
-    }
-    accum.to_rgb8_buffer(&scene.settings.tonemap)
+    let preview_size = ImageSize::new(resolution.width.div_ceil(TILE_SIZE), resolution.height.div_ceil(TILE_SIZE));
+    let preview = Mutex::new(RGBAccumlationBuffer::new(preview_size, BufferPrecision::Full));
+    let writer = Mutex::new(writer);
+    let fname = scene.settings.bucket_output.as_deref().unwrap_or_default();
+    let outputs = TileOutputs { unfiltered: outputs.unfiltered, ray_log: outputs.ray_log, ..Default::default() };
+    let filter_radius = scene.filter.as_ref().filter(|_| !outputs.unfiltered).map(|filter| filter.max_radius());
+    // tiles within reach of the filter, tiles are split row by row
+    let reach = AccumlationTileBuffer::<PixelSample<RGB>>::padding(filter_radius).div_ceil(TILE_SIZE);
+    let neighbours = |index: usize| {
+        let (column, row) = (index % preview_size.width, index / preview_size.width);
+        let columns = column.saturating_sub(reach)..(column + reach + 1).min(preview_size.width);
+        let rows = row.saturating_sub(reach)..(row + reach + 1).min(preview_size.height);
+        rows.flat_map(move |r| columns.clone().map(move |c| r * preview_size.width + c))
+    };
+    // merged buffer of the tile and number of its neighbours that aren't merged yet
+    let pending = Mutex::new(HashMap::new());
+    let ray_log = Mutex::new(None);
+    (0..tiles.len()).into_par_iter().for_each(|index| {
+        let mut buffers = render_tile_buffers(scene, tiles[index], tile_spp[index], &outputs, render_tile);
+        if let Some((_, _, log)) = buffers.ray_log.take() {
+            *ray_log.lock().unwrap() = Some(log);
+        }
+        let mut finished = Vec::new();
+        {
+            let mut pending = pending.lock().unwrap();
+            for neighbour in neighbours(index) {
+                let (buffer, remaining) = pending.entry(neighbour).or_insert_with(|| {
+                    let buffer = AccumlationTileBuffer::new(tiles[neighbour], filter_radius, resolution.width, resolution.height);
+                    (buffer, neighbours(neighbour).count())
+                });
+                buffer.add_tile_buffer(&buffers.radiance);
+                *remaining -= 1;
+                if *remaining == 0 {
+                    finished.push((tiles[neighbour], pending.remove(&neighbour).unwrap().0));
+                }
+            }
+        }
+        for (tile, buffer) in finished {
+            let pixels = buffer.resolve();
+            let average = pixels.iter().fold(RGB::zero(), |acc, p| acc + *p) * (pixels.len() as f32).recip();
+            preview.lock().unwrap().add(tile.x1 / TILE_SIZE, tile.y1 / TILE_SIZE, &average);
+            if let Err(e) = writer.lock().unwrap().write_tile(&tile, &pixels) {
+                println!("Error writing tile {:?} to bucket image {}: {:?}", tile, fname, e);
+            }
+        }
+    });
+    if let Err(e) = writer.into_inner().unwrap().finish() {
+        println!("Error writing bucket image {}: {:?}", fname, e);
+    }
+    FilmBuffers {
+        radiance: preview.into_inner().unwrap(),
+        layers: Vec::new(),
+        lpes: Vec::new(),
+        bent_normals: None,
+        sample_counts: None,
+        aovs: None,
+        ao_output: None,
+        ray_log: ray_log.into_inner().unwrap()
+    }
+}
+
+// Returns ambient occlusion, bent normals and depth of pixels.
+fn render_ambient_occlusion(scene: &Scene, ao_settings: &AmbientOcclusionProperties)
+    -> (RGBAccumlationBuffer, Option<RGBAccumlationBuffer>, Option<Vec<f32>>) {
+    let outputs = TileOutputs { aovs: true, bent_normals: ao_settings.bent_normal_output.is_some(), ..Default::default() };
+    let film = render_tiles(scene, outputs, |buffers, sampler| {
+        for i in buffers.passes() {
+            if scene.cancel_token.is_cancelled() {
+                break;
+            }
+            for (x, y) in buffers.tile {
+                let (sx, sy) = sampler.sample_pixel(x, y, i);
+                buffers.add_sample_count(x, y);
+                let px = x as f32 + sx;
+                let py = y as f32 + sy;
+                let ray = scene.camera.generate_ray_at_time(px, py, sampler.sample_time());
+                let isect_p = scene.geometry.intersect(&ray);
+                buffers.add_aov_sample(scene, x, y, px, py, &ray, isect_p.as_ref());
+                let (rgb, direction) = ambient_occlusion_sample(isect_p.as_ref(), &scene.geometry, sampler.as_mut(), ao_settings);
+                buffers.add(x, y, px, py, &rgb);
+                buffers.add_bent_normal(x, y, px, py, direction);
+            }
+        }
+    });
+    film.save_outputs(scene);
+    let depth = film.depth();
+    (film.radiance, film.bent_normals, depth)
+}
+
+// Average of unoccluded directions is normalized and stored as n * 0.5 + 0.5
+fn bent_normals_to_rgb8_buffer(bent_normals: &RGBAccumlationBuffer, resolution: ImageSize) -> RGB8uffer {
+    let mut image = RGB8uffer::new(resolution);
+    for y in 0..resolution.height {
+        for x in 0..resolution.width {
+            let direction = match bent_normals.get(x, y) {
+                Some(sample) => Vec3::new(sample.spectrum.r, sample.spectrum.g, sample.spectrum.b),
+                None => continue
+            };
+            let n = if direction.length_sqr() > 0.0 { direction.normalize() } else { direction };
+            let rgb = RGB::new(n.x * 0.5 + 0.5, n.y * 0.5 + 0.5, n.z * 0.5 + 0.5);
+            image.set(x, y, &rgb.into());
+        }
+    }
+    image
 }
 
 pub fn ambient_occlusion(ray: &Ray, shapes: &Geometry, sampler: &mut Box<dyn SamplerInterface>,
-                         cossample: bool, maxdistance: f32) -> RGB {
-    
-    let result = shapes.intersect(ray);
-    let si = match result {
+                         ao_settings: &AmbientOcclusionProperties) -> RGB {
+    ambient_occlusion_sample(shapes.intersect(ray).as_ref(), shapes, sampler.as_mut(), ao_settings).0
+}
+
+// Returns ambient occlusion estimate at hit of camera ray and average of unoccluded sampled directions.
+fn ambient_occlusion_sample(si: Option<&SurfaceInteraction>, shapes: &Geometry, sampler: &mut dyn SamplerInterface,
+                            ao_settings: &AmbientOcclusionProperties) -> (RGB, Vec3) {
+    let si = match si {
         Some(si) => si,
-        None => return RGB::new(1.0, 1.0, 1.0)
+        None => return (RGB::new(1.0, 1.0, 1.0), Vec3::new(0.0, 0.0, 0.0))
     };
+    if ao_settings.nsamples <= 1 {
+        let (u, v) = sampler.next_2d();
+        return occlusion_at_hit(si, shapes, u, v, ao_settings.cossample, ao_settings.maxdistance);
+    }
 
-    let (u, v) = sampler.next_2d();
+    let samples = sampler.next_2d_array(ao_settings.nsamples);
+    let (rgb, direction) = samples.iter().fold((RGB::zero(), Vec3::new(0.0, 0.0, 0.0)), |(rgb, dir), (u, v)| {
+        let (r, d) = occlusion_at_hit(si, shapes, *u, *v, ao_settings.cossample, ao_settings.maxdistance);
+        (rgb + r, dir + d)
+    });
+    let inv_n = (samples.len() as f32).recip();
+    (rgb * inv_n, direction * inv_n)
+}
+
+fn occlusion_at_hit(si: &SurfaceInteraction, shapes: &Geometry, u: f32, v: f32,
+                    cossample: bool, maxdistance: f32) -> (RGB, Vec3) {
+    let occluded = Vec3::new(0.0, 0.0, 0.0);
     let sample_dir = if cossample {
         sample_cos_hemisphere(u, v)
     } else {
         sample_uniform_hemisphere(u, v)
     };
     if sample_dir.pdfw == 0.0 {
-        return RGB::zero();
+        return (RGB::zero(), occluded);
     }
 
     let new_direction = Frame::from(si.normal).to_world(sample_dir.direction).normalize();
@@ -75,26 +872,58 @@ pub fn ambient_occlusion(ray: &Ray, shapes: &Geometry, sampler: &mut Box<dyn Sam
     match shadow_result {
         Some(res) => {
             if res.t < maxdistance {
-                return RGB::zero();
+                return (RGB::zero(), occluded);
             }
-            calc_result(new_direction, si.normal, sample_dir.pdfw)
+            (calc_result(new_direction, si.normal, sample_dir.pdfw), new_direction)
         },
-        None => calc_result(new_direction, si.normal, sample_dir.pdfw)
+        None => (calc_result(new_direction, si.normal, sample_dir.pdfw), new_direction)
     }
 }
 
 
-fn visible(p1: Point3, normal: Normal, p2: Point3, shapes: &Geometry) -> bool {
+pub fn visible(p1: Point3, normal: Normal, p2: Point3, shapes: &Geometry) -> bool {
     let new_direction = (p2 - p1).normalize();
     let shadow_ray = crate::ray::spawn_new_ray(p1, normal, new_direction);
     let result = shapes.intersect(&shadow_ray);
     let distance = shadow_ray.origin.distance(p2);
     match result {
-        Some(si) => si.t > distance,
+        Some(si) => si.t > shapes.epsilon_policy().shadow_tmax(distance),
         None => true
     }
 }
 
+/// Number of transparent surfaces that shadow ray passes before Russian roulette is played
+const SHADOW_RR_DEPTH: usize = 3;
+/// Shadow ray is treated as occluded after this many transparent surfaces
+const MAX_SHADOW_SURFACES: usize = 32;
+
+/// Transmittance of shadow ray between points, surfaces of transparent materials attenuate the ray and
+/// opaque surface occludes it. Russian roulette terminates rays that pass through many surfaces, so
+/// sampler is used only when shadow ray crosses more than few transparent surfaces.
+pub fn transmittance(p1: Point3, normal: Normal, p2: Point3, scene: &Scene, sampler: &mut Box<dyn SamplerInterface>) -> RGB {
+    let direction = (p2 - p1).normalize();
+    let mut ray = spawn_new_ray(p1, normal, direction);
+    let mut throughput = RGB::new(1.0, 1.0, 1.0);
+    for depth in 0..MAX_SHADOW_SURFACES {
+        let tmax = scene.geometry.epsilon_policy().shadow_tmax(ray.origin.distance(p2));
+        let isect_p = match scene.geometry.intersect(&ray) {
+            Some(isect_p) if isect_p.t <= tmax => isect_p,
+            _ => return throughput
+        };
+        let material = scene.material_at(&isect_p);
+        let transmitted = match material.transmittance(-direction, isect_p.normal) {
+            Some(transmitted) if !transmitted.is_black() => transmitted,
+            _ => return RGB::zero()
+        };
+        throughput = match russian_roulette(throughput * transmitted, depth, SHADOW_RR_DEPTH, sampler) {
+            Some(throughput) => throughput,
+            None => return RGB::zero()
+        };
+        ray = spawn_new_ray(isect_p.hit_point, isect_p.normal, direction);
+    }
+    RGB::zero()
+}
+
 pub fn pdfw_to_a(pdfw: f32, dist: f32, cos_there: f32) -> f32 {
     pdfw * cos_there.abs() / (dist * dist)
 }
@@ -104,159 +933,1163 @@ pub fn pdfa_to_w(pdfa: f32, dist: f32, cos_there: f32) -> f32 {
 }
 
 pub fn direct_lgt_integrator(scene: &Scene) -> RGB8uffer {
-    let spp = scene.settings.spp;
-    let resolution = scene.settings.resolution;
-    let camera = &scene.camera;
-    let tile = Tile::new(0, 0, resolution.width, resolution.height);
-    let mut accum = AccumlationBuffer::<PixelSample<RGB>>::new(tile.size());
-    let mut sampler = scene.sampler.create_sampler();
-    sampler.initialize(&tile, 0);
-
-    for i in 0..spp {
-        for (x, y) in tile {
-            let (sx, sy) = sampler.sample_pixel(x, y, i);
-            let px = x as f32 + sx;
-            let py = y as f32 + sy;
-            let ray = camera.generate_ray(px, py);
-            let rgb = radiance_direct_lgt(&ray, scene, &mut sampler);
-            if x == 512 && y == 0 {
-                println!("rgb: {:?}", rgb);
-                let bb = rgb;
-                println!("bb: {:?}", bb);
-            }
-            accum.add(x, y, &rgb);
-        } 
-    }
-    accum.to_rgb8_buffer(&scene.settings.tonemap)
-}
-
-pub fn radiance_direct_lgt (ray: &Ray, scene: &Scene, _sampler: &mut Box<dyn SamplerInterface>) -> RGB {
+    if let Some(cache) = &scene.irradiance_cache {
+        cache.clear();
+    }
+    let outputs = TileOutputs { aovs: true, light_layers: true, ray_log: true, ..Default::default() };
+    let film = render_tiles(scene, outputs, |buffers, sampler| {
+        for i in buffers.passes() {
+            if scene.cancel_token.is_cancelled() {
+                break;
+            }
+            if let LightStrategy::Restir(settings) = scene.settings.light_strategy {
+                if settings.spatial_samples > 0 {
+                    restir_pass(scene, &settings, buffers, sampler, i);
+                    continue;
+                }
+            }
+            for (x, y) in buffers.tile {
+                let (sx, sy) = sampler.sample_pixel(x, y, i);
+                buffers.add_sample_count(x, y);
+                let px = x as f32 + sx;
+                let py = y as f32 + sy;
+                let ray = scene.camera.generate_ray_at_time(px, py, sampler.sample_time());
+                let isect_p = scene.geometry.intersect(&ray);
+                buffers.add_aov_sample(scene, x, y, px, py, &ray, isect_p.as_ref());
+                let mut layers = vec![RGB::zero(); scene.light_layers.len()];
+                let rgb = direct_lighting_at(&ray, isect_p, scene, sampler, &mut layers, buffers.ray_log(x, y));
+                let scale = clamp_scale(&rgb, scene.settings.max_component);
+                for (index, value) in layers.iter().enumerate() {
+                    buffers.add_layer(index, x, y, px, py, &(*value * scale));
+                }
+                buffers.add(x, y, px, py, &(rgb * scale));
+            }
+        }
+    });
+    film.save_outputs(scene);
+    finish_image(scene, &film.radiance, film.depth())
+}
+
+// NOTE: preview shades one ray through center of every pixel, so it isn't blurred by reconstruction filter
+pub fn intersector_integrator(scene: &Scene, settings: &IntersectorProperties) -> RGB8uffer {
+    let film = render_tiles(scene, TileOutputs { unfiltered: true, ..Default::default() }, |buffers, _sampler| {
+        for (x, y) in buffers.tile {
+            let ray = scene.camera.generate_ray(x as f32 + 0.5, y as f32 + 0.5);
+            let rgb = primary_hit_shading(&ray, scene, settings.shading);
+            buffers.add(x, y, x as f32 + 0.5, y as f32 + 0.5, &rgb);
+        }
+    });
+    finish_image(scene, &film.radiance, film.depth())
+}
+
+pub fn primary_hit_shading(ray: &Ray, scene: &Scene, shading: PreviewShading) -> RGB {
     let isect_p = match scene.geometry.intersect(ray) {
         Some(isect_p) => isect_p,
         None => return RGB::zero()
     };
-
-    let wo = -ray.direction;
-    let mut acum = RGB::zero();
-
-    for light in scene.lights.iter() {
-        let ls = light.illuminate(isect_p.hit_point);
-        let ls = match ls {
-            Some(ls) => ls,
-            None => continue
-        };
-        if visible(isect_p.hit_point, isect_p.normal, ls.position, &scene.geometry) {
-            let material = &scene.materials[isect_p.material_id as usize];
-            let result = material.eval(wo, isect_p.normal, ls.wi);
-            let (mat_spectrum, _pdfw) = match result {
-                Some(result) => (result.color, result.pdfw),
-                None => continue
-            };
-            let cosa = (ls.wi * isect_p.normal).abs();
-            let dist = isect_p.hit_point.distance(ls.position);
-            let pdf = pdfa_to_w(ls.pdfa, dist, ls.cos_theta);
-            acum += (mat_spectrum * ls.intensity) * (cosa / pdf);
-        }
+    let facing = (ray.direction * isect_p.normal).abs();
+    match shading {
+        PreviewShading::Facing => RGB::new(facing, facing, facing),
+        PreviewShading::Albedo => scene.material_at(&isect_p).albedo() * facing
     }
-    acum
 }
 
-pub fn random_walk_integrator(scene: &Scene, rw_settings: &RandomWalkProperties) -> RGB8uffer {
-    let spp = scene.settings.spp;
-    let resolution = scene.settings.resolution;
-    let camera = &scene.camera;
-    let tile = Tile::new(0, 0, resolution.width, resolution.height);
-    let mut accum = AccumlationBuffer::<PixelSample<RGB>>::new(tile.size());
-    let filter_radius = match &scene.filter {
-        Some(filter) => Some(filter.max_radius()),
-        None => None
-    };
-    let mut tile_buffer = AccumlationTileBuffer::<PixelSample<RGB>>::new(tile, filter_radius, resolution.width, resolution.height);
-    let maxdepth = rw_settings.maxdepth;
-    let mut sampler = scene.sampler.create_sampler();
-    sampler.initialize(&tile, 0);
-
-    let calc_weight = |x: f32, y: f32| -> f32 {
-       match &scene.filter {
-        Some(filter) => filter.evaluate(x, y),
-        None => 1.0
-       }
-    };
+pub fn radiance_direct_lgt (ray: &Ray, scene: &Scene, sampler: &mut Box<dyn SamplerInterface>) -> RGB {
+    direct_lighting(ray, scene, sampler, &mut [], None)
+}
 
-    for i in 0..spp {
-        for (x, y) in tile {
-            let (sx, sy) = sampler.sample_pixel(x, y, i);
-            let px = x as f32 + sx;
-            let py = y as f32 + sy;
-            let ray = camera.generate_ray(px, py);
-            let rgb = random_walk(&ray, scene, &mut sampler, 0, maxdepth);
-            // accum.add(x, y, &rgb);
-            tile_buffer.add(x, y, px, py, &rgb, &calc_weight);
-        } 
-    }
-    accum.add_accumulation_tile_buffer(&tile_buffer);
-    accum.to_rgb8_buffer(&scene.settings.tonemap)
+pub fn radiance_random_walk(ray: &Ray, scene: &Scene, sampler: &mut Box<dyn SamplerInterface>,
+                            rw_settings: &RandomWalkProperties) -> RGB {
+    random_walk(ray, scene, sampler, rw_settings, None, &mut [], None)
 }
 
-fn random_walk(ray: &Ray, scene: &Scene, sampler: &mut Box<dyn SamplerInterface>, depth: usize, maxdepth: usize) -> RGB {
-    // TODO: return radiance from inifinite light sources
-    let isect_p = match scene.geometry.intersect(ray) {
-        Some(isect_p) => isect_p,
+// Contribution of every light is also added to its light layer, layers are empty when scene doesn't use them.
+// Lights can't be sampled at specular surfaces, so ray follows specular bounces to first non-specular hit.
+fn direct_lighting(ray: &Ray, scene: &Scene, sampler: &mut Box<dyn SamplerInterface>, layers: &mut [RGB],
+                   log: Option<&mut RayLog>) -> RGB {
+    direct_lighting_at(ray, scene.geometry.intersect(ray), scene, sampler, layers, log)
+}
+
+// Direct lighting of camera ray whose first hit isect_p was already found.
+fn direct_lighting_at(ray: &Ray, isect_p: Option<SurfaceInteraction>, scene: &Scene, sampler: &mut Box<dyn SamplerInterface>,
+                      layers: &mut [RGB], mut log: Option<&mut RayLog>) -> RGB {
+    let (ray, hit, throughput) = match specular_chain(ray, isect_p, scene, sampler, log.as_deref_mut()) {
+        Some(result) => result,
         None => return RGB::zero()
     };
+    let (isect_p, material) = match hit {
+        Some(hit) => hit,
+        None => {
+            let radiance = missed_radiance(&ray, scene, layers);
+            scale_layers(layers, throughput);
+            return radiance * throughput
+        }
+    };
 
-    let material = &scene.materials[isect_p.material_id as usize];
     let wo = -ray.direction;
-    let le = material.emssion(wo, isect_p.normal, isect_p.back_side);
-
-    if depth == maxdepth {
-        return le;
-    }
+    let mut acum = RGB::zero();
+    let nlights = scene.lights.len();
+    let mis = scene.settings.mis;
 
+    let mut add_light = |index: usize, pmf: f32, sampler: &mut Box<dyn SamplerInterface>, log: Option<&mut RayLog>| {
+        let mis_pmf = if mis { Some(pmf) } else { None };
+        if let Some(contribution) = light_contribution(scene, &isect_p, &*material, wo, index, mis_pmf, sampler, log) {
+            let contribution = contribution * pmf.recip();
+            if !layers.is_empty() {
+                layers[scene.light_layers.lights[index]] += contribution;
+            }
+            acum += contribution;
+        }
+    };
+
+    match scene.settings.light_strategy {
+        LightStrategy::All => {
+            for index in 0..nlights {
+                add_light(index, 1.0, sampler, log.as_deref_mut());
+            }
+            if mis {
+                acum += bsdf_contribution(scene, &isect_p, &*material, wo, sampler, layers, log, |_| 1.0);
+            }
+        }
+        LightStrategy::One => {
+            if let Some(light) = scene.light_sampler.sample(isect_p.hit_point, sampler.next_1d()) {
+                add_light(light.index, light.pmf, sampler, log.as_deref_mut());
+            }
+            if mis {
+                let hit_point = isect_p.hit_point;
+                acum += bsdf_contribution(scene, &isect_p, &*material, wo, sampler, layers, log,
+                                          |index| scene.light_sampler.pmf(hit_point, index));
+            }
+        }
+        LightStrategy::Restir(settings) => {
+            // NOTE: single ray has no neighbours, only candidates of the hit are resampled
+            let reservoir = candidate_reservoir(scene, &isect_p, &*material, wo, settings.candidates, sampler);
+            let weight = reservoir.contribution_weight();
+            acum += reservoir_contribution(scene, &isect_p, &reservoir, weight, layers, sampler, log);
+        }
+    }
+    if let Some(settings) = &scene.settings.irradiance_cache {
+        acum += indirect_diffuse(scene, &isect_p, &*material, wo, settings, sampler);
+    }
+    scale_layers(layers, throughput);
+    acum * throughput
+}
+
+/// Maximum number of specular bounces that direct lighting follows (e.g. glass seen in mirror)
+const MAX_SPECULAR_DEPTH: usize = 8;
+
+// Last ray of specular chain, its hit with material and throughput of specular bounces
+type SpecularChainEnd<'a> = (Ray, Option<(SurfaceInteraction, HitMaterial<'a>)>, RGB);
+
+// Ray is traced through specular surfaces, None is returned when specular surface absorbs
+// the ray or chain is longer than maximum depth. Every hit starts its own bounce of sampler,
+// so sampling at returned hit uses dimensions of its depth.
+fn specular_chain<'a>(ray: &Ray, isect_p: Option<SurfaceInteraction>, scene: &'a Scene, sampler: &mut Box<dyn SamplerInterface>,
+                      mut log: Option<&mut RayLog>) -> Option<SpecularChainEnd<'a>> {
+    let mut ray = *ray;
+    let mut throughput = RGB::new(1.0, 1.0, 1.0);
+    let mut first_hit = Some(isect_p);
+    for depth in 0..=MAX_SPECULAR_DEPTH {
+        let isect_p = first_hit.take().unwrap_or_else(|| scene.geometry.intersect(&ray));
+        if let Some(log) = log.as_deref_mut() {
+            let kind = if depth == 0 { RayKind::Primary } else { RayKind::Indirect };
+            log.add_ray(kind, &ray, isect_p.as_ref().map(|isect_p| isect_p.t));
+        }
+        let isect = match isect_p {
+            Some(isect) => isect,
+            None => return Some((ray, None, throughput))
+        };
+        let material = scene.material_at(&isect);
+        sampler.start_bounce(depth as u32);
+        if material.scattering_type() != ScatteringType::Specular {
+            return Some((ray, Some((isect, material)), throughput))
+        }
+        if depth == MAX_SPECULAR_DEPTH {
+            break
+        }
+        let wo = -ray.direction;
+        let normal = shading_normal(&isect, &scene.settings.shading_normals);
+        let bs = material.sample(wo, normal, sampler)?;
+        throughput = throughput * bs.color * ((normal * bs.wi).abs() / bs.pdfw);
+        let medium = isect.medium(bs.wi, ray.medium);
+        ray = spawn_new_ray(isect.hit_point, isect.normal, bs.wi).with_time(ray.time).with_medium(medium);
+    }
+    None
+}
+
+fn scale_layers(layers: &mut [RGB], scale: RGB) {
+    for layer in layers.iter_mut() {
+        *layer = *layer * scale;
+    }
+}
+
+// One bounce of diffuse interreflection, irradiance is interpolated from irradiance cache or estimated
+// by hemisphere sampling at every hit when cache is disabled. Indirect light isn't added to light layers.
+fn indirect_diffuse(scene: &Scene, isect_p: &SurfaceInteraction, material: &dyn BSDFInterface, wo: Vec3,
+                    settings: &IrradianceCacheProperties, sampler: &mut Box<dyn SamplerInterface>) -> RGB {
+    if material.scattering_type() != ScatteringType::Diffuse {
+        return RGB::zero()
+    }
+    // NOTE: records are oriented by geometric normal on the side of the viewer
+    let normal = if isect_p.normal * wo < 0.0 { -isect_p.normal } else { isect_p.normal };
+    let reflectance = match material.eval(wo, normal, Vec3::from(normal)) {
+        Some(eval) => eval.color,
+        None => return RGB::zero()
+    };
+    if let Some(cache) = &scene.irradiance_cache {
+        if let Some(irradiance) = cache.lookup(isect_p.hit_point, normal) {
+            return reflectance * irradiance
+        }
+    }
+
+    // NOTE: lights are direct lighting, so only light they reflect once is gathered. Emissive materials
+    // aren't lights and direct lighting doesn't see them, so their emission is gathered directly.
+    let (m, n) = hemisphere_strata(settings.samples);
+    let frame = Frame::from(normal);
+    let mut samples = Vec::with_capacity(m * n);
+    for j in 0..m {
+        for k in 0..n {
+            let (u1, u2) = sampler.next_2d();
+            let direction = stratum_direction(j, k, m, n, u1, u2);
+            let wi = frame.to_world(direction);
+            let ray = spawn_new_ray(isect_p.hit_point, isect_p.normal, wi);
+            let sample = match scene.geometry.intersect(&ray) {
+                Some(isect_g) => {
+                    let mut radiance = RGB::zero();
+                    let material_g = scene.material_at(&isect_g);
+                    if material_g.is_emissive() {
+                        radiance += material_g.emssion(-wi, isect_g.normal, isect_g.back_side);
+                    }
+                    for index in 0..scene.lights.len() {
+                        if let Some(contribution) = light_contribution(scene, &isect_g, &*material_g, -wi, index, None, sampler, None) {
+                            radiance += contribution;
+                        }
+                    }
+                    GatherSample { radiance, distance: isect_g.t, cos_theta: direction.z }
+                }
+                None => GatherSample { radiance: RGB::zero(), distance: INFINITE_DISTANCE, cos_theta: direction.z }
+            };
+            samples.push(sample);
+        }
+    }
+    let irradiance = match &scene.irradiance_cache {
+        Some(cache) => {
+            let tangents = (frame.to_world(Vec3::new(1.0, 0.0, 0.0)), frame.to_world(Vec3::new(0.0, 1.0, 0.0)));
+            let record = IrradianceRecord::new(isect_p.hit_point, normal, tangents, &samples, n);
+            cache.insert(record);
+            record.irradiance
+        }
+        None => gather_irradiance(&samples)
+    };
+    reflectance * irradiance
+}
+
+// Power heuristic with exponent two, weight of sample with density f_pdf.
+fn power_heuristic(f_pdf: f32, g_pdf: f32) -> f32 {
+    let f = f_pdf * f_pdf;
+    let g = g_pdf * g_pdf;
+    if f == 0.0 {
+        return 0.0
+    }
+    f / (f + g)
+}
+
+// Emission of lights reached by BSDF sampled direction weighted against light sampling, pmf gives
+// probability that light sampling strategy chooses light. Delta lights can't be reached and when
+// scene has only delta lights no sample is taken, specular BSDF can't be sampled by lights so its
+// samples get full weight.
+#[allow(clippy::too_many_arguments)]
+fn bsdf_contribution<F: Fn(usize) -> f32>(scene: &Scene, isect_p: &SurfaceInteraction, material: &dyn BSDFInterface,
+                                          wo: Vec3, sampler: &mut Box<dyn SamplerInterface>, layers: &mut [RGB],
+                                          log: Option<&mut RayLog>, pmf: F) -> RGB {
+    if scene.lights.iter().all(|light| light.is_delta_light()) {
+        return RGB::zero()
+    }
+    let settings = &scene.settings.shading_normals;
+    let normal = shading_normal(isect_p, settings);
+    let record = |outcome: SampleOutcome| if let Some(telemetry) = &scene.telemetry {
+        telemetry.record_material(isect_p.material_id as usize, outcome);
+    };
+    let bs = match material.sample(wo, normal, sampler) {
+        Some(bs) => bs,
+        None => {
+            record(SampleOutcome::ZeroPdf);
+            return RGB::zero()
+        }
+    };
+    let specular = material.scattering_type() == ScatteringType::Specular;
+    let sample = (bs.wi, bs.color * ((bs.wi * normal).abs() / bs.pdfw), bs.pdfw);
+    let (wi, weight, pdfw) = compensate_direction(isect_p, material, wo, normal, sample, settings,
+                                                  |w| material.eval(wo, normal, w).map_or(0.0, |res| res.pdfw));
+    let weight = weight * shading_normal_factor(isect_p, wo, wi, settings);
+
+    let ray = spawn_new_ray(isect_p.hit_point, isect_p.normal, wi);
+    let isect_b = scene.geometry.intersect(&ray);
+    if let Some(log) = log {
+        log.add_ray(RayKind::Indirect, &ray, isect_b.as_ref().map(|isect_b| isect_b.t));
+    }
+    let tmax = isect_b.as_ref().map_or(INFINITE_DISTANCE, |isect_b| isect_b.t);
+    // NOTE: lights behind transparent surface are attenuated same as shadow rays of light sampling
+    let transparent = isect_b.is_some_and(|isect_b| {
+        scene.material_at(&isect_b).transmittance(-wi, isect_b.normal).is_some()
+    });
+
+    let mut acum = RGB::zero();
+    // sample that doesn't reach any light is rejected
+    let mut outcome = SampleOutcome::Rejected;
+    for (index, light) in scene.lights.iter().enumerate().filter(|(_, light)| !light.is_delta_light()) {
+        let position = match light.intersect(&ray) {
+            Some(position) => position,
+            None => continue
+        };
+        let dist = ray.origin.distance(position);
+        let transmitted = if tmax >= dist {
+            RGB::new(1.0, 1.0, 1.0)
+        } else if transparent {
+            transmittance(isect_p.hit_point, isect_p.normal, position, scene, sampler)
+        } else {
+            continue;
+        };
+        if transmitted.is_black() {
+            continue;
+        }
+        let ls = match light.eval_sample(isect_p.hit_point, position) {
+            Some(ls) => ls,
+            None => continue
+        };
+        let mis_weight = if specular {
+            1.0
+        } else {
+            power_heuristic(pdfw, pmf(index) * light.pdf_li(isect_p.hit_point, wi))
+        };
+        outcome = SampleOutcome::Accepted(if specular { None } else { Some(mis_weight) });
+        let contribution = weight * ls.intensity * transmitted * mis_weight;
+        if !layers.is_empty() {
+            layers[scene.light_layers.lights[index]] += contribution;
+        }
+        acum += contribution;
+    }
+    record(outcome);
+    acum
+}
+
+fn missed_radiance(ray: &Ray, scene: &Scene, layers: &mut [RGB]) -> RGB {
+    if !layers.is_empty() {
+        scene.add_environment_radiance_to_layers(ray.direction, RGB::new(1.0, 1.0, 1.0), layers);
+    }
+    scene.environment_radiance(ray.direction)
+}
+
+// Contribution of one light sample to the hit point, None if light is occluded or not sampled. When
+// pmf of choosing the light is given, sample is weighted against BSDF sampling.
+#[allow(clippy::too_many_arguments)]
+fn light_contribution(scene: &Scene, isect_p: &SurfaceInteraction, material: &dyn BSDFInterface, wo: Vec3, index: usize,
+                      pmf: Option<f32>, sampler: &mut Box<dyn SamplerInterface>, log: Option<&mut RayLog>) -> Option<RGB> {
+    let light = &scene.lights[index];
+    let record = |outcome: SampleOutcome| if let Some(telemetry) = &scene.telemetry {
+        telemetry.record_light(index, outcome);
+    };
+    let ls = match light.illuminate(isect_p.hit_point, sampler) {
+        Some(ls) => ls,
+        None => {
+            record(SampleOutcome::ZeroPdf);
+            return None
+        }
+    };
+    if let Some(log) = log {
+        log.add(RayKind::Shadow, isect_p.hit_point, ls.position);
+    }
+    let transmitted = transmittance(isect_p.hit_point, isect_p.normal, ls.position, scene, sampler);
+    let contribution = match unshadowed_contribution(scene, isect_p, material, wo, &ls) {
+        Some(contribution) if !transmitted.is_black() => contribution * transmitted,
+        _ => {
+            record(SampleOutcome::Rejected);
+            return None
+        }
+    };
+    match pmf {
+        Some(pmf) if !light.is_delta_light() => {
+            let normal = shading_normal(isect_p, &scene.settings.shading_normals);
+            let bsdf_pdf = material.eval(wo, normal, ls.wi)?.pdfw;
+            let dist = isect_p.hit_point.distance(ls.position);
+            let light_pdf = pmf * pdfa_to_w(ls.pdfa, dist, ls.cos_theta);
+            let mis_weight = power_heuristic(light_pdf, bsdf_pdf);
+            record(SampleOutcome::Accepted(Some(mis_weight)));
+            Some(contribution * mis_weight)
+        }
+        _ => {
+            record(SampleOutcome::Accepted(None));
+            Some(contribution)
+        }
+    }
+}
+
+// Contribution of light sample to the hit point without visibility.
+fn unshadowed_contribution(scene: &Scene, isect_p: &SurfaceInteraction, material: &dyn BSDFInterface, wo: Vec3,
+                           ls: &LightSample) -> Option<RGB> {
+    let settings = &scene.settings.shading_normals;
+    let normal = shading_normal(isect_p, settings);
+    let mat_spectrum = material.eval(wo, normal, ls.wi)?.color;
+    let cosa = (ls.wi * normal).abs() * shading_normal_factor(isect_p, wo, ls.wi, settings);
+    let dist = isect_p.hit_point.distance(ls.position);
+    let pdf = pdfa_to_w(ls.pdfa, dist, ls.cos_theta);
+    Some((mat_spectrum * ls.intensity) * (cosa / pdf))
+}
+
+// Point of the light in reservoir and its unshadowed contribution with respect to area of the light
+// at hit point of the reservoir.
+#[derive(Clone, Copy)]
+struct LightCandidate {
+    light: usize,
+    position: Point3,
+    value: RGB,
+}
+
+impl LightCandidate {
+    fn new(scene: &Scene, isect_p: &SurfaceInteraction, material: &dyn BSDFInterface, wo: Vec3, light: usize,
+           ls: &LightSample) -> Option<Self> {
+        let value = unshadowed_contribution(scene, isect_p, material, wo, ls)? * ls.pdfa;
+        Some(Self { light, position: ls.position, value })
+    }
+
+    // Candidate of other hit point evaluated at this hit point
+    fn reuse(&self, scene: &Scene, isect_p: &SurfaceInteraction, material: &dyn BSDFInterface, wo: Vec3) -> Option<Self> {
+        let ls = scene.lights[self.light].eval_sample(isect_p.hit_point, self.position)?;
+        Self::new(scene, isect_p, material, wo, self.light, &ls)
+    }
+
+    fn target(&self) -> f32 {
+        self.value.luminance().max(0.0)
+    }
+}
+
+// Reservoir of candidates of lights chosen by light sampler, target function is luminance of unshadowed contribution.
+fn candidate_reservoir(scene: &Scene, isect_p: &SurfaceInteraction, material: &dyn BSDFInterface, wo: Vec3,
+                       candidates: usize, sampler: &mut Box<dyn SamplerInterface>) -> Reservoir<LightCandidate> {
+    let mut reservoir = Reservoir::default();
+    if scene.lights.is_empty() {
+        return reservoir
+    }
+    for _ in 0..candidates {
+        let light = scene.light_sampler.sample(isect_p.hit_point, sampler.next_1d());
+        let ls = light.and_then(|light| scene.lights[light.index].illuminate(isect_p.hit_point, sampler));
+        let u = sampler.next_1d();
+        let candidate = light.zip(ls).and_then(|(light, ls)| {
+            Some((LightCandidate::new(scene, isect_p, material, wo, light.index, &ls)?, light.pmf * ls.pdfa))
+        });
+        match candidate {
+            Some((candidate, pdf)) if pdf > 0.0 => {
+                let target = candidate.target();
+                reservoir.update(candidate, target / pdf, target, u);
+            }
+            _ => reservoir.reject()
+        }
+    }
+    reservoir
+}
+
+// Selected sample of reservoir is shaded, only one shadow ray is traced.
+fn reservoir_contribution(scene: &Scene, isect_p: &SurfaceInteraction, reservoir: &Reservoir<LightCandidate>,
+                          weight: f32, layers: &mut [RGB], sampler: &mut Box<dyn SamplerInterface>,
+                          log: Option<&mut RayLog>) -> RGB {
+    let candidate = match reservoir.sample() {
+        Some(candidate) if weight > 0.0 => candidate,
+        _ => return RGB::zero()
+    };
+    if let Some(log) = log {
+        log.add(RayKind::Shadow, isect_p.hit_point, candidate.position);
+    }
+    let transmitted = transmittance(isect_p.hit_point, isect_p.normal, candidate.position, scene, sampler);
+    if transmitted.is_black() {
+        return RGB::zero()
+    }
+    let contribution = candidate.value * transmitted * weight;
+    if !layers.is_empty() {
+        layers[scene.light_layers.lights[candidate.light]] += contribution;
+    }
+    contribution
+}
+
+// First hit of camera ray and its reservoir of candidates.
+struct RestirPixel {
+    px: f32,
+    py: f32,
+    ray: Ray,
+    hit: Option<(SurfaceInteraction, Reservoir<LightCandidate>)>,
+}
+
+// Pixels are similar when their surfaces have similar orientation and distance from camera, reusing
+// samples of different surfaces increases variance.
+fn similar_hits(isect_p: &SurfaceInteraction, other: &SurfaceInteraction) -> bool {
+    isect_p.normal * other.normal > 0.9 && (isect_p.t - other.t).abs() < 0.1 * isect_p.t
+}
+
+// Reservoir of pixel is merged with reservoirs of random similar neighbours in the tile. Normalization
+// is returned next to the reservoir, only neighbours where selected sample has non-zero target are counted,
+// so reuse of samples that other pixels can't produce is unbiased (visibility is not part of target).
+fn spatial_reuse(scene: &Scene, settings: &RestirProperties, pixels: &[RestirPixel], index: usize, tile: &Tile,
+                 sampler: &mut Box<dyn SamplerInterface>) -> (Reservoir<LightCandidate>, f32) {
+    let (isect_p, own) = match &pixels[index].hit {
+        Some(hit) => hit,
+        None => return (Reservoir::default(), 0.0)
+    };
+    let wo = -pixels[index].ray.direction;
+    let material = scene.material_at(isect_p);
+    let (width, height) = (tile.width() as i32, tile.height() as i32);
+    let (x, y) = (index as i32 % width, index as i32 / width);
+    let radius = settings.spatial_radius as f32;
+    let mut reservoir = *own;
+    let mut merged = vec![index];
+    for _ in 0..settings.spatial_samples {
+        let (u1, u2) = sampler.next_2d();
+        let u = sampler.next_1d();
+        let nx = (x + ((2.0 * u1 - 1.0) * radius).round() as i32).clamp(0, width - 1);
+        let ny = (y + ((2.0 * u2 - 1.0) * radius).round() as i32).clamp(0, height - 1);
+        let neighbour = (ny * width + nx) as usize;
+        if merged.contains(&neighbour) {
+            continue;
+        }
+        let other = match &pixels[neighbour].hit {
+            Some((other_isect, other)) if similar_hits(isect_p, other_isect) => other,
+            _ => continue
+        };
+        let candidate = other.sample().and_then(|candidate| candidate.reuse(scene, isect_p, &*material, wo));
+        reservoir.merge(other, candidate, candidate.map_or(0.0, |c| c.target()), u);
+        merged.push(neighbour);
+    }
+    let normalization = match reservoir.sample() {
+        Some(candidate) => merged.iter().filter_map(|&n| {
+            let (n_isect, n_reservoir) = pixels[n].hit.as_ref()?;
+            let wo = -pixels[n].ray.direction;
+            let target = if n == index {
+                candidate.target()
+            } else {
+                candidate.reuse(scene, n_isect, &*scene.material_at(n_isect), wo).map_or(0.0, |c| c.target())
+            };
+            (target > 0.0).then(|| n_reservoir.count())
+        }).sum(),
+        None => 0.0
+    };
+    (reservoir, normalization)
+}
+
+// Pass of direct lighting with spatial reuse, reservoirs of candidates are created at first hits of all
+// pixels of the tile and every pixel then merges its reservoir with reservoirs of neighbours.
+fn restir_pass(scene: &Scene, settings: &RestirProperties, buffers: &mut TileBuffers,
+               sampler: &mut Box<dyn SamplerInterface>, pass: usize) {
+    let tile = buffers.tile;
+    let mut pixels = Vec::with_capacity(tile.width() * tile.height());
+    for (x, y) in tile {
+        let (sx, sy) = sampler.sample_pixel(x, y, pass);
+        buffers.add_sample_count(x, y);
+        let px = x as f32 + sx;
+        let py = y as f32 + sy;
+        let ray = scene.camera.generate_ray_at_time(px, py, sampler.sample_time());
+        let isect_p = scene.geometry.intersect(&ray);
+        buffers.add_aov_sample(scene, x, y, px, py, &ray, isect_p.as_ref());
+        let hit = isect_p.map(|isect_p| {
+            let material = scene.material_at(&isect_p);
+            let reservoir = candidate_reservoir(scene, &isect_p, &*material, -ray.direction, settings.candidates, sampler);
+            (isect_p, reservoir)
+        });
+        pixels.push(RestirPixel { px, py, ray, hit });
+    }
+    let reservoirs: Vec<_> = (0..pixels.len()).map(|index| spatial_reuse(scene, settings, &pixels, index, &tile, sampler)).collect();
+    for ((x, y), (pixel, (reservoir, normalization))) in tile.into_iter().zip(pixels.iter().zip(reservoirs.iter())) {
+        let mut layers = vec![RGB::zero(); scene.light_layers.len()];
+        let rgb = match &pixel.hit {
+            Some((isect_p, _)) => {
+                let weight = reservoir.normalized_contribution_weight(*normalization);
+                reservoir_contribution(scene, isect_p, reservoir, weight, &mut layers, sampler, None)
+            }
+            None => missed_radiance(&pixel.ray, scene, &mut layers)
+        };
+        let scale = clamp_scale(&rgb, scene.settings.max_component);
+        for (index, value) in layers.iter().enumerate() {
+            buffers.add_layer(index, x, y, pixel.px, pixel.py, &(*value * scale));
+        }
+        buffers.add(x, y, pixel.px, pixel.py, &(rgb * scale));
+    }
+}
+
+// Tracks state of light path expressions along a path and contributions of matching paths.
+struct LpePath<'a> {
+    lpes: &'a [Lpe],
+    states: Vec<LpeState>,
+    throughput: RGB,
+    contributions: Vec<RGB>,
+}
+
+impl<'a> LpePath<'a> {
+    fn new(lpes: &'a [Lpe]) -> Self {
+        let states = lpes.iter().map(|lpe| lpe.advance(&lpe.start(), LpeEvent::Camera)).collect();
+        let contributions = vec![RGB::zero(); lpes.len()];
+        Self { lpes, states, throughput: RGB::new(1.0, 1.0, 1.0), contributions }
+    }
+
+    fn add_emission(&mut self, le: RGB) {
+        for (i, lpe) in self.lpes.iter().enumerate() {
+            if lpe.is_accepting(&lpe.advance(&self.states[i], LpeEvent::Light)) {
+                self.contributions[i] += self.throughput * le;
+            }
+        }
+    }
+
+    // Light reaching the path through scattering event that the path itself does not take
+    fn add_scattered_light(&mut self, event: LpeEvent, le: RGB) {
+        for (i, lpe) in self.lpes.iter().enumerate() {
+            let state = lpe.advance(&lpe.advance(&self.states[i], event), LpeEvent::Light);
+            if lpe.is_accepting(&state) {
+                self.contributions[i] += self.throughput * le;
+            }
+        }
+    }
+
+    fn scatter(&mut self, event: LpeEvent, weight: RGB) {
+        for (i, lpe) in self.lpes.iter().enumerate() {
+            self.states[i] = lpe.advance(&self.states[i], event);
+        }
+        self.throughput = self.throughput * weight;
+    }
+}
+
+pub fn random_walk_integrator(scene: &Scene, rw_settings: &RandomWalkProperties) -> RGB8uffer {
+    if let Some(guide) = &scene.path_guide {
+        train_path_guide(scene, guide, rw_settings);
+    }
+    let outputs = TileOutputs { aovs: true, light_layers: true, lpes: true, ray_log: true, ..Default::default() };
+
+    let film = render_tiles(scene, outputs, |buffers, sampler| {
+        for i in buffers.passes() {
+            if scene.cancel_token.is_cancelled() {
+                break;
+            }
+            for (x, y) in buffers.tile {
+                let (sx, sy) = sampler.sample_pixel(x, y, i);
+                buffers.add_sample_count(x, y);
+                let px = x as f32 + sx;
+                let py = y as f32 + sy;
+                let ray = scene.camera.generate_ray_at_time(px, py, sampler.sample_time()).with_medium(scene.camera_medium);
+                let isect_p = scene.geometry.intersect(&ray);
+                buffers.add_aov_sample(scene, x, y, px, py, &ray, isect_p.as_ref());
+                let mut lpe_path = (!scene.lpes.is_empty()).then(|| LpePath::new(&scene.lpes));
+                let mut layers = vec![RGB::zero(); scene.light_layers.len()];
+                let rgb = random_walk_at(&ray, isect_p, scene, sampler, rw_settings, lpe_path.as_mut(), &mut layers, buffers.ray_log(x, y));
+                let scale = clamp_scale(&rgb, scene.settings.max_component);
+                if let Some(lpe_path) = lpe_path {
+                    for (index, value) in lpe_path.contributions.iter().enumerate() {
+                        buffers.add_lpe(index, x, y, px, py, &(*value * scale));
+                    }
+                }
+                for (index, value) in layers.iter().enumerate() {
+                    buffers.add_layer(index, x, y, px, py, &(*value * scale));
+                }
+                buffers.add(x, y, px, py, &(rgb * scale));
+            }
+        }
+    });
+    film.save_outputs(scene);
+    finish_image(scene, &film.radiance, film.depth())
+}
+
+/// Guide is trained by random walks that are not part of the image, iteration k traces 2^k paths
+/// per pixel and guide is refined after every iteration.
+fn train_path_guide(scene: &Scene, guide: &PathGuide, rw_settings: &RandomWalkProperties) {
+    let resolution = scene.settings.resolution;
+    let tiles = Tile::new(0, 0, resolution.width, resolution.height).split(TILE_SIZE, TILE_SIZE);
+    guide.reset();
+    guide.set_training(true);
+    for iteration in 0..guide.settings().iterations {
+        tiles.par_iter().for_each(|tile| {
+            if scene.cancel_token.is_cancelled() {
+                return
+            }
+            let mut sampler = scene.sampler.clone_for_tile(tile, 2 + iteration as u32);
+            for i in 0..1 << iteration {
+                for (x, y) in *tile {
+                    let (sx, sy) = sampler.sample_pixel(x, y, i);
+                    let ray = scene.camera.generate_ray_at_time(x as f32 + sx, y as f32 + sy, sampler.sample_time());
+                    random_walk(&ray.with_medium(scene.camera_medium), scene, &mut sampler, rw_settings, None, &mut [], None);
+                }
+            }
+        });
+        guide.refine(iteration);
+    }
+    guide.set_training(false);
+}
+
+// Segments of rays that miss geometry are as long as diagonal of the scene.
+fn ray_log_miss_length(scene: &Scene) -> f32 {
+    scene.geometry.bounds().map_or(1.0, |bounds| bounds.min().distance(bounds.max()).max(1.0))
+}
+
+/// Scale factor that brings largest component of radiance sample down to max_component, hue of
+/// sample is preserved. Same factor is used for light layers and light path expressions of the sample.
+pub fn clamp_scale(rgb: &RGB, max_component: Option<f32>) -> f32 {
+    let max_component = match max_component {
+        Some(max_component) => max_component,
+        None => return 1.0
+    };
+    let largest = rgb.r.max(rgb.g).max(rgb.b);
+    if largest > max_component {
+        max_component / largest
+    } else {
+        1.0
+    }
+}
+
+/// Russian roulette, after rrdepth bounces path survives with probability of its largest throughput
+/// component. Returns throughput of surviving path compensated for termination, None if path is terminated.
+/// Sample is taken only when roulette is played, so shallow paths use same samples as without it.
+pub fn russian_roulette(throughput: RGB, depth: usize, rrdepth: usize, sampler: &mut Box<dyn SamplerInterface>) -> Option<RGB> {
+    if depth < rrdepth {
+        return Some(throughput)
+    }
+    let q = throughput.r.max(throughput.g).max(throughput.b).min(1.0);
+    if sampler.next_1d() >= q {
+        return None
+    }
+    Some(throughput * q.recip())
+}
+
+/// Normal used for evaluation of materials at the hit.
+pub fn shading_normal(isect_p: &SurfaceInteraction, settings: &ShadingNormalSettings) -> Normal {
+    if settings.enabled { isect_p.shading_normal } else { isect_p.normal }
+}
+
+/// Factor of material evaluated with shading normal for direction wi. Direction that is on different side of
+/// geometric surface than shading normal expects would leak light through the surface and gets zero, otherwise
+/// shadow terminator smoothing term is returned.
+pub fn shading_normal_factor(isect_p: &SurfaceInteraction, wo: Vec3, wi: Vec3, settings: &ShadingNormalSettings) -> f32 {
+    if !settings.enabled {
+        return 1.0
+    }
+    let (ng, ns) = (isect_p.normal, isect_p.shading_normal);
+    let shading_reflection = (wi * ns) * (wo * ns) > 0.0;
+    let geometric_reflection = (wi * ng) * (wo * ng) > 0.0;
+    if shading_reflection != geometric_reflection {
+        return 0.0
+    }
+    if !settings.terminator_smoothing {
+        return 1.0
+    }
+    let denom = (ns * wi).abs() * (ng * ns).abs();
+    if denom == 0.0 {
+        return 1.0
+    }
+    let g = ((ng * wi).abs() / denom).min(1.0);
+    -g * g * g + g * g + g
+}
+
+/// Sampled direction that is on wrong side of geometric surface is mirrored by geometric surface
+/// when compensation is enabled, shading_normal_factor rejects it otherwise. Sample is direction, its
+/// weight (bsdf * cos / pdfw) and pdfw, pdf gives density of the sampling strategy for any direction.
+/// Direction whose mirror image is on wrong side can be sampled directly and through mirroring, so
+/// its weight is recomputed with bsdf of the direction and sum of densities of both ways.
+/// Specular materials can't be evaluated and are never compensated.
+pub fn compensate_direction<F: Fn(Vec3) -> f32>(isect_p: &SurfaceInteraction, material: &dyn BSDFInterface, wo: Vec3,
+                                                normal: Normal, sample: (Vec3, RGB, f32), settings: &ShadingNormalSettings,
+                                                pdf: F) -> (Vec3, RGB, f32) {
+    if !settings.enabled || !settings.compensation || material.scattering_type() == ScatteringType::Specular {
+        return sample
+    }
+    let (ng, ns) = (isect_p.normal, isect_p.shading_normal);
+    let wrong_side = |w: Vec3| ((w * ns) * (wo * ns) > 0.0) != ((w * ng) * (wo * ng) > 0.0);
+    let mirror = |w: Vec3| w - Vec3::from(ng) * (2.0 * (w * ng));
+    let (wi, _, _) = sample;
+    let wi = if wrong_side(wi) { mirror(wi) } else { wi };
+    let mirrored = mirror(wi);
+    if wrong_side(wi) {
+        // direction that is on wrong side on both sides of geometric surface
+        return (wi, RGB::zero(), sample.2)
+    }
+    if !wrong_side(mirrored) {
+        return sample
+    }
+    let pdfw = pdf(wi) + pdf(mirrored);
+    match material.eval(wo, normal, wi) {
+        Some(res) if pdfw > 0.0 => (wi, res.color * ((normal * wi).abs() / pdfw), pdfw),
+        _ => (wi, RGB::zero(), pdfw)
+    }
+}
+
+/// Direction sampled from BSDF or from learned incident radiance, weight is bsdf * cos / pdfw where pdfw
+/// is density of the mixture of both strategies (one-sample MIS). Density is also returned.
+pub fn guided_direction(guide: &PathGuide, material: &dyn BSDFInterface, wo: Vec3, normal: Normal, hit_point: Point3,
+                        sampler: &mut Box<dyn SamplerInterface>) -> Option<(Vec3, RGB, f32)> {
+    let fraction = guide.settings().bsdf_fraction;
+    let wi = if sampler.next_1d() < fraction {
+        material.sample(wo, normal, sampler)?.wi
+    } else {
+        let (u1, u2) = sampler.next_2d();
+        guide.sample(hit_point, u1, u2).0
+    };
+    let res = material.eval(wo, normal, wi)?;
+    let pdfw = fraction * res.pdfw + (1.0 - fraction) * guide.pdf(hit_point, wi);
+    if pdfw <= 0.0 {
+        return None
+    }
+    Some((wi, res.color * ((normal * wi).abs() / pdfw), pdfw))
+}
+
+/// Density of direction wi sampled by guided_direction.
+pub fn guided_pdf(guide: &PathGuide, material: &dyn BSDFInterface, wo: Vec3, normal: Normal, hit_point: Point3, wi: Vec3) -> f32 {
+    let fraction = guide.settings().bsdf_fraction;
+    let bsdf_pdfw = material.eval(wo, normal, wi).map_or(0.0, |res| res.pdfw);
+    fraction * bsdf_pdfw + (1.0 - fraction) * guide.pdf(hit_point, wi)
+}
+
+/// Density of directions that random_walk_direction samples for non-specular materials.
+pub const UNIFORM_SPHERE_PDF: f32 = 0.25 * std::f32::consts::FRAC_1_PI;
+
+/// Direction of next segment of random walk and its weight (bsdf * cos / pdfw). Directions are sampled
+/// uniformly on sphere (UNIFORM_SPHERE_PDF), only specular materials are sampled because they can't be evaluated.
+pub fn random_walk_direction(material: &dyn BSDFInterface, wo: Vec3, normal: Normal,
+                             sampler: &mut Box<dyn SamplerInterface>) -> Option<(Vec3, RGB)> {
+    if material.scattering_type() == ScatteringType::Specular {
+        let bs = material.sample(wo, normal, sampler)?;
+        return Some((bs.wi, bs.color * ((normal * bs.wi).abs() / bs.pdfw)));
+    }
     let (u1, u2) = sampler.next_2d();
     let sample_dist = sample_uniform_sphere(u1, u2);
+    let wi = Frame::from(normal).to_world(sample_dist.direction).normalize();
+    let res = material.eval(wo, normal, wi)?;
+    Some((wi, res.color * ((normal * wi).abs() / sample_dist.pdfw)))
+}
 
-    let wi = Frame::from(isect_p.normal).to_world(sample_dist.direction).normalize();
-    let res = material.eval(wo, isect_p.normal, wi);
+// Single scattering of delta lights along ray segment inside medium. Distance is sampled equi-angularly
+// toward every light (Kulla and Fajardo), so light shafts near the lights are not noisy as with distance
+// sampling by transmittance. Light is attenuated only by medium of the segment and by surfaces in between.
+#[allow(clippy::too_many_arguments)]
+fn medium_delta_lights(scene: &Scene, medium: &Medium, ray: &Ray, tmax: f32, sampler: &mut Box<dyn SamplerInterface>,
+                       mut lpe_path: Option<&mut LpePath>, layers: &mut [RGB], throughput: RGB) -> RGB {
+    let mut acum = RGB::zero();
+    for (index, light) in scene.lights.iter().enumerate().filter(|(_, light)| light.is_delta_light()) {
+        let record = |outcome: SampleOutcome| if let Some(telemetry) = &scene.telemetry {
+            telemetry.record_light(index, outcome);
+        };
+        let position = match light.illuminate(ray.origin, sampler) {
+            Some(ls) => ls.position,
+            None => {
+                record(SampleOutcome::ZeroPdf);
+                continue
+            }
+        };
+        let u = sampler.next_1d();
+        let (t, pdf) = match sample_equi_angular(ray.origin, ray.direction, 0.0, tmax, position, u) {
+            Some(sample) => sample,
+            None => {
+                record(SampleOutcome::ZeroPdf);
+                continue
+            }
+        };
+        let point = ray.point_at(t);
+        let ls = match light.illuminate(point, sampler) {
+            Some(ls) => ls,
+            None => {
+                record(SampleOutcome::ZeroPdf);
+                continue
+            }
+        };
+        let shadow_ray = Ray::new(point, ls.wi).with_time(ray.time);
+        let distance = point.distance(ls.position);
+        let tmax_light = scene.geometry.intersect(&shadow_ray).map_or(distance, |isect| isect.t.min(distance));
+        let transmitted = medium.transmittance(ray, t, sampler) * medium.transmittance(&shadow_ray, tmax_light, sampler);
+        let visibility = if transmitted.is_black() {
+            RGB::zero()
+        } else {
+            transmittance(point, Normal::from(ls.wi), ls.position, scene, sampler)
+        };
+        if visibility.is_black() {
+            record(SampleOutcome::Rejected);
+            continue
+        }
+        record(SampleOutcome::Accepted(None));
+        let phase = medium.phase().p(-ray.direction, ls.wi);
+        // NOTE: intensity of light sample already includes falloff with squared distance
+        let le = medium.sigma_s(point) * transmitted * visibility * ls.intensity * (phase / pdf);
+        if let Some(path) = lpe_path.as_deref_mut() {
+            path.add_scattered_light(LpeEvent::Scatter { transmission: false, typ: ScatteringType::Diffuse }, le);
+        }
+        let contribution = throughput * le;
+        if !layers.is_empty() {
+            layers[scene.light_layers.lights[index]] += contribution;
+        }
+        acum += contribution;
+    }
+    acum
+}
 
-    let fcos = match res {
-        Some(res) => {
-            res.color * (isect_p.normal * wi).abs()
+/// Vertex of random walk whose incident radiance is recorded to path guide, radiance and throughput
+/// are values of the path after scattering at the vertex.
+struct GuideVertex {
+    position: Point3,
+    wi: Vec3,
+    pdfw: f32,
+    radiance: RGB,
+    throughput: RGB,
+}
+
+// Contributions of lights are also added to their light layers, layers are empty when scene doesn't use them.
+// When path guide is trained incident radiance of path vertices is recorded to it.
+fn random_walk(ray: &Ray, scene: &Scene, sampler: &mut Box<dyn SamplerInterface>, rw_settings: &RandomWalkProperties,
+               lpe_path: Option<&mut LpePath>, layers: &mut [RGB], log: Option<&mut RayLog>) -> RGB {
+    random_walk_at(ray, scene.geometry.intersect(ray), scene, sampler, rw_settings, lpe_path, layers, log)
+}
+
+// Random walk of camera ray whose first hit isect_p was already found.
+#[allow(clippy::too_many_arguments)]
+fn random_walk_at(ray: &Ray, isect_p: Option<SurfaceInteraction>, scene: &Scene, sampler: &mut Box<dyn SamplerInterface>,
+                  rw_settings: &RandomWalkProperties, mut lpe_path: Option<&mut LpePath>, layers: &mut [RGB],
+                  mut log: Option<&mut RayLog>) -> RGB {
+    let maxdepth = rw_settings.maxdepth;
+    let mut ray = *ray;
+    let mut throughput = RGB::new(1.0, 1.0, 1.0);
+    let mut radiance = RGB::zero();
+    let mut depth = 0;
+    let mut crossings = 0;
+    let mut guide_vertices = scene.path_guide.as_ref().filter(|guide| guide.is_training()).map(|_| Vec::new());
+    let mut first_hit = Some(isect_p);
+    loop {
+        let isect_p = first_hit.take().unwrap_or_else(|| scene.geometry.intersect(&ray));
+        let kind = if depth == 0 { RayKind::Primary } else { RayKind::Indirect };
+        if let Some(medium_id) = ray.medium {
+            let medium = &scene.media[medium_id as usize];
+            let tmax = isect_p.as_ref().map_or(INFINITE_DISTANCE, |isect_p| isect_p.t);
+            if depth < maxdepth {
+                radiance += medium_delta_lights(scene, medium, &ray, tmax, sampler, lpe_path.as_deref_mut(), layers, throughput);
+            }
+            let ms = medium.sample(&ray, tmax, sampler);
+            throughput = throughput * ms.weight;
+            if ms.scattered {
+                if let Some(log) = log.as_deref_mut() {
+                    log.add_ray(kind, &ray, Some(ms.t));
+                }
+                if depth == maxdepth {
+                    break;
+                }
+                sampler.start_bounce(depth as u32);
+                let (u1, u2) = sampler.next_2d();
+                // NOTE: phase function is sampled exactly so weight of the direction is one,
+                // light path expressions see volume scattering as diffuse reflection
+                let (wi, _pdf) = medium.phase().sample(-ray.direction, u1, u2);
+                if let Some(path) = lpe_path.as_deref_mut() {
+                    let event = LpeEvent::Scatter { transmission: false, typ: ScatteringType::Diffuse };
+                    path.scatter(event, RGB::new(1.0, 1.0, 1.0));
+                }
+                ray = Ray::new(ray.point_at(ms.t), wi).with_time(ray.time).with_medium(ray.medium);
+                depth += 1;
+                continue;
+            }
         }
-        None => { return le; }
-    };
+        if let Some(log) = log.as_deref_mut() {
+            log.add_ray(kind, &ray, isect_p.as_ref().map(|isect_p| isect_p.t));
+        }
+        let isect_p = match isect_p {
+            Some(isect_p) => isect_p,
+            None => {
+                let le = scene.environment_radiance(ray.direction);
+                if let Some(path) = lpe_path.as_deref_mut() {
+                    path.add_emission(le);
+                }
+                if !layers.is_empty() {
+                    scene.add_environment_radiance_to_layers(ray.direction, throughput, layers);
+                }
+                radiance += throughput * le;
+                break;
+            }
+        };
+        let material = scene.material_at(&isect_p);
+        let wo = -ray.direction;
+        let le = material.emssion(wo, isect_p.normal, isect_p.back_side);
+        if material.is_emissive() {
+            if let Some(path) = lpe_path.as_deref_mut() {
+                path.add_emission(le);
+            }
+            if !layers.is_empty() {
+                layers[scene.light_layers.materials[isect_p.material_id as usize]] += throughput * le;
+            }
+        }
+        radiance += throughput * le;
 
-    let new_ray = spawn_new_ray(isect_p.hit_point, isect_p.normal, wi);
-    le + fcos * random_walk(&new_ray, scene, sampler, depth + 1, maxdepth) * sample_dist.pdfw.recip()
+        // NOTE: medium boundary doesn't scatter the ray, so its crossing is not a bounce
+        if material.is_interface() && crossings < MAX_INTERFACE_CROSSINGS {
+            crossings += 1;
+            let medium = isect_p.medium(ray.direction, ray.medium);
+            ray = spawn_new_ray(isect_p.hit_point, isect_p.normal, ray.direction).with_time(ray.time).with_medium(medium);
+            continue;
+        }
+
+        if depth == maxdepth {
+            break;
+        }
+
+        sampler.start_bounce(depth as u32);
+        let settings = &scene.settings.shading_normals;
+        let normal = shading_normal(&isect_p, settings);
+        let guide = scene.path_guide.as_ref().filter(|_| material.scattering_type() != ScatteringType::Specular);
+        let record = |outcome: SampleOutcome| if let Some(telemetry) = &scene.telemetry {
+            telemetry.record_material(isect_p.material_id as usize, outcome);
+        };
+        let sample = match guide {
+            Some(guide) => guided_direction(guide, &*material, wo, normal, isect_p.hit_point, sampler).map(|sample| {
+                let (wi, weight, pdfw) = compensate_direction(&isect_p, &*material, wo, normal, sample, settings,
+                    |w| guided_pdf(guide, &*material, wo, normal, isect_p.hit_point, w));
+                (wi, weight, Some(pdfw))
+            }),
+            None => random_walk_direction(&*material, wo, normal, sampler).map(|(wi, weight)| {
+                let (wi, weight, _) = compensate_direction(&isect_p, &*material, wo, normal, (wi, weight, UNIFORM_SPHERE_PDF),
+                                                           settings, |_| UNIFORM_SPHERE_PDF);
+                (wi, weight, None)
+            })
+        };
+        let (wi, weight, guide_pdfw) = match sample {
+            Some(sample) => sample,
+            None => {
+                record(SampleOutcome::ZeroPdf);
+                break
+            }
+        };
+        let weight = weight * shading_normal_factor(&isect_p, wo, wi, settings);
+        record(if weight.is_black() { SampleOutcome::Rejected } else { SampleOutcome::Accepted(None) });
+
+        if let Some(path) = lpe_path.as_deref_mut() {
+            let transmission = (isect_p.normal * wi) * (isect_p.normal * wo) < 0.0;
+            let event = LpeEvent::Scatter { transmission, typ: material.scattering_type() };
+            path.scatter(event, weight);
+        }
+
+        throughput = match russian_roulette(throughput * weight, depth, rw_settings.rrdepth, sampler) {
+            Some(throughput) => throughput,
+            None => break
+        };
+        if let (Some(vertices), Some(pdfw)) = (guide_vertices.as_mut(), guide_pdfw) {
+            vertices.push(GuideVertex { position: isect_p.hit_point, wi, pdfw, radiance, throughput });
+        }
+        let medium = isect_p.medium(wi, ray.medium);
+        ray = spawn_new_ray(isect_p.hit_point, isect_p.normal, wi).with_time(ray.time).with_medium(medium);
+        depth += 1;
+    }
+    if let (Some(guide), Some(vertices)) = (scene.path_guide.as_ref(), guide_vertices) {
+        // NOTE: incident radiance is radiance that path collected after the vertex divided by throughput,
+        // luminances are used because guide learns only luminance
+        for vertex in vertices.iter() {
+            let throughput = vertex.throughput.luminance();
+            if throughput > 0.0 {
+                let li = (radiance.luminance() - vertex.radiance.luminance()) / throughput;
+                guide.record(vertex.position, vertex.wi, li.max(0.0) / vertex.pdfw);
+            }
+        }
+    }
+    radiance
+}
+
+
+/// Render scene with integrator selected in settings, scene.settings.nthreads threads are used (0 is
+/// number of threads of current pool). When pool with nthreads threads can't be created (e.g. wasm
+/// without threads), warning is printed and scene is rendered in current pool, so number of used threads
+/// can differ from nthreads. Use render_scene_in_pool when exact number of threads is required.
+pub fn render_scene(scene: &Scene) -> RGB8uffer {
+    in_render_pool(scene, || render_with_integrator(scene))
 }
 
+// Run rendering in pool with scene.settings.nthreads threads. Current pool is used when it already has
+// nthreads threads or when new pool can't be created (e.g. wasm without threads).
+pub(crate) fn in_render_pool<R: Send>(scene: &Scene, render: impl FnOnce() -> R + Send) -> R {
+    let nthreads = scene.settings.nthreads;
+    if nthreads == 0 || nthreads == rayon::current_num_threads() {
+        return render()
+    }
+    match ThreadPoolBuilder::new().num_threads(nthreads).build() {
+        Ok(pool) => pool.install(render),
+        Err(e) => {
+            println!("Warning: Thread pool with {} threads can't be created, current pool with {} threads is used: {}",
+                     nthreads, rayon::current_num_threads(), e);
+            render()
+        }
+    }
+}
+
+/// Render scene inside of thread pool provided by host application, nthreads setting is ignored.
+pub fn render_scene_in_pool(scene: &Scene, pool: &ThreadPool) -> RGB8uffer {
+    pool.install(|| render_with_integrator(scene))
+}
+
+// Integrators that accumulate auxiliary outputs in their tile pass, ambient occlusion output is accumulated
+// with them, other integrators render it in separate pass.
+fn accumulates_aovs(scene: &Scene) -> bool {
+    if scene.bake_map.is_some() || scene.settings.bucket_output.is_some() {
+        return false
+    }
+    match &scene.settings.rendering_algorithm {
+        RenderingAlgorithm::AmbientOcclusion(_) | RenderingAlgorithm::DirectLighting => true,
+        RenderingAlgorithm::RandomWalk(rw_settings) => !rw_settings.wavefront,
+        _ => false
+    }
+}
 
-fn render_scene(scene: &Scene) -> RGB8uffer {
-    match scene.settings.rendering_algorithm {
+fn render_with_integrator(scene: &Scene) -> RGB8uffer {
+    if let Some(ao_output) = scene.settings.ao_output.as_ref().filter(|_| !accumulates_aovs(scene)) {
+        if let Err(e) = ambient_occlusion_pass(scene, ao_output) {
+            println!("Error saving ambient occlusion image {}: {:?}", ao_output.output_fname, e);
+        }
+    }
+    if let Some(map) = &scene.bake_map {
+        return bake_integrator(scene, map)
+    }
+    let image = match &scene.settings.rendering_algorithm {
         RenderingAlgorithm::AmbientOcclusion(ao_settings) => {
-            ambient_occlusion_integrator(scene, &ao_settings)
+            ambient_occlusion_integrator(scene, ao_settings)
         }
         RenderingAlgorithm::DirectLighting => {
             direct_lgt_integrator(scene)
         }
         RenderingAlgorithm::RandomWalk(rw_settings) => {
-            random_walk_integrator(scene, &rw_settings)
+            if rw_settings.wavefront {
+                random_walk_wavefront_integrator(scene, rw_settings)
+            } else {
+                random_walk_integrator(scene, rw_settings)
+            }
+        }
+        RenderingAlgorithm::Furnace(furnace_settings) => {
+            furnace_integrator(scene, furnace_settings)
+        }
+        RenderingAlgorithm::Sppm(sppm_settings) => {
+            sppm_integrator(scene, sppm_settings)
+        }
+        RenderingAlgorithm::Intersector(intersector_settings) => {
+            intersector_integrator(scene, intersector_settings)
         }
         _ => {
             panic!("Unsupported algorithm");
         }
+    };
+    if let Some(telemetry) = &scene.telemetry {
+        println!("Sampling telemetry\n{}", telemetry);
     }
+    image
+}
+
+/// Parse scene from memory buffer, render it and return encoded PNG image.
+pub fn render_scene_to_png(data: &[u8], format: SceneFormat) -> Result<Vec<u8>, Box<dyn Error>> {
+    let desc = parse_scene_description(data, format)?;
+    let scene = Scene::try_from(desc)?;
+    render_scene(&scene).encode_png()
 }
 
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    #[cfg(feature = "fs")]
     use std::time::Instant;
+    #[cfg(feature = "fs")]
     use crate::pbrt_v4::parse_pbrt_v4_input_file;
+    #[cfg(feature = "fs")]
     use crate::json::load_scene_description_from_json;
 
     #[test]
+    #[cfg(feature = "fs")]
     fn test_render_scene() {
         // let path = "D://rtlib_scenes//sphere//sphere.json";
         // let path = "D://rtlib_scenes//spheres//spheres.json";
@@ -278,11 +2111,1323 @@ mod tests {
                 return;
             }
         };
-        let scene = Scene::from(scene_description);
+        let scene = Scene::try_from(scene_description).unwrap();
         let total_time = Instant::now();
         let image = render_scene(&scene);
         let total_duration = total_time.elapsed();
         println!("Rendering time: {:?}", total_duration);
         let _res = image.save(scene.settings.output_fname);
     }
+
+    #[test]
+    fn scene_conversion_errors() {
+        use crate::scene::{SceneDescription, LpeOutput};
+        use crate::materials::MaterialDescription;
+        use crate::shapes::{ShapeDescription, SphereDescription};
+
+        let mut desc = SceneDescription::default();
+        desc.materials.push(MaterialDescription { name: "matte".to_string(), ..Default::default() });
+        desc.shapes.push(ShapeDescription::Sphere(SphereDescription { material: "matte".to_string(), ..Default::default() }));
+        desc.shapes.push(ShapeDescription::Sphere(SphereDescription { material: "missing".to_string(), ..Default::default() }));
+        let err = Scene::try_from(desc).err().unwrap();
+        assert!(err.to_string().contains("missing"));
+
+        let mut desc = SceneDescription::default();
+        desc.settings.lpes.push(LpeOutput { expression: "C(".to_string(), output_fname: "lpe.png".to_string() });
+        assert!(Scene::try_from(desc).is_err());
+    }
+
+    #[test]
+    fn aov_first_hit_values() {
+        let text = br#"
+            LookAt 0 0 3  0 0 0  0 1 0
+            Camera "perspective" "float fov" 45
+            Film "rgb" "integer xresolution" 4 "integer yresolution" 4
+                "string aovs" [ "depth" "depth.png" "normal" "normal.png" "albedo" "albedo.png" ]
+            WorldBegin
+            Material "diffuse" "rgb reflectance" [ 0.2 0.4 0.6 ]
+            Shape "sphere" "float radius" 1
+        "#;
+        let desc = parse_scene_description(text, SceneFormat::Pbrt).unwrap();
+        assert_eq!(desc.settings.aovs.len(), 3);
+        let scene = Scene::try_from(desc).unwrap();
+        let mut aovs = create_aov_buffers(&scene);
+        let ray = Ray::new(Point3::new(0.0, 0.0, 3.0), Vec3::new(0.0, 0.0, -1.0));
+        add_aov_sample(&scene, &mut aovs, 1, 2, &ray, scene.geometry.intersect(&ray).as_ref());
+        let miss = Ray::new(Point3::new(0.0, 0.0, 3.0), Vec3::new(0.0, 0.0, 1.0));
+        add_aov_sample(&scene, &mut aovs, 0, 0, &miss, None);
+
+        let aovs = aovs.unwrap();
+        assert_eq!(aovs.types()[1], crate::color::AovType::GeometricNormal);
+        assert!((aovs.get(0, 1, 2).unwrap().r - 2.0).abs() < 1e-4);
+        assert!((aovs.get(1, 1, 2).unwrap().b - 1.0).abs() < 1e-4);
+        let albedo = aovs.get(2, 1, 2).unwrap();
+        assert!((albedo.r - 0.2).abs() < 1e-4 && (albedo.b - 0.6).abs() < 1e-4);
+        assert!(aovs.get(0, 0, 0).is_none());
+
+        let text = br#"
+            Film "rgb" "string aovs" [ "depth" ]
+            WorldBegin
+        "#;
+        assert!(parse_scene_description(text, SceneFormat::Pbrt).is_err());
+    }
+
+    #[test]
+    fn aov_skips_medium_interfaces() {
+        use crate::scene::SceneDescription;
+        use crate::materials::{MaterialDescription, MaterialType};
+        use crate::shapes::{ShapeDescription, SphereDescription};
+        use crate::color::AovType;
+
+        // Diffuse sphere inside bigger sphere that only bounds a medium
+        let mut desc = SceneDescription::default();
+        desc.settings.aovs.push(crate::scene::AovOutput { typ: AovType::Depth, output_fname: "depth.png".to_string() });
+        desc.settings.aovs.push(crate::scene::AovOutput { typ: AovType::Albedo, output_fname: "albedo.png".to_string() });
+        desc.materials.push(MaterialDescription { name: "interface".to_string(), typ: MaterialType::Interface, ..Default::default() });
+        desc.materials.push(MaterialDescription { name: "matte".to_string(), diffuse: RGB::new(0.2, 0.4, 0.6), ..Default::default() });
+        desc.shapes.push(ShapeDescription::Sphere(SphereDescription { radius: 2.0, material: "interface".to_string(), ..Default::default() }));
+        desc.shapes.push(ShapeDescription::Sphere(SphereDescription { radius: 1.0, material: "matte".to_string(), ..Default::default() }));
+        let scene = Scene::try_from(desc).unwrap();
+
+        let mut aovs = create_aov_buffers(&scene);
+        let ray = Ray::new(Point3::new(0.0, 0.0, 3.0), Vec3::new(0.0, 0.0, -1.0));
+        let isect_p = scene.geometry.intersect(&ray);
+        assert!((isect_p.as_ref().unwrap().t - 1.0).abs() < 1e-4);
+        add_aov_sample(&scene, &mut aovs, 0, 0, &ray, isect_p.as_ref());
+        // ray leaves through the interface when it misses the inner sphere
+        let miss = Ray::new(Point3::new(0.0, 1.5, 3.0), Vec3::new(0.0, 0.0, -1.0));
+        add_aov_sample(&scene, &mut aovs, 1, 0, &miss, scene.geometry.intersect(&miss).as_ref());
+
+        let aovs = aovs.unwrap();
+        assert!((aovs.get(0, 0, 0).unwrap().r - 2.0).abs() < 1e-3);
+        assert!((aovs.get(1, 0, 0).unwrap().r - 0.2).abs() < 1e-4);
+        assert!(aovs.get(0, 1, 0).is_none());
+    }
+
+    #[test]
+    fn intersector_preview() {
+        let text = br#"
+            LookAt 0 0 3  0 0 0  0 1 0
+            Camera "perspective" "float fov" 45
+            Film "rgb" "integer xresolution" 8 "integer yresolution" 8
+            Integrator "intersector" "string shading" "albedo"
+            WorldBegin
+            Material "diffuse" "rgb reflectance" [ 0.2 0.4 0.6 ]
+            Shape "sphere" "float radius" 1
+        "#;
+        let desc = parse_scene_description(text, SceneFormat::Pbrt).unwrap();
+        let scene = Scene::try_from(desc).unwrap();
+        assert_eq!(render_scene(&scene).size().width, 8);
+        // wide filter of the scene doesn't blur sphere into corner of the preview
+        let filtered = String::from_utf8_lossy(text).replace("WorldBegin", "PixelFilter \"gaussian\" \"float xradius\" 3 \"float yradius\" 3\nWorldBegin");
+        let filtered = Scene::try_from(parse_scene_description(filtered.as_bytes(), SceneFormat::Pbrt).unwrap()).unwrap();
+        assert_eq!(render_scene(&filtered).get(0, 0).unwrap().red, 0);
+
+        let ray = Ray::new(Point3::new(0.0, 0.0, 3.0), Vec3::new(0.0, 0.0, -1.0));
+        let rgb = primary_hit_shading(&ray, &scene, PreviewShading::Albedo);
+        assert!((rgb.g - 0.4).abs() < 1e-4);
+        let rgb = primary_hit_shading(&ray, &scene, PreviewShading::Facing);
+        assert!((rgb.r - 1.0).abs() < 1e-4);
+        let miss = Ray::new(Point3::new(0.0, 0.0, 3.0), Vec3::new(0.0, 0.0, 1.0));
+        assert_eq!(primary_hit_shading(&miss, &scene, PreviewShading::Facing).r, 0.0);
+    }
+
+    #[test]
+    fn direct_lighting_one_light_strategy() {
+        use crate::light_sampler::{LightSamplerType, create_light_sampler};
+        use crate::scene::SceneDescription;
+        use crate::materials::MaterialDescription;
+        use crate::shapes::{ShapeDescription, SphereDescription};
+        use crate::lights::{LightDescription, LightType};
+
+        let mut desc = SceneDescription::default();
+        desc.materials.push(MaterialDescription { name: "matte".to_string(), ..Default::default() });
+        desc.shapes.push(ShapeDescription::Sphere(SphereDescription { material: "matte".to_string(), ..Default::default() }));
+        desc.lights.push(LightDescription { typ: LightType::Point, position: Point3::new(2.0, 0.0, 4.0),
+                                            intensity: RGB::new(5.0, 5.0, 5.0), ..Default::default() });
+        desc.lights.push(LightDescription { typ: LightType::Point, position: Point3::new(-1.0, 1.0, 3.0),
+                                            intensity: RGB::new(2.0, 2.0, 2.0), ..Default::default() });
+        let mut scene = Scene::try_from(desc).unwrap();
+        let ray = Ray::new(Point3::new(0.0, 0.0, 3.0), Vec3::new(0.0, 0.0, -1.0));
+        let mut sampler: Box<dyn SamplerInterface> = Box::new(crate::samplers::RandomPathSampler::new(1));
+        let all = direct_lighting(&ray, &scene, &mut sampler, &mut [], None);
+        assert!(all.r > 0.0);
+
+        scene.settings.light_strategy = LightStrategy::One;
+        for typ in [LightSamplerType::Uniform, LightSamplerType::Power, LightSamplerType::Bvh] {
+            scene.light_sampler = create_light_sampler(typ, &scene.lights);
+            let n = 4000;
+            let sum = (0..n).fold(0.0, |acc, _| acc + direct_lighting(&ray, &scene, &mut sampler, &mut [], None).r);
+            assert!((sum / n as f32 - all.r).abs() < 0.05 * all.r);
+        }
+    }
+
+    #[test]
+    fn restir_direct_lighting() {
+        let mut text = String::from(r#"
+            LookAt 0 0 3  0 0 0  0 1 0
+            Camera "perspective" "float fov" 45
+            Sampler "independent" "integer pixelsamples" 16
+            Film "rgb" "integer xresolution" 32 "integer yresolution" 32
+            Integrator "direct_lighting" "string strategy" "restir" "integer candidates" 8
+                "integer spatialsamples" 3 "integer spatialradius" 4
+            WorldBegin
+        "#);
+        for i in 0..40 {
+            let phi = i as f32 * 0.7;
+            let (x, y, z) = (3.0 * phi.cos(), 3.0 * phi.sin(), 1.0 + (i % 5) as f32);
+            let intensity = 0.5 + (i % 3) as f32;
+            text += &format!("LightSource \"point\" \"point3 from\" [{} {} {}] \"rgb I\" [{} {} {}]\n", x, y, z, intensity, intensity, intensity);
+        }
+        text += "Material \"diffuse\"\nShape \"sphere\" \"float radius\" 1\n";
+        let desc = parse_scene_description(text.as_bytes(), SceneFormat::Pbrt).unwrap();
+        let settings = RestirProperties { candidates: 8, spatial_samples: 3, spatial_radius: 4 };
+        assert_eq!(desc.settings.light_strategy, LightStrategy::Restir(settings));
+        let mut scene = Scene::try_from(desc).unwrap();
+
+        // candidates of single hit are resampled
+        let ray = Ray::new(Point3::new(0.0, 0.0, 3.0), Vec3::new(0.1, 0.2, -1.0).normalize());
+        let mut sampler: Box<dyn SamplerInterface> = Box::new(crate::samplers::RandomPathSampler::new(1));
+        scene.settings.light_strategy = LightStrategy::All;
+        let all = direct_lighting(&ray, &scene, &mut sampler, &mut [], None);
+        scene.settings.light_strategy = LightStrategy::Restir(settings);
+        let n = 4000;
+        let sum = (0..n).fold(0.0, |acc, _| acc + direct_lighting(&ray, &scene, &mut sampler, &mut [], None).r);
+        assert!((sum / n as f32 - all.r).abs() < 0.05 * all.r);
+
+        // spatial reuse keeps average radiance of the image
+        let average = |film: &FilmBuffers| film.radiance.resolve().iter().map(|p| p.r).sum::<f32>() / (32.0 * 32.0);
+        let restir = render_tiles(&scene, TileOutputs::default(), |buffers, sampler| {
+            for i in 0..scene.settings.spp {
+                restir_pass(&scene, &settings, buffers, sampler, i);
+            }
+        });
+        scene.settings.light_strategy = LightStrategy::All;
+        let reference = render_tiles(&scene, TileOutputs::default(), |buffers, sampler| {
+            for (x, y) in buffers.tile {
+                let ray = scene.camera.generate_ray(x as f32 + 0.5, y as f32 + 0.5);
+                let rgb = direct_lighting(&ray, &scene, sampler, &mut [], None);
+                buffers.add(x, y, x as f32 + 0.5, y as f32 + 0.5, &rgb);
+            }
+        });
+        assert!(average(&reference) > 0.0);
+        assert!((average(&restir) - average(&reference)).abs() < 0.05 * average(&reference));
+    }
+
+    #[test]
+    fn direct_lighting_through_glass() {
+        let text = r#"
+            LookAt 0 0 3  0 0 0  0 1 0
+            Camera "perspective" "float fov" 45
+            Integrator "direct_lighting"
+            WorldBegin
+            LightSource "point" "point3 from" [0 3 0] "rgb I" [4 4 4]
+            AttributeBegin
+            Material "diffuse"
+            Translate 0 0 -3
+            Shape "sphere" "float radius" 1
+            AttributeEnd
+            Material "dielectric" "spectrum eta" "glass-BK7"
+            Shape "sphere" "float radius" 1
+        "#;
+        let desc = parse_scene_description(text.as_bytes(), SceneFormat::Pbrt).unwrap();
+        let mut scene = Scene::try_from(desc).unwrap();
+        let ray = Ray::new(Point3::new(0.0, 0.0, 3.0), Vec3::new(0.0, 0.0, -1.0));
+        let mut sampler: Box<dyn SamplerInterface> = Box::new(crate::samplers::RandomPathSampler::new(1));
+        let mut values = Vec::new();
+        for _ in 0..1000 {
+            values.push(direct_lighting(&ray, &scene, &mut sampler, &mut [], None).r);
+        }
+        // ray is refracted through center of glass without change of direction, other rays are reflected to black background
+        scene.materials[1] = Box::new(crate::materials::InterfaceMaterial);
+        let unobstructed = direct_lighting(&ray, &scene, &mut sampler, &mut [], None).r;
+        assert!(unobstructed > 0.0);
+        let transmitted = values.iter().filter(|v| **v > 0.0).count();
+        assert!(values.iter().all(|v| *v == 0.0 || (v - unobstructed).abs() < 1e-4 * unobstructed));
+        assert!(transmitted > 880 && transmitted < 960);
+    }
+
+    #[test]
+    fn transparent_shadows() {
+        let scene_text = |panes: usize| {
+            let mut text = String::from(r#"
+                Integrator "direct_lighting"
+                WorldBegin
+                LightSource "point" "point3 from" [0 3 0] "rgb I" [4 4 4]
+                Material "thindielectric" "float eta" 1.5
+            "#);
+            for i in 0..panes {
+                let y = 1.0 + 0.1 * i as f32;
+                text.push_str(&format!(r#"Shape "trianglemesh" "point3 P" [-1 {y} -1  1 {y} -1  1 {y} 1  -1 {y} 1]
+                    "integer indices" [0 1 2 0 2 3]
+                "#));
+            }
+            Scene::try_from(parse_scene_description(text.as_bytes(), SceneFormat::Pbrt).unwrap()).unwrap()
+        };
+        let mut sampler: Box<dyn SamplerInterface> = Box::new(crate::samplers::RandomPathSampler::new(1));
+        let (p1, normal, p2) = (Point3::new(0.0, 0.0, 0.0), Normal::new(0.0, 1.0, 0.0), Point3::new(0.0, 3.0, 0.0));
+        // window glass slab with eta 1.5 transmits 12/13 of light at normal incidence
+        let pane = 12.0 / 13.0;
+        let tr = transmittance(p1, normal, p2, &scene_text(1), &mut sampler);
+        assert!((tr.g - pane).abs() < 1e-5);
+
+        // roulette is played behind third pane, its estimate is unbiased
+        let scene = scene_text(6);
+        let n = 20000;
+        let mut terminated = 0;
+        let sum = (0..n).fold(0.0, |acc, _| {
+            let tr = transmittance(p1, normal, p2, &scene, &mut sampler);
+            if tr.is_black() { terminated += 1; }
+            acc + tr.g
+        });
+        assert!(terminated > 0);
+        assert!((sum / n as f32 - pane.powi(6)).abs() < 0.02);
+
+        let mut scene = scene_text(1);
+        scene.materials[0] = Box::new(crate::materials::MatteMaterial::new(RGB::new(0.5, 0.5, 0.5)));
+        assert!(transmittance(p1, normal, p2, &scene, &mut sampler).is_black());
+        assert!(!visible(p1, normal, p2, &scene.geometry));
+    }
+
+    #[test]
+    fn direct_lighting_mis() {
+        let text = r#"
+            LookAt 0 0 3  0 0 0  0 1 0
+            Camera "perspective" "float fov" 45
+            Integrator "direct_lighting" "bool mis" false
+            WorldBegin
+            LightSource "point" "point3 from" [1 2 3] "rgb I" [4 4 4] "float radius" 0.5
+            LightSource "point" "point3 from" [-2 0 2] "rgb I" [1 1 1]
+            LightSource "infinite" "rgb L" [0.3 0.3 0.3]
+            Material "diffuse"
+            Shape "sphere" "float radius" 1
+        "#;
+        let desc = parse_scene_description(text.as_bytes(), SceneFormat::Pbrt).unwrap();
+        assert!(!desc.settings.mis);
+        let mut scene = Scene::try_from(desc).unwrap();
+        let ray = Ray::new(Point3::new(0.0, 0.0, 3.0), Vec3::new(0.1, 0.2, -1.0).normalize());
+        let mut sampler: Box<dyn SamplerInterface> = Box::new(crate::samplers::RandomPathSampler::new(1));
+        let n = 20000;
+        let mut average = |scene: &Scene| {
+            (0..n).fold(0.0, |acc, _| acc + direct_lighting(&ray, scene, &mut sampler, &mut [], None).r) / n as f32
+        };
+        let reference = average(&scene);
+        assert!(reference > 0.0);
+        scene.settings.mis = true;
+        for strategy in [LightStrategy::All, LightStrategy::One] {
+            scene.settings.light_strategy = strategy;
+            assert!((average(&scene) - reference).abs() < 0.02 * reference);
+        }
+    }
+
+    #[test]
+    fn direct_lighting_starts_bounce() {
+        let text = r#"
+            LookAt 0 0 3  0 0 0  0 1 0
+            Camera "perspective" "float fov" 45
+            Integrator "direct_lighting" "bool mis" true
+            WorldBegin
+            LightSource "infinite" "rgb L" [0.5 0.5 0.5]
+            Material "diffuse"
+            Shape "sphere" "float radius" 1
+        "#;
+        let desc = parse_scene_description(text.as_bytes(), SceneFormat::Pbrt).unwrap();
+        let scene = Scene::try_from(desc).unwrap();
+        let ray = Ray::new(Point3::new(0.0, 0.0, 3.0), Vec3::new(0.1, 0.2, -1.0).normalize());
+        let mut sampler: Box<dyn SamplerInterface> = Box::new(crate::samplers::StratifiedPathSampler::new(1, 4, 4, false));
+        sampler.sample_pixel(0, 0, 5);
+        let reference = direct_lighting(&ray, &scene, &mut sampler, &mut [], None);
+        assert!(reference.luminance() > 0.0);
+        // samples used before the first hit don't change samples of direct lighting
+        sampler.sample_pixel(0, 0, 5);
+        sampler.next_2d();
+        let radiance = direct_lighting(&ray, &scene, &mut sampler, &mut [], None);
+        assert_eq!((radiance.r, radiance.g, radiance.b), (reference.r, reference.g, reference.b));
+    }
+
+    #[test]
+    fn sampling_telemetry() {
+        let text = r#"
+            LookAt 0 0 3  0 0 0  0 1 0
+            Camera "perspective" "float fov" 45
+            Film "rgb" "bool telemetry" true
+            Integrator "direct_lighting" "bool mis" true
+            WorldBegin
+            LightSource "point" "point3 from" [1 2 3] "rgb I" [4 4 4] "float radius" 0.5
+            LightSource "point" "point3 from" [-2 0 2] "rgb I" [1 1 1]
+            Material "diffuse"
+            Shape "sphere" "float radius" 1
+        "#;
+        let desc = parse_scene_description(text.as_bytes(), SceneFormat::Pbrt).unwrap();
+        let mut scene = Scene::try_from(desc).unwrap();
+        scene.settings.light_strategy = LightStrategy::All;
+        let ray = Ray::new(Point3::new(0.0, 0.0, 3.0), Vec3::new(0.1, 0.2, -1.0).normalize());
+        let mut sampler: Box<dyn SamplerInterface> = Box::new(crate::samplers::RandomPathSampler::new(1));
+        let n = 1000;
+        for _ in 0..n {
+            direct_lighting(&ray, &scene, &mut sampler, &mut [], None);
+        }
+        let telemetry = scene.telemetry.as_ref().unwrap();
+        // samples of point light aren't combined with BSDF sampling
+        let (area, point) = (telemetry.light(0), telemetry.light(1));
+        assert_eq!((area.samples(), point.samples()), (n, n));
+        assert!(area.mean_mis_weight().is_some() && point.mean_mis_weight().is_none());
+        let material = telemetry.material(0);
+        assert_eq!(material.samples(), n);
+        // most of BSDF samples miss small light
+        assert!(material.rejected() > n / 2 && material.mean_mis_weight().is_some());
+    }
+
+    #[test]
+    fn sampling_telemetry_random_walk() {
+        let text = r#"
+            LookAt 0 0 3  0 0 0  0 1 0
+            Camera "perspective" "float fov" 45
+            Film "rgb" "bool telemetry" true
+            Integrator "randomwalk" "integer maxdepth" 3
+            WorldBegin
+            LightSource "point" "point3 from" [1 2 3] "rgb I" [4 4 4]
+            Material "diffuse"
+            Shape "sphere" "float radius" 1
+        "#;
+        let desc = parse_scene_description(text.as_bytes(), SceneFormat::Pbrt).unwrap();
+        let scene = Scene::try_from(desc).unwrap();
+        let rw_settings = match &scene.settings.rendering_algorithm {
+            RenderingAlgorithm::RandomWalk(rw_settings) => *rw_settings,
+            _ => panic!("Random walk expected")
+        };
+        let ray = Ray::new(Point3::new(0.0, 0.0, 3.0), Vec3::new(0.0, 0.0, -1.0));
+        let mut sampler: Box<dyn SamplerInterface> = Box::new(crate::samplers::RandomPathSampler::new(1));
+        let n = 100;
+        for _ in 0..n {
+            random_walk(&ray, &scene, &mut sampler, &rw_settings, None, &mut [], None);
+        }
+        let telemetry = scene.telemetry.as_ref().unwrap();
+        assert!(telemetry.material(0).samples() >= n);
+        // random walk samples lights only from media
+        assert_eq!(telemetry.light(0).samples(), 0);
+
+        let text = text.replace(r#""randomwalk" "integer maxdepth" 3"#, r#""ambientocclusion""#);
+        let desc = parse_scene_description(text.as_bytes(), SceneFormat::Pbrt).unwrap();
+        assert!(desc.settings.telemetry);
+        assert!(Scene::try_from(desc).unwrap().telemetry.is_none());
+    }
+
+    #[test]
+    fn indirect_diffuse_of_emissive_material() {
+        let text = r#"
+            LookAt 0 3 3  0 0 0  0 1 0
+            Camera "perspective" "float fov" 30
+            Film "rgb" "integer xresolution" 8 "integer yresolution" 8
+            Integrator "direct_lighting" "bool indirectdiffuse" true "integer irradiancesamples" 64
+            WorldBegin
+            Material "diffuse" "rgb reflectance" [0.7 0.7 0.7]
+            Shape "trianglemesh" "point3 P" [-5 0 -5  5 0 -5  5 0 5  -5 0 5] "integer indices" [0 2 1  0 3 2]
+            AttributeBegin
+            AreaLightSource "diffuse" "rgb L" [4 4 4]
+            Translate 0 2 0
+            Shape "sphere" "float radius" 0.5
+            AttributeEnd
+        "#;
+        let mut scene = Scene::try_from(parse_scene_description(text.as_bytes(), SceneFormat::Pbrt).unwrap()).unwrap();
+        assert!(scene.lights.is_empty());
+        scene.irradiance_cache = None;
+        // floor below the emissive sphere is lit only by gathered emission
+        let ray = Ray::new(Point3::new(0.0, 1.0, 0.0), Vec3::new(0.0, -1.0, 0.0));
+        let mut sampler: Box<dyn SamplerInterface> = Box::new(crate::samplers::RandomPathSampler::new(1));
+        let rgb = direct_lighting(&ray, &scene, &mut sampler, &mut [], None);
+        assert!(rgb.r > 0.0);
+    }
+
+    #[test]
+    fn irradiance_cache_indirect_diffuse() {
+        let text = r#"
+            LookAt 0 1 4  0 0.5 0  0 1 0
+            Camera "perspective" "float fov" 45
+            Film "rgb" "integer xresolution" 24 "integer yresolution" 24
+            Integrator "direct_lighting" "bool indirectdiffuse" true "integer irradiancesamples" 64
+            WorldBegin
+            LightSource "point" "point3 from" [1 3 2] "rgb I" [20 20 20]
+            Material "diffuse" "rgb reflectance" [0.7 0.7 0.7]
+            Shape "trianglemesh" "point3 P" [-5 0 -5  5 0 -5  5 0 5  -5 0 5] "integer indices" [0 2 1  0 3 2]
+            Translate 0 0.5 0
+            Shape "sphere" "float radius" 0.5
+        "#;
+        let desc = parse_scene_description(text.as_bytes(), SceneFormat::Pbrt).unwrap();
+        let settings = desc.settings.irradiance_cache.unwrap();
+        assert!(settings.enabled && settings.samples == 64);
+        let mut scene = Scene::try_from(desc).unwrap();
+        let cache = scene.irradiance_cache.take().unwrap();
+
+        let render = |scene: &Scene| {
+            let film = render_tiles(scene, TileOutputs::default(), |buffers, sampler| {
+                for (x, y) in buffers.tile {
+                    let ray = scene.camera.generate_ray(x as f32 + 0.5, y as f32 + 0.5);
+                    let rgb = direct_lighting(&ray, scene, sampler, &mut [], None);
+                    buffers.add(x, y, x as f32 + 0.5, y as f32 + 0.5, &rgb);
+                }
+            });
+            film.radiance.resolve().iter().map(|p| p.r).sum::<f32>() / (24.0 * 24.0)
+        };
+        // disabled cache estimates irradiance at every hit
+        let reference = render(&scene);
+        scene.irradiance_cache = Some(cache);
+        let cached = render(&scene);
+        let records = scene.irradiance_cache.as_ref().unwrap().len();
+        assert!(records > 0 && records < 24 * 24 / 2);
+        scene.settings.irradiance_cache = None;
+        let direct = render(&scene);
+        assert!(reference > direct);
+        assert!((cached - reference).abs() < 0.1 * (reference - direct));
+    }
+
+    #[test]
+    fn tile_importance_samples() {
+        let tiles = Tile::new(0, 0, 64, 48).split(32, 32);
+        assert_eq!(allocate_tile_samples(&tiles, &[0.0; 4], 8, 1), vec![8; 4]);
+        // extra 7 samples per pixel go to tiles with variance, bottom tiles have half of pixels
+        let spp = allocate_tile_samples(&tiles, &[1.0, 0.0, 4.0, 0.0], 8, 1);
+        assert_eq!(spp, vec![8, 1, 29, 1]);
+        let pixels: Vec<_> = tiles.iter().map(|tile| tile.width() * tile.height()).collect();
+        assert_eq!(spp.iter().zip(pixels.iter()).map(|(s, p)| s * p).sum::<usize>(), 8 * 64 * 48);
+        // tiles without variance still get one sample per pixel
+        assert_eq!(allocate_tile_samples(&tiles, &[1.0, 0.0, 4.0, 0.0], 8, 0)[1], 1);
+
+        let text = r#"
+            LookAt 0 0 5  0 0 0  0 1 0
+            Camera "perspective" "float fov" 45
+            Sampler "independent" "integer pixelsamples" 8
+            Film "rgb" "integer xresolution" 64 "integer yresolution" 32 "bool tileimportance" true
+                "integer prepasssamples" 2
+            Integrator "direct_lighting"
+            WorldBegin
+            LightSource "point" "point3 from" [0 2 5] "rgb I" [10 10 10]
+            Material "diffuse"
+            Translate 1.2 0 0
+            Shape "sphere" "float radius" 0.5
+        "#;
+        let desc = parse_scene_description(text.as_bytes(), SceneFormat::Pbrt).unwrap();
+        assert_eq!(desc.settings.tile_importance, Some(crate::scene::TileImportanceProperties { prepass_spp: 2, min_spp: 1 }));
+        let scene = Scene::try_from(desc).unwrap();
+
+        // sharp edge between pixels isn't noise, only different samples of one pixel are
+        let mut buffers = TileBuffers::new(&scene, Tile::new(0, 0, 2, 1), &TileOutputs { variance: true, ..Default::default() });
+        for _ in 0..4 {
+            buffers.add(0, 0, 0.5, 0.5, &RGB::zero());
+            buffers.add(1, 0, 1.5, 0.5, &RGB::new(1.0, 1.0, 1.0));
+        }
+        assert_eq!(tile_deviation(&buffers), 0.0);
+        buffers.add(1, 0, 1.5, 0.5, &RGB::zero());
+        assert!(tile_deviation(&buffers) > 0.0);
+
+        let passes = Mutex::new(Vec::new());
+        let film = render_tiles(&scene, TileOutputs::default(), |buffers, sampler| {
+            for i in buffers.passes() {
+                for (x, y) in buffers.tile {
+                    let (sx, sy) = sampler.sample_pixel(x, y, i);
+                    let ray = scene.camera.generate_ray(x as f32 + sx, y as f32 + sy);
+                    let rgb = direct_lighting(&ray, &scene, sampler, &mut [], None);
+                    buffers.add(x, y, x as f32 + sx, y as f32 + sy, &rgb);
+                }
+            }
+            passes.lock().unwrap().push((buffers.tile.x1, buffers.spp));
+        });
+        let mut passes = passes.into_inner().unwrap();
+        passes.sort();
+        // sphere is only in left tile, empty background gets minimal number of samples
+        assert_eq!(passes, vec![(0, 2), (0, 15), (32, 1), (32, 2)]);
+        assert!(film.radiance.get(10, 16).unwrap().weight > 0.0);
+    }
+
+    #[test]
+    fn guided_random_walk() {
+        let text = r#"
+            LookAt 0 0 5  0 0 0  0 1 0
+            Camera "perspective" "float fov" 45
+            Sampler "independent" "integer pixelsamples" 16
+            Film "rgb" "integer xresolution" 16 "integer yresolution" 16
+            Integrator "randomwalk" "integer maxdepth" 3 "bool guiding" true "integer guidingiterations" 3
+                "float bsdffraction" 0.3 "float spatialthreshold" 10
+            WorldBegin
+            AttributeBegin
+            AreaLightSource "diffuse" "rgb L" [4 4 4]
+            Translate 0 5 0
+            Shape "sphere" "float radius" 2
+            AttributeEnd
+            Material "diffuse"
+            Shape "sphere" "float radius" 1.5
+        "#;
+        let desc = parse_scene_description(text.as_bytes(), SceneFormat::Pbrt).unwrap();
+        let guiding = crate::path_guiding::PathGuidingProperties { iterations: 3, bsdf_fraction: 0.3,
+            spatial_threshold: 10.0, ..Default::default() };
+        assert_eq!(desc.settings.path_guiding, Some(guiding));
+        let rw_settings = match desc.settings.rendering_algorithm {
+            RenderingAlgorithm::RandomWalk(settings) => settings,
+            _ => panic!("Random walk integrator expected")
+        };
+        let mut unguided_desc = parse_scene_description(text.as_bytes(), SceneFormat::Pbrt).unwrap();
+        unguided_desc.settings.path_guiding = None;
+
+        let scene = Scene::try_from(desc).unwrap();
+        let guided = random_walk_integrator(&scene, &rw_settings);
+        let guide = scene.path_guide.as_ref().unwrap();
+        assert!(!guide.is_training());
+        assert!(guide.leaves() > 1);
+        // top of the sphere learned that light is above it
+        // light is above the sphere, so most of learned directions go up
+        let p = Point3::new(0.0, 1.2, 0.9);
+        let u = |i: usize| (i as f32 + 0.5) / 32.0;
+        let up = (0..32 * 32).filter(|i| guide.sample(p, u(i % 32), u(i / 32)).0.y > 0.5).count();
+        assert!(up > 512);
+
+        let scene = Scene::try_from(unguided_desc).unwrap();
+        assert!(scene.path_guide.is_none());
+        let unguided = random_walk_integrator(&scene, &rw_settings);
+        let mean = |image: &RGB8uffer| {
+            let mut sum = 0.0;
+            for y in 0..16 {
+                for x in 0..16 {
+                    let p = image.get(x, y).unwrap();
+                    sum += (p.red as f32 + p.green as f32 + p.blue as f32) / 3.0;
+                }
+            }
+            sum / 256.0
+        };
+        assert!(mean(&unguided) > 1.0);
+        assert!((mean(&guided) - mean(&unguided)).abs() < 0.1 * mean(&unguided));
+    }
+
+    #[test]
+    fn shading_normal_safeguards() {
+        let ng = Normal::new(0.0, 0.0, 1.0);
+        let isect_p = SurfaceInteraction { t: 1.0, hit_point: Point3::new(0.0, 0.0, 0.0), normal: ng,
+            shading_normal: Normal::new(0.5, 0.0, 1.0).normalize(), material_id: 0, back_side: false,
+            uv: crate::vec::Point2::new(0.0, 0.0), medium_interface: None, object_space: None, time: 0.0 };
+        let settings = ShadingNormalSettings { compensation: true, ..Default::default() };
+        let wo = Vec3::new(0.0, 0.0, 1.0);
+        let material = crate::materials::MatteMaterial::new(RGB::new(0.5, 0.5, 0.5));
+        let normal = isect_p.shading_normal;
+        let uniform = |wi: Vec3| {
+            let weight = material.eval(wo, normal, wi).map_or(RGB::zero(), |res| res.color * ((normal * wi).abs() / UNIFORM_SPHERE_PDF));
+            (wi, weight, UNIFORM_SPHERE_PDF)
+        };
+        // above shading hemisphere but below geometric surface, it would leak light
+        let wi = Vec3::new(1.0, 0.0, -0.2).normalize();
+        assert_eq!(shading_normal_factor(&isect_p, wo, wi, &settings), 0.0);
+        let (mirrored, _, pdfw) = compensate_direction(&isect_p, &material, wo, normal, uniform(wi), &settings, |_| UNIFORM_SPHERE_PDF);
+        assert!(mirrored.z > 0.0 && (mirrored.x - wi.x).abs() < 1e-6);
+        assert!(shading_normal_factor(&isect_p, wo, mirrored, &settings) > 0.0);
+        assert_eq!(pdfw, 2.0 * UNIFORM_SPHERE_PDF);
+        assert!(!ShadingNormalSettings::default().compensation);
+        let no_compensation = ShadingNormalSettings::default();
+        let (wi_rejected, _, _) = compensate_direction(&isect_p, &material, wo, normal, uniform(wi), &no_compensation, |_| UNIFORM_SPHERE_PDF);
+        assert_eq!(wi_rejected.z, wi.z);
+
+        // mirroring only moves energy that would be rejected, the estimate of reflected light stays the same
+        use crate::rng::{PCGRng, Rng};
+        let mut rng = PCGRng::new(7, 0);
+        let n = 200000;
+        let (mut rejected, mut compensated) = (0.0, 0.0);
+        for _ in 0..n {
+            let wi = sample_uniform_sphere(rng.rand_f32(), rng.rand_f32()).direction;
+            let (_, weight, _) = uniform(wi);
+            rejected += weight.r * shading_normal_factor(&isect_p, wo, wi, &no_compensation);
+            let (wi, weight, _) = compensate_direction(&isect_p, &material, wo, normal, uniform(wi), &settings, |_| UNIFORM_SPHERE_PDF);
+            compensated += weight.r * shading_normal_factor(&isect_p, wo, wi, &settings);
+        }
+        assert!((rejected - compensated).abs() < 0.01 * rejected, "rejected {} compensated {}", rejected / n as f32, compensated / n as f32);
+
+        // terminator smoothing darkens grazing light, it doesn't change light along normal
+        let wi = Vec3::new(0.0, 0.0, 1.0);
+        assert!(shading_normal_factor(&isect_p, wo, wi, &settings) > 0.999);
+        let wi = Vec3::new(1.0, 0.0, 0.05).normalize();
+        let factor = shading_normal_factor(&isect_p, wo, wi, &settings);
+        assert!(factor > 0.0 && factor < 1.0);
+        let disabled = ShadingNormalSettings { terminator_smoothing: false, ..settings };
+        assert_eq!(shading_normal_factor(&isect_p, wo, wi, &disabled), 1.0);
+    }
+
+    #[test]
+    fn radiance_clamping() {
+        let rgb = RGB::new(2.0, 8.0, 4.0);
+        assert_eq!(clamp_scale(&rgb, None), 1.0);
+        assert_eq!(clamp_scale(&rgb, Some(10.0)), 1.0);
+        let clamped = rgb * clamp_scale(&rgb, Some(4.0));
+        assert!((clamped.g - 4.0).abs() < 1e-6 && (clamped.r - 1.0).abs() < 1e-6);
+
+        let text = br#"
+            Integrator "randomwalk" "float maxcomponent" 5
+            WorldBegin
+        "#;
+        let desc = parse_scene_description(text, SceneFormat::Pbrt).unwrap();
+        assert_eq!(desc.settings.max_component, Some(5.0));
+    }
+
+    #[test]
+    fn russian_roulette_is_unbiased() {
+        let throughput = RGB::new(0.2, 0.3, 0.1);
+        let mut sampler: Box<dyn SamplerInterface> = Box::new(crate::samplers::RandomPathSampler::new(5));
+        assert_eq!(russian_roulette(throughput, 1, 3, &mut sampler).unwrap().g, 0.3);
+        let n = 20000;
+        let mut survived = 0;
+        let sum = (0..n).fold(0.0, |acc, _| {
+            acc + russian_roulette(throughput, 3, 3, &mut sampler).map_or(0.0, |t| { survived += 1; t.g })
+        });
+        assert!((sum / n as f32 - 0.3).abs() < 0.01);
+        assert!(survived < n / 2);
+    }
+
+    #[test]
+    fn random_walk_homogeneous_medium() {
+        use crate::scene::SceneDescription;
+        use crate::materials::{MaterialDescription, MaterialType};
+        use crate::shapes::{ShapeDescription, SphereDescription};
+        use crate::lights::{LightDescription, LightType};
+        use crate::media::{MediumDescription, MediumInterface};
+
+        // Sphere of medium inside uniform white environment
+        let medium_scene = |sigma_a: f32, sigma_s: f32| {
+            let mut desc = SceneDescription::default();
+            desc.materials.push(MaterialDescription { name: "interface".to_string(), typ: MaterialType::Interface, ..Default::default() });
+            desc.media.push(MediumDescription { name: "fog".to_string(), sigma_a: RGB::new(sigma_a, sigma_a, sigma_a),
+                                                sigma_s: RGB::new(sigma_s, sigma_s, sigma_s), g: 0.5, ..Default::default() });
+            desc.shapes.push(ShapeDescription::Sphere(SphereDescription { material: "interface".to_string(),
+                medium_interface: MediumInterface::new(Some("fog".to_string()), None), ..Default::default() }));
+            desc.lights.push(LightDescription { typ: LightType::Infinite, ..Default::default() });
+            Scene::try_from(desc).unwrap()
+        };
+        let ray = Ray::new(Point3::new(0.0, 0.0, 3.0), Vec3::new(0.0, 0.0, -1.0));
+        let mut sampler: Box<dyn SamplerInterface> = Box::new(crate::samplers::RandomPathSampler::new(1));
+        let n = 4000;
+
+        // absorption only, transmittance along diameter of the sphere
+        let scene = medium_scene(0.5, 0.0);
+        let mut sum = 0.0;
+        for _ in 0..n {
+            sum += random_walk(&ray, &scene, &mut sampler, &RandomWalkProperties { maxdepth: 10, ..Default::default() }, None, &mut [], None).r;
+        }
+        assert!((sum / n as f32 - (-1.0f32).exp()).abs() < 0.02, "transmitted {}", sum / n as f32);
+
+        // scattering without absorption conserves energy of environment
+        let scene = medium_scene(0.0, 2.0);
+        let mut sum = 0.0;
+        for _ in 0..n {
+            sum += random_walk(&ray, &scene, &mut sampler, &RandomWalkProperties { maxdepth: 200, ..Default::default() }, None, &mut [], None).r;
+        }
+        assert!((sum / n as f32 - 1.0).abs() < 0.02, "scattered {}", sum / n as f32);
+    }
+
+    #[test]
+    fn specular_chain_keeps_medium() {
+        use crate::scene::SceneDescription;
+        use crate::materials::{MaterialDescription, MaterialType};
+        use crate::shapes::{ShapeDescription, SphereDescription};
+        use crate::media::{MediumDescription, MediumInterface};
+
+        // diffuse sphere inside of glass sphere filled with medium
+        let mut desc = SceneDescription::default();
+        desc.materials.push(MaterialDescription { name: "glass".to_string(), typ: MaterialType::Dielectric, eta: 1.0, ..Default::default() });
+        desc.media.push(MediumDescription { name: "fog".to_string(), ..Default::default() });
+        desc.shapes.push(ShapeDescription::Sphere(SphereDescription { material: "glass".to_string(),
+            medium_interface: MediumInterface::new(Some("fog".to_string()), None), ..Default::default() }));
+        desc.materials.push(MaterialDescription::default());
+        desc.shapes.push(ShapeDescription::Sphere(SphereDescription { radius: 0.5, material: "matte".to_string(), ..Default::default() }));
+        let scene = Scene::try_from(desc).unwrap();
+        let mut sampler: Box<dyn SamplerInterface> = Box::new(crate::samplers::RandomPathSampler::new(1));
+        let ray = Ray::new(Point3::new(0.0, 0.0, 3.0), Vec3::new(0.0, 0.0, -1.0));
+        let (ray, hit, _) = specular_chain(&ray, scene.geometry.intersect(&ray), &scene, &mut sampler, None).unwrap();
+        assert!(hit.is_some());
+        assert_eq!(ray.medium, Some(0));
+    }
+
+    #[test]
+    fn random_walk_point_light_in_medium() {
+        use crate::scene::SceneDescription;
+        use crate::materials::{MaterialDescription, MaterialType};
+        use crate::shapes::{ShapeDescription, SphereDescription};
+        use crate::lights::LightDescription;
+        use crate::media::{MediumDescription, MediumInterface, MediumType};
+
+        // Point light in the center of sphere of non-absorbing fog, single scattering only
+        let (sigma_s, intensity) = (0.5, 2.0);
+        let fog = MediumDescription { name: "fog".to_string(), sigma_a: RGB::zero(),
+                                      sigma_s: RGB::new(sigma_s, sigma_s, sigma_s), g: 0.0, ..Default::default() };
+        // grid of constant density is the same fog, light is attenuated by ratio tracking
+        let grid = MediumDescription { name: "fog".to_string(), sigma_a: RGB::zero(), sigma_s: RGB::new(sigma_s, sigma_s, sigma_s),
+                                       typ: MediumType::UniformGrid, density: Some(vec![1.0; 8]), nx: 2, ny: 2, nz: 2,
+                                       p0: Point3::new(-2.0, -2.0, -2.0), p1: Point3::new(2.0, 2.0, 2.0), ..Default::default() };
+
+        // integral of sigma_s * Tr(start, x) * phase * I * Tr(x, light) / r^2 along the ray inside of the sphere,
+        // segment starts at z and ray travels in -z direction at height 0.5 above the light
+        let expected = |z: f32| {
+            let length = z + 0.75f32.sqrt();
+            let steps = 10000;
+            let mut expected = 0.0;
+            for i in 0..steps {
+                let s = (i as f32 + 0.5) * length / steps as f32;
+                let r2 = 0.25 + (z - s) * (z - s);
+                expected += sigma_s * (-sigma_s * s).exp() * 0.25 * std::f32::consts::FRAC_1_PI * intensity * (-sigma_s * r2.sqrt()).exp() / r2;
+            }
+            expected * length / steps as f32
+        };
+
+        for medium in [fog, grid] {
+            let mut desc = SceneDescription::default();
+            desc.materials.push(MaterialDescription { name: "interface".to_string(), typ: MaterialType::Interface, ..Default::default() });
+            desc.media.push(medium);
+            desc.shapes.push(ShapeDescription::Sphere(SphereDescription { material: "interface".to_string(),
+                medium_interface: MediumInterface::new(Some("fog".to_string()), None), ..Default::default() }));
+            desc.lights.push(LightDescription { intensity: RGB::new(intensity, intensity, intensity), ..Default::default() });
+            let scene = Scene::try_from(desc).unwrap();
+
+            let mut sampler: Box<dyn SamplerInterface> = Box::new(crate::samplers::RandomPathSampler::new(1));
+            let settings = RandomWalkProperties { maxdepth: 1, ..Default::default() };
+            let n = 20000;
+            let mut estimate = |ray: &Ray| {
+                (0..n).fold(0.0, |acc, _| acc + random_walk(ray, &scene, &mut sampler, &settings, None, &mut [], None).r) / n as f32
+            };
+
+            // ray starts inside the fog
+            let ray = Ray::new(Point3::new(0.0, 0.5, 0.5), Vec3::new(0.0, 0.0, -1.0)).with_medium(Some(0));
+            let (estimate_inside, expected_inside) = (estimate(&ray), expected(0.5));
+            assert!((estimate_inside - expected_inside).abs() < 0.02 * expected_inside,
+                    "estimate {} expected {}", estimate_inside, expected_inside);
+            // crossing of the medium boundary is not a bounce, so single scattering is still gathered
+            let ray = Ray::new(Point3::new(0.0, 0.5, 3.0), Vec3::new(0.0, 0.0, -1.0));
+            let (estimate_outside, expected_outside) = (estimate(&ray), expected(0.75f32.sqrt()));
+            assert!((estimate_outside - expected_outside).abs() < 0.02 * expected_outside,
+                    "estimate {} expected {}", estimate_outside, expected_outside);
+        }
+    }
+
+    #[test]
+    fn ambient_occlusion_bent_normals() {
+        use crate::scene::SceneDescription;
+        use crate::materials::MaterialDescription;
+        use crate::shapes::{ShapeDescription, SphereDescription};
+
+        let mut desc = SceneDescription::default();
+        desc.set_resolution(ImageSize::new(9, 9));
+        desc.settings.spp = 256;
+        desc.camera_desc.position = Point3::new(0.0, 0.0, 3.0);
+        desc.camera_desc.look_at = Point3::new(0.0, 0.0, 0.0);
+        desc.materials.push(MaterialDescription { name: "matte".to_string(), ..Default::default() });
+        desc.shapes.push(ShapeDescription::Sphere(SphereDescription { radius: 1.0, material: "matte".to_string(), ..Default::default() }));
+        let scene = Scene::try_from(desc).unwrap();
+
+        let ao_settings = AmbientOcclusionProperties { bent_normal_output: Some("bent.png".to_string()), ..Default::default() };
+        let (accum, bent_normals, _) = render_ambient_occlusion(&scene, &ao_settings);
+        let ao = accum.get(4, 4).unwrap();
+        assert!((ao.spectrum.r / ao.weight - 1.0).abs() < 0.1);
+        let sample = bent_normals.unwrap().get(4, 4).unwrap();
+        let direction = Vec3::new(sample.spectrum.r, sample.spectrum.g, sample.spectrum.b).normalize();
+        assert!(direction.z > 0.9);
+
+        // several occlusion rays per hit converge with few camera samples
+        let ao_settings = AmbientOcclusionProperties { nsamples: 64, bent_normal_output: Some("bent.png".to_string()), ..Default::default() };
+        let mut scene = scene;
+        scene.settings.spp = 4;
+        let (accum, bent_normals, _) = render_ambient_occlusion(&scene, &ao_settings);
+        let ao = accum.get(4, 4).unwrap();
+        assert!((ao.spectrum.r / ao.weight - 1.0).abs() < 0.1);
+        let sample = bent_normals.unwrap().get(4, 4).unwrap();
+        let direction = Vec3::new(sample.spectrum.r, sample.spectrum.g, sample.spectrum.b).normalize();
+        assert!(direction.z > 0.9);
+    }
+
+    #[test]
+    fn render_scene_from_memory() {
+        let text = br#"
+            LookAt 0 0 3  0 0 0  0 1 0
+            Camera "perspective" "float fov" 45
+            Film "rgb" "integer xresolution" 8 "integer yresolution" 8
+            Integrator "ambientocclusion"
+            WorldBegin
+            Material "diffuse"
+            Shape "sphere" "float radius" 1
+        "#;
+        let png = render_scene_to_png(text, SceneFormat::Pbrt).unwrap();
+        let img = image::load_from_memory(&png).unwrap();
+        assert_eq!((img.width(), img.height()), (8, 8));
+        assert!(render_scene_to_png(b"{", SceneFormat::Json).is_err());
+    }
+
+    #[test]
+    fn light_layers_are_additive() {
+        let text = br#"
+            LookAt 0 0 3  0 0 0  0 1 0
+            Camera "perspective" "float fov" 45
+            Film "rgb" "integer xresolution" 4 "integer yresolution" 4
+            Integrator "randomwalk" "integer maxdepth" 2
+            WorldBegin
+            Material "diffuse"
+            Shape "sphere" "float radius" 1
+            LightSource "point" "point3 from" [0 2 2] "rgb I" [5 5 5] "string lightgroup" "key"
+            LightSource "point" "point3 from" [2 0 2] "rgb I" [1 1 1]
+            LightSource "infinite" "rgb L" [0.5 0.5 0.5] "string lightgroup" "rim"
+            AttributeBegin
+            AreaLightSource "diffuse" "rgb L" [2 2 2] "string lightgroup" "key"
+            Translate 0 0 -3
+            Shape "sphere" "float radius" 1
+            AttributeEnd
+        "#;
+        let scene = Scene::try_from(parse_scene_description(text, SceneFormat::Pbrt).unwrap()).unwrap();
+        assert_eq!(scene.light_layers.names, vec!["default", "key", "rim"]);
+        assert_eq!(scene.light_layers.lights, vec![1, 0, 2]);
+        assert_eq!(light_layer_fname("out/image.png", "key"), "out/image_key.png");
+
+        let mut sampler = scene.sampler.create_sampler();
+        for (x, y) in [(0.5, 0.5), (2.0, 2.0), (3.5, 2.5)] {
+            let ray = scene.camera.generate_ray(x, y);
+            let mut layers = vec![RGB::zero(); 3];
+            let rgb = direct_lighting(&ray, &scene, &mut sampler, &mut layers, None);
+            let sum = layers.iter().fold(RGB::zero(), |acc, layer| acc + *layer);
+            assert!((sum.r - rgb.r).abs() < 1e-5);
+            let mut layers = vec![RGB::zero(); 3];
+            let rgb = random_walk(&ray, &scene, &mut sampler, &RandomWalkProperties { maxdepth: 2, ..Default::default() }, None, &mut layers, None);
+            let sum = layers.iter().fold(RGB::zero(), |acc, layer| acc + *layer);
+            assert!((sum.g - rgb.g).abs() < 1e-5);
+        }
+    }
+
+    #[test]
+    fn sample_count_aov() {
+        let mut counts = SampleCounts::new(ImageSize::new(4, 2));
+        for _ in 0..3 {
+            counts.add(1, 1);
+        }
+        counts.add(0, 0);
+        assert_eq!(counts.get(1, 1), 3);
+        assert_eq!(counts.tile_total(&Tile::new(0, 0, 2, 2)), 4);
+        assert_eq!(counts.tile_total(&Tile::new(2, 0, 4, 2)), 0);
+        let image = counts.to_rgb8_buffer();
+        assert_eq!(image.get(1, 1).unwrap().red, 255);
+        assert_eq!(image.get(3, 0).unwrap().red, 0);
+    }
+
+    #[test]
+    fn render_scene_with_threads() {
+        let text = br#"
+            LookAt 0 0 3  0 0 0  0 1 0
+            Camera "perspective" "float fov" 45
+            Film "rgb" "integer xresolution" 8 "integer yresolution" 8
+            Integrator "ambientocclusion"
+            WorldBegin
+            Material "diffuse"
+            Shape "sphere" "float radius" 1
+        "#;
+        let mut desc = parse_scene_description(text, SceneFormat::Pbrt).unwrap();
+        assert_eq!(desc.settings.nthreads, 0);
+        desc.settings.nthreads = 2;
+        let scene = Scene::try_from(desc).unwrap();
+        assert_eq!(render_scene(&scene).size().width, 8);
+
+        let pool = ThreadPoolBuilder::new().num_threads(3).build().unwrap();
+        let threads = pool.install(rayon::current_num_threads);
+        assert_eq!(threads, 3);
+        assert_eq!(render_scene_in_pool(&scene, &pool).size().height, 8);
+    }
+
+    #[test]
+    fn tiled_rendering_is_independent_of_threads() {
+        let text = br#"
+            LookAt 0 0 3  0 0 0  0 1 0
+            Camera "perspective" "float fov" 45
+            Sampler "independent" "integer pixelsamples" 2
+            Film "rgb" "integer xresolution" 70 "integer yresolution" 40
+            Integrator "ambientocclusion"
+            WorldBegin
+            Material "diffuse"
+            Shape "sphere" "float radius" 1
+        "#;
+        let mut desc = parse_scene_description(text, SceneFormat::Pbrt).unwrap();
+        desc.settings.sample_count_output = Some("counts.png".to_string());
+        let scene = Scene::try_from(desc).unwrap();
+        let render = |nthreads: usize| {
+            let pool = ThreadPoolBuilder::new().num_threads(nthreads).build().unwrap();
+            pool.install(|| render_tiles(&scene, TileOutputs::default(), |buffers, sampler| {
+                for (x, y) in buffers.tile {
+                    buffers.add_sample_count(x, y);
+                    let value = sampler.next_1d();
+                    buffers.add(x, y, x as f32 + 0.5, y as f32 + 0.5, &RGB::new(value, value, value));
+                }
+            }))
+        };
+        let (film1, film4) = (render(1), render(4));
+        assert_eq!((film1.radiance.size().width, film1.radiance.size().height), (70, 40));
+        let counts = film1.sample_counts.as_ref().unwrap();
+        assert_eq!(counts.tile_total(&Tile::new(0, 0, 70, 40)), 70 * 40);
+        // every tile is merged and result doesn't depend on number of threads
+        for (p1, p4) in film1.radiance.resolve().iter().zip(film4.radiance.resolve().iter()) {
+            assert!(p1.r > 0.0 && p1.r < 1.0);
+            assert_eq!(p1.r, p4.r);
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "fs")]
+    fn bucket_rendering() {
+        let fname = std::env::temp_dir().join("rtlib_bucket_test.exr");
+        let text = br#"
+            LookAt 0 0 3  0 0 0  0 1 0
+            Camera "perspective" "float fov" 45
+            Film "rgb" "integer xresolution" 70 "integer yresolution" 40
+            Integrator "intersector"
+            WorldBegin
+            Material "diffuse"
+            Shape "sphere" "float radius" 1
+        "#;
+        let mut desc = parse_scene_description(text, SceneFormat::Pbrt).unwrap();
+        desc.settings.bucket_output = Some(fname.to_str().unwrap().to_string());
+        let scene = Scene::try_from(desc).unwrap();
+        let preview = render_scene(&scene);
+        assert_eq!((preview.size().width, preview.size().height), (3, 2));
+        // sphere is in the middle of the image
+        assert!(preview.get(1, 0).unwrap().red > preview.get(0, 0).unwrap().red);
+        let data = std::fs::read(&fname).unwrap();
+        let tiles_data = 6 * 20 + 70 * 40 * 3 * 4 + 6 * 8;
+        assert!(data.len() > tiles_data && data.len() < tiles_data + 1024);
+        std::fs::remove_file(&fname).unwrap();
+    }
+
+    #[test]
+    #[cfg(feature = "fs")]
+    fn ambient_occlusion_output_in_tile_pass() {
+        let fname = std::env::temp_dir().join("rtlib_ao_output_test.png");
+        let text = format!(r#"
+            LookAt 0 0 3  0 0 0  0 1 0
+            Camera "perspective" "float fov" 45
+            Film "rgb" "integer xresolution" 40 "integer yresolution" 30 "string aooutput" "{}"
+            Sampler "independent" "integer pixelsamples" 2
+            Integrator "direct_lighting"
+            WorldBegin
+            LightSource "point" "point3 from" [0 0 5]
+            Material "diffuse"
+            Shape "sphere" "float radius" 1
+        "#, fname.to_str().unwrap().replace('\\', "/"));
+        let scene = Scene::try_from(parse_scene_description(text.as_bytes(), SceneFormat::Pbrt).unwrap()).unwrap();
+        assert!(accumulates_aovs(&scene));
+        let outputs = TileOutputs { aovs: true, ..Default::default() };
+        let film = render_tiles(&scene, outputs, |buffers, sampler| {
+            for (x, y) in buffers.tile {
+                let (px, py) = (x as f32 + 0.5, y as f32 + 0.5);
+                let ray = scene.camera.generate_ray_at_time(px, py, sampler.sample_time());
+                buffers.add_aov_sample(&scene, x, y, px, py, &ray, scene.geometry.intersect(&ray).as_ref());
+            }
+        });
+        // nothing occludes the sphere, so every pixel is unoccluded
+        let (occlusion, _) = film.ao_output.as_ref().unwrap();
+        assert!(occlusion.resolve().iter().all(|rgb| (rgb.r - 1.0).abs() < 1e-4));
+
+        render_scene(&scene);
+        let image = image::open(&fname).unwrap().to_rgb8();
+        assert_eq!(image.dimensions(), (40, 30));
+        assert!(image.pixels().all(|p| p[0] == 255));
+        std::fs::remove_file(&fname).unwrap();
+    }
+
+    #[test]
+    fn fog_depth_from_aovs() {
+        let text = br#"
+            LookAt 0 0 3  0 0 0  0 1 0
+            Camera "perspective" "float fov" 45
+            Film "rgb" "integer xresolution" 24 "integer yresolution" 16
+            Integrator "direct_lighting"
+            WorldBegin
+            Material "diffuse"
+            Shape "sphere" "float radius" 1
+        "#;
+        let mut desc = parse_scene_description(text, SceneFormat::Pbrt).unwrap();
+        desc.settings.fog = Some(crate::postprocess::FogProperties::default());
+        let scene = Scene::try_from(desc).unwrap();
+        let outputs = TileOutputs { aovs: true, ..Default::default() };
+        let film = render_tiles(&scene, outputs, |buffers, _sampler| {
+            for (x, y) in buffers.tile {
+                let (px, py) = (x as f32 + 0.5, y as f32 + 0.5);
+                let ray = scene.camera.generate_ray(px, py);
+                buffers.add_aov_sample(&scene, x, y, px, py, &ray, scene.geometry.intersect(&ray).as_ref());
+            }
+        });
+        // depth is accumulated for fog although it isn't saved auxiliary output
+        let depth = film.depth().unwrap();
+        let traced = crate::postprocess::render_depth(&scene);
+        assert!(depth.iter().any(|d| d.is_finite()) && depth.iter().any(|d| d.is_infinite()));
+        for (d, t) in depth.iter().zip(traced.iter()) {
+            assert!(d == t || (d - t).abs() < 1e-3);
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "fs")]
+    fn resumed_checkpoint() {
+        let fname = std::env::temp_dir().join("rtlib_checkpoint_test.ckpt");
+        let text = format!(r#"
+            LookAt 0 0 3  0 0 0  0 1 0
+            Camera "perspective" "float fov" 45
+            Sampler "independent" "integer pixelsamples" 2
+            Film "rgb" "integer xresolution" 70 "integer yresolution" 40
+                "string checkpoint" "{}" "float checkpointinterval" 0
+            Integrator "ambientocclusion"
+            WorldBegin
+            Material "diffuse"
+            Shape "sphere" "float radius" 1
+        "#, fname.to_str().unwrap().replace('\\', "/"));
+        let desc = parse_scene_description(text.as_bytes(), SceneFormat::Pbrt).unwrap();
+        let output = desc.settings.checkpoint.clone().unwrap();
+        assert_eq!((output.interval, output.resume), (0.0, true));
+        let fname = output.output_fname.clone();
+        let _ = std::fs::remove_file(&fname);
+        let fingerprint = desc.fingerprint();
+        let mut scene = Scene::try_from(desc).unwrap();
+        scene.fingerprint = fingerprint;
+
+        // cancelled rendering doesn't store unfinished passes
+        scene.cancel_token.cancel();
+        render_scene(&scene);
+        assert_eq!(Checkpoint::load(&fname).unwrap().stored_tiles(), 0);
+        scene.cancel_token.reset();
+
+        let reference = render_scene(&scene);
+        let full = Checkpoint::load(&fname).unwrap();
+        assert_eq!(full.stored_tiles(), 6);
+        assert!((0..6).all(|index| full.tile(index).unwrap().0 == 2));
+        // first pass of the tile doesn't depend on number of passes
+        scene.settings.spp = 1;
+        render_scene(&scene);
+        let first_pass = Checkpoint::load(&fname).unwrap();
+        scene.settings.spp = 2;
+
+        // only some tiles and passes are in checkpoint, resumed image is same as image rendered at once
+        let mut partial = Checkpoint::new(scene.settings.resolution, 2, 6, fingerprint);
+        partial.add_tile(4, 2, full.tile(4).unwrap().1.to_vec());
+        partial.add_tile(1, 1, first_pass.tile(1).unwrap().1.to_vec());
+        partial.save(&fname).unwrap();
+        let assert_reference = |image: &RGB8uffer| {
+            for y in 0..40 {
+                for x in 0..70 {
+                    let (p1, p2) = (reference.get(x, y).unwrap(), image.get(x, y).unwrap());
+                    assert_eq!((p1.red, p1.green, p1.blue), (p2.red, p2.green, p2.blue));
+                }
+            }
+        };
+        assert_reference(&render_scene(&scene));
+        let resumed = Checkpoint::load(&fname).unwrap();
+        assert_eq!(resumed.stored_tiles(), 6);
+        assert!((0..6).all(|index| resumed.tile(index).unwrap().0 == 2));
+
+        // checkpoint of other scene is not resumed
+        let mut other = Checkpoint::new(scene.settings.resolution, 2, 6, fingerprint ^ 1);
+        other.add_tile(4, 2, full.tile(4).unwrap().1.iter().map(|buffer| vec![PixelSample::default(); buffer.len()]).collect());
+        other.save(&fname).unwrap();
+        assert_reference(&render_scene(&scene));
+        std::fs::remove_file(&fname).unwrap();
+    }
+
+    #[test]
+    fn tiles_use_filter_of_scene() {
+        let text = br#"
+            Film "rgb" "integer xresolution" 64 "integer yresolution" 64
+            PixelFilter "triangle" "float xradius" 2 "float yradius" 2
+            WorldBegin
+        "#;
+        let scene = Scene::try_from(parse_scene_description(text, SceneFormat::Pbrt).unwrap()).unwrap();
+        assert_eq!(scene.filter_radius(), Some(2.0));
+        assert_eq!(scene.filter_weight(0.0, 0.0), 4.0);
+        // sample at first pixel of tile is splatted also to pixels of previous tile
+        let render = |outputs: TileOutputs| render_tiles(&scene, outputs, |buffers, _sampler| {
+            if buffers.tile.x1 == 32 && buffers.tile.y1 == 32 {
+                buffers.add(32, 40, 32.5, 40.5, &RGB::new(1.0, 1.0, 1.0));
+            }
+        }).radiance;
+        let filtered = render(TileOutputs::default());
+        assert_eq!(filtered.get(32, 40).unwrap().weight, 4.0);
+        assert_eq!(filtered.get(31, 40).unwrap().weight, 2.0);
+        assert_eq!(filtered.get(30, 40).unwrap().weight, 0.0);
+        let unfiltered = render(TileOutputs { unfiltered: true, ..Default::default() });
+        assert_eq!(unfiltered.get(32, 40).unwrap().weight, 1.0);
+        assert_eq!(unfiltered.get(31, 40).unwrap().weight, 0.0);
+    }
+
+    #[test]
+    #[cfg(feature = "fs")]
+    fn bucket_filtering_across_tiles() {
+        let fname = std::env::temp_dir().join("rtlib_bucket_filter_test.exr");
+        let text = br#"
+            Film "rgb" "integer xresolution" 70 "integer yresolution" 40
+            PixelFilter "triangle" "float xradius" 2 "float yradius" 2
+            WorldBegin
+        "#;
+        let mut desc = parse_scene_description(text, SceneFormat::Pbrt).unwrap();
+        desc.settings.bucket_output = Some(fname.to_str().unwrap().to_string());
+        let scene = Scene::try_from(desc).unwrap();
+        // bright pixels at the right border of the first tile and dark pixels elsewhere
+        render_tiles(&scene, TileOutputs::default(), |buffers, _sampler| {
+            for (x, y) in buffers.tile {
+                let value = if x == 31 { 1.0 } else { 0.0 };
+                buffers.add(x, y, x as f32 + 0.5, y as f32 + 0.5, &RGB::new(value, value, value));
+            }
+        });
+        let image = crate::image_diff::load_image(&fname).unwrap();
+        let pixel = |x: usize, y: usize| image.get(x, y).unwrap().r;
+        // neighbouring tile gets splats of the bright column, also at corner of four tiles
+        assert!((pixel(32, 10) - 0.25).abs() < 1e-5);
+        assert!((pixel(32, 31) - 0.25).abs() < 1e-5);
+        assert!((pixel(32, 32) - 0.25).abs() < 1e-5);
+        assert!((pixel(31, 10) - 0.5).abs() < 1e-5);
+        assert_eq!(pixel(33, 10), 0.0);
+        std::fs::remove_file(&fname).unwrap();
+    }
+
+    #[test]
+    fn warm_started_rendering() {
+        let text = br#"
+            Film "rgb" "integer xresolution" 8 "integer yresolution" 4
+            WorldBegin
+        "#;
+        let mut scene = Scene::try_from(parse_scene_description(text, SceneFormat::Pbrt).unwrap()).unwrap();
+        assert!(scene.set_warm_start(vec![RGB::new(1.0, 1.0, 1.0); 16], 3.0).is_err());
+        let mut pixels = vec![RGB::new(1.0, 1.0, 1.0); 32];
+        pixels[9] = RGB::new(0.0, 2.0, 0.0);
+        scene.set_warm_start(pixels, 3.0).unwrap();
+        // one black sample per pixel refines image that stands for three samples
+        let radiance = render_tiles(&scene, TileOutputs::default(), |buffers, _sampler| {
+            for (x, y) in buffers.tile {
+                buffers.add(x, y, x as f32 + 0.5, y as f32 + 0.5, &RGB::zero());
+            }
+        }).radiance.resolve();
+        assert_eq!(radiance[0].r, 0.75);
+        assert_eq!((radiance[9].r, radiance[9].g), (0.0, 1.5));
+
+        // integrators without tiled accumulation and buffers that warm start can't seed are rejected
+        let rejected = |text: &str| {
+            let mut scene = Scene::try_from(parse_scene_description(text.as_bytes(), SceneFormat::Pbrt).unwrap()).unwrap();
+            scene.set_warm_start(vec![RGB::new(1.0, 1.0, 1.0); 32], 1.0).is_err()
+        };
+        let film = "Film \"rgb\" \"integer xresolution\" 8 \"integer yresolution\" 4\n";
+        assert!(rejected(&format!("{}Integrator \"sppm\"\nWorldBegin\n", film)));
+        assert!(rejected(&format!("{}Integrator \"furnace\"\nWorldBegin\n", film)));
+        assert!(rejected(&format!("{}WorldBegin\nLightSource \"point\" \"string lightgroup\" \"key\"\n", film)));
+        assert!(!rejected(&format!("{}Integrator \"randomwalk\"\nWorldBegin\n", film)));
+    }
+
+    #[test]
+    #[cfg(feature = "fs")]
+    fn warm_start_from_linear_image() {
+        let (exr, png) = (std::env::temp_dir().join("rtlib_warm_start.exr"), std::env::temp_dir().join("rtlib_warm_start.png"));
+        image::Rgb32FImage::from_pixel(8, 4, image::Rgb([2.0, 0.5, 0.25])).save(&exr).unwrap();
+        image::RgbImage::from_pixel(8, 4, image::Rgb([255, 128, 64])).save(&png).unwrap();
+        let scene = |image: &std::path::Path| {
+            let text = format!("Film \"rgb\" \"integer xresolution\" 8 \"integer yresolution\" 4 \"string warmstart\" \"{}\"\nWorldBegin\n",
+                               image.display());
+            Scene::try_from(parse_scene_description(text.as_bytes(), SceneFormat::Pbrt).unwrap())
+        };
+        // radiance above one survives, tone mapped 8-bit image isn't radiance
+        let warm_start = scene(&exr).unwrap().warm_start.unwrap();
+        assert_eq!((warm_start.pixels[0].r, warm_start.pixels[31].b), (2.0, 0.25));
+        assert!(scene(&png).is_err());
+        std::fs::remove_file(&exr).unwrap();
+        std::fs::remove_file(&png).unwrap();
+    }
+
+    #[test]
+    fn cancelled_rendering() {
+        let text = br#"
+            LookAt 0 0 3  0 0 0  0 1 0
+            Camera "perspective" "float fov" 45
+            Film "rgb" "integer xresolution" 40 "integer yresolution" 40
+            Integrator "ambientocclusion"
+            WorldBegin
+            Material "diffuse"
+            Shape "sphere" "float radius" 1
+        "#;
+        let desc = parse_scene_description(text, SceneFormat::Pbrt).unwrap();
+        let scene = Scene::try_from(desc).unwrap();
+        let token = scene.cancel_token.clone();
+        token.cancel();
+        let image = render_scene(&scene);
+        assert_eq!(image.get(0, 0).unwrap().red, 0);
+
+        // token is cancelled from another thread while tiles are rendered
+        token.reset();
+        let outputs = TileOutputs::default();
+        let film = render_tiles(&scene, outputs, |buffers, _sampler| {
+            if buffers.tile.x1 == 0 && buffers.tile.y1 == 0 {
+                std::thread::scope(|s| { s.spawn(|| token.cancel()); });
+            }
+            for i in 0..4 {
+                if scene.cancel_token.is_cancelled() {
+                    break;
+                }
+                buffers.add(buffers.tile.x1, buffers.tile.y1, 0.5, 0.5, &RGB::new(i as f32, 0.0, 0.0));
+            }
+        });
+        assert!(token.is_cancelled());
+        assert_eq!(film.radiance.get(0, 0).unwrap().weight, 0.0);
+    }
+
+    #[test]
+    #[cfg(feature = "fs")]
+    fn ray_log_of_pixel() {
+        let fname = std::env::temp_dir().join("rtlib_ray_log_test.obj");
+        let text = format!(r#"
+            LookAt 0 0 3  0 0 0  0 1 0
+            Camera "perspective" "float fov" 45
+            Sampler "independent" "integer pixelsamples" 3
+            Film "rgb" "integer xresolution" 8 "integer yresolution" 8 "string raylog" "{}"
+                "integer raylogpixel" [4 4] "string raylograys" ["primary" "shadow"]
+            Integrator "direct_lighting"
+            WorldBegin
+            LightSource "point" "point3 from" [0 5 5] "rgb I" [10 10 10]
+            Material "diffuse"
+            Shape "sphere" "float radius" 1
+        "#, fname.to_str().unwrap().replace('\\', "/"));
+        let desc = parse_scene_description(text.as_bytes(), SceneFormat::Pbrt).unwrap();
+        let output = desc.settings.ray_log.as_ref().unwrap();
+        assert_eq!((output.x, output.y), (4, 4));
+        assert_eq!(output.kinds, vec![RayKind::Primary, RayKind::Shadow]);
+        let scene = Scene::try_from(desc).unwrap();
+        render_scene(&scene);
+        let obj = std::fs::read_to_string(&fname).unwrap();
+        std::fs::remove_file(&fname).unwrap();
+        // every sample has primary ray that hits sphere and shadow ray to the light
+        assert_eq!(obj.matches("g primary").count(), 3);
+        assert_eq!(obj.matches("g shadow").count(), 3);
+        assert!(obj.contains("v 0 5 5"));
+        assert_eq!(obj.matches("\nl ").count(), 6);
+    }
+
+    #[test]
+    fn ray_log_of_rendered_samples() {
+        let text = br#"
+            LookAt 0 0 3  0 0 0  0 1 0
+            Camera "perspective" "float fov" 45
+            Sampler "independent" "integer pixelsamples" 3
+            Film "rgb" "integer xresolution" 8 "integer yresolution" 8 "string raylog" "rays.obj"
+                "integer raylogpixel" [4 4] "bool tileimportance" true "integer prepasssamples" 2
+            WorldBegin
+        "#;
+        let scene = Scene::try_from(parse_scene_description(text, SceneFormat::Pbrt).unwrap()).unwrap();
+        let samples = Mutex::new(Vec::new());
+        let film = render_tiles(&scene, TileOutputs { ray_log: true, ..Default::default() }, |buffers, sampler| {
+            for i in buffers.passes() {
+                for (x, y) in buffers.tile {
+                    let (sx, sy) = sampler.sample_pixel(x, y, i);
+                    let ray = scene.camera.generate_ray(x as f32 + sx, y as f32 + sy);
+                    if (x, y) == (4, 4) {
+                        samples.lock().unwrap().push(ray.point_at(1.0));
+                    }
+                    if let Some(log) = buffers.ray_log(x, y) {
+                        log.add_ray(RayKind::Primary, &ray, Some(1.0));
+                    }
+                }
+            }
+        });
+        // rays of pre-pass of tile importance aren't logged, logged rays are rays of the image
+        let samples = samples.into_inner().unwrap();
+        let segments = film.ray_log.as_ref().unwrap().segments();
+        assert_eq!((samples.len(), segments.len()), (5, 3));
+        for (segment, end) in segments.iter().zip(&samples[2..]) {
+            assert_eq!((segment.end.x, segment.end.y, segment.end.z), (end.x, end.y, end.z));
+        }
+    }
 }
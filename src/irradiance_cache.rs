@@ -0,0 +1,411 @@
+//! Irradiance caching of diffuse interreflection
+//!
+//! Indirect irradiance changes slowly over diffuse surfaces, so it is estimated by stratified hemisphere
+//! sampling only at sparse set of points and interpolated elsewhere (Ward et al. 1988). Records also store
+//! rotational and translational gradients (Ward and Heckbert 1992) that are used for extrapolation, so
+//! interpolated irradiance is smooth. Records are found through octree over their areas of influence.
+
+use std::sync::RwLock;
+
+use crate::color::RGB;
+use crate::epsilon::INFINITE_DISTANCE;
+use crate::shapes::AABB;
+use crate::vec::{Normal, Point3, Vec3};
+
+const OCTREE_MAX_DEPTH: usize = 16;
+
+/// Indirect diffuse lighting of direct lighting integrator. Sizes of areas where records are
+/// reused are relative to radius of bounding sphere of the scene.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct IrradianceCacheProperties {
+    /// When disabled irradiance is estimated at every hit point, used for reference renders
+    pub enabled: bool,
+    /// Hemisphere samples used to estimate irradiance of one record
+    pub samples: usize,
+    /// Maximum allowed interpolation error, smaller value means denser records
+    pub max_error: f32,
+    pub min_spacing: f32,
+    pub max_spacing: f32,
+}
+
+impl Default for IrradianceCacheProperties {
+    fn default() -> Self {
+        Self { enabled: true, samples: 256, max_error: 0.2, min_spacing: 0.005, max_spacing: 0.2 }
+    }
+}
+
+/// Number of strata in theta and phi, phi has about PI times more strata.
+pub fn hemisphere_strata(samples: usize) -> (usize, usize) {
+    let m = ((samples as f32 / std::f32::consts::PI).sqrt().round() as usize).max(1);
+    let n = (samples / m).max(1);
+    (m, n)
+}
+
+/// Cosine distributed direction inside of stratum j (theta) and k (phi) in local frame
+/// where z axis is normal.
+pub fn stratum_direction(j: usize, k: usize, m: usize, n: usize, u1: f32, u2: f32) -> Vec3 {
+    let sin_theta_sqr = (j as f32 + u1) / m as f32;
+    let cos_theta = (1.0 - sin_theta_sqr).max(0.0).sqrt();
+    let sin_theta = sin_theta_sqr.sqrt();
+    let phi = 2.0 * std::f32::consts::PI * (k as f32 + u2) / n as f32;
+    Vec3::new(sin_theta * phi.cos(), sin_theta * phi.sin(), cos_theta)
+}
+
+/// Radiance that arrived from direction of stratum and distance to the hit point,
+/// INFINITE_DISTANCE when direction didn't hit anything.
+#[derive(Debug, Clone, Copy)]
+pub struct GatherSample {
+    pub radiance: RGB,
+    pub distance: f32,
+    pub cos_theta: f32,
+}
+
+/// Irradiance estimated from gather samples, samples are ordered by theta strata.
+pub fn gather_irradiance(samples: &[GatherSample]) -> RGB {
+    let mut sum = RGB::zero();
+    for sample in samples.iter() {
+        sum += sample.radiance;
+    }
+    sum * (std::f32::consts::PI / samples.len() as f32)
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct IrradianceRecord {
+    pub position: Point3,
+    pub normal: Normal,
+    pub irradiance: RGB,
+    /// Harmonic mean distance to surfaces seen from the record, clamped by spacing limits
+    pub radius: f32,
+    /// Gradients of red, green and blue irradiance in world space
+    pub rotational: [Vec3; 3],
+    pub translational: [Vec3; 3],
+}
+
+impl IrradianceRecord {
+    /// Gradients are calculated in local frame whose z axis is normal (u and v are tangents of the frame),
+    /// samples are ordered by theta strata and every stratum has n samples.
+    pub fn new(position: Point3, normal: Normal, tangents: (Vec3, Vec3), samples: &[GatherSample], n: usize) -> Self {
+        let m = samples.len() / n;
+        let pi = std::f32::consts::PI;
+        let sample = |j: usize, k: usize| &samples[j * n + k];
+        let channels = |c: RGB| [c.r, c.g, c.b];
+
+        let mut rotational = [Vec3::new(0.0, 0.0, 0.0); 3];
+        let mut translational = [Vec3::new(0.0, 0.0, 0.0); 3];
+        let mut inv_distance_sum = 0.0;
+        for k in 0..n {
+            let phi = 2.0 * pi * (k as f32 + 0.5) / n as f32;
+            let phi_minus = 2.0 * pi * k as f32 / n as f32;
+            let uk = Vec3::new(phi.cos(), phi.sin(), 0.0);
+            let vk = Vec3::new(-phi.sin(), phi.cos(), 0.0);
+            let vk_minus = Vec3::new(-phi_minus.sin(), phi_minus.cos(), 0.0);
+            let prev_k = (k + n - 1) % n;
+            for j in 0..m {
+                let s = sample(j, k);
+                inv_distance_sum += s.distance.recip();
+                let cos_theta = s.cos_theta.max(1e-4);
+                let sin_theta = (1.0 - cos_theta * cos_theta).max(0.0).sqrt();
+                let l = channels(s.radiance);
+                for c in 0..3 {
+                    rotational[c] += vk * (sin_theta / cos_theta * l[c] * pi / samples.len() as f32);
+                }
+
+                // NOTE: projected solid angles of cells change when point moves, boundary between theta
+                // strata moves along u and boundary between phi strata along v
+                let sin_minus = (j as f32 / m as f32).sqrt();
+                let sin_plus = ((j + 1) as f32 / m as f32).sqrt();
+                if j > 0 {
+                    let sp = sample(j - 1, k);
+                    let cos_minus = (1.0 - j as f32 / m as f32).max(0.0).sqrt();
+                    let lp = channels(sp.radiance);
+                    let factor = 2.0 * pi / n as f32 * sin_minus * cos_minus * cos_minus / s.distance.min(sp.distance);
+                    for c in 0..3 {
+                        translational[c] += uk * (factor * (l[c] - lp[c]));
+                    }
+                }
+                let sp = sample(j, prev_k);
+                let lp = channels(sp.radiance);
+                let factor = (sin_plus - sin_minus) / s.distance.min(sp.distance);
+                for c in 0..3 {
+                    translational[c] += vk_minus * (factor * (l[c] - lp[c]));
+                }
+            }
+        }
+        let (u, v) = tangents;
+        let w = Vec3::from(normal);
+        let to_world = |g: Vec3| u * g.x + v * g.y + w * g.z;
+        let radius = if inv_distance_sum > 0.0 { samples.len() as f32 / inv_distance_sum } else { INFINITE_DISTANCE };
+        Self {
+            position,
+            normal,
+            irradiance: gather_irradiance(samples),
+            radius,
+            rotational: rotational.map(to_world),
+            translational: translational.map(to_world),
+        }
+    }
+
+    /// Weight of the record at point, records with weight below one over maximum error aren't used.
+    pub fn weight(&self, p: Point3, n: Normal) -> f32 {
+        let d = (p - self.position).length() / self.radius + (1.0 - (n * self.normal).min(1.0)).sqrt();
+        if d > 0.0 { d.recip() } else { f32::MAX }
+    }
+
+    /// Irradiance of the record extrapolated to point by gradients.
+    pub fn extrapolate(&self, p: Point3, n: Normal) -> RGB {
+        let axis = Vec3::from(self.normal).cross(Vec3::from(n));
+        let dp = p - self.position;
+        let delta = |c: usize| axis * self.rotational[c] + dp * self.translational[c];
+        let e = self.irradiance;
+        RGB::new((e.r + delta(0)).max(0.0), (e.g + delta(1)).max(0.0), (e.b + delta(2)).max(0.0))
+    }
+
+    // Records are used only inside of sphere of this radius
+    fn influence_radius(&self, max_error: f32) -> f32 {
+        self.radius * max_error
+    }
+}
+
+fn octant(bounds: &AABB, center: Point3, index: usize) -> AABB {
+    let (min, max) = (bounds.min(), bounds.max());
+    let pick = |bit: usize, low: f32, mid: f32, high: f32| if index & bit == 0 { (low, mid) } else { (mid, high) };
+    let (x0, x1) = pick(1, min.x, center.x, max.x);
+    let (y0, y1) = pick(2, min.y, center.y, max.y);
+    let (z0, z1) = pick(4, min.z, center.z, max.z);
+    AABB::new(Point3::new(x0, y0, z0), Point3::new(x1, y1, z1))
+}
+
+fn overlaps(a: &AABB, b: &AABB) -> bool {
+    let (amin, amax, bmin, bmax) = (a.min(), a.max(), b.min(), b.max());
+    amin.x <= bmax.x && amax.x >= bmin.x && amin.y <= bmax.y && amax.y >= bmin.y && amin.z <= bmax.z && amax.z >= bmin.z
+}
+
+#[derive(Default)]
+struct OctreeNode {
+    children: Option<Box<[OctreeNode; 8]>>,
+    items: Vec<u32>,
+}
+
+// Item is stored in every node that overlaps its bounds and is not much smaller than the bounds,
+// lookup visits nodes on the path from root to leaf that contains point.
+struct Octree {
+    bounds: AABB,
+    root: OctreeNode,
+}
+
+impl Octree {
+    fn new(bounds: AABB) -> Self {
+        Self { bounds, root: OctreeNode::default() }
+    }
+
+    fn add(&mut self, item: u32, item_bounds: &AABB) {
+        let diag_sqr = (item_bounds.max() - item_bounds.min()).length_sqr();
+        Self::add_to_node(&mut self.root, &self.bounds, item, item_bounds, diag_sqr, 0);
+    }
+
+    fn add_to_node(node: &mut OctreeNode, bounds: &AABB, item: u32, item_bounds: &AABB, diag_sqr: f32, depth: usize) {
+        if depth == OCTREE_MAX_DEPTH || (bounds.max() - bounds.min()).length_sqr() < diag_sqr {
+            node.items.push(item);
+            return;
+        }
+        let center = bounds.centroid();
+        let children = node.children.get_or_insert_with(Default::default);
+        for (index, child) in children.iter_mut().enumerate() {
+            let child_bounds = octant(bounds, center, index);
+            if overlaps(&child_bounds, item_bounds) {
+                Self::add_to_node(child, &child_bounds, item, item_bounds, diag_sqr, depth + 1);
+            }
+        }
+    }
+
+    fn lookup<F: FnMut(u32)>(&self, p: Point3, mut f: F) {
+        let mut node = &self.root;
+        let mut bounds = self.bounds;
+        loop {
+            for item in node.items.iter() {
+                f(*item);
+            }
+            let children = match &node.children {
+                Some(children) => children,
+                None => break
+            };
+            let center = bounds.centroid();
+            let index = (p.x > center.x) as usize | ((p.y > center.y) as usize) << 1 | ((p.z > center.z) as usize) << 2;
+            bounds = octant(&bounds, center, index);
+            node = &children[index];
+        }
+    }
+}
+
+struct CacheData {
+    records: Vec<IrradianceRecord>,
+    octree: Octree,
+}
+
+/// Records shared by rendering threads, new records are added while rendering.
+pub struct IrradianceCache {
+    settings: IrradianceCacheProperties,
+    bounds: AABB,
+    min_spacing: f32,
+    max_spacing: f32,
+    data: RwLock<CacheData>,
+}
+
+impl IrradianceCache {
+    pub fn new(settings: IrradianceCacheProperties, scene_bounds: AABB) -> Self {
+        let center = scene_bounds.centroid();
+        let scene_radius = center.distance(scene_bounds.max()).max(1e-3);
+        // NOTE: octree is a cube, so subdivided cells have same size along all axes
+        let r = Vec3::new(scene_radius, scene_radius, scene_radius) * 1.01;
+        let bounds = AABB::new(center + -r, center + r);
+        let data = RwLock::new(CacheData { records: Vec::new(), octree: Octree::new(bounds) });
+        Self { settings, bounds, min_spacing: settings.min_spacing * scene_radius,
+               max_spacing: settings.max_spacing * scene_radius, data }
+    }
+
+    pub fn settings(&self) -> &IrradianceCacheProperties {
+        &self.settings
+    }
+
+    /// Weighted average of extrapolated irradiance of records that are valid at the point,
+    /// None when there is no such record and new record has to be created.
+    pub fn lookup(&self, p: Point3, n: Normal) -> Option<RGB> {
+        let data = self.data.read().unwrap_or_else(|err| err.into_inner());
+        let min_weight = self.settings.max_error.recip();
+        let mut sum = RGB::zero();
+        let mut weight_sum = 0.0;
+        data.octree.lookup(p, |index| {
+            let record = &data.records[index as usize];
+            let weight = record.weight(p, n);
+            if weight <= min_weight {
+                return;
+            }
+            // NOTE: records in front of the point see different occluders
+            let average_normal = Vec3::from(record.normal + n) * 0.5;
+            if (p - record.position) * average_normal < -0.01 * record.radius {
+                return;
+            }
+            sum += record.extrapolate(p, n) * weight;
+            weight_sum += weight;
+        });
+        if weight_sum > 0.0 {
+            Some(sum * weight_sum.recip())
+        } else {
+            None
+        }
+    }
+
+    /// Radius of the record is limited by spacing and by its translational gradient, so
+    /// extrapolation doesn't change irradiance more than irradiance itself.
+    pub fn insert(&self, mut record: IrradianceRecord) {
+        let e = record.irradiance;
+        for (value, gradient) in [e.r, e.g, e.b].iter().zip(record.translational.iter()) {
+            let length = gradient.length();
+            if length > 0.0 {
+                record.radius = record.radius.min(value / length);
+            }
+        }
+        record.radius = record.radius.clamp(self.min_spacing, self.max_spacing);
+        let r = record.influence_radius(self.settings.max_error);
+        let r = Vec3::new(r, r, r);
+        let record_bounds = AABB::new(record.position + -r, record.position + r);
+        if !overlaps(&record_bounds, &self.bounds) {
+            return;
+        }
+        let mut data = self.data.write().unwrap_or_else(|err| err.into_inner());
+        let index = data.records.len() as u32;
+        data.records.push(record);
+        data.octree.add(index, &record_bounds);
+    }
+
+    pub fn len(&self) -> usize {
+        self.data.read().unwrap_or_else(|err| err.into_inner()).records.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn clear(&self) {
+        let mut data = self.data.write().unwrap_or_else(|err| err.into_inner());
+        data.records.clear();
+        data.octree = Octree::new(self.bounds);
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Emitter is ceiling z = 1 with x > 0, everything else is black.
+    fn half_ceiling_samples(p: Point3, m: usize, n: usize) -> Vec<GatherSample> {
+        let mut samples = Vec::new();
+        for j in 0..m {
+            for k in 0..n {
+                let wi = stratum_direction(j, k, m, n, 0.5, 0.5);
+                let t = (1.0 - p.z) / wi.z;
+                let lit = p.x + wi.x * t > 0.0;
+                let radiance = if lit { RGB::new(1.0, 1.0, 1.0) } else { RGB::zero() };
+                samples.push(GatherSample { radiance, distance: t, cos_theta: wi.z });
+            }
+        }
+        samples
+    }
+
+    #[test]
+    fn irradiance_gradients() {
+        let normal = Normal::new(0.0, 0.0, 1.0);
+        let tangents = (Vec3::new(1.0, 0.0, 0.0), Vec3::new(0.0, 1.0, 0.0));
+        let (m, n) = hemisphere_strata(2000);
+        assert_eq!((m, n), (25, 80));
+
+        // translational gradient follows irradiance of points next to the record
+        let p = Point3::new(0.0, 0.0, 0.0);
+        let record = IrradianceRecord::new(p, normal, tangents, &half_ceiling_samples(p, m, n), n);
+        assert!((record.irradiance.r - 0.5 * std::f32::consts::PI).abs() < 0.05);
+        let delta = 0.05;
+        let irradiance = |x: f32| gather_irradiance(&half_ceiling_samples(Point3::new(x, 0.0, 0.0), 200, 600)).r;
+        let expected = (irradiance(delta) - irradiance(-delta)) / (2.0 * delta);
+        let gradient = record.translational[0];
+        assert!((gradient.x - expected).abs() < 0.1 * expected);
+        assert!(gradient.y.abs() < 0.05 * expected && gradient.z.abs() < 1e-4);
+        let extrapolated = record.extrapolate(Point3::new(delta, 0.0, 0.0), normal);
+        assert!((extrapolated.r - irradiance(delta)).abs() < 0.01);
+
+        // radiance that grows towards x axis, irradiance grows when normal is rotated towards it
+        let samples: Vec<_> = (0..m * n).map(|i| {
+            let wi = stratum_direction(i / n, i % n, m, n, 0.5, 0.5);
+            let l = 1.0 + wi.x;
+            GatherSample { radiance: RGB::new(l, l, l), distance: INFINITE_DISTANCE, cos_theta: wi.z }
+        }).collect();
+        let record = IrradianceRecord::new(p, normal, tangents, &samples, n);
+        assert!(record.translational[1].length() < 1e-6);
+        let rotated = Normal::new(0.1f32.sin(), 0.0, 0.1f32.cos());
+        let expected = std::f32::consts::PI + 0.1 * 2.0 * std::f32::consts::PI / 3.0;
+        assert!((record.extrapolate(p, rotated).g - expected).abs() < 0.02);
+    }
+
+    #[test]
+    fn irradiance_cache_lookup() {
+        let bounds = AABB::new(Point3::new(-10.0, -10.0, -10.0), Point3::new(10.0, 10.0, 10.0));
+        let cache = IrradianceCache::new(IrradianceCacheProperties::default(), bounds);
+        let normal = Normal::new(0.0, 0.0, 1.0);
+        let p = Point3::new(1.0, 2.0, 0.0);
+        assert!(cache.lookup(p, normal).is_none());
+        let record = IrradianceRecord {
+            position: p, normal, irradiance: RGB::new(1.0, 2.0, 3.0), radius: 5.0,
+            rotational: [Vec3::new(0.0, 0.0, 0.0); 3], translational: [Vec3::new(0.0, 0.0, 0.0); 3]
+        };
+        cache.insert(record);
+        assert_eq!(cache.len(), 1);
+        // radius is clamped to maximum spacing (0.2 * 10 * sqrt(3))
+        let e = cache.lookup(Point3::new(1.1, 2.0, 0.0), normal).unwrap();
+        assert!((e.r - 1.0).abs() < 1e-5 && (e.b - 3.0).abs() < 1e-5);
+        assert!(cache.lookup(Point3::new(2.0, 2.0, 0.0), normal).is_none());
+        assert!(cache.lookup(p, Normal::new(0.0, 1.0, 0.0)).is_none());
+        assert!(cache.lookup(Point3::new(1.1, 2.0, -0.2), normal).is_none());
+        cache.clear();
+        assert!(cache.is_empty() && cache.lookup(p, normal).is_none());
+    }
+}
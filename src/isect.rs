@@ -1,5 +1,6 @@
 use crate::vec::{Vec3, Point3};
 use crate::ray::Ray;
+use crate::epsilon::INFINITE_DISTANCE;
 
 /// Calculate intersection of ray with sphere
 /// 
@@ -34,6 +35,37 @@ pub fn isect_ray_sphere(ray: &Ray, position: Point3, radius: f32, tmin: f32, tma
 }
 
 
+/// Calculate intersection of ray with sphere clipped by z range and maximum phi angle
+/// (measured around z axis from x axis). Clipping values are relative to center of sphere.
+#[allow(clippy::too_many_arguments)]
+pub fn isect_ray_partial_sphere(ray: &Ray, position: Point3, radius: f32, zmin: f32, zmax: f32,
+                                phimax: f32, tmin: f32, tmax: f32) -> Option<f32> {
+    let f = ray.origin - position;
+    let c = f * f - radius * radius;
+    let b_prime = -(f * ray.direction);
+    let tmp = f + b_prime * ray.direction;
+    let discriminant = radius * radius - tmp * tmp;
+    if discriminant < 0.0 {
+        return None;
+    }
+    let q = b_prime + b_prime.signum() * discriminant.sqrt();
+    let (t0, t1) = (c / q, q);
+    let (t0, t1) = if t0 < t1 { (t0, t1) } else { (t1, t0) };
+
+    let inside_clip = |t: f32| -> bool {
+        let p = f + ray.direction * t;
+        if (zmin > -radius && p.z < zmin) || (zmax < radius && p.z > zmax) {
+            return false;
+        }
+        let mut phi = p.y.atan2(p.x);
+        if phi < 0.0 {
+            phi += 2.0 * std::f32::consts::PI;
+        }
+        phi <= phimax
+    };
+    [t0, t1].into_iter().find(|t| *t > tmin && *t < tmax && inside_clip(*t))
+}
+
 fn isect_ray_sphere2(origin: Point3, direction: Vec3, position: Point3, radius: f32, tmax: f32) -> Option<f32>{
     let ox = origin.x as f64;
     let oy = origin.y as f64;
@@ -77,10 +109,10 @@ fn isect_ray_sphere2(origin: Point3, direction: Vec3, position: Point3, radius:
     }
 }
 
-// This intersection routine includes boundary
+// This intersection routine includes boundary, it returns entry distance of the ray
 // https://tavianator.com/2022/ray_box_boundary.html
 #[inline(always)]
-pub fn isect_ray_bbox(ray_origin: Point3, ray_inv_dir: Vec3, bbox_min: Point3, bbox_max: Point3) -> bool {
+pub fn isect_ray_bbox(ray_origin: Point3, ray_inv_dir: Vec3, bbox_min: Point3, bbox_max: Point3) -> Option<f32> {
 
     #[inline(always)]
     fn min(x: f32, y: f32) -> f32 {
@@ -93,7 +125,7 @@ pub fn isect_ray_bbox(ray_origin: Point3, ray_inv_dir: Vec3, bbox_min: Point3, b
     }
 
     let mut tmin = 0.0;
-    let mut tmax = 1e38;
+    let mut tmax = INFINITE_DISTANCE;
 
     let t1 = (bbox_min.x - ray_origin.x) * ray_inv_dir.x;
     let t2 = (bbox_max.x - ray_origin.x) * ray_inv_dir.x;
@@ -113,7 +145,11 @@ pub fn isect_ray_bbox(ray_origin: Point3, ray_inv_dir: Vec3, bbox_min: Point3, b
     tmin = min(max(t1, tmin), max(t2, tmin));
     tmax = max(min(t1, tmax), min(t2, tmax));
 
-    tmin <= tmax
+    if tmin <= tmax {
+        Some(tmin)
+    } else {
+        None
+    }
 }
 
 pub fn isect_ray_triangle(ray: &Ray, v0: Point3, v1: Point3, v2: Point3, tmin: f32) -> Option<f32> {
@@ -169,6 +205,22 @@ pub fn isect_ray_triangle(ray: &Ray, v0: Point3, v1: Point3, v2: Point3, tmin: f
 mod tests {
     use super::*;
 
+    #[test]
+    fn isect_bbox_test() {
+        let bbox_min = Point3::new(-1.0, -1.0, 2.0);
+        let bbox_max = Point3::new(1.0, 1.0, 4.0);
+        let origin = Point3::new(0.0, 0.0, 0.0);
+        let direction = Vec3::new(0.0, 0.0, 1.0);
+        let inv_dir = Vec3::new(1.0 / direction.x, 1.0 / direction.y, 1.0 / direction.z);
+        assert_eq!(isect_ray_bbox(origin, inv_dir, bbox_min, bbox_max), Some(2.0));
+
+        let origin = Point3::new(0.0, 0.0, 3.0);
+        assert_eq!(isect_ray_bbox(origin, inv_dir, bbox_min, bbox_max), Some(0.0));
+
+        let origin = Point3::new(2.0, 0.0, 0.0);
+        assert_eq!(isect_ray_bbox(origin, inv_dir, bbox_min, bbox_max), None);
+    }
+
     #[test]
     fn isect_sphere_test() {
         let origin = Point3::new(1.0, -2.0, -1.0);
@@ -1,23 +1,46 @@
 use std::error::Error;
+#[cfg(feature = "fs")]
 use std::fs;
 use serde_json::Value;
+use std::collections::HashSet;
+#[cfg(feature = "fs")]
 use std::path::Path;
 
 use crate::rgb::ImageSize;
-use crate::color::{TMOType, RGB};
-use crate::vec::{Point3, Vec3};
-use crate::materials::{MaterialDescription, MaterialType};
-use crate::shapes::{ShapeDescription, SphereDescription};
-use crate::lights::{LightDescription, LightType};
-use crate::scene::{SceneDescription, RenderingAlgorithm};
+use crate::color::{TMOType, RGB, BufferPrecision, AovType, ColorEncoding};
+use crate::vec::{Point3, Vec3, Normal, Point2};
+use crate::materials::{MaterialDescription, MaterialType, conductor_preset};
+use crate::textures::{TextureDescription, TextureInput, TextureType};
+use crate::shapes::{ShapeDescription, SphereDescription, MeshDescription, CoordinateSpace};
+use crate::lights::{LightDescription, LightType, EnvironmentMapping};
+use crate::sky::DEFAULT_SKY_SCALE;
+use crate::scene::{SceneDescription, RenderingAlgorithm, LpeOutput, AovOutput};
+use crate::camera::{ScreenWindow, StereoSettings, StereoLayout, ShutterCurve};
 use crate::transformations::Transformation;
-use crate::scene::AmbientOcclusionProperties;
-use crate::scene::{RandomSamplerSettings, Sampler, StratifiedSamplerSettings};
+use crate::scene::{AmbientOcclusionProperties, AmbientOcclusionOutput, FurnaceProperties, SppmProperties};
+use crate::scene::{IntersectorProperties, PreviewShading, LightStrategy, RandomWalkProperties, RestirProperties};
+use crate::scene::{RandomSamplerSettings, Sampler, StratifiedSamplerSettings, TileImportanceProperties, WarmStartProperties};
+use crate::postprocess::{BloomProperties, FogProperties};
+use crate::epsilon::EpsilonPolicy;
+use crate::media::{MediumDescription, MediumInterface, MediumType};
+use crate::raylog::{RayKind, RayLogOutput};
+use crate::checkpoint::CheckpointOutput;
+use crate::light_sampler::LightSamplerType;
+use crate::shapes::AcceleratorType;
+use crate::bvh::BVHBuildMethod;
+use crate::irradiance_cache::IrradianceCacheProperties;
+use crate::path_guiding::PathGuidingProperties;
+use crate::bake::BakeProperties;
 
 
+#[cfg(feature = "fs")]
 pub fn load_scene_description_from_json<P: AsRef<Path>>(path: P) -> Result<SceneDescription, Box<dyn Error>> {
     let contents = fs::read_to_string(path)?;
-    let val: Value = serde_json::from_str(&contents)?;
+    parse_scene_description_from_json(&contents)
+}
+
+pub fn parse_scene_description_from_json(text: &str) -> Result<SceneDescription, Box<dyn Error>> {
+    let val: Value = serde_json::from_str(text)?;
 
     let mut scene_desc = SceneDescription::default();
 
@@ -37,6 +60,10 @@ pub fn load_scene_description_from_json<P: AsRef<Path>>(path: P) -> Result<Scene
     if !camera.is_null() {
         parse_camera(&mut scene_desc, camera)?;
     }
+    let textures = &val["textures"];
+    if !textures.is_null() {
+        scene_desc.textures = parse_textures(textures)?;
+    }
     let materials = &val["materials"];
     if !materials.is_null() {
         let mat_descs = parse_materials(materials)?;
@@ -44,7 +71,7 @@ pub fn load_scene_description_from_json<P: AsRef<Path>>(path: P) -> Result<Scene
     }
     let shapes = &val["shapes"];
     if !shapes.is_null() {
-        let shape_descs = parse_shapes(shapes)?;
+        let shape_descs = parse_shapes(shapes, scene_desc.settings.skip_invalid_transforms, &mut scene_desc.materials)?;
         scene_desc.shapes.extend(shape_descs);
     }
     let lights = &val["lights"];
@@ -52,6 +79,11 @@ pub fn load_scene_description_from_json<P: AsRef<Path>>(path: P) -> Result<Scene
         let light_descs = parse_lights(lights)?;
         scene_desc.lights.extend(light_descs);
     }
+    let media = &val["media"];
+    if !media.is_null() {
+        let medium_descs = parse_media(media)?;
+        scene_desc.media.extend(medium_descs);
+    }
 
     Ok(scene_desc)
 }
@@ -81,10 +113,198 @@ fn parse_global(scene_desc: &mut SceneDescription, section: &Value) -> Result<()
         let output = parse_string(&section["output"], "output")?;
         scene_desc.settings.output_fname = output;
     }
+    if !section["samplecountoutput"].is_null() {
+        let output = parse_string(&section["samplecountoutput"], "samplecountoutput")?;
+        scene_desc.settings.sample_count_output = Some(output);
+    }
+    if !section["raylog"].is_null() {
+        let section = &section["raylog"];
+        let output_fname = parse_string(&section["output"], "raylog->output")?;
+        let x = parse_usize(&section["pixel"][0], "raylog->pixel")?;
+        let y = parse_usize(&section["pixel"][1], "raylog->pixel")?;
+        let mut kinds = vec![RayKind::Primary, RayKind::Shadow, RayKind::Indirect];
+        if !section["rays"].is_null() {
+            kinds.clear();
+            for name in parse_array(&section["rays"], "raylog->rays")?.iter() {
+                let name = parse_string(name, "raylog->rays")?;
+                match RayKind::from_name(&name) {
+                    Some(kind) => kinds.push(kind),
+                    None => return Err(format!("Unsupported kind of rays: {}", name).into())
+                }
+            }
+        }
+        scene_desc.settings.ray_log = Some(RayLogOutput { x, y, kinds, output_fname });
+    }
+    if !section["checkpoint"].is_null() {
+        let section = &section["checkpoint"];
+        let output_fname = parse_string(&section["output"], "checkpoint->output")?;
+        let mut checkpoint = CheckpointOutput { output_fname, interval: 60.0, resume: true };
+        if !section["interval"].is_null() {
+            checkpoint.interval = parse_f32(&section["interval"], "checkpoint->interval")?;
+        }
+        if !section["resume"].is_null() {
+            checkpoint.resume = parse_bool(&section["resume"], "checkpoint->resume")?;
+        }
+        scene_desc.settings.checkpoint = Some(checkpoint);
+    }
+    if !section["warmstart"].is_null() {
+        let section = &section["warmstart"];
+        let image = parse_string(&section["image"], "warmstart->image")?;
+        let mut settings = WarmStartProperties { image, weight: 1.0 };
+        if !section["weight"].is_null() {
+            settings.weight = parse_f32(&section["weight"], "warmstart->weight")?;
+        }
+        scene_desc.settings.warm_start = Some(settings);
+    }
+    if !section["skipinvalidtransforms"].is_null() {
+        scene_desc.settings.skip_invalid_transforms = parse_bool(&section["skipinvalidtransforms"], "skipinvalidtransforms")?;
+    }
+    if !section["telemetry"].is_null() {
+        scene_desc.settings.telemetry = parse_bool(&section["telemetry"], "telemetry")?;
+    }
+    if !section["tileimportance"].is_null() {
+        let section = &section["tileimportance"];
+        let mut settings = TileImportanceProperties::default();
+        if !section["prepassspp"].is_null() {
+            settings.prepass_spp = parse_usize(&section["prepassspp"], "tileimportance->prepassspp")?.max(2);
+        }
+        if !section["minspp"].is_null() {
+            settings.min_spp = parse_usize(&section["minspp"], "tileimportance->minspp")?.max(1);
+        }
+        scene_desc.settings.tile_importance = Some(settings);
+    }
+    if !section["bake"].is_null() {
+        let mesh = parse_usize(&section["bake"]["mesh"], "bake->mesh")?;
+        scene_desc.settings.bake = Some(BakeProperties { mesh });
+    }
+    if !section["bucketoutput"].is_null() {
+        let output = parse_string(&section["bucketoutput"], "bucketoutput")?;
+        scene_desc.settings.bucket_output = Some(output);
+    }
     if !section["nthreads"].is_null() {
         let nthreads = parse_usize(&section["nthreads"], "nthreads")?;
         scene_desc.settings.nthreads = nthreads;
     }
+    if !section["meshcleanup"].is_null() {
+        scene_desc.settings.mesh_cleanup = parse_bool(&section["meshcleanup"], "meshcleanup")?;
+    }
+    if !section["shadingnormals"].is_null() {
+        let shading_normals = &mut scene_desc.settings.shading_normals;
+        let section = &section["shadingnormals"];
+        if !section["enabled"].is_null() {
+            shading_normals.enabled = parse_bool(&section["enabled"], "shadingnormals->enabled")?;
+        }
+        if !section["terminatorsmoothing"].is_null() {
+            shading_normals.terminator_smoothing = parse_bool(&section["terminatorsmoothing"], "shadingnormals->terminatorsmoothing")?;
+        }
+        if !section["compensation"].is_null() {
+            shading_normals.compensation = parse_bool(&section["compensation"], "shadingnormals->compensation")?;
+        }
+    }
+    if !section["fog"].is_null() {
+        let mut fog = FogProperties::default();
+        if !section["fog"]["color"].is_null() {
+            fog.color = parse_rgb_color(&section["fog"]["color"], "fog->color")?;
+        }
+        if !section["fog"]["density"].is_null() {
+            fog.density = parse_f32(&section["fog"]["density"], "fog->density")?;
+        }
+        scene_desc.settings.fog = Some(fog);
+    }
+    if !section["bloom"].is_null() {
+        let mut bloom = BloomProperties::default();
+        let section = &section["bloom"];
+        if !section["threshold"].is_null() {
+            bloom.threshold = parse_f32(&section["threshold"], "bloom->threshold")?;
+        }
+        if !section["intensity"].is_null() {
+            bloom.intensity = parse_f32(&section["intensity"], "bloom->intensity")?;
+        }
+        if !section["radius"].is_null() {
+            bloom.radius = parse_f32(&section["radius"], "bloom->radius")?;
+        }
+        if !section["levels"].is_null() {
+            bloom.levels = parse_usize(&section["levels"], "bloom->levels")?;
+        }
+        scene_desc.settings.bloom = Some(bloom);
+    }
+    if !section["epsilon"].is_null() {
+        let section = &section["epsilon"];
+        let mut epsilon = EpsilonPolicy::default();
+        if !section["relativetmin"].is_null() {
+            epsilon.relative_tmin = parse_f32(&section["relativetmin"], "epsilon->relativetmin")?;
+        }
+        if !section["absolutetmin"].is_null() {
+            epsilon.absolute_tmin = parse_f32(&section["absolutetmin"], "epsilon->absolutetmin")?;
+        }
+        if !section["shadow"].is_null() {
+            epsilon.shadow_epsilon = parse_f32(&section["shadow"], "epsilon->shadow")?;
+        }
+        scene_desc.settings.epsilon = epsilon;
+    }
+    if !section["accelerator"].is_null() {
+        let section = &section["accelerator"];
+        let typ = parse_string(&section["type"], "accelerator->type")?;
+        scene_desc.settings.accelerator = match typ.as_str() {
+            "bvh" => {
+                let mut method = BVHBuildMethod::SAH;
+                if !section["splitmethod"].is_null() {
+                    let name = parse_string(&section["splitmethod"], "accelerator->splitmethod")?;
+                    method = match BVHBuildMethod::from_name(&name) {
+                        Some(method) => method,
+                        None => return Err(format!("Unsupported BVH split method: {}", name).into())
+                    };
+                }
+                AcceleratorType::Bvh(method)
+            }
+            "linear" => AcceleratorType::Linear,
+            _ => return Err(format!("Unsupported accelerator: {}", typ).into())
+        };
+    }
+    if !section["meshorientation"].is_null() {
+        scene_desc.settings.mesh_orientation = parse_bool(&section["meshorientation"], "meshorientation")?;
+    }
+    if !section["lpes"].is_null() {
+        let lpes = match section["lpes"].as_array() {
+            Some(lpes) => lpes,
+            None => return Err("List of light path expressions expected!".into())
+        };
+        for lpe in lpes.iter() {
+            let expression = parse_string(&lpe["expression"], "lpes->expression")?;
+            let output_fname = parse_string(&lpe["output"], "lpes->output")?;
+            scene_desc.settings.lpes.push(LpeOutput { expression, output_fname });
+        }
+    }
+    if !section["aovs"].is_null() {
+        let aovs = match section["aovs"].as_array() {
+            Some(aovs) => aovs,
+            None => return Err("List of auxiliary outputs expected!".into())
+        };
+        for aov in aovs.iter() {
+            let name = parse_string(&aov["type"], "aovs->type")?;
+            let typ = match AovType::from_name(&name) {
+                Some(typ) => typ,
+                None => return Err(format!("Unsupported auxiliary output type: {}", name).into())
+            };
+            let output_fname = parse_string(&aov["output"], "aovs->output")?;
+            scene_desc.settings.aovs.push(AovOutput { typ, output_fname });
+        }
+    }
+    if !section["aooutput"].is_null() {
+        let section = &section["aooutput"];
+        let settings = parse_ambientocclusion_settings(section, "aooutput")?;
+        let output_fname = parse_string(&section["output"], "aooutput->output")?;
+        scene_desc.settings.ao_output = Some(AmbientOcclusionOutput { settings, output_fname });
+    }
+    if !section["precision"].is_null() {
+        let precision = parse_string(&section["precision"], "precision")?;
+        let precision = match precision.as_str() {
+            "full" => BufferPrecision::Full,
+            "half" => BufferPrecision::Half,
+            _ => return Err(format!("Unknown buffer precision: {}", precision).into())
+        };
+        scene_desc.settings.buffer_precision = precision;
+    }
 
     Ok(())
 }
@@ -142,12 +362,19 @@ fn parse_stratified_sampler(scene_desc: &mut SceneDescription, section: &Value)
 
 
 fn parse_integrator(scene_desc: &mut SceneDescription, section: &Value) -> Result<(), Box<dyn Error>> {
+    if !section["maxcomponent"].is_null() {
+        scene_desc.settings.max_component = Some(parse_f32(&section["maxcomponent"], "integrator->maxcomponent")?);
+    }
     if !section["type"].is_null() {
         let alg = parse_string(&section["type"], "integrator->type")?;
         match alg.as_str() {
             "ambientocclusion" => parse_ambientocclusion(scene_desc, section)?,
             "direct_lighting" => parse_directlighting(scene_desc, section)?,
             "path" => parse_path(scene_desc, section)?,
+            "randomwalk" => parse_randomwalk(scene_desc, section)?,
+            "furnace" => parse_furnace(scene_desc, section)?,
+            "sppm" => parse_sppm(scene_desc, section)?,
+            "intersector" => parse_intersector(scene_desc, section)?,
             _ => return Err(format!("Unknown rendering algorithm: {}", alg).into())
         }
     }
@@ -155,20 +382,89 @@ fn parse_integrator(scene_desc: &mut SceneDescription, section: &Value) -> Resul
 }
 
 fn parse_ambientocclusion(scene_desc: &mut SceneDescription, section: &Value) -> Result<(), Box<dyn Error>> {
+    let settings = parse_ambientocclusion_settings(section, "integrator")?;
+    scene_desc.settings.rendering_algorithm = RenderingAlgorithm::AmbientOcclusion(settings);
+    Ok(())
+}
+
+fn parse_ambientocclusion_settings(section: &Value, field_name: &str) -> Result<AmbientOcclusionProperties, Box<dyn Error>> {
     let mut settings = AmbientOcclusionProperties::default();
     if !section["cossample"].is_null() {
-        let cossample = parse_bool(&section["cossample"], "integrator->cossample")?;
+        let cossample = parse_bool(&section["cossample"], &format!("{}->cossample", field_name))?;
         settings.cossample = cossample;
     }
     if !section["maxdistance"].is_null() {
-        let maxdistance = parse_f32(&section["maxdistance"], "integrator->maxdistance")?;
+        let maxdistance = parse_f32(&section["maxdistance"], &format!("{}->maxdistance", field_name))?;
         settings.maxdistance = maxdistance;
     }
-    scene_desc.settings.rendering_algorithm = RenderingAlgorithm::AmbientOcclusion(settings);
-    Ok(())
+    if !section["nsamples"].is_null() {
+        let nsamples = parse_usize(&section["nsamples"], &format!("{}->nsamples", field_name))?;
+        settings.nsamples = nsamples.max(1) as u32;
+    }
+    if !section["bentnormals"].is_null() {
+        let bent_normals = parse_string(&section["bentnormals"], &format!("{}->bentnormals", field_name))?;
+        settings.bent_normal_output = Some(bent_normals);
+    }
+    Ok(settings)
+}
+
+fn parse_restir(section: &Value) -> Result<RestirProperties, Box<dyn Error>> {
+    let mut settings = RestirProperties::default();
+    if !section["candidates"].is_null() {
+        settings.candidates = parse_usize(&section["candidates"], "integrator->candidates")?;
+    }
+    if !section["spatialsamples"].is_null() {
+        settings.spatial_samples = parse_usize(&section["spatialsamples"], "integrator->spatialsamples")?;
+    }
+    if !section["spatialradius"].is_null() {
+        settings.spatial_radius = parse_usize(&section["spatialradius"], "integrator->spatialradius")?;
+    }
+    Ok(settings)
+}
+
+fn parse_irradiance_cache(section: &Value) -> Result<IrradianceCacheProperties, Box<dyn Error>> {
+    let mut settings = IrradianceCacheProperties::default();
+    if !section["enabled"].is_null() {
+        settings.enabled = parse_bool(&section["enabled"], "irradiancecache->enabled")?;
+    }
+    if !section["samples"].is_null() {
+        settings.samples = parse_usize(&section["samples"], "irradiancecache->samples")?.max(1);
+    }
+    if !section["maxerror"].is_null() {
+        settings.max_error = parse_f32(&section["maxerror"], "irradiancecache->maxerror")?;
+    }
+    if !section["minspacing"].is_null() {
+        settings.min_spacing = parse_f32(&section["minspacing"], "irradiancecache->minspacing")?;
+    }
+    if !section["maxspacing"].is_null() {
+        settings.max_spacing = parse_f32(&section["maxspacing"], "irradiancecache->maxspacing")?;
+    }
+    Ok(settings)
 }
 
 fn parse_directlighting(scene_desc: &mut SceneDescription, section: &Value) -> Result<(), Box<dyn Error>> {
+    if !section["strategy"].is_null() {
+        let strategy = parse_string(&section["strategy"], "integrator->strategy")?;
+        scene_desc.settings.light_strategy = match strategy.as_str() {
+            "all" => LightStrategy::All,
+            "one" => LightStrategy::One,
+            "restir" => LightStrategy::Restir(parse_restir(section)?),
+            _ => return Err(format!("Unknown light sampling strategy: {}", strategy).into())
+        };
+    }
+    if !section["lightsampler"].is_null() {
+        let name = parse_string(&section["lightsampler"], "integrator->lightsampler")?;
+        scene_desc.settings.light_sampler = match LightSamplerType::from_name(&name) {
+            Some(typ) => typ,
+            None => return Err(format!("Unknown light sampler: {}", name).into())
+        };
+    }
+    if !section["mis"].is_null() {
+        scene_desc.settings.mis = parse_bool(&section["mis"], "integrator->mis")?;
+    }
+    if !section["irradiancecache"].is_null() {
+        scene_desc.settings.irradiance_cache = Some(parse_irradiance_cache(&section["irradiancecache"])?);
+    }
     scene_desc.settings.rendering_algorithm = RenderingAlgorithm::DirectLighting;
     Ok(())
 }
@@ -178,11 +474,99 @@ fn parse_path(scene_desc: &mut SceneDescription, section: &Value) -> Result<(),
     Ok(())
 }
 
+fn parse_randomwalk(scene_desc: &mut SceneDescription, section: &Value) -> Result<(), Box<dyn Error>> {
+    let mut settings = RandomWalkProperties::default();
+    if !section["maxdepth"].is_null() {
+        settings.maxdepth = parse_usize(&section["maxdepth"], "integrator->maxdepth")?;
+    }
+    if !section["rrdepth"].is_null() {
+        settings.rrdepth = parse_usize(&section["rrdepth"], "integrator->rrdepth")?;
+    }
+    if !section["wavefront"].is_null() {
+        settings.wavefront = parse_bool(&section["wavefront"], "integrator->wavefront")?;
+    }
+    if !section["guiding"].is_null() {
+        scene_desc.settings.path_guiding = Some(parse_path_guiding(&section["guiding"])?);
+    }
+    scene_desc.settings.rendering_algorithm = RenderingAlgorithm::RandomWalk(settings);
+    Ok(())
+}
+
+fn parse_path_guiding(section: &Value) -> Result<PathGuidingProperties, Box<dyn Error>> {
+    let mut settings = PathGuidingProperties::default();
+    if !section["iterations"].is_null() {
+        settings.iterations = parse_usize(&section["iterations"], "guiding->iterations")?;
+    }
+    if !section["bsdffraction"].is_null() {
+        settings.bsdf_fraction = parse_f32(&section["bsdffraction"], "guiding->bsdffraction")?.clamp(0.0, 1.0);
+    }
+    if !section["spatialthreshold"].is_null() {
+        settings.spatial_threshold = parse_f32(&section["spatialthreshold"], "guiding->spatialthreshold")?;
+    }
+    if !section["directionalthreshold"].is_null() {
+        settings.directional_threshold = parse_f32(&section["directionalthreshold"], "guiding->directionalthreshold")?;
+    }
+    Ok(settings)
+}
+
+fn parse_intersector(scene_desc: &mut SceneDescription, section: &Value) -> Result<(), Box<dyn Error>> {
+    let mut settings = IntersectorProperties::default();
+    if !section["shading"].is_null() {
+        let shading = parse_string(&section["shading"], "integrator->shading")?;
+        settings.shading = match shading.as_str() {
+            "facing" => PreviewShading::Facing,
+            "albedo" => PreviewShading::Albedo,
+            _ => return Err(format!("Unknown intersector shading: {}", shading).into())
+        };
+    }
+    scene_desc.settings.rendering_algorithm = RenderingAlgorithm::Intersector(settings);
+    Ok(())
+}
+
+fn parse_furnace(scene_desc: &mut SceneDescription, section: &Value) -> Result<(), Box<dyn Error>> {
+    let mut settings = FurnaceProperties::default();
+    if !section["maxdepth"].is_null() {
+        settings.maxdepth = parse_usize(&section["maxdepth"], "integrator->maxdepth")?;
+    }
+    if !section["radiance"].is_null() {
+        settings.radiance = parse_f32(&section["radiance"], "integrator->radiance")?;
+    }
+    if !section["material"].is_null() {
+        settings.material = Some(parse_string(&section["material"], "integrator->material")?);
+    }
+    scene_desc.settings.rendering_algorithm = RenderingAlgorithm::Furnace(settings);
+    Ok(())
+}
+
+fn parse_sppm(scene_desc: &mut SceneDescription, section: &Value) -> Result<(), Box<dyn Error>> {
+    let mut settings = SppmProperties::default();
+    if !section["iterations"].is_null() {
+        settings.iterations = parse_usize(&section["iterations"], "integrator->iterations")?;
+    }
+    if !section["photonsperiteration"].is_null() {
+        settings.photons_per_iteration = parse_usize(&section["photonsperiteration"], "integrator->photonsperiteration")?;
+    }
+    if !section["maxdepth"].is_null() {
+        settings.maxdepth = parse_usize(&section["maxdepth"], "integrator->maxdepth")?;
+    }
+    if !section["radius"].is_null() {
+        settings.initial_radius = parse_f32(&section["radius"], "integrator->radius")?;
+    }
+    if !section["alpha"].is_null() {
+        settings.alpha = parse_f32(&section["alpha"], "integrator->alpha")?;
+    }
+    scene_desc.settings.rendering_algorithm = RenderingAlgorithm::Sppm(settings);
+    Ok(())
+}
+
 fn parse_camera(scene_desc: &mut SceneDescription, section: &Value) -> Result<(), Box<dyn Error>> {
     if !section["eye"].is_null() {
         let eye = parse_point3(&section["eye"], "camera->eye")?;
         scene_desc.camera_desc.position = eye;
     }
+    if !section["medium"].is_null() {
+        scene_desc.camera_medium = Some(parse_string(&section["medium"], "camera->medium")?);
+    }
     if !section["lookat"].is_null() {
         let look_at = parse_point3(&section["lookat"], "camera->lookat")?;
         scene_desc.camera_desc.look_at = look_at;
@@ -195,6 +579,65 @@ fn parse_camera(scene_desc: &mut SceneDescription, section: &Value) -> Result<()
         let up = parse_vec3(&section["up"], "camera->up")?;
         scene_desc.camera_desc.up = Some(up);
     }
+    if !section["pixelaspect"].is_null() {
+        let pixel_aspect = parse_f32(&section["pixelaspect"], "camera->pixelaspect")?;
+        scene_desc.camera_desc.pixel_aspect_ratio = pixel_aspect;
+    }
+    if !section["screenwindow"].is_null() {
+        let field_name = "camera->screenwindow";
+        let window = &section["screenwindow"];
+        if !window[4].is_null() {
+            return Err(format!("Field: {} - Exactly 4 values expected!", field_name).into())
+        }
+        scene_desc.camera_desc.screen_window = Some(ScreenWindow::new(
+            parse_f32(&window[0], field_name)?, parse_f32(&window[1], field_name)?,
+            parse_f32(&window[2], field_name)?, parse_f32(&window[3], field_name)?));
+    }
+    if !section["lensshift"].is_null() {
+        let field_name = "camera->lensshift";
+        let shift = &section["lensshift"];
+        if !shift[2].is_null() {
+            return Err(format!("Field: {} - Exactly 2 values expected!", field_name).into())
+        }
+        scene_desc.camera_desc.lens_shift = (parse_f32(&shift[0], field_name)?, parse_f32(&shift[1], field_name)?);
+    }
+    if !section["stereo"].is_null() {
+        let stereo = &section["stereo"];
+        let mut settings = StereoSettings::default();
+        if !stereo["layout"].is_null() {
+            let layout = parse_string(&stereo["layout"], "camera->stereo->layout")?;
+            settings.layout = match layout.as_str() {
+                "sidebyside" => StereoLayout::SideBySide,
+                "topbottom" => StereoLayout::TopBottom,
+                _ => return Err(format!("Unknown stereo layout: {}", layout).into())
+            };
+        }
+        if !stereo["interocular"].is_null() {
+            settings.interocular_distance = parse_f32(&stereo["interocular"], "camera->stereo->interocular")?;
+        }
+        if !stereo["convergence"].is_null() {
+            settings.convergence_distance = parse_f32(&stereo["convergence"], "camera->stereo->convergence")?;
+        }
+        scene_desc.camera_desc.stereo = Some(settings);
+    }
+    if !section["shutter"].is_null() {
+        let shutter = &section["shutter"];
+        let settings = &mut scene_desc.camera_desc.shutter;
+        if !shutter["open"].is_null() {
+            settings.open = parse_f32(&shutter["open"], "camera->shutter->open")?;
+        }
+        if !shutter["close"].is_null() {
+            settings.close = parse_f32(&shutter["close"], "camera->shutter->close")?;
+        }
+        if !shutter["curve"].is_null() {
+            let curve = parse_string(&shutter["curve"], "camera->shutter->curve")?;
+            settings.curve = match curve.as_str() {
+                "box" => ShutterCurve::Box,
+                "triangle" => ShutterCurve::Triangle,
+                _ => return Err(format!("Unknown shutter curve: {}", curve).into())
+            };
+        }
+    }
     Ok(())
 }
 
@@ -203,9 +646,13 @@ fn parse_materials(section: &Value) -> Result<Vec<MaterialDescription>, Box<dyn
         Some(mtrs) => mtrs,
         None => return Err("List of materials expected.".into())
     };
-    let mut materials = Vec::new();
+    let mut materials: Vec<MaterialDescription> = Vec::new();
+    let mut names = HashSet::new();
     for mat in mtrs.iter() {
         let name = parse_string(&mat["name"], "material->name")?;
+        if !names.insert(name.clone()) {
+            return Err(format!("Material {} is defined more than once!", name).into())
+        }
         let material_desc = parse_material(mat, &name)?;
         materials.push(material_desc);
     }
@@ -214,23 +661,252 @@ fn parse_materials(section: &Value) -> Result<Vec<MaterialDescription>, Box<dyn
 
 fn parse_material(section: &Value, name: &str) -> Result<MaterialDescription, Box<dyn Error>> {
     let typ = parse_string(&section["type"], "material->type")?;
-    let material_desc = match typ.as_str() {
+    let mut material_desc = match typ.as_str() {
         "matte" => parse_matte_material(section, name)?,
-        // "matte_emissive" => parse_matte_emissive_material(scene_data, section, name)?,
+        "thindielectric" => parse_thin_dielectric_material(section, name)?,
+        "diffusetransmission" => parse_diffuse_transmission_material(section, name)?,
+        "interface" => MaterialDescription { name: name.to_string(), typ: MaterialType::Interface, ..Default::default() },
+        "conductor" => parse_conductor_material(section, name)?,
+        "dielectric" => parse_dielectric_material(section, name)?,
+        "matte_emissive" => parse_matte_emissive_material(section, name)?,
         _ => return Err(format!("Unknown material type {}", typ).into())
     };
+    if !section["diffuse_texture"].is_null() {
+        material_desc.diffuse_texture = Some(parse_string(&section["diffuse_texture"], &format!("material:{}:diffuse_texture", name))?);
+    }
+    if !section["roughness_texture"].is_null() {
+        material_desc.roughness_texture = Some(parse_string(&section["roughness_texture"], &format!("material:{}:roughness_texture", name))?);
+    }
+    if !section["two_sided"].is_null() {
+        material_desc.two_sided = parse_bool(&section["two_sided"], &format!("material:{}:two_sided", name))?;
+    }
     Ok(material_desc)
 }
 
+// Input of texture is number, RGB color or name of other texture
+fn parse_texture_input(section: &Value, field_name: &str) -> Result<TextureInput, Box<dyn Error>> {
+    if section.is_string() {
+        return Ok(TextureInput::Texture(parse_string(section, field_name)?))
+    }
+    if section.is_array() {
+        return Ok(TextureInput::Value(parse_rgb_color(section, field_name)?))
+    }
+    Ok(TextureInput::constant(parse_f32(section, field_name)?))
+}
+
+fn parse_textures(section: &Value) -> Result<Vec<TextureDescription>, Box<dyn Error>> {
+    let textures = parse_array(section, "textures")?;
+    let mut texture_descs: Vec<TextureDescription> = Vec::new();
+    for texture in textures.iter() {
+        let name = parse_string(&texture["name"], "texture->name")?;
+        if texture_descs.iter().any(|desc| desc.name == name) {
+            return Err(format!("Texture {} is defined more than once!", name).into())
+        }
+        let typ = match parse_string(&texture["type"], "texture->type")?.as_str() {
+            "image" => TextureType::Image,
+            "constant" => TextureType::Constant,
+            "checkerboard" => TextureType::Checkerboard,
+            "scale" => TextureType::Scale,
+            "mix" => TextureType::Mix,
+            "fbm" => TextureType::Fbm,
+            "marble" => TextureType::Marble,
+            typ => return Err(format!("Unknown texture type {}", typ).into())
+        };
+        let mut desc = TextureDescription::new(&name, typ);
+        let field = |key: &str| format!("texture:{}:{}", name, key);
+        if typ == TextureType::Image {
+            desc.filename = Some(parse_string(&texture["filename"], &field("filename"))?);
+        }
+        for (key, input) in [("tex1", &mut desc.tex1), ("tex2", &mut desc.tex2), ("amount", &mut desc.amount)] {
+            if !texture[key].is_null() {
+                *input = parse_texture_input(&texture[key], &field(key))?;
+            }
+        }
+        if !texture["value"].is_null() {
+            desc.tex1 = parse_texture_input(&texture["value"], &field("value"))?;
+        }
+        if !texture["scale"].is_null() {
+            // NOTE: scale of scale texture is its second input, it can be texture
+            match typ {
+                TextureType::Scale => desc.tex2 = parse_texture_input(&texture["scale"], &field("scale"))?,
+                _ => desc.scale = parse_f32(&texture["scale"], &field("scale"))?
+            }
+        }
+        if !texture["invert"].is_null() {
+            desc.invert = parse_bool(&texture["invert"], &field("invert"))?;
+        }
+        if !texture["encoding"].is_null() {
+            let encoding = parse_string(&texture["encoding"], &field("encoding"))?;
+            desc.encoding = match ColorEncoding::from_name(&encoding) {
+                Some(encoding) => encoding,
+                None => return Err(format!("Texture {}: Unknown encoding {}", name, encoding).into())
+            };
+        }
+        if !texture["uscale"].is_null() {
+            desc.uscale = parse_f32(&texture["uscale"], &field("uscale"))?;
+        }
+        if !texture["vscale"].is_null() {
+            desc.vscale = parse_f32(&texture["vscale"], &field("vscale"))?;
+        }
+        if !texture["octaves"].is_null() {
+            desc.octaves = parse_usize(&texture["octaves"], &field("octaves"))? as u32;
+        }
+        if !texture["roughness"].is_null() {
+            desc.roughness = parse_f32(&texture["roughness"], &field("roughness"))?;
+        }
+        if !texture["variation"].is_null() {
+            desc.variation = parse_f32(&texture["variation"], &field("variation"))?;
+        }
+        if !texture["transformations"].is_null() {
+            desc.transform = Some(parse_transformations(&texture["transformations"])?);
+        }
+        if !texture["space"].is_null() {
+            let space = parse_string(&texture["space"], &field("space"))?;
+            desc.space = match CoordinateSpace::from_name(&space) {
+                Some(space) => space,
+                None => return Err(format!("Texture {}: Unknown space {}", name, space).into())
+            };
+        }
+        texture_descs.push(desc);
+    }
+    Ok(texture_descs)
+}
+
 
 fn parse_matte_material(section: &Value, name: &str) -> Result<MaterialDescription, Box<dyn Error>> {
     let mut desc = MaterialDescription::default();
-    desc.diffuse = parse_rgb_color(&section["diffuse"], &format!("material:{}:diffuse", name))?;
+    // NOTE: diffuse color can be omitted when it is given by texture
+    if section["diffuse_texture"].is_null() || !section["diffuse"].is_null() {
+        desc.diffuse = parse_rgb_color(&section["diffuse"], &format!("material:{}:diffuse", name))?;
+    }
     desc.name = name.to_string();
     desc.typ = MaterialType::Matte;
     Ok(desc)
 }
 
+fn parse_matte_emissive_material(section: &Value, name: &str) -> Result<MaterialDescription, Box<dyn Error>> {
+    let mut desc = MaterialDescription { name: name.to_string(), typ: MaterialType::EmissiveMatte,
+                                         diffuse: RGB::zero(), ..Default::default() };
+    if !section["diffuse"].is_null() {
+        desc.diffuse = parse_rgb_color(&section["diffuse"], &format!("material:{}:diffuse", name))?;
+    }
+    desc.emission = parse_rgb_color(&section["emission"], &format!("material:{}:emission", name))?;
+    if !section["light_group"].is_null() {
+        desc.light_group = Some(parse_string(&section["light_group"], &format!("material:{}:light_group", name))?);
+    }
+    Ok(desc)
+}
+
+fn parse_thin_dielectric_material(section: &Value, name: &str) -> Result<MaterialDescription, Box<dyn Error>> {
+    let mut desc = MaterialDescription::default();
+    if !section["eta"].is_null() {
+        desc.eta = parse_f32(&section["eta"], &format!("material:{}:eta", name))?;
+    }
+    desc.name = name.to_string();
+    desc.typ = MaterialType::ThinDielectric;
+    Ok(desc)
+}
+
+fn parse_diffuse_transmission_material(section: &Value, name: &str) -> Result<MaterialDescription, Box<dyn Error>> {
+    let diffuse = parse_rgb_color(&section["diffuse"], &format!("material:{}:diffuse", name))?;
+    let transmittance = parse_rgb_color(&section["transmittance"], &format!("material:{}:transmittance", name))?;
+    Ok(MaterialDescription { name: name.to_string(), typ: MaterialType::DiffuseTransmission,
+                             diffuse, transmittance, ..Default::default() })
+}
+
+fn parse_dielectric_material(section: &Value, name: &str) -> Result<MaterialDescription, Box<dyn Error>> {
+    let mut desc = MaterialDescription { name: name.to_string(), typ: MaterialType::Dielectric, ..Default::default() };
+    if !section["eta"].is_null() {
+        desc.eta = parse_f32(&section["eta"], &format!("material:{}:eta", name))?;
+    }
+    Ok(desc)
+}
+
+fn parse_conductor_material(section: &Value, name: &str) -> Result<MaterialDescription, Box<dyn Error>> {
+    let mut desc = MaterialDescription { name: name.to_string(), typ: MaterialType::Conductor, ..Default::default() };
+    if !section["metal"].is_null() {
+        let metal = parse_string(&section["metal"], &format!("material:{}:metal", name))?;
+        (desc.conductor_eta, desc.conductor_k) = match conductor_preset(&metal) {
+            Some(preset) => preset,
+            None => return Err(format!("Unknown metal {} in material {}", metal, name).into())
+        };
+    }
+    if !section["eta"].is_null() {
+        desc.conductor_eta = parse_rgb_color(&section["eta"], &format!("material:{}:eta", name))?;
+    }
+    if !section["k"].is_null() {
+        desc.conductor_k = parse_rgb_color(&section["k"], &format!("material:{}:k", name))?;
+    }
+    if !section["roughness"].is_null() {
+        desc.roughness = parse_f32(&section["roughness"], &format!("material:{}:roughness", name))?;
+    }
+    if !section["uroughness"].is_null() {
+        desc.uroughness = Some(parse_f32(&section["uroughness"], &format!("material:{}:uroughness", name))?);
+    }
+    if !section["vroughness"].is_null() {
+        desc.vroughness = Some(parse_f32(&section["vroughness"], &format!("material:{}:vroughness", name))?);
+    }
+    if !section["remaproughness"].is_null() {
+        desc.remap_roughness = parse_bool(&section["remaproughness"], &format!("material:{}:remaproughness", name))?;
+    }
+    Ok(desc)
+}
+
+fn parse_media(section: &Value) -> Result<Vec<MediumDescription>, Box<dyn Error>> {
+    let media = parse_array(section, "media")?;
+    let mut medium_descs = Vec::new();
+    for medium in media.iter() {
+        let mut desc = MediumDescription { name: parse_string(&medium["name"], "medium->name")?, ..Default::default() };
+        if !medium["sigma_a"].is_null() {
+            desc.sigma_a = parse_rgb_color(&medium["sigma_a"], "medium->sigma_a")?;
+        }
+        if !medium["sigma_s"].is_null() {
+            desc.sigma_s = parse_rgb_color(&medium["sigma_s"], "medium->sigma_s")?;
+        }
+        if !medium["scale"].is_null() {
+            desc.scale = parse_f32(&medium["scale"], "medium->scale")?;
+        }
+        if !medium["g"].is_null() {
+            desc.g = parse_f32(&medium["g"], "medium->g")?;
+        }
+        if !medium["type"].is_null() {
+            desc.typ = match parse_string(&medium["type"], "medium->type")?.as_str() {
+                "homogeneous" => MediumType::Homogeneous,
+                "uniformgrid" => MediumType::UniformGrid,
+                typ => return Err(format!("Unknown medium type {}", typ).into())
+            };
+        }
+        if !medium["density"].is_null() {
+            let density = parse_array(&medium["density"], "medium->density")?;
+            desc.density = Some(density.iter().map(|d| parse_f32(d, "medium->density")).collect::<Result<_, _>>()?);
+            desc.nx = parse_usize(&medium["nx"], "medium->nx")?;
+            desc.ny = parse_usize(&medium["ny"], "medium->ny")?;
+            desc.nz = parse_usize(&medium["nz"], "medium->nz")?;
+        }
+        if !medium["p0"].is_null() {
+            desc.p0 = parse_point3(&medium["p0"], "medium->p0")?;
+        }
+        if !medium["p1"].is_null() {
+            desc.p1 = parse_point3(&medium["p1"], "medium->p1")?;
+        }
+        desc.check()?;
+        medium_descs.push(desc);
+    }
+    Ok(medium_descs)
+}
+
+// NOTE: "inside" and "outside" name media on both sides of shape, missing medium is vacuum
+fn parse_medium_interface(section: &Value) -> Result<MediumInterface, Box<dyn Error>> {
+    let mut mi = MediumInterface::default();
+    if !section["inside"].is_null() {
+        mi.inside = Some(parse_string(&section["inside"], "shape->inside")?);
+    }
+    if !section["outside"].is_null() {
+        mi.outside = Some(parse_string(&section["outside"], "shape->outside")?);
+    }
+    Ok(mi)
+}
+
 fn parse_lights(section: &Value) -> Result<Vec<LightDescription>, Box<dyn Error>> {
     let lights = match section.as_array() {
         Some(lights) => lights,
@@ -248,6 +924,8 @@ fn parse_light(section: &Value) -> Result<LightDescription, Box<dyn Error>> {
     let typ = parse_string(&section["type"], "light->type")?;
     let light_desc = match typ.as_str() {
         "point" => parse_point_light(section)?,
+        "infinite" => parse_infinite_light(section)?,
+        "sky" => parse_sky_light(section)?,
         _ => return Err(format!("Unknown light type {}", typ).into())
     };
     Ok(light_desc)
@@ -257,28 +935,125 @@ fn parse_point_light(section: &Value) -> Result<LightDescription, Box<dyn Error>
     let mut desc = LightDescription::default();
     desc.intensity = parse_rgb_color(&section["intensity"], "light->intensity")?;
     desc.position = parse_point3(&section["position"], "light->position")?;
+    if !section["scale"].is_null() {
+        desc.scale = parse_f32(&section["scale"], "light->scale")?;
+    }
+    if !section["power"].is_null() {
+        desc.power = Some(parse_f32(&section["power"], "light->power")?);
+    }
+    if !section["radius"].is_null() {
+        desc.radius = parse_f32(&section["radius"], "light->radius")?;
+    }
+    if !section["group"].is_null() {
+        desc.group = Some(parse_string(&section["group"], "light->group")?);
+    }
     desc.typ = LightType::Point;
     Ok(desc)
 }
 
+fn parse_infinite_light(section: &Value) -> Result<LightDescription, Box<dyn Error>> {
+    let mut desc = LightDescription { typ: LightType::Infinite, ..Default::default() };
+    if !section["radiance"].is_null() {
+        desc.intensity = parse_rgb_color(&section["radiance"], "light->radiance")?;
+    }
+    if !section["filename"].is_null() {
+        desc.filename = Some(parse_string(&section["filename"], "light->filename")?);
+    }
+    if !section["mapping"].is_null() {
+        let name = parse_string(&section["mapping"], "light->mapping")?;
+        desc.mapping = match EnvironmentMapping::from_name(&name) {
+            Some(mapping) => mapping,
+            None => return Err(format!("Unsupported mapping of environment image: {}", name).into())
+        };
+    }
+    if !section["scale"].is_null() {
+        desc.scale = parse_f32(&section["scale"], "light->scale")?;
+    }
+    if !section["transformations"].is_null() {
+        desc.transform = Some(parse_transformations(&section["transformations"])?);
+    }
+    if !section["group"].is_null() {
+        desc.group = Some(parse_string(&section["group"], "light->group")?);
+    }
+    Ok(desc)
+}
+
+fn parse_sky_light(section: &Value) -> Result<LightDescription, Box<dyn Error>> {
+    let mut desc = LightDescription { typ: LightType::Sky, scale: DEFAULT_SKY_SCALE, ..Default::default() };
+    desc.sun_direction = parse_vec3(&section["sun_direction"], "light->sun_direction")?;
+    if desc.sun_direction.length_sqr() == 0.0 {
+        return Err("light->sun_direction is zero vector!".into())
+    }
+    if !section["turbidity"].is_null() {
+        desc.turbidity = parse_f32(&section["turbidity"], "light->turbidity")?;
+    }
+    if !section["scale"].is_null() {
+        desc.scale = parse_f32(&section["scale"], "light->scale")?;
+    }
+    if !section["transformations"].is_null() {
+        desc.transform = Some(parse_transformations(&section["transformations"])?);
+    }
+    if !section["group"].is_null() {
+        desc.group = Some(parse_string(&section["group"], "light->group")?);
+    }
+    Ok(desc)
+}
+
 
-fn parse_shapes(section: &Value) -> Result<Vec<ShapeDescription>, Box<dyn Error>> {
+// NOTE: transformations are checked before shape is parsed, so shape with invalid transformation can be skipped
+fn parse_shapes(section: &Value, skip_invalid_transforms: bool,
+                materials: &mut Vec<MaterialDescription>) -> Result<Vec<ShapeDescription>, Box<dyn Error>> {
     let shapes = match section.as_array() {
         Some(shapes) => shapes,
         None => return Err("List of shapes expected!".into())
     };
     let mut shape_descs = Vec::new();
-    for shape in shapes.iter() {
-        let shape_desc = parse_shape(shape)?;
+    for (index, shape) in shapes.iter().enumerate() {
+        if !shape["transformations"].is_null() {
+            if let Err(e) = parse_transformations(&shape["transformations"]) {
+                if !skip_invalid_transforms {
+                    return Err(format!("Shape {}: Invalid transformation - {}", index, e).into())
+                }
+                println!("Warning: Shape {} skipped, invalid transformation - {}", index, e);
+                continue;
+            }
+        }
+        let mut shape_desc = parse_shape(shape)?;
+        if !shape["two_sided"].is_null() && parse_bool(&shape["two_sided"], "shape->two_sided")? {
+            two_sided_shape(&mut shape_desc, materials)?;
+        }
         shape_descs.push(shape_desc);
     }
     Ok(shape_descs)
 }
 
+// NOTE: two-sided shape gets two-sided copy of its material, so one material can be shared
+// by one-sided and two-sided shapes
+fn two_sided_shape(shape: &mut ShapeDescription, materials: &mut Vec<MaterialDescription>) -> Result<(), Box<dyn Error>> {
+    let material = match shape {
+        ShapeDescription::Sphere(desc) => &mut desc.material,
+        ShapeDescription::Mesh(desc) => &mut desc.material,
+        _ => return Err("Field: shape->two_sided".into())
+    };
+    let desc = match materials.iter().find(|desc| &desc.name == material) {
+        Some(desc) if desc.two_sided => return Ok(()),
+        Some(desc) => desc,
+        None => return Err(format!("Shape: two-sided material {} doesn't exist!", material).into())
+    };
+    let name = format!("{}_two_sided", material);
+    if !materials.iter().any(|desc| desc.name == name) {
+        let desc = MaterialDescription { name: name.clone(), two_sided: true, ..desc.clone() };
+        materials.push(desc);
+    }
+    *material = name;
+    Ok(())
+}
+
 fn parse_shape(section: &Value) -> Result<ShapeDescription, Box<dyn Error>> {
     let typ = parse_string(&section["type"], "shape->type")?;
     let shape_desc = match typ.as_str() {
         "sphere" => parse_sphere_shape(section)?,
+        "mesh" => parse_mesh_shape(section)?,
         _ => return Err(format!("Unknown shape type {}", typ).into())
     };
     Ok(shape_desc)
@@ -292,9 +1067,19 @@ fn parse_sphere_shape(section: &Value) -> Result<ShapeDescription, Box<dyn Error
         desc.position = position;
     }
     let radius = parse_f32(&section["radius"], "shape->radius")?;
-    
+    if !section["zmin"].is_null() {
+        desc.zmin = parse_f32(&section["zmin"], "shape->zmin")?;
+    }
+    if !section["zmax"].is_null() {
+        desc.zmax = parse_f32(&section["zmax"], "shape->zmax")?;
+    }
+    if !section["phimax"].is_null() {
+        desc.phimax = parse_f32(&section["phimax"], "shape->phimax")?;
+    }
+
     desc.material = material;
     desc.radius = radius;
+    desc.medium_interface = parse_medium_interface(section)?;
     if !section["transformations"].is_null() {
         let transform = parse_transformations(&section["transformations"])?;
         desc.transform = Some(transform);
@@ -302,6 +1087,43 @@ fn parse_sphere_shape(section: &Value) -> Result<ShapeDescription, Box<dyn Error
     Ok(ShapeDescription::Sphere(desc))
 }
 
+// NOTE: optional "faces" holds number of vertices of every face, polygons are triangulated
+fn parse_mesh_shape(section: &Value) -> Result<ShapeDescription, Box<dyn Error>> {
+    let mut desc = MeshDescription {
+        material: parse_string(&section["material"], "shape->material")?,
+        ..Default::default()
+    };
+    let vertices = parse_array(&section["vertices"], "shape->vertices")?;
+    desc.vertices = Some(vertices.iter().map(|v| parse_point3(v, "shape->vertices")).collect::<Result<_, _>>()?);
+    let indices = parse_array(&section["indices"], "shape->indices")?;
+    desc.indices = Some(indices.iter().map(|i| parse_usize(i, "shape->indices").map(|i| i as u32)).collect::<Result<_, _>>()?);
+    if !section["normals"].is_null() {
+        let normals = parse_array(&section["normals"], "shape->normals")?;
+        desc.normals = Some(normals.iter().map(|n| {
+            parse_vec3(n, "shape->normals").map(|n| Normal::new(n.x, n.y, n.z))
+        }).collect::<Result<_, _>>()?);
+    }
+    if !section["uvs"].is_null() {
+        let uvs = parse_array(&section["uvs"], "shape->uvs")?;
+        desc.uvs = Some(uvs.iter().map(|uv| {
+            Ok(Point2::new(parse_f32(&uv[0], "shape->uvs")?, parse_f32(&uv[1], "shape->uvs")?))
+        }).collect::<Result<_, Box<dyn Error>>>()?);
+    }
+    desc.check_vertex_attributes().map_err(|e| format!("Field: shape->{}", e))?;
+    if !section["faces"].is_null() {
+        let faces = parse_array(&section["faces"], "shape->faces")?;
+        let faces: Vec<u32> = faces.iter().map(|f| parse_usize(f, "shape->faces").map(|f| f as u32)).collect::<Result<_, _>>()?;
+        desc.triangulate(&faces)?;
+    } else if desc.indices.as_ref().is_some_and(|indices| indices.len() % 3 != 0) {
+        return Err("Field: shape->indices - Number of indices must be multiple of 3 without faces!".into());
+    }
+    if !section["transformations"].is_null() {
+        desc.transform = Some(parse_transformations(&section["transformations"])?);
+    }
+    desc.medium_interface = parse_medium_interface(section)?;
+    Ok(ShapeDescription::Mesh(desc))
+}
+
 fn parse_transformations(section: &Value) -> Result<Transformation, Box<dyn Error>> {
     let transformations = match section.as_array() {
         Some(transformations) => transformations,
@@ -331,6 +1153,9 @@ fn parse_translate(section: &Value) -> Result<Transformation, Box<dyn Error>> {
 
 fn parse_scale(section: &Value) -> Result<Transformation, Box<dyn Error>> {
     let delta = parse_vec3(&section["delta"], "transformation->scale->delta")?;
+    if delta.x * delta.y * delta.z == 0.0 {
+        return Err(format!("Scale ({}, {}, {}) is not invertible.", delta.x, delta.y, delta.z).into())
+    }
     Ok(Transformation::scale(delta.x, delta.y, delta.z))
 }
 
@@ -367,6 +1192,13 @@ fn parse_usize(section: &Value, field_name: &str) -> Result<usize, Box<dyn Error
     Ok(val)
 }
 
+fn parse_array<'a>(section: &'a Value, field_name: &str) -> Result<&'a Vec<Value>, Box<dyn Error>> {
+    match section.as_array() {
+        Some(val) => Ok(val),
+        None => Err(format!("Field: {} - List expected!", field_name).into())
+    }
+}
+
 fn parse_string(section: &Value, field_name: &str) -> Result<String, Box<dyn Error>> {
     let val = match section.as_str() {
         Some(val) => val,
@@ -397,3 +1229,35 @@ fn parse_f32(section: &Value, field_name: &str) -> Result<f32, Box<dyn Error>> {
     };
     Ok(val)
 }
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn two_sided_materials_and_shapes() {
+        let text = r#"{
+            "materials": [
+                {"name": "lamp", "type": "matte_emissive", "emission": [4, 4, 4], "two_sided": true},
+                {"name": "panel", "type": "matte_emissive", "emission": [1, 1, 1]}
+            ],
+            "shapes": [
+                {"type": "sphere", "radius": 1, "material": "lamp", "two_sided": true},
+                {"type": "sphere", "radius": 1, "material": "panel", "two_sided": true},
+                {"type": "sphere", "radius": 1, "material": "panel"}
+            ]
+        }"#;
+        let desc = parse_scene_description_from_json(text).unwrap();
+        let materials: Vec<_> = desc.materials.iter().map(|m| (m.name.as_str(), m.two_sided)).collect();
+        assert_eq!(materials, vec![("lamp", true), ("panel", false), ("panel_two_sided", true)]);
+        let shape_materials: Vec<_> = desc.shapes.iter().map(|shape| match shape {
+            ShapeDescription::Sphere(sphere) => sphere.material.as_str(),
+            _ => ""
+        }).collect();
+        assert_eq!(shape_materials, vec!["lamp", "panel_two_sided", "panel"]);
+
+        let missing = r#"{"shapes": [{"type": "sphere", "radius": 1, "material": "lamp", "two_sided": true}]}"#;
+        assert!(parse_scene_description_from_json(missing).is_err());
+    }
+}
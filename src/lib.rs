@@ -14,24 +14,57 @@ pub mod frame;
 pub mod transformations;
 pub mod camera;
 pub mod ray;
+pub mod epsilon;
 pub mod tile;
 pub mod color;
 pub mod shapes;
+pub mod bvh;
+pub mod mesh;
 pub mod samplings;
 pub mod lights;
+pub mod sky;
+pub mod light_sampler;
 pub mod materials;
+pub mod textures;
+pub mod hair;
 pub mod json;
 pub mod scene;
 pub mod pbrt_v4_tokenizer;
 pub mod pbrt_v4;
 pub mod integrators;
 pub mod samplers;
+pub mod sampler_audit;
 pub mod filter;
+pub mod wavefront;
+pub mod furnace;
+pub mod sppm;
+pub mod image_diff;
+pub mod exr;
+pub mod postprocess;
+pub mod golden;
+pub mod lpe;
+pub mod raylog;
+pub mod telemetry;
+pub mod restir;
+pub mod checkpoint;
+pub mod irradiance_cache;
+pub mod path_guiding;
+pub mod bake;
+pub mod media;
+#[cfg(feature = "ffi")]
+pub mod ffi;
 
 pub use crate::color::{RGBPixelSample, AccumlationBuffer};
 pub use crate::rgb::ImageSize;
 pub use crate::camera::{PerspectiveCameraDescriptor, PerspectiveCamera};
 pub use crate::ray::Ray;
 pub use crate::tile::Tile;
+#[cfg(feature = "fs")]
 pub use crate::json::load_scene_description_from_json;
+#[cfg(feature = "fs")]
 pub use crate::pbrt_v4::parse_pbrt_v4_input_file;
+pub use crate::json::parse_scene_description_from_json;
+pub use crate::pbrt_v4::parse_pbrt_v4_string;
+pub use crate::scene::{SceneFormat, parse_scene_description, CancelToken};
+pub use crate::integrators::{render_scene, render_scene_in_pool, render_scene_to_png};
+pub use rayon::{ThreadPool, ThreadPoolBuilder};
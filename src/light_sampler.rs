@@ -0,0 +1,300 @@
+//! Selection of one light for shading point
+//!
+//! Uniform and power samplers don't depend on shading point. Light BVH estimates importance of clusters
+//! of lights from their power and distance, so close bright lights are chosen more often. Lights without
+//! bounds (infinite lights) are chosen by BVH sampler with fixed probability.
+
+use crate::lights::LightInterface;
+use crate::shapes::AABB;
+use crate::vec::Point3;
+
+// Largest f32 smaller than one
+const ONE_MINUS_EPSILON: f32 = 1.0 - f32::EPSILON / 2.0;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LightSamplerType {
+    Uniform,
+    /// Probability of light is proportional to its power
+    Power,
+    /// Probability of light depends on its power and distance from shading point
+    Bvh
+}
+
+impl LightSamplerType {
+    /// Names are same as names of light samplers in pbrt
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "uniform" => Some(LightSamplerType::Uniform),
+            "power" => Some(LightSamplerType::Power),
+            "bvh" => Some(LightSamplerType::Bvh),
+            _ => None
+        }
+    }
+}
+
+/// Chosen light and probability of its selection
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SampledLight {
+    pub index: usize,
+    pub pmf: f32,
+}
+
+pub trait LightSamplerInterface: Send + Sync {
+    fn sample(&self, p: Point3, u: f32) -> Option<SampledLight>;
+    /// Probability that sample at point p chooses light with given index
+    fn pmf(&self, p: Point3, index: usize) -> f32;
+}
+
+pub fn create_light_sampler(typ: LightSamplerType, lights: &[Box<dyn LightInterface>]) -> Box<dyn LightSamplerInterface> {
+    match typ {
+        LightSamplerType::Uniform => Box::new(UniformLightSampler::new(lights.len())),
+        LightSamplerType::Power => Box::new(PowerLightSampler::new(lights)),
+        LightSamplerType::Bvh => Box::new(BVHLightSampler::new(lights)),
+    }
+}
+
+pub struct UniformLightSampler {
+    nlights: usize,
+}
+
+impl UniformLightSampler {
+    pub fn new(nlights: usize) -> Self {
+        Self { nlights }
+    }
+}
+
+impl LightSamplerInterface for UniformLightSampler {
+    fn sample(&self, _p: Point3, u: f32) -> Option<SampledLight> {
+        if self.nlights == 0 {
+            return None
+        }
+        let index = ((u * self.nlights as f32) as usize).min(self.nlights - 1);
+        Some(SampledLight { index, pmf: (self.nlights as f32).recip() })
+    }
+
+    fn pmf(&self, _p: Point3, index: usize) -> f32 {
+        if index < self.nlights { (self.nlights as f32).recip() } else { 0.0 }
+    }
+}
+
+/// Lights are chosen proportionally to luminance of their power, uniformly if no light emits.
+pub struct PowerLightSampler {
+    pmfs: Vec<f32>,
+    cdf: Vec<f32>,
+}
+
+impl PowerLightSampler {
+    pub fn new(lights: &[Box<dyn LightInterface>]) -> Self {
+        let powers: Vec<f32> = lights.iter().map(|light| light.power().luminance().max(0.0)).collect();
+        let total: f32 = powers.iter().sum();
+        let pmfs: Vec<f32> = if total > 0.0 {
+            powers.iter().map(|power| power / total).collect()
+        } else {
+            vec![(lights.len() as f32).recip(); lights.len()]
+        };
+        let cdf = pmfs.iter().scan(0.0, |acc, pmf| { *acc += pmf; Some(*acc) }).collect();
+        Self { pmfs, cdf }
+    }
+}
+
+impl LightSamplerInterface for PowerLightSampler {
+    fn sample(&self, _p: Point3, u: f32) -> Option<SampledLight> {
+        let total = *self.cdf.last()?;
+        let index = self.cdf.partition_point(|c| *c <= u * total).min(self.cdf.len() - 1);
+        // NOTE: lights without power are never chosen
+        let index = (index..self.pmfs.len()).chain((0..index).rev()).find(|i| self.pmfs[*i] > 0.0)?;
+        Some(SampledLight { index, pmf: self.pmfs[index] })
+    }
+
+    fn pmf(&self, _p: Point3, index: usize) -> f32 {
+        self.pmfs.get(index).copied().unwrap_or(0.0)
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct LightNode {
+    bounds: AABB,
+    // Luminance of power of all lights in the node
+    phi: f32,
+    // NOTE: for interior node index of second child, for leaf node index of light
+    offset: u32,
+    is_leaf: bool,
+}
+
+// Importance of node for shading point, distance is clamped to half of diagonal of the node,
+// so points inside of the node don't get infinite importance.
+fn importance(node: &LightNode, p: Point3) -> f32 {
+    if node.phi <= 0.0 {
+        return 0.0
+    }
+    let half_diagonal = 0.5 * node.bounds.min().distance(node.bounds.max());
+    let dist_sqr = p.distance(node.bounds.centroid()).powi(2).max(half_diagonal * half_diagonal).max(1e-6);
+    node.phi / dist_sqr
+}
+
+/// Lights with bounds are stored in binary hierarchy, every leaf is one light. Light is chosen by
+/// descending from root, child is chosen proportionally to its importance for the shading point.
+pub struct BVHLightSampler {
+    nodes: Vec<LightNode>,
+    infinite_lights: Vec<usize>,
+    // Path from root to leaf of bounded lights, bit i is set when second child was chosen at depth i
+    trails: Vec<Option<u64>>,
+}
+
+impl BVHLightSampler {
+    pub fn new(lights: &[Box<dyn LightInterface>]) -> Self {
+        let mut infinite_lights = Vec::new();
+        let mut bounded = Vec::new();
+        for (index, light) in lights.iter().enumerate() {
+            match light.bounds() {
+                Some(bounds) => bounded.push((index, bounds, light.power().luminance().max(0.0))),
+                None => infinite_lights.push(index)
+            }
+        }
+        let mut sampler = Self { nodes: Vec::new(), infinite_lights, trails: vec![None; lights.len()] };
+        if !bounded.is_empty() {
+            sampler.build(&mut bounded, 0, 0);
+        }
+        sampler
+    }
+
+    fn build(&mut self, lights: &mut [(usize, AABB, f32)], trail: u64, depth: u32) -> usize {
+        let node_idx = self.nodes.len();
+        let bounds = lights.iter().skip(1).fold(lights[0].1, |acc, light| acc.union(&light.1));
+        let phi = lights.iter().map(|light| light.2).sum();
+        // NOTE: trail has 64 bits, deeper nodes become leaves with only first light
+        if lights.len() == 1 || depth == 63 {
+            self.nodes.push(LightNode { bounds, phi: lights[0].2, offset: lights[0].0 as u32, is_leaf: true });
+            self.trails[lights[0].0] = Some(trail);
+            return node_idx
+        }
+        let extent = bounds.max() - bounds.min();
+        let axis_value = |p: Point3| -> f32 {
+            if extent.x >= extent.y && extent.x >= extent.z { p.x } else if extent.y >= extent.z { p.y } else { p.z }
+        };
+        let mid = lights.len() / 2;
+        lights.select_nth_unstable_by(mid, |a, b| axis_value(a.1.centroid()).total_cmp(&axis_value(b.1.centroid())));
+        self.nodes.push(LightNode { bounds, phi, offset: 0, is_leaf: false });
+        let (left, right) = lights.split_at_mut(mid);
+        self.build(left, trail, depth + 1);
+        let second_child = self.build(right, trail | (1 << depth), depth + 1);
+        self.nodes[node_idx].offset = second_child as u32;
+        node_idx
+    }
+
+    // Probability that infinite lights are chosen instead of hierarchy
+    fn infinite_probability(&self) -> f32 {
+        let bvh = if self.nodes.is_empty() { 0.0 } else { 1.0 };
+        let ninfinite = self.infinite_lights.len() as f32;
+        if ninfinite > 0.0 { ninfinite / (ninfinite + bvh) } else { 0.0 }
+    }
+}
+
+impl LightSamplerInterface for BVHLightSampler {
+    fn sample(&self, p: Point3, u: f32) -> Option<SampledLight> {
+        let p_infinite = self.infinite_probability();
+        if u < p_infinite {
+            let ninfinite = self.infinite_lights.len();
+            let index = ((u / p_infinite * ninfinite as f32) as usize).min(ninfinite - 1);
+            return Some(SampledLight { index: self.infinite_lights[index], pmf: p_infinite / ninfinite as f32 })
+        }
+        if self.nodes.is_empty() {
+            return None
+        }
+        let mut u = ((u - p_infinite) / (1.0 - p_infinite)).min(ONE_MINUS_EPSILON);
+        let mut pmf = 1.0 - p_infinite;
+        let mut node_idx = 0;
+        loop {
+            let node = &self.nodes[node_idx];
+            if node.is_leaf {
+                return (importance(node, p) > 0.0).then_some(SampledLight { index: node.offset as usize, pmf })
+            }
+            let c0 = importance(&self.nodes[node_idx + 1], p);
+            let c1 = importance(&self.nodes[node.offset as usize], p);
+            if c0 == 0.0 && c1 == 0.0 {
+                return None
+            }
+            let p0 = c0 / (c0 + c1);
+            if u < p0 {
+                u = (u / p0).min(ONE_MINUS_EPSILON);
+                pmf *= p0;
+                node_idx += 1;
+            } else {
+                u = ((u - p0) / (1.0 - p0)).min(ONE_MINUS_EPSILON);
+                pmf *= 1.0 - p0;
+                node_idx = node.offset as usize;
+            }
+        }
+    }
+
+    fn pmf(&self, p: Point3, index: usize) -> f32 {
+        let p_infinite = self.infinite_probability();
+        let mut trail = match self.trails.get(index) {
+            Some(Some(trail)) => *trail,
+            Some(None) if self.infinite_lights.contains(&index) => return p_infinite / self.infinite_lights.len() as f32,
+            _ => return 0.0
+        };
+        let mut pmf = 1.0 - p_infinite;
+        let mut node_idx = 0;
+        while !self.nodes[node_idx].is_leaf {
+            let node = &self.nodes[node_idx];
+            let c0 = importance(&self.nodes[node_idx + 1], p);
+            let c1 = importance(&self.nodes[node.offset as usize], p);
+            if c0 == 0.0 && c1 == 0.0 {
+                return 0.0
+            }
+            if trail & 1 == 0 {
+                pmf *= c0 / (c0 + c1);
+                node_idx += 1;
+            } else {
+                pmf *= c1 / (c0 + c1);
+                node_idx = node.offset as usize;
+            }
+            trail >>= 1;
+        }
+        if self.nodes[node_idx].offset as usize == index { pmf } else { 0.0 }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::color::RGB;
+    use crate::lights::{LightDescription, LightType};
+
+    fn point_light(x: f32, intensity: f32) -> Box<dyn LightInterface> {
+        LightDescription { typ: LightType::Point, position: Point3::new(x, 0.0, 0.0),
+                           intensity: RGB::new(intensity, intensity, intensity), ..Default::default() }.create().unwrap()
+    }
+
+    #[test]
+    fn light_sampler_pmfs() {
+        let mut lights = vec![point_light(0.0, 1.0), point_light(10.0, 1.0), point_light(20.0, 3.0), point_light(30.0, 0.0)];
+        lights.push(LightDescription { typ: LightType::Infinite, ..Default::default() }.create().unwrap());
+        let p = Point3::new(1.0, 1.0, 0.0);
+        for typ in [LightSamplerType::Uniform, LightSamplerType::Power, LightSamplerType::Bvh] {
+            let sampler = create_light_sampler(typ, &lights);
+            let total: f32 = (0..lights.len()).map(|index| sampler.pmf(p, index)).sum();
+            assert!((total - 1.0).abs() < 1e-5, "{:?}", typ);
+            let mut counts = [0usize; 5];
+            let n = 10000;
+            for i in 0..n {
+                let sample = sampler.sample(p, (i as f32 + 0.5) / n as f32).unwrap();
+                assert!((sample.pmf - sampler.pmf(p, sample.index)).abs() < 1e-6);
+                counts[sample.index] += 1;
+            }
+            // selection frequencies follow pmfs
+            for (index, count) in counts.iter().enumerate() {
+                assert!((*count as f32 / n as f32 - sampler.pmf(p, index)).abs() < 1e-3, "{:?}", typ);
+            }
+        }
+        let power = PowerLightSampler::new(&lights);
+        assert_eq!(power.pmf(p, 3), 0.0);
+        // close light is preferred by BVH sampler although farther light is brighter
+        let bvh = BVHLightSampler::new(&lights);
+        assert!(bvh.pmf(p, 0) > bvh.pmf(p, 2));
+        assert_eq!(bvh.pmf(p, 4), 0.5);
+        assert!(BVHLightSampler::new(&[]).sample(p, 0.5).is_none());
+    }
+}
@@ -1,6 +1,20 @@
 use crate::vec::Point3;
 use crate::color::RGB;
-use crate::vec::Vec3;
+use crate::vec::{Vec3, Normal};
+use crate::frame::Frame;
+use crate::ray::Ray;
+use crate::samplers::SamplerInterface;
+use crate::samplings::{sample_uniform_cone, sample_uniform_sphere, sample_cos_hemisphere, sample_uniform_disk};
+use crate::samplings::equal_area_sphere_to_square;
+use crate::transformations::Transformation;
+use crate::shapes::AABB;
+use crate::isect::isect_ray_sphere;
+use crate::epsilon::INFINITE_DISTANCE;
+use crate::sky::{PreethamSky, SUN_ANGULAR_RADIUS};
+#[cfg(feature = "fs")]
+use std::error::Error;
+#[cfg(feature = "fs")]
+use std::path::Path;
 
 pub struct LightSample {
     pub intensity: RGB,
@@ -10,12 +24,66 @@ pub struct LightSample {
     pub cos_theta: f32
 }
 
-pub trait LightInterface {
-    fn illuminate(&self, hit: Point3) -> Option<LightSample>;
+/// Ray leaving the light, used by integrators that trace paths from lights.
+pub struct LightLeSample {
+    /// Emitted radiance, intensity for delta lights
+    pub radiance: RGB,
+    pub ray: Ray,
+    /// Normal of light surface at origin of the ray, None for point and infinite lights
+    pub normal: Option<Normal>,
+    /// Density of ray origin with respect to area, 1 for delta position
+    pub pdf_pos: f32,
+    /// Density of ray direction with respect to solid angle
+    pub pdf_dir: f32
+}
+
+pub trait LightInterface: Send + Sync {
+    fn illuminate(&self, hit: Point3, sampler: &mut Box<dyn SamplerInterface>) -> Option<LightSample>;
+    /// Light sample of given point of the light as if it was sampled by illuminate, so samples can be
+    /// reused at other hit points. None when point is not visible from hit or light can't evaluate it.
+    fn eval_sample(&self, _hit: Point3, _position: Point3) -> Option<LightSample> {
+        None
+    }
+    /// First point of the light hit by the ray, it is evaluated by eval_sample when BSDF sampled
+    /// direction is weighted against light sampling. None for delta lights that can't be hit.
+    fn intersect(&self, _ray: &Ray) -> Option<Point3> {
+        None
+    }
+    /// Solid angle density of illuminate sampling direction wi at hit, so BSDF sampled direction that
+    /// hits the light can be weighted against light sampling. Zero for delta lights and for directions
+    /// that illuminate can't generate.
+    fn pdf_li(&self, _hit: Point3, _wi: Vec3) -> f32 {
+        0.0
+    }
+    /// Sample ray emitted by the light.
+    fn sample_le(&self, sampler: &mut Box<dyn SamplerInterface>) -> Option<LightLeSample>;
+    /// Position and direction densities of ray that sample_le could generate, position density
+    /// of delta lights is zero because they can't be hit by random ray.
+    fn pdf_le(&self, ray: &Ray) -> (f32, f32);
+    /// Bounding sphere of the scene is known only after geometry is built.
+    fn preprocess(&mut self, _scene_center: Point3, _scene_radius: f32) {
+    }
     fn is_delta_light(&self) -> bool;
+    /// Total power emitted by the light
+    fn power(&self) -> RGB;
     fn is_area_light(&self) -> bool {
         false
     }
+    fn is_infinite_light(&self) -> bool {
+        false
+    }
+    /// Bounding box of emitting points, None for lights that are infinitely far away
+    fn bounds(&self) -> Option<AABB> {
+        None
+    }
+    /// Bytes used by textures of the light (environment images)
+    fn texture_memory(&self) -> usize {
+        0
+    }
+    /// Radiance arriving from infinity in given direction (ray that left the scene)
+    fn le(&self, _direction: Vec3) -> RGB {
+        RGB::zero()
+    }
 }
 
 pub struct PointLight {
@@ -30,7 +98,11 @@ impl PointLight {
 }
 
 impl LightInterface for PointLight {
-    fn illuminate(&self, hit: Point3) -> Option<LightSample> {
+    fn illuminate(&self, hit: Point3, _sampler: &mut Box<dyn SamplerInterface>) -> Option<LightSample> {
+        self.eval_sample(hit, self.position)
+    }
+
+    fn eval_sample(&self, hit: Point3, _position: Point3) -> Option<LightSample> {
         let direction_to_light = self.position - hit;
         let wi = direction_to_light.normalize();
         let intensity = self.intensity * direction_to_light.length_sqr().recip();
@@ -40,26 +112,570 @@ impl LightInterface for PointLight {
         Some(LightSample { intensity, position, wi, pdfa, cos_theta})
     }
 
+    fn sample_le(&self, sampler: &mut Box<dyn SamplerInterface>) -> Option<LightLeSample> {
+        let (u1, u2) = sampler.next_2d();
+        let sample_direction = sample_uniform_sphere(u1, u2);
+        let ray = Ray::new(self.position, sample_direction.direction);
+        Some(LightLeSample { radiance: self.intensity, ray, normal: None, pdf_pos: 1.0, pdf_dir: sample_direction.pdfw })
+    }
+
+    fn pdf_le(&self, _ray: &Ray) -> (f32, f32) {
+        (0.0, 0.25 * std::f32::consts::FRAC_1_PI)
+    }
+
+    fn is_delta_light(&self) -> bool {
+        true
+    }
+
+    fn power(&self) -> RGB {
+        self.intensity * (4.0 * std::f32::consts::PI)
+    }
+
+    fn bounds(&self) -> Option<AABB> {
+        Some(AABB::new(self.position, self.position))
+    }
+}
+
+// Point light with radius, it is treated as small spherical emitter that is sampled
+// uniformly inside of the cone of directions subtended by the sphere.
+pub struct SphereLight {
+    radiance: RGB,
+    position: Point3,
+    radius: f32
+}
+
+impl SphereLight {
+    pub fn new(intensity: RGB, position: Point3, radius: f32) -> SphereLight {
+        // NOTE: projected area of sphere is PI * r^2 in every direction
+        let radiance = intensity * (std::f32::consts::PI * radius * radius).recip();
+        SphereLight { radiance, position, radius }
+    }
+}
+
+impl LightInterface for SphereLight {
+    fn illuminate(&self, hit: Point3, sampler: &mut Box<dyn SamplerInterface>) -> Option<LightSample> {
+        let direction_to_center = self.position - hit;
+        let dist_sqr = direction_to_center.length_sqr();
+        let radius_sqr = self.radius * self.radius;
+        if dist_sqr <= radius_sqr {
+            return None
+        }
+        let dist = dist_sqr.sqrt();
+        let sin_theta_max_sqr = radius_sqr / dist_sqr;
+        let cos_theta_max = (1.0 - sin_theta_max_sqr).max(0.0).sqrt();
+
+        let (u1, u2) = sampler.next_2d();
+        let sample_direction = sample_uniform_cone(u1, u2, cos_theta_max);
+        let wi = Frame::from(direction_to_center * dist.recip()).to_world(sample_direction.direction).normalize();
+
+        let cos_theta = sample_direction.direction.z;
+        let sin_theta_sqr = (1.0 - cos_theta * cos_theta).max(0.0);
+        let ds = dist * cos_theta - (radius_sqr - dist_sqr * sin_theta_sqr).max(0.0).sqrt();
+        let position = hit + wi * ds;
+
+        let normal = (position - self.position) * self.radius.recip();
+        let cos_theta = (-wi * normal).abs();
+        if cos_theta == 0.0 {
+            return None
+        }
+        let pdfa = sample_direction.pdfw * cos_theta / (ds * ds);
+        Some(LightSample { intensity: self.radiance, position, wi, pdfa, cos_theta })
+    }
+
+    fn eval_sample(&self, hit: Point3, position: Point3) -> Option<LightSample> {
+        let dist_sqr = (self.position - hit).length_sqr();
+        let radius_sqr = self.radius * self.radius;
+        if dist_sqr <= radius_sqr {
+            return None
+        }
+        let cos_theta_max = (1.0 - radius_sqr / dist_sqr).max(0.0).sqrt();
+        let direction_to_light = position - hit;
+        let ds = direction_to_light.length();
+        let wi = direction_to_light * ds.recip();
+        let normal = (position - self.position) * self.radius.recip();
+        // NOTE: points on far side of the sphere are never sampled by cone sampling
+        let cos_theta = -wi * normal;
+        if cos_theta <= 0.0 {
+            return None
+        }
+        let pdfw = 1.0 / (2.0 * std::f32::consts::PI * (1.0 - cos_theta_max));
+        let pdfa = pdfw * cos_theta / (ds * ds);
+        Some(LightSample { intensity: self.radiance, position, wi, pdfa, cos_theta })
+    }
+
+    fn intersect(&self, ray: &Ray) -> Option<Point3> {
+        let t = isect_ray_sphere(ray, self.position, self.radius, 0.0, INFINITE_DISTANCE)?;
+        Some(ray.point_at(t))
+    }
+
+    fn pdf_li(&self, hit: Point3, wi: Vec3) -> f32 {
+        let direction_to_center = self.position - hit;
+        let dist_sqr = direction_to_center.length_sqr();
+        let radius_sqr = self.radius * self.radius;
+        if dist_sqr <= radius_sqr {
+            return 0.0
+        }
+        let cos_theta_max = (1.0 - radius_sqr / dist_sqr).max(0.0).sqrt();
+        if wi * direction_to_center < cos_theta_max * dist_sqr.sqrt() {
+            return 0.0
+        }
+        1.0 / (2.0 * std::f32::consts::PI * (1.0 - cos_theta_max))
+    }
+
+    // NOTE: origin is uniform on the sphere, direction is cosine distributed around normal
+    fn sample_le(&self, sampler: &mut Box<dyn SamplerInterface>) -> Option<LightLeSample> {
+        let (u1, u2) = sampler.next_2d();
+        let normal = sample_uniform_sphere(u1, u2).direction;
+        let origin = self.position + normal * self.radius;
+        let (u1, u2) = sampler.next_2d();
+        let sample_direction = sample_cos_hemisphere(u1, u2);
+        if sample_direction.pdfw == 0.0 {
+            return None
+        }
+        let direction = Frame::from(normal).to_world(sample_direction.direction).normalize();
+        let area = 4.0 * std::f32::consts::PI * self.radius * self.radius;
+        let ray = Ray::new(origin, direction);
+        let normal = Normal::new(normal.x, normal.y, normal.z);
+        Some(LightLeSample { radiance: self.radiance, ray, normal: Some(normal), pdf_pos: area.recip(), pdf_dir: sample_direction.pdfw })
+    }
+
+    fn pdf_le(&self, ray: &Ray) -> (f32, f32) {
+        let area = 4.0 * std::f32::consts::PI * self.radius * self.radius;
+        let normal = (ray.origin - self.position).normalize();
+        let cos_theta = normal * ray.direction;
+        let pdf_dir = if cos_theta > 0.0 { cos_theta * std::f32::consts::FRAC_1_PI } else { 0.0 };
+        (area.recip(), pdf_dir)
+    }
+
     fn is_delta_light(&self) -> bool {
+        false
+    }
+
+    fn power(&self) -> RGB {
+        let area = 4.0 * std::f32::consts::PI * self.radius * self.radius;
+        self.radiance * (std::f32::consts::PI * area)
+    }
+
+    fn is_area_light(&self) -> bool {
         true
     }
+
+    fn bounds(&self) -> Option<AABB> {
+        let radius = Vec3::new(self.radius, self.radius, self.radius);
+        Some(AABB::new(self.position + -radius, self.position + radius))
+    }
+}
+
+/// Layout of environment image
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum EnvironmentMapping {
+    /// Latitude-longitude image, z axis is up and u coordinate follows phi measured from x axis
+    Equirect,
+    /// Square image with equal-area octahedral mapping, layout of pbrt-v4 environment images
+    EqualArea
+}
+
+impl EnvironmentMapping {
+    pub fn name(&self) -> &'static str {
+        match self {
+            EnvironmentMapping::Equirect => "equirect",
+            EnvironmentMapping::EqualArea => "equalarea"
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "equirect" => Some(EnvironmentMapping::Equirect),
+            "equalarea" => Some(EnvironmentMapping::EqualArea),
+            _ => None
+        }
+    }
 }
 
+/// Environment image in light space, see EnvironmentMapping for its layout.
+pub struct EnvironmentMap {
+    width: usize,
+    height: usize,
+    pixels: Vec<RGB>,
+    mapping: EnvironmentMapping
+}
+
+impl EnvironmentMap {
+    pub fn new(width: usize, height: usize, pixels: Vec<RGB>, mapping: EnvironmentMapping) -> Result<Self, String> {
+        assert_eq!(width * height, pixels.len());
+        if mapping == EnvironmentMapping::EqualArea && width != height {
+            return Err(format!("equal-area environment image must be square, its size is {}x{}", width, height))
+        }
+        Ok(Self { width, height, pixels, mapping })
+    }
+
+    pub fn memory_usage(&self) -> usize {
+        self.pixels.capacity() * std::mem::size_of::<RGB>()
+    }
+
+    #[cfg(feature = "fs")]
+    pub fn load<P: AsRef<Path>>(path: P, mapping: EnvironmentMapping) -> Result<Self, Box<dyn Error>> {
+        let img = image::open(path)?.to_rgb32f();
+        let pixels = img.pixels().map(|p| RGB::new(p[0], p[1], p[2])).collect();
+        Ok(Self::new(img.width() as usize, img.height() as usize, pixels, mapping)?)
+    }
+
+    #[cfg(not(feature = "fs"))]
+    pub fn load(_path: &str, _mapping: EnvironmentMapping) -> Result<Self, String> {
+        Err("loading of environment image requires fs feature".to_string())
+    }
+
+    pub fn lookup(&self, direction: Vec3) -> RGB {
+        let (u, v) = match self.mapping {
+            EnvironmentMapping::Equirect => {
+                let mut phi = direction.y.atan2(direction.x);
+                if phi < 0.0 {
+                    phi += 2.0 * std::f32::consts::PI;
+                }
+                let theta = direction.z.clamp(-1.0, 1.0).acos();
+                (phi * 0.5 * std::f32::consts::FRAC_1_PI, theta * std::f32::consts::FRAC_1_PI)
+            }
+            EnvironmentMapping::EqualArea => equal_area_sphere_to_square(direction)
+        };
+        let x = ((u * self.width as f32) as usize).min(self.width - 1);
+        let y = ((v * self.height as f32) as usize).min(self.height - 1);
+        self.pixels[y * self.width + x]
+    }
+
+    // Average over sphere of directions, rows of equirect image are weighted by sin(theta)
+    fn average(&self) -> RGB {
+        if self.mapping == EnvironmentMapping::EqualArea {
+            let sum = self.pixels.iter().fold(RGB::zero(), |sum, pixel| sum + *pixel);
+            return sum * (self.pixels.len() as f32).recip()
+        }
+        let mut sum = RGB::zero();
+        let mut weight_sum = 0.0;
+        for y in 0..self.height {
+            let weight = (std::f32::consts::PI * (y as f32 + 0.5) / self.height as f32).sin();
+            for x in 0..self.width {
+                sum += self.pixels[y * self.width + x] * weight;
+            }
+            weight_sum += weight * self.width as f32;
+        }
+        sum * weight_sum.recip()
+    }
+}
+
+// NOTE: shadow rays towards infinite light end at this distance
+const INFINITE_LIGHT_DISTANCE: f32 = 1e6;
+
+/// Light that surrounds the scene, radiance is constant or given by environment map.
+pub struct InfiniteLight {
+    radiance: RGB,
+    map: Option<EnvironmentMap>,
+    world_to_light: Transformation,
+    scene_center: Point3,
+    scene_radius: f32
+}
+
+impl InfiniteLight {
+    pub fn new(radiance: RGB, map: Option<EnvironmentMap>, light_to_world: Transformation) -> Self {
+        Self { radiance, map, world_to_light: light_to_world.inverse(),
+               scene_center: Point3::new(0.0, 0.0, 0.0), scene_radius: 1.0 }
+    }
+}
+
+impl LightInterface for InfiniteLight {
+    fn illuminate(&self, hit: Point3, sampler: &mut Box<dyn SamplerInterface>) -> Option<LightSample> {
+        let (u1, u2) = sampler.next_2d();
+        let sample_direction = sample_uniform_sphere(u1, u2);
+        let wi = sample_direction.direction;
+        let position = hit + wi * INFINITE_LIGHT_DISTANCE;
+        // NOTE: pdfa is chosen so that conversion to solid angle gives back pdfw
+        let pdfa = sample_direction.pdfw / (INFINITE_LIGHT_DISTANCE * INFINITE_LIGHT_DISTANCE);
+        Some(LightSample { intensity: self.le(wi), position, wi, pdfa, cos_theta: 1.0 })
+    }
+
+    fn eval_sample(&self, hit: Point3, position: Point3) -> Option<LightSample> {
+        let wi = (position - hit).normalize();
+        let pdfa = 0.25 * std::f32::consts::FRAC_1_PI / (INFINITE_LIGHT_DISTANCE * INFINITE_LIGHT_DISTANCE);
+        Some(LightSample { intensity: self.le(wi), position, wi, pdfa, cos_theta: 1.0 })
+    }
+
+    fn intersect(&self, ray: &Ray) -> Option<Point3> {
+        Some(ray.point_at(INFINITE_LIGHT_DISTANCE))
+    }
+
+    fn pdf_li(&self, _hit: Point3, _wi: Vec3) -> f32 {
+        0.25 * std::f32::consts::FRAC_1_PI
+    }
+
+    // NOTE: ray starts on disk that covers bounding sphere of the scene and is perpendicular to ray direction
+    fn sample_le(&self, sampler: &mut Box<dyn SamplerInterface>) -> Option<LightLeSample> {
+        let (u1, u2) = sampler.next_2d();
+        let sample_direction = sample_uniform_sphere(u1, u2);
+        let wi = sample_direction.direction;
+        let frame = Frame::from(-wi);
+        let (u1, u2) = sampler.next_2d();
+        let (dx, dy) = sample_uniform_disk(u1, u2);
+        let disk_point = frame.to_world(Vec3::new(dx, dy, 0.0));
+        let origin = self.scene_center + (wi + disk_point) * self.scene_radius;
+        let pdf_pos = (std::f32::consts::PI * self.scene_radius * self.scene_radius).recip();
+        let ray = Ray::new(origin, -wi);
+        Some(LightLeSample { radiance: self.le(wi), ray, normal: None, pdf_pos, pdf_dir: sample_direction.pdfw })
+    }
+
+    fn pdf_le(&self, _ray: &Ray) -> (f32, f32) {
+        let pdf_pos = (std::f32::consts::PI * self.scene_radius * self.scene_radius).recip();
+        (pdf_pos, 0.25 * std::f32::consts::FRAC_1_PI)
+    }
+
+    fn preprocess(&mut self, scene_center: Point3, scene_radius: f32) {
+        self.scene_center = scene_center;
+        self.scene_radius = scene_radius.max(1e-3);
+    }
+
+    fn is_delta_light(&self) -> bool {
+        false
+    }
+
+    // NOTE: power is calculated for scene with bounding sphere of unit radius
+    fn power(&self) -> RGB {
+        let average = match &self.map {
+            Some(map) => self.radiance * map.average(),
+            None => self.radiance
+        };
+        average * (4.0 * std::f32::consts::PI * std::f32::consts::PI)
+    }
+
+    fn is_infinite_light(&self) -> bool {
+        true
+    }
+
+    fn texture_memory(&self) -> usize {
+        self.map.as_ref().map_or(0, |map| map.memory_usage())
+    }
+
+    fn le(&self, direction: Vec3) -> RGB {
+        match &self.map {
+            Some(map) => self.radiance * map.lookup((self.world_to_light * direction).normalize()),
+            None => self.radiance
+        }
+    }
+}
+
+/// Procedural sun and sky surrounding the scene (see PreethamSky), up direction of the sky is z axis of
+/// light space. Directions are sampled from mixture of the sun cone and cosine distribution of the sky,
+/// probability of the sun is its share of power of the light. Scale converts radiance of the model in kcd/m^2
+/// to radiance of the scene (see DEFAULT_SKY_SCALE).
+pub struct SkyLight {
+    sky: PreethamSky,
+    scale: RGB,
+    world_to_light: Transformation,
+    up: Vec3,
+    sun_direction: Vec3,
+    cos_sun: f32,
+    sun_pdf: f32,
+    sun_probability: f32,
+    average: RGB,
+    scene_center: Point3,
+    scene_radius: f32
+}
+
+impl SkyLight {
+    /// Sun direction is given in light space.
+    pub fn new(sun_direction: Vec3, turbidity: f32, scale: RGB, light_to_world: Transformation) -> Self {
+        let sky = PreethamSky::new(sun_direction, turbidity);
+        let up = (light_to_world * Vec3::new(0.0, 0.0, 1.0)).normalize();
+        let sun_direction = (light_to_world * sky.sun_direction()).normalize();
+        // NOTE: 1 - cos is computed from half angle, cos of tiny angle is too close to one
+        let half_angle = (0.5 * SUN_ANGULAR_RADIUS).sin();
+        let sun_solid_angle = 4.0 * std::f32::consts::PI * half_angle * half_angle;
+
+        // Sky is integrated over upper hemisphere in midpoints of cells of angles
+        let (ntheta, nphi) = (32, 64);
+        let (dtheta, dphi) = (std::f32::consts::FRAC_PI_2 / ntheta as f32, 2.0 * std::f32::consts::PI / nphi as f32);
+        let mut sky_sum = RGB::zero();
+        for i in 0..ntheta {
+            let theta = (i as f32 + 0.5) * dtheta;
+            for j in 0..nphi {
+                let phi = (j as f32 + 0.5) * dphi;
+                let direction = Vec3::new(theta.sin() * phi.cos(), theta.sin() * phi.sin(), theta.cos());
+                sky_sum += sky.radiance(direction) * (theta.sin() * dtheta * dphi);
+            }
+        }
+        let sun_power = sky.sun_radiance() * sun_solid_angle;
+        let average = (sky_sum + sun_power) * (0.25 * std::f32::consts::FRAC_1_PI);
+        let total = sky_sum.luminance() + sun_power.luminance();
+        let sun_probability = if total > 0.0 { (sun_power.luminance() / total).min(0.9) } else { 0.0 };
+
+        Self { sky, scale, world_to_light: light_to_world.inverse(), up, sun_direction,
+               cos_sun: 1.0 - 2.0 * half_angle * half_angle, sun_pdf: sun_solid_angle.recip(),
+               sun_probability, average, scene_center: Point3::new(0.0, 0.0, 0.0), scene_radius: 1.0 }
+    }
+
+    // First sample selects the sun or the sky and it is reused for sampling of the direction
+    fn sample_direction(&self, u1: f32, u2: f32) -> Option<(Vec3, f32)> {
+        let wi = if u1 < self.sun_probability {
+            let u1 = u1 / self.sun_probability;
+            let direction = sample_uniform_cone(u1, u2, self.cos_sun).direction;
+            Frame::from(self.sun_direction).to_world(direction).normalize()
+        } else {
+            let u1 = ((u1 - self.sun_probability) / (1.0 - self.sun_probability)).min(1.0 - f32::EPSILON);
+            let direction = sample_cos_hemisphere(u1, u2).direction;
+            Frame::from(self.up).to_world(direction).normalize()
+        };
+        let pdfw = self.pdf(wi);
+        if pdfw == 0.0 {
+            return None
+        }
+        Some((wi, pdfw))
+    }
+
+    fn pdf(&self, wi: Vec3) -> f32 {
+        let sun = if wi * self.sun_direction >= self.cos_sun { self.sun_pdf } else { 0.0 };
+        let sky = (wi * self.up).max(0.0) * std::f32::consts::FRAC_1_PI;
+        self.sun_probability * sun + (1.0 - self.sun_probability) * sky
+    }
+}
+
+impl LightInterface for SkyLight {
+    fn illuminate(&self, hit: Point3, sampler: &mut Box<dyn SamplerInterface>) -> Option<LightSample> {
+        let (u1, u2) = sampler.next_2d();
+        let (wi, pdfw) = self.sample_direction(u1, u2)?;
+        let position = hit + wi * INFINITE_LIGHT_DISTANCE;
+        let pdfa = pdfw / (INFINITE_LIGHT_DISTANCE * INFINITE_LIGHT_DISTANCE);
+        Some(LightSample { intensity: self.le(wi), position, wi, pdfa, cos_theta: 1.0 })
+    }
+
+    fn eval_sample(&self, hit: Point3, position: Point3) -> Option<LightSample> {
+        let wi = (position - hit).normalize();
+        let pdfa = self.pdf(wi) / (INFINITE_LIGHT_DISTANCE * INFINITE_LIGHT_DISTANCE);
+        Some(LightSample { intensity: self.le(wi), position, wi, pdfa, cos_theta: 1.0 })
+    }
+
+    fn intersect(&self, ray: &Ray) -> Option<Point3> {
+        Some(ray.point_at(INFINITE_LIGHT_DISTANCE))
+    }
+
+    fn pdf_li(&self, _hit: Point3, wi: Vec3) -> f32 {
+        self.pdf(wi)
+    }
+
+    fn sample_le(&self, sampler: &mut Box<dyn SamplerInterface>) -> Option<LightLeSample> {
+        let (u1, u2) = sampler.next_2d();
+        let (wi, pdf_dir) = self.sample_direction(u1, u2)?;
+        let frame = Frame::from(-wi);
+        let (u1, u2) = sampler.next_2d();
+        let (dx, dy) = sample_uniform_disk(u1, u2);
+        let disk_point = frame.to_world(Vec3::new(dx, dy, 0.0));
+        let origin = self.scene_center + (wi + disk_point) * self.scene_radius;
+        let pdf_pos = (std::f32::consts::PI * self.scene_radius * self.scene_radius).recip();
+        let ray = Ray::new(origin, -wi);
+        Some(LightLeSample { radiance: self.le(wi), ray, normal: None, pdf_pos, pdf_dir })
+    }
+
+    fn pdf_le(&self, ray: &Ray) -> (f32, f32) {
+        let pdf_pos = (std::f32::consts::PI * self.scene_radius * self.scene_radius).recip();
+        (pdf_pos, self.pdf(-ray.direction))
+    }
+
+    fn preprocess(&mut self, scene_center: Point3, scene_radius: f32) {
+        self.scene_center = scene_center;
+        self.scene_radius = scene_radius.max(1e-3);
+    }
+
+    fn is_delta_light(&self) -> bool {
+        false
+    }
+
+    // NOTE: power is calculated for scene with bounding sphere of unit radius
+    fn power(&self) -> RGB {
+        self.scale * self.average * (4.0 * std::f32::consts::PI * std::f32::consts::PI)
+    }
+
+    fn is_infinite_light(&self) -> bool {
+        true
+    }
+
+    fn le(&self, direction: Vec3) -> RGB {
+        let mut radiance = self.sky.radiance((self.world_to_light * direction).normalize());
+        if direction * self.sun_direction >= self.cos_sun {
+            radiance += self.sky.sun_radiance();
+        }
+        self.scale * radiance
+    }
+}
+
+#[derive(Debug)]
 pub enum LightType {
-    Point
+    Point,
+    Infinite,
+    Sky
 }
 
+impl LightType {
+    pub fn name(&self) -> &'static str {
+        match self {
+            LightType::Point => "point",
+            LightType::Infinite => "infinite",
+            LightType::Sky => "sky"
+        }
+    }
+}
+
+#[derive(Debug)]
 pub struct LightDescription {
     pub typ: LightType,
     pub intensity: RGB,
-    pub position: Point3
+    pub position: Point3,
+    pub scale: f32,
+    pub power: Option<f32>,
+    pub radius: f32,
+    /// Environment image of infinite light
+    pub filename: Option<String>,
+    /// Layout of environment image
+    pub mapping: EnvironmentMapping,
+    /// Light to world transformation, orients environment of infinite light
+    pub transform: Option<Transformation>,
+    /// Name of light layer (light group) that light belongs to
+    pub group: Option<String>,
+    /// Direction to the sun of sky light in light space, z axis is up
+    pub sun_direction: Vec3,
+    /// Turbidity of atmosphere of sky light, 2 is very clear sky and 10 is hazy sky
+    pub turbidity: f32
 }
 
 impl LightDescription {
-    pub fn create(&self) -> Box<dyn LightInterface> {
-        match self.typ {
-            LightType::Point => Box::new(PointLight::new(self.intensity, self.position))
+    pub fn create(&self) -> Result<Box<dyn LightInterface>, String> {
+        let light: Box<dyn LightInterface> = match self.typ {
+            LightType::Point if self.radius > 0.0 => {
+                Box::new(SphereLight::new(self.point_intensity(), self.position, self.radius))
+            }
+            LightType::Point => Box::new(PointLight::new(self.point_intensity(), self.position)),
+            LightType::Infinite => {
+                let map = match &self.filename {
+                    Some(fname) => match EnvironmentMap::load(fname, self.mapping) {
+                        Ok(map) => Some(map),
+                        Err(err) => return Err(format!("Infinite light: {} - {}", fname, err))
+                    },
+                    None => None
+                };
+                Box::new(InfiniteLight::new(self.intensity * self.scale, map, self.transform.unwrap_or_default()))
+            }
+            LightType::Sky => {
+                Box::new(SkyLight::new(self.sun_direction, self.turbidity, self.intensity * self.scale, self.transform.unwrap_or_default()))
+            }
+        };
+        Ok(light)
+    }
+
+    // NOTE: if power is specified intensity is normalized so that light emits
+    // given power, scale is applied on top of that
+    fn point_intensity(&self) -> RGB {
+        let mut intensity = self.intensity * self.scale;
+        if let Some(power) = self.power {
+            let luminance = self.intensity.luminance();
+            if luminance > 0.0 {
+                intensity = intensity * (power / (4.0 * std::f32::consts::PI * luminance));
+            }
         }
+        intensity
     }
 }
 
@@ -68,7 +684,210 @@ impl Default for LightDescription {
         Self {
             typ: LightType::Point,
             intensity: RGB::new(1.0, 1.0, 1.0),
-            position: Point3::new(0.0, 0.0, 0.0)
+            position: Point3::new(0.0, 0.0, 0.0),
+            scale: 1.0,
+            power: None,
+            radius: 0.0,
+            filename: None,
+            mapping: EnvironmentMapping::Equirect,
+            transform: None,
+            group: None,
+            sun_direction: Vec3::new(0.0, 0.0, 1.0),
+            turbidity: 3.0
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::samplers::RandomPathSampler;
+
+    fn sampler() -> Box<dyn SamplerInterface> {
+        Box::new(RandomPathSampler::new(1))
+    }
+
+    #[test]
+    fn sphere_light_sample() {
+        let desc = LightDescription {
+            intensity: RGB::new(1.0, 1.0, 1.0),
+            position: Point3::new(0.0, 0.0, 5.0),
+            radius: 0.5,
+            ..Default::default()
+        };
+        let light = desc.create().unwrap();
+        assert!(!light.is_delta_light());
+        let mut sampler = sampler();
+        let hit = Point3::new(0.0, 0.0, 0.0);
+        for _ in 0..100 {
+            let ls = light.illuminate(hit, &mut sampler).unwrap();
+            let dist = ls.position.distance(Point3::new(0.0, 0.0, 5.0));
+            assert!((dist - 0.5).abs() < 1e-3);
+            assert!(ls.wi.z > 0.0);
+            assert!(ls.pdfa > 0.0);
+            let eval = light.eval_sample(hit, ls.position).unwrap();
+            assert!((eval.pdfa - ls.pdfa).abs() < 1e-3 * ls.pdfa);
+            assert!((eval.wi - ls.wi).length() < 1e-3);
+            let position = light.intersect(&Ray::new(hit, ls.wi)).unwrap();
+            assert!(position.distance(ls.position) < 1e-3);
+        }
+        assert!(light.intersect(&Ray::new(hit, Vec3::new(0.0, 1.0, 0.0))).is_none());
+        // far side of the light is not visible
+        assert!(light.eval_sample(hit, Point3::new(0.0, 0.0, 5.5)).is_none());
+        assert!(light.illuminate(Point3::new(0.0, 0.0, 5.2), &mut sampler).is_none());
+    }
+
+    #[test]
+    fn light_sampling_pdf() {
+        let mut sampler = sampler();
+        let hit = Point3::new(0.0, 0.0, 0.0);
+        let sphere = LightDescription { position: Point3::new(0.0, 0.0, 5.0), radius: 2.0, ..Default::default() }.create().unwrap();
+        let infinite = InfiniteLight::new(RGB::new(1.0, 1.0, 1.0), None, Transformation::identity());
+        for light in [&sphere, &(Box::new(infinite) as Box<dyn LightInterface>)] {
+            for _ in 0..100 {
+                let ls = light.illuminate(hit, &mut sampler).unwrap();
+                let pdfw = ls.pdfa * hit.distance(ls.position).powi(2) / ls.cos_theta;
+                assert!((light.pdf_li(hit, ls.wi) - pdfw).abs() < 1e-3 * pdfw);
+            }
+        }
+        // density of directions integrates to one over the sphere of directions
+        let n = 100000;
+        let integral = (0..n).fold(0.0, |acc, _| {
+            let (u1, u2) = sampler.next_2d();
+            let sample = sample_uniform_sphere(u1, u2);
+            acc + sphere.pdf_li(hit, sample.direction) / sample.pdfw
+        }) / n as f32;
+        assert!((integral - 1.0).abs() < 0.05);
+        assert_eq!(sphere.pdf_li(hit, Vec3::new(0.0, 1.0, 0.0)), 0.0);
+        assert_eq!(sphere.pdf_li(Point3::new(0.0, 0.0, 5.2), Vec3::new(0.0, 0.0, 1.0)), 0.0);
+        let point = LightDescription { position: Point3::new(0.0, 0.0, 5.0), ..Default::default() }.create().unwrap();
+        assert_eq!(point.pdf_li(hit, Vec3::new(0.0, 0.0, 1.0)), 0.0);
+    }
+
+    #[test]
+    fn sky_light_sampling() {
+        let mut sampler = sampler();
+        let hit = Point3::new(0.0, 0.0, 0.0);
+        // sky is rotated so that its up direction is y axis of the world
+        let to_world = Transformation::rotate_x(-std::f32::consts::FRAC_PI_2);
+        let desc = LightDescription { typ: LightType::Sky, sun_direction: Vec3::new(0.0, -1.0, 1.0), turbidity: 3.0,
+                                      transform: Some(to_world), ..Default::default() };
+        let light = desc.create().unwrap();
+        assert!(light.is_infinite_light());
+        assert!(light.le(Vec3::new(0.0, 1.0, 0.0)).luminance() > 0.0);
+        assert_eq!(light.le(Vec3::new(0.0, -1.0, 0.0)).luminance(), 0.0);
+
+        let sun = (to_world * Vec3::new(0.0, -1.0, 1.0)).normalize();
+        let (n, mut sun_hits) = (20000, 0);
+        let mut estimate = 0.0;
+        for _ in 0..n {
+            let ls = light.illuminate(hit, &mut sampler).unwrap();
+            let pdfw = light.pdf_li(hit, ls.wi);
+            assert!((ls.pdfa * hit.distance(ls.position).powi(2) - pdfw).abs() < 1e-3 * pdfw);
+            assert!(ls.wi.y > -1e-5);
+            if ls.wi * sun > 0.9999 {
+                sun_hits += 1;
+            }
+            estimate += ls.intensity.luminance() / pdfw;
+        }
+        // sun is sampled often although it is tiny part of the sky, sampling gives back power of the light
+        assert!(sun_hits > n / 4);
+        let power = light.power().luminance() / (4.0 * std::f32::consts::PI * std::f32::consts::PI);
+        assert!((estimate / n as f32 / (4.0 * std::f32::consts::PI) - power).abs() < 0.05 * power);
+    }
+
+    #[test]
+    fn light_power() {
+        let power = 50.0;
+        for radius in [0.0, 0.3] {
+            let desc = LightDescription { intensity: RGB::new(1.0, 2.0, 3.0), power: Some(power), radius, ..Default::default() };
+            let light = desc.create().unwrap();
+            assert!((light.power().luminance() - power).abs() < 1e-3);
+        }
+    }
+
+    #[test]
+    fn point_light_power() {
+        let desc = LightDescription {
+            intensity: RGB::new(2.0, 2.0, 2.0),
+            scale: 3.0,
+            ..Default::default()
+        };
+        let sample = desc.create().unwrap().illuminate(Point3::new(1.0, 0.0, 0.0), &mut sampler()).unwrap();
+        assert_eq!(sample.intensity.r, 6.0);
+        assert_eq!(sample.intensity.g, 6.0);
+        assert_eq!(sample.intensity.b, 6.0);
+
+        let power = 100.0;
+        let desc = LightDescription {
+            intensity: RGB::new(2.0, 2.0, 2.0),
+            power: Some(power),
+            ..Default::default()
+        };
+        let sample = desc.create().unwrap().illuminate(Point3::new(1.0, 0.0, 0.0), &mut sampler()).unwrap();
+        let expected = power / (4.0 * std::f32::consts::PI);
+        assert!((sample.intensity.luminance() - expected).abs() < 1e-3);
+    }
+
+    #[test]
+    fn infinite_light_rotation() {
+        // Left half of the map (phi < PI) is red, right half is blue
+        let (red, blue) = (RGB::new(1.0, 0.0, 0.0), RGB::new(0.0, 0.0, 1.0));
+        let map = || EnvironmentMap::new(4, 2, vec![red, red, blue, blue, red, red, blue, blue], EnvironmentMapping::Equirect).unwrap();
+        let light = InfiniteLight::new(RGB::new(2.0, 2.0, 2.0), Some(map()), Transformation::identity());
+        assert_eq!(light.le(Vec3::new(0.0, 1.0, 0.0)).r, 2.0);
+        assert_eq!(light.le(Vec3::new(0.0, -1.0, 0.0)).b, 2.0);
+
+        let rotated = InfiniteLight::new(RGB::new(2.0, 2.0, 2.0), Some(map()), Transformation::rotate_z(std::f32::consts::PI));
+        assert_eq!(rotated.le(Vec3::new(0.0, 1.0, 0.0)).b, 2.0);
+        assert_eq!(rotated.le(Vec3::new(0.0, -1.0, 0.0)).r, 2.0);
+
+        // upper hemisphere is the inner diamond of equal-area image
+        let pixels = (0..16).map(|i| if [5, 6, 9, 10].contains(&i) { red } else { blue }).collect();
+        let map = EnvironmentMap::new(4, 4, pixels, EnvironmentMapping::EqualArea).unwrap();
+        assert_eq!(map.lookup(Vec3::new(0.0, 0.0, 1.0)).r, 1.0);
+        assert_eq!(map.lookup(Vec3::new(0.0, 0.0, -1.0)).b, 1.0);
+        assert_eq!(map.lookup(Vec3::new(1.0, 0.0, 0.01).normalize()).b, 1.0);
+        assert!((map.average().b - 0.75).abs() < 1e-6);
+        assert!(EnvironmentMap::new(4, 2, vec![red; 8], EnvironmentMapping::EqualArea).is_err());
+
+        let mut sampler = sampler();
+        let ls = rotated.illuminate(Point3::new(0.0, 0.0, 0.0), &mut sampler).unwrap();
+        let dist = ls.position.distance(Point3::new(0.0, 0.0, 0.0));
+        let pdfw = ls.pdfa * dist * dist / ls.cos_theta;
+        assert!((pdfw - 0.25 * std::f32::consts::FRAC_1_PI).abs() < 1e-4);
+        assert!(rotated.is_infinite_light());
+    }
+
+    #[test]
+    fn emitted_ray_sampling() {
+        let mut sampler = sampler();
+        let point = PointLight::new(RGB::new(1.0, 1.0, 1.0), Point3::new(1.0, 2.0, 3.0));
+        let sample = point.sample_le(&mut sampler).unwrap();
+        assert_eq!(sample.ray.origin, Point3::new(1.0, 2.0, 3.0));
+        assert_eq!(point.pdf_le(&sample.ray).1, sample.pdf_dir);
+
+        let center = Point3::new(0.0, 0.0, 5.0);
+        let sphere = SphereLight::new(RGB::new(1.0, 1.0, 1.0), center, 0.5);
+        for _ in 0..100 {
+            let sample = sphere.sample_le(&mut sampler).unwrap();
+            assert!((sample.ray.origin.distance(center) - 0.5).abs() < 1e-4);
+            assert!(sample.normal.unwrap() * sample.ray.direction >= 0.0);
+            let (pdf_pos, pdf_dir) = sphere.pdf_le(&sample.ray);
+            assert!((pdf_pos - sample.pdf_pos).abs() < 1e-4 && (pdf_dir - sample.pdf_dir).abs() < 1e-4);
+        }
+
+        let mut infinite = InfiniteLight::new(RGB::new(1.0, 1.0, 1.0), None, Transformation::identity());
+        infinite.preprocess(center, 2.0);
+        for _ in 0..100 {
+            let sample = infinite.sample_le(&mut sampler).unwrap();
+            // ray starts outside of bounding sphere and passes through it
+            let to_center = center - sample.ray.origin;
+            let along = to_center * sample.ray.direction;
+            assert!((along - 2.0).abs() < 1e-3);
+            assert!((to_center.length_sqr() - along * along).sqrt() <= 2.0 + 1e-3);
+            assert_eq!(infinite.pdf_le(&sample.ray), (sample.pdf_pos, sample.pdf_dir));
         }
     }
 }
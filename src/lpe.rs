@@ -0,0 +1,396 @@
+//! Light path expressions
+//!
+//! Regular expressions over path vertices used to route light contributions to separate images.
+//! Supported symbols are `C` (camera), `L` (light), `D`, `G`, `S` (diffuse, glossy and specular
+//! scattering), `R`, `T` (reflection, transmission) and `.` (any vertex). Scattering constraints can
+//! be combined with `<RD>`, `<T.>`..., sets with `[DG]` or `[^S]`. Operators are `*`, `+`, `?`,
+//! `|` and parenthesis. Example: `CD*L` - diffuse only paths, `C<RS>.*L` - reflections.
+
+use crate::materials::ScatteringType;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LpeEvent {
+    Camera,
+    Light,
+    Scatter { transmission: bool, typ: ScatteringType },
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Matcher {
+    camera: bool,
+    light: bool,
+    reflection: bool,
+    transmission: bool,
+    diffuse: bool,
+    glossy: bool,
+    specular: bool,
+}
+
+impl Matcher {
+    fn none() -> Self {
+        Self { camera: false, light: false, reflection: false, transmission: false,
+               diffuse: false, glossy: false, specular: false }
+    }
+
+    fn scatter() -> Self {
+        Self { reflection: true, transmission: true, diffuse: true, glossy: true, specular: true, ..Self::none() }
+    }
+
+    fn any() -> Self {
+        Self { camera: true, light: true, ..Self::scatter() }
+    }
+
+    fn union(self, other: Self) -> Self {
+        Self {
+            camera: self.camera || other.camera,
+            light: self.light || other.light,
+            reflection: self.reflection || other.reflection,
+            transmission: self.transmission || other.transmission,
+            diffuse: self.diffuse || other.diffuse,
+            glossy: self.glossy || other.glossy,
+            specular: self.specular || other.specular,
+        }
+    }
+
+    fn matches(&self, event: LpeEvent) -> bool {
+        match event {
+            LpeEvent::Camera => self.camera,
+            LpeEvent::Light => self.light,
+            LpeEvent::Scatter { transmission, typ } => {
+                let direction = if transmission { self.transmission } else { self.reflection };
+                let scattering = match typ {
+                    ScatteringType::Diffuse => self.diffuse,
+                    ScatteringType::Glossy => self.glossy,
+                    ScatteringType::Specular => self.specular,
+                };
+                direction && scattering
+            }
+        }
+    }
+}
+
+// Set of all events that are not matched by matcher
+fn complement(matcher: Matcher) -> Vec<Matcher> {
+    let mut result = Vec::new();
+    if !matcher.camera {
+        result.push(Matcher { camera: true, ..Matcher::none() });
+    }
+    if !matcher.light {
+        result.push(Matcher { light: true, ..Matcher::none() });
+    }
+    for (reflection, transmission) in [(true, false), (false, true)] {
+        for typ in [ScatteringType::Diffuse, ScatteringType::Glossy, ScatteringType::Specular] {
+            let event = LpeEvent::Scatter { transmission, typ };
+            if !matcher.matches(event) {
+                result.push(Matcher {
+                    reflection, transmission,
+                    diffuse: typ == ScatteringType::Diffuse,
+                    glossy: typ == ScatteringType::Glossy,
+                    specular: typ == ScatteringType::Specular,
+                    ..Matcher::none()
+                });
+            }
+        }
+    }
+    result
+}
+
+#[derive(Debug, Clone)]
+enum Node {
+    Atom(Vec<Matcher>),
+    Concat(Vec<Node>),
+    Alternate(Vec<Node>),
+    Star(Box<Node>),
+    Plus(Box<Node>),
+    Optional(Box<Node>),
+}
+
+struct Parser<'a> {
+    chars: std::iter::Peekable<std::str::Chars<'a>>,
+}
+
+impl Parser<'_> {
+    fn parse_alternate(&mut self) -> Result<Node, String> {
+        let mut nodes = vec![self.parse_concat()?];
+        while let Some('|') = self.chars.peek() {
+            self.chars.next();
+            nodes.push(self.parse_concat()?);
+        }
+        if nodes.len() == 1 {
+            return Ok(nodes.remove(0));
+        }
+        Ok(Node::Alternate(nodes))
+    }
+
+    fn parse_concat(&mut self) -> Result<Node, String> {
+        let mut nodes = Vec::new();
+        while let Some(&c) = self.chars.peek() {
+            if c == '|' || c == ')' {
+                break;
+            }
+            let mut node = self.parse_atom()?;
+            while let Some(&op) = self.chars.peek() {
+                node = match op {
+                    '*' => Node::Star(Box::new(node)),
+                    '+' => Node::Plus(Box::new(node)),
+                    '?' => Node::Optional(Box::new(node)),
+                    _ => break
+                };
+                self.chars.next();
+            }
+            nodes.push(node);
+        }
+        Ok(Node::Concat(nodes))
+    }
+
+    fn parse_atom(&mut self) -> Result<Node, String> {
+        let c = match self.chars.next() {
+            Some(c) => c,
+            None => return Err("Unexpected end of expression!".to_string())
+        };
+        match c {
+            '(' => {
+                let node = self.parse_alternate()?;
+                match self.chars.next() {
+                    Some(')') => Ok(node),
+                    _ => Err("Missing ')'!".to_string())
+                }
+            }
+            '<' => {
+                let mut matcher = Matcher::scatter();
+                loop {
+                    match self.chars.next() {
+                        Some('>') => break,
+                        Some('.') => {},
+                        Some('R') => matcher.transmission = false,
+                        Some('T') => matcher.reflection = false,
+                        Some(c @ ('D' | 'G' | 'S')) => {
+                            matcher.diffuse = c == 'D';
+                            matcher.glossy = c == 'G';
+                            matcher.specular = c == 'S';
+                        }
+                        Some(c) => return Err(format!("Unsupported symbol '{}' inside '<>'!", c)),
+                        None => return Err("Missing '>'!".to_string())
+                    }
+                }
+                Ok(Node::Atom(vec![matcher]))
+            }
+            '[' => {
+                let negate = if let Some('^') = self.chars.peek() {
+                    self.chars.next();
+                    true
+                } else {
+                    false
+                };
+                let mut matcher = Matcher::none();
+                loop {
+                    match self.chars.next() {
+                        Some(']') => break,
+                        Some(c) => matcher = matcher.union(symbol_matcher(c)?),
+                        None => return Err("Missing ']'!".to_string())
+                    }
+                }
+                if negate {
+                    Ok(Node::Atom(complement(matcher)))
+                } else {
+                    Ok(Node::Atom(vec![matcher]))
+                }
+            }
+            c => Ok(Node::Atom(vec![symbol_matcher(c)?]))
+        }
+    }
+}
+
+fn symbol_matcher(c: char) -> Result<Matcher, String> {
+    let matcher = match c {
+        'C' => Matcher { camera: true, ..Matcher::none() },
+        'L' => Matcher { light: true, ..Matcher::none() },
+        'D' => Matcher { glossy: false, specular: false, ..Matcher::scatter() },
+        'G' => Matcher { diffuse: false, specular: false, ..Matcher::scatter() },
+        'S' => Matcher { diffuse: false, glossy: false, ..Matcher::scatter() },
+        'R' => Matcher { transmission: false, ..Matcher::scatter() },
+        'T' => Matcher { reflection: false, ..Matcher::scatter() },
+        '.' => Matcher::any(),
+        _ => return Err(format!("Unsupported symbol '{}'!", c))
+    };
+    Ok(matcher)
+}
+
+#[derive(Debug, Clone)]
+enum State {
+    Match(Vec<Matcher>, usize),
+    Split(usize, usize),
+    Accept,
+}
+
+/// Active states of light path expression automaton.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LpeState {
+    active: Vec<bool>,
+}
+
+impl LpeState {
+    pub fn is_dead(&self) -> bool {
+        !self.active.iter().any(|a| *a)
+    }
+}
+
+/// Compiled light path expression (Thompson NFA).
+#[derive(Debug, Clone)]
+pub struct Lpe {
+    states: Vec<State>,
+    start: usize,
+}
+
+impl Lpe {
+    pub fn parse(expression: &str) -> Result<Lpe, String> {
+        let mut parser = Parser { chars: expression.chars().peekable() };
+        let node = parser.parse_alternate()?;
+        if parser.chars.next().is_some() {
+            return Err(format!("Unexpected ')' in light path expression {}", expression));
+        }
+        let mut lpe = Lpe { states: vec![State::Accept], start: 0 };
+        lpe.start = lpe.compile(&node, 0);
+        Ok(lpe)
+    }
+
+    // Compile node so that it continues to state next, returns entry state
+    fn compile(&mut self, node: &Node, next: usize) -> usize {
+        match node {
+            Node::Atom(matchers) => self.push(State::Match(matchers.clone(), next)),
+            Node::Concat(nodes) => {
+                let mut entry = next;
+                for node in nodes.iter().rev() {
+                    entry = self.compile(node, entry);
+                }
+                entry
+            }
+            Node::Alternate(nodes) => {
+                let mut entry = self.compile(&nodes[nodes.len() - 1], next);
+                for node in nodes[..nodes.len() - 1].iter().rev() {
+                    let branch = self.compile(node, next);
+                    entry = self.push(State::Split(branch, entry));
+                }
+                entry
+            }
+            Node::Star(node) => {
+                let split = self.push(State::Split(0, next));
+                let body = self.compile(node, split);
+                self.states[split] = State::Split(body, next);
+                split
+            }
+            Node::Plus(node) => {
+                let split = self.push(State::Split(0, next));
+                let body = self.compile(node, split);
+                self.states[split] = State::Split(body, next);
+                body
+            }
+            Node::Optional(node) => {
+                let body = self.compile(node, next);
+                self.push(State::Split(body, next))
+            }
+        }
+    }
+
+    fn push(&mut self, state: State) -> usize {
+        self.states.push(state);
+        self.states.len() - 1
+    }
+
+    fn add_state(&self, active: &mut [bool], index: usize) {
+        if active[index] {
+            return;
+        }
+        active[index] = true;
+        if let State::Split(s1, s2) = self.states[index] {
+            self.add_state(active, s1);
+            self.add_state(active, s2);
+        }
+    }
+
+    /// State before any path vertex was processed
+    pub fn start(&self) -> LpeState {
+        let mut active = vec![false; self.states.len()];
+        self.add_state(&mut active, self.start);
+        LpeState { active }
+    }
+
+    pub fn advance(&self, state: &LpeState, event: LpeEvent) -> LpeState {
+        let mut active = vec![false; self.states.len()];
+        for (index, is_active) in state.active.iter().enumerate() {
+            if !is_active {
+                continue;
+            }
+            if let State::Match(matchers, next) = &self.states[index] {
+                if matchers.iter().any(|m| m.matches(event)) {
+                    self.add_state(&mut active, *next);
+                }
+            }
+        }
+        LpeState { active }
+    }
+
+    pub fn is_accepting(&self, state: &LpeState) -> bool {
+        state.active[0]
+    }
+
+    pub fn matches(&self, events: &[LpeEvent]) -> bool {
+        let mut state = self.start();
+        for event in events {
+            state = self.advance(&state, *event);
+        }
+        self.is_accepting(&state)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DIFFUSE: LpeEvent = LpeEvent::Scatter { transmission: false, typ: ScatteringType::Diffuse };
+    const SPECULAR: LpeEvent = LpeEvent::Scatter { transmission: false, typ: ScatteringType::Specular };
+    const SPECULAR_T: LpeEvent = LpeEvent::Scatter { transmission: true, typ: ScatteringType::Specular };
+
+    #[test]
+    fn diffuse_only_paths() {
+        let lpe = Lpe::parse("CD*L").unwrap();
+        assert!(lpe.matches(&[LpeEvent::Camera, LpeEvent::Light]));
+        assert!(lpe.matches(&[LpeEvent::Camera, DIFFUSE, DIFFUSE, LpeEvent::Light]));
+        assert!(!lpe.matches(&[LpeEvent::Camera, DIFFUSE, SPECULAR, LpeEvent::Light]));
+        assert!(!lpe.matches(&[LpeEvent::Camera, DIFFUSE]));
+    }
+
+    #[test]
+    fn reflection_paths() {
+        let lpe = Lpe::parse("C<RS>.*L").unwrap();
+        assert!(lpe.matches(&[LpeEvent::Camera, SPECULAR, DIFFUSE, LpeEvent::Light]));
+        assert!(!lpe.matches(&[LpeEvent::Camera, SPECULAR_T, LpeEvent::Light]));
+        assert!(!lpe.matches(&[LpeEvent::Camera, DIFFUSE, SPECULAR, LpeEvent::Light]));
+
+        let lpe = Lpe::parse("C<S>.*L").unwrap();
+        assert!(lpe.matches(&[LpeEvent::Camera, SPECULAR_T, LpeEvent::Light]));
+    }
+
+    #[test]
+    fn sets_and_alternation() {
+        let lpe = Lpe::parse("C[^S]+L|CSL").unwrap();
+        assert!(lpe.matches(&[LpeEvent::Camera, DIFFUSE, LpeEvent::Light]));
+        assert!(lpe.matches(&[LpeEvent::Camera, SPECULAR, LpeEvent::Light]));
+        assert!(!lpe.matches(&[LpeEvent::Camera, LpeEvent::Light]));
+        assert!(!lpe.matches(&[LpeEvent::Camera, DIFFUSE, SPECULAR, LpeEvent::Light]));
+
+        let lpe = Lpe::parse("C(D|S)?L").unwrap();
+        assert!(lpe.matches(&[LpeEvent::Camera, LpeEvent::Light]));
+        assert!(lpe.matches(&[LpeEvent::Camera, SPECULAR, LpeEvent::Light]));
+        assert!(!lpe.matches(&[LpeEvent::Camera, SPECULAR, DIFFUSE, LpeEvent::Light]));
+    }
+
+    #[test]
+    fn invalid_expressions() {
+        assert!(Lpe::parse("C(DL").is_err());
+        assert!(Lpe::parse("CX").is_err());
+        assert!(Lpe::parse("C<D").is_err());
+        assert!(Lpe::parse("CD)L").is_err());
+    }
+}
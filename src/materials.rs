@@ -1,8 +1,13 @@
+use std::ops::Deref;
+use std::sync::Arc;
+
 use crate::color::RGB;
+use crate::hair::{HairMaterial, HairProperties};
+use crate::textures::{FloatTexture, RGBTexture, TextureContext};
 use crate::vec::Vec3;
 use crate::vec::Normal;
 use crate::frame::Frame;
-use crate::samplings::sample_cos_hemisphere;
+use crate::samplings::{sample_cos_hemisphere, sample_uniform_disk};
 use crate::samplers::SamplerInterface;
 
 pub struct BSDFEvalSample {
@@ -16,7 +21,14 @@ pub struct BSDFSample {
     pub pdfw: f32
 }
 
-pub trait BSDFInterface {
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ScatteringType {
+    Diffuse,
+    Glossy,
+    Specular
+}
+
+pub trait BSDFInterface: Send + Sync {
     fn eval(&self, wo: Vec3, normal: Normal, wi: Vec3) -> Option<BSDFEvalSample>;
     fn sample(&self, wo: Vec3, normal: Normal, sampler: &mut Box<dyn SamplerInterface>) -> Option<BSDFSample>;
     fn is_emissive(&self) -> bool {
@@ -25,6 +37,21 @@ pub trait BSDFInterface {
     fn emssion(&self, _wo: Vec3, _normal: Normal, _back_side: bool) -> RGB {
         RGB::zero()
     }
+    fn scattering_type(&self) -> ScatteringType {
+        ScatteringType::Diffuse
+    }
+    /// Color of the material used by albedo output, white for materials without reflectance
+    fn albedo(&self) -> RGB {
+        RGB::new(1.0, 1.0, 1.0)
+    }
+    /// Fraction of light that shadow ray carries straight through the surface, None for opaque materials
+    fn transmittance(&self, _wo: Vec3, _normal: Normal) -> Option<RGB> {
+        None
+    }
+    /// Surface only marks boundary between media and it isn't visible in auxiliary outputs
+    fn is_interface(&self) -> bool {
+        false
+    }
 }
 
 pub struct MatteMaterial {
@@ -61,6 +88,10 @@ impl BSDFInterface for MatteMaterial {
         }
         Some(BSDFSample{wi, color, pdfw})
     }
+
+    fn albedo(&self) -> RGB {
+        self.reflectance
+    }
 }
 
 pub struct EmissiveMatteMaterial {
@@ -108,26 +139,755 @@ impl BSDFInterface for EmissiveMatteMaterial {
         }
         self.emission
     }
+
+    fn albedo(&self) -> RGB {
+        self.reflectance
+    }
+}
+
+/// Wrapper of material that emits from both sides of the surface, scattering is unchanged.
+pub struct TwoSidedMaterial {
+    material: Box<dyn BSDFInterface>
+}
+
+impl TwoSidedMaterial {
+    pub fn new(material: Box<dyn BSDFInterface>) -> TwoSidedMaterial {
+        TwoSidedMaterial {material}
+    }
+}
+
+impl BSDFInterface for TwoSidedMaterial {
+    fn eval(&self, wo: Vec3, normal: Normal, wi: Vec3) -> Option<BSDFEvalSample> {
+        self.material.eval(wo, normal, wi)
+    }
+
+    fn sample(&self, wo: Vec3, normal: Normal, sampler: &mut Box<dyn SamplerInterface>) -> Option<BSDFSample> {
+        self.material.sample(wo, normal, sampler)
+    }
+
+    fn is_emissive(&self) -> bool {
+        self.material.is_emissive()
+    }
+
+    fn emssion(&self, wo: Vec3, normal: Normal, _back_side: bool) -> RGB {
+        self.material.emssion(wo, normal, false)
+    }
+
+    fn scattering_type(&self) -> ScatteringType {
+        self.material.scattering_type()
+    }
+
+    fn albedo(&self) -> RGB {
+        self.material.albedo()
+    }
+
+    fn transmittance(&self, wo: Vec3, normal: Normal) -> Option<RGB> {
+        self.material.transmittance(wo, normal)
+    }
+}
+
+/// Lambertian reflection and transmission (e.g. leaves, paper, lampshades), one side of
+/// the surface is lit through the other.
+pub struct DiffuseTransmissionMaterial {
+    reflectance: RGB,
+    transmittance: RGB
+}
+
+impl DiffuseTransmissionMaterial {
+    pub fn new(reflectance: RGB, transmittance: RGB) -> DiffuseTransmissionMaterial {
+        DiffuseTransmissionMaterial {reflectance, transmittance}
+    }
+
+    // Probability of sampling reflection hemisphere
+    fn reflection_probability(&self) -> f32 {
+        let r = self.reflectance.r.max(self.reflectance.g).max(self.reflectance.b);
+        let t = self.transmittance.r.max(self.transmittance.g).max(self.transmittance.b);
+        if r + t == 0.0 {
+            return 0.0
+        }
+        r / (r + t)
+    }
+}
+
+impl BSDFInterface for DiffuseTransmissionMaterial {
+    fn eval(&self, wo: Vec3, normal: Normal, wi: Vec3) -> Option<BSDFEvalSample> {
+        let pr = self.reflection_probability();
+        let (color, probability) = if (normal * wi) * (normal * wo) > 0.0 {
+            (self.reflectance, pr)
+        } else {
+            (self.transmittance, 1.0 - pr)
+        };
+        let pdfw = probability * (normal * wi).abs() * std::f32::consts::FRAC_1_PI;
+        if pdfw == 0.0 {
+            return None
+        }
+        Some(BSDFEvalSample{color: color * std::f32::consts::FRAC_1_PI, pdfw})
+    }
+
+    fn sample(&self, wo: Vec3, normal: Normal, sampler: &mut Box<dyn SamplerInterface>) -> Option<BSDFSample> {
+        let pr = self.reflection_probability();
+        let u = sampler.next_1d();
+        let (u1, u2) = sampler.next_2d();
+        let sample_direction = sample_cos_hemisphere(u1, u2);
+        // Hemisphere of wo is reflection, other one is transmission
+        let side = if normal * wo < 0.0 { -normal } else { normal };
+        let (side, color, probability) = if u < pr {
+            (side, self.reflectance, pr)
+        } else {
+            (-side, self.transmittance, 1.0 - pr)
+        };
+        let wi = Frame::from(side).to_world(sample_direction.direction).normalize();
+        let pdfw = probability * sample_direction.pdfw;
+        if pdfw == 0.0 {
+            return None
+        }
+        Some(BSDFSample{wi, color: color * std::f32::consts::FRAC_1_PI, pdfw})
+    }
+
+    fn albedo(&self) -> RGB {
+        self.reflectance + self.transmittance
+    }
+}
+
+/// Invisible surface that only marks boundary between media, rays pass through it unchanged.
+pub struct InterfaceMaterial;
+
+impl BSDFInterface for InterfaceMaterial {
+    fn eval(&self, _wo: Vec3, _normal: Normal, _wi: Vec3) -> Option<BSDFEvalSample> {
+        None
+    }
+
+    fn sample(&self, wo: Vec3, normal: Normal, _sampler: &mut Box<dyn SamplerInterface>) -> Option<BSDFSample> {
+        let cos_theta = (normal * wo).abs();
+        if cos_theta == 0.0 {
+            return None
+        }
+        Some(BSDFSample { wi: -wo, color: RGB::new(1.0, 1.0, 1.0) * cos_theta.recip(), pdfw: 1.0 })
+    }
+
+    fn scattering_type(&self) -> ScatteringType {
+        ScatteringType::Specular
+    }
+
+    fn transmittance(&self, _wo: Vec3, _normal: Normal) -> Option<RGB> {
+        Some(RGB::new(1.0, 1.0, 1.0))
+    }
+
+    fn is_interface(&self) -> bool {
+        true
+    }
+}
+
+/// Unpolarized Fresnel reflectance of dielectric interface, `eta` is relative index of refraction.
+pub fn fr_dielectric(cos_theta_i: f32, eta: f32) -> f32 {
+    let (cos_theta_i, eta) = if cos_theta_i < 0.0 { (-cos_theta_i, eta.recip()) } else { (cos_theta_i, eta) };
+    let cos_theta_i = cos_theta_i.min(1.0);
+    let sin2_theta_t = (1.0 - cos_theta_i * cos_theta_i) / (eta * eta);
+    if sin2_theta_t >= 1.0 {
+        return 1.0;
+    }
+    let cos_theta_t = (1.0 - sin2_theta_t).max(0.0).sqrt();
+    let r_parl = (eta * cos_theta_i - cos_theta_t) / (eta * cos_theta_i + cos_theta_t);
+    let r_perp = (cos_theta_i - eta * cos_theta_t) / (cos_theta_i + eta * cos_theta_t);
+    (r_parl * r_parl + r_perp * r_perp) * 0.5
+}
+
+/// Trowbridge-Reitz (GGX) distribution of microfacet normals, directions are in local frame
+/// where z is normal. Alpha is given separately in x and y direction of the frame (anisotropy).
+#[derive(Debug, Clone, Copy)]
+pub struct TrowbridgeReitz {
+    alpha_x: f32,
+    alpha_y: f32
+}
+
+impl TrowbridgeReitz {
+    pub fn new(alpha_x: f32, alpha_y: f32) -> Self {
+        // NOTE: very small alpha would overflow in D, such surface is rendered as smooth anyway
+        Self { alpha_x: alpha_x.max(1e-4), alpha_y: alpha_y.max(1e-4) }
+    }
+
+    /// Surfaces with smaller alpha should be sampled as perfectly specular
+    pub fn is_smooth(alpha_x: f32, alpha_y: f32) -> bool {
+        alpha_x.max(alpha_y) < 1e-3
+    }
+
+    // Squared cosine and sine of azimuth weighted by alphas, e.g. alpha of projected roughness
+    fn alpha2(&self, w: Vec3) -> (f32, f32) {
+        let sin2_theta = w.x * w.x + w.y * w.y;
+        if sin2_theta == 0.0 {
+            return (1.0, 0.0)
+        }
+        (w.x * w.x / sin2_theta, w.y * w.y / sin2_theta)
+    }
+
+    /// Density of microfacet normals wm
+    pub fn d(&self, wm: Vec3) -> f32 {
+        let cos2_theta = wm.z * wm.z;
+        let tan2_theta = (wm.x * wm.x + wm.y * wm.y) / cos2_theta;
+        if !tan2_theta.is_finite() {
+            return 0.0
+        }
+        let (cos2_phi, sin2_phi) = self.alpha2(wm);
+        let e = tan2_theta * (cos2_phi / (self.alpha_x * self.alpha_x) + sin2_phi / (self.alpha_y * self.alpha_y));
+        std::f32::consts::FRAC_1_PI / (self.alpha_x * self.alpha_y * cos2_theta * cos2_theta * (1.0 + e) * (1.0 + e))
+    }
+
+    /// Smith auxiliary function, masked area of microfacets per visible area
+    pub fn lambda(&self, w: Vec3) -> f32 {
+        let tan2_theta = (w.x * w.x + w.y * w.y) / (w.z * w.z);
+        if !tan2_theta.is_finite() {
+            return 0.0
+        }
+        let (cos2_phi, sin2_phi) = self.alpha2(w);
+        let alpha2 = cos2_phi * self.alpha_x * self.alpha_x + sin2_phi * self.alpha_y * self.alpha_y;
+        ((1.0 + alpha2 * tan2_theta).sqrt() - 1.0) * 0.5
+    }
+
+    /// Smith masking of direction w
+    pub fn g1(&self, w: Vec3) -> f32 {
+        1.0 / (1.0 + self.lambda(w))
+    }
+
+    /// Height correlated Smith masking and shadowing
+    pub fn g(&self, wo: Vec3, wi: Vec3) -> f32 {
+        1.0 / (1.0 + self.lambda(wo) + self.lambda(wi))
+    }
+
+    /// Density of microfacet normals that are visible from direction w
+    pub fn d_visible(&self, w: Vec3, wm: Vec3) -> f32 {
+        self.g1(w) / w.z.abs() * self.d(wm) * (w * wm).abs()
+    }
+
+    /// Visible normal sampled from direction w (Heitz 2018), w is in upper hemisphere.
+    pub fn sample_wm(&self, w: Vec3, u1: f32, u2: f32) -> Vec3 {
+        let wh = Vec3::new(self.alpha_x * w.x, self.alpha_y * w.y, w.z).normalize();
+        let t1 = if wh.z < 0.99999 {
+            Vec3::new(0.0, 0.0, 1.0).cross(wh).normalize()
+        } else {
+            Vec3::new(1.0, 0.0, 0.0)
+        };
+        let t2 = wh.cross(t1);
+        let (px, py) = sample_uniform_disk(u1, u2);
+        // NOTE: disk is warped to projection of visible hemisphere
+        let h = (1.0 - px * px).sqrt();
+        let s = (1.0 + wh.z) * 0.5;
+        let py = (1.0 - s) * h + s * py;
+        let pz = (1.0 - px * px - py * py).max(0.0).sqrt();
+        let nh = t1 * px + t2 * py + wh * pz;
+        Vec3::new(self.alpha_x * nh.x, self.alpha_y * nh.y, nh.z.max(1e-6)).normalize()
+    }
+}
+
+/// Metal with rough surface, microfacets are perfect mirrors distributed by Trowbridge-Reitz distribution.
+/// NOTE: hits don't have tangents (dpdu), so anisotropy is oriented by tangent of Frame::from(normal).
+/// That tangent depends only on direction of the normal, it is same on flat surface but it doesn't
+/// follow uv parametrization and it turns abruptly on curved surfaces where normal crosses z = 0.
+pub struct RoughConductorMaterial {
+    eta: RGB,
+    k: RGB,
+    distribution: TrowbridgeReitz
+}
+
+impl RoughConductorMaterial {
+    pub fn new(eta: RGB, k: RGB, distribution: TrowbridgeReitz) -> RoughConductorMaterial {
+        RoughConductorMaterial {eta, k, distribution}
+    }
+
+    // BSDF value and pdf of local directions, wo is in upper hemisphere
+    fn eval_local(&self, wo: Vec3, wi: Vec3) -> Option<BSDFEvalSample> {
+        if wi.z <= 0.0 || wo.z <= 0.0 {
+            return None
+        }
+        let wm = wo + wi;
+        if wm.length_sqr() == 0.0 {
+            return None
+        }
+        let wm = wm.normalize();
+        let fresnel = fr_conductor((wo * wm).abs(), self.eta, self.k);
+        let d = self.distribution.d(wm);
+        let color = fresnel * (d * self.distribution.g(wo, wi) / (4.0 * wi.z * wo.z));
+        let pdfw = self.distribution.d_visible(wo, wm) / (4.0 * (wo * wm).abs());
+        if pdfw == 0.0 || !pdfw.is_finite() {
+            return None
+        }
+        Some(BSDFEvalSample{color, pdfw})
+    }
+}
+
+impl BSDFInterface for RoughConductorMaterial {
+    fn eval(&self, wo: Vec3, normal: Normal, wi: Vec3) -> Option<BSDFEvalSample> {
+        // NOTE: metal reflects on both sides of surface
+        let normal = if normal * wo < 0.0 { -normal } else { normal };
+        let frame = Frame::from(normal);
+        self.eval_local(frame.to_local(wo), frame.to_local(wi))
+    }
+
+    fn sample(&self, wo: Vec3, normal: Normal, sampler: &mut Box<dyn SamplerInterface>) -> Option<BSDFSample> {
+        let normal = if normal * wo < 0.0 { -normal } else { normal };
+        let frame = Frame::from(normal);
+        let wo_local = frame.to_local(wo);
+        if wo_local.z == 0.0 {
+            return None
+        }
+        let (u1, u2) = sampler.next_2d();
+        let wm = self.distribution.sample_wm(wo_local, u1, u2);
+        let wi_local = (-wo_local + wm * (2.0 * (wo_local * wm))).normalize();
+        let eval = self.eval_local(wo_local, wi_local)?;
+        Some(BSDFSample{wi: frame.to_world(wi_local), color: eval.color, pdfw: eval.pdfw})
+    }
+
+    fn scattering_type(&self) -> ScatteringType {
+        ScatteringType::Glossy
+    }
+
+    fn albedo(&self) -> RGB {
+        fr_conductor(1.0, self.eta, self.k)
+    }
+}
+
+/// Direction refracted through interface with relative index of refraction `eta`, normal can be
+/// on any side of the surface. None is returned for total internal reflection.
+pub fn refract(wo: Vec3, normal: Normal, eta: f32) -> Option<Vec3> {
+    let cos_theta_i = normal * wo;
+    let (normal, cos_theta_i, eta) = if cos_theta_i < 0.0 {
+        (-normal, -cos_theta_i, eta.recip())
+    } else {
+        (normal, cos_theta_i, eta)
+    };
+    let sin2_theta_t = (1.0 - cos_theta_i * cos_theta_i).max(0.0) / (eta * eta);
+    if sin2_theta_t >= 1.0 {
+        return None
+    }
+    let cos_theta_t = (1.0 - sin2_theta_t).sqrt();
+    Some((-wo * eta.recip() + Vec3::from(normal) * (cos_theta_i / eta - cos_theta_t)).normalize())
+}
+
+/// Index of refraction of glasses that pbrt has as named spectra (e.g. glass-BK7), values are at 587nm.
+pub fn glass_ior(name: &str) -> Option<f32> {
+    match name {
+        "glass-BK7" => Some(1.5168),
+        "glass-BAF10" => Some(1.6700),
+        "glass-FK51A" => Some(1.4866),
+        "glass-LASF9" => Some(1.8503),
+        "glass-F5" => Some(1.6034),
+        "glass-F10" => Some(1.7283),
+        "glass-F11" => Some(1.7847),
+        _ => None
+    }
+}
+
+/// Smooth interface between two dielectrics (e.g. glass or water), light is reflected or refracted
+/// with probability given by Fresnel reflectance. Radiance isn't scaled by eta^2 when refracted,
+/// the scale cancels out when light leaves closed object.
+pub struct DielectricMaterial {
+    eta: f32
+}
+
+impl DielectricMaterial {
+    pub fn new(eta: f32) -> DielectricMaterial {
+        DielectricMaterial {eta}
+    }
+}
+
+impl BSDFInterface for DielectricMaterial {
+    fn eval(&self, _wo: Vec3, _normal: Normal, _wi: Vec3) -> Option<BSDFEvalSample> {
+        None
+    }
+
+    fn sample(&self, wo: Vec3, normal: Normal, sampler: &mut Box<dyn SamplerInterface>) -> Option<BSDFSample> {
+        let cos_theta = normal * wo;
+        if cos_theta == 0.0 {
+            return None
+        }
+        let r = fr_dielectric(cos_theta, self.eta);
+        let u = sampler.next_1d();
+        let refracted = if u < r { None } else { refract(wo, normal, self.eta) };
+        match refracted {
+            Some(wi) => {
+                let t = 1.0 - r;
+                Some(BSDFSample{wi, color: RGB::new(t, t, t) * (normal * wi).abs().recip(), pdfw: t})
+            }
+            None => {
+                let wi = (-wo + Vec3::from(normal) * (2.0 * cos_theta)).normalize();
+                Some(BSDFSample{wi, color: RGB::new(r, r, r) * cos_theta.abs().recip(), pdfw: r})
+            }
+        }
+    }
+
+    fn scattering_type(&self) -> ScatteringType {
+        ScatteringType::Specular
+    }
+
+    // NOTE: refraction is ignored, shadow ray continues in its direction
+    fn transmittance(&self, wo: Vec3, normal: Normal) -> Option<RGB> {
+        let t = 1.0 - fr_dielectric(normal * wo, self.eta);
+        Some(RGB::new(t, t, t))
+    }
+}
+
+/// Rough interface between two dielectrics, microfacets distributed by Trowbridge-Reitz distribution
+/// reflect or refract light with probability given by their Fresnel reflectance. Radiance isn't scaled
+/// by eta^2 when refracted same as for smooth dielectric. Anisotropy is oriented as for RoughConductorMaterial.
+pub struct RoughDielectricMaterial {
+    eta: f32,
+    distribution: TrowbridgeReitz
+}
+
+impl RoughDielectricMaterial {
+    pub fn new(eta: f32, distribution: TrowbridgeReitz) -> RoughDielectricMaterial {
+        RoughDielectricMaterial {eta, distribution}
+    }
+
+    // Microfacet normal (on side of the normal) that reflects or refracts wo to wi and relative
+    // index of refraction of the path, 1.0 for reflection.
+    fn microfacet_normal(&self, wo: Vec3, wi: Vec3) -> Option<(Vec3, f32)> {
+        if wo.z == 0.0 || wi.z == 0.0 {
+            return None
+        }
+        let etap = match (wo.z * wi.z > 0.0, wo.z > 0.0) {
+            (true, _) => 1.0,
+            (false, true) => self.eta,
+            (false, false) => self.eta.recip()
+        };
+        let wm = wi * etap + wo;
+        if wm.length_sqr() == 0.0 {
+            return None
+        }
+        let wm = wm.normalize();
+        let wm = if wm.z < 0.0 { -wm } else { wm };
+        // NOTE: microfacets that face away from wo or wi can't connect them
+        if (wm * wi) * wi.z < 0.0 || (wm * wo) * wo.z < 0.0 {
+            return None
+        }
+        Some((wm, etap))
+    }
+
+    // BSDF value and pdf of local directions, directions can be on any side of the surface
+    fn eval_local(&self, wo: Vec3, wi: Vec3) -> Option<BSDFEvalSample> {
+        let (wm, etap) = self.microfacet_normal(wo, wi)?;
+        let r = fr_dielectric(wo * wm, self.eta);
+        let d = self.distribution.d(wm);
+        let g = self.distribution.g(wo, wi);
+        let pdf_wm = self.distribution.d_visible(wo, wm);
+        let (f, pdfw) = if wo.z * wi.z > 0.0 {
+            (d * g * r / (4.0 * wi.z * wo.z).abs(), pdf_wm / (4.0 * (wo * wm).abs()) * r)
+        } else {
+            let denom = (wi * wm + (wo * wm) / etap) * (wi * wm + (wo * wm) / etap);
+            let f = d * g * (1.0 - r) * ((wi * wm) * (wo * wm) / (wi.z * wo.z * denom)).abs();
+            (f, pdf_wm * (wi * wm).abs() / denom * (1.0 - r))
+        };
+        if pdfw == 0.0 || !pdfw.is_finite() {
+            return None
+        }
+        Some(BSDFEvalSample{color: RGB::new(f, f, f), pdfw})
+    }
+}
+
+impl BSDFInterface for RoughDielectricMaterial {
+    fn eval(&self, wo: Vec3, normal: Normal, wi: Vec3) -> Option<BSDFEvalSample> {
+        let frame = Frame::from(normal);
+        self.eval_local(frame.to_local(wo), frame.to_local(wi))
+    }
+
+    fn sample(&self, wo: Vec3, normal: Normal, sampler: &mut Box<dyn SamplerInterface>) -> Option<BSDFSample> {
+        let frame = Frame::from(normal);
+        let wo_local = frame.to_local(wo);
+        if wo_local.z == 0.0 {
+            return None
+        }
+        let (u1, u2) = sampler.next_2d();
+        let wm = self.distribution.sample_wm(if wo_local.z < 0.0 { -wo_local } else { wo_local }, u1, u2);
+        let r = fr_dielectric(wo_local * wm, self.eta);
+        let wi_local = if sampler.next_1d() < r {
+            (-wo_local + wm * (2.0 * (wo_local * wm))).normalize()
+        } else {
+            refract(wo_local, Normal::from(wm), self.eta)?
+        };
+        let eval = self.eval_local(wo_local, wi_local)?;
+        Some(BSDFSample{wi: frame.to_world(wi_local), color: eval.color, pdfw: eval.pdfw})
+    }
+
+    fn scattering_type(&self) -> ScatteringType {
+        ScatteringType::Glossy
+    }
+
+    // NOTE: refraction is ignored, shadow ray continues in its direction
+    fn transmittance(&self, wo: Vec3, normal: Normal) -> Option<RGB> {
+        let t = 1.0 - fr_dielectric(normal * wo, self.eta);
+        Some(RGB::new(t, t, t))
+    }
+}
+
+/// Thin slab of dielectric (e.g. window glass). Light that is transmitted is not refracted,
+/// reflectance includes all internal reflections between front and back side of the slab.
+pub struct ThinDielectricMaterial {
+    eta: f32
+}
+
+impl ThinDielectricMaterial {
+    pub fn new(eta: f32) -> ThinDielectricMaterial {
+        ThinDielectricMaterial {eta}
+    }
+
+    // Reflectance and transmittance of the slab
+    fn reflect_transmit(&self, cos_theta: f32) -> (f32, f32) {
+        let mut r = fr_dielectric(cos_theta.abs(), self.eta);
+        let mut t = 1.0 - r;
+        if r < 1.0 {
+            r += t * t * r / (1.0 - r * r);
+            t = 1.0 - r;
+        }
+        (r, t)
+    }
+}
+
+impl BSDFInterface for ThinDielectricMaterial {
+    fn eval(&self, _wo: Vec3, _normal: Normal, _wi: Vec3) -> Option<BSDFEvalSample> {
+        None
+    }
+
+    fn sample(&self, wo: Vec3, normal: Normal, sampler: &mut Box<dyn SamplerInterface>) -> Option<BSDFSample> {
+        let cos_theta = normal * wo;
+        let (r, t) = self.reflect_transmit(cos_theta);
+        if cos_theta == 0.0 || r + t == 0.0 {
+            return None
+        }
+        let u = sampler.next_1d();
+        // NOTE: for specular directions color is divided by cosine, so that color * cos / pdfw is reflectance (transmittance)
+        let cos_theta = cos_theta.abs();
+        if u < r / (r + t) {
+            let wi = (-wo + Vec3::from(normal) * (2.0 * (normal * wo))).normalize();
+            let pdfw = r / (r + t);
+            Some(BSDFSample{wi, color: RGB::new(r, r, r) * cos_theta.recip(), pdfw})
+        } else {
+            let pdfw = t / (r + t);
+            Some(BSDFSample{wi: -wo, color: RGB::new(t, t, t) * cos_theta.recip(), pdfw})
+        }
+    }
+
+    fn scattering_type(&self) -> ScatteringType {
+        ScatteringType::Specular
+    }
+
+    fn transmittance(&self, wo: Vec3, normal: Normal) -> Option<RGB> {
+        let (_, t) = self.reflect_transmit(normal * wo);
+        Some(RGB::new(t, t, t))
+    }
+}
+
+/// Fresnel reflectance of conductor with complex index of refraction eta + i * k.
+pub fn fr_complex(cos_theta_i: f32, eta: f32, k: f32) -> f32 {
+    let cos2_theta_i = cos_theta_i.abs().min(1.0).powi(2);
+    let sin2_theta_i = 1.0 - cos2_theta_i;
+    // NOTE: real and imaginary part of eta^2 - sin^2, a2b2 is its magnitude
+    let t0 = eta * eta - k * k - sin2_theta_i;
+    let a2b2 = (t0 * t0 + 4.0 * eta * eta * k * k).sqrt();
+    let a = ((a2b2 + t0) * 0.5).max(0.0).sqrt();
+    let t1 = a2b2 + cos2_theta_i;
+    let t2 = 2.0 * cos_theta_i.abs().min(1.0) * a;
+    let r_perp = (t1 - t2) / (t1 + t2);
+    let t3 = cos2_theta_i * a2b2 + sin2_theta_i * sin2_theta_i;
+    let t4 = t2 * sin2_theta_i;
+    let r_parl = r_perp * (t3 - t4) / (t3 + t4);
+    if !(r_perp + r_parl).is_finite() {
+        return 1.0
+    }
+    (r_perp + r_parl) * 0.5
+}
+
+/// Fresnel reflectance of conductor for each color channel.
+pub fn fr_conductor(cos_theta_i: f32, eta: RGB, k: RGB) -> RGB {
+    RGB::new(fr_complex(cos_theta_i, eta.r, k.r),
+             fr_complex(cos_theta_i, eta.g, k.g),
+             fr_complex(cos_theta_i, eta.b, k.b))
+}
+
+/// Complex index of refraction (eta, k) of measured metals, spectral data are sampled at
+/// 650nm, 550nm and 450nm. Names are chemical symbols (Au, Ag, Cu, Al).
+pub fn conductor_preset(name: &str) -> Option<(RGB, RGB)> {
+    match name {
+        "Au" => Some((RGB::new(0.143, 0.374, 1.442), RGB::new(3.983, 2.385, 1.603))),
+        "Ag" => Some((RGB::new(0.155, 0.117, 0.138), RGB::new(4.828, 3.122, 2.147))),
+        "Cu" => Some((RGB::new(0.200, 0.924, 1.102), RGB::new(3.912, 2.452, 2.142))),
+        "Al" => Some((RGB::new(1.657, 0.880, 0.521), RGB::new(9.224, 6.270, 4.837))),
+        _ => None
+    }
+}
+
+/// Complex index of refraction with given reflectance at normal incidence, eta is one (pbrt-v4).
+pub fn conductor_from_reflectance(reflectance: RGB) -> (RGB, RGB) {
+    let k = |r: f32| {
+        let r = r.clamp(0.0, 0.9999);
+        2.0 * r.sqrt() / (1.0 - r).sqrt()
+    };
+    (RGB::new(1.0, 1.0, 1.0), RGB::new(k(reflectance.r), k(reflectance.g), k(reflectance.b)))
+}
+
+/// Perfectly smooth metal, light is only reflected in mirror direction and it is tinted by Fresnel
+/// reflectance of complex index of refraction.
+pub struct ConductorMaterial {
+    eta: RGB,
+    k: RGB
+}
+
+impl ConductorMaterial {
+    pub fn new(eta: RGB, k: RGB) -> ConductorMaterial {
+        ConductorMaterial {eta, k}
+    }
+}
+
+impl BSDFInterface for ConductorMaterial {
+    fn eval(&self, _wo: Vec3, _normal: Normal, _wi: Vec3) -> Option<BSDFEvalSample> {
+        None
+    }
+
+    fn sample(&self, wo: Vec3, normal: Normal, _sampler: &mut Box<dyn SamplerInterface>) -> Option<BSDFSample> {
+        let cos_theta = (normal * wo).abs();
+        if cos_theta == 0.0 {
+            return None
+        }
+        let wi = (-wo + Vec3::from(normal) * (2.0 * (normal * wo))).normalize();
+        let color = fr_conductor(cos_theta, self.eta, self.k) * cos_theta.recip();
+        Some(BSDFSample{wi, color, pdfw: 1.0})
+    }
+
+    fn scattering_type(&self) -> ScatteringType {
+        ScatteringType::Specular
+    }
+
+    fn albedo(&self) -> RGB {
+        fr_conductor(1.0, self.eta, self.k)
+    }
 }
 
+/// Microfacet alpha of roughness given in pbrt-v4 scenes (TrowbridgeReitzDistribution::RoughnessToAlpha).
+pub fn roughness_to_alpha(roughness: f32) -> f32 {
+    roughness.max(0.0).sqrt()
+}
+
+/// Microfacet alpha of perceptual roughness (Disney and most game engines), alpha is its square.
+pub fn perceptual_roughness_to_alpha(roughness: f32) -> f32 {
+    roughness * roughness
+}
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum MaterialType {
     Matte,
-    EmissiveMatte
+    EmissiveMatte,
+    ThinDielectric,
+    DiffuseTransmission,
+    Interface,
+    Conductor,
+    Dielectric,
+    Hair
 }
 
+#[derive(Debug, Clone)]
 pub struct MaterialDescription {
     pub name: String,
     pub typ: MaterialType,
     pub diffuse: RGB,
-    pub emission: RGB
+    pub transmittance: RGB,
+    pub emission: RGB,
+    pub power: Option<f32>,
+    /// Index of refraction of dielectric materials
+    pub eta: f32,
+    /// Complex index of refraction (eta + i * k) of conductors
+    pub conductor_eta: RGB,
+    pub conductor_k: RGB,
+    /// Microfacet roughness, see alphas
+    pub roughness: f32,
+    /// Roughness in tangent and bitangent direction of anisotropic materials, None is roughness.
+    /// Tangent isn't derived from uv parametrization, see RoughConductorMaterial.
+    pub uroughness: Option<f32>,
+    pub vroughness: Option<f32>,
+    /// Roughness is remapped to microfacet alpha, otherwise it is alpha
+    pub remap_roughness: bool,
+    /// Light layer of emissive material
+    pub light_group: Option<String>,
+    /// Emission leaves both sides of the surface, otherwise only side of the geometric normal
+    pub two_sided: bool,
+    /// Name of texture that replaces diffuse color
+    pub diffuse_texture: Option<String>,
+    /// Name of texture that replaces roughness
+    pub roughness_texture: Option<String>,
+    /// Absorption and roughness of hair material
+    pub hair: HairProperties
+}
+
+/// Parameters of material without its name, identical materials have equal keys.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct MaterialKey {
+    typ: MaterialType,
+    values: Vec<u32>,
+    light_group: Option<String>,
+    textures: [Option<String>; 2]
 }
 
 impl MaterialDescription {
-    pub fn create(&self) -> Result<Box<dyn BSDFInterface>, String> { 
+    pub fn key(&self) -> MaterialKey {
+        let mut values = vec![self.diffuse.r, self.diffuse.g, self.diffuse.b,
+                              self.transmittance.r, self.transmittance.g, self.transmittance.b,
+                              self.emission.r, self.emission.g, self.emission.b, self.eta,
+                              self.conductor_eta.r, self.conductor_eta.g, self.conductor_eta.b,
+                              self.conductor_k.r, self.conductor_k.g, self.conductor_k.b,
+                              self.roughness, self.remap_roughness as u32 as f32, self.two_sided as u32 as f32];
+        values.extend(self.power);
+        // NOTE: roughness is never negative, so it marks missing anisotropic roughness
+        values.extend([self.uroughness.unwrap_or(-1.0), self.vroughness.unwrap_or(-1.0)]);
+        let hair = &self.hair;
+        values.extend([hair.sigma_a.r, hair.sigma_a.g, hair.sigma_a.b, hair.eta, hair.beta_m, hair.beta_n, hair.alpha]);
+        MaterialKey { typ: self.typ, values: values.iter().map(|v| v.to_bits()).collect(), light_group: self.light_group.clone(),
+                      textures: [self.diffuse_texture.clone(), self.roughness_texture.clone()] }
+    }
+
+    /// Microfacet alphas in tangent and bitangent direction
+    pub fn alphas(&self) -> (f32, f32) {
+        (self.remap(self.uroughness.unwrap_or(self.roughness)), self.remap(self.vroughness.unwrap_or(self.roughness)))
+    }
+
+    fn remap(&self, roughness: f32) -> f32 {
+        if self.remap_roughness {
+            roughness_to_alpha(roughness)
+        } else {
+            roughness
+        }
+    }
+
+    fn create_conductor(&self) -> Box<dyn BSDFInterface> {
+        let (alpha_x, alpha_y) = self.alphas();
+        if TrowbridgeReitz::is_smooth(alpha_x, alpha_y) {
+            return Box::new(ConductorMaterial::new(self.conductor_eta, self.conductor_k))
+        }
+        Box::new(RoughConductorMaterial::new(self.conductor_eta, self.conductor_k, TrowbridgeReitz::new(alpha_x, alpha_y)))
+    }
+
+    fn create_dielectric(&self) -> Box<dyn BSDFInterface> {
+        let (alpha_x, alpha_y) = self.alphas();
+        if TrowbridgeReitz::is_smooth(alpha_x, alpha_y) {
+            return Box::new(DielectricMaterial::new(self.eta))
+        }
+        Box::new(RoughDielectricMaterial::new(self.eta, TrowbridgeReitz::new(alpha_x, alpha_y)))
+    }
+
+    pub fn create(&self) -> Result<Box<dyn BSDFInterface>, String> {
+        let material = self.create_one_sided()?;
+        if self.two_sided {
+            return Ok(Box::new(TwoSidedMaterial::new(material)))
+        }
+        Ok(material)
+    }
+
+    fn create_one_sided(&self) -> Result<Box<dyn BSDFInterface>, String> {
         match self.typ {
             MaterialType::Matte => Ok(Box::new(MatteMaterial::new(self.diffuse))),
-            MaterialType::EmissiveMatte => Ok(Box::new(EmissiveMatteMaterial::new(self.diffuse, self.emission)))
+            MaterialType::EmissiveMatte => Ok(Box::new(EmissiveMatteMaterial::new(self.diffuse, self.emission))),
+            MaterialType::ThinDielectric => Ok(Box::new(ThinDielectricMaterial::new(self.eta))),
+            MaterialType::DiffuseTransmission => Ok(Box::new(DiffuseTransmissionMaterial::new(self.diffuse, self.transmittance))),
+            MaterialType::Interface => Ok(Box::new(InterfaceMaterial)),
+            MaterialType::Conductor => Ok(self.create_conductor()),
+            MaterialType::Dielectric => Ok(self.create_dielectric()),
+            MaterialType::Hair => Ok(Box::new(HairMaterial::new(self.hair)))
         }
     }
 }
@@ -138,7 +898,333 @@ impl Default for MaterialDescription {
             name: "matte".to_string(),
             typ: MaterialType::Matte,
             diffuse: RGB::new(0.5, 0.5, 0.5),
-            emission: RGB::zero()
+            transmittance: RGB::new(0.25, 0.25, 0.25),
+            emission: RGB::zero(),
+            power: None,
+            eta: 1.5,
+            // NOTE: copper is default conductor in pbrt
+            conductor_eta: RGB::new(0.200, 0.924, 1.102),
+            conductor_k: RGB::new(3.912, 2.452, 2.142),
+            roughness: 0.0,
+            uroughness: None,
+            vroughness: None,
+            remap_roughness: true,
+            light_group: None,
+            two_sided: false,
+            diffuse_texture: None,
+            roughness_texture: None,
+            hair: HairProperties::default()
+        }
+    }
+}
+
+/// Material with parameters given by textures, its BSDF is created for each hit from parameters
+/// evaluated at the hit point.
+pub struct TexturedMaterial {
+    desc: MaterialDescription,
+    reflectance: Option<Arc<dyn RGBTexture>>,
+    roughness: Option<Arc<dyn FloatTexture>>
+}
+
+impl TexturedMaterial {
+    pub fn new(desc: &MaterialDescription, reflectance: Option<Arc<dyn RGBTexture>>, roughness: Option<Arc<dyn FloatTexture>>) -> Result<Self, String> {
+        // NOTE: names aren't needed by BSDF, so copy of description made for each hit doesn't allocate
+        let name = desc.name.clone();
+        let desc = MaterialDescription { name: String::new(), light_group: None, diffuse_texture: None,
+                                         roughness_texture: None, ..desc.clone() };
+        desc.create().map_err(|e| format!("Material {}: {}", name, e))?;
+        Ok(Self { desc, reflectance, roughness })
+    }
+
+    pub fn bsdf(&self, ctx: &TextureContext) -> Box<dyn BSDFInterface> {
+        let mut desc = self.desc.clone();
+        if let Some(texture) = &self.reflectance {
+            desc.diffuse = texture.evaluate(ctx);
+        }
+        if let Some(texture) = &self.roughness {
+            desc.roughness = texture.evaluate(ctx);
+        }
+        // NOTE: description is validated when textured material is created, so creation can't fail
+        desc.create().expect("Textured material")
+    }
+}
+
+/// Material at hit point, either shared material of the scene or BSDF of textured material.
+pub enum HitMaterial<'a> {
+    Shared(&'a (dyn BSDFInterface + 'static)),
+    Textured(Box<dyn BSDFInterface>)
+}
+
+impl Deref for HitMaterial<'_> {
+    type Target = dyn BSDFInterface;
+
+    fn deref(&self) -> &Self::Target {
+        match self {
+            HitMaterial::Shared(material) => *material,
+            HitMaterial::Textured(material) => material.as_ref()
         }
     }
 }
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::samplers::RandomPathSampler;
+
+    #[test]
+    fn thin_dielectric() {
+        assert!((fr_dielectric(1.0, 1.5) - 0.04).abs() < 1e-5);
+        assert_eq!(fr_dielectric(-0.1, 1.5), 1.0);
+
+        let material = ThinDielectricMaterial::new(1.5);
+        let normal = Normal::new(0.0, 0.0, 1.0);
+        let wo = Vec3::new(0.6, 0.0, 0.8);
+        let mut sampler: Box<dyn SamplerInterface> = Box::new(RandomPathSampler::new(7));
+        let (mut reflected, mut transmitted) = (0, 0);
+        for _ in 0..1000 {
+            let bs = material.sample(wo, normal, &mut sampler).unwrap();
+            // no absorption and no refraction offset
+            let weight = bs.color * ((normal * bs.wi).abs() / bs.pdfw);
+            assert!((weight.r - 1.0).abs() < 1e-5);
+            if bs.wi.z > 0.0 {
+                assert!((bs.wi.x + 0.6).abs() < 1e-5);
+                reflected += 1;
+            } else {
+                assert!((bs.wi.x + 0.6).abs() < 1e-5 && (bs.wi.z + 0.8).abs() < 1e-5);
+                transmitted += 1;
+            }
+        }
+        // both sides of slab reflect, so reflectance is almost double of single interface
+        let (r, _t) = material.reflect_transmit(0.8);
+        assert!(r > 1.5 * fr_dielectric(0.8, 1.5));
+        assert!(reflected > 0 && transmitted > reflected);
+        assert!(material.eval(wo, normal, -wo).is_none());
+    }
+
+    #[test]
+    fn two_sided_emission() {
+        let mut desc = MaterialDescription { typ: MaterialType::EmissiveMatte, emission: RGB::new(2.0, 2.0, 2.0), ..Default::default() };
+        let normal = Normal::new(0.0, 0.0, 1.0);
+        let wo = Vec3::new(0.0, 0.0, -1.0);
+        let one_sided = desc.create().unwrap();
+        assert_eq!(one_sided.emssion(wo, normal, true).r, 0.0);
+        desc.two_sided = true;
+        let two_sided = desc.create().unwrap();
+        assert!(two_sided.is_emissive());
+        assert_eq!(two_sided.emssion(wo, normal, true).r, 2.0);
+        assert_eq!(two_sided.emssion(-wo, normal, false).r, 2.0);
+        assert_ne!(desc.key(), MaterialDescription { two_sided: false, ..desc.clone() }.key());
+    }
+
+    #[test]
+    fn textured_material() {
+        use crate::textures::{ImageTexture, TextureImage};
+        use crate::vec::{Point2, Point3};
+
+        // left half of image is black, right half is white
+        let image = Arc::new(TextureImage::new(2, 1, vec![RGB::zero(), RGB::new(1.0, 1.0, 1.0)]));
+        let texture: Arc<dyn RGBTexture> = Arc::new(ImageTexture::new(image, 0.8, false));
+        let desc = MaterialDescription { diffuse_texture: Some("checker".to_string()), ..Default::default() };
+        let material = TexturedMaterial::new(&desc, Some(texture), None).unwrap();
+        let ctx = |u: f32| TextureContext { uv: Point2::new(u, 0.5), point: Point3::new(0.0, 0.0, 0.0), object_point: Point3::new(0.0, 0.0, 0.0) };
+        assert_eq!(material.bsdf(&ctx(0.25)).albedo().r, 0.0);
+        assert!((material.bsdf(&ctx(0.75)).albedo().r - 0.8).abs() < 1e-6);
+        assert_ne!(desc.key(), MaterialDescription::default().key());
+    }
+
+    #[test]
+    fn diffuse_transmission() {
+        let material = DiffuseTransmissionMaterial::new(RGB::new(0.2, 0.2, 0.2), RGB::new(0.6, 0.6, 0.6));
+        let normal = Normal::new(0.0, 0.0, 1.0);
+        let wo = Vec3::new(0.0, 0.6, 0.8);
+        let mut sampler: Box<dyn SamplerInterface> = Box::new(RandomPathSampler::new(7));
+        let mut albedo = 0.0;
+        let mut transmitted = 0;
+        for _ in 0..1000 {
+            let bs = material.sample(wo, normal, &mut sampler).unwrap();
+            let eval = material.eval(wo, normal, bs.wi).unwrap();
+            assert!((eval.pdfw - bs.pdfw).abs() < 1e-4);
+            assert_eq!(eval.color.r, bs.color.r);
+            albedo += bs.color.r * (normal * bs.wi).abs() / bs.pdfw;
+            if bs.wi.z < 0.0 {
+                transmitted += 1;
+            }
+        }
+        assert!((albedo / 1000.0 - 0.8).abs() < 1e-3);
+        assert!(transmitted > 700 && transmitted < 800);
+    }
+
+    #[test]
+    fn conductor() {
+        // reflectance at normal incidence is ((eta - 1)^2 + k^2) / ((eta + 1)^2 + k^2)
+        assert!((fr_complex(1.0, 0.2, 3.0) - 9.64 / 10.44).abs() < 1e-5);
+        assert!((fr_complex(0.0, 0.2, 3.0) - 1.0).abs() < 1e-5);
+        // without absorption it is dielectric
+        assert!((fr_complex(0.7, 1.5, 0.0) - fr_dielectric(0.7, 1.5)).abs() < 1e-5);
+        for metal in ["Au", "Ag", "Cu", "Al"] {
+            assert!(conductor_preset(metal).is_some());
+        }
+        assert!(conductor_preset("Fe").is_none());
+
+        let (eta, k) = conductor_preset("Au").unwrap();
+        let material = ConductorMaterial::new(eta, k);
+        let normal = Normal::new(0.0, 0.0, 1.0);
+        let wo = Vec3::new(0.6, 0.0, 0.8);
+        let mut sampler: Box<dyn SamplerInterface> = Box::new(RandomPathSampler::new(7));
+        let bs = material.sample(wo, normal, &mut sampler).unwrap();
+        assert!((bs.wi.x + 0.6).abs() < 1e-5 && (bs.wi.z - 0.8).abs() < 1e-5);
+        let weight = bs.color * ((normal * bs.wi).abs() / bs.pdfw);
+        assert!((weight.r - fr_complex(0.8, eta.r, k.r)).abs() < 1e-5);
+        // gold reflects red more than blue
+        assert!(material.albedo().r > material.albedo().b);
+        assert_eq!(material.scattering_type(), ScatteringType::Specular);
+        assert!(material.eval(wo, normal, bs.wi).is_none());
+    }
+
+    #[test]
+    fn trowbridge_reitz() {
+        let distribution = TrowbridgeReitz::new(0.3, 0.6);
+        // projected area of microfacets is one and visible normals are normalized for any direction
+        let n = 256;
+        let wo = Vec3::new(0.6, 0.0, 0.8);
+        let (mut area, mut visible) = (0.0, 0.0);
+        for j in 0..n {
+            for i in 0..n {
+                let cos_theta = (j as f32 + 0.5) / n as f32;
+                let phi = 2.0 * std::f32::consts::PI * (i as f32 + 0.5) / n as f32;
+                let sin_theta = (1.0 - cos_theta * cos_theta).sqrt();
+                let wm = Vec3::new(sin_theta * phi.cos(), sin_theta * phi.sin(), cos_theta);
+                let dw = 2.0 * std::f32::consts::PI / (n * n) as f32;
+                area += distribution.d(wm) * cos_theta * dw;
+                if wo * wm > 0.0 {
+                    visible += distribution.d_visible(wo, wm) * dw;
+                }
+            }
+        }
+        assert!((area - 1.0).abs() < 0.01);
+        assert!((visible - 1.0).abs() < 0.01);
+        // surface is rougher along y axis
+        assert!(distribution.d(Vec3::new(0.3, 0.0, 1.0).normalize()) < distribution.d(Vec3::new(0.0, 0.3, 1.0).normalize()));
+        assert_eq!(distribution.g1(Vec3::new(0.0, 0.0, 1.0)), 1.0);
+        assert!(distribution.g(wo, wo) < distribution.g1(wo));
+    }
+
+    #[test]
+    fn rough_conductor() {
+        let (eta, k) = conductor_from_reflectance(RGB::new(0.999, 0.999, 0.999));
+        let material = RoughConductorMaterial::new(eta, k, TrowbridgeReitz::new(0.2, 0.2));
+        let normal = Normal::new(0.0, 0.0, 1.0);
+        let wo = Vec3::new(0.0, 0.6, 0.8);
+        let mut sampler: Box<dyn SamplerInterface> = Box::new(RandomPathSampler::new(7));
+        let n = 10000;
+        let mut albedo = 0.0;
+        for _ in 0..n {
+            if let Some(bs) = material.sample(wo, normal, &mut sampler) {
+                let eval = material.eval(wo, normal, bs.wi).unwrap();
+                assert!((eval.pdfw - bs.pdfw).abs() < 1e-3 * bs.pdfw);
+                albedo += bs.color.r * (normal * bs.wi) / bs.pdfw;
+            }
+        }
+        // single scattering loses some energy, but white metal with low roughness reflects almost all
+        let albedo = albedo / n as f32;
+        assert!(albedo > 0.9 && albedo <= 1.0);
+        // it is same from the other side of surface
+        let bs = material.sample(-wo, normal, &mut sampler).unwrap();
+        assert!(bs.wi.z < 0.0);
+        assert!(material.eval(wo, normal, Vec3::new(0.0, 0.0, -1.0)).is_none());
+
+        let smooth = MaterialDescription { typ: MaterialType::Conductor, ..Default::default() };
+        assert_eq!(smooth.create().unwrap().scattering_type(), ScatteringType::Specular);
+        let rough = MaterialDescription { roughness: 0.1, ..smooth.clone() };
+        assert_eq!(rough.create().unwrap().scattering_type(), ScatteringType::Glossy);
+        let anisotropic = MaterialDescription { uroughness: Some(0.04), vroughness: Some(0.16), ..smooth.clone() };
+        assert_eq!(anisotropic.alphas(), (0.2, 0.4));
+        assert_ne!(anisotropic.key(), MaterialDescription { uroughness: Some(0.16), vroughness: Some(0.04), ..smooth }.key());
+    }
+
+    #[test]
+    fn dielectric() {
+        let normal = Normal::new(0.0, 0.0, 1.0);
+        let wo = Vec3::new(0.6, 0.0, 0.8);
+        // Snell's law, sin_theta_t = sin_theta_i / eta
+        let wi = refract(wo, normal, 1.5).unwrap();
+        assert!((wi.x + 0.4).abs() < 1e-5 && wi.z < 0.0);
+        let back = refract(wi, normal, 1.5).unwrap();
+        assert!((back.x - 0.6).abs() < 1e-5 && (back.z - 0.8).abs() < 1e-5);
+        assert!(refract(Vec3::new(0.8, 0.0, -0.6), normal, 1.5).is_none());
+        assert_eq!(glass_ior("glass-BK7"), Some(1.5168));
+
+        let material = DielectricMaterial::new(1.5);
+        let mut sampler: Box<dyn SamplerInterface> = Box::new(RandomPathSampler::new(7));
+        let mut reflected = 0;
+        for _ in 0..1000 {
+            let bs = material.sample(wo, normal, &mut sampler).unwrap();
+            let weight = bs.color * ((normal * bs.wi).abs() / bs.pdfw);
+            assert!((weight.r - 1.0).abs() < 1e-4);
+            if bs.wi.z > 0.0 {
+                assert!((bs.wi.x + 0.6).abs() < 1e-5);
+                reflected += 1;
+            } else {
+                assert!((bs.wi.x - wi.x).abs() < 1e-5);
+            }
+        }
+        let r = fr_dielectric(0.8, 1.5);
+        assert!((reflected as f32 / 1000.0 - r).abs() < 0.03);
+        // total internal reflection inside of glass
+        let inside = Vec3::new(0.8, 0.0, -0.6);
+        for _ in 0..10 {
+            assert!(material.sample(inside, normal, &mut sampler).unwrap().wi.z < 0.0);
+        }
+        assert!(material.eval(wo, normal, wi).is_none());
+    }
+
+    #[test]
+    fn rough_dielectric() {
+        let material = RoughDielectricMaterial::new(1.5, TrowbridgeReitz::new(0.2, 0.2));
+        let normal = Normal::new(0.0, 0.0, 1.0);
+        let mut sampler: Box<dyn SamplerInterface> = Box::new(RandomPathSampler::new(7));
+        // from outside and from inside of the glass
+        for wo in [Vec3::new(0.0, 0.6, 0.8), Vec3::new(0.0, 0.6, -0.8)] {
+            let n = 10000;
+            let (mut albedo, mut transmitted) = (0.0, 0);
+            for _ in 0..n {
+                if let Some(bs) = material.sample(wo, normal, &mut sampler) {
+                    let eval = material.eval(wo, normal, bs.wi).unwrap();
+                    assert!((eval.pdfw - bs.pdfw).abs() < 1e-3 * bs.pdfw);
+                    albedo += bs.color.r * (normal * bs.wi).abs() / bs.pdfw;
+                    transmitted += (bs.wi.z * wo.z < 0.0) as usize;
+                }
+            }
+            // single scattering loses some energy, but almost all light is reflected or transmitted
+            let albedo = albedo / n as f32;
+            assert!(albedo > 0.9 && albedo <= 1.0);
+            assert!(transmitted > n / 2);
+        }
+
+        let smooth = MaterialDescription { typ: MaterialType::Dielectric, ..Default::default() };
+        assert_eq!(smooth.create().unwrap().scattering_type(), ScatteringType::Specular);
+        let rough = MaterialDescription { roughness: 0.1, ..smooth };
+        assert_eq!(rough.create().unwrap().scattering_type(), ScatteringType::Glossy);
+    }
+
+    #[test]
+    fn roughness_remapping() {
+        assert_eq!(roughness_to_alpha(0.25), 0.5);
+        assert_eq!(roughness_to_alpha(-1.0), 0.0);
+        assert_eq!(perceptual_roughness_to_alpha(0.5), 0.25);
+
+        let desc = MaterialDescription { typ: MaterialType::Conductor, roughness: 0.04, ..Default::default() };
+        let (alpha_x, alpha_y) = desc.alphas();
+        assert!((alpha_x - 0.2).abs() < 1e-6 && alpha_x == alpha_y);
+        assert_eq!(desc.create().unwrap().scattering_type(), ScatteringType::Glossy);
+        let raw = MaterialDescription { remap_roughness: false, ..desc.clone() };
+        assert_eq!(raw.alphas(), (0.04, 0.04));
+        assert_ne!(desc.key(), raw.key());
+        // remapped roughness 1e-4 is rough, raw alpha 1e-4 is smooth conductor
+        let desc = MaterialDescription { roughness: 1e-4, ..desc };
+        assert_eq!(desc.create().unwrap().scattering_type(), ScatteringType::Glossy);
+        let raw = MaterialDescription { remap_roughness: false, ..desc };
+        assert_eq!(raw.create().unwrap().scattering_type(), ScatteringType::Specular);
+    }
+}
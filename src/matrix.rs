@@ -38,6 +38,26 @@ impl Matrix4x4 {
         true   
     }
 
+    pub fn is_finite(&self) -> bool {
+        self.m.iter().flatten().all(|val| val.is_finite())
+    }
+
+    /// Largest absolute difference of corresponding elements
+    pub fn max_difference(&self, other: &Matrix4x4) -> f32 {
+        self.m.iter().flatten().zip(other.m.iter().flatten()).fold(0.0, |acc, (a, b)| acc.max((a - b).abs()))
+    }
+
+    /// Largest absolute difference of corresponding elements divided by corresponding element of scale
+    pub fn max_scaled_difference(&self, other: &Matrix4x4, scale: &Matrix4x4) -> f32 {
+        let elements = self.m.iter().flatten().zip(other.m.iter().flatten()).zip(scale.m.iter().flatten());
+        elements.fold(0.0, |acc, ((a, b), s)| acc.max((a - b).abs() / s.max(f32::MIN_POSITIVE)))
+    }
+
+    /// Matrix of absolute values of elements
+    pub fn abs(&self) -> Matrix4x4 {
+        Matrix4x4::new(self.m.map(|row| row.map(f32::abs)))
+    }
+
     pub fn transpose(&self) -> Matrix4x4 {
         let m = [
             [self.m[0][0],   self.m[1][0], self.m[2][0], self.m[3][0]],
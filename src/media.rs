@@ -0,0 +1,498 @@
+use crate::color::RGB;
+use crate::frame::Frame;
+use crate::ray::Ray;
+use crate::samplers::SamplerInterface;
+use crate::transformations::Transformation;
+use crate::vec::{Point3, Vec3};
+
+#[derive(Debug)]
+pub enum MediumType {
+    Homogeneous,
+    UniformGrid,
+    RGBGrid,
+    Cloud,
+    NanoVDB
+}
+
+#[derive(Debug)]
+pub struct MediumDescription {
+    pub name: String,
+    pub typ: MediumType,
+    pub sigma_a: RGB,
+    pub sigma_s: RGB,
+    pub scale: f32,
+    pub g: f32,
+    pub le: RGB,
+    pub le_scale: f32,
+    pub preset: Option<String>,
+    pub density: Option<Vec<f32>>,
+    pub nx: usize,
+    pub ny: usize,
+    pub nz: usize,
+    pub p0: Point3,
+    pub p1: Point3,
+    pub transform: Option<Transformation>
+}
+
+impl MediumDescription {
+    /// Only homogeneous media and uniform density grids are supported, grid needs nx*ny*nz density values.
+    pub fn check(&self) -> Result<(), String> {
+        match (&self.typ, &self.density) {
+            (MediumType::Homogeneous, _) => Ok(()),
+            (MediumType::UniformGrid, Some(density)) if density.len() == self.nx * self.ny * self.nz => Ok(()),
+            (MediumType::UniformGrid, _) => Err(format!("Medium {}: nx*ny*nz density values expected!", self.name)),
+            (typ, _) => Err(format!("Medium {}: {:?} medium is not supported!", self.name, typ))
+        }
+    }
+
+    pub fn create(&self) -> Result<Medium, String> {
+        self.check()?;
+        let (sigma_a, sigma_s) = (self.sigma_a * self.scale, self.sigma_s * self.scale);
+        match (&self.typ, &self.density) {
+            (MediumType::UniformGrid, Some(density)) => {
+                let grid = DensityGrid::new(density.clone(), self.nx, self.ny, self.nz);
+                Ok(Medium::Grid(Box::new(GridMedium::new(sigma_a, sigma_s, self.g, grid, self.p0, self.p1, self.transform))))
+            }
+            _ => Ok(Medium::Homogeneous(HomogeneousMedium::new(sigma_a, sigma_s, self.g)))
+        }
+    }
+}
+
+impl Default for MediumDescription {
+    fn default() -> Self {
+        Self {
+            name: String::new(),
+            typ: MediumType::Homogeneous,
+            sigma_a: RGB::new(1.0, 1.0, 1.0),
+            sigma_s: RGB::new(1.0, 1.0, 1.0),
+            scale: 1.0,
+            g: 0.0,
+            le: RGB::zero(),
+            le_scale: 1.0,
+            preset: None,
+            density: None,
+            nx: 1,
+            ny: 1,
+            nz: 1,
+            p0: Point3::new(0.0, 0.0, 0.0),
+            p1: Point3::new(1.0, 1.0, 1.0),
+            transform: None
+        }
+    }
+}
+
+/// Names of media on the inside and outside of a shape, `None` is vacuum.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct MediumInterface {
+    pub inside: Option<String>,
+    pub outside: Option<String>
+}
+
+impl MediumInterface {
+    pub fn new(inside: Option<String>, outside: Option<String>) -> Self {
+        Self { inside, outside }
+    }
+
+    pub fn is_medium_transition(&self) -> bool {
+        self.inside != self.outside
+    }
+}
+
+/// Ids of media on the inside and outside of a shape, `None` is vacuum.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct MediumIds {
+    pub inside: Option<u32>,
+    pub outside: Option<u32>
+}
+
+/// Henyey-Greenstein phase function for cosine of angle between wo and wi (both pointing away
+/// from scattering point), positive g is forward scattering.
+pub fn henyey_greenstein(cos_theta: f32, g: f32) -> f32 {
+    let denom = 1.0 + g * g + 2.0 * g * cos_theta;
+    (1.0 - g * g) / (4.0 * std::f32::consts::PI * denom * denom.max(0.0).sqrt())
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct HGPhaseFunction {
+    g: f32
+}
+
+impl HGPhaseFunction {
+    pub fn new(g: f32) -> Self {
+        Self { g: g.clamp(-0.99, 0.99) }
+    }
+
+    pub fn p(&self, wo: Vec3, wi: Vec3) -> f32 {
+        henyey_greenstein(wo * wi, self.g)
+    }
+
+    /// Sample incident direction, returns direction and its pdf that is equal to phase function.
+    pub fn sample(&self, wo: Vec3, u1: f32, u2: f32) -> (Vec3, f32) {
+        let g = self.g;
+        let cos_theta = if g.abs() < 1e-3 {
+            1.0 - 2.0 * u1
+        } else {
+            let sqr = (1.0 - g * g) / (1.0 + g - 2.0 * g * u1);
+            -(1.0 + g * g - sqr * sqr) / (2.0 * g)
+        }.clamp(-1.0, 1.0);
+        let sin_theta = (1.0 - cos_theta * cos_theta).max(0.0).sqrt();
+        let phi = 2.0 * std::f32::consts::PI * u2;
+        let local = Vec3::new(sin_theta * phi.cos(), sin_theta * phi.sin(), cos_theta);
+        let wi = Frame::from(wo).to_world(local).normalize();
+        (wi, henyey_greenstein(cos_theta, g))
+    }
+}
+
+/// Result of distance sampling along ray inside of medium.
+pub struct MediumSample {
+    /// Distance of scattering event or tmax if ray passed through medium
+    pub t: f32,
+    /// Transmittance (times sigma_s for scattering event) divided by pdf
+    pub weight: RGB,
+    pub scattered: bool
+}
+
+pub struct HomogeneousMedium {
+    sigma_a: RGB,
+    sigma_s: RGB,
+    phase: HGPhaseFunction
+}
+
+impl HomogeneousMedium {
+    pub fn new(sigma_a: RGB, sigma_s: RGB, g: f32) -> Self {
+        Self { sigma_a, sigma_s, phase: HGPhaseFunction::new(g) }
+    }
+
+    pub fn sigma_t(&self) -> RGB {
+        self.sigma_a + self.sigma_s
+    }
+
+    pub fn phase(&self) -> &HGPhaseFunction {
+        &self.phase
+    }
+
+    pub fn transmittance(&self, distance: f32) -> RGB {
+        let sigma_t = self.sigma_t();
+        RGB::new((-sigma_t.r * distance).exp(), (-sigma_t.g * distance).exp(), (-sigma_t.b * distance).exp())
+    }
+
+    /// Sample distance of scattering event before tmax. Channel whose extinction is used is chosen
+    /// uniformly with u_channel, pdf is average of pdfs of all channels.
+    pub fn sample(&self, tmax: f32, u_channel: f32, u: f32) -> MediumSample {
+        let sigma_t = self.sigma_t();
+        let channels = [sigma_t.r, sigma_t.g, sigma_t.b];
+        let sigma = channels[((u_channel * 3.0) as usize).min(2)];
+        let t = if sigma > 0.0 { -(1.0 - u).ln() / sigma } else { f32::INFINITY };
+        if t < tmax {
+            let tr = self.transmittance(t);
+            let pdf = (sigma_t.r * tr.r + sigma_t.g * tr.g + sigma_t.b * tr.b) / 3.0;
+            MediumSample { t, weight: self.sigma_s * tr * pdf.recip(), scattered: true }
+        } else {
+            let tr = self.transmittance(tmax);
+            let pdf = (tr.r + tr.g + tr.b) / 3.0;
+            MediumSample { t: tmax, weight: tr * pdf.recip(), scattered: false }
+        }
+    }
+}
+
+/// Density values at centers of cells of unit cube, density is trilinearly interpolated
+/// and zero outside of the grid.
+pub struct DensityGrid {
+    values: Vec<f32>,
+    nx: usize,
+    ny: usize,
+    nz: usize,
+}
+
+impl DensityGrid {
+    pub fn new(values: Vec<f32>, nx: usize, ny: usize, nz: usize) -> Self {
+        Self { values, nx, ny, nz }
+    }
+
+    pub fn memory_usage(&self) -> usize {
+        self.values.capacity() * std::mem::size_of::<f32>()
+    }
+
+    pub fn max_value(&self) -> f32 {
+        self.values.iter().fold(0.0f32, |acc, v| acc.max(*v))
+    }
+
+    fn value(&self, x: i64, y: i64, z: i64) -> f32 {
+        if x < 0 || y < 0 || z < 0 || x >= self.nx as i64 || y >= self.ny as i64 || z >= self.nz as i64 {
+            return 0.0
+        }
+        self.values[(z as usize * self.ny + y as usize) * self.nx + x as usize]
+    }
+
+    /// Density at point of unit cube
+    pub fn lookup(&self, p: Point3) -> f32 {
+        let (x, y, z) = (p.x * self.nx as f32 - 0.5, p.y * self.ny as f32 - 0.5, p.z * self.nz as f32 - 0.5);
+        let (ix, iy, iz) = (x.floor(), y.floor(), z.floor());
+        let (dx, dy, dz) = (x - ix, y - iy, z - iz);
+        let (ix, iy, iz) = (ix as i64, iy as i64, iz as i64);
+        let lerp = |t: f32, a: f32, b: f32| a + t * (b - a);
+        let d00 = lerp(dx, self.value(ix, iy, iz), self.value(ix + 1, iy, iz));
+        let d10 = lerp(dx, self.value(ix, iy + 1, iz), self.value(ix + 1, iy + 1, iz));
+        let d01 = lerp(dx, self.value(ix, iy, iz + 1), self.value(ix + 1, iy, iz + 1));
+        let d11 = lerp(dx, self.value(ix, iy + 1, iz + 1), self.value(ix + 1, iy + 1, iz + 1));
+        lerp(dz, lerp(dy, d00, d10), lerp(dy, d01, d11))
+    }
+}
+
+/// Medium whose coefficients are scaled by density grid spanning box p0-p1 in medium space.
+/// Distances are sampled with delta tracking and transmittance is estimated with ratio
+/// tracking, both use one majorant for the whole grid.
+pub struct GridMedium {
+    sigma_a: RGB,
+    sigma_s: RGB,
+    phase: HGPhaseFunction,
+    grid: DensityGrid,
+    p0: Point3,
+    p1: Point3,
+    world_to_medium: Option<Transformation>,
+    sigma_maj: f32,
+}
+
+impl GridMedium {
+    pub fn new(sigma_a: RGB, sigma_s: RGB, g: f32, grid: DensityGrid, p0: Point3, p1: Point3,
+               medium_to_world: Option<Transformation>) -> Self {
+        let sigma_t = sigma_a + sigma_s;
+        let sigma_maj = sigma_t.r.max(sigma_t.g).max(sigma_t.b) * grid.max_value();
+        let world_to_medium = medium_to_world.map(|transform| transform.inverse());
+        Self { sigma_a, sigma_s, phase: HGPhaseFunction::new(g), grid, p0, p1, world_to_medium, sigma_maj }
+    }
+
+    pub fn phase(&self) -> &HGPhaseFunction {
+        &self.phase
+    }
+
+    /// Density at point in world space
+    pub fn density(&self, p: Point3) -> f32 {
+        let p = match &self.world_to_medium {
+            Some(transform) => *transform * p,
+            None => p
+        };
+        let size = self.p1 - self.p0;
+        let local = p - self.p0;
+        self.grid.lookup(Point3::new(local.x / size.x, local.y / size.y, local.z / size.z))
+    }
+
+    // Interval of ray parameter inside of grid box clipped to [0, tmax], transformation keeps
+    // parametrization because direction is not normalized in medium space.
+    fn clip(&self, ray: &Ray, tmax: f32) -> Option<(f32, f32)> {
+        let (origin, direction) = match &self.world_to_medium {
+            Some(transform) => (*transform * ray.origin, *transform * ray.direction),
+            None => (ray.origin, ray.direction)
+        };
+        let (mut t0, mut t1) = (0.0f32, tmax);
+        for axis in 0..3 {
+            let (o, d, lo, hi) = match axis {
+                0 => (origin.x, direction.x, self.p0.x, self.p1.x),
+                1 => (origin.y, direction.y, self.p0.y, self.p1.y),
+                _ => (origin.z, direction.z, self.p0.z, self.p1.z)
+            };
+            let inv_d = 1.0 / d;
+            let (mut tnear, mut tfar) = ((lo - o) * inv_d, (hi - o) * inv_d);
+            if tnear > tfar {
+                std::mem::swap(&mut tnear, &mut tfar);
+            }
+            // NOTE: NaN (ray parallel to slab and on its boundary) must not shrink interval
+            t0 = if tnear > t0 { tnear } else { t0 };
+            t1 = if tfar < t1 { tfar } else { t1 };
+            if t0 > t1 {
+                return None
+            }
+        }
+        Some((t0, t1))
+    }
+
+    /// Delta tracking with null collisions, absorption is handled by weights so every real
+    /// collision scatters.
+    pub fn sample(&self, ray: &Ray, tmax: f32, sampler: &mut Box<dyn SamplerInterface>) -> MediumSample {
+        let passed = MediumSample { t: tmax, weight: RGB::new(1.0, 1.0, 1.0), scattered: false };
+        let (t0, t1) = match self.clip(ray, tmax) {
+            Some(interval) if self.sigma_maj > 0.0 => interval,
+            _ => return passed
+        };
+        let mut weight = RGB::new(1.0, 1.0, 1.0);
+        let mut t = t0;
+        loop {
+            t -= (1.0 - sampler.next_1d()).ln() / self.sigma_maj;
+            if t >= t1 {
+                return MediumSample { weight, ..passed }
+            }
+            let density = self.density(ray.point_at(t));
+            let sigma_s = self.sigma_s * density;
+            let sigma_t = (self.sigma_a + self.sigma_s) * density;
+            let sigma_n = RGB::new((self.sigma_maj - sigma_t.r).max(0.0), (self.sigma_maj - sigma_t.g).max(0.0),
+                                   (self.sigma_maj - sigma_t.b).max(0.0));
+            let ps = (sigma_s.r + sigma_s.g + sigma_s.b) / 3.0;
+            let pn = (sigma_n.r + sigma_n.g + sigma_n.b) / 3.0;
+            if ps + pn <= 0.0 {
+                return MediumSample { t, weight: RGB::zero(), scattered: false }
+            }
+            let prob_scatter = ps / (ps + pn);
+            if sampler.next_1d() < prob_scatter {
+                weight = weight * sigma_s * (self.sigma_maj * prob_scatter).recip();
+                return MediumSample { t, weight, scattered: true }
+            }
+            weight = weight * sigma_n * (self.sigma_maj * (1.0 - prob_scatter)).recip();
+        }
+    }
+
+    /// Ratio tracking estimate of transmittance between ray origin and tmax
+    pub fn transmittance(&self, ray: &Ray, tmax: f32, sampler: &mut Box<dyn SamplerInterface>) -> RGB {
+        let mut tr = RGB::new(1.0, 1.0, 1.0);
+        let (t0, t1) = match self.clip(ray, tmax) {
+            Some(interval) if self.sigma_maj > 0.0 => interval,
+            _ => return tr
+        };
+        let mut t = t0;
+        loop {
+            t -= (1.0 - sampler.next_1d()).ln() / self.sigma_maj;
+            if t >= t1 {
+                return tr
+            }
+            let sigma_t = (self.sigma_a + self.sigma_s) * self.density(ray.point_at(t));
+            tr = tr * RGB::new(1.0 - sigma_t.r / self.sigma_maj, 1.0 - sigma_t.g / self.sigma_maj,
+                               1.0 - sigma_t.b / self.sigma_maj);
+        }
+    }
+}
+
+pub enum Medium {
+    Homogeneous(HomogeneousMedium),
+    Grid(Box<GridMedium>)
+}
+
+impl Medium {
+    pub fn phase(&self) -> &HGPhaseFunction {
+        match self {
+            Medium::Homogeneous(medium) => medium.phase(),
+            Medium::Grid(medium) => medium.phase()
+        }
+    }
+
+    /// Bytes used by density grid
+    pub fn memory_usage(&self) -> usize {
+        match self {
+            Medium::Homogeneous(_) => 0,
+            Medium::Grid(medium) => medium.grid.memory_usage()
+        }
+    }
+
+    /// Scattering coefficient at point in world space
+    pub fn sigma_s(&self, p: Point3) -> RGB {
+        match self {
+            Medium::Homogeneous(medium) => medium.sigma_s,
+            Medium::Grid(medium) => medium.sigma_s * medium.density(p)
+        }
+    }
+
+    /// Sample distance of scattering event along ray before tmax.
+    pub fn sample(&self, ray: &Ray, tmax: f32, sampler: &mut Box<dyn SamplerInterface>) -> MediumSample {
+        match self {
+            Medium::Homogeneous(medium) => {
+                let (u1, u2) = sampler.next_2d();
+                medium.sample(tmax, u1, u2)
+            }
+            Medium::Grid(medium) => medium.sample(ray, tmax, sampler)
+        }
+    }
+
+    /// Transmittance between ray origin and tmax, estimate for heterogeneous media.
+    pub fn transmittance(&self, ray: &Ray, tmax: f32, sampler: &mut Box<dyn SamplerInterface>) -> RGB {
+        match self {
+            Medium::Homogeneous(medium) => medium.transmittance(tmax),
+            Medium::Grid(medium) => medium.transmittance(ray, tmax, sampler)
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::samplings::sample_uniform_sphere;
+    use crate::rng::{PCGRng, Rng};
+
+    #[test]
+    fn henyey_greenstein_sampling() {
+        let mut rng = PCGRng::new(7, 0);
+        let wo = Vec3::new(0.0, 0.6, 0.8);
+        for g in [-0.7, 0.0, 0.3, 0.9] {
+            let phase = HGPhaseFunction::new(g);
+            let n = 20000;
+            let mut integral = 0.0;
+            for _ in 0..n {
+                let ds = sample_uniform_sphere(rng.rand_f32(), rng.rand_f32());
+                integral += phase.p(wo, ds.direction) / ds.pdfw;
+                let (wi, pdf) = phase.sample(wo, rng.rand_f32(), rng.rand_f32());
+                assert!((phase.p(wo, wi) - pdf).abs() <= 1e-3 * pdf.max(1.0));
+            }
+            assert!((integral / n as f32 - 1.0).abs() < 0.05, "g {} integral {}", g, integral / n as f32);
+        }
+    }
+
+    #[test]
+    fn homogeneous_distance_sampling() {
+        let medium = HomogeneousMedium::new(RGB::new(0.2, 0.5, 1.0), RGB::new(0.5, 0.5, 0.5), 0.0);
+        let mut rng = PCGRng::new(3, 0);
+        let n = 100000;
+        let mut transmitted = RGB::zero();
+        for _ in 0..n {
+            let ms = medium.sample(2.0, rng.rand_f32(), rng.rand_f32());
+            if !ms.scattered {
+                transmitted += ms.weight;
+            }
+        }
+        // expected value of weight of unscattered samples is transmittance
+        let expected = medium.transmittance(2.0);
+        let estimate = transmitted * (n as f32).recip();
+        assert!((estimate.r - expected.r).abs() < 0.01);
+        assert!((estimate.b - expected.b).abs() < 0.01);
+    }
+
+    #[test]
+    fn unsupported_media() {
+        let grid = MediumDescription { typ: MediumType::UniformGrid, density: Some(vec![1.0; 8]), nx: 2, ny: 2, nz: 2,
+                                       ..Default::default() };
+        assert!(matches!(grid.create(), Ok(Medium::Grid(_))));
+        let grid = MediumDescription { nz: 3, ..grid };
+        assert!(grid.create().is_err());
+        let grid = MediumDescription { density: None, ..grid };
+        assert!(grid.create().is_err());
+        assert!(MediumDescription { typ: MediumType::Cloud, ..Default::default() }.create().is_err());
+        assert!(MediumDescription { typ: MediumType::RGBGrid, ..Default::default() }.check().is_err());
+    }
+
+    #[test]
+    fn grid_medium_tracking() {
+        let mut sampler: Box<dyn SamplerInterface> = Box::new(crate::samplers::RandomPathSampler::new(11));
+        // Density is 1 in the first half of the box along x and 0 in the second half
+        let grid = DensityGrid::new(vec![1.0, 1.0, 0.0, 0.0], 4, 1, 1);
+        assert_eq!(grid.lookup(Point3::new(0.25, 0.5, 0.5)), 1.0);
+        assert_eq!(grid.lookup(Point3::new(0.875, 0.5, 0.5)), 0.0);
+        let transform = Transformation::translate(&Vec3::new(1.0, 0.0, 0.0));
+        let medium = GridMedium::new(RGB::new(0.5, 1.0, 0.5), RGB::new(0.5, 0.5, 0.5), 0.0, grid,
+                                     Point3::new(0.0, 0.0, 0.0), Point3::new(4.0, 1.0, 1.0), Some(transform));
+        let ray = Ray::new(Point3::new(0.0, 0.5, 0.5), Vec3::new(1.0, 0.0, 0.0));
+        // density integrated along the ray is 1.875, density falls off linearly toward zero
+        // outside of the grid and between cell centers 1.5 and 2.5
+        let expected = RGB::new((-1.875f32).exp(), (-2.8125f32).exp(), (-1.875f32).exp());
+        let n = 20000;
+        let mut ratio = RGB::zero();
+        let mut delta = RGB::zero();
+        for _ in 0..n {
+            ratio += medium.transmittance(&ray, 10.0, &mut sampler);
+            let ms = medium.sample(&ray, 10.0, &mut sampler);
+            if !ms.scattered {
+                delta += ms.weight;
+            }
+        }
+        let ratio = ratio * (n as f32).recip();
+        let delta = delta * (n as f32).recip();
+        assert!((ratio.r - expected.r).abs() < 0.01 && (ratio.g - expected.g).abs() < 0.01);
+        assert!((delta.r - expected.r).abs() < 0.02 && (delta.g - expected.g).abs() < 0.02);
+    }
+}
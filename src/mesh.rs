@@ -0,0 +1,423 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+
+use crate::isect::isect_ray_triangle;
+use crate::ray::Ray;
+use crate::shapes::MeshDescription;
+use crate::vec::{Point3, Vec3};
+
+/// Problems found in triangle mesh. Every triangle is counted only once, under
+/// the first problem that was detected for it.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct MeshValidationReport {
+    /// Vertices that contain NaN or infinite coordinate
+    pub invalid_vertices: usize,
+    /// Indices left over when number of indices is not a multiple of 3
+    pub dangling_indices: usize,
+    pub out_of_range_triangles: usize,
+    pub invalid_vertex_triangles: usize,
+    pub repeated_index_triangles: usize,
+    pub zero_area_triangles: usize,
+}
+
+impl MeshValidationReport {
+    pub fn invalid_triangles(&self) -> usize {
+        self.out_of_range_triangles + self.invalid_vertex_triangles +
+        self.repeated_index_triangles + self.zero_area_triangles
+    }
+
+    pub fn is_valid(&self) -> bool {
+        self.invalid_triangles() == 0 && self.dangling_indices == 0
+    }
+}
+
+impl fmt::Display for MeshValidationReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "invalid vertices: {}, dangling indices: {}, out of range triangles: {}, \
+                   triangles with invalid vertex: {}, triangles with repeated index: {}, zero area triangles: {}",
+               self.invalid_vertices, self.dangling_indices, self.out_of_range_triangles,
+               self.invalid_vertex_triangles, self.repeated_index_triangles, self.zero_area_triangles)
+    }
+}
+
+fn is_valid_point(p: &Point3) -> bool {
+    p.x.is_finite() && p.y.is_finite() && p.z.is_finite()
+}
+
+// Returns false if triangle is invalid and records the reason in report
+fn check_triangle(vertices: &[Point3], triangle: &[u32], report: &mut MeshValidationReport) -> bool {
+    if triangle.iter().any(|index| *index as usize >= vertices.len()) {
+        report.out_of_range_triangles += 1;
+        return false;
+    }
+    let (p0, p1, p2) = (vertices[triangle[0] as usize], vertices[triangle[1] as usize], vertices[triangle[2] as usize]);
+    if !(is_valid_point(&p0) && is_valid_point(&p1) && is_valid_point(&p2)) {
+        report.invalid_vertex_triangles += 1;
+        return false;
+    }
+    if triangle[0] == triangle[1] || triangle[1] == triangle[2] || triangle[0] == triangle[2] {
+        report.repeated_index_triangles += 1;
+        return false;
+    }
+    if (p1 - p0).cross(p2 - p0).length_sqr() == 0.0 {
+        report.zero_area_triangles += 1;
+        return false;
+    }
+    true
+}
+
+pub fn validate_mesh(vertices: &[Point3], indices: &[u32]) -> MeshValidationReport {
+    let mut report = MeshValidationReport {
+        invalid_vertices: vertices.iter().filter(|p| !is_valid_point(p)).count(),
+        dangling_indices: indices.len() % 3,
+        ..Default::default()
+    };
+    for triangle in indices.chunks_exact(3) {
+        check_triangle(vertices, triangle, &mut report);
+    }
+    report
+}
+
+/// Remove invalid triangles and dangling indices. Vertices are kept so that
+/// per vertex attributes (normals, uvs) stay consistent with indices.
+pub fn cleanup_mesh(vertices: &[Point3], indices: &mut Vec<u32>) -> MeshValidationReport {
+    let mut report = validate_mesh(vertices, indices);
+    if report.is_valid() {
+        return report;
+    }
+    report = MeshValidationReport { invalid_vertices: report.invalid_vertices, dangling_indices: report.dangling_indices, ..Default::default() };
+    let mut valid = Vec::with_capacity(indices.len() - indices.len() % 3);
+    for triangle in indices.chunks_exact(3) {
+        if check_triangle(vertices, triangle, &mut report) {
+            valid.extend_from_slice(triangle);
+        }
+    }
+    *indices = valid;
+    report
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct MeshOrientationReport {
+    /// Number of edge connected components
+    pub components: usize,
+    /// Triangles flipped to match winding of their neighbours
+    pub flipped_triangles: usize,
+    /// Closed components that were turned inside out, so that normals point outward
+    pub inverted_components: usize,
+}
+
+fn flip_triangle(indices: &mut [u32], triangle: usize) {
+    indices.swap(triangle * 3 + 1, triangle * 3 + 2);
+}
+
+// Directed edges of triangle
+fn triangle_edges(indices: &[u32], triangle: usize) -> [(u32, u32); 3] {
+    let t = &indices[triangle * 3..triangle * 3 + 3];
+    [(t[0], t[1]), (t[1], t[2]), (t[2], t[0])]
+}
+
+fn triangle_normal(vertices: &[Point3], indices: &[u32], triangle: usize) -> Vec3 {
+    let t = &indices[triangle * 3..triangle * 3 + 3];
+    let (p0, p1, p2) = (vertices[t[0] as usize], vertices[t[1] as usize], vertices[t[2] as usize]);
+    (p1 - p0).cross(p2 - p0)
+}
+
+// Ray is shot from centroid of triangle in direction of its normal, odd number of
+// crossings with closed component means that normal points inside.
+fn points_inward(vertices: &[Point3], indices: &[u32], component: &[usize], triangle: usize) -> bool {
+    let t = &indices[triangle * 3..triangle * 3 + 3];
+    let (p0, p1, p2) = (vertices[t[0] as usize], vertices[t[1] as usize], vertices[t[2] as usize]);
+    let centroid = Point3::new((p0.x + p1.x + p2.x) / 3.0, (p0.y + p1.y + p2.y) / 3.0, (p0.z + p1.z + p2.z) / 3.0);
+    let ray = Ray::new(centroid, triangle_normal(vertices, indices, triangle).normalize());
+    let crossings = component.iter().filter(|other| {
+        let o = &indices[**other * 3..**other * 3 + 3];
+        **other != triangle &&
+        isect_ray_triangle(&ray, vertices[o[0] as usize], vertices[o[1] as usize], vertices[o[2] as usize], 1e-5).is_some()
+    }).count();
+    crossings % 2 == 1
+}
+
+/// Make winding of triangles consistent inside every connected component and turn
+/// closed components inside out when their normals point inward. Mesh is expected
+/// to be free of invalid triangles (see cleanup_mesh).
+pub fn orient_mesh(vertices: &[Point3], indices: &mut [u32]) -> MeshOrientationReport {
+    let ntriangles = indices.len() / 3;
+    let mut edges: HashMap<(u32, u32), Vec<usize>> = HashMap::new();
+    for triangle in 0..ntriangles {
+        for (a, b) in triangle_edges(indices, triangle) {
+            edges.entry((a.min(b), a.max(b))).or_default().push(triangle);
+        }
+    }
+
+    let mut report = MeshOrientationReport::default();
+    let mut visited = vec![false; ntriangles];
+    for start in 0..ntriangles {
+        if visited[start] {
+            continue;
+        }
+        report.components += 1;
+        visited[start] = true;
+        let mut component = vec![start];
+        let mut stack = vec![start];
+        let mut closed = true;
+        while let Some(triangle) = stack.pop() {
+            for (a, b) in triangle_edges(indices, triangle) {
+                let neighbours = &edges[&(a.min(b), a.max(b))];
+                closed &= neighbours.len() == 2;
+                for &other in neighbours.iter() {
+                    if visited[other] {
+                        continue;
+                    }
+                    // NOTE: consistently oriented neighbour traverses shared edge in opposite direction
+                    if triangle_edges(indices, other).contains(&(a, b)) {
+                        flip_triangle(indices, other);
+                        report.flipped_triangles += 1;
+                    }
+                    visited[other] = true;
+                    component.push(other);
+                    stack.push(other);
+                }
+            }
+        }
+
+        if closed {
+            // Majority vote over few triangles, single ray can graze an edge
+            let step = (component.len() / 5).max(1);
+            let votes: Vec<bool> = component.iter().step_by(step).take(5)
+                .map(|triangle| points_inward(vertices, indices, &component, *triangle)).collect();
+            if votes.iter().filter(|inward| **inward).count() * 2 > votes.len() {
+                for triangle in component.iter() {
+                    flip_triangle(indices, *triangle);
+                }
+                report.inverted_components += 1;
+            }
+        }
+    }
+    report
+}
+
+// Newell normal of polygon, it is robust for non planar and concave polygons
+fn polygon_normal(points: &[Point3]) -> Vec3 {
+    let mut normal = Vec3::new(0.0, 0.0, 0.0);
+    for (i, p) in points.iter().enumerate() {
+        let q = points[(i + 1) % points.len()];
+        normal.x += (p.y - q.y) * (p.z + q.z);
+        normal.y += (p.z - q.z) * (p.x + q.x);
+        normal.z += (p.x - q.x) * (p.y + q.y);
+    }
+    normal
+}
+
+fn cross_2d(a: (f32, f32), b: (f32, f32), c: (f32, f32)) -> f32 {
+    (b.0 - a.0) * (c.1 - a.1) - (b.1 - a.1) * (c.0 - a.0)
+}
+
+fn inside_triangle_2d(p: (f32, f32), a: (f32, f32), b: (f32, f32), c: (f32, f32)) -> bool {
+    cross_2d(a, b, p) >= 0.0 && cross_2d(b, c, p) >= 0.0 && cross_2d(c, a, p) >= 0.0
+}
+
+/// Split polygon into triangles by ear clipping, polygon is list of vertex indices in order.
+/// Polygon is projected on plane perpendicular to its normal and triangles keep its winding.
+/// When no ear can be found (self intersecting or degenerate polygon) rest of the polygon
+/// is triangulated as fan. Indices are expected to be in range of vertices.
+pub fn triangulate_polygon(vertices: &[Point3], polygon: &[u32]) -> Vec<u32> {
+    let mut triangles = Vec::with_capacity(polygon.len().saturating_sub(2) * 3);
+    if polygon.len() < 3 {
+        return triangles;
+    }
+    let points: Vec<Point3> = polygon.iter().map(|index| vertices[*index as usize]).collect();
+    let normal = polygon_normal(&points);
+    let (ax, ay, az) = (normal.x.abs(), normal.y.abs(), normal.z.abs());
+    // NOTE: drop dominant axis of normal, swap remaining axes when needed so polygon is counter clockwise
+    let project = |p: &Point3| -> (f32, f32) {
+        if az >= ax && az >= ay {
+            if normal.z >= 0.0 { (p.x, p.y) } else { (p.y, p.x) }
+        } else if ax >= ay {
+            if normal.x >= 0.0 { (p.y, p.z) } else { (p.z, p.y) }
+        } else if normal.y >= 0.0 {
+            (p.z, p.x)
+        } else {
+            (p.x, p.z)
+        }
+    };
+    let projected: Vec<(f32, f32)> = points.iter().map(project).collect();
+    let mut remaining: Vec<usize> = (0..polygon.len()).collect();
+    while remaining.len() > 3 {
+        let n = remaining.len();
+        let is_ear = |i: usize| {
+            let (a, b, c) = (remaining[(i + n - 1) % n], remaining[i], remaining[(i + 1) % n]);
+            let (pa, pb, pc) = (projected[a], projected[b], projected[c]);
+            cross_2d(pa, pb, pc) > 0.0 && remaining.iter().all(|v| {
+                *v == a || *v == b || *v == c || projected[*v] == pa || projected[*v] == pb ||
+                projected[*v] == pc || !inside_triangle_2d(projected[*v], pa, pb, pc)
+            })
+        };
+        match (0..n).find(|i| is_ear(*i)) {
+            Some(i) => {
+                triangles.extend([polygon[remaining[(i + n - 1) % n]], polygon[remaining[i]], polygon[remaining[(i + 1) % n]]]);
+                remaining.remove(i);
+            }
+            None => break
+        }
+    }
+    for i in 1..remaining.len() - 1 {
+        triangles.extend([polygon[remaining[0]], polygon[remaining[i]], polygon[remaining[i + 1]]]);
+    }
+    triangles
+}
+
+/// Triangulate faces with arbitrary number of vertices. Face i uses next face_sizes[i] indices.
+/// Vertices are not changed so per vertex attributes (normals, uvs) stay valid.
+pub fn triangulate_faces(vertices: &[Point3], face_sizes: &[u32], indices: &[u32]) -> Result<Vec<u32>, Box<dyn Error>> {
+    let total: usize = face_sizes.iter().map(|size| *size as usize).sum();
+    if total != indices.len() {
+        return Err(format!("Mesh: faces use {} indices but {} indices are specified!", total, indices.len()).into());
+    }
+    if let Some(index) = indices.iter().find(|index| **index as usize >= vertices.len()) {
+        return Err(format!("Mesh: index {} is out of range of {} vertices!", index, vertices.len()).into());
+    }
+    let mut triangles = Vec::with_capacity(indices.len() * 3);
+    let mut start = 0;
+    for size in face_sizes.iter() {
+        let face = &indices[start..start + *size as usize];
+        if face.len() < 3 {
+            return Err(format!("Mesh: face with {} vertices!", face.len()).into());
+        }
+        triangles.extend(triangulate_polygon(vertices, face));
+        start += face.len();
+    }
+    Ok(triangles)
+}
+
+impl MeshDescription {
+    pub fn validate(&self) -> MeshValidationReport {
+        match (&self.vertices, &self.indices) {
+            (Some(vertices), Some(indices)) => validate_mesh(vertices, indices),
+            _ => MeshValidationReport::default()
+        }
+    }
+
+    pub fn cleanup(&mut self) -> MeshValidationReport {
+        let (vertices, indices) = match (&self.vertices, &mut self.indices) {
+            (Some(vertices), Some(indices)) => (vertices, indices),
+            _ => return MeshValidationReport::default()
+        };
+        if let Some(face_indices) = self.face_indices.as_mut() {
+            let mut report = MeshValidationReport::default();
+            *face_indices = indices.chunks_exact(3).zip(face_indices.iter())
+                .filter(|(triangle, _)| check_triangle(vertices, triangle, &mut report))
+                .map(|(_, face_index)| *face_index).collect();
+        }
+        cleanup_mesh(vertices, indices)
+    }
+
+    pub fn orient(&mut self) -> MeshOrientationReport {
+        match (&self.vertices, &mut self.indices) {
+            (Some(vertices), Some(indices)) => orient_mesh(vertices, indices),
+            _ => MeshOrientationReport::default()
+        }
+    }
+
+    /// Replace polygon faces given by face_sizes and indices with triangles (see triangulate_faces).
+    /// Face indices given per polygon are repeated for every triangle of the polygon.
+    pub fn triangulate(&mut self, face_sizes: &[u32]) -> Result<(), Box<dyn Error>> {
+        if let (Some(vertices), Some(indices)) = (&self.vertices, &self.indices) {
+            self.indices = Some(triangulate_faces(vertices, face_sizes, indices)?);
+        }
+        if let Some(face_indices) = self.face_indices.as_mut() {
+            if face_indices.len() != face_sizes.len() {
+                return Err(format!("Mesh: {} face indices for {} faces!", face_indices.len(), face_sizes.len()).into());
+            }
+            *face_indices = face_indices.iter().zip(face_sizes.iter())
+                .flat_map(|(face_index, size)| std::iter::repeat_n(*face_index, *size as usize - 2)).collect();
+        }
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mesh_cleanup() {
+        let vertices = vec![Point3::new(0.0, 0.0, 0.0), Point3::new(1.0, 0.0, 0.0), Point3::new(0.0, 1.0, 0.0),
+                            Point3::new(2.0, 0.0, 0.0), Point3::new(f32::NAN, 0.0, 0.0)];
+        let mut indices = vec![0, 1, 2,   // valid
+                               0, 1, 3,   // zero area, collinear
+                               0, 0, 2,   // repeated index
+                               0, 1, 7,   // out of range
+                               0, 4, 2,   // NaN vertex
+                               1, 2];     // dangling
+        let report = validate_mesh(&vertices, &indices);
+        assert_eq!(report.invalid_vertices, 1);
+        assert_eq!(report.dangling_indices, 2);
+        assert_eq!(report.zero_area_triangles, 1);
+        assert_eq!(report.repeated_index_triangles, 1);
+        assert_eq!(report.out_of_range_triangles, 1);
+        assert_eq!(report.invalid_vertex_triangles, 1);
+        assert!(!report.is_valid());
+
+        assert_eq!(cleanup_mesh(&vertices, &mut indices), report);
+        assert_eq!(indices, vec![0, 1, 2]);
+        assert!(validate_mesh(&vertices, &indices).is_valid());
+    }
+
+    // Cube with normals pointing inward and two faces with flipped winding
+    fn cube() -> (Vec<Point3>, Vec<u32>) {
+        let vertices = vec![Point3::new(0.0, 0.0, 0.0), Point3::new(1.0, 0.0, 0.0), Point3::new(1.0, 1.0, 0.0), Point3::new(0.0, 1.0, 0.0),
+                            Point3::new(0.0, 0.0, 1.0), Point3::new(1.0, 0.0, 1.0), Point3::new(1.0, 1.0, 1.0), Point3::new(0.0, 1.0, 1.0)];
+        let indices = vec![0, 1, 2,  0, 2, 3,   // z = 0
+                           4, 6, 5,  4, 7, 6,   // z = 1
+                           0, 4, 5,  0, 5, 1,   // y = 0
+                           3, 2, 6,  3, 6, 7,   // y = 1
+                           0, 3, 7,  4, 7, 0,   // x = 0, second triangle flipped
+                           1, 5, 6,  1, 2, 6];  // x = 1, second triangle flipped
+        (vertices, indices)
+    }
+
+    #[test]
+    fn mesh_orientation() {
+        let (vertices, mut indices) = cube();
+        let report = orient_mesh(&vertices, &mut indices);
+        assert_eq!(report.components, 1);
+        assert_eq!(report.flipped_triangles, 2);
+        assert_eq!(report.inverted_components, 1);
+
+        let center = Point3::new(0.5, 0.5, 0.5);
+        for triangle in 0..indices.len() / 3 {
+            let p0 = vertices[indices[triangle * 3] as usize];
+            let normal = triangle_normal(&vertices, &indices, triangle);
+            assert!(normal * (p0 - center) > 0.0);
+        }
+        let report = orient_mesh(&vertices, &mut indices);
+        assert_eq!(report, MeshOrientationReport { components: 1, flipped_triangles: 0, inverted_components: 0 });
+    }
+
+    #[test]
+    fn polygon_triangulation() {
+        // Concave L shaped hexagon in xz plane with normal pointing down
+        let vertices = vec![Point3::new(0.0, 0.0, 0.0), Point3::new(0.0, 0.0, 2.0), Point3::new(2.0, 0.0, 2.0),
+                            Point3::new(2.0, 0.0, 1.0), Point3::new(1.0, 0.0, 1.0), Point3::new(1.0, 0.0, 0.0),
+                            Point3::new(0.0, 0.0, 3.0), Point3::new(1.0, 0.0, 3.0)];
+        let indices = vec![0, 1, 2, 3, 4, 5,  1, 6, 7];
+        let triangles = triangulate_faces(&vertices, &[6, 3], &indices).unwrap();
+        assert_eq!(triangles.len(), 5 * 3);
+        assert!(validate_mesh(&vertices, &triangles).is_valid());
+        let expected = polygon_normal(&vertices[0..6]).normalize();
+        let mut area = 0.0;
+        for triangle in 0..4 {
+            let normal = triangle_normal(&vertices, &triangles, triangle);
+            assert!(normal.normalize() * expected > 0.99);
+            area += 0.5 * normal.length();
+        }
+        assert!((area - 3.0).abs() < 1e-5);
+        assert_eq!(&triangles[12..15], &[1, 6, 7]);
+
+        assert!(triangulate_faces(&vertices, &[4], &indices[0..5]).is_err());
+        assert!(triangulate_faces(&vertices, &[2, 3], &indices[0..5]).is_err());
+        assert!(triangulate_faces(&vertices, &[3], &[0, 1, 9]).is_err());
+    }
+}
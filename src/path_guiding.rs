@@ -0,0 +1,454 @@
+//! Practical path guiding (Müller et al. 2017)
+//!
+//! Incident radiance is learned in SD-tree, binary tree over the scene (S-tree) whose leaves have
+//! quadtree over directions (D-tree). Directions are mapped to unit square by cylindrical mapping that
+//! preserves area, so density of quadtree cell is proportional to energy that arrived from its directions.
+//! Training is done in iterations with doubling number of samples, after every iteration leaves of S-tree
+//! that got many samples are split and D-trees are subdivided where they have most of the energy.
+//! Learned distribution is combined with BSDF sampling by one-sample MIS.
+
+use std::sync::RwLock;
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+
+use crate::shapes::AABB;
+use crate::vec::{Point3, Vec3};
+
+const DTREE_MAX_DEPTH: usize = 20;
+const STREE_MAX_DEPTH: usize = 48;
+
+/// Guiding of random walk integrator, training iterations are traced before the image is rendered.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PathGuidingProperties {
+    /// Number of training iterations, iteration k traces 2^k paths per pixel
+    pub iterations: usize,
+    /// Probability that direction is sampled from BSDF instead of learned distribution
+    pub bsdf_fraction: f32,
+    /// Leaf of S-tree is split when it gets more than threshold * sqrt(2^k) samples in iteration k
+    pub spatial_threshold: f32,
+    /// Cell of D-tree is subdivided when it has larger fraction of energy of the tree
+    pub directional_threshold: f32,
+}
+
+impl Default for PathGuidingProperties {
+    fn default() -> Self {
+        Self { iterations: 5, bsdf_fraction: 0.5, spatial_threshold: 12000.0, directional_threshold: 0.01 }
+    }
+}
+
+/// Cylindrical mapping of direction to unit square, x is (cos_theta + 1) / 2 and y is phi / 2PI.
+pub fn direction_to_square(direction: Vec3) -> (f32, f32) {
+    let x = ((direction.z + 1.0) * 0.5).clamp(0.0, 1.0);
+    let mut phi = direction.y.atan2(direction.x);
+    if phi < 0.0 {
+        phi += 2.0 * std::f32::consts::PI;
+    }
+    (x, (phi * 0.5 * std::f32::consts::FRAC_1_PI).clamp(0.0, 1.0))
+}
+
+pub fn square_to_direction(x: f32, y: f32) -> Vec3 {
+    let cos_theta = 2.0 * x - 1.0;
+    let sin_theta = (1.0 - cos_theta * cos_theta).max(0.0).sqrt();
+    let phi = 2.0 * std::f32::consts::PI * y;
+    Vec3::new(sin_theta * phi.cos(), sin_theta * phi.sin(), cos_theta)
+}
+
+#[derive(Default)]
+struct AtomicF32(AtomicU32);
+
+impl AtomicF32 {
+    fn new(value: f32) -> Self {
+        Self(AtomicU32::new(value.to_bits()))
+    }
+
+    fn load(&self) -> f32 {
+        f32::from_bits(self.0.load(Ordering::Relaxed))
+    }
+
+    fn add(&self, value: f32) {
+        let _ = self.0.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |bits| Some((f32::from_bits(bits) + value).to_bits()));
+    }
+}
+
+impl Clone for AtomicF32 {
+    fn clone(&self) -> Self {
+        Self::new(self.load())
+    }
+}
+
+// NOTE: root is never a child, so zero child index is a leaf quadrant
+#[derive(Clone, Default)]
+struct QuadNode {
+    sums: [AtomicF32; 4],
+    children: [u32; 4],
+}
+
+impl QuadNode {
+    fn sums(&self) -> [f32; 4] {
+        [self.sums[0].load(), self.sums[1].load(), self.sums[2].load(), self.sums[3].load()]
+    }
+}
+
+/// Point is moved to coordinates of the quadrant, quadrant index is x + 2 * y.
+fn quadrant(p: &mut (f32, f32)) -> usize {
+    let xbit = usize::from(p.0 >= 0.5);
+    let ybit = usize::from(p.1 >= 0.5);
+    p.0 = (p.0 * 2.0 - xbit as f32).clamp(0.0, 1.0);
+    p.1 = (p.1 * 2.0 - ybit as f32).clamp(0.0, 1.0);
+    xbit + 2 * ybit
+}
+
+/// Discrete choice between two options with weights a and b, u is rescaled so it can be reused.
+fn choose(a: f32, b: f32, u: &mut f32) -> usize {
+    let p = a / (a + b);
+    let choice = if *u < p {
+        *u /= p;
+        0
+    } else {
+        *u = (*u - p) / (1.0 - p);
+        1
+    };
+    *u = u.clamp(0.0, 1.0 - f32::EPSILON);
+    choice
+}
+
+/// Quadtree of energy over unit square, cells can be recorded from many threads.
+#[derive(Clone)]
+pub struct DTree {
+    nodes: Vec<QuadNode>,
+}
+
+impl DTree {
+    pub fn new() -> Self {
+        Self { nodes: vec![QuadNode::default()] }
+    }
+
+    pub fn total(&self) -> f32 {
+        self.nodes[0].sums().iter().sum()
+    }
+
+    pub fn nodes(&self) -> usize {
+        self.nodes.len()
+    }
+
+    pub fn record(&self, mut p: (f32, f32), value: f32) {
+        let mut node = 0;
+        loop {
+            let q = quadrant(&mut p);
+            self.nodes[node].sums[q].add(value);
+            match self.nodes[node].children[q] {
+                0 => break,
+                child => node = child as usize
+            }
+        }
+    }
+
+    /// Density on unit square, it is uniform when nothing was recorded.
+    pub fn pdf(&self, mut p: (f32, f32)) -> f32 {
+        if self.total() <= 0.0 {
+            return 1.0
+        }
+        let mut pdf = 1.0;
+        let mut node = 0;
+        loop {
+            let sums = self.nodes[node].sums();
+            let total: f32 = sums.iter().sum();
+            if total <= 0.0 {
+                return 0.0
+            }
+            let q = quadrant(&mut p);
+            pdf *= 4.0 * sums[q] / total;
+            match self.nodes[node].children[q] {
+                0 => return pdf,
+                child => node = child as usize
+            }
+        }
+    }
+
+    /// Quadrant is chosen proportionally to its energy, first along x and then along y.
+    pub fn sample(&self, mut u1: f32, mut u2: f32) -> (f32, f32) {
+        if self.total() <= 0.0 {
+            return (u1, u2)
+        }
+        let (mut x, mut y, mut size) = (0.0, 0.0, 1.0);
+        let mut node = 0;
+        loop {
+            let s = self.nodes[node].sums();
+            if s.iter().sum::<f32>() <= 0.0 {
+                return (x + u1 * size, y + u2 * size)
+            }
+            let xbit = choose(s[0] + s[2], s[1] + s[3], &mut u1);
+            let ybit = choose(s[xbit], s[xbit + 2], &mut u2);
+            size *= 0.5;
+            x += xbit as f32 * size;
+            y += ybit as f32 * size;
+            match self.nodes[node].children[xbit + 2 * ybit] {
+                0 => return (x + u1 * size, y + u2 * size),
+                child => node = child as usize
+            }
+        }
+    }
+
+    /// Empty tree for next iteration, cells with more than threshold fraction of energy are subdivided
+    /// and subtrees with less energy are collapsed.
+    pub fn refined(&self, threshold: f32) -> DTree {
+        let mut tree = DTree::new();
+        let total = self.total();
+        if total <= 0.0 {
+            return tree
+        }
+        let mut stack = vec![(0, Some(0), self.nodes[0].sums(), 1)];
+        while let Some((node, old, energy, depth)) = stack.pop() {
+            for (q, &quadrant_energy) in energy.iter().enumerate() {
+                if quadrant_energy <= threshold * total || depth >= DTREE_MAX_DEPTH {
+                    continue
+                }
+                let old_child = old.and_then(|old: usize| match self.nodes[old].children[q] {
+                    0 => None,
+                    child => Some(child as usize)
+                });
+                // NOTE: energy of leaf is assumed to be uniform over its quadrants
+                let child_energy = match old_child {
+                    Some(child) => self.nodes[child].sums(),
+                    None => [quadrant_energy * 0.25; 4]
+                };
+                let index = tree.nodes.len();
+                tree.nodes.push(QuadNode::default());
+                tree.nodes[node].children[q] = index as u32;
+                stack.push((index, old_child, child_energy, depth + 1));
+            }
+        }
+        tree
+    }
+}
+
+impl Default for DTree {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Distribution used for sampling in current iteration and distribution that is recorded for next one.
+struct SLeaf {
+    sampling: DTree,
+    building: DTree,
+    samples: AtomicU32,
+}
+
+impl Clone for SLeaf {
+    fn clone(&self) -> Self {
+        Self { sampling: self.sampling.clone(), building: self.building.clone(),
+               samples: AtomicU32::new(self.samples.load(Ordering::Relaxed)) }
+    }
+}
+
+// NOTE: node with zero first child is a leaf, cells are split in half along axis
+struct SNode {
+    axis: usize,
+    children: [u32; 2],
+    leaf: u32,
+    depth: usize,
+}
+
+struct STree {
+    nodes: Vec<SNode>,
+    leaves: Vec<SLeaf>,
+}
+
+impl STree {
+    fn new() -> Self {
+        let leaf = SLeaf { sampling: DTree::new(), building: DTree::new(), samples: AtomicU32::new(0) };
+        Self { nodes: vec![SNode { axis: 0, children: [0, 0], leaf: 0, depth: 0 }], leaves: vec![leaf] }
+    }
+
+    /// Leaf of point given in unit cube of the tree
+    fn leaf(&self, p: Vec3) -> &SLeaf {
+        let mut p = [p.x, p.y, p.z];
+        let mut node = &self.nodes[0];
+        while node.children[0] != 0 {
+            let v = p[node.axis] * 2.0;
+            let side = usize::from(v >= 1.0);
+            p[node.axis] = v - side as f32;
+            node = &self.nodes[node.children[side] as usize];
+        }
+        &self.leaves[node.leaf as usize]
+    }
+
+    /// Leaves with more samples than threshold are split, both halves get copy of distributions
+    /// and half of samples, so they are split again until they are below threshold.
+    fn split(&mut self, threshold: f32) {
+        let mut stack = vec![0];
+        while let Some(index) = stack.pop() {
+            if self.nodes[index].children[0] != 0 {
+                stack.extend(self.nodes[index].children.iter().map(|child| *child as usize));
+                continue
+            }
+            let (leaf, depth) = (self.nodes[index].leaf as usize, self.nodes[index].depth);
+            let samples = self.leaves[leaf].samples.load(Ordering::Relaxed);
+            if (samples as f32) <= threshold || depth >= STREE_MAX_DEPTH {
+                continue
+            }
+            self.leaves[leaf].samples.store(samples / 2, Ordering::Relaxed);
+            let second = self.leaves.len() as u32;
+            self.leaves.push(self.leaves[leaf].clone());
+            let first = self.nodes.len() as u32;
+            let axis = depth % 3;
+            self.nodes.push(SNode { axis: (axis + 1) % 3, children: [0, 0], leaf: leaf as u32, depth: depth + 1 });
+            self.nodes.push(SNode { axis: (axis + 1) % 3, children: [0, 0], leaf: second, depth: depth + 1 });
+            self.nodes[index].axis = axis;
+            self.nodes[index].children = [first, first + 1];
+            stack.push(first as usize);
+            stack.push(first as usize + 1);
+        }
+    }
+}
+
+/// Learned incident radiance, it is recorded only while guide is trained.
+pub struct PathGuide {
+    settings: PathGuidingProperties,
+    bounds: AABB,
+    tree: RwLock<STree>,
+    training: AtomicBool,
+}
+
+impl PathGuide {
+    pub fn new(settings: PathGuidingProperties, scene_bounds: AABB) -> Self {
+        // NOTE: tree is a cube, so cells don't get elongated by splitting
+        let center = scene_bounds.centroid();
+        let r = center.distance(scene_bounds.max()).max(1e-3) * 1.01;
+        let bounds = AABB::new(center + Vec3::new(-r, -r, -r), center + Vec3::new(r, r, r));
+        Self { settings, bounds, tree: RwLock::new(STree::new()), training: AtomicBool::new(false) }
+    }
+
+    pub fn settings(&self) -> &PathGuidingProperties {
+        &self.settings
+    }
+
+    pub fn is_training(&self) -> bool {
+        self.training.load(Ordering::Relaxed)
+    }
+
+    pub fn set_training(&self, training: bool) {
+        self.training.store(training, Ordering::Relaxed);
+    }
+
+    /// Forget learned distributions
+    pub fn reset(&self) {
+        *self.tree.write().unwrap_or_else(|err| err.into_inner()) = STree::new();
+    }
+
+    pub fn leaves(&self) -> usize {
+        self.tree.read().unwrap_or_else(|err| err.into_inner()).leaves.len()
+    }
+
+    fn unit_position(&self, p: Point3) -> Vec3 {
+        let (min, max) = (self.bounds.min(), self.bounds.max());
+        let unit = |v: f32, min: f32, max: f32| ((v - min) / (max - min)).clamp(0.0, 1.0);
+        Vec3::new(unit(p.x, min.x, max.x), unit(p.y, min.y, max.y), unit(p.z, min.z, max.z))
+    }
+
+    /// Direction sampled from learned distribution at the point and its pdf (solid angle).
+    pub fn sample(&self, p: Point3, u1: f32, u2: f32) -> (Vec3, f32) {
+        let tree = self.tree.read().unwrap_or_else(|err| err.into_inner());
+        let dtree = &tree.leaf(self.unit_position(p)).sampling;
+        let square = dtree.sample(u1, u2);
+        let pdfw = dtree.pdf(square) * 0.25 * std::f32::consts::FRAC_1_PI;
+        (square_to_direction(square.0, square.1), pdfw)
+    }
+
+    pub fn pdf(&self, p: Point3, wi: Vec3) -> f32 {
+        let tree = self.tree.read().unwrap_or_else(|err| err.into_inner());
+        tree.leaf(self.unit_position(p)).sampling.pdf(direction_to_square(wi)) * 0.25 * std::f32::consts::FRAC_1_PI
+    }
+
+    /// Record estimate of incident radiance from direction wi divided by pdf of the direction.
+    pub fn record(&self, p: Point3, wi: Vec3, value: f32) {
+        if !value.is_finite() || value < 0.0 {
+            return
+        }
+        let tree = self.tree.read().unwrap_or_else(|err| err.into_inner());
+        let leaf = tree.leaf(self.unit_position(p));
+        leaf.samples.fetch_add(1, Ordering::Relaxed);
+        if value > 0.0 {
+            leaf.building.record(direction_to_square(wi), value);
+        }
+    }
+
+    /// End of training iteration, recorded distributions are used for sampling in next iteration.
+    pub fn refine(&self, iteration: usize) {
+        let mut tree = self.tree.write().unwrap_or_else(|err| err.into_inner());
+        tree.split(self.settings.spatial_threshold * 2.0f32.powi(iteration as i32).sqrt());
+        for leaf in tree.leaves.iter_mut() {
+            let refined = leaf.building.refined(self.settings.directional_threshold);
+            leaf.sampling = std::mem::replace(&mut leaf.building, refined);
+            leaf.samples.store(0, Ordering::Relaxed);
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rng::{PCGRng, Rng};
+
+    #[test]
+    fn dtree_sampling() {
+        let d = Vec3::new(0.3, -0.5, 0.8).normalize();
+        let (x, y) = direction_to_square(d);
+        let back = square_to_direction(x, y);
+        assert!((back - d).length() < 1e-5);
+
+        // energy is in one corner of the square, refined tree subdivides it
+        let mut tree = DTree::new();
+        tree.record((0.1, 0.2), 6.0);
+        tree.record((0.8, 0.9), 2.0);
+        tree = tree.refined(0.01);
+        assert!(tree.nodes() > 1);
+        let mut rng = PCGRng::new(0xf123456789012345, 0);
+        for _ in 0..1000 {
+            let p = (rng.rand_f32() * 0.25, rng.rand_f32() * 0.25);
+            tree.record(p, 1.0);
+        }
+        tree.record((0.75, 0.75), 50.0);
+        let n = 20000;
+        let mut inside = 0;
+        let mut integral = 0.0;
+        for _ in 0..n {
+            let p = tree.sample(rng.rand_f32(), rng.rand_f32());
+            if p.0 < 0.25 && p.1 < 0.25 {
+                inside += 1;
+            }
+            assert!(tree.pdf(p) > 0.0);
+            integral += tree.pdf((rng.rand_f32(), rng.rand_f32()));
+        }
+        // samples follow recorded energy and density integrates to one
+        assert!((inside as f32 / n as f32 - 1000.0 / 1050.0).abs() < 0.02);
+        assert!((integral / n as f32 - 1.0).abs() < 0.05);
+        assert_eq!(DTree::new().pdf((0.3, 0.3)), 1.0);
+    }
+
+    #[test]
+    fn path_guide_refinement() {
+        let bounds = AABB::new(Point3::new(-1.0, -1.0, -1.0), Point3::new(1.0, 1.0, 1.0));
+        let settings = PathGuidingProperties { spatial_threshold: 100.0, ..Default::default() };
+        let guide = PathGuide::new(settings, bounds);
+        let up = Vec3::new(0.0, 0.0, 1.0);
+        let mut rng = PCGRng::new(0xabcdef, 0);
+        let p = Point3::new(0.5, 0.5, 0.5);
+        assert!((guide.pdf(p, up) - 0.25 * std::f32::consts::FRAC_1_PI).abs() < 1e-6);
+        for iteration in 0..2 {
+            for _ in 0..1000 {
+                let p = Point3::new(rng.rand_f32() * 2.0 - 1.0, rng.rand_f32() * 2.0 - 1.0, rng.rand_f32() * 2.0 - 1.0);
+                guide.record(p, up, 1.0);
+            }
+            guide.refine(iteration);
+        }
+        assert_eq!(guide.leaves(), 16);
+        // all energy arrives from above, so sampled directions point up
+        let (wi, pdfw) = guide.sample(p, 0.3, 0.6);
+        assert!(wi.z > 0.85 && pdfw > 10.0);
+        assert_eq!(guide.pdf(p, -up), 0.0);
+        guide.reset();
+        assert_eq!(guide.leaves(), 1);
+    }
+}
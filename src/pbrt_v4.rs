@@ -1,35 +1,115 @@
-use crate::color::RGB;
+use crate::color::{RGB, AovType, ColorEncoding};
 use crate::vec::{Point3, Vec3, Normal, Point2};
 use std::path::PathBuf;
 use std::error::Error;
+#[cfg(feature = "fs")]
 use std::fs;
 use std::path::Path;
-use crate::scene::SceneDescription;
-use std::collections::HashSet;
-use crate::pbrt_v4_tokenizer::PBRTTokenizer;
+use crate::scene::{SceneDescription, FragmentPosition};
+use crate::camera::{ScreenWindow, StereoSettings, StereoLayout};
+use std::collections::{HashMap, HashSet};
+use rayon::prelude::*;
+use std::panic::{self, AssertUnwindSafe};
+use crate::pbrt_v4_tokenizer::{PBRTTokenizer, block_values};
 use crate::transformations::Transformation;
 use crate::scene::RenderingAlgorithm;
 use std::str::FromStr;
 use std::fmt::Display;
 use crate::rgb::ImageSize;
-use crate::materials::MaterialDescription;
-use crate::materials::MaterialType;
+use crate::materials::{MaterialDescription, MaterialKey};
+use crate::textures::{TextureDescription, TextureInput, TextureType};
+use crate::materials::{MaterialType, conductor_preset, conductor_from_reflectance, glass_ior};
+use crate::hair::{sigma_a_from_melanin, sigma_a_from_reflectance};
 use crate::lights::LightDescription;
-use crate::lights::LightType;
+use crate::lights::{LightType, EnvironmentMapping};
+use crate::sky::DEFAULT_SKY_SCALE;
 use crate::shapes::ShapeDescription;
-use crate::scene::{AmbientOcclusionProperties, RandomWalkProperties};
+use crate::scene::{AmbientOcclusionProperties, AmbientOcclusionOutput, RandomWalkProperties, LpeOutput, FurnaceProperties};
+use crate::scene::{SppmProperties, AovOutput, IntersectorProperties, PreviewShading, LightStrategy, RestirProperties};
 use crate::matrix::Matrix4x4;
-use crate::scene::{Sampler, RandomSamplerSettings, StratifiedSamplerSettings};
-use crate::shapes::{MeshDescription, SphereDescription};
+use crate::scene::{Sampler, RandomSamplerSettings, StratifiedSamplerSettings, TileImportanceProperties, WarmStartProperties};
+use crate::shapes::{MeshDescription, BilinearMeshDescription, SphereDescription, PrototypeDescription, InstanceDescription, AcceleratorType, CoordinateSpace};
+use crate::bvh::BVHBuildMethod;
+use crate::irradiance_cache::IrradianceCacheProperties;
+use crate::path_guiding::PathGuidingProperties;
+use crate::bake::BakeProperties;
 use crate::filter::{FilterDescriptor, FilterType};
-
+use crate::media::{MediumDescription, MediumInterface, MediumType};
+use crate::raylog::{RayKind, RayLogOutput};
+use crate::checkpoint::CheckpointOutput;
+use crate::light_sampler::LightSamplerType;
+
+
+// Transformations that are changed by transformation directives
+#[derive(Clone, Copy, PartialEq)]
+enum ActiveTransform {
+    Start,
+    End,
+    All
+}
 
 struct ParseState {
     transformations: Vec<Transformation>,
+    // Transformations at end time, they differ from transformations only for animated objects
+    end_transformations: Vec<Transformation>,
+    // Error of singular transformation that made current transformation invalid
+    transformation_errors: Vec<Option<String>>,
+    skip_invalid_transforms: bool,
+    active_transforms: Vec<ActiveTransform>,
+    transform_times: (f32, f32),
+    // Object that is being defined and index of its first shape in scene
+    object: Option<(String, usize)>,
     materials: Vec<String>,
+    // Scopes of named materials, name in file -> name of material in scene description
+    named_materials: Vec<HashMap<String, String>>,
     area_lights: Vec<String>,
+    // Named textures, name in file -> name of texture in scene description. Textures are not scoped.
+    textures: HashMap<String, String>,
+    // Anonymous materials that were already added to scene
+    material_cache: HashMap<MaterialKey, String>,
+    medium_interfaces: Vec<MediumInterface>,
     current_path: PathBuf,
     directives: HashSet<&'static str>,
+    name_prefix: String,
+    imports: Vec<PendingImport>,
+}
+
+// Imported file with copy of graphics state at its Import directive, it's parsed in thread pool
+// when the file that imports it is parsed.
+struct PendingImport {
+    position: FragmentPosition,
+    filename: String,
+    state: ParseState,
+}
+
+fn parse_import(mut import: PendingImport) -> Result<SceneDescription, String> {
+    let filename = import.filename;
+    let parse_file = |state: &mut ParseState| -> Result<SceneDescription, Box<dyn Error>> {
+        let contents = read_file(&filename)?;
+        let mut scene = SceneDescription::default();
+        parse_input_string(&contents, &mut scene, state)?;
+        state.finish_imports(&mut scene)?;
+        Ok(scene)
+    };
+    parse_file(&mut import.state).map_err(|e| format!("Import: {} - {}", filename, e))
+}
+
+// Imports are parsed with parallel iterator, so thread that waits for them helps to parse them.
+// Fragments are merged in order of Import directives, each one at the position of its directive.
+fn merge_imports(imports: Vec<PendingImport>, scene: &mut SceneDescription) -> Result<(), Box<dyn Error>> {
+    let positions: Vec<FragmentPosition> = imports.iter().map(|import| import.position).collect();
+    let fragments: Vec<Result<SceneDescription, String>> = imports.into_par_iter().map(|import| {
+        panic::catch_unwind(AssertUnwindSafe(|| parse_import(import)))
+            .unwrap_or_else(|_| Err("Import: Parsing thread panicked!".to_string()))
+    }).collect();
+    let mut inserted = FragmentPosition::default();
+    for (fragment, position) in fragments.into_iter().zip(positions) {
+        let fragment = fragment?;
+        let size = fragment.fragment_position();
+        scene.merge_at(fragment, Some(position + inserted));
+        inserted = inserted + size;
+    }
+    Ok(())
 }
 
 impl ParseState {
@@ -37,23 +117,73 @@ impl ParseState {
         let transformations = vec![Transformation::identity()];
         let materials = Vec::new();
         let area_lights = Vec::new();
+        let medium_interfaces = vec![MediumInterface::default()];
         let current_path = PathBuf::new();
         let directives: HashSet<_> = vec!["LookAt", "Camera", "Sampler", "Integrator", "Film", "PixelFilter",
         "WorldBegin", "AttributeBegin", "AttributeEnd", "LightSource", "AreaLightSource", "Texture",
         "Material", "MakeNamedMaterial", "NamedMaterial", "Include", "Accelerator", "Shape",
-        "Scale", "Translate", "Rotate", "Identity", "Transform", "ConcatTransform"].into_iter().collect();
+        "Scale", "Translate", "Rotate", "Identity", "Transform", "ConcatTransform",
+        "MakeNamedMedium", "MediumInterface", "Import", "ObjectBegin", "ObjectEnd", "ObjectInstance",
+        "ActiveTransform", "TransformTimes", "Option"].into_iter().collect();
         Self {
+            end_transformations: transformations.clone(),
             transformations,
+            transformation_errors: vec![None],
+            skip_invalid_transforms: false,
+            active_transforms: vec![ActiveTransform::All],
+            transform_times: (0.0, 1.0),
+            object: None,
             materials,
+            named_materials: vec![HashMap::new()],
             area_lights,
+            textures: HashMap::new(),
+            material_cache: HashMap::new(),
+            medium_interfaces,
             current_path,
-            directives
+            directives,
+            name_prefix: String::new(),
+            imports: Vec::new()
+        }
+    }
+
+    // Copy of graphics state used for parsing of imported file
+    pub fn snapshot(&self, name_prefix: String) -> Self {
+        Self {
+            transformations: vec![self.current_transformation()],
+            end_transformations: vec![self.current_end_transformation()],
+            transformation_errors: vec![self.transformation_error().cloned()],
+            skip_invalid_transforms: self.skip_invalid_transforms,
+            active_transforms: vec![self.active_transform()],
+            transform_times: self.transform_times,
+            object: None,
+            materials: self.materials.last().cloned().into_iter().collect(),
+            named_materials: vec![self.named_materials.iter().flatten().map(|(k, v)| (k.clone(), v.clone())).collect()],
+            area_lights: self.area_lights.last().cloned().into_iter().collect(),
+            textures: self.textures.clone(),
+            material_cache: HashMap::new(),
+            medium_interfaces: vec![self.current_medium_interface()],
+            current_path: self.current_path.clone(),
+            directives: self.directives.clone(),
+            name_prefix,
+            imports: Vec::new()
         }
     }
 
+    // Parse imported files and merge them into scene in order of Import directives
+    pub fn finish_imports(&mut self, scene: &mut SceneDescription) -> Result<(), Box<dyn Error>> {
+        merge_imports(std::mem::take(&mut self.imports), scene)
+    }
+
     pub fn push_state(&mut self) {
         self.transformations.push(self.current_transformation());
-        self.materials.push(self.current_material());
+        self.end_transformations.push(self.current_end_transformation());
+        self.transformation_errors.push(self.transformation_error().cloned());
+        self.active_transforms.push(self.active_transform());
+        if !self.materials.is_empty() {
+            self.materials.push(self.current_material());
+        }
+        self.named_materials.push(HashMap::new());
+        self.medium_interfaces.push(self.current_medium_interface());
         if !self.area_lights.is_empty() {
             self.area_lights.push(self.area_lights.last().expect("No area light exist!").clone());
         }
@@ -61,7 +191,12 @@ impl ParseState {
 
     pub fn pop_state(&mut self) {
         self.transformations.pop();
+        self.end_transformations.pop();
+        self.transformation_errors.pop();
+        self.active_transforms.pop();
         self.materials.pop();
+        self.named_materials.pop();
+        self.medium_interfaces.pop();
         self.area_lights.pop();
     }
 
@@ -69,13 +204,78 @@ impl ParseState {
         self.transformations[self.transformations.len() - 1]
     }
 
+    pub fn current_end_transformation(&self) -> Transformation {
+        self.end_transformations[self.end_transformations.len() - 1]
+    }
+
+    pub fn transformation_error(&self) -> Option<&String> {
+        self.transformation_errors.last().and_then(|error| error.as_ref())
+    }
+
+    // Singular transformation can't be applied, current transformation is kept but objects placed
+    // by it are invalid until transformation is replaced
+    fn invalidate_transformation(&mut self, error: String) {
+        if let Some(last) = self.transformation_errors.last_mut() {
+            *last = Some(error);
+        }
+    }
+
+    fn active_transform(&self) -> ActiveTransform {
+        self.active_transforms.last().copied().unwrap_or(ActiveTransform::All)
+    }
+
+    fn set_active_transform(&mut self, active: ActiveTransform) {
+        if let Some(last) = self.active_transforms.last_mut() {
+            *last = active;
+        }
+    }
+
     pub fn current_material(&self) -> String {
         self.materials.last().expect("No material exist!").clone()
     }
 
+    pub fn current_medium_interface(&self) -> MediumInterface {
+        self.medium_interfaces.last().cloned().unwrap_or_default()
+    }
+
+    pub fn set_medium_interface(&mut self, medium_interface: MediumInterface) {
+        if let Some(last) = self.medium_interfaces.last_mut() {
+            *last = medium_interface;
+        }
+    }
+
+    /// Set active transformations (see ActiveTransform directive)
     pub fn set_transformation(&mut self, transformation: Transformation) {
-        if let Some(last) = self.transformations.last_mut() {
-            *last = transformation;
+        let active = self.active_transform();
+        if active == ActiveTransform::All {
+            if let Some(last) = self.transformation_errors.last_mut() {
+                *last = None;
+            }
+        }
+        if active != ActiveTransform::End {
+            if let Some(last) = self.transformations.last_mut() {
+                *last = transformation;
+            }
+        }
+        if active != ActiveTransform::Start {
+            if let Some(last) = self.end_transformations.last_mut() {
+                *last = transformation;
+            }
+        }
+    }
+
+    /// Post-multiply active transformations by transformation
+    pub fn concat_transformation(&mut self, transformation: Transformation) {
+        let active = self.active_transform();
+        if active != ActiveTransform::End {
+            if let Some(last) = self.transformations.last_mut() {
+                *last = *last * transformation;
+            }
+        }
+        if active != ActiveTransform::Start {
+            if let Some(last) = self.end_transformations.last_mut() {
+                *last = *last * transformation;
+            }
         }
     }
 
@@ -88,6 +288,17 @@ impl ParseState {
         }
     }
 
+    pub fn define_named_material(&mut self, name: String, material: String) {
+        if let Some(scope) = self.named_materials.last_mut() {
+            scope.insert(name, material);
+        }
+    }
+
+    // Innermost definition wins, definitions from closed attribute blocks are not visible
+    pub fn named_material(&self, name: &str) -> Option<String> {
+        self.named_materials.iter().rev().find_map(|scope| scope.get(name).cloned())
+    }
+
     pub fn set_area_light(&mut self, material: String) {
         if self.area_lights.is_empty() {
             self.area_lights.push(material);
@@ -102,12 +313,23 @@ impl ParseState {
     }
 }
 
+#[cfg(feature = "fs")]
 pub fn parse_pbrt_v4_input_file<P: AsRef<Path>>(path: P) -> Result<SceneDescription, Box<dyn Error>> {
     let mut state = ParseState::new();
     state.current_path = path.as_ref().to_path_buf();
     let contents = fs::read_to_string(path)?;
     let mut scene = SceneDescription::default();
     parse_input_string(&contents, &mut scene, &mut state)?;
+    state.finish_imports(&mut scene)?;
+    Ok(scene)
+}
+
+/// Parse scene given as text, relative paths of included files are resolved against working directory.
+pub fn parse_pbrt_v4_string(text: &str) -> Result<SceneDescription, Box<dyn Error>> {
+    let mut state = ParseState::new();
+    let mut scene = SceneDescription::default();
+    parse_input_string(text, &mut scene, &mut state)?;
+    state.finish_imports(&mut scene)?;
     Ok(scene)
 }
 
@@ -133,18 +355,28 @@ fn parse_input_string(text: &str, scene: &mut SceneDescription, state: &mut Pars
             "AttributeEnd" => process_attribute_end(&mut ct, scene, state)?,
             "LightSource" => process_light(&mut ct, scene, state)?,
             "AreaLightSource" => process_area_light_source(&mut ct, scene, state)?,
-            // "Texture" => process_texture(tokens, scene, state)?,
+            "Texture" => process_texture(&mut ct, scene, state)?,
             "Material" => process_material(&mut ct, scene, state)?,
             "Shape" => process_shape(&mut ct, scene, state)?,
             "MakeNamedMaterial" => process_make_named_material(&mut ct, scene, state)?,
             "NamedMaterial" => process_named_material(&mut ct, scene, state)?,
-            // "Accelerator" => process_accelerator(tokens, scene, state)?,
+            "Accelerator" => process_accelerator(&mut ct, scene, state)?,
             "Scale" => process_scale_transform(&mut ct, scene, state)?,
             "Translate" => process_translate_transform(&mut ct, scene, state)?,
             // "Rotate" => process_rotate_transform(tokens, scene, state)?,
             "Identity" => process_identity_transform(&mut ct, scene, state)?,
             "Transform" => process_transform(&mut ct, scene, state)?,
             "ConcatTransform" => process_concat_transform(&mut ct, scene, state)?,
+            "Include" => process_include(&mut ct, scene, state)?,
+            "Import" => process_import(&mut ct, scene, state)?,
+            "MakeNamedMedium" => process_make_named_medium(&mut ct, scene, state)?,
+            "MediumInterface" => process_medium_interface(&mut ct, scene, state)?,
+            "ObjectBegin" => process_object_begin(&mut ct, scene, state)?,
+            "ObjectEnd" => process_object_end(&mut ct, scene, state)?,
+            "ObjectInstance" => process_object_instance(&mut ct, scene, state)?,
+            "ActiveTransform" => process_active_transform(&mut ct, scene, state)?,
+            "TransformTimes" => process_transform_times(&mut ct, scene, state)?,
+            "Option" => process_option(&mut ct, scene, state)?,
             _=> return Err(format!("Unsupported directive to process: {}", cur_directive).into())
         };
         match new_directive {
@@ -177,8 +409,10 @@ fn process_look_at(tokenizer: &mut PBRTTokenizer, _scene: &mut SceneDescription,
     let v1 = parse_f32(tokenizer, "LookAt:up y ")?;
     let v2 = parse_f32(tokenizer, "LookAt:up z ")?;
     let up = Vec3::new(v0, v1, v2);
-    let tranformation = state.current_transformation() * Transformation::look_at(eye, look_at, up);
-    state.set_transformation(tranformation);
+    match Transformation::look_at(eye, look_at, up) {
+        Ok(transform) => state.concat_transformation(transform),
+        Err(e) => state.invalidate_transformation(format!("LookAt: {}", e))
+    }
     Ok(next_directive(tokenizer))
 }
 
@@ -189,8 +423,7 @@ fn process_translate_transform(tokenizer: &mut PBRTTokenizer, _scene: &mut Scene
     let v1 = parse_f32(tokenizer, "Translate: y ")?;
     let v2 = parse_f32(tokenizer, "Translate: z ")?;
     let delta = Vec3::new(v0, v1, v2);
-    let transformation = state.current_transformation() * Transformation::translate(&delta);
-    state.set_transformation(transformation);
+    state.concat_transformation(Transformation::translate(&delta));
     Ok(next_directive(tokenizer))
 }
 
@@ -200,8 +433,11 @@ fn process_scale_transform(tokenizer: &mut PBRTTokenizer, _scene: &mut SceneDesc
     let v0 = parse_f32(tokenizer, "Scale: x ")?;
     let v1 = parse_f32(tokenizer, "Scale: y ")?;
     let v2 = parse_f32(tokenizer, "Scale: z ")?;
-    let transformation = state.current_transformation() * Transformation::scale(v0, v1, v2);
-    state.set_transformation(transformation);
+    if v0 * v1 * v2 == 0.0 {
+        state.invalidate_transformation(format!("Scale: Scale ({}, {}, {}) is not invertible.", v0, v1, v2));
+    } else {
+        state.concat_transformation(Transformation::scale(v0, v1, v2));
+    }
     Ok(next_directive(tokenizer))
 }
 
@@ -218,8 +454,10 @@ fn process_transform(tokenizer: &mut PBRTTokenizer, _scene: &mut SceneDescriptio
         [values[2], values[6], values[10], values[14]],
         [values[3], values[7], values[11], values[15]],
     ];
-    let transform = Transformation::from(Matrix4x4::new(m));
-    state.set_transformation(transform);
+    match Transformation::try_from(Matrix4x4::new(m)) {
+        Ok(transform) => state.set_transformation(transform),
+        Err(e) => state.invalidate_transformation(format!("Transform: {}", e))
+    }
     Ok(next_directive(tokenizer))
 }
 
@@ -236,8 +474,10 @@ fn process_concat_transform(tokenizer: &mut PBRTTokenizer, _scene: &mut SceneDes
         [values[2], values[6], values[10], values[14]],
         [values[3], values[7], values[11], values[15]],
     ];
-    let transform = Transformation::from(Matrix4x4::new(m));
-    state.set_transformation(state.current_transformation() * transform);
+    match Transformation::try_from(Matrix4x4::new(m)) {
+        Ok(transform) => state.concat_transformation(transform),
+        Err(e) => state.invalidate_transformation(format!("ConcatTransform: {}", e))
+    }
     Ok(next_directive(tokenizer))
 }
 
@@ -266,6 +506,8 @@ fn process_perspective_camera(tokenizer: &mut PBRTTokenizer, scene: &mut SceneDe
                               state: &mut ParseState) -> Result<Option<String>, Box<dyn Error>> {
 
     let mut fov: f32 = 90.0;                           
+    let mut stereo = StereoSettings::default();
+    let mut use_stereo = false;
     let result = loop {
         let token = match tokenizer.next() {
             Some(token) => token.trim(),
@@ -276,12 +518,47 @@ fn process_perspective_camera(tokenizer: &mut PBRTTokenizer, scene: &mut SceneDe
         }
         match token {
             "float fov" => fov = extract_value(tokenizer, "Perspective Camera::fov - ")?,
+            "float pixelaspect" => scene.camera_desc.pixel_aspect_ratio = extract_value(tokenizer, "Perspective Camera::pixelaspect - ")?,
+            "float screenwindow" => {
+                let window = parse_f32_array(tokenizer, "Perspective Camera::screenwindow - ")?;
+                if window.len() != 4 {
+                    return Err("Perspective Camera::screenwindow - 4 values expected!".into());
+                }
+                scene.camera_desc.screen_window = Some(ScreenWindow::new(window[0], window[1], window[2], window[3]));
+            }
+            "float lensshift" => {
+                let shift = parse_f32_array(tokenizer, "Perspective Camera::lensshift - ")?;
+                if shift.len() != 2 {
+                    return Err("Perspective Camera::lensshift - 2 values expected!".into());
+                }
+                scene.camera_desc.lens_shift = (shift[0], shift[1]);
+            }
+            "string stereo" => {
+                let layout: String = extract_value(tokenizer, "Perspective Camera::stereo - ")?;
+                stereo.layout = match layout.as_str() {
+                    "sidebyside" => StereoLayout::SideBySide,
+                    "topbottom" => StereoLayout::TopBottom,
+                    _ => return Err(format!("Perspective Camera::stereo - Unknown layout {}", layout).into())
+                };
+                use_stereo = true;
+            }
+            "float interocular" => stereo.interocular_distance = extract_value(tokenizer, "Perspective Camera::interocular - ")?,
+            "float convergence" => stereo.convergence_distance = extract_value(tokenizer, "Perspective Camera::convergence - ")?,
+            "float shutteropen" => scene.camera_desc.shutter.open = extract_value(tokenizer, "Perspective Camera::shutteropen - ")?,
+            "float shutterclose" => scene.camera_desc.shutter.close = extract_value(tokenizer, "Perspective Camera::shutterclose - ")?,
             _ => return Err(format!("Unsupported parameter in Perspective Camera: {}", token).into())
         }
 
     };
     scene.camera_desc.fov = fov;
+    if use_stereo {
+        scene.camera_desc.stereo = Some(stereo);
+    }
+    if let Some(error) = state.transformation_error() {
+        return Err(format!("Camera: Invalid transformation - {}", error).into())
+    }
     scene.camera_desc.camera_to_world = Some(state.current_transformation().inverse());
+    scene.camera_medium = state.current_medium_interface().outside;
     Ok(result)
 }
 
@@ -295,15 +572,70 @@ fn process_integrator(tokenizer: &mut PBRTTokenizer, scene: &mut SceneDescriptio
         "direct_lighting" => direct_lighting_integrator(tokenizer, scene, state),
         "ambientocclusion" => ambientocclusion_integrator(tokenizer, scene, state),
         "randomwalk" => randomwalk_integrator(tokenizer, scene, state),
+        "furnace" => furnace_integrator(tokenizer, scene, state),
+        "sppm" => sppm_integrator(tokenizer, scene, state),
+        "intersector" => intersector_integrator(tokenizer, scene, state),
         _=> Err(format!("Unsupported integrator type {}", token).into())
     }
 }
 
 fn direct_lighting_integrator(tokenizer: &mut PBRTTokenizer, scene: &mut SceneDescription,
-                                      _state: &mut ParseState) -> Result<Option<String>, Box<dyn Error>> {
+                                      state: &mut ParseState) -> Result<Option<String>, Box<dyn Error>> {
 
-    scene.settings.rendering_algorithm = RenderingAlgorithm::DirectLighting;                                   
-    Ok(next_directive(tokenizer))
+    let mut strategy = LightStrategy::All;
+    let mut max_component: Option<f32> = None;
+    let mut restir = RestirProperties::default();
+    let mut light_sampler = LightSamplerType::Uniform;
+    let mut mis = true;
+    let mut indirect_diffuse = false;
+    let mut irradiance_cache = IrradianceCacheProperties::default();
+
+    let mut process_attribute = |tokenizer: &mut PBRTTokenizer, token: &str| -> Result<(), Box<dyn Error>> {
+        match token {
+            "string strategy" => strategy = parse_light_strategy(&extract_value::<String>(tokenizer, "DirectLighting::strategy - ")?)?,
+            "float maxcomponent" => max_component = Some(extract_value(tokenizer, "DirectLighting::maxcomponent - ")?),
+            "string lightsampler" => {
+                let name: String = extract_value(tokenizer, "DirectLighting::lightsampler - ")?;
+                light_sampler = match LightSamplerType::from_name(&name) {
+                    Some(typ) => typ,
+                    None => return Err(format!("DirectLighting::lightsampler - Unsupported light sampler {}", name).into())
+                };
+            }
+            "bool mis" => mis = extract_value(tokenizer, "DirectLighting::mis - ")?,
+            "bool indirectdiffuse" => indirect_diffuse = extract_value(tokenizer, "DirectLighting::indirectdiffuse - ")?,
+            "bool irradiancecache" => irradiance_cache.enabled = extract_value(tokenizer, "DirectLighting::irradiancecache - ")?,
+            "integer irradiancesamples" => irradiance_cache.samples = extract_value::<usize>(tokenizer, "DirectLighting::irradiancesamples - ")?.max(1),
+            "float maxerror" => irradiance_cache.max_error = extract_value(tokenizer, "DirectLighting::maxerror - ")?,
+            "float minspacing" => irradiance_cache.min_spacing = extract_value(tokenizer, "DirectLighting::minspacing - ")?,
+            "float maxspacing" => irradiance_cache.max_spacing = extract_value(tokenizer, "DirectLighting::maxspacing - ")?,
+            "integer candidates" => restir.candidates = extract_value(tokenizer, "DirectLighting::candidates - ")?,
+            "integer spatialsamples" => restir.spatial_samples = extract_value(tokenizer, "DirectLighting::spatialsamples - ")?,
+            "integer spatialradius" => restir.spatial_radius = extract_value(tokenizer, "DirectLighting::spatialradius - ")?,
+            _ => return Err(format!("Unsupported parameter in direct lighting integrator: {}", token).into())
+        }
+        Ok(())
+    };
+    let result = process_attributes(tokenizer, state, &mut process_attribute)?;
+
+    if let LightStrategy::Restir(_) = strategy {
+        strategy = LightStrategy::Restir(restir);
+    }
+    scene.settings.light_strategy = strategy;
+    scene.settings.light_sampler = light_sampler;
+    scene.settings.mis = mis;
+    scene.settings.irradiance_cache = if indirect_diffuse { Some(irradiance_cache) } else { None };
+    scene.settings.max_component = max_component;
+    scene.settings.rendering_algorithm = RenderingAlgorithm::DirectLighting;
+    Ok(result)
+}
+
+fn parse_light_strategy(name: &str) -> Result<LightStrategy, Box<dyn Error>> {
+    match name {
+        "all" => Ok(LightStrategy::All),
+        "one" => Ok(LightStrategy::One),
+        "restir" => Ok(LightStrategy::Restir(RestirProperties::default())),
+        _ => Err(format!("DirectLighting::strategy - Unsupported strategy {}", name).into())
+    }
 }
 
 fn ambientocclusion_integrator(tokenizer: &mut PBRTTokenizer, scene: &mut SceneDescription,
@@ -315,6 +647,8 @@ fn ambientocclusion_integrator(tokenizer: &mut PBRTTokenizer, scene: &mut SceneD
         match token {
             "bool cossample" => settings.cossample = extract_value(tokenizer, "Ambientocclusion::cossample - ")?,
             "float maxdistance" => settings.maxdistance = extract_value(tokenizer, "Ambientocclusion::maxdistance - ")?,
+            "integer nsamples" => settings.nsamples = extract_value::<u32>(tokenizer, "Ambientocclusion::nsamples - ")?.max(1),
+            "string bentnormals" => settings.bent_normal_output = Some(extract_value(tokenizer, "Ambientocclusion::bentnormals - ")?),
             _ => return Err(format!("Unsupported parameter in ambient occlusion integrator: {}", token).into())
         }
         Ok(())
@@ -325,24 +659,104 @@ fn ambientocclusion_integrator(tokenizer: &mut PBRTTokenizer, scene: &mut SceneD
     Ok(result)
 }
 
+fn intersector_integrator(tokenizer: &mut PBRTTokenizer, scene: &mut SceneDescription,
+                          state: &mut ParseState) -> Result<Option<String>, Box<dyn Error>> {
+
+    let mut settings = IntersectorProperties::default();
+
+    let mut process_attribute = |tokenizer: &mut PBRTTokenizer, token: &str| -> Result<(), Box<dyn Error>> {
+        match token {
+            "string shading" => settings.shading = parse_preview_shading(&extract_value::<String>(tokenizer, "Intersector::shading - ")?)?,
+            _ => return Err(format!("Unsupported parameter in intersector integrator: {}", token).into())
+        }
+        Ok(())
+    };
+    let result = process_attributes(tokenizer, state, &mut process_attribute)?;
+
+    scene.settings.rendering_algorithm = RenderingAlgorithm::Intersector(settings);
+    Ok(result)
+}
+
+fn parse_preview_shading(name: &str) -> Result<PreviewShading, Box<dyn Error>> {
+    match name {
+        "facing" => Ok(PreviewShading::Facing),
+        "albedo" => Ok(PreviewShading::Albedo),
+        _ => Err(format!("Intersector::shading - Unsupported shading {}", name).into())
+    }
+}
+
 fn randomwalk_integrator(tokenizer: &mut PBRTTokenizer, scene: &mut SceneDescription,
                          state: &mut ParseState) -> Result<Option<String>, Box<dyn Error>> {
 
     let mut settings = RandomWalkProperties::default();
+    let mut max_component: Option<f32> = None;
+    let mut guiding = false;
+    let mut guiding_settings = PathGuidingProperties::default();
 
     let mut process_attribute = |tokenizer: &mut PBRTTokenizer, token: &str| -> Result<(), Box<dyn Error>> {
         match token {
             "integer maxdepth" => settings.maxdepth = extract_value(tokenizer, "Randomwalk::maxdepth - ")?,
+            "integer rrdepth" => settings.rrdepth = extract_value(tokenizer, "Randomwalk::rrdepth - ")?,
+            "float maxcomponent" => max_component = Some(extract_value(tokenizer, "Randomwalk::maxcomponent - ")?),
+            "bool wavefront" => settings.wavefront = extract_value(tokenizer, "Randomwalk::wavefront - ")?,
+            "bool guiding" => guiding = extract_value(tokenizer, "Randomwalk::guiding - ")?,
+            "integer guidingiterations" => guiding_settings.iterations = extract_value(tokenizer, "Randomwalk::guidingiterations - ")?,
+            "float bsdffraction" => guiding_settings.bsdf_fraction = extract_value::<f32>(tokenizer, "Randomwalk::bsdffraction - ")?.clamp(0.0, 1.0),
+            "float spatialthreshold" => guiding_settings.spatial_threshold = extract_value(tokenizer, "Randomwalk::spatialthreshold - ")?,
+            "float directionalthreshold" => guiding_settings.directional_threshold = extract_value(tokenizer, "Randomwalk::directionalthreshold - ")?,
             _ => return Err(format!("Unsupported parameter in random walk integrator: {}", token).into())
         }
         Ok(())
     };
     let result = process_attributes(tokenizer, state, &mut process_attribute)?;
 
+    scene.settings.max_component = max_component;
+    scene.settings.path_guiding = if guiding { Some(guiding_settings) } else { None };
     scene.settings.rendering_algorithm = RenderingAlgorithm::RandomWalk(settings);                                                                      
     Ok(result)
 }
 
+fn furnace_integrator(tokenizer: &mut PBRTTokenizer, scene: &mut SceneDescription,
+                      state: &mut ParseState) -> Result<Option<String>, Box<dyn Error>> {
+
+    let mut settings = FurnaceProperties::default();
+
+    let mut process_attribute = |tokenizer: &mut PBRTTokenizer, token: &str| -> Result<(), Box<dyn Error>> {
+        match token {
+            "integer maxdepth" => settings.maxdepth = extract_value(tokenizer, "Furnace::maxdepth - ")?,
+            "float radiance" => settings.radiance = extract_value(tokenizer, "Furnace::radiance - ")?,
+            "string material" => settings.material = Some(extract_value(tokenizer, "Furnace::material - ")?),
+            _ => return Err(format!("Unsupported parameter in furnace integrator: {}", token).into())
+        }
+        Ok(())
+    };
+    let result = process_attributes(tokenizer, state, &mut process_attribute)?;
+
+    scene.settings.rendering_algorithm = RenderingAlgorithm::Furnace(settings);
+    Ok(result)
+}
+
+fn sppm_integrator(tokenizer: &mut PBRTTokenizer, scene: &mut SceneDescription,
+                   state: &mut ParseState) -> Result<Option<String>, Box<dyn Error>> {
+
+    let mut settings = SppmProperties::default();
+
+    let mut process_attribute = |tokenizer: &mut PBRTTokenizer, token: &str| -> Result<(), Box<dyn Error>> {
+        match token {
+            "integer iterations" => settings.iterations = extract_value(tokenizer, "Sppm::iterations - ")?,
+            "integer photonsperiteration" => settings.photons_per_iteration = extract_value(tokenizer, "Sppm::photonsperiteration - ")?,
+            "integer maxdepth" => settings.maxdepth = extract_value(tokenizer, "Sppm::maxdepth - ")?,
+            "float radius" => settings.initial_radius = extract_value(tokenizer, "Sppm::radius - ")?,
+            _ => return Err(format!("Unsupported parameter in sppm integrator: {}", token).into())
+        }
+        Ok(())
+    };
+    let result = process_attributes(tokenizer, state, &mut process_attribute)?;
+
+    scene.settings.rendering_algorithm = RenderingAlgorithm::Sppm(settings);
+    Ok(result)
+}
+
 
 fn process_attributes(tokenizer: &mut PBRTTokenizer,
                       state: &mut ParseState,
@@ -380,23 +794,134 @@ fn process_rgb_film(tokenizer: &mut PBRTTokenizer, scene: &mut SceneDescription,
     let mut xresolution: usize = 1280;
     let mut yresolution: usize = 720;
     let mut filename: String = "".to_string();
+    let mut lpes: Vec<String> = Vec::new();
+    let mut aovs: Vec<String> = Vec::new();
+    let mut ao_output: Option<String> = None;
+    let mut sample_count_output: Option<String> = None;
+    let mut bucket_output: Option<String> = None;
+    let mut ray_log: Option<String> = None;
+    let mut ray_log_pixel: Vec<u32> = vec![0, 0];
+    let mut ray_log_rays: Vec<String> = vec!["primary".to_string(), "shadow".to_string(), "indirect".to_string()];
+    let mut checkpoint: Option<String> = None;
+    let mut checkpoint_interval: f32 = 60.0;
+    let mut checkpoint_resume = true;
+    let mut tile_importance = false;
+    let mut tile_importance_settings = TileImportanceProperties::default();
+    let mut bake_mesh: Option<usize> = None;
+    let mut warm_start: Option<String> = None;
+    let mut warm_start_weight: f32 = 1.0;
 
     let mut process_attribute = |tokenizer: &mut PBRTTokenizer, token: &str| -> Result<(), Box<dyn Error>> {
         match token {
             "integer xresolution" => xresolution = extract_value(tokenizer, "Film::xresolution - ")?,
             "integer yresolution" => yresolution = extract_value(tokenizer, "Film::yresolution - ")?,
             "string filename" => filename = extract_value(tokenizer, "Film::filename - ")?,
+            "string lpes" => lpes = parse_string_array(tokenizer, "Film::lpes - ")?,
+            "string aovs" => aovs = parse_string_array(tokenizer, "Film::aovs - ")?,
+            "string aooutput" => ao_output = Some(extract_value(tokenizer, "Film::aooutput - ")?),
+            "string samplecountoutput" => sample_count_output = Some(extract_value(tokenizer, "Film::samplecountoutput - ")?),
+            "string bucketoutput" => bucket_output = Some(extract_value(tokenizer, "Film::bucketoutput - ")?),
+            "string raylog" => ray_log = Some(extract_value(tokenizer, "Film::raylog - ")?),
+            "integer raylogpixel" => ray_log_pixel = parse_u32_array(tokenizer, "Film::raylogpixel - ")?,
+            "string raylograys" => ray_log_rays = parse_string_array(tokenizer, "Film::raylograys - ")?,
+            "string checkpoint" => checkpoint = Some(extract_value(tokenizer, "Film::checkpoint - ")?),
+            "float checkpointinterval" => checkpoint_interval = extract_value(tokenizer, "Film::checkpointinterval - ")?,
+            "bool checkpointresume" => checkpoint_resume = extract_value(tokenizer, "Film::checkpointresume - ")?,
+            "bool tileimportance" => tile_importance = extract_value(tokenizer, "Film::tileimportance - ")?,
+            "integer prepasssamples" => tile_importance_settings.prepass_spp = extract_value::<usize>(tokenizer, "Film::prepasssamples - ")?.max(2),
+            "integer minsamples" => tile_importance_settings.min_spp = extract_value::<usize>(tokenizer, "Film::minsamples - ")?.max(1),
+            "integer bakemesh" => bake_mesh = Some(extract_value(tokenizer, "Film::bakemesh - ")?),
+            "string warmstart" => warm_start = Some(extract_value(tokenizer, "Film::warmstart - ")?),
+            "float warmstartweight" => warm_start_weight = extract_value(tokenizer, "Film::warmstartweight - ")?,
+            "bool telemetry" => scene.settings.telemetry = extract_value(tokenizer, "Film::telemetry - ")?,
             _ => return Err(format!("Unsupported parameter in Rgb film: {}", token).into())
         }
         Ok(())
     };
     let result = process_attributes(tokenizer, state, &mut process_attribute)?;
 
+    // NOTE: light path expressions are given as pairs - expression, filename
+    if !lpes.len().is_multiple_of(2) {
+        return Err("Film::lpes - Pairs of expression and filename expected!".into());
+    }
+    for pair in lpes.chunks_exact(2) {
+        scene.settings.lpes.push(LpeOutput { expression: pair[0].clone(), output_fname: pair[1].clone() });
+    }
+    // NOTE: auxiliary outputs are also given as pairs - type, filename
+    if !aovs.len().is_multiple_of(2) {
+        return Err("Film::aovs - Pairs of type and filename expected!".into());
+    }
+    for pair in aovs.chunks_exact(2) {
+        let typ = match AovType::from_name(&pair[0]) {
+            Some(typ) => typ,
+            None => return Err(format!("Film::aovs - Unsupported auxiliary output type: {}", pair[0]).into())
+        };
+        scene.settings.aovs.push(AovOutput { typ, output_fname: pair[1].clone() });
+    }
+    if let Some(output_fname) = ao_output {
+        let settings = AmbientOcclusionProperties::default();
+        scene.settings.ao_output = Some(AmbientOcclusionOutput { settings, output_fname });
+    }
+    scene.settings.sample_count_output = sample_count_output;
+    scene.settings.bucket_output = bucket_output;
+    if let Some(output_fname) = ray_log {
+        if ray_log_pixel.len() != 2 {
+            return Err("Film::raylogpixel - Two coordinates expected!".into());
+        }
+        let mut kinds = Vec::new();
+        for name in ray_log_rays.iter() {
+            match RayKind::from_name(name) {
+                Some(kind) => kinds.push(kind),
+                None => return Err(format!("Film::raylograys - Unsupported kind of rays: {}", name).into())
+            }
+        }
+        let (x, y) = (ray_log_pixel[0] as usize, ray_log_pixel[1] as usize);
+        scene.settings.ray_log = Some(RayLogOutput { x, y, kinds, output_fname });
+    }
+    scene.settings.checkpoint = checkpoint.map(|output_fname| {
+        CheckpointOutput { output_fname, interval: checkpoint_interval, resume: checkpoint_resume }
+    });
+    scene.settings.tile_importance = tile_importance.then_some(tile_importance_settings);
+    scene.settings.bake = bake_mesh.map(|mesh| BakeProperties { mesh });
+    scene.settings.warm_start = warm_start.map(|image| {
+        WarmStartProperties { image: create_path(state, &image), weight: warm_start_weight }
+    });
     scene.set_resolution(ImageSize::new(xresolution, yresolution));
     scene.settings.output_fname = filename;
     Ok(result)
 }
 
+fn process_accelerator(tokenizer: &mut PBRTTokenizer, scene: &mut SceneDescription,
+                       state: &mut ParseState) -> Result<Option<String>, Box<dyn Error>> {
+
+    let accelerator_type = match tokenizer.next() {
+        Some(token) => token.trim().to_string(),
+        None => return Err("Missing accelerator type token!".to_string().into())
+    };
+    let mut split_method = "sah".to_string();
+
+    let mut process_attribute = |tokenizer: &mut PBRTTokenizer, token: &str| -> Result<(), Box<dyn Error>> {
+        match token {
+            "string splitmethod" => split_method = extract_value(tokenizer, "Accelerator::splitmethod - ")?,
+            // NOTE: leaves of BVH have fixed maximal size
+            "integer maxnodeprims" => { extract_value::<i32>(tokenizer, "Accelerator::maxnodeprims - ")?; }
+            _ => return Err(format!("Unsupported parameter in accelerator: {}", token).into())
+        }
+        Ok(())
+    };
+    let result = process_attributes(tokenizer, state, &mut process_attribute)?;
+
+    scene.settings.accelerator = match accelerator_type.as_str() {
+        "bvh" => match BVHBuildMethod::from_name(&split_method) {
+            Some(method) => AcceleratorType::Bvh(method),
+            None => return Err(format!("Accelerator: Unsupported split method - {}", split_method).into())
+        },
+        "linear" => AcceleratorType::Linear,
+        _ => return Err(format!("Accelerator: Unsupported accelerator type - {}", accelerator_type).into())
+    };
+    Ok(result)
+}
+
 fn process_sampler(tokenizer: &mut PBRTTokenizer, scene: &mut SceneDescription,
                   state: &mut ParseState) -> Result<Option<String>, Box<dyn Error>> {
 
@@ -526,41 +1051,31 @@ fn process_filter_data(tokenizer: &mut PBRTTokenizer, scene: &mut SceneDescripti
 fn process_material(tokenizer: &mut PBRTTokenizer, scene: &mut SceneDescription,
                       state: &mut ParseState) -> Result<Option<String>, Box<dyn Error>> {
     let material_type = match tokenizer.next() {
-        Some(token) => token.trim(),
+        Some(token) => token.trim().to_string(),
         None => return Err("Material: Type of material not specified!".into())
     };
-    // TODO improve this - use unique name
-    let name = format!("Material_generated_name_17654_{}", scene.materials.len());
-    let result = match material_type {
-        "diffuse" => process_diffuse_material(tokenizer, scene, state, &name),
-        _=> Err(format!("Unsupported material type {}", material_type).into())
-    };
+    material_type_from_name(&material_type)?;
+    let (mut desc, result) = process_material_parameters(tokenizer, state, Some(material_type))?;
+    desc.name = format!("{}material", state.name_prefix);
+    let name = add_anonymous_material(scene, state, desc);
     state.set_material(name);
-    result
+    Ok(result)
 }
 
 fn process_make_named_material(tokenizer: &mut PBRTTokenizer, scene: &mut SceneDescription,
                       state: &mut ParseState) -> Result<Option<String>, Box<dyn Error>> {
     let name = match tokenizer.next() {
-        Some(token) => token.trim(),
+        Some(token) => token.trim().to_string(),
         None => return Err("Make Named Material: Name of material not specified!".into())
     };
-
-    let string_type = match tokenizer.next() {
-        Some(token) => token.trim(),
-        None => return Err("Make Named Material: Data type 'string type' of material not specified!".into())
-    };
-
-    if string_type != "string type" {
-        return Err(format!("Make Named Material: 'string type' is expected! - {}", string_type).into());
-    }
-
-    let material_type: String = extract_value(tokenizer, "Make Named Material: Type of material - ")?;
-
-    match material_type.as_str() {
-        "diffuse" => process_diffuse_material(tokenizer, scene, state, name),
-        _=> Err(format!("Make Named Material: Unsupported material type {}", material_type).into())
-    }
+    // NOTE: type is ordinary "string type" parameter and it can appear anywhere in the parameter list
+    let (mut desc, result) = process_material_parameters(tokenizer, state, None)
+        .map_err(|e| format!("Make Named Material {}: {}", name, e))?;
+    // NOTE: same name can be defined in different attribute blocks, so material gets unique name in scene
+    desc.name = format!("{}{}", state.name_prefix, name);
+    let material_name = scene.add_material(desc);
+    state.define_named_material(name, material_name);
+    Ok(result)
 }
 
 fn process_named_material(tokenizer: &mut PBRTTokenizer, _scene: &mut SceneDescription,
@@ -569,40 +1084,383 @@ fn process_named_material(tokenizer: &mut PBRTTokenizer, _scene: &mut SceneDescr
         Some(token) => token.trim(),
         None => return Err("Named Material: Name of material not specified!".into())
     };
-    state.set_material(name.to_string());
+    let material = match state.named_material(name) {
+        Some(material) => material,
+        None => return Err(format!("Named Material: Material {} is not defined in current scope!", name).into())
+    };
+    state.set_material(material);
     Ok(next_directive(tokenizer))
 }
 
-fn process_diffuse_material(tokenizer: &mut PBRTTokenizer, scene: &mut SceneDescription,
-                              state: &mut ParseState, name: &str) -> Result<Option<String>, Box<dyn Error>> {
+fn material_type_from_name(material_type: &str) -> Result<MaterialType, Box<dyn Error>> {
+    match material_type {
+        "diffuse" => Ok(MaterialType::Matte),
+        "thindielectric" => Ok(MaterialType::ThinDielectric),
+        "diffusetransmission" => Ok(MaterialType::DiffuseTransmission),
+        "interface" => Ok(MaterialType::Interface),
+        "conductor" => Ok(MaterialType::Conductor),
+        "dielectric" => Ok(MaterialType::Dielectric),
+        "hair" => Ok(MaterialType::Hair),
+        _ => Err(format!("Unsupported material type {}", material_type).into())
+    }
+}
+
+// Named spectra of metals (e.g. metal-Au-eta, metal-Cu-k)
+fn named_conductor_spectrum(name: &str) -> Result<RGB, Box<dyn Error>> {
+    let parts: Vec<&str> = name.split('-').collect();
+    let preset = match parts.as_slice() {
+        ["metal", metal, _] => conductor_preset(metal),
+        _ => None
+    };
+    match (preset, parts.last()) {
+        (Some((eta, _)), Some(&"eta")) => Ok(eta),
+        (Some((_, k)), Some(&"k")) => Ok(k),
+        _ => Err(format!("Unsupported named spectrum {}", name).into())
+    }
+}
+
+// Parameters of all material types are parsed here, so Material and MakeNamedMaterial share one path.
+fn process_material_parameters(tokenizer: &mut PBRTTokenizer, state: &mut ParseState, material_type: Option<String>)
+                               -> Result<(MaterialDescription, Option<String>), Box<dyn Error>> {
 
     let mut desc = MaterialDescription::default();
+    let mut material_type = material_type;
+    let mut reflectance = None;
+    let mut scale = None;
+    let mut conductor_eta = None;
+    let mut conductor_k = None;
+    let mut eta_spectrum: Option<String> = None;
+    let mut reflectance_texture: Option<String> = None;
+    let mut roughness_texture: Option<String> = None;
+    let mut eta = None;
+    let mut sigma_a = None;
+    let mut eumelanin = None;
+    let mut pheomelanin = None;
 
     let mut process_attribute = |tokenizer: &mut PBRTTokenizer, token: &str| -> Result<(), Box<dyn Error>> {
         match token {
-            "rgb reflectance" => desc.diffuse = parse_rgb(tokenizer, "Material:rgb ")?,
-            _ => return Err(format!("Unsupported parameter in diffuse material: {}", token).into())
+            "string type" => material_type = Some(extract_value(tokenizer, "Material:type - ")?),
+            "rgb reflectance" => reflectance = Some(parse_rgb(tokenizer, "Material:rgb ")?),
+            "texture reflectance" => reflectance_texture = Some(extract_value(tokenizer, "Material:reflectance - ")?),
+            "rgb transmittance" => desc.transmittance = parse_rgb(tokenizer, "Material:transmittance ")?,
+            "float scale" => scale = Some(extract_value::<f32>(tokenizer, "Material:scale - ")?),
+            "float eta" => eta = Some(extract_value(tokenizer, "Material:eta - ")?),
+            "float roughness" => desc.roughness = extract_value(tokenizer, "Material:roughness - ")?,
+            "texture roughness" => roughness_texture = Some(extract_value(tokenizer, "Material:roughness - ")?),
+            "float uroughness" => desc.uroughness = Some(extract_value(tokenizer, "Material:uroughness - ")?),
+            "float vroughness" => desc.vroughness = Some(extract_value(tokenizer, "Material:vroughness - ")?),
+            "bool remaproughness" => desc.remap_roughness = extract_value(tokenizer, "Material:remaproughness - ")?,
+            "spectrum eta" => eta_spectrum = Some(extract_value(tokenizer, "Material:eta - ")?),
+            "spectrum k" => conductor_k = Some(named_conductor_spectrum(&extract_value::<String>(tokenizer, "Material:k - ")?)?),
+            "rgb eta" => conductor_eta = Some(parse_rgb(tokenizer, "Material:eta ")?),
+            "rgb k" => conductor_k = Some(parse_rgb(tokenizer, "Material:k ")?),
+            "rgb sigma_a" => sigma_a = Some(parse_rgb(tokenizer, "Material:sigma_a ")?),
+            "float eumelanin" => eumelanin = Some(extract_value(tokenizer, "Material:eumelanin - ")?),
+            "float pheomelanin" => pheomelanin = Some(extract_value(tokenizer, "Material:pheomelanin - ")?),
+            "float beta_m" => desc.hair.beta_m = extract_value(tokenizer, "Material:beta_m - ")?,
+            "float beta_n" => desc.hair.beta_n = extract_value(tokenizer, "Material:beta_n - ")?,
+            "float alpha" => desc.hair.alpha = extract_value(tokenizer, "Material:alpha - ")?,
+            _ => return Err(format!("Unsupported parameter in material: {}", token).into())
         }
         Ok(())
     };
     let result = process_attributes(tokenizer, state, &mut process_attribute)?;
 
-    desc.name = name.to_string();
-    desc.typ = MaterialType::Matte;
-    scene.materials.push(desc); 
+    let texture = |name: Option<String>| -> Result<Option<String>, Box<dyn Error>> {
+        match name {
+            Some(name) => match state.textures.get(&name) {
+                Some(texture) => Ok(Some(texture.clone())),
+                None => Err(format!("Texture {} is not defined!", name).into())
+            },
+            None => Ok(None)
+        }
+    };
+    desc.diffuse_texture = texture(reflectance_texture)?;
+    desc.roughness_texture = texture(roughness_texture)?;
+
+    desc.typ = match material_type {
+        Some(material_type) => material_type_from_name(&material_type)?,
+        None => return Err("Type of material not specified!".into())
+    };
+    // NOTE: pbrt uses different default reflectance for diffuse transmission
+    desc.diffuse = match (reflectance, desc.typ) {
+        (Some(reflectance), _) => reflectance,
+        (None, MaterialType::DiffuseTransmission) => RGB::new(0.25, 0.25, 0.25),
+        (None, _) => desc.diffuse
+    };
+    if desc.typ == MaterialType::Hair {
+        desc.hair.eta = eta.unwrap_or(desc.hair.eta);
+        // NOTE: absorption is given directly, by color of hair or by melanin, eumelanin defaults to 1.3 if nothing is given
+        desc.hair.sigma_a = match (sigma_a, reflectance, eumelanin, pheomelanin) {
+            (Some(sigma_a), _, _, _) => sigma_a,
+            (None, Some(color), _, _) => sigma_a_from_reflectance(color, desc.hair.beta_n),
+            (None, None, None, None) => sigma_a_from_melanin(1.3, 0.0),
+            (None, None, eumelanin, pheomelanin) => sigma_a_from_melanin(eumelanin.unwrap_or(0.0), pheomelanin.unwrap_or(0.0))
+        };
+    } else if let Some(eta) = eta {
+        desc.eta = eta;
+    }
+    // NOTE: as in pbrt scale is parameter only of diffuse transmission
+    match (scale, desc.typ) {
+        (Some(scale), MaterialType::DiffuseTransmission) => {
+            desc.diffuse = desc.diffuse * scale;
+            desc.transmittance = desc.transmittance * scale;
+        }
+        (Some(_), _) => return Err("Material: scale is supported only by diffusetransmission material!".into()),
+        (None, _) => {}
+    }
+    // NOTE: named spectrum of eta is glass or metal depending on type of material
+    if let Some(name) = eta_spectrum {
+        match desc.typ {
+            MaterialType::Dielectric | MaterialType::ThinDielectric => desc.eta = match glass_ior(&name) {
+                Some(eta) => eta,
+                None => return Err(format!("Unsupported named spectrum {}", name).into())
+            },
+            _ => conductor_eta = Some(named_conductor_spectrum(&name)?)
+        }
+    }
+    if desc.typ == MaterialType::Conductor {
+        // NOTE: reflectance of conductor replaces its index of refraction
+        (desc.conductor_eta, desc.conductor_k) = match reflectance {
+            Some(reflectance) => conductor_from_reflectance(reflectance),
+            None => (conductor_eta.unwrap_or(desc.conductor_eta), conductor_k.unwrap_or(desc.conductor_k))
+        };
+    }
+    Ok((desc, result))
+}
+
+fn texture_type_from_class(class: &str) -> Option<TextureType> {
+    match class {
+        "imagemap" => Some(TextureType::Image),
+        "constant" => Some(TextureType::Constant),
+        "checkerboard" => Some(TextureType::Checkerboard),
+        "scale" => Some(TextureType::Scale),
+        "mix" => Some(TextureType::Mix),
+        "fbm" => Some(TextureType::Fbm),
+        "marble" => Some(TextureType::Marble),
+        _ => None
+    }
+}
+
+// Parameter of texture that can be value or other texture (e.g. "float tex1" 0.5, "texture tex1" "grid")
+fn parse_texture_input(tokenizer: &mut PBRTTokenizer, value_type: &str, err_msg: &str) -> Result<TextureInput, Box<dyn Error>> {
+    match value_type {
+        "float" => Ok(TextureInput::constant(extract_value(tokenizer, err_msg)?)),
+        "rgb" => Ok(TextureInput::Value(parse_rgb(tokenizer, err_msg)?)),
+        "texture" => Ok(TextureInput::Texture(extract_value(tokenizer, err_msg)?)),
+        _ => Err(format!("{}Unsupported type {}", err_msg, value_type).into())
+    }
+}
+
+fn process_texture(tokenizer: &mut PBRTTokenizer, scene: &mut SceneDescription,
+                   state: &mut ParseState) -> Result<Option<String>, Box<dyn Error>> {
+    let mut next = |what: &str| -> Result<String, Box<dyn Error>> {
+        match tokenizer.next() {
+            Some(token) => Ok(token.trim().to_string()),
+            None => Err(format!("Texture: {} of texture not specified!", what).into())
+        }
+    };
+    let name = next("Name")?;
+    // NOTE: both spectrum and float textures are RGB textures, float value is average of channels
+    let value_type = next("Type")?;
+    if value_type != "spectrum" && value_type != "float" {
+        return Err(format!("Texture {}: Unsupported texture type {}", name, value_type).into())
+    }
+    let class = next("Class")?;
+    let typ = match texture_type_from_class(&class) {
+        Some(typ) => typ,
+        None => return Err(format!("Texture {}: Unsupported texture class {}", name, class).into())
+    };
+    if state.textures.contains_key(&name) {
+        return Err(format!("Texture {}: Texture is already defined!", name).into())
+    }
+
+    let mut desc = TextureDescription::new(&format!("{}{}", state.name_prefix, name), typ);
+    // NOTE: float textures are data (roughness, alpha) and their images are linear by default
+    if value_type == "float" {
+        desc.encoding = ColorEncoding::Linear;
+    }
+    let mut process_attribute = |tokenizer: &mut PBRTTokenizer, token: &str| -> Result<(), Box<dyn Error>> {
+        let (param_type, param) = token.split_once(' ').unwrap_or(("", token));
+        match (param_type, param) {
+            ("string", "filename") => desc.filename = Some(extract_value(tokenizer, "Texture:filename - ")?),
+            ("bool", "invert") => desc.invert = extract_value(tokenizer, "Texture:invert - ")?,
+            ("string", "encoding") => {
+                let encoding: String = extract_value(tokenizer, "Texture:encoding - ")?;
+                desc.encoding = match ColorEncoding::from_name(&encoding) {
+                    Some(encoding) => encoding,
+                    None => return Err(format!("Texture:encoding - Unsupported encoding {}", encoding).into())
+                };
+            }
+            // NOTE: scale of scale texture can be texture, scale of image and marble is number
+            (_, "scale") if typ == TextureType::Scale => desc.tex2 = parse_texture_input(tokenizer, param_type, "Texture:scale - ")?,
+            ("float", "scale") => desc.scale = extract_value(tokenizer, "Texture:scale - ")?,
+            (_, "tex") | (_, "tex1") | (_, "value") => desc.tex1 = parse_texture_input(tokenizer, param_type, "Texture:tex1 - ")?,
+            (_, "tex2") => desc.tex2 = parse_texture_input(tokenizer, param_type, "Texture:tex2 - ")?,
+            (_, "amount") => desc.amount = parse_texture_input(tokenizer, param_type, "Texture:amount - ")?,
+            ("float", "uscale") => desc.uscale = extract_value(tokenizer, "Texture:uscale - ")?,
+            ("float", "vscale") => desc.vscale = extract_value(tokenizer, "Texture:vscale - ")?,
+            ("integer", "dimension") => {
+                if extract_value::<u32>(tokenizer, "Texture:dimension - ")? != 2 {
+                    return Err("Texture:dimension - Only 2D checkerboard is supported!".into())
+                }
+            }
+            ("integer", "octaves") => desc.octaves = extract_value(tokenizer, "Texture:octaves - ")?,
+            ("float", "roughness") => desc.roughness = extract_value(tokenizer, "Texture:roughness - ")?,
+            ("float", "variation") => desc.variation = extract_value(tokenizer, "Texture:variation - ")?,
+            ("string", "space") => {
+                let space: String = extract_value(tokenizer, "Texture:space - ")?;
+                desc.space = match CoordinateSpace::from_name(&space) {
+                    Some(space) => space,
+                    None => return Err(format!("Texture:space - Unsupported space {}", space).into())
+                };
+            }
+            // NOTE: images are always repeated and filtered bilinearly, other modes are replaced with a warning
+            ("string", "filter") => {
+                let filter: String = extract_value(tokenizer, "Texture:filter - ")?;
+                if filter != "bilinear" {
+                    println!("Warning: Texture {}: filter {} is not supported, bilinear filter is used", name, filter);
+                }
+            }
+            ("string", "wrap") => {
+                let wrap: String = extract_value(tokenizer, "Texture:wrap - ")?;
+                if wrap != "repeat" {
+                    println!("Warning: Texture {}: wrap mode {} is not supported, image is repeated", name, wrap);
+                }
+            }
+            ("float", "maxanisotropy") => { extract_value::<f32>(tokenizer, "Texture:maxanisotropy - ")?; }
+            _ => return Err(format!("Unsupported parameter in texture: {}", token).into())
+        }
+        Ok(())
+    };
+    let result = process_attributes(tokenizer, state, &mut process_attribute)?;
+
+    // NOTE: textures are referenced by names in file, they are renamed to names in scene
+    for input in [&mut desc.tex1, &mut desc.tex2, &mut desc.amount] {
+        if let TextureInput::Texture(texture) = input {
+            match state.textures.get(texture.as_str()) {
+                Some(scene_name) => *texture = scene_name.clone(),
+                None => return Err(format!("Texture {}: Texture {} is not defined!", name, texture).into())
+            }
+        }
+    }
+    if typ == TextureType::Image {
+        desc.filename = match desc.filename {
+            Some(fname) => Some(create_path(state, &fname)),
+            None => return Err(format!("Texture {}: Image file not specified!", name).into())
+        };
+    }
+    // NOTE: transformation in effect at declaration places solid textures
+    if !state.current_transformation().is_identity() {
+        desc.transform = Some(state.current_transformation());
+    }
+    state.textures.insert(name, desc.name.clone());
+    scene.textures.push(desc);
     Ok(result)
 }
 
+// Identical anonymous materials (e.g. thousands of equal emitters) share one description in scene
+fn add_anonymous_material(scene: &mut SceneDescription, state: &mut ParseState, desc: MaterialDescription) -> String {
+    let key = desc.key();
+    if let Some(name) = state.material_cache.get(&key) {
+        return name.clone();
+    }
+    let name = scene.add_material(desc);
+    state.material_cache.insert(key, name.clone());
+    name
+}
+
 fn process_light(tokenizer: &mut PBRTTokenizer, scene: &mut SceneDescription,
                       state: &mut ParseState) -> Result<Option<String>, Box<dyn Error>> {
     let token = match tokenizer.next() {
         Some(token) => token.trim(),
         None => return Err("Light: Type of light not specified!".into())
     };
-    match token {
-        "point" => process_point_light(tokenizer, scene, state),
-        _=> Err(format!("Unsupported light type {}", token).into())
+    let (object, shapes, lights) = (format!("Light {}", token), scene.shapes.len(), scene.lights.len());
+    let result = match token {
+        "point" => process_point_light(tokenizer, scene, state)?,
+        "infinite" => process_infinite_light(tokenizer, scene, state)?,
+        "sky" => process_sky_light(tokenizer, scene, state)?,
+        _=> return Err(format!("Unsupported light type {}", token).into())
+    };
+    check_object_transformation(&object, scene, state, shapes, lights)?;
+    Ok(result)
+}
+
+// Object placed by invalid transformation is removed from scene with warning when invalid
+// transformations are skipped, otherwise it is an error
+fn check_object_transformation(object: &str, scene: &mut SceneDescription, state: &ParseState,
+                               shapes: usize, lights: usize) -> Result<(), Box<dyn Error>> {
+    let error = match state.transformation_error() {
+        Some(error) => error,
+        None => return Ok(())
+    };
+    if !state.skip_invalid_transforms {
+        return Err(format!("{}: Invalid transformation - {}", object, error).into())
     }
+    println!("Warning: {} skipped, invalid transformation - {}", object, error);
+    scene.shapes.truncate(shapes);
+    scene.lights.truncate(lights);
+    Ok(())
+}
+
+fn process_infinite_light(tokenizer: &mut PBRTTokenizer, scene: &mut SceneDescription,
+                          state: &mut ParseState) -> Result<Option<String>, Box<dyn Error>> {
+
+    let mut desc = LightDescription::default();
+
+    let mut process_attribute = |tokenizer: &mut PBRTTokenizer, token: &str| -> Result<(), Box<dyn Error>> {
+        match token {
+            "rgb L" => desc.intensity = parse_rgb(tokenizer, "InfiniteLight:rgb L ")?,
+            "string filename" => desc.filename = Some(extract_value(tokenizer, "InfiniteLight:filename - ")?),
+            "float scale" => desc.scale = extract_value(tokenizer, "InfiniteLight:scale - ")?,
+            "string lightgroup" => desc.group = Some(extract_value(tokenizer, "InfiniteLight:lightgroup - ")?),
+            _ => return Err(format!("Unsupported parameter in infinite light: {}", token).into())
+        }
+        Ok(())
+    };
+    let result = process_attributes(tokenizer, state, &mut process_attribute)?;
+
+    desc.filename = desc.filename.map(|fname| create_path(state, &fname));
+    // NOTE: environment images of pbrt-v4 use equal-area octahedral mapping
+    desc.mapping = EnvironmentMapping::EqualArea;
+    // NOTE: transformation in effect at declaration orients the environment
+    if !state.current_transformation().is_identity() {
+        desc.transform = Some(state.current_transformation());
+    }
+    desc.typ = LightType::Infinite;
+    scene.lights.push(desc);
+    Ok(result)
+}
+
+// NOTE: sky isn't pbrt-v4 light, up direction of the sky is z axis of the transformation in effect,
+// scale converts radiance in kcd/m^2 to radiance of the scene (see DEFAULT_SKY_SCALE)
+fn process_sky_light(tokenizer: &mut PBRTTokenizer, scene: &mut SceneDescription,
+                     state: &mut ParseState) -> Result<Option<String>, Box<dyn Error>> {
+
+    let mut desc = LightDescription { scale: DEFAULT_SKY_SCALE, ..Default::default() };
+
+    let mut process_attribute = |tokenizer: &mut PBRTTokenizer, token: &str| -> Result<(), Box<dyn Error>> {
+        match token {
+            "vector3 sundirection" => desc.sun_direction = parse_vec3(tokenizer, "SkyLight:sundirection ")?,
+            "float turbidity" => desc.turbidity = extract_value(tokenizer, "SkyLight:turbidity - ")?,
+            "float scale" => desc.scale = extract_value(tokenizer, "SkyLight:scale - ")?,
+            "string lightgroup" => desc.group = Some(extract_value(tokenizer, "SkyLight:lightgroup - ")?),
+            _ => return Err(format!("Unsupported parameter in sky light: {}", token).into())
+        }
+        Ok(())
+    };
+    let result = process_attributes(tokenizer, state, &mut process_attribute)?;
+
+    if desc.sun_direction.length_sqr() == 0.0 {
+        return Err("SkyLight: sun direction is zero vector!".into())
+    }
+    if !state.current_transformation().is_identity() {
+        desc.transform = Some(state.current_transformation());
+    }
+    desc.typ = LightType::Sky;
+    scene.lights.push(desc);
+    Ok(result)
 }
 
 fn process_point_light(tokenizer: &mut PBRTTokenizer, scene: &mut SceneDescription,
@@ -614,6 +1472,10 @@ fn process_point_light(tokenizer: &mut PBRTTokenizer, scene: &mut SceneDescripti
         match token {
             "rgb I" => desc.intensity = parse_rgb(tokenizer, "PointLight:rgb ")?,
             "point3 from" => desc.position = parse_point3(tokenizer, "PointLight:point from ")?,
+            "float scale" => desc.scale = extract_value(tokenizer, "PointLight:scale - ")?,
+            "float power" => desc.power = Some(extract_value(tokenizer, "PointLight:power - ")?),
+            "string lightgroup" => desc.group = Some(extract_value(tokenizer, "PointLight:lightgroup - ")?),
+            "float radius" => desc.radius = extract_value(tokenizer, "PointLight:radius - ")?,
             _ => return Err(format!("Unsupported parameter in point light: {}", token).into())
         }
         Ok(())
@@ -647,24 +1509,31 @@ fn process_area_diffuse_light(tokenizer: &mut PBRTTokenizer, scene: &mut SceneDe
 
     let mut desc = MaterialDescription::default();
     desc.diffuse = RGB::new(0.0, 0.0, 0.0);
+    let mut scale = 1.0;
 
     let mut process_attribute = |tokenizer: &mut PBRTTokenizer, token: &str| -> Result<(), Box<dyn Error>> {
         match token {
             "rgb reflectance" => desc.diffuse = parse_rgb(tokenizer, "Material:rgb ")?,
             "rgb L" => desc.emission = parse_rgb(tokenizer, "Material:emission ")?,
+            "float scale" => scale = extract_value(tokenizer, "AreaLight:scale - ")?,
+            "float power" => desc.power = Some(extract_value(tokenizer, "AreaLight:power - ")?),
+            "string lightgroup" => desc.light_group = Some(extract_value(tokenizer, "AreaLight:lightgroup - ")?),
+            "bool twosided" => desc.two_sided = extract_value(tokenizer, "AreaLight:twosided - ")?,
             _ => return Err(format!("Unsupported parameter in emissive diffuse material: {}", token).into())
         }
         Ok(())
     };
     let result = process_attributes(tokenizer, state, &mut process_attribute)?;
+    // scale is applied on top of emission normalized to given power, as in pbrt-v4
+    match desc.power {
+        Some(power) => desc.power = Some(power * scale),
+        None => desc.emission = desc.emission * scale
+    }
 
-    // TODO improve this - use unique name
-    let name = format!("Material_generated_name_emmisive_17654_{}", scene.materials.len());
-
-    desc.name = name.to_string();
+    desc.name = format!("{}arealight", state.name_prefix);
     desc.typ = MaterialType::EmissiveMatte;
-    scene.materials.push(desc);
-    state.set_area_light(name.to_string());
+    let name = add_anonymous_material(scene, state, desc);
+    state.set_area_light(name);
     Ok(result)
 }
 
@@ -675,11 +1544,43 @@ fn process_shape(tokenizer: &mut PBRTTokenizer, scene: &mut SceneDescription,
         Some(token) => token.trim(),
         None => return Err("Shape: Type of shape not specified!".into())
     };
-    match token {
-        "sphere" => process_sphere_shape(tokenizer, scene, state),
-        "trianglemesh" => process_trianglemesh_shape(tokenizer, scene, state),
-        _=> Err(format!("Unsupported shape type {}", token).into())
+    let (object, shapes, lights) = (format!("Shape {}", token), scene.shapes.len(), scene.lights.len());
+    let result = match token {
+        "sphere" => process_sphere_shape(tokenizer, scene, state)?,
+        "trianglemesh" => process_trianglemesh_shape(tokenizer, scene, state)?,
+        "bilinearmesh" => process_bilinearmesh_shape(tokenizer, scene, state)?,
+        _=> return Err(format!("Unsupported shape type {}", token).into())
+    };
+    check_object_transformation(&object, scene, state, shapes, lights)?;
+    Ok(result)
+}
+
+// NOTE: area light with specified power is normalized per shape, so each shape
+// gets its own copy of emissive material with emission scaled by area of the shape,
+// two-sided light emits from both sides so its emitting area is doubled
+fn shape_material(scene: &mut SceneDescription, state: &mut ParseState, area: f32) -> String {
+    let name = match state.area_lights.last() {
+        Some(name) => name,
+        None => return state.current_material()
+    };
+    let material = match scene.materials.iter().find(|m| &m.name == name) {
+        Some(material) => material,
+        None => return name.clone()
+    };
+    let power = match material.power {
+        Some(power) => power,
+        None => return name.clone()
+    };
+    let luminance = material.emission.luminance();
+    let area = if material.two_sided { 2.0 * area } else { area };
+    if luminance <= 0.0 || area <= 0.0 {
+        return name.clone()
     }
+    let mut desc = material.clone();
+    desc.emission = desc.emission * (power / (std::f32::consts::PI * area * luminance));
+    desc.power = None;
+    desc.name = format!("{}_shape", name);
+    add_anonymous_material(scene, state, desc)
 }
 
 fn process_sphere_shape(tokenizer: &mut PBRTTokenizer, scene: &mut SceneDescription,
@@ -691,20 +1592,20 @@ fn process_sphere_shape(tokenizer: &mut PBRTTokenizer, scene: &mut SceneDescript
         match token {
             "float radius" => desc.radius = extract_value(tokenizer, "Sphere:radius - ")?,
             "point3 position" => desc.position = parse_point3(tokenizer, "Sphere:position - ")?,
+            "float zmin" => desc.zmin = extract_value(tokenizer, "Sphere:zmin - ")?,
+            "float zmax" => desc.zmax = extract_value(tokenizer, "Sphere:zmax - ")?,
+            "float phimax" => desc.phimax = extract_value(tokenizer, "Sphere:phimax - ")?,
             _ => return Err(format!("Unsupported parameter in sphere shape: {}", token).into())
         }
         Ok(())
     };
     let result = process_attributes(tokenizer, state, &mut process_attribute)?;
 
-    desc.material = match state.area_lights.last() {
-        Some(name) => name.clone(),
-        None => state.current_material().clone()
-    };
-
     if !state.current_transformation().is_identity() {
         desc.transform = Some(state.current_transformation());
     }
+    desc.material = shape_material(scene, state, desc.area());
+    desc.medium_interface = state.current_medium_interface();
     let shape = ShapeDescription::Sphere(desc);
     scene.shapes.push(shape);
     Ok(result)
@@ -714,6 +1615,7 @@ fn process_trianglemesh_shape(tokenizer: &mut PBRTTokenizer, scene: &mut SceneDe
                               state: &mut ParseState) -> Result<Option<String>, Box<dyn Error>> {
 
     let mut desc = MeshDescription::default();
+    let mut alpha_texture = None;
 
     let mut process_attribute = |tokenizer: &mut PBRTTokenizer, token: &str| -> Result<(), Box<dyn Error>> {
         match token {
@@ -721,20 +1623,25 @@ fn process_trianglemesh_shape(tokenizer: &mut PBRTTokenizer, scene: &mut SceneDe
             "normal N" => desc.normals = Some(parse_normal_array(tokenizer, "Mesh:normals - ")?),
             "point3 P" => desc.vertices = Some(parse_point3_array(tokenizer, "Mesh:positions - ")?),
             "integer indices" => desc.indices = Some(parse_u32_array(tokenizer, "Mesh:indices - ")?),
+            "integer faceIndices" => desc.face_indices = Some(parse_u32_array(tokenizer, "Mesh:faceIndices - ")?),
+            "float alpha" => desc.alpha = extract_value(tokenizer, "Mesh:alpha - ")?,
+            "texture alpha" => alpha_texture = Some(extract_value::<String>(tokenizer, "Mesh:alpha - ")?),
             _ => return Err(format!("Unsupported parameter in sphere shape: {}", token).into())
         }
         Ok(())
     };
     let result = process_attributes(tokenizer, state, &mut process_attribute)?;
 
-    desc.material = match state.area_lights.last() {
-        Some(name) => name.clone(),
-        None => state.current_material().clone()
-    };
-
     if !state.current_transformation().is_identity() {
         desc.transform = Some(state.current_transformation());
     }
+    desc.medium_interface = state.current_medium_interface();
+    if let Some(name) = alpha_texture {
+        desc.alpha_texture = match state.textures.get(&name) {
+            Some(texture) => Some(texture.clone()),
+            None => return Err(format!("Mesh:alpha - Texture {} is not defined!", name).into())
+        };
+    }
 
     // NOTE: special case for one triangle
     if desc.indices.is_none() {
@@ -747,17 +1654,235 @@ fn process_trianglemesh_shape(tokenizer: &mut PBRTTokenizer, scene: &mut SceneDe
             None => {}
         }
     }
+    if let (Some(face_indices), Some(indices)) = (&desc.face_indices, &desc.indices) {
+        if face_indices.len() != indices.len() / 3 {
+            return Err(format!("Mesh:faceIndices - {} values expected, {} found!", indices.len() / 3, face_indices.len()).into());
+        }
+    }
+    desc.check_vertex_attributes().map_err(|e| format!("Mesh:{}", e))?;
+    desc.material = shape_material(scene, state, desc.area());
     let shape = ShapeDescription::Mesh(desc);
     scene.shapes.push(shape);
     Ok(result)
 }
 
+fn process_bilinearmesh_shape(tokenizer: &mut PBRTTokenizer, scene: &mut SceneDescription,
+                              state: &mut ParseState) -> Result<Option<String>, Box<dyn Error>> {
+
+    let mut desc = BilinearMeshDescription::default();
+
+    let mut process_attribute = |tokenizer: &mut PBRTTokenizer, token: &str| -> Result<(), Box<dyn Error>> {
+        match token {
+            "point2 uv" => desc.uvs = Some(parse_point2_array(tokenizer, "BilinearMesh:uvs - ")?),
+            "normal N" => desc.normals = Some(parse_normal_array(tokenizer, "BilinearMesh:normals - ")?),
+            "point3 P" => desc.vertices = Some(parse_point3_array(tokenizer, "BilinearMesh:positions - ")?),
+            "integer indices" => desc.indices = Some(parse_u32_array(tokenizer, "BilinearMesh:indices - ")?),
+            "integer faceIndices" => {
+                let _ = parse_u32_array(tokenizer, "BilinearMesh:faceIndices - ")?;
+            }
+            _ => return Err(format!("Unsupported parameter in bilinear mesh shape: {}", token).into())
+        }
+        Ok(())
+    };
+    let result = process_attributes(tokenizer, state, &mut process_attribute)?;
+
+    if !state.current_transformation().is_identity() {
+        desc.transform = Some(state.current_transformation());
+    }
+    desc.medium_interface = state.current_medium_interface();
+
+    // NOTE: special case for one patch
+    if desc.indices.is_none() && desc.vertices.as_ref().is_some_and(|vertices| vertices.len() == 4) {
+        desc.indices = Some(vec![0, 1, 2, 3]);
+    }
+    match &desc.indices {
+        Some(indices) if indices.len().is_multiple_of(4) => {}
+        Some(indices) => return Err(format!("BilinearMesh:indices - multiple of 4 values expected, {} found!", indices.len()).into()),
+        None => return Err("BilinearMesh:indices - indices are not specified!".into())
+    }
+    desc.check_vertex_attributes().map_err(|e| format!("BilinearMesh:{}", e))?;
+    desc.material = shape_material(scene, state, desc.area());
+    scene.shapes.push(ShapeDescription::BilinearMesh(desc));
+    Ok(result)
+}
+
+fn process_make_named_medium(tokenizer: &mut PBRTTokenizer, scene: &mut SceneDescription,
+                             state: &mut ParseState) -> Result<Option<String>, Box<dyn Error>> {
+    let name = match tokenizer.next() {
+        Some(token) => token.trim().to_string(),
+        None => return Err("Make Named Medium: Name of medium not specified!".into())
+    };
+
+    let mut desc = MediumDescription::default();
+    let mut medium_type: Option<String> = None;
+
+    let mut process_attribute = |tokenizer: &mut PBRTTokenizer, token: &str| -> Result<(), Box<dyn Error>> {
+        match token {
+            "string type" => medium_type = Some(extract_value(tokenizer, "Medium::type - ")?),
+            "rgb sigma_a" => desc.sigma_a = parse_rgb(tokenizer, "Medium::sigma_a - ")?,
+            "rgb sigma_s" => desc.sigma_s = parse_rgb(tokenizer, "Medium::sigma_s - ")?,
+            "float scale" => desc.scale = extract_value(tokenizer, "Medium::scale - ")?,
+            "float g" => desc.g = extract_value(tokenizer, "Medium::g - ")?,
+            "rgb Le" => desc.le = parse_rgb(tokenizer, "Medium::Le - ")?,
+            "float Lescale" => desc.le_scale = extract_value(tokenizer, "Medium::Lescale - ")?,
+            "string preset" => desc.preset = Some(extract_value(tokenizer, "Medium::preset - ")?),
+            "float density" => desc.density = Some(parse_f32_array(tokenizer, "Medium::density - ")?),
+            "integer nx" => desc.nx = extract_value(tokenizer, "Medium::nx - ")?,
+            "integer ny" => desc.ny = extract_value(tokenizer, "Medium::ny - ")?,
+            "integer nz" => desc.nz = extract_value(tokenizer, "Medium::nz - ")?,
+            "point3 p0" => desc.p0 = parse_point3(tokenizer, "Medium::p0 - ")?,
+            "point3 p1" => desc.p1 = parse_point3(tokenizer, "Medium::p1 - ")?,
+            _ => return Err(format!("Unsupported parameter in medium: {}", token).into())
+        }
+        Ok(())
+    };
+    let result = process_attributes(tokenizer, state, &mut process_attribute)?;
+
+    desc.typ = match medium_type.as_deref() {
+        Some("homogeneous") => MediumType::Homogeneous,
+        Some("uniformgrid") => MediumType::UniformGrid,
+        Some("rgbgrid") => MediumType::RGBGrid,
+        Some("cloud") => MediumType::Cloud,
+        Some("nanovdb") => MediumType::NanoVDB,
+        Some(typ) => return Err(format!("Make Named Medium: Unsupported medium type {}", typ).into()),
+        None => return Err(format!("Make Named Medium: Type of medium {} not specified!", name).into())
+    };
+    desc.name = name;
+    desc.check().map_err(|e| format!("Make Named Medium: {}", e))?;
+    if !state.current_transformation().is_identity() {
+        desc.transform = Some(state.current_transformation());
+    }
+    scene.media.push(desc);
+    Ok(result)
+}
+
+fn process_medium_interface(tokenizer: &mut PBRTTokenizer, _scene: &mut SceneDescription,
+                            state: &mut ParseState) -> Result<Option<String>, Box<dyn Error>> {
+
+    fn medium_name(name: &str) -> Option<String> {
+        if name.is_empty() { None } else { Some(name.to_string()) }
+    }
+
+    let inside = match tokenizer.next() {
+        Some(token) => token.trim().to_string(),
+        None => return Err("MediumInterface: Name of medium not specified!".into())
+    };
+    // NOTE: outside medium is optional, if it is not specified it is same as inside
+    let token = tokenizer.next().map(|token| token.trim().to_string());
+    let (outside, result) = match token {
+        Some(token) if !state.is_directive(&token) => (token, next_directive(tokenizer)),
+        Some(token) => (inside.clone(), Some(token)),
+        None => (inside.clone(), None)
+    };
+    state.set_medium_interface(MediumInterface::new(medium_name(&inside), medium_name(&outside)));
+    Ok(result)
+}
+
 fn process_world_begin(tokenizer: &mut PBRTTokenizer, _scene: &mut SceneDescription,
                        state: &mut ParseState) -> Result<Option<String>, Box<dyn Error>> {
+    state.set_active_transform(ActiveTransform::All);
     state.set_transformation(Transformation::identity());
     Ok(next_directive(tokenizer))
 }
 
+fn process_active_transform(tokenizer: &mut PBRTTokenizer, _scene: &mut SceneDescription,
+                            state: &mut ParseState) -> Result<Option<String>, Box<dyn Error>> {
+    let active = match tokenizer.next().map(|token| token.trim()) {
+        Some("StartTime") => ActiveTransform::Start,
+        Some("EndTime") => ActiveTransform::End,
+        Some("All") => ActiveTransform::All,
+        Some(token) => return Err(format!("ActiveTransform: Unknown transform {}!", token).into()),
+        None => return Err("ActiveTransform: Transform not specified!".into())
+    };
+    state.set_active_transform(active);
+    Ok(next_directive(tokenizer))
+}
+
+fn process_transform_times(tokenizer: &mut PBRTTokenizer, _scene: &mut SceneDescription,
+                           state: &mut ParseState) -> Result<Option<String>, Box<dyn Error>> {
+    let start = parse_f32(tokenizer, "TransformTimes: start ")?;
+    let end = parse_f32(tokenizer, "TransformTimes: end ")?;
+    state.transform_times = (start, end);
+    Ok(next_directive(tokenizer))
+}
+
+// NOTE: shapes of object are parsed as other shapes and moved to prototype at ObjectEnd
+fn process_object_begin(tokenizer: &mut PBRTTokenizer, scene: &mut SceneDescription,
+                        state: &mut ParseState) -> Result<Option<String>, Box<dyn Error>> {
+    let name = match tokenizer.next() {
+        Some(token) => token.trim().to_string(),
+        None => return Err("ObjectBegin: Name of object not specified!".into())
+    };
+    if state.object.is_some() {
+        return Err(format!("ObjectBegin: Object {} is defined inside of another object!", name).into())
+    }
+    state.push_state();
+    state.object = Some((format!("{}{}", state.name_prefix, name), scene.shapes.len()));
+    Ok(next_directive(tokenizer))
+}
+
+fn process_object_end(tokenizer: &mut PBRTTokenizer, scene: &mut SceneDescription,
+                      state: &mut ParseState) -> Result<Option<String>, Box<dyn Error>> {
+    let (name, first_shape) = match state.object.take() {
+        Some(object) => object,
+        None => return Err("ObjectEnd: There is no object to end!".into())
+    };
+    let mut meshes = Vec::new();
+    let mut skipped = 0;
+    for shape in scene.shapes.drain(first_shape..) {
+        match shape {
+            ShapeDescription::Mesh(desc) => meshes.push(desc),
+            _ => skipped += 1
+        }
+    }
+    if skipped > 0 {
+        println!("Warning: ObjectEnd: Object {} - only triangle meshes can be instanced, {} other shapes are skipped!", name, skipped);
+    }
+    scene.prototypes.push(PrototypeDescription { name, meshes });
+    state.pop_state();
+    Ok(next_directive(tokenizer))
+}
+
+fn process_object_instance(tokenizer: &mut PBRTTokenizer, scene: &mut SceneDescription,
+                           state: &mut ParseState) -> Result<Option<String>, Box<dyn Error>> {
+    let name = match tokenizer.next() {
+        Some(token) => token.trim(),
+        None => return Err("ObjectInstance: Name of object not specified!".into())
+    };
+    let object = format!("ObjectInstance {}", name);
+    if state.transformation_error().is_some() {
+        check_object_transformation(&object, scene, state, scene.shapes.len(), scene.lights.len())?;
+        return Ok(next_directive(tokenizer))
+    }
+    let (start, end) = (state.current_transformation(), state.current_end_transformation());
+    let desc = InstanceDescription {
+        prototype: format!("{}{}", state.name_prefix, name),
+        transform: start,
+        end_transform: (start != end).then_some(end),
+        start_time: state.transform_times.0,
+        end_time: state.transform_times.1
+    };
+    scene.shapes.push(ShapeDescription::Instance(desc));
+    Ok(next_directive(tokenizer))
+}
+
+// NOTE: only options of this renderer are supported
+fn process_option(tokenizer: &mut PBRTTokenizer, scene: &mut SceneDescription,
+                  state: &mut ParseState) -> Result<Option<String>, Box<dyn Error>> {
+    let mut skip_invalid_transforms = state.skip_invalid_transforms;
+    let mut process_attribute = |tokenizer: &mut PBRTTokenizer, token: &str| -> Result<(), Box<dyn Error>> {
+        match token {
+            "bool skipinvalidtransforms" => skip_invalid_transforms = extract_value(tokenizer, "Option::skipinvalidtransforms - ")?,
+            _ => return Err(format!("Unsupported option: {}", token).into())
+        }
+        Ok(())
+    };
+    let result = process_attributes(tokenizer, state, &mut process_attribute)?;
+    state.skip_invalid_transforms = skip_invalid_transforms;
+    scene.settings.skip_invalid_transforms = skip_invalid_transforms;
+    Ok(result)
+}
+
 fn process_attribute_begin(tokenizer: &mut PBRTTokenizer, _scene: &mut SceneDescription,
                            state: &mut ParseState) -> Result<Option<String>, Box<dyn Error>> {
     state.push_state();
@@ -770,16 +1895,58 @@ fn process_attribute_end(tokenizer: &mut PBRTTokenizer, _scene: &mut SceneDescri
     Ok(next_directive(tokenizer))
 }
 
-// TODO - test this function
+fn process_include(tokenizer: &mut PBRTTokenizer, scene: &mut SceneDescription,
+                   state: &mut ParseState) -> Result<Option<String>, Box<dyn Error>> {
+    let filename = match tokenizer.next() {
+        Some(token) => create_path(state, token.trim()),
+        None => return Err("Include: Filename not specified!".into())
+    };
+    let contents = match read_file(&filename) {
+        Ok(contents) => contents,
+        Err(e) => return Err(format!("Include: {} - {}", filename, e).into())
+    };
+    parse_input_string(&contents, scene, state)?;
+    Ok(next_directive(tokenizer))
+}
+
+// Imported files can't change graphics state of the parent, so they are parsed in rayon
+// thread pool and merged in scene description at the end of parsing.
+fn process_import(tokenizer: &mut PBRTTokenizer, scene: &mut SceneDescription,
+                  state: &mut ParseState) -> Result<Option<String>, Box<dyn Error>> {
+    let filename = match tokenizer.next() {
+        Some(token) => create_path(state, token.trim()),
+        None => return Err("Import: Filename not specified!".into())
+    };
+    if cfg!(not(feature = "fs")) {
+        return Err(format!("Import: {} - {}", filename, NO_FS_ERROR).into());
+    }
+    let name_prefix = format!("{}import{}_", state.name_prefix, state.imports.len());
+    let import_state = state.snapshot(name_prefix);
+    state.imports.push(PendingImport { position: scene.fragment_position(), filename, state: import_state });
+    Ok(next_directive(tokenizer))
+}
+
+const NO_FS_ERROR: &str = "reading files requires fs feature";
+
+#[cfg(feature = "fs")]
+fn read_file(filename: &str) -> Result<String, Box<dyn Error>> {
+    Ok(fs::read_to_string(filename)?)
+}
+
+#[cfg(not(feature = "fs"))]
+fn read_file(_filename: &str) -> Result<String, Box<dyn Error>> {
+    Err(NO_FS_ERROR.into())
+}
+
 fn create_path(state: &ParseState, filename: &str) -> String {
     if Path::new(filename).is_absolute() {
         return filename.to_string();
     }
     let full_path = match state.current_path.parent() {
         Some(dir) => dir.join(filename),
-        None => PathBuf::new(),
+        None => PathBuf::from(filename),
     };
-    return full_path.to_str().expect("Path conversion faild!").to_string();
+    full_path.to_str().expect("Path conversion faild!").to_string()
 }
 
 fn parse_rgb(tokenizer: &mut PBRTTokenizer, err_msg: &str) -> Result<RGB,  Box<dyn Error>> {
@@ -819,84 +1986,56 @@ fn parse_f32x3(tokenizer: &mut PBRTTokenizer, err_msg: &str) -> Result<(f32, f32
 }
 
 fn parse_f32_array(tokenizer: &mut PBRTTokenizer, err_msg: &str) -> Result<Vec<f32>, Box<dyn Error>> {
-    let token = match tokenizer.next() {
-        Some(token) => token.trim(),
-        None => return Err(format!("{} - Missing token!", err_msg).into())
-    };
-    if token != "[" {
-        return Err(format!("{} - Expected '[' token!", err_msg).into());
-    }
-    let mut values = Vec::<f32>::new();
-    loop {
-        let token = match tokenizer.next() {
-            Some(token) => token.trim(),
-            None => return Err(format!("{} - Missing ']' token!", err_msg).into())
-        };
-        if token == "]" {
-            break;
-        }
-        let val: f32 = match token.parse() {
-            Err(e) => return Err(format!("{} - Parsing '{}':{}", err_msg, token, e).into()),
-            Ok(val) => val
-        };
-        values.push(val);
-    }
-    Ok(values)
+    parse_numeric_array(tokenizer, err_msg, "value", |[value]| value)
 }
 
-
 fn parse_point3_array(tokenizer: &mut PBRTTokenizer, err_msg: &str) -> Result<Vec<Point3>, Box<dyn Error>> {
-    let values = parse_f32_array(tokenizer, err_msg)?;
-    let mut result = Vec::<Point3>::new();
-    let chunks = values.chunks_exact(3);
-    let rest = chunks.remainder();
-    for chunk in chunks {
-        result.push(Point3::new(chunk[0], chunk[1], chunk[2]));
-    }
-    if !rest.is_empty() {
-        return Err(format!("{} - Expected 3 values per point!", err_msg).into());
-    }
-    Ok(result)
+    parse_numeric_array(tokenizer, err_msg, "point", |[x, y, z]| Point3::new(x, y, z))
 }
 
 fn parse_point2_array(tokenizer: &mut PBRTTokenizer, err_msg: &str) -> Result<Vec<Point2>, Box<dyn Error>> {
-    let values = parse_f32_array(tokenizer, err_msg)?;
-    let mut result = Vec::<Point2>::new();
-    let chunks = values.chunks_exact(2);
-    let rest = chunks.remainder();
-    for chunk in chunks {
-        result.push(Point2::new(chunk[0], chunk[1]));
-    }
-    if !rest.is_empty() {
-        return Err(format!("{} - Expected 2 values per point!", err_msg).into());
-    }
-    Ok(result)
+    parse_numeric_array(tokenizer, err_msg, "point", |[x, y]| Point2::new(x, y))
 }
 
 fn parse_normal_array(tokenizer: &mut PBRTTokenizer, err_msg: &str) -> Result<Vec<Normal>, Box<dyn Error>> {
-    let values = parse_f32_array(tokenizer, err_msg)?;
-    let mut result = Vec::<Normal>::new();
-    let chunks = values.chunks_exact(3);
-    let rest = chunks.remainder();
-    for chunk in chunks {
-        result.push(Normal::new(chunk[0], chunk[1], chunk[2]));
-    }
-    if !rest.is_empty() {
-        return Err(format!("{} - Expected 3 values per normal!", err_msg).into());
-    }
-    Ok(result)
+    parse_numeric_array(tokenizer, err_msg, "normal", |[x, y, z]| Normal::new(x, y, z))
 }
 
+// NOTE: values of large inline meshes are parsed directly from text of the block into elements,
+// every N values create one element
+fn parse_numeric_array<T, U, const N: usize>(tokenizer: &mut PBRTTokenizer, err_msg: &str, element: &str,
+                                             create: impl Fn([T; N]) -> U) -> Result<Vec<U>, Box<dyn Error>>
+where T: FromStr + Default + Copy, <T as FromStr>::Err: Display
+{
+    let block = tokenizer.next_block().map_err(|e| format!("{} - {}", err_msg, e))?;
+    let mut values = block_values(block);
+    let mut result = Vec::new();
+    loop {
+        let mut element_values = [T::default(); N];
+        for (index, value) in element_values.iter_mut().enumerate() {
+            let token = match values.next() {
+                Some(token) => token,
+                None if index == 0 => return Ok(result),
+                None => return Err(format!("{} - Expected {} values per {}!", err_msg, N, element).into())
+            };
+            *value = match token.parse() {
+                Err(e) => return Err(format!("{} - Parsing '{}':{}", err_msg, token, e).into()),
+                Ok(val) => val
+            };
+        }
+        result.push(create(element_values));
+    }
+}
 
-fn parse_u32_array(tokenizer: &mut PBRTTokenizer, err_msg: &str) -> Result<Vec<u32>, Box<dyn Error>> {
+fn parse_string_array(tokenizer: &mut PBRTTokenizer, err_msg: &str) -> Result<Vec<String>, Box<dyn Error>> {
     let token = match tokenizer.next() {
         Some(token) => token.trim(),
         None => return Err(format!("{} - Missing token!", err_msg).into())
     };
     if token != "[" {
-        return Err(format!("{} - Expected '[' token!", err_msg).into());
+        return Ok(vec![token.to_string()]);
     }
-    let mut values = Vec::<u32>::new();
+    let mut values = Vec::<String>::new();
     loop {
         let token = match tokenizer.next() {
             Some(token) => token.trim(),
@@ -905,15 +2044,15 @@ fn parse_u32_array(tokenizer: &mut PBRTTokenizer, err_msg: &str) -> Result<Vec<u
         if token == "]" {
             break;
         }
-        let val: u32 = match token.parse() {
-            Err(e) => return Err(format!("{} - Parsing '{}':{}", err_msg, token, e).into()),
-            Ok(val) => val
-        };
-        values.push(val);
+        values.push(token.to_string());
     }
     Ok(values)
 }
 
+fn parse_u32_array(tokenizer: &mut PBRTTokenizer, err_msg: &str) -> Result<Vec<u32>, Box<dyn Error>> {
+    parse_numeric_array(tokenizer, err_msg, "value", |[value]| value)
+}
+
 fn extract_value<T>(tokenizer: &mut PBRTTokenizer, err_msg: &str) -> Result<T,  Box<dyn Error>>
 where T: FromStr, <T as FromStr>::Err: Display
 {
@@ -959,3 +2098,557 @@ fn parse_f32(tokenizer: &mut PBRTTokenizer, err_msg: &str) ->Result<f32,  Box<dy
     Ok(val)   
 }
 
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_text(text: &str) -> Result<SceneDescription, Box<dyn Error>> {
+        let mut state = ParseState::new();
+        let mut scene = SceneDescription::default();
+        parse_input_string(text, &mut scene, &mut state)?;
+        Ok(scene)
+    }
+
+    #[test]
+    fn parse_sky_light() {
+        let text = r#"
+            WorldBegin
+            Scale 1 1 -1
+            LightSource "sky" "vector3 sundirection" [1 0 1] "float turbidity" 4 "float scale" 0.5
+        "#;
+        let scene = parse_text(text).unwrap();
+        let desc = &scene.lights[0];
+        assert!(matches!(desc.typ, LightType::Sky));
+        assert_eq!((desc.sun_direction.x, desc.turbidity, desc.scale), (1.0, 4.0, 0.5));
+        assert!(desc.transform.is_some());
+        // transformation turns the sky upside down
+        let light = desc.create().unwrap();
+        assert!(light.le(Vec3::new(0.0, 0.0, -1.0)).luminance() > 0.0);
+        assert_eq!(light.le(Vec3::new(0.0, 0.0, 1.0)).luminance(), 0.0);
+
+        let scene = parse_text("WorldBegin\nLightSource \"sky\" \"vector3 sundirection\" [0 0 1]\n").unwrap();
+        assert_eq!(scene.lights[0].scale, DEFAULT_SKY_SCALE);
+        assert!(parse_text("WorldBegin\nLightSource \"sky\" \"vector3 sundirection\" [0 0 0]\n").is_err());
+    }
+
+    #[test]
+    fn parse_infinite_light() {
+        let scene = parse_text("WorldBegin\nLightSource \"infinite\" \"string filename\" \"sky.exr\"\n").unwrap();
+        assert_eq!(scene.lights[0].filename.as_deref(), Some("sky.exr"));
+        assert_eq!(scene.lights[0].mapping, EnvironmentMapping::EqualArea);
+    }
+
+    #[test]
+    fn parse_light_scale_and_power() {
+        let text = r#"
+            WorldBegin
+            Material "diffuse"
+            LightSource "point" "rgb I" [1 1 1] "float scale" 2 "float power" 10
+            AttributeBegin
+            AreaLightSource "diffuse" "rgb L" [1 1 1] "float power" 100
+            Shape "sphere" "float radius" 1
+            Shape "sphere" "float radius" 2
+            AttributeEnd
+            AttributeBegin
+            AreaLightSource "diffuse" "rgb L" [2 2 2] "float scale" 3
+            Shape "sphere" "float radius" 1
+            AttributeEnd
+            AttributeBegin
+            AreaLightSource "diffuse" "rgb L" [1 1 1] "float power" 100 "bool twosided" true
+            Shape "sphere" "float radius" 1
+            AttributeEnd
+            AttributeBegin
+            AreaLightSource "diffuse" "rgb L" [5 5 5] "float power" 100 "float scale" 0.5
+            Shape "sphere" "float radius" 1
+            AttributeEnd
+        "#;
+        let scene = parse_text(text).unwrap();
+        assert_eq!(scene.lights[0].scale, 2.0);
+        assert_eq!(scene.lights[0].power, Some(10.0));
+
+        let material = |index: usize| -> &MaterialDescription {
+            let name = match &scene.shapes[index] {
+                ShapeDescription::Sphere(desc) => &desc.material,
+                _ => panic!("Sphere expected!")
+            };
+            scene.materials.iter().find(|m| &m.name == name).unwrap()
+        };
+        let emission = |index: usize| material(index).emission;
+        let pi = std::f32::consts::PI;
+        assert!((emission(0).r - 100.0 / (pi * 4.0 * pi)).abs() < 1e-4);
+        assert!((emission(1).r - 100.0 / (pi * 16.0 * pi)).abs() < 1e-4);
+        assert_eq!(emission(2).r, 6.0);
+        // power of two-sided light is split between both sides
+        assert!(!material(0).two_sided && material(3).two_sided);
+        assert!((emission(3).r - 100.0 / (pi * 8.0 * pi)).abs() < 1e-4);
+        // scale isn't cancelled by normalization to power
+        assert!((emission(4).r - 0.5 * 100.0 / (pi * 4.0 * pi)).abs() < 1e-4);
+    }
+
+    #[test]
+    fn parse_media() {
+        let text = r#"
+            MediumInterface "" "fog"
+            Camera "perspective" "float fov" 45
+            WorldBegin
+            MakeNamedMedium "fog" "string type" "homogeneous" "rgb sigma_a" [0.1 0.2 0.3] "float scale" 2
+            Material "diffuse"
+            AttributeBegin
+            MediumInterface "fog"
+            Shape "sphere" "float radius" 1
+            AttributeEnd
+            Shape "sphere" "float radius" 2
+        "#;
+        let scene = parse_text(text).unwrap();
+        assert_eq!(scene.media.len(), 1);
+        assert_eq!(scene.media[0].name, "fog");
+        assert_eq!(scene.media[0].sigma_a.g, 0.2);
+        assert_eq!(scene.media[0].scale, 2.0);
+        assert_eq!(scene.camera_medium, Some("fog".to_string()));
+        match &scene.shapes[0] {
+            ShapeDescription::Sphere(desc) => {
+                assert_eq!(desc.medium_interface, MediumInterface::new(Some("fog".to_string()), Some("fog".to_string())));
+            }
+            _ => panic!("Sphere expected!")
+        }
+        match &scene.shapes[1] {
+            ShapeDescription::Sphere(desc) => {
+                assert_eq!(desc.medium_interface, MediumInterface::new(None, Some("fog".to_string())));
+            }
+            _ => panic!("Sphere expected!")
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "fs")]
+    fn parse_import_and_include() {
+        let dir = std::env::temp_dir().join("rtlib_pbrt_import_test");
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("part1.pbrt"), r#"
+            Material "diffuse" "rgb reflectance" [0.1 0.1 0.1]
+            Shape "sphere" "float radius" 1
+            Import "nested.pbrt"
+        "#).unwrap();
+        fs::write(dir.join("nested.pbrt"), r#"
+            Shape "sphere" "float radius" 1.5
+        "#).unwrap();
+        fs::write(dir.join("part2.pbrt"), r#"
+            Translate 1 0 0
+            Shape "sphere" "float radius" 2
+        "#).unwrap();
+        fs::write(dir.join("part3.pbrt"), r#"
+            Shape "sphere" "float radius" 3
+        "#).unwrap();
+        fs::write(dir.join("scene.pbrt"), r#"
+            WorldBegin
+            Material "diffuse"
+            Shape "sphere" "float radius" 0.5
+            Import "part1.pbrt"
+            Import "part2.pbrt"
+            Include "part3.pbrt"
+        "#).unwrap();
+        let scene = parse_pbrt_v4_input_file(dir.join("scene.pbrt")).unwrap();
+
+        let radii: Vec<f32> = scene.shapes.iter().map(|shape| match shape {
+            ShapeDescription::Sphere(desc) => desc.radius,
+            _ => 0.0
+        }).collect();
+        assert_eq!(radii, vec![0.5, 1.0, 1.5, 2.0, 3.0]);
+        assert_eq!(scene.materials.len(), 2);
+        assert_ne!(scene.materials[0].name, scene.materials[1].name);
+        match &scene.shapes[3] {
+            ShapeDescription::Sphere(desc) => {
+                assert!(desc.transform.is_some());
+                assert_eq!(desc.material, scene.materials[0].name);
+            }
+            _ => panic!("Sphere expected!")
+        }
+
+        // parsing thread that waits for imports helps to parse them
+        let pool = rayon::ThreadPoolBuilder::new().num_threads(1).build().unwrap();
+        let scene = pool.install(|| parse_pbrt_v4_input_file(dir.join("scene.pbrt")).map_err(|e| e.to_string())).unwrap();
+        assert_eq!(scene.shapes.len(), 5);
+
+        fs::write(dir.join("error.pbrt"), r#"Import "missing.pbrt""#).unwrap();
+        assert!(parse_pbrt_v4_input_file(dir.join("error.pbrt")).is_err());
+    }
+
+    #[test]
+    fn parse_textures() {
+        let text = r#"
+            WorldBegin
+            Texture "grid" "spectrum" "imagemap" "string filename" "grid.png" "float scale" 0.5
+                "string filter" "bilinear" "string wrap" "repeat" "float maxanisotropy" 8
+            Texture "bumps" "float" "imagemap" "string filename" "bumps.exr" "bool invert" true
+            Texture "wood" "spectrum" "imagemap" "string filename" "wood.png" "string encoding" "gamma 2.2"
+            Material "conductor" "texture roughness" "bumps"
+            Shape "sphere" "float radius" 1
+            MakeNamedMaterial "floor" "string type" "diffuse" "texture reflectance" "grid"
+        "#;
+        let scene = parse_text(text).unwrap();
+        assert_eq!(scene.textures.len(), 3);
+        let encodings: Vec<ColorEncoding> = scene.textures.iter().map(|texture| texture.encoding).collect();
+        assert_eq!(encodings, vec![ColorEncoding::SRGB, ColorEncoding::Linear, ColorEncoding::Gamma(2.2)]);
+        assert_eq!((scene.textures[0].name.as_str(), scene.textures[0].scale), ("grid", 0.5));
+        assert!(scene.textures[0].filename.as_ref().is_some_and(|fname| fname.ends_with("grid.png")));
+        assert!(scene.textures[1].invert);
+        assert_eq!(scene.materials[0].roughness_texture.as_deref(), Some("bumps"));
+        assert_eq!(scene.materials[1].diffuse_texture.as_deref(), Some("grid"));
+
+        assert!(parse_text("Material \"diffuse\" \"texture reflectance\" \"grid\"\n").is_err());
+        assert!(parse_text("Texture \"grid\" \"spectrum\" \"wrinkled\"\n").is_err());
+        assert!(parse_text("Texture \"grid\" \"spectrum\" \"imagemap\" \"float scale\" 2\n").is_err());
+        assert!(parse_text("Texture \"grid\" \"spectrum\" \"imagemap\" \"string filename\" \"grid.png\" \"string encoding\" \"log\"\n").is_err());
+    }
+
+    #[test]
+    fn parse_procedural_textures() {
+        let text = r#"
+            WorldBegin
+            Texture "checks" "spectrum" "checkerboard" "float uscale" 8 "float vscale" 8 "rgb tex1" [1 0 0] "float tex2" 0.2
+            Texture "noise" "float" "fbm" "integer octaves" 4
+            Texture "dirty" "spectrum" "mix" "texture tex1" "checks" "rgb tex2" [0.1 0.1 0.1] "texture amount" "noise"
+            Texture "dark" "spectrum" "scale" "texture tex" "dirty" "float scale" 0.5
+            Scale 2 2 2
+            Texture "stone" "spectrum" "marble" "float scale" 4 "float variation" 0.5 "string space" "object"
+        "#;
+        let scene = parse_text(text).unwrap();
+        let textures = &scene.textures;
+        assert_eq!(textures.iter().map(|desc| desc.typ).collect::<Vec<_>>(), vec![TextureType::Checkerboard,
+            TextureType::Fbm, TextureType::Mix, TextureType::Scale, TextureType::Marble]);
+        assert_eq!((textures[0].uscale, textures[1].octaves), (8.0, 4));
+        assert!(matches!(&textures[2].amount, TextureInput::Texture(name) if name == "noise"));
+        assert!(matches!(&textures[3].tex1, TextureInput::Texture(name) if name == "dirty"));
+        assert!(matches!(&textures[3].tex2, TextureInput::Value(rgb) if rgb.g == 0.5));
+        assert_eq!((textures[4].scale, textures[4].variation), (4.0, 0.5));
+        assert!(textures[4].transform.is_some() && textures[0].transform.is_none());
+        assert_eq!((textures[4].space, textures[1].space), (CoordinateSpace::Object, CoordinateSpace::World));
+
+        assert!(parse_text("Texture \"mix\" \"spectrum\" \"mix\" \"texture tex1\" \"missing\"\n").is_err());
+        assert!(parse_text("Texture \"checks\" \"spectrum\" \"checkerboard\" \"integer dimension\" 3\n").is_err());
+        assert!(parse_text("Texture \"noise\" \"float\" \"fbm\" \"string space\" \"camera\"\n").is_err());
+    }
+
+    #[test]
+    fn invalid_transformations() {
+        let text = r#"
+            LookAt 0 0 5  0 0 0  0 1 0
+            Camera "perspective"
+            WorldBegin
+            Material "diffuse"
+            AttributeBegin
+            Scale 1 0 1
+            Shape "sphere" "float radius" 1
+            LightSource "point" "point3 from" [0 1 0]
+            AttributeEnd
+            Shape "sphere" "float radius" 2
+            ConcatTransform [1 0 0 0  1 0 0 0  0 0 1 0  0 0 0 1]
+            Translate 0 1 0
+            Shape "sphere" "float radius" 3
+            Identity
+            Shape "sphere" "float radius" 4
+        "#;
+        let error = parse_text(text).err().unwrap().to_string();
+        assert!(error.contains("Shape sphere") && error.contains("Scale"));
+
+        // objects placed by invalid transformations are skipped until transformation is replaced
+        let scene = parse_text(&format!("Option \"bool skipinvalidtransforms\" true\n{}", text)).unwrap();
+        assert!(scene.settings.skip_invalid_transforms && scene.lights.is_empty());
+        let radii: Vec<f32> = scene.shapes.iter().map(|shape| match shape {
+            ShapeDescription::Sphere(desc) => desc.radius,
+            _ => 0.0
+        }).collect();
+        assert_eq!(radii, vec![2.0, 4.0]);
+
+        // camera can't be skipped
+        let text = "Option \"bool skipinvalidtransforms\" true\nLookAt 0 0 5  0 0 0  0 0 1\nCamera \"perspective\"\n";
+        assert!(parse_text(text).err().unwrap().to_string().contains("LookAt"));
+    }
+
+    #[test]
+    fn parse_named_materials() {
+        let text = r#"
+            WorldBegin
+            MakeNamedMaterial "red" "rgb reflectance" [0.8 0.1 0.1] "string type" "diffuse"
+            MakeNamedMaterial "gray" "string type" [ "diffuse" ]
+            NamedMaterial "red"
+            Shape "sphere" "float radius" 1
+        "#;
+        let scene = parse_text(text).unwrap();
+        assert_eq!(scene.materials.len(), 2);
+        assert_eq!(scene.materials[0].name, "red");
+        assert_eq!(scene.materials[0].diffuse.r, 0.8);
+        match &scene.shapes[0] {
+            ShapeDescription::Sphere(desc) => assert_eq!(desc.material, "red"),
+            _ => panic!("Sphere expected!")
+        }
+
+        // names of named and anonymous materials never collide
+        let text = r#"
+            WorldBegin
+            Material "diffuse" "rgb reflectance" [0.5 0.5 0.5]
+            Shape "sphere" "float radius" 1
+            MakeNamedMaterial "material" "string type" "conductor"
+            AttributeBegin
+            MakeNamedMaterial "material" "string type" "dielectric"
+            NamedMaterial "material"
+            Shape "sphere" "float radius" 2
+            AttributeEnd
+            NamedMaterial "material"
+            Shape "sphere" "float radius" 3
+        "#;
+        let scene = parse_text(text).unwrap();
+        let names: Vec<&str> = scene.materials.iter().map(|material| material.name.as_str()).collect();
+        assert_eq!(names, vec!["material", "material.1", "material.2"]);
+        let shape_materials: Vec<&str> = scene.shapes.iter().map(|shape| match shape {
+            ShapeDescription::Sphere(desc) => desc.material.as_str(),
+            _ => ""
+        }).collect();
+        assert_eq!(shape_materials, vec!["material", "material.2", "material.1"]);
+
+        let glass = parse_text("MakeNamedMaterial \"glass\" \"string type\" \"thindielectric\" \"float eta\" 1.33\n").unwrap();
+        assert_eq!(glass.materials[0].typ, MaterialType::ThinDielectric);
+        assert_eq!(glass.materials[0].eta, 1.33);
+        let bk7 = parse_text("MakeNamedMaterial \"bk7\" \"string type\" \"dielectric\" \"spectrum eta\" \"glass-BK7\"\n").unwrap();
+        assert_eq!(bk7.materials[0].typ, MaterialType::Dielectric);
+        assert_eq!(bk7.materials[0].eta, 1.5168);
+        assert!(parse_text("MakeNamedMaterial \"a\" \"string type\" \"dielectric\" \"spectrum eta\" \"glass-XYZ\"\n").is_err());
+        let leaf = parse_text("MakeNamedMaterial \"leaf\" \"string type\" \"diffusetransmission\" \"rgb transmittance\" [0.2 0.4 0.1] \"float scale\" 2\n").unwrap();
+        assert_eq!(leaf.materials[0].typ, MaterialType::DiffuseTransmission);
+        assert_eq!(leaf.materials[0].diffuse.r, 0.5);
+        assert_eq!(leaf.materials[0].transmittance.g, 0.8);
+        assert!(parse_text("MakeNamedMaterial \"a\" \"string type\" \"diffuse\" \"float scale\" 2\n").is_err());
+        let rough = parse_text("MakeNamedMaterial \"rough\" \"string type\" \"diffuse\" \"float roughness\" 0.09 \"bool remaproughness\" false\n").unwrap();
+        assert_eq!(rough.materials[0].roughness, 0.09);
+        assert!(!rough.materials[0].remap_roughness);
+        assert!(parse_text(r#"MakeNamedMaterial "a" "rgb reflectance" [0.8 0.1 0.1]"#).is_err());
+        assert!(parse_text(r#"NamedMaterial "missing""#).is_err());
+        assert!(parse_text(r#"MakeNamedMaterial "a" "string type" "glossy""#).is_err());
+
+        let hair = parse_text("MakeNamedMaterial \"hair\" \"string type\" \"hair\" \"float eumelanin\" 0.5 \"float beta_m\" 0.2 \"float eta\" 1.6\n").unwrap();
+        assert_eq!(hair.materials[0].typ, MaterialType::Hair);
+        assert_eq!(hair.materials[0].hair.sigma_a.r, sigma_a_from_melanin(0.5, 0.0).r);
+        assert_eq!((hair.materials[0].hair.beta_m, hair.materials[0].hair.eta), (0.2, 1.6));
+        let hair = parse_text(r#"MakeNamedMaterial "hair" "string type" "hair" "rgb sigma_a" [0.1 0.2 0.3]"#).unwrap();
+        assert_eq!(hair.materials[0].hair.sigma_a.b, 0.3);
+    }
+
+    #[test]
+    fn parse_conductor_materials() {
+        let gold = parse_text(r#"MakeNamedMaterial "gold" "string type" "conductor" "spectrum eta" "metal-Au-eta" "spectrum k" "metal-Au-k""#).unwrap();
+        assert_eq!(gold.materials[0].typ, MaterialType::Conductor);
+        let (eta, k) = conductor_preset("Au").unwrap();
+        assert_eq!((gold.materials[0].conductor_eta.b, gold.materials[0].conductor_k.r), (eta.b, k.r));
+        let copper = parse_text(r#"MakeNamedMaterial "copper" "string type" "conductor""#).unwrap();
+        let (eta, k) = conductor_preset("Cu").unwrap();
+        assert_eq!((copper.materials[0].conductor_eta.g, copper.materials[0].conductor_k.b), (eta.g, k.b));
+        let custom = parse_text(r#"MakeNamedMaterial "custom" "string type" "conductor" "rgb eta" [0.2 0.3 0.4] "rgb k" [3 2 1]"#).unwrap();
+        assert_eq!(custom.materials[0].conductor_eta.g, 0.3);
+        assert_eq!(custom.materials[0].conductor_k.r, 3.0);
+        let tinted = parse_text(r#"MakeNamedMaterial "tinted" "string type" "conductor" "rgb reflectance" [0.9 0.5 0.0]"#).unwrap();
+        let f0 = crate::materials::fr_conductor(1.0, tinted.materials[0].conductor_eta, tinted.materials[0].conductor_k);
+        assert!((f0.r - 0.9).abs() < 1e-4 && (f0.g - 0.5).abs() < 1e-4 && f0.b.abs() < 1e-4);
+        let brushed = parse_text(r#"MakeNamedMaterial "brushed" "string type" "conductor" "float uroughness" 0.01 "float vroughness" [0.09]"#).unwrap();
+        let (alpha_x, alpha_y) = brushed.materials[0].alphas();
+        assert!((alpha_x - 0.1).abs() < 1e-6 && (alpha_y - 0.3).abs() < 1e-6);
+        assert!(parse_text(r#"MakeNamedMaterial "a" "string type" "conductor" "spectrum eta" "metal-Xx-eta""#).is_err());
+    }
+
+    #[test]
+    fn parse_trianglemesh_alpha_and_face_indices() {
+        let text = r#"
+            WorldBegin
+            Material "diffuse"
+            Texture "leaf_mask" "float" "checkerboard" "float uscale" 4 "float vscale" 4
+            Shape "trianglemesh" "point3 P" [0 0 0 1 0 0 1 1 0 0 1 0] "integer indices" [0 1 2 0 2 3]
+                "integer faceIndices" [4 7] "float alpha" 0.5 "texture alpha" "leaf_mask"
+        "#;
+        let scene = parse_text(text).unwrap();
+        match &scene.shapes[0] {
+            ShapeDescription::Mesh(desc) => {
+                assert_eq!(desc.face_indices, Some(vec![4, 7]));
+                assert_eq!(desc.alpha, 0.5);
+                assert_eq!(desc.alpha_texture.as_ref(), Some(&scene.textures[0].name));
+            }
+            _ => panic!("Mesh expected!")
+        }
+        let text = "Shape \"trianglemesh\" \"point3 P\" [0 0 0 1 0 0 1 1 0] \"texture alpha\" \"missing\"\n";
+        assert!(parse_text(text).is_err());
+        let text = "Shape \"trianglemesh\" \"point3 P\" [0 0 0 1 0 0 1 1 0] \"integer faceIndices\" [1 2]\n";
+        assert!(parse_text(text).is_err());
+    }
+
+    #[test]
+    fn parse_mesh_arrays() {
+        let text = r#"
+            WorldBegin
+            Material "diffuse"
+            Shape "trianglemesh" "point3 P" [ 0 0 0
+                                              1 0 0  # second vertex
+                                              1 1 0 ]
+                "normal N" [0 0 1 0 0 1 0 0 1] "point2 uv" [0 0 1 0 1 1]
+                "integer indices" [0 1 2]
+        "#;
+        let scene = parse_text(text).unwrap();
+        match &scene.shapes[0] {
+            ShapeDescription::Mesh(desc) => {
+                assert_eq!(desc.vertices.as_ref().map(|vertices| vertices[2]), Some(Point3::new(1.0, 1.0, 0.0)));
+                assert_eq!(desc.normals.as_ref().map(|normals| normals.len()), Some(3));
+                assert_eq!(desc.uvs.as_ref().map(|uvs| uvs[1]), Some(Point2::new(1.0, 0.0)));
+                assert_eq!(desc.indices, Some(vec![0, 1, 2]));
+            }
+            _ => panic!("Mesh expected!")
+        }
+        let error = parse_text("Shape \"trianglemesh\" \"point3 P\" [0 0 0 1 0]\n").err().unwrap();
+        assert!(error.to_string().contains("Expected 3 values per point"));
+        assert!(parse_text("Shape \"trianglemesh\" \"integer indices\" [0 1 -2]\n").is_err());
+        assert!(parse_text("Shape \"trianglemesh\" \"point3 P\" [0 0 0\n").is_err());
+    }
+
+    #[test]
+    fn parse_bilinearmesh() {
+        let text = r#"
+            WorldBegin
+            Material "diffuse"
+            Translate 0 0 2
+            Shape "bilinearmesh" "point3 P" [0 0 0 1 0 0 0 1 0 1 1 0] "point2 uv" [0 0 1 0 0 1 1 1]
+            Shape "bilinearmesh" "point3 P" [0 0 0 1 0 0 0 1 0 1 1 0 2 0 0 2 1 0] "integer indices" [0 1 2 3 1 4 3 5]
+        "#;
+        let scene = parse_text(text).unwrap();
+        match &scene.shapes[0] {
+            ShapeDescription::BilinearMesh(desc) => {
+                assert_eq!(desc.indices, Some(vec![0, 1, 2, 3]));
+                assert_eq!(desc.uvs.as_ref().map(|uvs| uvs.len()), Some(4));
+                assert!(desc.transform.is_some());
+            }
+            _ => panic!("Bilinear mesh expected!")
+        }
+        match &scene.shapes[1] {
+            ShapeDescription::BilinearMesh(desc) => assert!((desc.area() - 2.0).abs() < 1e-5),
+            _ => panic!("Bilinear mesh expected!")
+        }
+        let text = "Shape \"bilinearmesh\" \"point3 P\" [0 0 0 1 0 0 0 1 0] \"integer indices\" [0 1 2]\n";
+        assert!(parse_text(text).is_err());
+        let text = "Shape \"bilinearmesh\" \"point3 P\" [0 0 0 1 0 0 0 1 0 1 1 0] \"point2 uv\" [0 0 1 0 0 1]\n";
+        assert!(parse_text(text).is_err());
+    }
+
+    #[test]
+    fn parse_scoped_named_materials() {
+        let text = r#"
+            WorldBegin
+            MakeNamedMaterial "paint" "string type" "diffuse" "rgb reflectance" [0.1 0.1 0.1]
+            AttributeBegin
+            MakeNamedMaterial "paint" "string type" "diffuse" "rgb reflectance" [0.9 0.9 0.9]
+            MakeNamedMaterial "local" "string type" "diffuse"
+            NamedMaterial "paint"
+            Shape "sphere" "float radius" 1
+            AttributeEnd
+            NamedMaterial "paint"
+            Shape "sphere" "float radius" 2
+        "#;
+        let scene = parse_text(text).unwrap();
+        let material = |index: usize| match &scene.shapes[index] {
+            ShapeDescription::Sphere(desc) => scene.materials.iter().find(|m| m.name == desc.material).unwrap(),
+            _ => panic!("Sphere expected!")
+        };
+        assert_eq!(material(0).diffuse.r, 0.9);
+        assert_eq!(material(1).diffuse.r, 0.1);
+
+        let text = r#"
+            WorldBegin
+            AttributeBegin
+            MakeNamedMaterial "local" "string type" "diffuse"
+            AttributeEnd
+            NamedMaterial "local"
+        "#;
+        assert!(parse_text(text).is_err());
+    }
+
+    #[test]
+    fn parse_object_instances() {
+        let text = r#"
+            WorldBegin
+            Material "diffuse"
+            ObjectBegin "quad"
+            Translate 0 0 5
+            Shape "trianglemesh" "point3 P" [0 0 0 1 0 0 1 1 0] "integer indices" [0 1 2]
+            ObjectEnd
+            ObjectInstance "quad"
+            TransformTimes 0 0.5
+            ActiveTransform EndTime
+            Translate 2 0 0
+            ActiveTransform All
+            ObjectInstance "quad"
+        "#;
+        let scene = parse_text(text).unwrap();
+        assert_eq!(scene.prototypes.len(), 1);
+        assert_eq!(scene.prototypes[0].meshes.len(), 1);
+        assert_eq!(scene.shapes.len(), 2);
+        match &scene.shapes[0] {
+            ShapeDescription::Instance(desc) => {
+                assert_eq!(desc.prototype, "quad");
+                assert!(desc.end_transform.is_none());
+            }
+            _ => panic!("Instance expected!")
+        }
+        match &scene.shapes[1] {
+            ShapeDescription::Instance(desc) => {
+                assert_eq!(desc.transform, Transformation::identity());
+                assert_eq!(desc.end_transform, Some(Transformation::translate(&Vec3::new(2.0, 0.0, 0.0))));
+                assert_eq!((desc.start_time, desc.end_time), (0.0, 0.5));
+            }
+            _ => panic!("Instance expected!")
+        }
+        // spheres can't be instanced, they are skipped
+        let scene = parse_text("Material \"diffuse\"\nObjectBegin \"a\"\nShape \"sphere\"\nObjectEnd\n").unwrap();
+        assert!(scene.shapes.is_empty() && scene.prototypes[0].meshes.is_empty());
+        assert!(parse_text("ObjectEnd\n").is_err());
+        assert!(parse_text("ActiveTransform Middle\n").is_err());
+    }
+
+    #[test]
+    fn parse_accelerator() {
+        let scene = parse_text("WorldBegin\n").unwrap();
+        assert_eq!(scene.settings.accelerator, AcceleratorType::Bvh(BVHBuildMethod::SAH));
+        let scene = parse_text("Accelerator \"bvh\" \"string splitmethod\" \"middle\" \"integer maxnodeprims\" 4\nWorldBegin\n").unwrap();
+        assert_eq!(scene.settings.accelerator, AcceleratorType::Bvh(BVHBuildMethod::Midpoint));
+        let scene = parse_text("Accelerator \"linear\"\nWorldBegin\n").unwrap();
+        assert_eq!(scene.settings.accelerator, AcceleratorType::Linear);
+        assert!(parse_text("Accelerator \"kdtree\"\nWorldBegin\n").is_err());
+    }
+
+    #[test]
+    fn identical_materials_are_shared() {
+        let mut text = String::from("WorldBegin\n");
+        for i in 0..100 {
+            text += &format!("AttributeBegin\nMaterial \"diffuse\"\nAreaLightSource \"diffuse\" \"rgb L\" [4 4 4]\n\
+                              Translate {} 0 0\nShape \"sphere\" \"float radius\" 0.5\nAttributeEnd\n", i);
+        }
+        text += "AreaLightSource \"diffuse\" \"rgb L\" [4 4 4] \"float power\" 10\n";
+        text += "Shape \"sphere\" \"float radius\" 1\nShape \"sphere\" \"float radius\" 1\n";
+        text += "Shape \"sphere\" \"float radius\" 2\n";
+        let scene = parse_text(&text).unwrap();
+        assert_eq!(scene.shapes.len(), 103);
+        // diffuse, emitter, emitter with power and two emitters normalized to area of shape
+        assert_eq!(scene.materials.len(), 5);
+    }
+
+    #[test]
+    fn parse_medium_without_type() {
+        let text = r#"MakeNamedMedium "fog" "float scale" 2"#;
+        assert!(parse_text(text).is_err());
+    }
+
+    #[test]
+    fn parse_grid_medium() {
+        let text = r#"MakeNamedMedium "smoke" "string type" "uniformgrid" "integer nx" 2 "integer ny" 1 "integer nz" 1 "float density" [0.5 1]"#;
+        let scene = parse_text(text).unwrap();
+        assert_eq!(scene.media[0].density.as_ref().unwrap().len(), 2);
+        assert!(parse_text(r#"MakeNamedMedium "smoke" "string type" "uniformgrid" "integer nx" 2"#).is_err());
+        assert!(parse_text(r#"MakeNamedMedium "cloud" "string type" "cloud""#).is_err());
+        assert!(parse_text(r#"MakeNamedMedium "vdb" "string type" "nanovdb""#).is_err());
+    }
+}
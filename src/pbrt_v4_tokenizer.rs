@@ -7,6 +7,37 @@ impl<'a> PBRTTokenizer<'a> {
     pub fn new(text: &'a str) -> Self {
         PBRTTokenizer {text}
     }
+
+    /// Text between brackets of block of values, next token must be '['. Whole block is found in one
+    /// pass instead of token by token, tokenizer continues after closing ']'.
+    pub fn next_block(&mut self) -> Result<&'a str, String> {
+        let (start, end) = find_offsets(self.text);
+        if end <= start {
+            return Err("Missing token!".to_string())
+        }
+        if &self.text[start..end] != "[" {
+            return Err("Expected '[' token!".to_string())
+        }
+        let text = &self.text[end..];
+        let mut inside_comment = false;
+        for (index, c) in text.bytes().enumerate() {
+            match c {
+                b'\n' => inside_comment = false,
+                b'#' => inside_comment = true,
+                b']' if !inside_comment => {
+                    self.text = &text[index + 1..];
+                    return Ok(&text[..index])
+                }
+                _ => {}
+            }
+        }
+        Err("Missing ']' token!".to_string())
+    }
+}
+
+/// Values of block returned by next_block, comments are skipped
+pub fn block_values(block: &str) -> impl Iterator<Item = &str> {
+    block.split('\n').flat_map(|line| line.split('#').next().unwrap_or_default().split_ascii_whitespace())
 }
 
 fn find_offsets(text: &str) -> (usize, usize) {
@@ -110,4 +141,19 @@ mod tests {
         }
     }
 
+    #[test]
+    fn numeric_blocks() {
+        let text = " [ 0 1.5\n-2 # comment ] 7\n\t3e2 ] \"float x\" [1] [2";
+        let mut tokenizer = PBRTTokenizer::new(text);
+        let block = tokenizer.next_block().unwrap();
+        assert_eq!(block_values(block).collect::<Vec<_>>(), vec!["0", "1.5", "-2", "3e2"]);
+        assert_eq!(tokenizer.next(), Some("float x"));
+        assert_eq!(tokenizer.next_block(), Ok("1"));
+        assert_eq!(tokenizer.next_block(), Err("Missing ']' token!".to_string()));
+
+        let mut tokenizer = PBRTTokenizer::new("\"float x\" [1]");
+        assert_eq!(tokenizer.next_block(), Err("Expected '[' token!".to_string()));
+        assert_eq!(PBRTTokenizer::new("  ").next_block(), Err("Missing token!".to_string()));
+    }
+
 }
@@ -0,0 +1,223 @@
+//! Image space passes applied to HDR image before tone mapping.
+
+use crate::color::{RGB, RGBAccumlationBuffer};
+use crate::image_diff::FloatImage;
+use crate::rgb::{ImageSize, RGB8uffer};
+use crate::scene::Scene;
+use rayon::prelude::*;
+
+/// Exponential fog, pixel is blended with fog color by its distance from camera.
+#[derive(Debug, Clone, Copy)]
+pub struct FogProperties {
+    pub color: RGB,
+    pub density: f32
+}
+
+impl Default for FogProperties {
+    fn default() -> Self {
+        Self { color: RGB::new(0.7, 0.75, 0.8), density: 0.1 }
+    }
+}
+
+/// Glow around bright pixels. Pixels brighter than threshold are blurred on several
+/// levels of image pyramid, every level doubles width of the blur.
+#[derive(Debug, Clone, Copy)]
+pub struct BloomProperties {
+    /// Luminance above which pixels contribute to bloom
+    pub threshold: f32,
+    pub intensity: f32,
+    /// Standard deviation of gaussian blur in pixels on first level
+    pub radius: f32,
+    pub levels: usize
+}
+
+impl Default for BloomProperties {
+    fn default() -> Self {
+        Self { threshold: 1.0, intensity: 0.2, radius: 2.0, levels: 4 }
+    }
+}
+
+/// Distance from camera to first intersection through center of every pixel (row-major order),
+/// pixels that don't hit anything have infinite depth. It is used only by integrators that don't
+/// accumulate depth with auxiliary outputs, rows are traced in parallel.
+pub fn render_depth(scene: &Scene) -> Vec<f32> {
+    let resolution = scene.settings.resolution;
+    (0..resolution.height).into_par_iter().flat_map_iter(|y| {
+        (0..resolution.width).map(move |x| {
+            let ray = scene.camera.generate_ray(x as f32 + 0.5, y as f32 + 0.5);
+            match scene.geometry.intersect(&ray) {
+                Some(isect) => isect.hit_point.distance(ray.origin),
+                None => f32::INFINITY
+            }
+        })
+    }).collect()
+}
+
+pub fn apply_fog(image: &mut FloatImage, depth: &[f32], fog: &FogProperties) {
+    for (pixel, distance) in image.pixels_mut().iter_mut().zip(depth.iter()) {
+        let transmittance = (-fog.density * distance).exp();
+        *pixel = *pixel * transmittance + fog.color * (1.0 - transmittance);
+    }
+}
+
+// Kernel of zero (or invalid) sigma doesn't blur, so bloom of zero radius is not spread
+fn gaussian_kernel(sigma: f32) -> Vec<f32> {
+    if sigma <= 0.0 || sigma.is_nan() {
+        return vec![1.0]
+    }
+    let radius = (3.0 * sigma).ceil().max(1.0) as i32;
+    let kernel: Vec<f32> = (-radius..=radius).map(|i| (-(i * i) as f32 / (2.0 * sigma * sigma)).exp()).collect();
+    let sum: f32 = kernel.iter().sum();
+    kernel.iter().map(|k| k / sum).collect()
+}
+
+// Separable gaussian blur, pixels outside of image are clamped to edge
+fn blur(pixels: &[RGB], size: ImageSize, kernel: &[f32]) -> Vec<RGB> {
+    let radius = (kernel.len() / 2) as i32;
+    let (w, h) = (size.width as i32, size.height as i32);
+    let pass = |src: &[RGB], horizontal: bool| -> Vec<RGB> {
+        let mut dst = vec![RGB::zero(); src.len()];
+        for y in 0..h {
+            for x in 0..w {
+                let mut sum = RGB::zero();
+                for (k, weight) in kernel.iter().enumerate() {
+                    let offset = k as i32 - radius;
+                    let (sx, sy) = if horizontal {
+                        ((x + offset).clamp(0, w - 1), y)
+                    } else {
+                        (x, (y + offset).clamp(0, h - 1))
+                    };
+                    sum += src[(sy * w + sx) as usize] * *weight;
+                }
+                dst[(y * w + x) as usize] = sum;
+            }
+        }
+        dst
+    };
+    pass(&pass(pixels, true), false)
+}
+
+// Half resolution image, every pixel is average of 2x2 block
+fn downsample(pixels: &[RGB], size: ImageSize) -> (Vec<RGB>, ImageSize) {
+    let half = ImageSize::new(size.width.div_ceil(2), size.height.div_ceil(2));
+    let mut result = Vec::with_capacity(half.width * half.height);
+    for y in 0..half.height {
+        for x in 0..half.width {
+            let mut sum = RGB::zero();
+            for (dx, dy) in [(0, 0), (1, 0), (0, 1), (1, 1)] {
+                let sx = (2 * x + dx).min(size.width - 1);
+                let sy = (2 * y + dy).min(size.height - 1);
+                sum += pixels[sy * size.width + sx];
+            }
+            result.push(sum * 0.25);
+        }
+    }
+    (result, half)
+}
+
+// Bilinear lookup, x and y are in pixel coordinates of the image
+fn sample_bilinear(pixels: &[RGB], size: ImageSize, x: f32, y: f32) -> RGB {
+    let x = (x - 0.5).clamp(0.0, (size.width - 1) as f32);
+    let y = (y - 0.5).clamp(0.0, (size.height - 1) as f32);
+    let (x0, y0) = (x as usize, y as usize);
+    let (x1, y1) = ((x0 + 1).min(size.width - 1), (y0 + 1).min(size.height - 1));
+    let (fx, fy) = (x - x0 as f32, y - y0 as f32);
+    let p = |x: usize, y: usize| pixels[y * size.width + x];
+    (p(x0, y0) * (1.0 - fx) + p(x1, y0) * fx) * (1.0 - fy) + (p(x0, y1) * (1.0 - fx) + p(x1, y1) * fx) * fy
+}
+
+pub fn apply_bloom(image: &mut FloatImage, bloom: &BloomProperties) {
+    let size = image.size();
+    let mut level: Vec<RGB> = image.pixels().iter().map(|p| {
+        let luminance = p.luminance();
+        if luminance > bloom.threshold {
+            *p * ((luminance - bloom.threshold) / luminance)
+        } else {
+            RGB::zero()
+        }
+    }).collect();
+    let mut level_size = size;
+    let kernel = gaussian_kernel(bloom.radius);
+    let mut glow = vec![RGB::zero(); size.width * size.height];
+    for depth in 0..bloom.levels.max(1) {
+        let blurred = blur(&level, level_size, &kernel);
+        let scale = 0.5f32.powi(depth as i32);
+        for y in 0..size.height {
+            for x in 0..size.width {
+                let (lx, ly) = ((x as f32 + 0.5) * scale, (y as f32 + 0.5) * scale);
+                glow[y * size.width + x] += sample_bilinear(&blurred, level_size, lx, ly);
+            }
+        }
+        if level_size.width == 1 && level_size.height == 1 {
+            break;
+        }
+        (level, level_size) = downsample(&level, level_size);
+    }
+    let weight = bloom.intensity / bloom.levels.max(1) as f32;
+    for (pixel, glow) in image.pixels_mut().iter_mut().zip(glow.iter()) {
+        *pixel += *glow * weight;
+    }
+}
+
+/// Apply post processing passes from settings and tone map image. Fog uses depth accumulated
+/// by integrator (see AovType::Depth), primary rays are traced again only when it is missing.
+pub fn finish_image(scene: &Scene, accum: &RGBAccumlationBuffer, depth: Option<Vec<f32>>) -> RGB8uffer {
+    let settings = &scene.settings;
+    // NOTE: postprocessing needs whole image, preview of bucket rendering is only tone mapped
+    if (settings.fog.is_none() && settings.bloom.is_none()) || settings.bucket_output.is_some() {
+        return accum.to_rgb8_buffer(&settings.tonemap)
+    }
+    let mut image = FloatImage::from(accum);
+    if let Some(fog) = &settings.fog {
+        let depth = depth.unwrap_or_else(|| render_depth(scene));
+        apply_fog(&mut image, &depth, fog);
+    }
+    if let Some(bloom) = &settings.bloom {
+        apply_bloom(&mut image, bloom);
+    }
+    image.to_rgb8_buffer(&settings.tonemap)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fog_attenuation() {
+        let mut image = FloatImage::new(ImageSize::new(3, 1), vec![RGB::new(1.0, 0.0, 0.0); 3]);
+        let fog = FogProperties { color: RGB::new(0.0, 0.0, 1.0), density: 0.5 };
+        apply_fog(&mut image, &[0.0, 2.0, f32::INFINITY], &fog);
+        assert_eq!(image.pixels()[0].r, 1.0);
+        let t = (-1.0f32).exp();
+        assert!((image.pixels()[1].r - t).abs() < 1e-6 && (image.pixels()[1].b - (1.0 - t)).abs() < 1e-6);
+        assert_eq!((image.pixels()[2].r, image.pixels()[2].b), (0.0, 1.0));
+    }
+
+    #[test]
+    fn bloom_spreads_bright_pixels() {
+        let size = ImageSize::new(65, 65);
+        let mut pixels = vec![RGB::new(0.5, 0.5, 0.5); size.width * size.height];
+        pixels[32 * 65 + 32] = RGB::new(100.0, 100.0, 100.0);
+        let mut image = FloatImage::new(size, pixels);
+        let bloom = BloomProperties { threshold: 1.0, intensity: 0.5, radius: 1.5, levels: 3 };
+        apply_bloom(&mut image, &bloom);
+        let at = |x: usize, y: usize| image.get(x, y).unwrap().r;
+        assert!(at(32, 32) > 100.0);
+        assert!(at(33, 32) > 0.5 && at(32, 36) > 0.5);
+        assert!(at(33, 32) > at(36, 32));
+        assert!(at(31, 32) > at(28, 32));
+        // energy of bloom is intensity * energy above threshold
+        let added: f32 = image.pixels().iter().map(|p| p.r - 0.5).sum::<f32>() + 0.5 - 100.0;
+        assert!((added - 0.5 * 99.0).abs() < 2.0);
+    }
+
+    #[test]
+    fn identity_kernel_of_zero_radius() {
+        assert_eq!(gaussian_kernel(0.0), vec![1.0]);
+        assert_eq!(gaussian_kernel(-1.0), vec![1.0]);
+        let kernel = gaussian_kernel(1.0);
+        assert_eq!(kernel.len(), 7);
+        assert!((kernel.iter().sum::<f32>() - 1.0).abs() < 1e-6);
+    }
+}
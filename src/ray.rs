@@ -7,11 +7,23 @@ use crate::vec::{Normal, Point3, Vec3};
 pub struct Ray {
     pub origin: Point3,
     pub direction: Vec3,
+    /// Time inside of camera shutter interval when ray was emitted
+    pub time: f32,
+    /// Id of medium in which ray travels, None is vacuum
+    pub medium: Option<u32>,
 }
 
 impl Ray {
     pub fn new(origin: Point3, direction: Vec3) -> Self {
-        Self { origin, direction }
+        Self { origin, direction, time: 0.0, medium: None }
+    }
+
+    pub fn with_time(self, time: f32) -> Self {
+        Self { time, ..self }
+    }
+
+    pub fn with_medium(self, medium: Option<u32>) -> Self {
+        Self { medium, ..self }
     }
 
     pub fn point_at(&self, t: f32) -> Point3 {
@@ -23,7 +35,7 @@ impl Mul<Transformation> for Ray {
     type Output = Self;
 
     fn mul(self, rhs: Transformation) -> Self::Output {
-        Self::new(rhs * self.origin, (rhs * self.direction).normalize())
+        Self::new(rhs * self.origin, (rhs * self.direction).normalize()).with_time(self.time).with_medium(self.medium)
     }
 }
 
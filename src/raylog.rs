@@ -0,0 +1,159 @@
+//! Recording of traced rays for debugging
+//!
+//! Rays traced for one pixel are stored as line segments and exported as OBJ (segments of every
+//! kind are in their own group) or PLY (segments are colored by kind) that can be inspected in a 3D viewer.
+
+use std::error::Error;
+use std::fmt::Write;
+
+use crate::ray::Ray;
+use crate::vec::Point3;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RayKind {
+    /// Ray from camera
+    Primary,
+    /// Visibility ray between hit point and light
+    Shadow,
+    /// Ray that continues path after scattering
+    Indirect,
+}
+
+impl RayKind {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "primary" => Some(RayKind::Primary),
+            "shadow" => Some(RayKind::Shadow),
+            "indirect" => Some(RayKind::Indirect),
+            _ => None
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            RayKind::Primary => "primary",
+            RayKind::Shadow => "shadow",
+            RayKind::Indirect => "indirect",
+        }
+    }
+
+    fn color(&self) -> [u8; 3] {
+        match self {
+            RayKind::Primary => [255, 255, 255],
+            RayKind::Shadow => [255, 255, 0],
+            RayKind::Indirect => [0, 128, 255],
+        }
+    }
+}
+
+/// Pixel whose rays are recorded, kinds of recorded rays and file name of OBJ or PLY file.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RayLogOutput {
+    pub x: usize,
+    pub y: usize,
+    pub kinds: Vec<RayKind>,
+    pub output_fname: String
+}
+
+#[derive(Clone)]
+pub struct RaySegment {
+    pub kind: RayKind,
+    pub start: Point3,
+    pub end: Point3,
+}
+
+#[derive(Clone)]
+pub struct RayLog {
+    kinds: Vec<RayKind>,
+    // Length of segments of rays that didn't hit anything
+    miss_length: f32,
+    segments: Vec<RaySegment>,
+}
+
+impl RayLog {
+    pub fn new(kinds: &[RayKind], miss_length: f32) -> Self {
+        Self { kinds: kinds.to_vec(), miss_length, segments: Vec::new() }
+    }
+
+    pub fn add(&mut self, kind: RayKind, start: Point3, end: Point3) {
+        if self.kinds.contains(&kind) {
+            self.segments.push(RaySegment { kind, start, end });
+        }
+    }
+
+    /// Add segment of ray that ends at distance t, None is ray that missed geometry.
+    pub fn add_ray(&mut self, kind: RayKind, ray: &Ray, t: Option<f32>) {
+        self.add(kind, ray.origin, ray.point_at(t.unwrap_or(self.miss_length)));
+    }
+
+    pub fn segments(&self) -> &[RaySegment] {
+        &self.segments
+    }
+
+    pub fn to_obj(&self) -> String {
+        let mut obj = String::new();
+        for (index, segment) in self.segments.iter().enumerate() {
+            let (s, e) = (segment.start, segment.end);
+            let _ = writeln!(obj, "g {}", segment.kind.name());
+            let _ = writeln!(obj, "v {} {} {}\nv {} {} {}", s.x, s.y, s.z, e.x, e.y, e.z);
+            let _ = writeln!(obj, "l {} {}", 2 * index + 1, 2 * index + 2);
+        }
+        obj
+    }
+
+    pub fn to_ply(&self) -> String {
+        let mut ply = String::from("ply\nformat ascii 1.0\n");
+        let _ = writeln!(ply, "element vertex {}", 2 * self.segments.len());
+        ply += "property float x\nproperty float y\nproperty float z\n";
+        ply += "property uchar red\nproperty uchar green\nproperty uchar blue\n";
+        let _ = writeln!(ply, "element edge {}", self.segments.len());
+        ply += "property int vertex1\nproperty int vertex2\nend_header\n";
+        for segment in self.segments.iter() {
+            let [r, g, b] = segment.kind.color();
+            for p in [segment.start, segment.end] {
+                let _ = writeln!(ply, "{} {} {} {} {} {}", p.x, p.y, p.z, r, g, b);
+            }
+        }
+        for index in 0..self.segments.len() {
+            let _ = writeln!(ply, "{} {}", 2 * index, 2 * index + 1);
+        }
+        ply
+    }
+
+    /// Format is selected by extension of file name, OBJ is default.
+    #[cfg(feature = "fs")]
+    pub fn save(&self, fname: &str) -> Result<(), Box<dyn Error>> {
+        let text = if fname.to_lowercase().ends_with(".ply") { self.to_ply() } else { self.to_obj() };
+        std::fs::write(fname, text)?;
+        Ok(())
+    }
+
+    #[cfg(not(feature = "fs"))]
+    pub fn save(&self, fname: &str) -> Result<(), Box<dyn Error>> {
+        Err(format!("Ray log {} can't be saved, writing files requires fs feature!", fname).into())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vec::Vec3;
+
+    #[test]
+    fn ray_log_export() {
+        let mut log = RayLog::new(&[RayKind::Primary, RayKind::Shadow], 10.0);
+        let ray = Ray::new(Point3::new(0.0, 0.0, 0.0), Vec3::new(0.0, 0.0, 1.0));
+        log.add_ray(RayKind::Primary, &ray, Some(2.0));
+        log.add_ray(RayKind::Shadow, &ray, None);
+        log.add_ray(RayKind::Indirect, &ray, Some(1.0));
+        assert_eq!(log.segments().len(), 2);
+        assert_eq!(log.segments()[1].end.z, 10.0);
+
+        let obj = log.to_obj();
+        assert!(obj.contains("g shadow\nv 0 0 0\nv 0 0 10\nl 3 4\n"));
+        let ply = log.to_ply();
+        assert!(ply.contains("element vertex 4\n") && ply.contains("element edge 2\n"));
+        assert!(ply.ends_with("0 0 2 255 255 255\n0 0 0 255 255 0\n0 0 10 255 255 0\n0 1\n2 3\n"));
+    }
+}
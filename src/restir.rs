@@ -0,0 +1,121 @@
+//! Reservoir-based resampling of light samples (ReSTIR)
+//!
+//! Reservoir streams candidate samples and keeps one of them with probability proportional to its
+//! resampling weight. Reservoirs of neighbouring pixels can be merged, so pixel chooses from candidates
+//! of all of them and still traces only one shadow ray.
+
+#[derive(Debug, Clone, Copy)]
+pub struct Reservoir<T> {
+    sample: Option<T>,
+    /// Target function of selected sample at hit point of the reservoir
+    target: f32,
+    weight_sum: f32,
+    /// Number of candidates that were streamed through reservoir
+    count: f32,
+}
+
+impl<T> Default for Reservoir<T> {
+    fn default() -> Self {
+        Self { sample: None, target: 0.0, weight_sum: 0.0, count: 0.0 }
+    }
+}
+
+impl<T: Copy> Reservoir<T> {
+    /// Stream candidate, weight is target function divided by density of candidate. True is returned
+    /// when candidate replaced selected sample.
+    pub fn update(&mut self, sample: T, weight: f32, target: f32, u: f32) -> bool {
+        self.count += 1.0;
+        if weight <= 0.0 || !weight.is_finite() {
+            return false
+        }
+        self.weight_sum += weight;
+        let selected = u * self.weight_sum < weight;
+        if selected {
+            self.sample = Some(sample);
+            self.target = target;
+        }
+        selected
+    }
+
+    /// Candidate that couldn't be generated (light sample failed) still counts.
+    pub fn reject(&mut self) {
+        self.count += 1.0;
+    }
+
+    /// Merge reservoir of other hit point, sample is selected sample of other reservoir evaluated
+    /// at hit point of this reservoir and target is its target function here.
+    pub fn merge(&mut self, other: &Self, sample: Option<T>, target: f32, u: f32) -> bool {
+        let count = self.count;
+        let selected = match sample {
+            Some(sample) => self.update(sample, target * other.contribution_weight() * other.count, target, u),
+            None => false
+        };
+        self.count = count + other.count;
+        selected
+    }
+
+    pub fn sample(&self) -> Option<T> {
+        self.sample
+    }
+
+    pub fn count(&self) -> f32 {
+        self.count
+    }
+
+    /// Weight of selected sample that replaces one over its density in estimator, normalization is number
+    /// of candidates that could produce selected sample.
+    pub fn normalized_contribution_weight(&self, normalization: f32) -> f32 {
+        if self.target > 0.0 && normalization > 0.0 {
+            self.weight_sum / (normalization * self.target)
+        } else {
+            0.0
+        }
+    }
+
+    pub fn contribution_weight(&self) -> f32 {
+        self.normalized_contribution_weight(self.count)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rng::{PCGRng, Rng};
+
+    #[test]
+    fn reservoir_resampling() {
+        let mut rng = PCGRng::new(0xf123456789012345, 0);
+        let targets = [1.0, 3.0, 0.0, 4.0];
+        let mut histogram = [0usize; 4];
+        let mut estimate = 0.0;
+        let n = 20000;
+        for _ in 0..n {
+            // candidates are chosen uniformly, so weight is target divided by 1/4
+            let mut reservoir = Reservoir::default();
+            for _ in 0..8 {
+                let index = ((rng.rand_f32() * 4.0) as usize).min(3);
+                reservoir.update(index, targets[index] * 4.0, targets[index], rng.rand_f32());
+            }
+            let index = reservoir.sample().unwrap();
+            histogram[index] += 1;
+            // contribution weight replaces one over density, so sum of targets is estimated
+            estimate += targets[index] * reservoir.contribution_weight();
+        }
+        assert_eq!(histogram[2], 0);
+        assert!(histogram[3] > histogram[1] && histogram[1] > histogram[0]);
+        assert!((estimate / n as f32 - 8.0).abs() < 0.1);
+
+        let mut first = Reservoir::default();
+        first.update(0, 2.0, 1.0, 0.5);
+        first.reject();
+        let mut second = Reservoir::default();
+        second.update(1, 6.0, 3.0, 0.5);
+        assert_eq!(first.contribution_weight(), 1.0);
+        first.merge(&second, Some(1), 3.0, 0.99);
+        assert_eq!(first.count(), 3.0);
+        assert_eq!(first.sample(), Some(0));
+        first.merge(&second, None, 0.0, 0.0);
+        assert_eq!(first.count(), 4.0);
+    }
+}
@@ -1,7 +1,10 @@
 use std::error::Error;
+#[cfg(feature = "fs")]
 use std::path::Path;
 
 extern crate image;
+use image::ImageEncoder;
+use image::codecs::png::PngEncoder;
 
 #[derive(Debug, Copy, Clone)]
 pub struct ImageSize {
@@ -39,6 +42,10 @@ impl RGB8uffer {
         RGB8uffer {size, pixels}
     }
 
+    pub fn size(&self) -> ImageSize {
+        self.size
+    }
+
     pub fn get(&self, x: usize, y: usize) -> Option<&RGB8> {
         return self.pixels.get(y * self.size.width + x)
     }
@@ -53,9 +60,14 @@ impl RGB8uffer {
         self.pixels[y * self.size.width + x] = *rgb;
     }
 
+    /// Pixels as interleaved RGB bytes, rows from top to bottom (layout of height x width x 3 array)
+    pub fn to_rgb_bytes(&self) -> Vec<u8> {
+        self.pixels.iter().flat_map(|val| [val.red, val.green, val.blue]).collect()
+    }
+
+    #[cfg(feature = "fs")]
     pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), Box<dyn Error>> {
-        let output: Vec<u8> = self.pixels.iter().flat_map(
-            |val| [val.red, val.green, val.blue]).collect();
+        let output = self.to_rgb_bytes();
 
         let result = image::save_buffer(path,
                                         &output[0..output.len()],
@@ -68,6 +80,18 @@ impl RGB8uffer {
             Err(err) => Err(err.into()),
         }
     }
+
+    /// Encode buffer as PNG image in memory
+    pub fn encode_png(&self) -> Result<Vec<u8>, Box<dyn Error>> {
+        let output = self.to_rgb_bytes();
+
+        let mut bytes = Vec::new();
+        PngEncoder::new(&mut bytes).write_image(&output,
+                                                self.size.width as u32,
+                                                self.size.height as u32,
+                                                image::ColorType::Rgb8)?;
+        Ok(bytes)
+    }
 }
 
 impl From<(usize, Vec<RGB8>)> for RGB8uffer {
@@ -127,6 +151,22 @@ mod tests {
                 }
             }
         }
+        #[cfg(feature = "fs")]
         let _result = col_buffer.save("samples.png");
     }
+
+    #[test]
+    fn encode_png_in_memory() {
+        let mut buffer = RGB8uffer::new(ImageSize::new(3, 2));
+        buffer.set(2, 1, &RGB8{red: 10, green: 20, blue: 30});
+        let raw = buffer.to_rgb_bytes();
+        assert_eq!(raw.len(), 3 * 2 * 3);
+        assert_eq!(&raw[15..18], &[10, 20, 30]);
+        let bytes = buffer.encode_png().unwrap();
+        assert_eq!(&bytes[0..8], &[0x89, b'P', b'N', b'G', 0x0D, 0x0A, 0x1A, 0x0A]);
+        let img = image::load_from_memory(&bytes).unwrap().to_rgb8();
+        assert_eq!((img.width(), img.height()), (3, 2));
+        assert_eq!(img.get_pixel(2, 1).0, [10, 20, 30]);
+        assert_eq!(img.get_pixel(0, 0).0, [0, 0, 0]);
+    }
 }
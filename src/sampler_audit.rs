@@ -0,0 +1,195 @@
+//! Diagnostics of samplers
+//!
+//! Raw output of sampler for one pixel is collected for projection to two dimensions, plotted to
+//! scatter-plot image and measured by discrepancy and correlation. Well stratified samples have low
+//! discrepancy and different dimensions of same pixel sample should not be correlated.
+
+use crate::rgb::{ImageSize, RGB8uffer, RGB8};
+use crate::samplers::SamplerInterface;
+use crate::tile::Tile;
+
+/// Dimensions of pixel sample that are plotted against each other.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Projection {
+    /// 2D sample that starts at the dimension, dimension 0 is position of sample in pixel
+    Sample2D(u32),
+    /// 1D samples of two dimensions, correlation between dimensions shows up as pattern in the plot
+    Dimensions(u32, u32),
+}
+
+/// Samples of the projection for iterations 0..count of the pixel.
+pub fn pixel_samples(sampler: &mut Box<dyn SamplerInterface>, x: usize, y: usize, projection: Projection,
+                     count: usize) -> Vec<(f32, f32)> {
+    sampler.initialize(&Tile::new(x, y, x + 1, y + 1), 0);
+    (0..count).map(|iteration| {
+        match projection {
+            Projection::Sample2D(dimension) => {
+                sampler.set_pixel_sample(x, y, iteration, dimension);
+                sampler.next_2d()
+            }
+            Projection::Dimensions(first, second) => {
+                sampler.set_pixel_sample(x, y, iteration, first);
+                let u = sampler.next_1d();
+                sampler.set_pixel_sample(x, y, iteration, second);
+                (u, sampler.next_1d())
+            }
+        }
+    }).collect()
+}
+
+/// Star discrepancy of samples in unit square, largest difference between fraction of samples inside
+/// of box anchored at origin and area of the box. Boxes with corners at coordinates of samples are
+/// tested both open and closed, so result is exact, cost is quadratic in number of samples.
+pub fn star_discrepancy(samples: &[(f32, f32)]) -> f32 {
+    let n = samples.len();
+    if n == 0 {
+        return 1.0
+    }
+    let mut by_x: Vec<usize> = (0..n).collect();
+    by_x.sort_by(|a, b| samples[*a].0.total_cmp(&samples[*b].0));
+    let mut ys: Vec<f32> = samples.iter().map(|sample| sample.1).collect();
+    ys.sort_by(f32::total_cmp);
+    ys.push(1.0);
+    // NOTE: equal y coordinates share rank of the first of them
+    let rank = |y: f32| ys.partition_point(|value| *value < y);
+
+    let inv_n = (n as f32).recip();
+    let mut counts = vec![0; n + 1];
+    let mut discrepancy: f32 = 0.0;
+    let mut start = 0;
+    while start <= n {
+        let t = if start < n { samples[by_x[start]].0 } else { 1.0 };
+        let end = (start..n).find(|i| samples[by_x[*i]].0 != t).unwrap_or(n);
+        // open boxes [0, t) x [0, u) contain samples that were inserted before this column
+        let mut below = 0;
+        for (r, u) in ys.iter().enumerate() {
+            discrepancy = discrepancy.max(t * u - below as f32 * inv_n);
+            below += counts[r];
+        }
+        for index in &by_x[start..end] {
+            counts[rank(samples[*index].1)] += 1;
+        }
+        // closed boxes [0, t] x [0, u] contain also samples of this column
+        let mut inside = 0;
+        for (r, u) in ys.iter().enumerate() {
+            inside += counts[r];
+            discrepancy = discrepancy.max(inside as f32 * inv_n - t * u);
+        }
+        start = end.max(start + 1);
+    }
+    discrepancy
+}
+
+/// Pearson correlation coefficient of coordinates of samples, zero for samples without variance
+/// (also for less than two samples).
+pub fn correlation(samples: &[(f32, f32)]) -> f32 {
+    if samples.len() < 2 {
+        return 0.0
+    }
+    let n = samples.len() as f64;
+    let (sx, sy) = samples.iter().fold((0.0, 0.0), |(sx, sy), (x, y)| (sx + *x as f64, sy + *y as f64));
+    let (mx, my) = (sx / n, sy / n);
+    let (mut cov, mut vx, mut vy) = (0.0, 0.0, 0.0);
+    for (x, y) in samples {
+        let (dx, dy) = (*x as f64 - mx, *y as f64 - my);
+        cov += dx * dy;
+        vx += dx * dx;
+        vy += dy * dy;
+    }
+    if vx == 0.0 || vy == 0.0 {
+        return 0.0
+    }
+    (cov / (vx * vy).sqrt()) as f32
+}
+
+/// Scatter plot of samples on white background, grid of strata x strata cells is drawn in gray
+/// when strata is bigger than one. Coordinate y of samples goes from top to bottom of the image.
+pub fn scatter_plot(samples: &[(f32, f32)], size: usize, strata: usize) -> RGB8uffer {
+    let mut image = RGB8uffer::new(ImageSize::new(size, size));
+    if size == 0 {
+        return image
+    }
+    let white = RGB8 { red: 255, green: 255, blue: 255 };
+    let gray = RGB8 { red: 192, green: 192, blue: 192 };
+    let black = RGB8 { red: 0, green: 0, blue: 0 };
+    let pixel = |value: f32| ((value * size as f32) as usize).min(size - 1);
+    for y in 0..size {
+        for x in 0..size {
+            image.set(x, y, &white);
+        }
+    }
+    for i in 1..strata {
+        let line = pixel(i as f32 / strata as f32);
+        for j in 0..size {
+            image.set(line, j, &gray);
+            image.set(j, line, &gray);
+        }
+    }
+    for (x, y) in samples {
+        image.set(pixel(*x), pixel(*y), &black);
+    }
+    image
+}
+
+/// Samples of the projection with their metrics.
+pub struct SampleAudit {
+    pub samples: Vec<(f32, f32)>,
+    pub discrepancy: f32,
+    pub correlation: f32,
+}
+
+impl SampleAudit {
+    pub fn new(sampler: &mut Box<dyn SamplerInterface>, x: usize, y: usize, projection: Projection, count: usize) -> Self {
+        let samples = pixel_samples(sampler, x, y, projection, count);
+        let discrepancy = star_discrepancy(&samples);
+        let correlation = correlation(&samples);
+        Self { samples, discrepancy, correlation }
+    }
+
+    pub fn plot(&self, size: usize, strata: usize) -> RGB8uffer {
+        scatter_plot(&self.samples, size, strata)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::samplers::{RandomPathSampler, StratifiedPathSampler};
+
+    #[test]
+    fn discrepancy_of_point_sets() {
+        // box [0, 0.5] x [0, 0.5] contains the point and has area 0.25
+        assert_eq!(star_discrepancy(&[(0.5, 0.5)]), 0.75);
+        let grid: Vec<(f32, f32)> = (0..16).map(|i| ((i % 4) as f32 * 0.25 + 0.125, (i / 4) as f32 * 0.25 + 0.125)).collect();
+        let column: Vec<(f32, f32)> = (0..16).map(|i| (0.5, i as f32 / 16.0 + 1.0 / 32.0)).collect();
+        assert!(star_discrepancy(&grid) < star_discrepancy(&column));
+        assert!(correlation(&grid).abs() < 1e-6);
+        let diagonal: Vec<(f32, f32)> = (0..8).map(|i| (i as f32 / 8.0, i as f32 / 8.0)).collect();
+        assert!((correlation(&diagonal) - 1.0).abs() < 1e-6);
+        assert_eq!(correlation(&[]), 0.0);
+        assert_eq!(correlation(&[(0.5, 0.5)]), 0.0);
+        assert_eq!(scatter_plot(&diagonal, 0, 4).size().width, 0);
+    }
+
+    #[test]
+    fn audit_of_samplers() {
+        let mut stratified: Box<dyn SamplerInterface> = Box::new(StratifiedPathSampler::new(7, 8, 8, true));
+        let mut random: Box<dyn SamplerInterface> = Box::new(RandomPathSampler::new(7));
+        let pixel = SampleAudit::new(&mut stratified, 3, 5, Projection::Sample2D(0), 64);
+        let independent = SampleAudit::new(&mut random, 3, 5, Projection::Sample2D(0), 64);
+        assert!(pixel.discrepancy < independent.discrepancy);
+
+        // dimensions are scrambled with different permutations, same dimension differs only by jitter
+        let dimensions = SampleAudit::new(&mut stratified, 3, 5, Projection::Dimensions(2, 3), 64);
+        assert!(dimensions.correlation.abs() < 0.4);
+        let same = SampleAudit::new(&mut stratified, 3, 5, Projection::Dimensions(2, 2), 64);
+        assert!(same.correlation > 0.99);
+
+        let image = pixel.plot(64, 8);
+        let black = (0..64).flat_map(|y| (0..64).map(move |x| (x, y)))
+            .filter(|(x, y)| image.get(*x, *y).unwrap().red == 0).count();
+        assert!(black > 32 && black <= 64);
+        assert!((0..64).all(|y| image.get(8, y).unwrap().red != 255));
+    }
+}
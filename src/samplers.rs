@@ -3,23 +3,58 @@ use crate::tile::Tile;
 use crate::math::permutation_element;
 use crate::rng::{PCGRng, Rng};
 
+/// Dimensions used for position of sample inside of pixel.
+pub const PIXEL_DIMENSIONS: u32 = 2;
+/// Dimensions reserved for every bounce of path. Bounce that uses less dimensions is padded,
+/// so dimensions of later bounces don't depend on sampling decisions of previous bounces.
+pub const BOUNCE_DIMENSIONS: u32 = 64;
+/// Dimension reserved for shutter time, it is outside of pixel and bounce dimensions so time
+/// samples are stratified per pixel and decorrelated from all other dimensions.
+pub const TIME_DIMENSION: u32 = u32::MAX / 2;
+
+/// First dimension of given bounce, depth 0 is first intersection from camera.
+pub fn bounce_dimension(depth: u32) -> u32 {
+    PIXEL_DIMENSIONS + depth * BOUNCE_DIMENSIONS
+}
 
 pub trait SamplerInterface {
     fn next_1d(&mut self) -> f32;
     fn next_2d(&mut self) -> (f32, f32);
     fn sample_pixel(&mut self, x: usize, y: usize, iteration: usize) -> (f32, f32);
     fn initialize(&mut self, tile: &Tile, iteration: u32);
+    /// Continue pixel sample from given dimension, used when paths are not processed one after another.
+    fn set_pixel_sample(&mut self, x: usize, y: usize, iteration: usize, dimension: u32);
+    /// Continue current pixel sample from first dimension of the bounce (see bounce_dimension).
+    fn start_bounce(&mut self, depth: u32);
+    /// Sample of the time dimension for current pixel sample, it doesn't advance current dimension.
+    fn sample_time(&mut self) -> f32;
+
+    /// Array of n 2D samples stratified among themselves (latin hypercube), it advances current
+    /// dimension same as next_2d so dimensions that follow don't depend on n.
+    fn next_2d_array(&mut self, n: u32) -> Vec<(f32, f32)> {
+        let (u, v) = self.next_2d();
+        let seed = u.to_bits() ^ v.to_bits().rotate_left(16);
+        let inv_n = (n as f32).recip();
+        (0..n).map(|i| {
+            let x = (i as f32 + u) * inv_n;
+            let y = (permutation_element(i, n, seed) as f32 + v) * inv_n;
+            (x, y)
+        }).collect()
+    }
 }
 
 pub struct RandomPathSampler {
     seed: u64,
     pcg_rng: PCGRng,
+    // Separate stream for time so that time samples don't change sequence of path samples
+    time_rng: PCGRng,
 }
 
 impl RandomPathSampler {
     pub fn new(seed: u64) -> RandomPathSampler {
         let pcg_rng = PCGRng::new(seed, 0);
-        RandomPathSampler{seed, pcg_rng}
+        let time_rng = PCGRng::new(seed, 1);
+        RandomPathSampler{seed, pcg_rng, time_rng}
     }
 }
 
@@ -36,9 +71,21 @@ impl SamplerInterface for RandomPathSampler {
         self.next_2d()
     }
 
+    // NOTE: first number of PCG depends only on its state, so iteration is part of the seed
     fn initialize(&mut self, tile: &Tile, iteration: u32) {
-        let seed = hash!(self.seed, tile.x1, tile.y1);
-        self.pcg_rng = PCGRng::new(seed, iteration as u64);
+        self.pcg_rng = PCGRng::new(hash!(self.seed, tile.x1, tile.y1, iteration as u64), 0);
+        self.time_rng = PCGRng::new(hash!(self.seed, tile.x1, tile.y1, 1 << 32 | iteration as u64), 1);
+    }
+
+    fn set_pixel_sample(&mut self, _x: usize, _y: usize, _iteration: usize, _dimension: u32) {
+    }
+
+    // NOTE: independent samples are not correlated between dimensions, there is nothing to pad
+    fn start_bounce(&mut self, _depth: u32) {
+    }
+
+    fn sample_time(&mut self) -> f32 {
+        self.time_rng.rand_f32()
     }
 }
 
@@ -60,14 +107,22 @@ impl StratifiedPathSampler {
         let pcg_rng = PCGRng::new(seed, 0);
         StratifiedPathSampler{seed, jitter, xsamples, ysamples, pcg_rng, x: 0, y: 0, iteration: 0, dimension: 0}
     }
+
+    // Stratum of current dimension. Every dimension is scrambled with its own permutation, when number
+    // of iterations exceeds number of strata next pass of iterations gets new permutations.
+    fn stratum(&self) -> u32 {
+        let total = self.xsamples * self.ysamples;
+        let pass = (self.iteration / total) as u64;
+        let hash = hash!(self.seed, self.x, self.y, pass << 32 | self.dimension as u64);
+        permutation_element(self.iteration % total, total, hash as u32)
+    }
 }
 
 impl SamplerInterface for StratifiedPathSampler {
 
     fn next_1d(&mut self) -> f32 {
-        let hash = hash!(self.seed, self.x, self.y, self.dimension);
         let total = self.xsamples * self.ysamples;
-        let stratum = permutation_element(self.iteration, total, hash as u32);
+        let stratum = self.stratum();
         self.dimension += 1;
 
         let dx = if self.jitter {
@@ -80,9 +135,7 @@ impl SamplerInterface for StratifiedPathSampler {
     }
 
     fn next_2d(&mut self) -> (f32, f32) {
-        let hash = hash!(self.seed, self.x, self.y, self.dimension);
-        let total = self.xsamples * self.ysamples;
-        let stratum = permutation_element(self.iteration, total, hash as u32);
+        let stratum = self.stratum();
         self.dimension += 2;
     
         let x = stratum % self.xsamples;
@@ -106,8 +159,121 @@ impl SamplerInterface for StratifiedPathSampler {
         self.next_2d()
     }
 
+    fn set_pixel_sample(&mut self, x: usize, y: usize, iteration: usize, dimension: u32) {
+        self.x = x as u32;
+        self.y = y as u32;
+        self.iteration = iteration as u32;
+        self.dimension = dimension;
+    }
+
+    fn start_bounce(&mut self, depth: u32) {
+        self.dimension = bounce_dimension(depth);
+    }
+
+    // NOTE: jitter is hashed, so time samples don't change sequence of path samples
+    fn sample_time(&mut self) -> f32 {
+        let dimension = self.dimension;
+        self.dimension = TIME_DIMENSION;
+        let stratum = self.stratum();
+        self.dimension = dimension;
+        let dx = if self.jitter {
+            let hash = hash!(self.seed, self.x, self.y, (self.iteration as u64) << 32 | TIME_DIMENSION as u64);
+            (hash >> 40) as f32 / (1u64 << 24) as f32
+        } else {
+            0.5
+        };
+        (stratum as f32 + dx) / (self.xsamples * self.ysamples) as f32
+    }
+
     fn initialize(&mut self, tile: &Tile, iteration: u32) {
-        let seed = hash!(self.seed, tile.x1, tile.y1);
-        self.pcg_rng = PCGRng::new(seed, iteration as u64);
+        self.pcg_rng = PCGRng::new(hash!(self.seed, tile.x1, tile.y1, iteration as u64), 0);
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sample_array_stratification() {
+        let mut sampler: Box<dyn SamplerInterface> = Box::new(RandomPathSampler::new(3));
+        let n = 16;
+        let samples = sampler.next_2d_array(n);
+        assert_eq!(samples.len(), n as usize);
+        // every stratum in both dimensions has exactly one sample
+        let mut xstrata = vec![0; n as usize];
+        let mut ystrata = vec![0; n as usize];
+        for (x, y) in samples.iter() {
+            xstrata[(x * n as f32) as usize] += 1;
+            ystrata[(y * n as f32) as usize] += 1;
+        }
+        assert!(xstrata.iter().all(|count| *count == 1));
+        assert!(ystrata.iter().all(|count| *count == 1));
+
+        // array uses same dimensions as next_2d
+        let mut sampler = StratifiedPathSampler::new(7, 4, 4, true);
+        sampler.sample_pixel(0, 0, 5);
+        sampler.next_2d_array(8);
+        assert_eq!(sampler.dimension, PIXEL_DIMENSIONS + 2);
+    }
+
+    #[test]
+    fn stratified_bounce_dimensions() {
+        let mut sampler = StratifiedPathSampler::new(7, 4, 4, false);
+        sampler.initialize(&Tile::new(0, 0, 4, 4), 0);
+        let mut first = Vec::new();
+        for i in 0..16 {
+            sampler.sample_pixel(1, 2, i);
+            sampler.start_bounce(0);
+            sampler.next_2d();
+            sampler.start_bounce(1);
+            first.push(sampler.next_2d());
+        }
+        // number of samples used in previous bounce doesn't shift dimensions of next bounce
+        for (i, sample) in first.iter().enumerate() {
+            sampler.sample_pixel(1, 2, i);
+            sampler.start_bounce(0);
+            sampler.next_1d();
+            sampler.next_2d();
+            sampler.next_1d();
+            sampler.start_bounce(1);
+            assert_eq!(sampler.next_2d(), *sample);
+        }
+        sampler.set_pixel_sample(1, 2, 3, bounce_dimension(1));
+        assert_eq!(sampler.next_2d(), first[3]);
+    }
+
+    #[test]
+    fn stratified_time_samples() {
+        let mut sampler = StratifiedPathSampler::new(7, 4, 4, true);
+        sampler.initialize(&Tile::new(0, 0, 4, 4), 0);
+        let mut pixel_strata = Vec::new();
+        let mut time_strata = Vec::new();
+        for i in 0..16 {
+            let (sx, sy) = sampler.sample_pixel(2, 3, i);
+            pixel_strata.push((sy * 4.0) as u32 * 4 + (sx * 4.0) as u32);
+            time_strata.push((sampler.sample_time() * 16.0) as u32);
+            // time doesn't shift dimensions of the path
+            assert_eq!(sampler.dimension, PIXEL_DIMENSIONS);
+        }
+        assert_ne!(pixel_strata, time_strata);
+        time_strata.sort();
+        assert_eq!(time_strata, (0..16).collect::<Vec<u32>>());
+    }
+
+    #[test]
+    fn stratified_more_iterations_than_strata() {
+        let mut sampler = StratifiedPathSampler::new(7, 2, 2, false);
+        sampler.initialize(&Tile::new(0, 0, 1, 1), 0);
+        for pass in 0..3 {
+            let mut strata: Vec<u32> = (0..4).map(|i| {
+                sampler.sample_pixel(0, 0, pass * 4 + i);
+                sampler.start_bounce(2);
+                (sampler.next_1d() * 4.0) as u32
+            }).collect();
+            strata.sort();
+            assert_eq!(strata, vec![0, 1, 2, 3]);
+        }
     }
 }
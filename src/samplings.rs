@@ -1,4 +1,4 @@
-use crate::vec::Vec3;
+use crate::vec::{Point3, Vec3};
 
 pub struct SampleDirection {
     pub direction: Vec3,
@@ -33,6 +33,18 @@ pub fn sample_uniform_hemisphere(u1: f32, u2: f32) -> SampleDirection {
     SampleDirection { direction, pdfw }
 }
 
+pub fn sample_uniform_cone(u1: f32, u2: f32, cos_theta_max: f32) -> SampleDirection {
+    let cos_theta = (1.0 - u1) + u1 * cos_theta_max;
+    let sin_theta = (1.0 - cos_theta * cos_theta).max(0.0).sqrt();
+    let phi = 2.0 * std::f32::consts::PI * u2;
+
+    let direction = Vec3::new(phi.cos() * sin_theta, phi.sin() * sin_theta, cos_theta);
+    // pdfw = 1 / (2 * PI * (1 - cos_theta_max))
+    let pdfw = 1.0 / (2.0 * std::f32::consts::PI * (1.0 - cos_theta_max));
+
+    SampleDirection { direction, pdfw }
+}
+
 
 pub fn sample_uniform_sphere(u1: f32, u2: f32) -> SampleDirection {
     let term1 = 2.0 * std::f32::consts::PI * u1;
@@ -48,3 +60,206 @@ pub fn sample_uniform_sphere(u1: f32, u2: f32) -> SampleDirection {
 
     SampleDirection { direction, pdfw }
 }
+
+/// Uniform point on unit disk, pdf is 1 / PI.
+pub fn sample_uniform_disk(u1: f32, u2: f32) -> (f32, f32) {
+    let r = u1.sqrt();
+    let theta = 2.0 * std::f32::consts::PI * u2;
+    (r * theta.cos(), r * theta.sin())
+}
+
+/// Equi-angular sampling of distance along ray segment [tmin, tmax] toward a point light
+/// (Kulla and Fajardo - Importance Sampling Techniques for Path Tracing in Participating Media).
+/// Pdf of distance is proportional to 1 / squared distance from the light.
+/// Returns distance and its pdf, None if light lies on the ray.
+pub fn sample_equi_angular(origin: Point3, direction: Vec3, tmin: f32, tmax: f32, light: Point3, u: f32) -> Option<(f32, f32)> {
+    let delta = (light - origin) * direction;
+    let closest = origin + direction * delta;
+    let d = closest.distance(light);
+    if d <= 0.0 {
+        return None
+    }
+    let theta_a = ((tmin - delta) / d).atan();
+    let theta_b = ((tmax - delta) / d).atan();
+    if theta_b <= theta_a {
+        return None
+    }
+    let t = d * (theta_a + u * (theta_b - theta_a)).tan();
+    let pdf = d / ((theta_b - theta_a) * (d * d + t * t));
+    Some(((delta + t).clamp(tmin, tmax), pdf))
+}
+
+/// Equal-area mapping of unit square to unit sphere (Clarberg - Fast Equal-Area Mapping of the (Hemi)Sphere
+/// using SIMD), octahedral layout used by environment maps of pbrt-v4.
+pub fn equal_area_square_to_sphere(u: f32, v: f32) -> Vec3 {
+    let (u, v) = (2.0 * u - 1.0, 2.0 * v - 1.0);
+    let (up, vp) = (u.abs(), v.abs());
+    let signed_distance = 1.0 - (up + vp);
+    let r = 1.0 - signed_distance.abs();
+    let phi = if r == 0.0 { 1.0 } else { (vp - up) / r + 1.0 } * std::f32::consts::FRAC_PI_4;
+    let z = (1.0 - r * r).copysign(signed_distance);
+    let scale = r * (2.0 - r * r).max(0.0).sqrt();
+    Vec3::new(phi.cos().copysign(u) * scale, phi.sin().copysign(v) * scale, z)
+}
+
+/// Inverse of equal_area_square_to_sphere, direction must be normalized.
+pub fn equal_area_sphere_to_square(direction: Vec3) -> (f32, f32) {
+    let (x, y, z) = (direction.x.abs(), direction.y.abs(), direction.z.abs());
+    let r = (1.0 - z).max(0.0).sqrt();
+    let (a, b) = (x.max(y), x.min(y));
+    let b = if a == 0.0 { 0.0 } else { b / a };
+    let mut phi = b.atan() * std::f32::consts::FRAC_2_PI;
+    if x < y {
+        phi = 1.0 - phi;
+    }
+    let mut v = phi * r;
+    let mut u = r - v;
+    if direction.z < 0.0 {
+        std::mem::swap(&mut u, &mut v);
+        u = 1.0 - u;
+        v = 1.0 - v;
+    }
+    (0.5 * (u.copysign(direction.x) + 1.0), 0.5 * (v.copysign(direction.y) + 1.0))
+}
+
+/// Uniform sampling of solid angle subtended by rectangle.
+/// Ureña et al. - An Area-Preserving Parametrization for Spherical Rectangles
+pub struct SphericalRectangle {
+    origin: Point3,
+    x: Vec3,
+    y: Vec3,
+    z: Vec3,
+    z0: f32,
+    x0: f32,
+    x1: f32,
+    y0: f32,
+    y1: f32,
+    b0: f32,
+    b1: f32,
+    k: f32,
+    solid_angle: f32
+}
+
+impl SphericalRectangle {
+    /// Rectangle is given by corner and two perpendicular edges. Returns None if
+    /// origin lies in plane of rectangle.
+    pub fn new(origin: Point3, corner: Point3, edge_x: Vec3, edge_y: Vec3) -> Option<Self> {
+        let (exl, eyl) = (edge_x.length(), edge_y.length());
+        let x = edge_x * exl.recip();
+        let y = edge_y * eyl.recip();
+        let mut z = x.cross(y);
+        let d = corner - origin;
+        let mut z0 = d * z;
+        if z0 == 0.0 {
+            return None;
+        }
+        // NOTE: flip z so that rectangle lies in negative half-space
+        if z0 > 0.0 {
+            z = -z;
+            z0 = -z0;
+        }
+        let (x0, y0) = (d * x, d * y);
+        let (x1, y1) = (x0 + exl, y0 + eyl);
+        let v00 = Vec3::new(x0, y0, z0);
+        let v01 = Vec3::new(x0, y1, z0);
+        let v10 = Vec3::new(x1, y0, z0);
+        let v11 = Vec3::new(x1, y1, z0);
+        let n0 = v00.cross(v10).normalize();
+        let n1 = v10.cross(v11).normalize();
+        let n2 = v11.cross(v01).normalize();
+        let n3 = v01.cross(v00).normalize();
+        let g0 = (-(n0 * n1)).clamp(-1.0, 1.0).acos();
+        let g1 = (-(n1 * n2)).clamp(-1.0, 1.0).acos();
+        let g2 = (-(n2 * n3)).clamp(-1.0, 1.0).acos();
+        let g3 = (-(n3 * n0)).clamp(-1.0, 1.0).acos();
+        let k = 2.0 * std::f32::consts::PI - g2 - g3;
+        let solid_angle = g0 + g1 - k;
+        if solid_angle <= 0.0 {
+            return None;
+        }
+        Some(Self { origin, x, y, z, z0, x0, x1, y0, y1, b0: n0.z, b1: n2.z, k, solid_angle })
+    }
+
+    pub fn solid_angle(&self) -> f32 {
+        self.solid_angle
+    }
+
+    /// Point on rectangle, density of directions towards it is 1 / solid_angle.
+    pub fn sample(&self, u1: f32, u2: f32) -> Point3 {
+        let au = u1 * self.solid_angle + self.k;
+        let fu = (au.cos() * self.b0 - self.b1) / au.sin();
+        let cu = ((fu * fu + self.b0 * self.b0).sqrt().recip() * fu.signum()).clamp(-1.0, 1.0);
+        let xu = (-(cu * self.z0) / (1.0 - cu * cu).max(1e-12).sqrt()).clamp(self.x0, self.x1);
+        let d = (xu * xu + self.z0 * self.z0).sqrt();
+        let h0 = self.y0 / (d * d + self.y0 * self.y0).sqrt();
+        let h1 = self.y1 / (d * d + self.y1 * self.y1).sqrt();
+        let hv = h0 + u2 * (h1 - h0);
+        let hv2 = hv * hv;
+        let yv = if hv2 < 1.0 - 1e-6 { (hv * d) / (1.0 - hv2).sqrt() } else { self.y1 };
+        self.origin + self.x * xu + self.y * yv.clamp(self.y0, self.y1) + self.z * self.z0
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rng::{PCGRng, Rng};
+
+    #[test]
+    fn spherical_rectangle_sampling() {
+        let origin = Point3::new(0.0, 0.0, 0.0);
+        let corner = Point3::new(-1.0, -1.0, -1.0);
+        let rect = SphericalRectangle::new(origin, corner, Vec3::new(2.0, 0.0, 0.0), Vec3::new(0.0, 2.0, 0.0)).unwrap();
+        assert!((rect.solid_angle() - 2.0 * std::f32::consts::PI / 3.0).abs() < 1e-4);
+
+        // Directions are uniform in solid angle, so E[1 / pdfa] must be area of rectangle
+        let mut rng = PCGRng::new(42, 0);
+        let n = 100000;
+        let mut area = 0.0f64;
+        for _ in 0..n {
+            let p = rect.sample(rng.rand_f32(), rng.rand_f32());
+            assert!(p.x >= -1.0 - 1e-4 && p.x <= 1.0 + 1e-4 && p.y >= -1.0 - 1e-4 && p.y <= 1.0 + 1e-4);
+            assert!((p.z + 1.0).abs() < 1e-5);
+            let dir = p - origin;
+            let dist2 = dir * dir;
+            let cos_theta = dir.z.abs() / dist2.sqrt();
+            area += (dist2 * rect.solid_angle() / cos_theta) as f64;
+        }
+        assert!((area / n as f64 - 4.0).abs() < 0.02);
+        assert!(SphericalRectangle::new(origin, Point3::new(-1.0, -1.0, 0.0), Vec3::new(2.0, 0.0, 0.0), Vec3::new(0.0, 2.0, 0.0)).is_none());
+    }
+
+    #[test]
+    fn equi_angular_sampling() {
+        let origin = Point3::new(0.0, 0.0, 0.0);
+        let direction = Vec3::new(1.0, 0.0, 0.0);
+        let light = Point3::new(3.0, 0.5, 0.0);
+        let mut rng = PCGRng::new(5, 0);
+        let n = 100000;
+        let mut length = 0.0;
+        for _ in 0..n {
+            let (t, pdf) = sample_equi_angular(origin, direction, 1.0, 10.0, light, rng.rand_f32()).unwrap();
+            assert!((1.0..=10.0).contains(&t));
+            length += 1.0 / pdf;
+        }
+        // E[1 / pdf] is length of the segment
+        assert!((length / n as f32 - 9.0).abs() < 0.1);
+        assert!(sample_equi_angular(origin, direction, 1.0, 10.0, Point3::new(2.0, 0.0, 0.0), 0.5).is_none());
+    }
+
+    #[test]
+    fn equal_area_mapping() {
+        let mut rng = PCGRng::new(7, 0);
+        for _ in 0..1000 {
+            let (u, v) = (rng.rand_f32(), rng.rand_f32());
+            let direction = equal_area_square_to_sphere(u, v);
+            assert!((direction.length() - 1.0).abs() < 1e-4);
+            let (u2, v2) = equal_area_sphere_to_square(direction);
+            assert!((u - u2).abs() < 1e-3 && (v - v2).abs() < 1e-3);
+        }
+        // center of the square is up direction, corners are down direction
+        assert!((equal_area_square_to_sphere(0.5, 0.5).z - 1.0).abs() < 1e-6);
+        assert!((equal_area_square_to_sphere(0.0, 1.0).z + 1.0).abs() < 1e-6);
+    }
+}
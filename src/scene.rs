@@ -1,47 +1,146 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::fmt::Write;
+use std::ops::Add;
 
 use crate::rgb::ImageSize;
-use crate::color::TMOType;
+use crate::color::{TMOType, BufferPrecision, PixelSample, HalfPixelSample, AovType};
 use crate::camera::{PerspectiveCameraDescriptor, PerspectiveCamera};
-use crate::materials::{MaterialDescription, BSDFInterface};
-use crate::shapes::{Geometry, ShapeDescription};
+use crate::materials::{MaterialDescription, BSDFInterface, TexturedMaterial, HitMaterial};
+use crate::textures::{TextureDescription, TextureContext, TextureImage, Texture};
+use crate::shapes::{Geometry, ShapeDescription, MeshDescription, PrototypeDescription, AcceleratorType};
 use crate::lights::{LightDescription, LightInterface};
 use crate::samplers::SamplerInterface;
 use crate::samplers::RandomPathSampler;
 use crate::samplers::StratifiedPathSampler;
+use crate::tile::Tile;
 use crate::filter::{FilterDescriptor, Filter};
+use crate::lpe::Lpe;
+use crate::raylog::RayLogOutput;
+use crate::telemetry::SamplingTelemetry;
+use crate::checkpoint::CheckpointOutput;
+use crate::irradiance_cache::{IrradianceCacheProperties, IrradianceCache};
+use crate::path_guiding::{PathGuidingProperties, PathGuide};
+use crate::bake::{BakeProperties, BakeMap, is_bakeable};
+use crate::light_sampler::{LightSamplerType, LightSamplerInterface, create_light_sampler};
+use crate::media::{MediumDescription, Medium};
+use crate::postprocess::{BloomProperties, FogProperties};
+use crate::epsilon::{EpsilonPolicy, INFINITE_DISTANCE};
+use crate::color::RGB;
+use crate::vec::{Point3, Vec3};
+use crate::shapes::SurfaceInteraction;
+use crate::json::parse_scene_description_from_json;
+use crate::pbrt_v4::parse_pbrt_v4_string;
+use crate::hash::murmur_hash64a;
+use rayon::prelude::*;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
 
 
-#[derive(Clone, Copy)]
+#[derive(Debug, Clone)]
 pub struct AmbientOcclusionProperties {
     pub cossample: bool,
-    pub maxdistance: f32
+    pub maxdistance: f32,
+    /// Number of occlusion rays traced from every camera ray hit
+    pub nsamples: u32,
+    /// File name of image where bent normals are stored
+    pub bent_normal_output: Option<String>
 }
 
 impl Default for AmbientOcclusionProperties {
     fn default() -> Self {
-        Self { cossample: true, maxdistance: 1e38 }
+        Self { cossample: true, maxdistance: INFINITE_DISTANCE, nsamples: 1, bent_normal_output: None }
     }
 }
 
-#[derive(Clone, Copy)]
+/// Ambient occlusion rendered as auxiliary pass next to the primary integrator.
+#[derive(Clone)]
+pub struct AmbientOcclusionOutput {
+    pub settings: AmbientOcclusionProperties,
+    pub output_fname: String
+}
+
+#[derive(Debug, Clone, Copy)]
 pub struct RandomWalkProperties {
-    pub maxdepth: usize
+    pub maxdepth: usize,
+    /// Depth after which paths are terminated with russian roulette
+    pub rrdepth: usize,
+    pub wavefront: bool
 }
 
 impl Default for RandomWalkProperties {
     fn default() -> Self {
-        Self { maxdepth: 5 }
+        Self { maxdepth: 5, rrdepth: 5, wavefront: false }
+    }
+}
+
+/// White furnace test, scene is lit by uniform environment and materials can be
+/// replaced with one material to check its energy conservation.
+#[derive(Debug, Clone)]
+pub struct FurnaceProperties {
+    pub maxdepth: usize,
+    pub radiance: f32,
+    pub material: Option<String>
+}
+
+impl Default for FurnaceProperties {
+    fn default() -> Self {
+        Self { maxdepth: 100, radiance: 1.0, material: None }
+    }
+}
+
+/// Stochastic progressive photon mapping, every iteration traces one camera sample per pixel
+/// and photons_per_iteration photons, gather radius shrinks with number of iterations.
+#[derive(Debug, Clone, Copy)]
+pub struct SppmProperties {
+    pub iterations: usize,
+    pub photons_per_iteration: usize,
+    pub maxdepth: usize,
+    pub initial_radius: f32,
+    /// Fraction of new photons that is kept in every iteration, controls speed of radius reduction
+    pub alpha: f32
+}
+
+impl Default for SppmProperties {
+    fn default() -> Self {
+        Self { iterations: 64, photons_per_iteration: 100000, maxdepth: 5, initial_radius: 0.1, alpha: 2.0 / 3.0 }
     }
 }
 
+/// Shading of primary hits in intersector preview.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PreviewShading {
+    /// Cosine between surface normal and view direction
+    Facing,
+    /// Material albedo multiplied by facing
+    Albedo
+}
+
+/// Primary visibility preview, one ray through center of every pixel is traced and hit is
+/// flat shaded, intended for checking camera framing and imported geometry.
+#[derive(Debug, Clone, Copy)]
+pub struct IntersectorProperties {
+    pub shading: PreviewShading
+}
+
+impl Default for IntersectorProperties {
+    fn default() -> Self {
+        Self { shading: PreviewShading::Facing }
+    }
+}
+
+#[derive(Debug)]
 pub enum RenderingAlgorithm {
     AmbientOcclusion(AmbientOcclusionProperties),
     RandomWalk(RandomWalkProperties),
     DirectLighting,
-    PathTracer
+    PathTracer,
+    Furnace(FurnaceProperties),
+    Sppm(SppmProperties),
+    Intersector(IntersectorProperties)
 }
 
+#[derive(Debug)]
 pub struct RandomSamplerSettings {
     pub seed: u64
 }
@@ -52,6 +151,7 @@ impl Default for RandomSamplerSettings {
     }
 }
 
+#[derive(Debug)]
 pub struct StratifiedSamplerSettings {
     pub seed: u64,
     pub xsamples: u32,
@@ -65,6 +165,7 @@ impl Default for StratifiedSamplerSettings {
     }
 }
 
+#[derive(Debug)]
 pub enum Sampler {
     Random(RandomSamplerSettings),
     Stratified(StratifiedSamplerSettings)
@@ -79,15 +180,166 @@ impl Sampler {
             }
         }
     }
+
+    /// Sampler of one tile and iteration, its sequence is seeded from seed of the sampler, position
+    /// of the tile and the iteration, so tiles are not correlated and can be rendered in any order.
+    pub fn clone_for_tile(&self, tile: &Tile, iteration: u32) -> Box<dyn SamplerInterface> {
+        let mut sampler = self.create_sampler();
+        sampler.initialize(tile, iteration);
+        sampler
+    }
+}
+
+/// Handling of interpolated shading normals that disagree with geometric normal of low-poly meshes.
+#[derive(Debug, Clone, Copy)]
+pub struct ShadingNormalSettings {
+    /// Materials are evaluated with interpolated vertex normals, otherwise with geometric normal
+    pub enabled: bool,
+    /// Shadow terminator smoothing (Chiang et al. 2019) of light arriving at grazing angles
+    pub terminator_smoothing: bool,
+    /// Sampled directions on wrong side of geometric surface are mirrored instead of rejected,
+    /// so energy that shading normal sends below the surface isn't lost. Off by default as in pbrt.
+    pub compensation: bool,
+}
+
+impl Default for ShadingNormalSettings {
+    fn default() -> Self {
+        Self { enabled: true, terminator_smoothing: true, compensation: false }
+    }
+}
+
+/// How lights are sampled at every hit when direct lighting is estimated.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LightStrategy {
+    /// Every light is sampled, lower variance for scenes with few lights
+    All,
+    /// One light chosen by light sampler is sampled, cost doesn't grow with number of lights
+    One,
+    /// Candidates of lights chosen by light sampler are resampled in reservoirs and reservoirs of neighbouring
+    /// pixels are reused, one shadow ray is traced for many lights
+    Restir(RestirProperties)
+}
+
+/// Reservoir-based direct lighting, reuse is only spatial (inside of the tile of the pass), temporal reuse
+/// needs previous frames.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RestirProperties {
+    /// Candidate light samples generated at every hit
+    pub candidates: usize,
+    /// Neighbouring pixels whose reservoirs are merged, 0 disables spatial reuse
+    pub spatial_samples: usize,
+    /// Radius in pixels where neighbours are chosen
+    pub spatial_radius: usize,
+}
+
+impl Default for RestirProperties {
+    fn default() -> Self {
+        Self { candidates: 32, spatial_samples: 4, spatial_radius: 8 }
+    }
+}
+
+/// Samples are distributed across tiles by their variance, it is estimated by quick pre-pass
+/// of the whole image. Total number of samples is same as spp in every pixel.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct TileImportanceProperties {
+    /// Samples per pixel of the pre-pass, they are not part of the final image. At least two samples
+    /// are needed for variance of pixels.
+    pub prepass_spp: usize,
+    /// Every tile gets at least this number of samples per pixel, at least one
+    pub min_spp: usize,
+}
+
+impl Default for TileImportanceProperties {
+    fn default() -> Self {
+        Self { prepass_spp: 4, min_spp: 1 }
+    }
+}
+
+/// Rendering continues from earlier image (e.g. preview with low spp) instead of black image. Image
+/// is added to every pixel as weight samples, so it fades out as new samples are accumulated.
+/// Image must be linear float image (EXR, HDR) of resolution of the scene. It seeds only radiance, so
+/// only ambient occlusion, direct lighting and random walk integrators without light layer, LPE, AOV
+/// and bent normal outputs support it.
+#[derive(Clone, Debug, PartialEq)]
+pub struct WarmStartProperties {
+    pub image: String,
+    /// Number of samples per pixel that image stands for
+    pub weight: f32,
+}
+
+/// Pixels (row-major order) of image that rendering continues from and their weight, see WarmStartProperties.
+pub struct WarmStart {
+    pub pixels: Vec<RGB>,
+    pub weight: f32,
+}
+
+/// Auxiliary output and file name of its image.
+pub struct AovOutput {
+    pub typ: AovType,
+    pub output_fname: String
+}
+
+/// Light path expression and file name of image where matching contributions are stored.
+pub struct LpeOutput {
+    pub expression: String,
+    pub output_fname: String
 }
 
 pub struct Settings {
     pub resolution: ImageSize,
     pub spp: usize,
+    /// Samples per pixel of tiles follow estimated variance of tiles, None is spp in every tile
+    pub tile_importance: Option<TileImportanceProperties>,
+    /// Lighting is baked to texture of the mesh instead of rendering camera image
+    pub bake: Option<BakeProperties>,
     pub rendering_algorithm: RenderingAlgorithm,
     pub tonemap: TMOType,
     pub output_fname: String,
-    pub nthreads: usize
+    /// Number of rendering threads, 0 means one thread per logical core. render_scene falls back to current
+    /// thread pool when pool with this number of threads can't be created
+    pub nthreads: usize,
+    pub buffer_precision: BufferPrecision,
+    pub light_strategy: LightStrategy,
+    /// Distribution of lights for strategies that choose lights
+    pub light_sampler: LightSamplerType,
+    /// Light samples are combined with BSDF samples by multiple importance sampling
+    pub mis: bool,
+    /// One bounce of diffuse interreflection is added to direct lighting, None is direct lighting only
+    pub irradiance_cache: Option<IrradianceCacheProperties>,
+    /// Directions of random walk are sampled from learned incident radiance, None is BSDF sampling only.
+    /// Wavefront random walk doesn't support it.
+    pub path_guiding: Option<PathGuidingProperties>,
+    /// Radiance samples with larger component are scaled down to it, suppresses fireflies
+    pub max_component: Option<f32>,
+    pub shading_normals: ShadingNormalSettings,
+    pub lpes: Vec<LpeOutput>,
+    pub aovs: Vec<AovOutput>,
+    pub ao_output: Option<AmbientOcclusionOutput>,
+    /// File name of image with number of samples that every pixel received
+    pub sample_count_output: Option<String>,
+    /// File name of tiled EXR image, tiles are written to it as soon as they are rendered and buffers of
+    /// whole image are not allocated. Rendered image is then only preview with one pixel per tile.
+    pub bucket_output: Option<String>,
+    /// Rays traced for one pixel are saved as line segments
+    pub ray_log: Option<RayLogOutput>,
+    /// Finished passes of tiles are periodically saved, so interrupted rendering can be resumed
+    pub checkpoint: Option<CheckpointOutput>,
+    /// Linear image that radiance of tiled integrators starts from, see WarmStartProperties
+    pub warm_start: Option<WarmStartProperties>,
+    /// Count sampling efficiency of materials and lights and print it at the end of rendering,
+    /// only direct lighting and random walk integrators record it
+    pub telemetry: bool,
+    /// Objects placed by singular transformation are skipped with warning during parsing, otherwise
+    /// parsing fails
+    pub skip_invalid_transforms: bool,
+    /// Remove degenerate and invalid triangles from meshes before building geometry
+    pub mesh_cleanup: bool,
+    /// Make winding of mesh triangles consistent and normals of closed meshes point outward
+    pub mesh_orientation: bool,
+    pub fog: Option<FogProperties>,
+    pub bloom: Option<BloomProperties>,
+    pub epsilon: EpsilonPolicy,
+    pub accelerator: AcceleratorType
 }
 
 impl Default for Settings {
@@ -95,10 +347,60 @@ impl Default for Settings {
         Self {
             resolution: ImageSize::new(256, 256),
             spp: 1,
+            tile_importance: None,
+            bake: None,
             rendering_algorithm: RenderingAlgorithm::AmbientOcclusion(AmbientOcclusionProperties::default()),
             tonemap: TMOType::Linear,
             output_fname: "output.png".to_string(),
-            nthreads: 1
+            nthreads: 0,
+            buffer_precision: BufferPrecision::Full,
+            light_strategy: LightStrategy::All,
+            light_sampler: LightSamplerType::Uniform,
+            mis: true,
+            irradiance_cache: None,
+            path_guiding: None,
+            max_component: None,
+            shading_normals: ShadingNormalSettings::default(),
+            lpes: Vec::new(),
+            aovs: Vec::new(),
+            ao_output: None,
+            sample_count_output: None,
+            bucket_output: None,
+            ray_log: None,
+            checkpoint: None,
+            warm_start: None,
+            telemetry: false,
+            skip_invalid_transforms: false,
+            mesh_cleanup: true,
+            mesh_orientation: false,
+            fog: None,
+            bloom: None,
+            epsilon: EpsilonPolicy::default(),
+            accelerator: AcceleratorType::default()
+        }
+    }
+}
+
+/// Numbers of world elements of scene description.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct FragmentPosition {
+    pub textures: usize,
+    pub shapes: usize,
+    pub lights: usize,
+    pub media: usize,
+    pub prototypes: usize,
+}
+
+impl Add for FragmentPosition {
+    type Output = FragmentPosition;
+
+    fn add(self, rhs: FragmentPosition) -> FragmentPosition {
+        FragmentPosition {
+            textures: self.textures + rhs.textures,
+            shapes: self.shapes + rhs.shapes,
+            lights: self.lights + rhs.lights,
+            media: self.media + rhs.media,
+            prototypes: self.prototypes + rhs.prototypes,
         }
     }
 }
@@ -108,17 +410,158 @@ pub struct SceneDescription {
     pub settings: Settings,
     pub camera_desc: PerspectiveCameraDescriptor,
     pub materials: Vec<MaterialDescription>,
+    pub textures: Vec<TextureDescription>,
     pub shapes: Vec<ShapeDescription>,
     pub lights: Vec<LightDescription>,
-    pub filter: Option<FilterDescriptor>
+    pub filter: Option<FilterDescriptor>,
+    pub media: Vec<MediumDescription>,
+    pub camera_medium: Option<String>,
+    /// Geometry that is rendered through instances
+    pub prototypes: Vec<PrototypeDescription>,
+    material_names: MaterialNames
+}
+
+// Names of materials of description and next numeric suffix of every base name, so unique names are
+// found without rebuilding set of all names. Materials pushed directly to description are indexed lazily.
+#[derive(Default)]
+struct MaterialNames {
+    names: HashSet<String>,
+    indexed: usize,
+    suffixes: HashMap<String, usize>
+}
+
+impl MaterialNames {
+    fn update(&mut self, materials: &[MaterialDescription]) {
+        if materials.len() < self.indexed {
+            *self = MaterialNames::default();
+        }
+        self.names.extend(materials[self.indexed..].iter().map(|material| material.name.clone()));
+        self.indexed = materials.len();
+    }
+
+    fn unique(&mut self, base: &str) -> String {
+        if !self.names.contains(base) {
+            return base.to_string()
+        }
+        let suffix = self.suffixes.entry(base.to_string()).or_insert(1);
+        loop {
+            let name = format!("{}.{}", base, suffix);
+            *suffix += 1;
+            if !self.names.contains(&name) {
+                return name
+            }
+        }
+    }
+}
+
+// Formatted values are streamed to the hash, so large meshes are not formatted to one string
+struct FingerprintWriter(u64);
+
+impl Write for FingerprintWriter {
+    fn write_str(&mut self, s: &str) -> std::fmt::Result {
+        self.0 = murmur_hash64a(s.as_bytes(), self.0);
+        Ok(())
+    }
 }
 
 impl SceneDescription {
+    /// Hash of everything that changes accumulated image except resolution and samples per pixel, that
+    /// are stored in checkpoint. Output files, number of threads and postprocessing don't change it.
+    pub fn fingerprint(&self) -> u64 {
+        let s = &self.settings;
+        let lpes: Vec<&str> = s.lpes.iter().map(|lpe| lpe.expression.as_str()).collect();
+        let ao_output = s.ao_output.as_ref().map(|ao| &ao.settings);
+        let mut writer = FingerprintWriter(0);
+        let _ = write!(writer, "{:?}{:?}{:?}{:?}{:?}{:?}{:?}{:?}{:?}{:?}{:?}{:?}{:?}{:?}{:?}",
+                       s.rendering_algorithm, s.tile_importance, s.bake, s.light_strategy, s.light_sampler, s.mis,
+                       s.irradiance_cache, s.path_guiding, s.max_component, s.shading_normals, lpes, ao_output,
+                       s.warm_start, s.epsilon, (s.skip_invalid_transforms, s.mesh_cleanup, s.mesh_orientation));
+        let _ = write!(writer, "{:?}{:?}{:?}{:?}{:?}{:?}{:?}{:?}{:?}{:?}", self.sampler, self.camera_desc, self.materials,
+                       self.textures, self.shapes, self.lights, self.filter, self.media, self.camera_medium, self.prototypes);
+        writer.0
+    }
+
     pub fn set_resolution(&mut self, resolution: ImageSize) {
         self.settings.resolution = resolution;
         self.camera_desc.resolution = resolution;
     }
 
+    /// Name that no material of the scene has. Base name is used when it is free, otherwise first
+    /// free numeric suffix is appended (e.g. glass.2), so names don't depend on anything but order of materials.
+    pub fn unique_material_name(&mut self, base: &str) -> String {
+        self.material_names.update(&self.materials);
+        self.material_names.unique(base)
+    }
+
+    /// Add material under unique name and return the name, see unique_material_name.
+    pub fn add_material(&mut self, mut desc: MaterialDescription) -> String {
+        desc.name = self.unique_material_name(&desc.name);
+        let name = desc.name.clone();
+        self.material_names.names.insert(name.clone());
+        self.material_names.indexed += 1;
+        self.materials.push(desc);
+        name
+    }
+
+    /// Append world elements (materials, shapes, lights and media) of another description. Materials
+    /// of other description whose names are already used get unique names and its shapes are updated,
+    /// so materials of merged fragments never alias.
+    pub fn merge(&mut self, other: SceneDescription) {
+        self.merge_at(other, None);
+    }
+
+    /// Same as merge, but world elements of other description are inserted at given position
+    /// instead of appended (e.g. where Import directive of the fragment was).
+    pub fn merge_at(&mut self, mut other: SceneDescription, position: Option<FragmentPosition>) {
+        let mut renamed = HashMap::new();
+        for desc in other.materials {
+            let name = desc.name.clone();
+            let unique = self.add_material(desc);
+            if unique != name {
+                renamed.insert(name, unique);
+            }
+        }
+        if !renamed.is_empty() {
+            let rename = |material: &mut String| {
+                if let Some(name) = renamed.get(material) {
+                    *material = name.clone();
+                }
+            };
+            for shape in other.shapes.iter_mut() {
+                match shape {
+                    ShapeDescription::Sphere(desc) => rename(&mut desc.material),
+                    ShapeDescription::Mesh(desc) => rename(&mut desc.material),
+                    ShapeDescription::BilinearMesh(desc) => rename(&mut desc.material),
+                    ShapeDescription::Instance(_) => {}
+                }
+            }
+            for mesh in other.prototypes.iter_mut().flat_map(|prototype| prototype.meshes.iter_mut()) {
+                rename(&mut mesh.material);
+            }
+        }
+        let position = position.unwrap_or_else(|| self.fragment_position());
+        fn insert<T>(items: &mut Vec<T>, index: usize, other: Vec<T>) {
+            let index = index.min(items.len());
+            items.splice(index..index, other);
+        }
+        insert(&mut self.textures, position.textures, other.textures);
+        insert(&mut self.shapes, position.shapes, other.shapes);
+        insert(&mut self.lights, position.lights, other.lights);
+        insert(&mut self.media, position.media, other.media);
+        insert(&mut self.prototypes, position.prototypes, other.prototypes);
+    }
+
+    /// Current number of world elements, it marks where fragment parsed later is merged.
+    pub fn fragment_position(&self) -> FragmentPosition {
+        FragmentPosition {
+            textures: self.textures.len(),
+            shapes: self.shapes.len(),
+            lights: self.lights.len(),
+            media: self.media.len(),
+            prototypes: self.prototypes.len(),
+        }
+    }
+
     pub fn create_sampler(&self) -> Box<dyn SamplerInterface> {
         match &self.sampler {
             Some(sampler) => sampler.create_sampler(),
@@ -127,6 +570,21 @@ impl SceneDescription {
     }
 }
 
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SceneFormat {
+    Json,
+    Pbrt
+}
+
+/// Parse scene from memory buffer (e.g. file fetched in browser), no filesystem access is needed.
+pub fn parse_scene_description(data: &[u8], format: SceneFormat) -> Result<SceneDescription, Box<dyn Error>> {
+    let text = std::str::from_utf8(data)?;
+    match format {
+        SceneFormat::Json => parse_scene_description_from_json(text),
+        SceneFormat::Pbrt => parse_pbrt_v4_string(text)
+    }
+}
+
 impl Default for SceneDescription {
     fn default() -> Self {
         Self {
@@ -134,52 +592,585 @@ impl Default for SceneDescription {
             settings: Settings::default(),
             camera_desc: PerspectiveCameraDescriptor::default(),
             materials: Vec::new(),
+            textures: Vec::new(),
             shapes: Vec::new(),
             lights: Vec::new(),
-            filter: None
+            filter: None,
+            media: Vec::new(),
+            camera_medium: None,
+            prototypes: Vec::new(),
+            material_names: MaterialNames::default()
         }
     }
 }
 
 
+/// Lights grouped in named layers, every layer is rendered to its own image and sum
+/// of all layers is the final image. Lights without group are in layer "default".
+#[derive(Debug, Default)]
+pub struct LightLayers {
+    pub names: Vec<String>,
+    /// Layer of every light in scene
+    pub lights: Vec<usize>,
+    /// Layer of every material, used for emission of emissive materials
+    pub materials: Vec<usize>
+}
+
+pub const DEFAULT_LIGHT_LAYER: &str = "default";
+
+impl LightLayers {
+    /// Layers are used only when at least one light (or emissive material) has a group
+    pub fn new(light_groups: &[Option<String>], material_groups: &[Option<String>]) -> Self {
+        if light_groups.iter().chain(material_groups.iter()).all(|group| group.is_none()) {
+            return Self::default();
+        }
+        let mut names = vec![DEFAULT_LIGHT_LAYER.to_string()];
+        let mut layer = |group: &Option<String>| -> usize {
+            let name = group.as_deref().unwrap_or(DEFAULT_LIGHT_LAYER);
+            match names.iter().position(|n| n == name) {
+                Some(index) => index,
+                None => {
+                    names.push(name.to_string());
+                    names.len() - 1
+                }
+            }
+        };
+        let lights = light_groups.iter().map(&mut layer).collect();
+        let materials = material_groups.iter().map(&mut layer).collect();
+        Self { names, lights, materials }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.names.is_empty()
+    }
+
+    pub fn len(&self) -> usize {
+        self.names.len()
+    }
+}
+
+/// File name of layer image, name of layer is appended to output file name (output_key.png).
+pub fn light_layer_fname(output_fname: &str, layer: &str) -> String {
+    match output_fname.rsplit_once('.') {
+        Some((stem, extension)) if !stem.is_empty() && !extension.contains('/') && !extension.contains('\\') => {
+            format!("{}_{}.{}", stem, layer, extension)
+        }
+        _ => format!("{}_{}.png", output_fname, layer)
+    }
+}
+
+/// Cooperative cancellation of rendering. Application keeps a clone of the token and cancels it
+/// from another thread, integrators check it between tiles and passes and return image rendered so far.
+#[derive(Debug, Clone, Default)]
+pub struct CancelToken {
+    cancelled: Arc<AtomicBool>
+}
+
+impl CancelToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+    }
+
+    /// Token can be reused for next rendering of the scene.
+    pub fn reset(&self) {
+        self.cancelled.store(false, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed)
+    }
+}
+
 pub struct Scene {
     pub settings: Settings,
     pub camera: PerspectiveCamera,
     pub materials: Vec<Box<dyn BSDFInterface>>,
+    pub material_names: HashMap<String, usize>,
+    /// Textured variant of every material, None for materials without textures
+    pub textured_materials: Vec<Option<TexturedMaterial>>,
+    /// Images used by textures, every image is loaded once
+    pub texture_images: Vec<Arc<TextureImage>>,
     pub geometry: Geometry,
     pub lights: Vec<Box<dyn LightInterface>>,
+    pub light_sampler: Box<dyn LightSamplerInterface>,
+    /// Records of indirect irradiance, created only when cache is enabled
+    pub irradiance_cache: Option<IrradianceCache>,
+    /// Learned incident radiance of random walk integrator, created only when path guiding is enabled
+    pub path_guide: Option<PathGuide>,
+    /// Texels of baked mesh, created only when lighting is baked
+    pub bake_map: Option<BakeMap>,
     pub sampler: Sampler,
-    pub filter: Option<Filter>
+    pub filter: Option<Filter>,
+    pub lpes: Vec<Lpe>,
+    pub light_layers: LightLayers,
+    pub media: Vec<Medium>,
+    /// Medium in which camera rays start, None is vacuum
+    pub camera_medium: Option<u32>,
+    pub warm_start: Option<WarmStart>,
+    /// Counters of sampling efficiency, created only when telemetry is enabled
+    pub telemetry: Option<SamplingTelemetry>,
+    /// Hash of scene description that checkpoints are matched against, computed only when checkpoint is written
+    pub fingerprint: u64,
+    pub cancel_token: CancelToken
+}
+
+/// Bytes used by parts of built scene, film is estimate of buffers allocated during rendering.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct MemoryReport {
+    /// Mesh vertices and indices and other shape data
+    pub vertex_data: usize,
+    /// Bounding boxes and primitive references of acceleration structures
+    pub acceleration: usize,
+    /// Environment images and density grids of media
+    pub textures: usize,
+    /// Accumulation buffers (image, light layers, light path expressions) and output image
+    pub film: usize,
+}
+
+impl MemoryReport {
+    pub fn total(&self) -> usize {
+        self.vertex_data + self.acceleration + self.textures + self.film
+    }
+}
+
+impl std::fmt::Display for MemoryReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mb = |bytes: usize| bytes as f64 / (1024.0 * 1024.0);
+        writeln!(f, "Vertex data:  {:10.2} MB", mb(self.vertex_data))?;
+        writeln!(f, "Acceleration: {:10.2} MB", mb(self.acceleration))?;
+        writeln!(f, "Textures:     {:10.2} MB", mb(self.textures))?;
+        writeln!(f, "Film:         {:10.2} MB", mb(self.film))?;
+        write!(f, "Total:        {:10.2} MB", mb(self.total()))
+    }
+}
+
+impl Scene {
+    pub fn memory_report(&self) -> MemoryReport {
+        let textures = self.lights.iter().map(|light| light.texture_memory()).sum::<usize>() +
+                       self.media.iter().map(|medium| medium.memory_usage()).sum::<usize>() +
+                       self.texture_images.iter().map(|image| image.memory_usage()).sum::<usize>();
+        let pixel_size = match self.settings.buffer_precision {
+            BufferPrecision::Full => std::mem::size_of::<PixelSample<RGB>>(),
+            BufferPrecision::Half => std::mem::size_of::<HalfPixelSample>(),
+        };
+        let resolution = self.settings.resolution;
+        let pixels = resolution.width * resolution.height;
+        let buffers = 1 + self.light_layers.len() + self.lpes.len();
+        let aov_size = self.settings.aovs.len() * pixel_size;
+        MemoryReport {
+            vertex_data: self.geometry.vertex_memory(),
+            acceleration: self.geometry.acceleration_memory(),
+            textures,
+            film: pixels * (buffers * pixel_size + aov_size + 3),
+        }
+    }
+
+    /// Start accumulation of radiance from image of the scene resolution instead of black image, image
+    /// stands for weight samples per pixel. Earlier render (e.g. low spp preview) is refined this way.
+    pub fn set_warm_start(&mut self, pixels: Vec<RGB>, weight: f32) -> Result<(), String> {
+        check_warm_start(&self.settings, &self.light_layers)?;
+        let resolution = self.settings.resolution;
+        if pixels.len() != resolution.width * resolution.height {
+            return Err(format!("Warm start: image has {} pixels, {}x{} expected!", pixels.len(), resolution.width, resolution.height))
+        }
+        if !(weight >= 0.0 && weight.is_finite()) {
+            return Err(format!("Warm start: invalid weight {}!", weight))
+        }
+        self.warm_start = Some(WarmStart { pixels, weight });
+        Ok(())
+    }
+
+    /// Material of the hit, textured materials are evaluated at the hit point.
+    pub fn material_at(&self, isect: &SurfaceInteraction) -> HitMaterial<'_> {
+        let material_id = isect.material_id as usize;
+        match &self.textured_materials[material_id] {
+            Some(material) => HitMaterial::Textured(material.bsdf(&TextureContext::new(isect, &self.geometry))),
+            None => HitMaterial::Shared(self.materials[material_id].as_ref())
+        }
+    }
+
+    /// Material with given id evaluated at texture context.
+    pub fn material(&self, material_id: usize, ctx: &TextureContext) -> HitMaterial<'_> {
+        match &self.textured_materials[material_id] {
+            Some(material) => HitMaterial::Textured(material.bsdf(ctx)),
+            None => HitMaterial::Shared(self.materials[material_id].as_ref())
+        }
+    }
+
+    /// Radius of reconstruction filter, None when samples are added only to their pixel
+    pub fn filter_radius(&self) -> Option<f32> {
+        self.filter.as_ref().map(|filter| filter.max_radius())
+    }
+
+    /// Weight of sample at offset from center of pixel, it is one without reconstruction filter
+    pub fn filter_weight(&self, x: f32, y: f32) -> f32 {
+        self.filter.as_ref().map_or(1.0, |filter| filter.evaluate(x, y))
+    }
+
+    /// Radiance of infinite lights seen in direction of ray that left the scene
+    pub fn environment_radiance(&self, direction: Vec3) -> RGB {
+        let mut radiance = RGB::zero();
+        for light in self.lights.iter().filter(|light| light.is_infinite_light()) {
+            radiance += light.le(direction);
+        }
+        radiance
+    }
+
+    /// Radiance of infinite lights added to accumulators of their light layers
+    pub fn add_environment_radiance_to_layers(&self, direction: Vec3, weight: RGB, layers: &mut [RGB]) {
+        for (index, light) in self.lights.iter().enumerate().filter(|(_, light)| light.is_infinite_light()) {
+            layers[self.light_layers.lights[index]] += weight * light.le(direction);
+        }
+    }
+}
+
+// Cleanup and orientation of mesh, returns messages about repairs that were made.
+fn process_mesh(index: usize, mesh: &mut MeshDescription, settings: &Settings) -> Vec<String> {
+    let mut messages = Vec::new();
+    if settings.mesh_cleanup {
+        let report = mesh.cleanup();
+        if !report.is_valid() {
+            messages.push(format!("Mesh {}: removed {} triangles - {}", index, report.invalid_triangles(), report));
+        }
+    }
+    if settings.mesh_orientation {
+        let report = mesh.orient();
+        if report.flipped_triangles > 0 || report.inverted_components > 0 {
+            messages.push(format!("Mesh {}: flipped {} triangles, inverted {} of {} components", index,
+                                  report.flipped_triangles, report.inverted_components, report.components));
+        }
+    }
+    messages
+}
+
+// Warm start image is radiance of camera image, so buffers of other outputs would start from black
+// image and integrators that don't accumulate tiles would ignore it.
+fn check_warm_start(settings: &Settings, light_layers: &LightLayers) -> Result<(), String> {
+    let supported = matches!(settings.rendering_algorithm, RenderingAlgorithm::AmbientOcclusion(_) |
+                             RenderingAlgorithm::DirectLighting | RenderingAlgorithm::RandomWalk(_));
+    if !supported || settings.bake.is_some() || settings.bucket_output.is_some() {
+        return Err("Warm start - only ambient occlusion, direct lighting and random walk integrators rendering \
+                    camera image support warm start!".to_string())
+    }
+    let bent_normals = matches!(&settings.rendering_algorithm,
+                                RenderingAlgorithm::AmbientOcclusion(ao) if ao.bent_normal_output.is_some());
+    if !light_layers.names.is_empty() || !settings.lpes.is_empty() || !settings.aovs.is_empty() ||
+       settings.ao_output.is_some() || bent_normals {
+        return Err("Warm start - image seeds only radiance, light layer, LPE, AOV and bent normal outputs \
+                    can't be warm started!".to_string())
+    }
+    Ok(())
+}
+
+fn load_warm_start(settings: &WarmStartProperties, resolution: ImageSize) -> Result<WarmStart, Box<dyn Error>> {
+    let image = match TextureImage::load_linear(&settings.image) {
+        Ok(image) => image,
+        Err(e) => return Err(format!("Warm start: {} - {}", settings.image, e).into())
+    };
+    if (image.width(), image.height()) != (resolution.width, resolution.height) {
+        return Err(format!("Warm start: image {} is {}x{}, {}x{} expected!", settings.image, image.width(),
+                           image.height(), resolution.width, resolution.height).into())
+    }
+    Ok(WarmStart { pixels: image.pixels().to_vec(), weight: settings.weight })
 }
 
-impl From<SceneDescription> for Scene {
-    fn from(mut desc: SceneDescription) -> Self {
+// Name of emissive material of a shape or prototype mesh
+fn emissive_shape_material<'a>(desc: &'a SceneDescription, materials: &[Box<dyn BSDFInterface>],
+                               mat_names: &HashMap<String, usize>) -> Option<&'a str> {
+    let shape_materials = desc.shapes.iter().filter_map(|shape| match shape {
+        ShapeDescription::Sphere(sphere) => Some(&sphere.material),
+        ShapeDescription::Mesh(mesh) => Some(&mesh.material),
+        ShapeDescription::BilinearMesh(mesh) => Some(&mesh.material),
+        ShapeDescription::Instance(_) => None
+    });
+    let prototype_materials = desc.prototypes.iter().flat_map(|prototype| prototype.meshes.iter().map(|mesh| &mesh.material));
+    shape_materials.chain(prototype_materials)
+        .find(|name| mat_names.get(name.as_str()).is_some_and(|&id| materials[id].is_emissive()))
+        .map(|name| name.as_str())
+}
+
+fn create_bake_map(desc: &SceneDescription, bake: BakeProperties) -> Result<BakeMap, Box<dyn Error>> {
+    if !is_bakeable(&desc.settings.rendering_algorithm) {
+        return Err("Bake - only ambient occlusion, direct lighting and random walk can be baked!".into())
+    }
+    match desc.shapes.get(bake.mesh) {
+        Some(ShapeDescription::Mesh(mesh)) => Ok(BakeMap::new(mesh, desc.settings.resolution)?),
+        _ => Err(format!("Bake - shape {} is not a triangle mesh!", bake.mesh).into())
+    }
+}
+
+/// Meshes are processed in parallel (in current rayon thread pool), ids of materials, shapes
+/// and lights follow order of scene description so conversion is deterministic.
+impl TryFrom<SceneDescription> for Scene {
+    type Error = Box<dyn Error>;
+
+    fn try_from(mut desc: SceneDescription) -> Result<Self, Self::Error> {
+        let fingerprint = if desc.settings.checkpoint.is_some() { desc.fingerprint() } else { 0 };
         let mut materials = Vec::new();
         let mut mat_names = HashMap::new();
         for mat_desc in desc.materials.iter() {
-            let mat = match mat_desc.create() {
-                Ok(mat) => mat,
-                Err(err) => panic!("{}", err)
-            };
+            let mat = mat_desc.create()?;
             mat_names.insert(mat_desc.name.clone(), materials.len());
             materials.push(mat);
         }
-        let geometry = Geometry::from_shape_descriptions(&mut desc.shapes, &mat_names);
+        // NOTE: photons are emitted only by lights, so area lights would be seen only directly
+        if let RenderingAlgorithm::Sppm(_) = desc.settings.rendering_algorithm {
+            if let Some(name) = emissive_shape_material(&desc, &materials, &mat_names) {
+                return Err(format!("SPPM - emissive material {} is not supported, only lights emit photons!", name).into())
+            }
+        }
+        let mut images = HashMap::new();
+        let mut textures: HashMap<String, Arc<Texture>> = HashMap::new();
+        for tex_desc in desc.textures.iter() {
+            let texture = tex_desc.create(&mut images, &textures)?;
+            textures.insert(tex_desc.name.clone(), Arc::new(texture));
+        }
+        let texture = |material: &MaterialDescription, name: &Option<String>| -> Result<Option<Arc<Texture>>, String> {
+            match name {
+                Some(name) => match textures.get(name) {
+                    Some(texture) => Ok(Some(texture.clone())),
+                    None => Err(format!("Material {}: texture {} doesn't exist!", material.name, name))
+                },
+                None => Ok(None)
+            }
+        };
+        let mut textured_materials = Vec::new();
+        for mat_desc in desc.materials.iter() {
+            let reflectance = texture(mat_desc, &mat_desc.diffuse_texture)?;
+            let roughness = texture(mat_desc, &mat_desc.roughness_texture)?;
+            textured_materials.push(match (reflectance, roughness) {
+                (None, None) => None,
+                (reflectance, roughness) => Some(TexturedMaterial::new(mat_desc,
+                    reflectance.map(|texture| texture as _), roughness.map(|texture| texture as _))?)
+            });
+        }
+        let texture_images = images.into_values().collect();
+        let settings = &desc.settings;
+        let mut messages: Vec<_> = desc.shapes.par_iter_mut().enumerate().map(|(index, shape)| {
+            match shape {
+                ShapeDescription::Mesh(mesh) => process_mesh(index, mesh, settings),
+                _ => Vec::new()
+            }
+        }).collect();
+        for prototype in desc.prototypes.iter_mut() {
+            messages.extend(prototype.meshes.iter_mut().enumerate().map(|(index, mesh)| process_mesh(index, mesh, settings)));
+        }
+        for message in messages.iter().flatten() {
+            println!("{}", message);
+        }
+        // NOTE: map is created before geometry takes vertices of meshes
+        let bake_map = match desc.settings.bake {
+            Some(bake) => Some(create_bake_map(&desc, bake)?),
+            None => None
+        };
+        let media = desc.media.iter().map(|medium| medium.create()).collect::<Result<Vec<_>, _>>()?;
+        let medium_names: HashMap<_, _> = desc.media.iter().enumerate()
+            .map(|(id, medium)| (medium.name.clone(), id as u32)).collect();
+        let camera_medium = match &desc.camera_medium {
+            Some(name) => match medium_names.get(name) {
+                Some(id) => Some(*id),
+                None => return Err(format!("Camera medium {} doesn't exist!", name).into())
+            },
+            None => None
+        };
+        let mut geometry = Geometry::from_shape_descriptions(&mut desc.shapes, &mut desc.prototypes, &mat_names, &medium_names, &textures)?;
+        geometry.set_epsilon_policy(desc.settings.epsilon);
+        geometry.set_accelerator_type(desc.settings.accelerator);
+        let (scene_center, scene_radius) = match geometry.bounds() {
+            Some(bounds) => {
+                let center = bounds.centroid();
+                (center, center.distance(bounds.max()))
+            }
+            None => (Point3::new(0.0, 0.0, 0.0), 1.0)
+        };
         let mut lights = Vec::new();
         for light_desc in desc.lights.iter() {
-            let light = light_desc.create();
+            let mut light = light_desc.create()?;
+            light.preprocess(scene_center, scene_radius);
             lights.push(light);
         }
+        let light_sampler = create_light_sampler(desc.settings.light_sampler, &lights);
+        let irradiance_cache = match desc.settings.irradiance_cache {
+            Some(settings) if settings.enabled => geometry.bounds().map(|bounds| IrradianceCache::new(settings, bounds)),
+            _ => None
+        };
+        // NOTE: wavefront random walk doesn't sample the guide, so guiding would be silently ignored
+        if let (Some(_), RenderingAlgorithm::RandomWalk(settings)) = (desc.settings.path_guiding, &desc.settings.rendering_algorithm) {
+            if settings.wavefront {
+                return Err("Path guiding - wavefront random walk doesn't support path guiding!".into())
+            }
+        }
+        let path_guide = match (desc.settings.path_guiding, geometry.bounds()) {
+            (Some(settings), Some(bounds)) => Some(PathGuide::new(settings, bounds)),
+            _ => None
+        };
+        let light_groups: Vec<_> = desc.lights.iter().map(|light| light.group.clone()).collect();
+        let material_groups: Vec<_> = desc.materials.iter().map(|mat| mat.light_group.clone()).collect();
+        let light_layers = LightLayers::new(&light_groups, &material_groups);
         let sampler = desc.sampler.unwrap_or(Sampler::Random(RandomSamplerSettings::default()));
         let filter = desc.filter.map(|desc| desc.create());
-        Self {
+        let warm_start = match &desc.settings.warm_start {
+            Some(settings) => {
+                check_warm_start(&desc.settings, &light_layers)?;
+                Some(load_warm_start(settings, desc.settings.resolution)?)
+            }
+            None => None
+        };
+        let records_telemetry = matches!(desc.settings.rendering_algorithm,
+                                         RenderingAlgorithm::DirectLighting | RenderingAlgorithm::RandomWalk(_));
+        if desc.settings.telemetry && !records_telemetry {
+            println!("Warning: Sampling telemetry is supported only by direct lighting and random walk integrators, it is disabled!");
+        }
+        let telemetry = (desc.settings.telemetry && records_telemetry).then(|| {
+            let light_names = desc.lights.iter().enumerate().map(|(index, light)| format!("{} {}", index, light.typ.name())).collect();
+            SamplingTelemetry::new(desc.materials.iter().map(|mat| mat.name.clone()).collect(), light_names)
+        });
+        let mut lpes = Vec::new();
+        for lpe_output in desc.settings.lpes.iter() {
+            match Lpe::parse(&lpe_output.expression) {
+                Ok(lpe) => lpes.push(lpe),
+                Err(err) => return Err(format!("Light path expression {}: {}", lpe_output.expression, err).into())
+            }
+        }
+        Ok(Self {
             settings: desc.settings,
-            camera: desc.camera_desc.create(),
+            camera: desc.camera_desc.create().map_err(|e| format!("Camera: {}", e))?,
             materials,
+            material_names: mat_names,
+            textured_materials,
+            texture_images,
             geometry,
             lights,
+            light_sampler,
+            irradiance_cache,
+            path_guide,
+            bake_map,
             sampler,
-            filter
+            filter,
+            lpes,
+            light_layers,
+            media,
+            camera_medium,
+            warm_start,
+            telemetry,
+            fingerprint,
+            cancel_token: CancelToken::new()
+        })
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::shapes::MeshDescription;
+
+    #[test]
+    fn tile_samplers() {
+        let sampler = Sampler::Random(RandomSamplerSettings::default());
+        let tile = Tile::new(32, 0, 64, 32);
+        let samples = |tile: &Tile, iteration: u32| {
+            let mut sampler = sampler.clone_for_tile(tile, iteration);
+            let (x, y) = sampler.sample_pixel(tile.x1, tile.y1, 0);
+            (x, y, sampler.sample_time())
+        };
+        // same tile and iteration repeats its samples, other tiles and iterations get different ones
+        assert_eq!(samples(&tile, 3), samples(&tile, 3));
+        assert_ne!(samples(&tile, 3), samples(&tile, 4));
+        assert_ne!(samples(&tile, 3), samples(&Tile::new(0, 0, 32, 32), 3));
+        assert_ne!(samples(&tile, 3).2, samples(&Tile::new(0, 0, 32, 32), 3).2);
+    }
+
+    #[test]
+    fn merged_materials_dont_alias() {
+        let material = |name: &str| MaterialDescription { name: name.to_string(), ..Default::default() };
+        let mut scene = SceneDescription::default();
+        assert_eq!(scene.add_material(material("glass")), "glass");
+        let mut fragment = SceneDescription::default();
+        fragment.add_material(material("glass"));
+        assert_eq!(fragment.add_material(material("glass")), "glass.1");
+        fragment.shapes.push(ShapeDescription::Mesh(MeshDescription { material: "glass".to_string(), ..Default::default() }));
+        fragment.shapes.push(ShapeDescription::Mesh(MeshDescription { material: "glass.1".to_string(), ..Default::default() }));
+        scene.merge(fragment);
+
+        let names: Vec<&str> = scene.materials.iter().map(|material| material.name.as_str()).collect();
+        assert_eq!(names, vec!["glass", "glass.1", "glass.1.1"]);
+        let shape_materials: Vec<&str> = scene.shapes.iter().map(|shape| match shape {
+            ShapeDescription::Mesh(desc) => desc.material.as_str(),
+            _ => ""
+        }).collect();
+        assert_eq!(shape_materials, vec!["glass.1", "glass.1.1"]);
+
+        // materials pushed directly are also taken into account
+        scene.materials.push(material("wood"));
+        assert_eq!(scene.add_material(material("wood")), "wood.1");
+        for _ in 0..1000 {
+            scene.add_material(material("light"));
         }
+        assert_eq!(scene.add_material(material("light")), "light.1000");
+    }
+
+    #[test]
+    fn scene_fingerprint() {
+        let scene = || {
+            let mut desc = SceneDescription::default();
+            desc.materials.push(MaterialDescription { name: "matte".to_string(), ..Default::default() });
+            desc.shapes.push(ShapeDescription::Mesh(MeshDescription { material: "matte".to_string(), ..Default::default() }));
+            desc
+        };
+        let fingerprint = scene().fingerprint();
+        assert_eq!(scene().fingerprint(), fingerprint);
+        let mut desc = scene();
+        desc.settings.output_fname = "other.png".to_string();
+        desc.settings.nthreads = 3;
+        assert_eq!(desc.fingerprint(), fingerprint);
+        let mut desc = scene();
+        desc.materials[0].diffuse = RGB::new(0.1, 0.2, 0.3);
+        assert_ne!(desc.fingerprint(), fingerprint);
+        let mut desc = scene();
+        desc.sampler = Some(Sampler::Random(RandomSamplerSettings { seed: 7 }));
+        assert_ne!(desc.fingerprint(), fingerprint);
+        let mut desc = scene();
+        desc.settings.rendering_algorithm = RenderingAlgorithm::DirectLighting;
+        assert_ne!(desc.fingerprint(), fingerprint);
+    }
+
+    #[test]
+    fn missing_texture() {
+        let mut desc = SceneDescription::default();
+        desc.materials.push(MaterialDescription { diffuse_texture: Some("wood".to_string()), ..Default::default() });
+        let err = Scene::try_from(desc).err().expect("Missing texture is an error");
+        assert!(err.to_string().contains("wood"));
+    }
+
+    #[test]
+    fn wavefront_path_guiding() {
+        let mut desc = SceneDescription::default();
+        desc.settings.path_guiding = Some(PathGuidingProperties::default());
+        desc.settings.rendering_algorithm = RenderingAlgorithm::RandomWalk(RandomWalkProperties { wavefront: true, ..Default::default() });
+        let err = Scene::try_from(desc).err().expect("Path guiding of wavefront random walk is an error");
+        assert!(err.to_string().contains("wavefront"));
+    }
+
+    #[test]
+    fn scene_memory_report() {
+        let mut desc = SceneDescription::default();
+        desc.set_resolution(ImageSize::new(10, 10));
+        desc.settings.mesh_cleanup = false;
+        desc.materials.push(MaterialDescription::default());
+        let vertices = vec![Point3::new(0.0, 0.0, 0.0), Point3::new(1.0, 0.0, 0.0), Point3::new(0.0, 1.0, 0.0)];
+        desc.shapes.push(ShapeDescription::Mesh(MeshDescription { vertices: Some(vertices), indices: Some(vec![0, 1, 2]),
+                                                                   material: "matte".to_string(), ..Default::default() }));
+        let scene = Scene::try_from(desc).unwrap();
+        let report = scene.memory_report();
+        assert!(report.vertex_data >= 3 * std::mem::size_of::<Point3>() + 3 * std::mem::size_of::<u32>());
+        assert!(report.acceleration > 0);
+        assert_eq!(report.textures, 0);
+        assert_eq!(report.film, 100 * (std::mem::size_of::<PixelSample<RGB>>() + 3));
+        assert_eq!(report.total(), report.vertex_data + report.acceleration + report.film);
     }
 }
@@ -1,8 +1,14 @@
 use crate::vec::{Point3, Normal, Vec3, Point2};
-use crate::transformations::Transformation;
+use crate::transformations::{Transformation, AnimatedTransformation};
 use crate::ray::Ray;
-use std::ops::Mul;
+use std::ops::{Add, Mul};
 use std::collections::HashMap;
+use crate::media::{MediumInterface, MediumIds};
+use crate::epsilon::{EpsilonPolicy, INFINITE_DISTANCE};
+use rayon::prelude::*;
+use std::sync::Arc;
+use crate::textures::{FloatTexture, Texture, TextureContext};
+use crate::bvh::{BVH, BVHBuildMethod};
 
 pub trait Intersect {
     fn intersect(&self, ray: &Ray, tmin: f32) -> Option<f32>;
@@ -12,6 +18,10 @@ pub trait CalculateNormal {
     fn normal(&self, ray: &Ray, hit_point: Point3) -> Normal;
 }
 
+pub trait CalculateUV {
+    fn uv(&self, hit_point: Point3) -> Point2;
+}
+
 pub trait BoundingBox {
     fn bounding_box(&self) -> AABB;
 }
@@ -27,9 +37,30 @@ impl AABB {
         Self { min, max }
     }
 
-    pub fn intersect(&self, ray_origin: Point3, ray_inv_direction: Vec3) -> bool {
+    pub fn intersect(&self, ray_origin: Point3, ray_inv_direction: Vec3) -> Option<f32> {
         crate::isect::isect_ray_bbox(ray_origin, ray_inv_direction, self.min, self.max)
     }
+
+    pub fn min(&self) -> Point3 {
+        self.min
+    }
+
+    pub fn max(&self) -> Point3 {
+        self.max
+    }
+
+    pub fn union(&self, other: &AABB) -> AABB {
+        AABB::new(self.min.min(other.min), self.max.max(other.max))
+    }
+
+    pub fn centroid(&self) -> Point3 {
+        self.min + (self.max - self.min) * 0.5
+    }
+
+    pub fn surface_area(&self) -> f32 {
+        let d = self.max - self.min;
+        2.0 * (d.x * d.y + d.x * d.z + d.y * d.z)
+    }
 }
 
 impl Mul<Transformation> for AABB {
@@ -41,9 +72,9 @@ impl Mul<Transformation> for AABB {
         let p3 = rhs * (self.min + Vec3::new(delta.x, 0.0, 0.0));
         let p4 = rhs * (self.min + Vec3::new(0.0, delta.y, 0.0));
         let p5 = rhs * (self.min + Vec3::new(delta.x, delta.y, 0.0));
-        let p6 = rhs * (self.max + Vec3::new(delta.x, 0.0, 0.0));
-        let p7 = rhs * (self.max + Vec3::new(0.0, delta.y, 0.0));
-        let p8 = rhs * (self.max + Vec3::new(delta.x, delta.y, 0.0));
+        let p6 = rhs * (self.min + Vec3::new(0.0, delta.y, delta.z));
+        let p7 = rhs * (self.min + Vec3::new(delta.x, 0.0, delta.z));
+        let p8 = rhs * (self.min + Vec3::new(0.0, 0.0, delta.z));
         let min_p = p1.min(p2).min(p3).min(p4).min(p5).min(p6).min(p7).min(p8);
         let max_p = p1.max(p2).max(p3).max(p4).max(p5).max(p6).max(p7).max(p8);
         AABB::new(min_p, max_p)
@@ -59,25 +90,26 @@ impl LinearIntersector {
         Self { bboxes: Vec::new() }
     }
 
+    // NOTE: boxes are computed in parallel, collect keeps them in order of primitives
     pub fn prepare_for_rendering(&mut self, n_primitives: usize,
-        calculate_bbox_fn: &dyn Fn(usize) -> AABB) {
-        self.bboxes.clear();
-        for i in 0..n_primitives {
-            self.bboxes.push(calculate_bbox_fn(i));
-        }
+        calculate_bbox_fn: &(dyn Fn(usize) -> AABB + Sync)) {
+        self.bboxes = (0..n_primitives).into_par_iter().map(calculate_bbox_fn).collect();
     }
 
     pub fn intersect(&self, ray: &Ray,
     isect_fn: &dyn Fn(usize, &Ray) -> Option<f32>) -> Option<ShapeIntersection> {
         let mut primitive_id = 0;
-        const BIG_NUMBER: f32 = 1e38;
-        let mut current_t = BIG_NUMBER;
+        let mut current_t = INFINITE_DISTANCE;
         let rd = ray.direction;
         let inv_rd = Vec3::new(1.0 / rd.x, 1.0 / rd.y, 1.0 / rd.z);
     
         for (idx, bbox) in self.bboxes.iter().enumerate() {
-            // Note: ray-bbox to return t and used that information to improve performance
-            if bbox.intersect(ray.origin, inv_rd) {
+            // NOTE: boxes that are entered after the closest hit found so far are skipped
+            let entry_t = match bbox.intersect(ray.origin, inv_rd) {
+                Some(entry_t) => entry_t,
+                None => continue
+            };
+            if entry_t <= current_t {
                 let result = isect_fn(idx, ray);
                 if let Some(t) = result {
                     if t < current_t {
@@ -87,29 +119,125 @@ impl LinearIntersector {
                 }
             }
         }
-        if current_t < BIG_NUMBER {
+        if current_t < INFINITE_DISTANCE {
             Some(ShapeIntersection { t: current_t, shape_id: primitive_id})
         } else {
             None
         }
     }
+
+    /// Bounding box of all primitives, None if there are no primitives.
+    pub fn bounds(&self) -> Option<AABB> {
+        self.bboxes.iter().copied().reduce(|a, b| a.union(&b))
+    }
+
+    /// Bytes used by bounding boxes
+    pub fn memory_usage(&self) -> usize {
+        self.bboxes.capacity() * std::mem::size_of::<AABB>()
+    }
+}
+
+/// Acceleration structure that is used for intersection of primitives
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AcceleratorType {
+    Bvh(BVHBuildMethod),
+    /// Every primitive is tested, useful for debugging of BVH
+    Linear
+}
+
+impl Default for AcceleratorType {
+    fn default() -> Self {
+        AcceleratorType::Bvh(BVHBuildMethod::SAH)
+    }
+}
+
+/// Bounding boxes of primitives are always kept for bounds of the geometry, BVH is built from them.
+pub struct Accelerator {
+    typ: AcceleratorType,
+    linear: LinearIntersector,
+    bvh: Option<BVH>,
+}
+
+impl Accelerator {
+    pub fn new(typ: AcceleratorType) -> Self {
+        Self { typ, linear: LinearIntersector::new(), bvh: None }
+    }
+
+    pub fn accelerator_type(&self) -> AcceleratorType {
+        self.typ
+    }
+
+    /// Type is changed, prepare_for_rendering must be called again.
+    pub fn set_accelerator_type(&mut self, typ: AcceleratorType) {
+        self.typ = typ;
+    }
+
+    pub fn prepare_for_rendering(&mut self, n_primitives: usize,
+        calculate_bbox_fn: &(dyn Fn(usize) -> AABB + Sync)) {
+        self.linear.prepare_for_rendering(n_primitives, calculate_bbox_fn);
+        self.bvh = match self.typ {
+            AcceleratorType::Bvh(method) => {
+                let mut bvh = BVH::new(method);
+                bvh.build(n_primitives, &|idx| self.linear.bboxes[idx]);
+                Some(bvh)
+            }
+            AcceleratorType::Linear => None
+        };
+    }
+
+    pub fn intersect(&self, ray: &Ray, isect_fn: &dyn Fn(usize, &Ray) -> Option<f32>) -> Option<ShapeIntersection> {
+        match &self.bvh {
+            Some(bvh) => bvh.intersect(ray, isect_fn),
+            None => self.linear.intersect(ray, isect_fn)
+        }
+    }
+
+    /// Bounding box of all primitives, None if there are no primitives.
+    pub fn bounds(&self) -> Option<AABB> {
+        self.linear.bounds()
+    }
+
+    /// Bytes used by bounding boxes and BVH
+    pub fn memory_usage(&self) -> usize {
+        self.linear.memory_usage() + self.bvh.as_ref().map_or(0, |bvh| bvh.memory_usage())
+    }
 }
 
 
 pub struct Sphere {
     center: Point3,
     radius: f32,
+    // NOTE: clipping is relative to center, phimax is in radians
+    zmin: f32,
+    zmax: f32,
+    phimax: f32
 }
 
 impl Sphere {
     pub fn new(center: Point3, radius: f32) -> Self {
-        Self { center, radius }
+        Self { center, radius, zmin: -radius, zmax: radius, phimax: 2.0 * std::f32::consts::PI }
+    }
+
+    /// Sphere clipped by z range and maximum phi angle (in degrees) like pbrt sphere.
+    pub fn partial(center: Point3, radius: f32, zmin: f32, zmax: f32, phimax: f32) -> Self {
+        let (zmin, zmax) = (zmin.min(zmax).clamp(-radius, radius), zmin.max(zmax).clamp(-radius, radius));
+        let phimax = phimax.clamp(0.0, 360.0).to_radians();
+        Self { center, radius, zmin, zmax, phimax }
+    }
+
+    fn is_full(&self) -> bool {
+        self.zmin <= -self.radius && self.zmax >= self.radius && self.phimax >= 2.0 * std::f32::consts::PI
     }
 }
 
 impl Intersect for Sphere {
     fn intersect(&self, ray: &Ray, tmin: f32) -> Option<f32> {
-        crate::isect::isect_ray_sphere(ray, self.center, self.radius, tmin, 1e38)
+        if self.is_full() {
+            crate::isect::isect_ray_sphere(ray, self.center, self.radius, tmin, INFINITE_DISTANCE)
+        } else {
+            crate::isect::isect_ray_partial_sphere(ray, self.center, self.radius, self.zmin, self.zmax,
+                                                   self.phimax, tmin, INFINITE_DISTANCE)
+        }
     }
 }
 
@@ -119,14 +247,186 @@ impl CalculateNormal for Sphere {
     }
 }
 
+// u = phi / phimax, v goes from zmin to zmax along theta
+impl CalculateUV for Sphere {
+    fn uv(&self, hit_point: Point3) -> Point2 {
+        let p = hit_point - self.center;
+        let mut phi = p.y.atan2(p.x);
+        if phi < 0.0 {
+            phi += 2.0 * std::f32::consts::PI;
+        }
+        let theta = (p.z / self.radius).clamp(-1.0, 1.0).acos();
+        let theta_zmin = (self.zmin / self.radius).clamp(-1.0, 1.0).acos();
+        let theta_zmax = (self.zmax / self.radius).clamp(-1.0, 1.0).acos();
+        let u = if self.phimax > 0.0 { phi / self.phimax } else { 0.0 };
+        let v = if theta_zmax != theta_zmin { (theta - theta_zmin) / (theta_zmax - theta_zmin) } else { 0.0 };
+        Point2::new(u.clamp(0.0, 1.0), v.clamp(0.0, 1.0))
+    }
+}
+
 impl BoundingBox for Sphere {
     fn bounding_box(&self) -> AABB {
-        let min = self.center + Vec3::new(-self.radius, -self.radius, -self.radius);
-        let max = self.center + Vec3::new(self.radius, self.radius, self.radius);
+        let min = self.center + Vec3::new(-self.radius, -self.radius, self.zmin);
+        let max = self.center + Vec3::new(self.radius, self.radius, self.zmax);
         AABB::new(min, max)
     }
 }
 
+/// Bilinear patch p(u, v) = lerp(v, lerp(u, p00, p10), lerp(u, p01, p11)), optional vertex
+/// normals and uvs are interpolated the same way.
+pub struct BilinearPatch {
+    vertices: [Point3; 4],
+    normals: Option<[Normal; 4]>,
+    uvs: Option<[Point2; 4]>,
+}
+
+fn bilerp<T: Add<Output = T> + Mul<f32, Output = T> + Copy>(values: &[T; 4], u: f32, v: f32) -> T {
+    values[0] * ((1.0 - u) * (1.0 - v)) + values[1] * (u * (1.0 - v)) + values[2] * ((1.0 - u) * v) + values[3] * (u * v)
+}
+
+impl BilinearPatch {
+    pub fn new(p00: Point3, p10: Point3, p01: Point3, p11: Point3) -> Self {
+        Self { vertices: [p00, p10, p01, p11], normals: None, uvs: None }
+    }
+
+    pub fn with_normals(self, normals: Option<[Normal; 4]>) -> Self {
+        Self { normals, ..self }
+    }
+
+    pub fn with_uvs(self, uvs: Option<[Point2; 4]>) -> Self {
+        Self { uvs, ..self }
+    }
+
+    pub fn point(&self, u: f32, v: f32) -> Point3 {
+        bilerp(&self.vertices, u, v)
+    }
+
+    fn derivatives(&self, u: f32, v: f32) -> (Vec3, Vec3) {
+        let [p00, p10, p01, p11] = self.vertices;
+        let dpdu = (p10 - p00) * (1.0 - v) + (p11 - p01) * v;
+        let dpdv = (p01 - p00) * (1.0 - u) + (p11 - p10) * u;
+        (dpdu, dpdv)
+    }
+
+    /// Parametric coordinates of point on the patch, bilinear map is inverted by Newton iterations
+    /// of least squares problem, so point slightly off the surface is also handled.
+    pub fn parametric_coordinates(&self, hit_point: Point3) -> (f32, f32) {
+        let (mut u, mut v) = (0.5, 0.5);
+        for _ in 0..8 {
+            let (dpdu, dpdv) = self.derivatives(u, v);
+            let r = hit_point - self.point(u, v);
+            let (a, b, c) = (dpdu * dpdu, dpdu * dpdv, dpdv * dpdv);
+            let det = a * c - b * b;
+            if det == 0.0 {
+                break
+            }
+            let (ru, rv) = (dpdu * r, dpdv * r);
+            let du = (c * ru - b * rv) / det;
+            let dv = (a * rv - b * ru) / det;
+            u = (u + du).clamp(0.0, 1.0);
+            v = (v + dv).clamp(0.0, 1.0);
+            if du.abs() < 1e-6 && dv.abs() < 1e-6 {
+                break
+            }
+        }
+        (u, v)
+    }
+
+    /// Vertex normals interpolated at hit point, None if patch doesn't have normals.
+    pub fn shading_normal(&self, hit_point: Point3) -> Option<Normal> {
+        let normals = self.normals.as_ref()?;
+        let (u, v) = self.parametric_coordinates(hit_point);
+        let normal = bilerp(normals, u, v);
+        if normal.length_sqr() == 0.0 {
+            return None
+        }
+        Some(normal.normalize())
+    }
+}
+
+// NOTE: direct intersection of ray with bilinear patch (Reshetov, Ray Tracing Gems 2019),
+// quadratic equation is solved for u and v, t follow from the closest point of the ray and line at u
+impl Intersect for BilinearPatch {
+    fn intersect(&self, ray: &Ray, tmin: f32) -> Option<f32> {
+        let [p00, p10, p01, p11] = self.vertices;
+        let (o, d) = (ray.origin, ray.direction);
+        let a = (p10 - p00).cross(p01 - p11) * d;
+        let c = (p00 - o).cross(d) * (p01 - p00);
+        let b = (p10 - o).cross(d) * (p11 - p10) - (a + c);
+        let det = b * b - 4.0 * a * c;
+        if det < 0.0 {
+            return None
+        }
+        let (u1, u2) = if a == 0.0 {
+            (-c / b, -1.0)
+        } else {
+            let q = -0.5 * (b + det.sqrt().copysign(b));
+            (q / a, c / q)
+        };
+
+        let mut result: Option<f32> = None;
+        for u in [u1, u2] {
+            if !(0.0..=1.0).contains(&u) {
+                continue
+            }
+            let uo = p00 + (p10 - p00) * u;
+            let ud = (p01 + (p11 - p01) * u) - uo;
+            let deltao = uo - o;
+            let perp = d.cross(ud);
+            let p2 = perp * perp;
+            let v = deltao * d.cross(perp);
+            let t = deltao * ud.cross(perp);
+            if p2 == 0.0 || v < 0.0 || v > p2 || t <= tmin * p2 {
+                continue
+            }
+            let t = t / p2;
+            if result.is_none_or(|current| t < current) {
+                result = Some(t);
+            }
+        }
+        result
+    }
+}
+
+impl CalculateNormal for BilinearPatch {
+    fn normal(&self, _ray: &Ray, hit_point: Point3) -> Normal {
+        let (u, v) = self.parametric_coordinates(hit_point);
+        let (dpdu, dpdv) = self.derivatives(u, v);
+        let mut normal = dpdu.cross(dpdv);
+        // NOTE: degenerate corner (two vertices are same), normal is taken from the whole patch
+        if normal.length_sqr() == 0.0 {
+            let [p00, p10, p01, p11] = self.vertices;
+            normal = (p11 - p00).cross(p01 - p10);
+        }
+        let normal = Normal::from(normal.normalize());
+        match self.normals {
+            Some(normals) if bilerp(&normals, u, v) * normal < 0.0 => -normal,
+            _ => normal
+        }
+    }
+}
+
+impl CalculateUV for BilinearPatch {
+    fn uv(&self, hit_point: Point3) -> Point2 {
+        let (u, v) = self.parametric_coordinates(hit_point);
+        match &self.uvs {
+            Some(uvs) => {
+                let x = bilerp(&uvs.map(|uv| uv.x), u, v);
+                let y = bilerp(&uvs.map(|uv| uv.y), u, v);
+                Point2::new(x, y)
+            }
+            None => Point2::new(u, v)
+        }
+    }
+}
+
+impl BoundingBox for BilinearPatch {
+    fn bounding_box(&self) -> AABB {
+        let [p00, p10, p01, p11] = self.vertices;
+        AABB::new(p00.min(p10).min(p01).min(p11), p00.max(p10).max(p01).max(p11))
+    }
+}
+
 pub struct TransformedShape<T> {
     shape: T,
     obj_to_world: Option<Transformation>,
@@ -138,19 +438,26 @@ impl<T> TransformedShape<T> {
     }
 }
 
+/// Ray in object space with normalized direction and scale of its parametric distance, object
+/// space distance t corresponds to world space t / scale along the original ray. Hit point is
+/// not transformed back, so returned t doesn't lose precision and is valid for any affine transformation.
+pub fn object_space_ray(ray: &Ray, world_to_object: Transformation) -> (Ray, f32) {
+    let direction = world_to_object * ray.direction;
+    let scale = direction.length();
+    let local_ray = Ray::new(world_to_object * ray.origin, direction * scale.recip())
+        .with_time(ray.time).with_medium(ray.medium);
+    (local_ray, scale)
+}
+
 impl<T: Intersect> Intersect for TransformedShape<T> {
     fn intersect(&self, ray: &Ray, tmin: f32) -> Option<f32> {
         match self.obj_to_world {
-            Some(transformation) => {   
-                let local_ray = *ray * transformation.inverse();
-                let result = self.shape.intersect(&local_ray, tmin);
-                if let Some(t) = result {
-                    let local_point = local_ray.point_at(t);
-                    let world_point = transformation * local_point;
-                    Some(world_point.distance(ray.origin))
-                } else {
-                    None
+            Some(transformation) => {
+                let (local_ray, scale) = object_space_ray(ray, transformation.inverse());
+                if scale == 0.0 {
+                    return None
                 }
+                self.shape.intersect(&local_ray, tmin * scale).map(|t| t / scale)
             }
             None => self.shape.intersect(ray, tmin)
         }
@@ -182,54 +489,117 @@ impl<T: CalculateNormal> CalculateNormal for TransformedShape<T> {
     }
 }
 
+impl<T: CalculateUV> CalculateUV for TransformedShape<T> {
+    fn uv(&self, hit_point: Point3) -> Point2 {
+        match self.obj_to_world {
+            Some(transformation) => self.shape.uv(hit_point * transformation.inverse()),
+            None => self.shape.uv(hit_point)
+        }
+    }
+}
+
 
 pub struct ShapeIntersection {
-    t: f32,
-    shape_id: usize,
+    pub(crate) t: f32,
+    pub(crate) shape_id: usize,
 }
 
 pub struct Primitives<T> {
     shapes: Vec<TransformedShape<T>>,
     material_ids: Vec<u32>,
-    linear_intersector: LinearIntersector,
+    // Only shapes that separate different media have medium interface
+    medium_interfaces: Vec<Option<MediumIds>>,
+    // Object space of shapes with transformation, see Geometry::object_to_world
+    spaces: Vec<Option<u32>>,
+    accelerator: Accelerator,
 }
 
-impl<T: Intersect + CalculateNormal + BoundingBox> Primitives<T> {
+impl<T: Intersect + CalculateNormal + CalculateUV + BoundingBox + Sync> Primitives<T> {
     pub fn new() -> Self {
         Self {
             shapes: Vec::new(),
             material_ids: Vec::new(),
-            linear_intersector: LinearIntersector::new(),
+            medium_interfaces: Vec::new(),
+            spaces: Vec::new(),
+            accelerator: Accelerator::new(AcceleratorType::default()),
         }
     }
 
     pub fn prepare_for_rendering(&mut self) {
         let calculate_bbox_fn = |idx: usize| self.shapes[idx].bounding_box();
-        self.linear_intersector.prepare_for_rendering(self.shapes.len(), &calculate_bbox_fn);
+        self.accelerator.prepare_for_rendering(self.shapes.len(), &calculate_bbox_fn);
+    }
+
+    pub fn set_accelerator_type(&mut self, typ: AcceleratorType) {
+        self.accelerator.set_accelerator_type(typ);
     }
 
-    pub fn add(&mut self, shape: T, object_to_world: Option<Transformation>, material_id: u32) {
+    pub fn add(&mut self, shape: T, object_to_world: Option<Transformation>, material_id: u32,
+               medium_interface: Option<MediumIds>, space: Option<u32>) {
         self.shapes.push(TransformedShape::new(shape, object_to_world));
         self.material_ids.push(material_id);
+        self.medium_interfaces.push(medium_interface);
+        self.spaces.push(space);
     }
 
     pub fn normal(&self, ray: &Ray, isect: &ShapeIntersection) -> Normal {
         self.shapes[isect.shape_id].normal(ray, ray.point_at(isect.t))
     }
 
+    pub fn uv(&self, ray: &Ray, isect: &ShapeIntersection) -> Point2 {
+        self.shapes[isect.shape_id].uv(ray.point_at(isect.t))
+    }
+
     pub fn material(&self, isect: &ShapeIntersection) -> u32 {
         self.material_ids[isect.shape_id]
     }
 
-    pub fn intersect(&self, ray: &Ray) -> Option<ShapeIntersection> {
-        let isect_fn = |idx: usize, ray: &Ray| self.shapes[idx].intersect(ray, 0.0);
-        self.linear_intersector.intersect(ray, &isect_fn)
+    pub fn medium_interface(&self, isect: &ShapeIntersection) -> Option<MediumIds> {
+        self.medium_interfaces[isect.shape_id]
+    }
+
+    pub fn space(&self, isect: &ShapeIntersection) -> Option<u32> {
+        self.spaces[isect.shape_id]
+    }
+
+    pub fn intersect(&self, ray: &Ray, tmin: f32) -> Option<ShapeIntersection> {
+        let isect_fn = |idx: usize, ray: &Ray| self.shapes[idx].intersect(ray, tmin);
+        self.accelerator.intersect(ray, &isect_fn)
+    }
+
+    pub fn bounds(&self) -> Option<AABB> {
+        self.accelerator.bounds()
+    }
+
+    /// Bytes used by shapes, their materials and media
+    pub fn shape_memory(&self) -> usize {
+        self.shapes.capacity() * std::mem::size_of::<TransformedShape<T>>() +
+        self.material_ids.capacity() * std::mem::size_of::<u32>() +
+        self.medium_interfaces.capacity() * std::mem::size_of::<Option<MediumIds>>() +
+        self.spaces.capacity() * std::mem::size_of::<Option<u32>>()
+    }
+
+    pub fn acceleration_memory(&self) -> usize {
+        self.accelerator.memory_usage()
+    }
+}
+
+impl Primitives<BilinearPatch> {
+    pub fn shading_normal(&self, ray: &Ray, isect: &ShapeIntersection) -> Option<Normal> {
+        self.shapes[isect.shape_id].shape.shading_normal(ray.point_at(isect.t))
     }
 }
 
 pub struct Mesh {
     vertices: Vec<Point3>,
     indices: Vec<u32>,
+    /// Per vertex normals, they are interpolated to shading normal
+    normals: Option<Vec<Normal>>,
+    /// Per vertex texture coordinates
+    uvs: Option<Vec<Point2>>,
+    alpha: f32,
+    /// Alpha evaluated at uv of hit point, it replaces constant alpha
+    alpha_texture: Option<Arc<dyn FloatTexture>>,
 }
 
 impl From<(Vec<Point3>, Vec<u32>)> for Mesh {
@@ -240,11 +610,45 @@ impl From<(Vec<Point3>, Vec<u32>)> for Mesh {
         Self {
             vertices: descriptor.0,
             indices: descriptor.1,
+            normals: None,
+            uvs: None,
+            alpha: 1.0,
+            alpha_texture: None,
         }
     }
 }
 
 impl Mesh {
+    pub fn memory_usage(&self) -> usize {
+        let normals = self.normals.as_ref().map_or(0, |normals| normals.capacity() * std::mem::size_of::<Normal>());
+        let uvs = self.uvs.as_ref().map_or(0, |uvs| uvs.capacity() * std::mem::size_of::<Point2>());
+        self.vertices.capacity() * std::mem::size_of::<Point3>() + self.indices.capacity() * std::mem::size_of::<u32>() + normals + uvs
+    }
+}
+
+impl Mesh {
+    /// Constant alpha cutout, hit is accepted with probability alpha.
+    pub fn with_alpha(self, alpha: f32) -> Self {
+        Self { alpha, ..self }
+    }
+
+    /// Alpha cutout given by texture, hit is rejected where alpha is zero and accepted with probability alpha elsewhere.
+    pub fn with_alpha_texture(self, alpha_texture: Option<Arc<dyn FloatTexture>>) -> Self {
+        Self { alpha_texture, ..self }
+    }
+
+    /// Per vertex normals, they are ignored if their count doesn't match number of vertices.
+    pub fn with_normals(self, normals: Option<Vec<Normal>>) -> Self {
+        let normals = normals.filter(|normals| normals.len() == self.vertices.len());
+        Self { normals, ..self }
+    }
+
+    /// Per vertex uvs, they are ignored if their count doesn't match number of vertices.
+    pub fn with_uvs(self, uvs: Option<Vec<Point2>>) -> Self {
+        let uvs = uvs.filter(|uvs| uvs.len() == self.vertices.len());
+        Self { uvs, ..self }
+    }
+
     pub fn bounding_box(&self, triangle_id: usize) -> AABB {
         let vertices = triangle_id * 3;
         let v0 = self.vertices[self.indices[vertices] as usize];
@@ -263,15 +667,78 @@ impl Mesh {
         Normal::from((v1 - v0).cross(v2 - v0).normalize())
     }
 
+    // Vertex indices and barycentric coordinates of hit point, None for degenerate triangle
+    fn barycentrics(&self, triangle_id: usize, hit_point: Point3) -> Option<([usize; 3], [f32; 3])> {
+        let vertices = triangle_id * 3;
+        let (i0, i1, i2) = (self.indices[vertices] as usize, self.indices[vertices + 1] as usize, self.indices[vertices + 2] as usize);
+        let (v0, v1, v2) = (self.vertices[i0], self.vertices[i1], self.vertices[i2]);
+        let (e0, e1, ep) = (v1 - v0, v2 - v0, hit_point - v0);
+        let (d00, d01, d11) = (e0 * e0, e0 * e1, e1 * e1);
+        let (d20, d21) = (ep * e0, ep * e1);
+        let denom = d00 * d11 - d01 * d01;
+        if denom == 0.0 {
+            return None
+        }
+        let b1 = (d11 * d20 - d01 * d21) / denom;
+        let b2 = (d00 * d21 - d01 * d20) / denom;
+        Some(([i0, i1, i2], [1.0 - b1 - b2, b1, b2]))
+    }
+
+    /// Vertex normals interpolated at hit point, None if mesh doesn't have normals.
+    pub fn shading_normal(&self, triangle_id: usize, hit_point: Point3) -> Option<Normal> {
+        let normals = self.normals.as_ref()?;
+        let ([i0, i1, i2], [b0, b1, b2]) = self.barycentrics(triangle_id, hit_point)?;
+        let normal = normals[i0] * b0 + normals[i1] * b1 + normals[i2] * b2;
+        if normal.length_sqr() == 0.0 {
+            return None
+        }
+        Some(normal.normalize())
+    }
+
+    /// Vertex uvs interpolated at hit point, mesh without uvs has uvs (0, 0), (1, 0) and (1, 1)
+    /// at vertices of every triangle same as in pbrt.
+    pub fn uv(&self, triangle_id: usize, hit_point: Point3) -> Point2 {
+        let ([i0, i1, i2], [b0, b1, b2]) = match self.barycentrics(triangle_id, hit_point) {
+            Some(barycentrics) => barycentrics,
+            None => return Point2::new(0.0, 0.0)
+        };
+        match &self.uvs {
+            Some(uvs) => Point2::new(uvs[i0].x * b0 + uvs[i1].x * b1 + uvs[i2].x * b2,
+                                     uvs[i0].y * b0 + uvs[i1].y * b1 + uvs[i2].y * b2),
+            None => Point2::new(b1 + b2, b2)
+        }
+    }
+
     pub fn intersect(&self, triangle_id: usize, ray: &Ray, tmin: f32) -> Option<f32> {
         let vertices = triangle_id * 3;
         let v0 = self.vertices[self.indices[vertices] as usize];
         let v1 = self.vertices[self.indices[vertices + 1] as usize];
         let v2 = self.vertices[self.indices[vertices + 2] as usize];
-        crate::isect::isect_ray_triangle(ray, v0, v1, v2, tmin)
+        let t = crate::isect::isect_ray_triangle(ray, v0, v1, v2, tmin)?;
+        let alpha = match &self.alpha_texture {
+            Some(texture) => {
+                // NOTE: object space of the hit isn't known here, alpha textures are evaluated in world space
+                let point = ray.point_at(t);
+                texture.evaluate(&TextureContext { uv: self.uv(triangle_id, point), point, object_point: point })
+            }
+            None => self.alpha
+        };
+        if alpha < 1.0 && (alpha <= 0.0 || alpha_test_value(ray) >= alpha) {
+            return None
+        }
+        Some(t)
     }
 }
 
+// NOTE: stochastic alpha test is deterministic for given ray, so all queries
+// along the same ray agree on whether surface is hit
+fn alpha_test_value(ray: &Ray) -> f32 {
+    let bits = |a: f32, b: f32| (a.to_bits() as u64) << 32 | b.to_bits() as u64;
+    let (o, d) = (ray.origin, ray.direction);
+    let hash = crate::hash!(bits(o.x, o.y), bits(o.z, d.x), bits(d.y, d.z));
+    (hash >> 40) as f32 / (1u64 << 24) as f32
+}
+
 pub struct Triangle {
     mesh_id: u32,
     triangle_id: u32,
@@ -281,9 +748,11 @@ pub struct Triangles {
     meshes: Vec<Mesh>,
     obj_to_world: Vec<Transformation>,
     material_ids: Vec<u32>,
+    medium_interfaces: Vec<Option<MediumIds>>,
+    spaces: Vec<Option<u32>>,
 
     triangles: Vec<Triangle>,
-    linear_intersector: LinearIntersector,
+    accelerator: Accelerator,
 }
 
 impl Triangles {
@@ -292,8 +761,10 @@ impl Triangles {
             meshes: Vec::new(),
             obj_to_world: Vec::new(),
             material_ids: Vec::new(),
+            medium_interfaces: Vec::new(),
+            spaces: Vec::new(),
             triangles: Vec::new(),
-            linear_intersector: LinearIntersector::new(),
+            accelerator: Accelerator::new(AcceleratorType::default()),
         }
     }
 
@@ -303,18 +774,31 @@ impl Triangles {
             let mesh = &self.meshes[triangle.mesh_id as usize];
             mesh.bounding_box(triangle.triangle_id as usize)
         };
-        self.linear_intersector.prepare_for_rendering(self.triangles.len(), &calculate_bbox_fn);
+        self.accelerator.prepare_for_rendering(self.triangles.len(), &calculate_bbox_fn);
+    }
+
+    pub fn set_accelerator_type(&mut self, typ: AcceleratorType) {
+        self.accelerator.set_accelerator_type(typ);
     }
 
-    pub fn add(&mut self, mut mesh: Mesh, object_to_world: Option<Transformation>, material_id: u32) {
+    /// Vertices are transformed to world space, space is id of object space of the mesh in geometry.
+    pub fn add(&mut self, mut mesh: Mesh, object_to_world: Option<Transformation>, material_id: u32,
+               medium_interface: Option<MediumIds>, space: Option<u32>) {
         let transformation = object_to_world.unwrap_or_default();
         self.obj_to_world.push(transformation);
         self.material_ids.push(material_id);
+        self.medium_interfaces.push(medium_interface);
+        self.spaces.push(space);
         let triangle_count = mesh.indices.len() / 3;
         if object_to_world.is_some() {
             for vertex in mesh.vertices.iter_mut() {
                 *vertex = *vertex * transformation;
             }
+            if let Some(normals) = mesh.normals.as_mut() {
+                for normal in normals.iter_mut() {
+                    *normal = (transformation * *normal).normalize();
+                }
+            }
         }
         let mesh_id = self.meshes.len() as u32;
         for i in 0..triangle_count {
@@ -331,18 +815,58 @@ impl Triangles {
         mesh.normal(triangle.triangle_id as usize)
     }
 
+    pub fn shading_normal(&self, ray: &Ray, isect: &ShapeIntersection) -> Option<Normal> {
+        let triangle = &self.triangles[isect.shape_id];
+        let mesh = &self.meshes[triangle.mesh_id as usize];
+        mesh.shading_normal(triangle.triangle_id as usize, ray.point_at(isect.t))
+    }
+
+    pub fn uv(&self, ray: &Ray, isect: &ShapeIntersection) -> Point2 {
+        let triangle = &self.triangles[isect.shape_id];
+        let mesh = &self.meshes[triangle.mesh_id as usize];
+        mesh.uv(triangle.triangle_id as usize, ray.point_at(isect.t))
+    }
+
     pub fn material(&self, isect: &ShapeIntersection) -> u32 {
         let triangle = &self.triangles[isect.shape_id];
         self.material_ids[triangle.mesh_id as usize]
     }
 
-    pub fn intersect(&self, ray: &Ray) -> Option<ShapeIntersection> {
+    pub fn medium_interface(&self, isect: &ShapeIntersection) -> Option<MediumIds> {
+        let triangle = &self.triangles[isect.shape_id];
+        self.medium_interfaces[triangle.mesh_id as usize]
+    }
+
+    pub fn space(&self, isect: &ShapeIntersection) -> Option<u32> {
+        let triangle = &self.triangles[isect.shape_id];
+        self.spaces[triangle.mesh_id as usize]
+    }
+
+    pub fn intersect(&self, ray: &Ray, tmin: f32) -> Option<ShapeIntersection> {
         let isect_fn = |idx: usize, ray: &Ray| {
             let triangle = &self.triangles[idx];
             let mesh = &self.meshes[triangle.mesh_id as usize];
-            mesh.intersect(triangle.triangle_id as usize, ray, 0.000001)
+            mesh.intersect(triangle.triangle_id as usize, ray, tmin)
         };
-        self.linear_intersector.intersect(ray, &isect_fn)
+        self.accelerator.intersect(ray, &isect_fn)
+    }
+
+    pub fn bounds(&self) -> Option<AABB> {
+        self.accelerator.bounds()
+    }
+
+    /// Bytes used by vertices and indices of meshes and per mesh data
+    pub fn vertex_memory(&self) -> usize {
+        let meshes: usize = self.meshes.iter().map(|mesh| mesh.memory_usage()).sum();
+        meshes + self.obj_to_world.capacity() * std::mem::size_of::<Transformation>() +
+        self.material_ids.capacity() * std::mem::size_of::<u32>() +
+        self.medium_interfaces.capacity() * std::mem::size_of::<Option<MediumIds>>() +
+        self.spaces.capacity() * std::mem::size_of::<Option<u32>>()
+    }
+
+    /// Bytes used by triangle references and their bounding boxes
+    pub fn acceleration_memory(&self) -> usize {
+        self.triangles.capacity() * std::mem::size_of::<Triangle>() + self.accelerator.memory_usage()
     }
 
 }
@@ -353,65 +877,429 @@ impl Default for Triangles {
     }
 }
 
+struct Instance {
+    prototype: u32,
+    obj_to_world: AnimatedTransformation,
+    space: u32,
+}
+
+/// Number of time steps whose bounds enclose moving instance
+const MOTION_BOUNDS_STEPS: usize = 16;
+
+/// Two level geometry. Prototypes (bottom level) have their own acceleration structure in object space,
+/// instances (top level) reference prototype and its optionally animated transformation. Inverses of
+/// transformations are stored with them, static instances don't invert matrices and moving ones
+/// interpolate inverses of key transformations. Bounds of animated instance enclose the prototype at
+/// evenly spaced times of its time interval.
+pub struct Instances {
+    prototypes: Vec<Triangles>,
+    instances: Vec<Instance>,
+    accelerator: Accelerator,
+}
+
+/// Hit of prototype geometry in object space of the instance.
+pub struct InstanceHit {
+    pub obj_to_world: Transformation,
+    pub local_ray: Ray,
+    pub local_isect: ShapeIntersection,
+    pub prototype: usize,
+    /// Ratio of object space and world space distances along the ray, see object_space_ray
+    pub scale: f32,
+}
+
+impl Instances {
+    pub fn new() -> Self {
+        Self { prototypes: Vec::new(), instances: Vec::new(), accelerator: Accelerator::new(AcceleratorType::default()) }
+    }
+
+    /// Prototypes are rebuilt with new accelerator, instances are built in prepare_for_rendering.
+    pub fn set_accelerator_type(&mut self, typ: AcceleratorType) {
+        self.accelerator.set_accelerator_type(typ);
+        for prototype in self.prototypes.iter_mut() {
+            prototype.set_accelerator_type(typ);
+            prototype.prepare_for_rendering();
+        }
+    }
+
+    /// Add prototype geometry and return its id
+    pub fn add_prototype(&mut self, mut prototype: Triangles) -> u32 {
+        prototype.set_accelerator_type(self.accelerator.accelerator_type());
+        prototype.prepare_for_rendering();
+        self.prototypes.push(prototype);
+        (self.prototypes.len() - 1) as u32
+    }
+
+    /// Space is id of object space of the instance in geometry, it is space of the prototype.
+    pub fn add_instance(&mut self, prototype: u32, obj_to_world: AnimatedTransformation, space: u32) {
+        self.instances.push(Instance { prototype, obj_to_world, space });
+    }
+
+    pub fn prepare_for_rendering(&mut self) {
+        let calculate_bbox_fn = |idx: usize| {
+            let instance = &self.instances[idx];
+            let bounds = match self.prototypes[instance.prototype as usize].bounds() {
+                Some(bounds) => bounds,
+                None => return AABB::new(Point3::new(0.0, 0.0, 0.0), Point3::new(0.0, 0.0, 0.0))
+            };
+            let start = bounds * instance.obj_to_world.start();
+            if instance.obj_to_world.is_animated() {
+                instance.obj_to_world.steps(MOTION_BOUNDS_STEPS).fold(start, |acc, step| acc.union(&(bounds * step)))
+            } else {
+                start
+            }
+        };
+        self.accelerator.prepare_for_rendering(self.instances.len(), &calculate_bbox_fn);
+    }
+
+    fn intersect_instance(&self, idx: usize, ray: &Ray, tmin: f32) -> Option<InstanceHit> {
+        let instance = &self.instances[idx];
+        let obj_to_world = instance.obj_to_world.at(ray.time);
+        let (local_ray, scale) = object_space_ray(ray, obj_to_world.inverse());
+        if scale == 0.0 {
+            return None
+        }
+        let prototype = instance.prototype as usize;
+        let local_isect = self.prototypes[prototype].intersect(&local_ray, tmin * scale)?;
+        Some(InstanceHit { obj_to_world, local_ray, local_isect, prototype, scale })
+    }
+
+    pub fn intersect(&self, ray: &Ray, tmin: f32) -> Option<ShapeIntersection> {
+        let isect_fn = |idx: usize, ray: &Ray| {
+            self.intersect_instance(idx, ray, tmin).map(|hit| hit.local_isect.t / hit.scale)
+        };
+        self.accelerator.intersect(ray, &isect_fn)
+    }
+
+    /// Hit in object space of the instance that was found by intersect
+    pub fn instance_hit(&self, ray: &Ray, isect: &ShapeIntersection, tmin: f32) -> Option<InstanceHit> {
+        self.intersect_instance(isect.shape_id, ray, tmin)
+    }
+
+    pub fn normal(&self, hit: &InstanceHit) -> Normal {
+        let normal = self.prototypes[hit.prototype].normal(&hit.local_ray, &hit.local_isect);
+        (hit.obj_to_world * normal).normalize()
+    }
+
+    pub fn shading_normal(&self, hit: &InstanceHit) -> Option<Normal> {
+        let normal = self.prototypes[hit.prototype].shading_normal(&hit.local_ray, &hit.local_isect)?;
+        Some((hit.obj_to_world * normal).normalize())
+    }
+
+    pub fn uv(&self, hit: &InstanceHit) -> Point2 {
+        self.prototypes[hit.prototype].uv(&hit.local_ray, &hit.local_isect)
+    }
+
+    pub fn material(&self, hit: &InstanceHit) -> u32 {
+        self.prototypes[hit.prototype].material(&hit.local_isect)
+    }
+
+    pub fn medium_interface(&self, hit: &InstanceHit) -> Option<MediumIds> {
+        self.prototypes[hit.prototype].medium_interface(&hit.local_isect)
+    }
+
+    pub fn space(&self, isect: &ShapeIntersection) -> u32 {
+        self.instances[isect.shape_id].space
+    }
+
+    pub fn bounds(&self) -> Option<AABB> {
+        self.accelerator.bounds()
+    }
+
+    /// Bytes used by vertices of prototypes and transformations of instances
+    pub fn vertex_memory(&self) -> usize {
+        let prototypes: usize = self.prototypes.iter().map(|prototype| prototype.vertex_memory()).sum();
+        prototypes + self.instances.capacity() * std::mem::size_of::<Instance>()
+    }
+
+    pub fn acceleration_memory(&self) -> usize {
+        let prototypes: usize = self.prototypes.iter().map(|prototype| prototype.acceleration_memory()).sum();
+        prototypes + self.accelerator.memory_usage()
+    }
+}
+
+impl Default for Instances {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 pub struct Geometry {
     spheres: Primitives<Sphere>,
     triangles: Triangles,
+    patches: Primitives<BilinearPatch>,
+    instances: Instances,
+    // Object to world transformations of primitives, primitives reference them by id
+    spaces: Vec<AnimatedTransformation>,
+    // Cached world to object transformations of spaces, None for animated spaces that are interpolated
+    // at time of the ray
+    world_to_object: Vec<Option<Transformation>>,
+    epsilon: EpsilonPolicy,
+    // Largest absolute coordinate of the geometry, tmin is derived from it
+    extent: f32,
+    tmin: f32,
 }
 
 pub enum GeometryIntersection {
     Sphere(ShapeIntersection),
     Triangle(ShapeIntersection),
+    BilinearPatch(ShapeIntersection),
+    Instance(ShapeIntersection),
     None
 }
 
+#[derive(Clone)]
 pub struct SurfaceInteraction {
     pub t: f32,
     pub hit_point: Point3,
     pub normal: Normal,
+    /// Interpolated normal on the same side as normal, equals normal for shapes without vertex normals
+    pub shading_normal: Normal,
     pub material_id: u32,
     pub back_side: bool,
+    /// Surface parameterization at hit point, zero for shapes without it
+    pub uv: Point2,
+    /// Media on both sides of the surface, None if surface doesn't change medium
+    pub medium_interface: Option<MediumIds>,
+    /// Id of object space of the hit primitive (see Geometry::object_to_world), None for primitives in world space
+    pub object_space: Option<u32>,
+    /// Time of the ray, object space of animated instances depends on it
+    pub time: f32,
+}
+
+/// Coordinate space where hit point and normal are expressed.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CoordinateSpace {
+    World,
+    /// Space of primitive before its object to world transformation, for instances it is space
+    /// of the prototype, so it moves together with the instance
+    Object,
+}
+
+impl CoordinateSpace {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "world" => Some(CoordinateSpace::World),
+            "object" => Some(CoordinateSpace::Object),
+            _ => None
+        }
+    }
+}
+
+impl SurfaceInteraction {
+    /// Object to world transformation of the hit primitive, None if primitive is in world space.
+    pub fn object_to_world(&self, geometry: &Geometry) -> Option<Transformation> {
+        self.object_space.map(|space| geometry.object_to_world(space, self.time))
+    }
+
+    /// World to object transformation of the hit primitive, None if primitive is in world space.
+    pub fn world_to_object(&self, geometry: &Geometry) -> Option<Transformation> {
+        self.object_space.map(|space| geometry.world_to_object(space, self.time))
+    }
+
+    pub fn object_point(&self, geometry: &Geometry) -> Point3 {
+        match self.world_to_object(geometry) {
+            Some(transformation) => self.hit_point * transformation,
+            None => self.hit_point
+        }
+    }
+
+    /// Geometric normal in object space, it is on the same side of the surface as normal.
+    pub fn object_normal(&self, geometry: &Geometry) -> Normal {
+        match self.world_to_object(geometry) {
+            Some(transformation) => (transformation * self.normal).normalize(),
+            None => self.normal
+        }
+    }
+
+    pub fn point_in(&self, space: CoordinateSpace, geometry: &Geometry) -> Point3 {
+        match space {
+            CoordinateSpace::World => self.hit_point,
+            CoordinateSpace::Object => self.object_point(geometry)
+        }
+    }
+
+    pub fn normal_in(&self, space: CoordinateSpace, geometry: &Geometry) -> Normal {
+        match space {
+            CoordinateSpace::World => self.normal,
+            CoordinateSpace::Object => self.object_normal(geometry)
+        }
+    }
+
+    /// Medium of ray leaving the surface in given direction, ray_medium is medium of incoming ray.
+    pub fn medium(&self, direction: Vec3, ray_medium: Option<u32>) -> Option<u32> {
+        match &self.medium_interface {
+            Some(mi) => {
+                // NOTE: normal is flipped toward incoming ray, back side hit means we came from inside
+                let outward = if self.back_side { -self.normal } else { self.normal };
+                if outward * direction > 0.0 { mi.outside } else { mi.inside }
+            }
+            None => ray_medium
+        }
+    }
 }
 
 impl Geometry {
     pub fn new() -> Self {
+        let epsilon = EpsilonPolicy::default();
         Self {
             spheres: Primitives::new(),
-            triangles: Triangles::new()
+            triangles: Triangles::new(),
+            patches: Primitives::new(),
+            instances: Instances::new(),
+            spaces: Vec::new(),
+            world_to_object: Vec::new(),
+            epsilon,
+            extent: 0.0,
+            tmin: epsilon.tmin(0.0)
+        }
+    }
+
+    /// Acceleration structures of all shapes are rebuilt when type changes.
+    pub fn set_accelerator_type(&mut self, typ: AcceleratorType) {
+        if self.spheres.accelerator.accelerator_type() == typ {
+            return
+        }
+        self.spheres.set_accelerator_type(typ);
+        self.triangles.set_accelerator_type(typ);
+        self.patches.set_accelerator_type(typ);
+        self.instances.set_accelerator_type(typ);
+        self.prepare_for_rendering();
+    }
+
+    pub fn set_epsilon_policy(&mut self, epsilon: EpsilonPolicy) {
+        self.epsilon = epsilon;
+        self.tmin = epsilon.tmin(self.extent);
+    }
+
+    pub fn epsilon_policy(&self) -> &EpsilonPolicy {
+        &self.epsilon
+    }
+
+    /// Minimal hit distance of rays, see EpsilonPolicy
+    pub fn tmin(&self) -> f32 {
+        self.tmin
+    }
+
+    // Id of object space with given transformation, shapes without transformation are in world space
+    fn add_space(&mut self, object_to_world: Option<Transformation>) -> Option<u32> {
+        let transformation = object_to_world.filter(|transformation| !transformation.is_identity())?;
+        Some(self.push_space(AnimatedTransformation::from(transformation)))
+    }
+
+    fn push_space(&mut self, object_to_world: AnimatedTransformation) -> u32 {
+        self.world_to_object.push((!object_to_world.is_animated()).then(|| object_to_world.start().inverse()));
+        self.spaces.push(object_to_world);
+        (self.spaces.len() - 1) as u32
+    }
+
+    /// Object to world transformation of object space at given time.
+    pub fn object_to_world(&self, space: u32, time: f32) -> Transformation {
+        self.spaces[space as usize].at(time)
+    }
+
+    /// World to object transformation of object space at given time.
+    pub fn world_to_object(&self, space: u32, time: f32) -> Transformation {
+        match self.world_to_object[space as usize] {
+            Some(transformation) => transformation,
+            None => self.spaces[space as usize].at(time).inverse()
         }
     }
 
-    pub fn add_sphere(&mut self, sphere: Sphere, object_to_world: Option<Transformation>, material_id: u32) {
-        self.spheres.add(sphere, object_to_world, material_id);
+    pub fn add_sphere(&mut self, sphere: Sphere, object_to_world: Option<Transformation>, material_id: u32,
+                      medium_interface: Option<MediumIds>) {
+        let space = self.add_space(object_to_world);
+        self.spheres.add(sphere, object_to_world, material_id, medium_interface, space);
+    }
+
+    pub fn add_mesh(&mut self, mesh: Mesh, object_to_world: Option<Transformation>, material_id: u32,
+                    medium_interface: Option<MediumIds>) {
+        let space = self.add_space(object_to_world);
+        self.triangles.add(mesh, object_to_world, material_id, medium_interface, space);
     }
 
-    pub fn add_mesh(&mut self, mesh: Mesh, object_to_world: Option<Transformation>, material_id: u32) {
-        self.triangles.add(mesh, object_to_world, material_id);
+    pub fn add_bilinear_patch(&mut self, patch: BilinearPatch, object_to_world: Option<Transformation>, material_id: u32,
+                              medium_interface: Option<MediumIds>) {
+        let space = self.add_space(object_to_world);
+        self.patches.add(patch, object_to_world, material_id, medium_interface, space);
+    }
+
+    /// Add prototype geometry of instances and return its id
+    pub fn add_prototype(&mut self, prototype: Triangles) -> u32 {
+        self.instances.add_prototype(prototype)
+    }
+
+    pub fn add_instance(&mut self, prototype: u32, obj_to_world: AnimatedTransformation) {
+        let space = self.push_space(obj_to_world);
+        self.instances.add_instance(prototype, obj_to_world, space);
     }
 
     pub fn prepare_for_rendering(&mut self) {
         self.spheres.prepare_for_rendering();
         self.triangles.prepare_for_rendering();
+        self.patches.prepare_for_rendering();
+        self.instances.prepare_for_rendering();
+        self.extent = match self.bounds() {
+            Some(bounds) => {
+                let (min, max) = (bounds.min(), bounds.max());
+                [min.x, min.y, min.z, max.x, max.y, max.z].iter().fold(0.0f32, |acc, v| acc.max(v.abs()))
+            }
+            None => 0.0
+        };
+        self.tmin = self.epsilon.tmin(self.extent);
+    }
+
+    /// Bytes used by shape data (mesh vertices and indices, spheres, bilinear patches)
+    pub fn vertex_memory(&self) -> usize {
+        self.spheres.shape_memory() + self.triangles.vertex_memory() + self.patches.shape_memory() +
+        self.instances.vertex_memory() + self.spaces.capacity() * std::mem::size_of::<AnimatedTransformation>() +
+        self.world_to_object.capacity() * std::mem::size_of::<Option<Transformation>>()
+    }
+
+    /// Bytes used by acceleration structures
+    pub fn acceleration_memory(&self) -> usize {
+        self.spheres.acceleration_memory() + self.triangles.acceleration_memory() + self.patches.acceleration_memory() +
+        self.instances.acceleration_memory()
+    }
+
+    /// Bounding box of all shapes, valid after prepare_for_rendering.
+    pub fn bounds(&self) -> Option<AABB> {
+        [self.spheres.bounds(), self.triangles.bounds(), self.patches.bounds(), self.instances.bounds()].into_iter().flatten()
+            .reduce(|a, b| a.union(&b))
     }
 
     pub fn intersect(&self, ray: &Ray) -> Option<SurfaceInteraction> {
-        let sphere_isect = self.spheres.intersect(ray);
-        let triangle_isect = self.triangles.intersect(ray);
+        let sphere_isect = self.spheres.intersect(ray, self.tmin);
+        let triangle_isect = self.triangles.intersect(ray, self.tmin);
         let sphere_isect = sphere_isect.unwrap_or(ShapeIntersection { t: -1.0, shape_id: 0 });
         let triangle_isect = triangle_isect.unwrap_or(ShapeIntersection { t: -1.0, shape_id: 0 });
 
-        let mut current_t = 1e38;
+        let mut current_t = INFINITE_DISTANCE;
         let mut type_id = -1;
         if sphere_isect.t > 0.0 && sphere_isect.t < current_t {
             current_t = sphere_isect.t;
             type_id = 0;
         }
         if triangle_isect.t > 0.0 && triangle_isect.t < current_t {
+            current_t = triangle_isect.t;
             type_id = 1;
         }
+        let patch_isect = self.patches.intersect(ray, self.tmin);
+        let patch_isect = patch_isect.unwrap_or(ShapeIntersection { t: -1.0, shape_id: 0 });
+        if patch_isect.t > 0.0 && patch_isect.t < current_t {
+            current_t = patch_isect.t;
+            type_id = 3;
+        }
+        let instance_isect = self.instances.intersect(ray, self.tmin);
+        let instance_isect = instance_isect.unwrap_or(ShapeIntersection { t: -1.0, shape_id: 0 });
+        if instance_isect.t > 0.0 && instance_isect.t < current_t {
+            type_id = 2;
+        }
 
         match type_id {
             0 => self.surface_interaction(ray, &GeometryIntersection::Sphere(sphere_isect)),
             1 => self.surface_interaction(ray, &GeometryIntersection::Triangle(triangle_isect)),
+            2 => self.surface_interaction(ray, &GeometryIntersection::Instance(instance_isect)),
+            3 => self.surface_interaction(ray, &GeometryIntersection::BilinearPatch(patch_isect)),
             _ => None
         }
     }
@@ -427,7 +1315,11 @@ impl Geometry {
                     back_side = true;
                 }
                 let material_id = self.spheres.material(shape_intersection);
-                Some(SurfaceInteraction { t: shape_intersection.t, hit_point, normal, material_id, back_side })
+                let uv = self.spheres.uv(ray, shape_intersection);
+                let medium_interface = self.spheres.medium_interface(shape_intersection);
+                let object_space = self.spheres.space(shape_intersection);
+                Some(SurfaceInteraction { t: shape_intersection.t, hit_point, normal, shading_normal: normal,
+                                          material_id, back_side, uv, medium_interface, object_space, time: ray.time })
             }
             GeometryIntersection::Triangle(shape_intersection) => {
                 let hit_point = ray.point_at(shape_intersection.t);
@@ -438,28 +1330,146 @@ impl Geometry {
                     back_side = true;
                 }
                 let material_id = self.triangles.material(shape_intersection);
-                Some(SurfaceInteraction { t: shape_intersection.t, hit_point, normal, material_id, back_side })
+                let shading_normal = match self.triangles.shading_normal(ray, shape_intersection) {
+                    Some(ns) if ns * normal < 0.0 => -ns,
+                    Some(ns) => ns,
+                    None => normal
+                };
+                let uv = self.triangles.uv(ray, shape_intersection);
+                let medium_interface = self.triangles.medium_interface(shape_intersection);
+                let object_space = self.triangles.space(shape_intersection);
+                Some(SurfaceInteraction { t: shape_intersection.t, hit_point, normal, shading_normal,
+                                          material_id, back_side, uv, medium_interface, object_space, time: ray.time })
+            }
+            GeometryIntersection::BilinearPatch(shape_intersection) => {
+                let hit_point = ray.point_at(shape_intersection.t);
+                let mut normal = self.patches.normal(ray, shape_intersection);
+                let mut back_side = false;
+                if (-ray.direction) * normal < 0.0 {
+                    normal = -normal;
+                    back_side = true;
+                }
+                let material_id = self.patches.material(shape_intersection);
+                let shading_normal = match self.patches.shading_normal(ray, shape_intersection) {
+                    Some(ns) if ns * normal < 0.0 => -ns,
+                    Some(ns) => ns,
+                    None => normal
+                };
+                let uv = self.patches.uv(ray, shape_intersection);
+                let medium_interface = self.patches.medium_interface(shape_intersection);
+                let object_space = self.patches.space(shape_intersection);
+                Some(SurfaceInteraction { t: shape_intersection.t, hit_point, normal, shading_normal,
+                                          material_id, back_side, uv, medium_interface, object_space, time: ray.time })
+            }
+            GeometryIntersection::Instance(shape_intersection) => {
+                let hit = self.instances.instance_hit(ray, shape_intersection, self.tmin)?;
+                let hit_point = ray.point_at(shape_intersection.t);
+                let mut normal = self.instances.normal(&hit);
+                let mut back_side = false;
+                if (-ray.direction) * normal < 0.0 {
+                    normal = -normal;
+                    back_side = true;
+                }
+                let shading_normal = match self.instances.shading_normal(&hit) {
+                    Some(ns) if ns * normal < 0.0 => -ns,
+                    Some(ns) => ns,
+                    None => normal
+                };
+                let material_id = self.instances.material(&hit);
+                let uv = self.instances.uv(&hit);
+                let medium_interface = self.instances.medium_interface(&hit);
+                let object_space = Some(self.instances.space(shape_intersection));
+                Some(SurfaceInteraction { t: shape_intersection.t, hit_point, normal, shading_normal,
+                                          material_id, back_side, uv, medium_interface, object_space, time: ray.time })
             }
             GeometryIntersection::None => None
         }
     }
 
-    pub fn from_shape_descriptions(descs: &mut [ShapeDescription], mat_names: &HashMap<String, usize>) -> Self {
+    pub fn from_shape_descriptions(descs: &mut [ShapeDescription], prototypes: &mut [PrototypeDescription],
+                                   mat_names: &HashMap<String, usize>,
+                                   medium_names: &HashMap<String, u32>,
+                                   textures: &HashMap<String, Arc<Texture>>) -> Result<Self, String> {
+        let material_id = |index: usize, name: &str| -> Result<u32, String> {
+            match mat_names.get(name) {
+                Some(id) => Ok(*id as u32),
+                None => Err(format!("Shape {}: material {} doesn't exist!", index, name))
+            }
+        };
+        let medium_id = |index: usize, name: &Option<String>| -> Result<Option<u32>, String> {
+            match name {
+                Some(name) => match medium_names.get(name) {
+                    Some(id) => Ok(Some(*id)),
+                    None => Err(format!("Shape {}: medium {} doesn't exist!", index, name))
+                },
+                None => Ok(None)
+            }
+        };
+        let medium_ids = |index: usize, mi: &MediumInterface| -> Result<Option<MediumIds>, String> {
+            if !mi.is_medium_transition() {
+                return Ok(None)
+            }
+            Ok(Some(MediumIds { inside: medium_id(index, &mi.inside)?, outside: medium_id(index, &mi.outside)? }))
+        };
+        let mesh = |desc: &mut MeshDescription| -> Result<Mesh, String> {
+            let alpha_texture = match &desc.alpha_texture {
+                Some(name) => match textures.get(name) {
+                    Some(texture) => Some(texture.clone() as Arc<dyn FloatTexture>),
+                    None => return Err(format!("Mesh: alpha texture {} doesn't exist!", name))
+                },
+                None => None
+            };
+            let vertices = desc.vertices.take().unwrap_or_default();
+            let indices = desc.indices.take().unwrap_or_default();
+            Ok(Mesh::from((vertices, indices)).with_alpha(desc.alpha).with_alpha_texture(alpha_texture)
+                .with_normals(desc.normals.take()).with_uvs(desc.uvs.take()))
+        };
         let mut geometry = Self::new();
-        for desc in descs.iter_mut() {
+        let mut prototype_ids = HashMap::new();
+        for prototype in prototypes.iter_mut() {
+            let mut triangles = Triangles::new();
+            for desc in prototype.meshes.iter_mut() {
+                let material_id = match mat_names.get(&desc.material) {
+                    Some(id) => *id as u32,
+                    None => return Err(format!("Object {}: material {} doesn't exist!", prototype.name, desc.material))
+                };
+                let medium_interface = medium_ids(0, &desc.medium_interface)?;
+                triangles.add(mesh(desc)?, desc.transform, material_id, medium_interface, None);
+            }
+            prototype_ids.insert(prototype.name.clone(), geometry.add_prototype(triangles));
+        }
+        for (index, desc) in descs.iter_mut().enumerate() {
             match desc {
                 ShapeDescription::Sphere(desc) => {
-                    geometry.add_sphere(Sphere::new(desc.position, desc.radius), desc.transform, mat_names[&desc.material] as u32);
+                    let sphere = Sphere::partial(desc.position, desc.radius, desc.zmin, desc.zmax, desc.phimax);
+                    geometry.add_sphere(sphere, desc.transform, material_id(index, &desc.material)?,
+                                        medium_ids(index, &desc.medium_interface)?);
                 }
                 ShapeDescription::Mesh(desc) => {
-                    let vertices = desc.vertices.take().unwrap_or(Vec::new());
-                    let indices = desc.indices.take().unwrap_or(Vec::new());
-                    geometry.add_mesh(Mesh::from((vertices, indices)), desc.transform, mat_names[&desc.material] as u32);
+                    let mesh = mesh(desc)?;
+                    geometry.add_mesh(mesh, desc.transform, material_id(index, &desc.material)?,
+                                      medium_ids(index, &desc.medium_interface)?);
+                }
+                ShapeDescription::BilinearMesh(desc) => {
+                    let material_id = material_id(index, &desc.material)?;
+                    let medium_interface = medium_ids(index, &desc.medium_interface)?;
+                    for patch in desc.patches().map_err(|err| format!("Shape {}: {}", index, err))? {
+                        geometry.add_bilinear_patch(patch, None, material_id, medium_interface);
+                    }
+                }
+                ShapeDescription::Instance(desc) => {
+                    let prototype = match prototype_ids.get(&desc.prototype) {
+                        Some(id) => *id,
+                        None => return Err(format!("Shape {}: object {} doesn't exist!", index, desc.prototype))
+                    };
+                    let end_transform = desc.end_transform.unwrap_or(desc.transform);
+                    let obj_to_world = AnimatedTransformation::new(desc.transform, end_transform, desc.start_time, desc.end_time);
+                    geometry.add_instance(prototype, obj_to_world);
                 }
             }
         }
         geometry.prepare_for_rendering();
-        geometry
+        Ok(geometry)
     }
 }
 
@@ -469,11 +1479,18 @@ impl Default for Geometry {
     }
 }
 
+#[derive(Debug)]
 pub struct SphereDescription {
     pub position: Point3,
     pub radius: f32,
+    /// Clipping of sphere along z axis, relative to position and clamped to [-radius, radius]
+    pub zmin: f32,
+    pub zmax: f32,
+    /// Maximum phi angle in degrees
+    pub phimax: f32,
     pub material: String,
-    pub transform: Option<Transformation>
+    pub transform: Option<Transformation>,
+    pub medium_interface: MediumInterface
 }
 
 impl Default for SphereDescription {
@@ -481,22 +1498,119 @@ impl Default for SphereDescription {
         Self {
             position: Point3::new(0.0, 0.0, 0.0),
             radius: 1.0,
+            zmin: f32::MIN,
+            zmax: f32::MAX,
+            phimax: 360.0,
             material: String::new(),
-            transform: None
+            transform: None,
+            medium_interface: MediumInterface::default()
+        }
+    }
+}
+
+impl SphereDescription {
+    // NOTE: transformation is assumed to be similarity so area is scaled by det^(2/3)
+    pub fn area(&self) -> f32 {
+        let zmin = self.zmin.clamp(-self.radius, self.radius);
+        let zmax = self.zmax.clamp(-self.radius, self.radius);
+        let phimax = self.phimax.clamp(0.0, 360.0).to_radians();
+        let area = phimax * self.radius * (zmax - zmin).abs();
+        match &self.transform {
+            Some(transform) => area * transform.determinant().abs().powf(2.0 / 3.0),
+            None => area
         }
     }
 }
 
+#[derive(Debug)]
 pub struct MeshDescription {
     pub vertices: Option<Vec<Point3>>,
     pub indices: Option<Vec<u32>>,
     pub normals: Option<Vec<Normal>>,
     pub uvs: Option<Vec<Point2>>,
+    /// Per triangle integer values (pbrt faceIndices), they follow triangles when mesh is cleaned up
+    pub face_indices: Option<Vec<u32>>,
+    /// Constant alpha cutout, zero makes mesh invisible
+    pub alpha: f32,
+    /// Name of float texture of alpha cutout, it replaces constant alpha
+    pub alpha_texture: Option<String>,
     pub material: String,
-    pub transform: Option<Transformation>
+    pub transform: Option<Transformation>,
+    pub medium_interface: MediumInterface
 }
 
 impl Default for MeshDescription {
+    fn default() -> Self {
+        Self {
+            vertices: None,
+            indices: None,
+            normals: None,
+            uvs: None,
+            face_indices: None,
+            alpha: 1.0,
+            alpha_texture: None,
+            material: String::new(),
+            transform: None,
+            medium_interface: MediumInterface::default()
+        }
+    }
+}
+
+// Per vertex normals and uvs must have value for every vertex, error names attribute with wrong count
+fn check_vertex_attributes(vertices: &Option<Vec<Point3>>, normals: &Option<Vec<Normal>>,
+                           uvs: &Option<Vec<Point2>>) -> Result<(), String> {
+    let nvertices = match vertices {
+        Some(vertices) => vertices.len(),
+        None => return Ok(())
+    };
+    let counts = [("normals", normals.as_ref().map(|normals| normals.len())), ("uvs", uvs.as_ref().map(|uvs| uvs.len()))];
+    for (name, count) in counts {
+        match count {
+            Some(count) if count != nvertices => return Err(format!("{} - {} values expected, {} found!", name, nvertices, count)),
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+impl MeshDescription {
+    pub fn check_vertex_attributes(&self) -> Result<(), String> {
+        check_vertex_attributes(&self.vertices, &self.normals, &self.uvs)
+    }
+
+    pub fn area(&self) -> f32 {
+        let (vertices, indices) = match (&self.vertices, &self.indices) {
+            (Some(vertices), Some(indices)) => (vertices, indices),
+            _ => return 0.0
+        };
+        let vertex = |index: u32| -> Point3 {
+            match &self.transform {
+                Some(transform) => vertices[index as usize] * *transform,
+                None => vertices[index as usize]
+            }
+        };
+        let mut area = 0.0;
+        for triangle in indices.chunks_exact(3) {
+            let (p0, p1, p2) = (vertex(triangle[0]), vertex(triangle[1]), vertex(triangle[2]));
+            area += 0.5 * (p1 - p0).cross(p2 - p0).length();
+        }
+        area
+    }
+}
+
+/// Mesh of bilinear patches, every four indices are vertices p00, p10, p01 and p11 of one patch.
+#[derive(Debug)]
+pub struct BilinearMeshDescription {
+    pub vertices: Option<Vec<Point3>>,
+    pub indices: Option<Vec<u32>>,
+    pub normals: Option<Vec<Normal>>,
+    pub uvs: Option<Vec<Point2>>,
+    pub material: String,
+    pub transform: Option<Transformation>,
+    pub medium_interface: MediumInterface
+}
+
+impl Default for BilinearMeshDescription {
     fn default() -> Self {
         Self {
             vertices: None,
@@ -504,14 +1618,101 @@ impl Default for MeshDescription {
             normals: None,
             uvs: None,
             material: String::new(),
-            transform: None
+            transform: None,
+            medium_interface: MediumInterface::default()
+        }
+    }
+}
+
+impl BilinearMeshDescription {
+    pub fn check_vertex_attributes(&self) -> Result<(), String> {
+        check_vertex_attributes(&self.vertices, &self.normals, &self.uvs)
+    }
+
+    fn vertices(&self) -> Vec<Point3> {
+        let vertices = self.vertices.clone().unwrap_or_default();
+        match &self.transform {
+            Some(transform) => vertices.into_iter().map(|vertex| vertex * *transform).collect(),
+            None => vertices
+        }
+    }
+
+    // NOTE: patch is split to two triangles, so area of non planar patch is underestimated
+    pub fn area(&self) -> f32 {
+        let (vertices, indices) = (self.vertices(), self.indices.as_deref().unwrap_or_default());
+        let mut area = 0.0;
+        for patch in indices.chunks_exact(4) {
+            let p = |i: usize| vertices.get(patch[i] as usize).copied();
+            if let (Some(p00), Some(p10), Some(p01), Some(p11)) = (p(0), p(1), p(2), p(3)) {
+                area += 0.5 * ((p10 - p00).cross(p11 - p00).length() + (p11 - p00).cross(p01 - p00).length());
+            }
+        }
+        area
+    }
+
+    /// Patches in world space, vertex normals and uvs are ignored if their count doesn't match number of vertices.
+    pub fn patches(&self) -> Result<Vec<BilinearPatch>, String> {
+        let vertices = self.vertices();
+        let indices = self.indices.as_deref().unwrap_or_default();
+        if !indices.len().is_multiple_of(4) {
+            return Err(format!("Bilinear mesh - number of indices {} is not multiple of 4!", indices.len()))
+        }
+        if let Some(index) = indices.iter().find(|index| **index as usize >= vertices.len()) {
+            return Err(format!("Bilinear mesh - index {} is out of range, mesh has {} vertices!", index, vertices.len()))
+        }
+        let normals = self.normals.as_ref().filter(|normals| normals.len() == vertices.len()).map(|normals| {
+            match &self.transform {
+                Some(transform) => normals.iter().map(|normal| (*transform * *normal).normalize()).collect(),
+                None => normals.clone()
+            }
+        });
+        let uvs = self.uvs.as_ref().filter(|uvs| uvs.len() == vertices.len());
+        let patches = indices.chunks_exact(4).map(|patch| {
+            let [i0, i1, i2, i3] = [0, 1, 2, 3].map(|i| patch[i] as usize);
+            BilinearPatch::new(vertices[i0], vertices[i1], vertices[i2], vertices[i3])
+                .with_normals(normals.as_ref().map(|normals: &Vec<Normal>| [normals[i0], normals[i1], normals[i2], normals[i3]]))
+                .with_uvs(uvs.map(|uvs| [uvs[i0], uvs[i1], uvs[i2], uvs[i3]]))
+        }).collect();
+        Ok(patches)
+    }
+}
+
+/// Named group of meshes that is rendered only through its instances.
+#[derive(Debug)]
+pub struct PrototypeDescription {
+    pub name: String,
+    pub meshes: Vec<MeshDescription>
+}
+
+/// Instance of prototype, end_transform makes instance move from transform at start_time
+/// to end_transform at end_time.
+#[derive(Debug)]
+pub struct InstanceDescription {
+    pub prototype: String,
+    pub transform: Transformation,
+    pub end_transform: Option<Transformation>,
+    pub start_time: f32,
+    pub end_time: f32
+}
+
+impl Default for InstanceDescription {
+    fn default() -> Self {
+        Self {
+            prototype: String::new(),
+            transform: Transformation::identity(),
+            end_transform: None,
+            start_time: 0.0,
+            end_time: 1.0
         }
     }
 }
 
+#[derive(Debug)]
 pub enum ShapeDescription {
     Sphere(SphereDescription),
-    Mesh(MeshDescription)
+    Mesh(MeshDescription),
+    BilinearMesh(BilinearMeshDescription),
+    Instance(InstanceDescription)
 }
 
 
@@ -519,6 +1720,53 @@ pub enum ShapeDescription {
 mod tests {
     use super::*;
     use crate::vec::Point3;
+    use crate::matrix::Matrix4x4;
+
+    #[test]
+    fn shape_description_area() {
+        let pi = std::f32::consts::PI;
+        let desc = SphereDescription { radius: 2.0, transform: Some(Transformation::scale(3.0, 3.0, 3.0)), ..Default::default() };
+        assert!((desc.area() - 4.0 * pi * 36.0).abs() < 1e-2);
+
+        let desc = MeshDescription {
+            vertices: Some(vec![Point3::new(0.0, 0.0, 0.0), Point3::new(1.0, 0.0, 0.0),
+                                Point3::new(1.0, 1.0, 0.0), Point3::new(0.0, 1.0, 0.0)]),
+            indices: Some(vec![0, 1, 2, 0, 2, 3]),
+            transform: Some(Transformation::scale(2.0, 1.0, 1.0)),
+            ..Default::default()
+        };
+        assert!((desc.area() - 2.0).abs() < 1e-5);
+    }
+
+    #[test]
+    fn transformed_sphere_parametric_t() {
+        let unit = || Sphere::new(Point3::new(0.0, 0.0, 0.0), 1.0);
+        // ellipsoid with semi axis 3 along z
+        let ellipsoid = TransformedShape::new(unit(), Some(Transformation::scale(1.0, 1.0, 3.0)));
+        let ray = Ray::new(Point3::new(0.0, 0.0, -10.0), Vec3::new(0.0, 0.0, 1.0));
+        assert!((ellipsoid.intersect(&ray, 0.0).unwrap() - 7.0).abs() < 1e-5);
+        // t is parameter of the ray, not distance
+        let ray = Ray::new(Point3::new(0.0, 0.0, -10.0), Vec3::new(0.0, 0.0, 2.0));
+        assert!((ellipsoid.intersect(&ray, 0.0).unwrap() - 3.5).abs() < 1e-5);
+
+        let shear = Transformation::try_from(Matrix4x4::new([[1.0, 0.0, 1.0, 0.0], [0.0, 1.0, 0.0, 0.0],
+                                                              [0.0, 0.0, 1.0, 0.0], [0.0, 0.0, 0.0, 1.0]])).unwrap();
+        let sheared = TransformedShape::new(unit(), Some(Transformation::translate(&Vec3::new(0.0, 2.0, 0.0)) * shear));
+        for direction in [Vec3::new(2.0, 3.0, -0.2), Vec3::new(2.2, 3.0, 0.0), Vec3::new(1.8, 3.2, -0.1)] {
+            let ray = Ray::new(Point3::new(-2.0, -1.0, 0.2), direction.normalize());
+            let t = sheared.intersect(&ray, 0.0).unwrap();
+            let local = ray.point_at(t) * (Transformation::translate(&Vec3::new(0.0, 2.0, 0.0)) * shear).inverse();
+            assert!((local.distance(Point3::new(0.0, 0.0, 0.0)) - 1.0).abs() < 1e-5);
+            let normal = sheared.normal(&ray, ray.point_at(t));
+            assert!(normal * ray.direction < 0.0);
+        }
+
+        // tmin is distance in world space, it isn't scaled by transformation
+        let large = TransformedShape::new(unit(), Some(Transformation::scale(100.0, 100.0, 100.0)));
+        let ray = Ray::new(Point3::new(0.0, 0.0, 0.0), Vec3::new(1.0, 0.0, 0.0));
+        assert!((large.intersect(&ray, 50.0).unwrap() - 100.0).abs() < 1e-3);
+        assert!(large.intersect(&ray, 150.0).is_none());
+    }
 
     #[test]
     fn test_sphere_creation() {
@@ -536,8 +1784,8 @@ mod tests {
         let sphere1 = Sphere::new(Point3::new(0.0, 0.0, 0.0), 1.0);
         let sphere2 = Sphere::new(Point3::new(1.0, 1.0, 1.0), 2.0);
 
-        primitives.add(sphere1, None, 0);
-        primitives.add(sphere2, None, 0);
+        primitives.add(sphere1, None, 0, None, None);
+        primitives.add(sphere2, None, 0, None, None);
 
         assert_eq!(primitives.shapes.len(), 2);
         assert_eq!(primitives.shapes[0].shape.center, Point3::new(0.0, 0.0, 0.0));
@@ -545,5 +1793,303 @@ mod tests {
         assert_eq!(primitives.shapes[1].shape.center, Point3::new(1.0, 1.0, 1.0));
         assert_eq!(primitives.shapes[1].shape.radius, 2.0);
     }
-}
 
+    #[test]
+    fn geometry_tmin_scales_with_extent() {
+        let mut geometry = Geometry::new();
+        geometry.add_sphere(Sphere::new(Point3::new(0.0, 0.0, 0.0), 1.0), None, 0, None);
+        geometry.prepare_for_rendering();
+        assert_eq!(geometry.tmin(), EpsilonPolicy::default().absolute_tmin);
+
+        geometry.add_sphere(Sphere::new(Point3::new(0.0, -1e6, 0.0), 1.0), None, 0, None);
+        geometry.prepare_for_rendering();
+        assert!((geometry.tmin() - (1e6 + 1.0) * EpsilonPolicy::default().relative_tmin).abs() < 1e-4);
+
+        geometry.set_epsilon_policy(EpsilonPolicy { relative_tmin: 0.0, absolute_tmin: 0.5, ..Default::default() });
+        assert_eq!(geometry.tmin(), 0.5);
+    }
+
+    #[test]
+    fn mesh_alpha_cutout() {
+        let quad = || Mesh::from((vec![Point3::new(-1.0, -1.0, 0.0), Point3::new(1.0, -1.0, 0.0), Point3::new(1.0, 1.0, 0.0),
+                                       Point3::new(-1.0, 1.0, 0.0)], vec![0, 1, 2, 0, 2, 3]));
+        let (opaque, invisible, half) = (quad(), quad().with_alpha(0.0), quad().with_alpha(0.5));
+        let mut hits = 0;
+        for i in 0..1000 {
+            let ray = Ray::new(Point3::new(-0.5 + i as f32 * 1e-3, 0.5, -1.0), Vec3::new(0.0, 0.0, 1.0));
+            assert!(opaque.intersect(1, &ray, 0.0).is_some());
+            assert!(invisible.intersect(1, &ray, 0.0).is_none());
+            // same ray always gives the same answer
+            assert_eq!(half.intersect(1, &ray, 0.0).is_some(), half.intersect(1, &ray, 0.0).is_some());
+            hits += half.intersect(1, &ray, 0.0).is_some() as usize;
+        }
+        assert!(hits > 400 && hits < 600);
+
+        // left half of the quad (u < 0.5) is cut out by texture
+        let one = Arc::new(Texture::Constant(crate::color::RGB::new(1.0, 1.0, 1.0)));
+        let zero = Arc::new(Texture::Constant(crate::color::RGB::zero()));
+        let mask: Arc<dyn FloatTexture> = Arc::new(Texture::Checkerboard { tex1: zero, tex2: one, uscale: 2.0, vscale: 1.0 });
+        let quad = quad().with_uvs(Some(vec![Point2::new(0.0, 0.0), Point2::new(1.0, 0.0), Point2::new(1.0, 1.0), Point2::new(0.0, 1.0)]))
+            .with_alpha_texture(Some(mask));
+        let ray = |x: f32| Ray::new(Point3::new(x, 0.5, -1.0), Vec3::new(0.0, 0.0, 1.0));
+        assert!(quad.intersect(1, &ray(-0.5), 0.0).is_none());
+        assert!(quad.intersect(1, &ray(0.5), 0.0).is_some());
+    }
+
+    #[test]
+    fn partial_sphere() {
+        // Upper hemisphere, ray from below passes through open bottom and hits inside of the dome
+        let dome = Sphere::partial(Point3::new(0.0, 0.0, 0.0), 1.0, 0.0, 2.0, 360.0);
+        let ray = Ray::new(Point3::new(0.0, 0.0, -5.0), Vec3::new(0.0, 0.0, 1.0));
+        let t = dome.intersect(&ray, 0.0).unwrap();
+        assert!((t - 6.0).abs() < 1e-5);
+        let ray = Ray::new(Point3::new(0.0, 0.0, 5.0), Vec3::new(0.0, 0.0, -1.0));
+        assert!((dome.intersect(&ray, 0.0).unwrap() - 4.0).abs() < 1e-5);
+        let bbox = dome.bounding_box();
+        assert_eq!((bbox.min().z, bbox.max().z), (0.0, 1.0));
+
+        // Quarter of sphere, only phi in [0, 90] degrees
+        let quarter = Sphere::partial(Point3::new(0.0, 0.0, 0.0), 1.0, -1.0, 1.0, 90.0);
+        let ray = Ray::new(Point3::new(5.0, 0.5, 0.0), Vec3::new(-1.0, 0.0, 0.0));
+        let t = quarter.intersect(&ray, 0.0).unwrap();
+        assert!((ray.point_at(t).x - 0.75f32.sqrt()).abs() < 1e-5);
+        let ray = Ray::new(Point3::new(5.0, -0.5, 0.0), Vec3::new(-1.0, 0.0, 0.0));
+        assert!(quarter.intersect(&ray, 0.0).is_none());
+
+        let pi = std::f32::consts::PI;
+        let desc = SphereDescription { radius: 2.0, zmin: 0.0, phimax: 180.0, ..Default::default() };
+        assert!((desc.area() - pi * 2.0 * 2.0).abs() < 1e-4);
+    }
+
+    #[test]
+    fn sphere_uv() {
+        let sphere = Sphere::new(Point3::new(1.0, 0.0, 0.0), 2.0);
+        let uv = sphere.uv(Point3::new(1.0, 2.0, 0.0));
+        assert!((uv.x - 0.25).abs() < 1e-5 && (uv.y - 0.5).abs() < 1e-5);
+        let uv = sphere.uv(Point3::new(1.0, 0.0, 2.0));
+        assert!((uv.y - 1.0).abs() < 1e-5);
+        let uv = sphere.uv(Point3::new(1.0, 0.0, -2.0));
+        assert!(uv.y.abs() < 1e-5);
+
+        let dome = TransformedShape::new(Sphere::partial(Point3::new(0.0, 0.0, 0.0), 1.0, 0.0, 1.0, 180.0),
+                                         Some(Transformation::translate(&Vec3::new(0.0, 0.0, 5.0))));
+        let uv = dome.uv(Point3::new(-1.0, 0.0, 5.0));
+        assert!((uv.x - 1.0).abs() < 1e-5 && uv.y.abs() < 1e-5);
+    }
+
+    #[test]
+    fn mesh_shading_normal() {
+        let vertices = vec![Point3::new(0.0, 0.0, 0.0), Point3::new(1.0, 0.0, 0.0), Point3::new(0.0, 1.0, 0.0)];
+        let normals = vec![Normal::new(0.0, 0.0, 1.0), Normal::new(1.0, 0.0, 1.0).normalize(), Normal::new(0.0, 0.0, 1.0)];
+        let mesh = Mesh::from((vertices.clone(), vec![0, 1, 2])).with_normals(Some(normals));
+        let ns = mesh.shading_normal(0, Point3::new(0.0, 0.0, 0.0)).unwrap();
+        assert!((ns.z - 1.0).abs() < 1e-5);
+        let ns = mesh.shading_normal(0, Point3::new(1.0, 0.0, 0.0)).unwrap();
+        assert!((ns.x - ns.z).abs() < 1e-5);
+        let ns = mesh.shading_normal(0, Point3::new(0.5, 0.0, 0.0)).unwrap();
+        assert!(ns.x > 0.0 && ns.x < ns.z);
+        // normals that don't match vertices are ignored
+        let mesh = Mesh::from((vertices, vec![0, 1, 2])).with_normals(Some(vec![Normal::new(0.0, 0.0, 1.0)]));
+        assert!(mesh.shading_normal(0, Point3::new(0.2, 0.2, 0.0)).is_none());
+
+        // shading normal is flipped to the side of geometric normal
+        let mut geometry = Geometry::new();
+        let vertices = vec![Point3::new(-1.0, -1.0, 0.0), Point3::new(1.0, -1.0, 0.0), Point3::new(0.0, 1.0, 0.0)];
+        let normals = vec![Normal::new(0.2, 0.0, 1.0).normalize(); 3];
+        geometry.add_mesh(Mesh::from((vertices, vec![0, 1, 2])).with_normals(Some(normals)), None, 0, None);
+        geometry.prepare_for_rendering();
+        let ray = Ray::new(Point3::new(0.0, 0.0, -2.0), Vec3::new(0.0, 0.0, 1.0));
+        let si = geometry.intersect(&ray).unwrap();
+        assert!(si.normal.z < 0.0 && si.shading_normal.z < 0.0);
+        assert!(si.shading_normal.x < 0.0);
+    }
+
+    #[test]
+    fn mesh_uv() {
+        let vertices = vec![Point3::new(0.0, 0.0, 0.0), Point3::new(2.0, 0.0, 0.0), Point3::new(0.0, 2.0, 0.0)];
+        let uvs = vec![Point2::new(0.0, 0.0), Point2::new(4.0, 0.0), Point2::new(0.0, 1.0)];
+        let mesh = Mesh::from((vertices.clone(), vec![0, 1, 2])).with_uvs(Some(uvs));
+        let uv = mesh.uv(0, Point3::new(1.0, 0.5, 0.0));
+        assert!((uv.x - 2.0).abs() < 1e-5 && (uv.y - 0.25).abs() < 1e-5);
+        // default uvs of triangle
+        let mesh = Mesh::from((vertices.clone(), vec![0, 1, 2])).with_uvs(Some(vec![Point2::new(0.5, 0.5)]));
+        let uv = mesh.uv(0, Point3::new(0.0, 2.0, 0.0));
+        assert!((uv.x - 1.0).abs() < 1e-5 && (uv.y - 1.0).abs() < 1e-5);
+
+        // uvs of instance are uvs of prototype
+        let mut prototype = Triangles::new();
+        let uvs = vec![Point2::new(0.0, 0.0), Point2::new(1.0, 0.0), Point2::new(0.0, 1.0)];
+        prototype.add(Mesh::from((vertices, vec![0, 1, 2])).with_uvs(Some(uvs)), None, 0, None, None);
+        let mut geometry = Geometry::new();
+        let id = geometry.add_prototype(prototype);
+        geometry.add_instance(id, AnimatedTransformation::from(Transformation::translate(&Vec3::new(0.0, 0.0, 3.0))));
+        geometry.prepare_for_rendering();
+        let ray = Ray::new(Point3::new(0.5, 1.0, 5.0), Vec3::new(0.0, 0.0, -1.0));
+        let si = geometry.intersect(&ray).unwrap();
+        assert!((si.uv.x - 0.25).abs() < 1e-5 && (si.uv.y - 0.5).abs() < 1e-5);
+    }
+
+    #[test]
+    fn transformed_aabb() {
+        let bbox = AABB::new(Point3::new(0.0, 0.0, 0.0), Point3::new(1.0, 2.0, 3.0));
+        let rotated = bbox * Transformation::rotate_z(std::f32::consts::FRAC_PI_2);
+        assert!(rotated.min().distance(Point3::new(-2.0, 0.0, 0.0)) < 1e-5);
+        assert!(rotated.max().distance(Point3::new(0.0, 1.0, 3.0)) < 1e-5);
+    }
+
+    #[test]
+    fn moving_instance() {
+        let mut prototype = Triangles::new();
+        let quad = Mesh::from((vec![Point3::new(-1.0, -1.0, 0.0), Point3::new(1.0, -1.0, 0.0), Point3::new(1.0, 1.0, 0.0),
+                                    Point3::new(-1.0, 1.0, 0.0)], vec![0, 1, 2, 0, 2, 3]));
+        prototype.add(quad, None, 3, None, None);
+        let mut geometry = Geometry::new();
+        let id = geometry.add_prototype(prototype);
+        let start = Transformation::translate(&Vec3::new(0.0, 0.0, 1.0));
+        let end = Transformation::translate(&Vec3::new(4.0, 0.0, 1.0));
+        geometry.add_instance(id, AnimatedTransformation::new(start, end, 0.0, 1.0));
+        geometry.add_instance(id, AnimatedTransformation::from(Transformation::translate(&Vec3::new(0.0, 0.0, 3.0))));
+        geometry.prepare_for_rendering();
+
+        let bounds = geometry.bounds().unwrap();
+        assert_eq!((bounds.min().x, bounds.max().x), (-1.0, 5.0));
+        assert_eq!((bounds.min().z, bounds.max().z), (1.0, 3.0));
+
+        let ray = Ray::new(Point3::new(0.0, 0.0, -2.0), Vec3::new(0.0, 0.0, 1.0));
+        let si = geometry.intersect(&ray).unwrap();
+        assert!((si.hit_point.z - 1.0).abs() < 1e-5);
+        assert_eq!(si.material_id, 3);
+        // at the end of shutter first instance moved away and static instance is hit
+        let si = geometry.intersect(&ray.with_time(1.0)).unwrap();
+        assert!((si.hit_point.z - 3.0).abs() < 1e-5);
+        let ray = Ray::new(Point3::new(4.0, 0.0, -2.0), Vec3::new(0.0, 0.0, 1.0));
+        assert!(geometry.intersect(&ray).is_none());
+        let si = geometry.intersect(&ray.with_time(0.75)).unwrap();
+        assert!((si.hit_point.z - 1.0).abs() < 1e-5 && si.normal.z.abs() > 0.99);
+    }
+
+    #[test]
+    fn object_space_of_hits() {
+        let mut geometry = Geometry::new();
+        let to_world = Transformation::translate(&Vec3::new(0.0, 0.0, 5.0)) * Transformation::scale(2.0, 2.0, 2.0);
+        geometry.add_sphere(Sphere::new(Point3::new(0.0, 0.0, 0.0), 1.0), Some(to_world), 0, None);
+        let mut prototype = Triangles::new();
+        let quad = Mesh::from((vec![Point3::new(-1.0, -1.0, 0.0), Point3::new(1.0, -1.0, 0.0), Point3::new(1.0, 1.0, 0.0),
+                                    Point3::new(-1.0, 1.0, 0.0)], vec![0, 1, 2, 0, 2, 3]));
+        prototype.add(quad, None, 1, None, None);
+        let id = geometry.add_prototype(prototype);
+        let start = Transformation::translate(&Vec3::new(10.0, 0.0, 0.0));
+        let end = Transformation::translate(&Vec3::new(11.0, 0.0, 0.0));
+        geometry.add_instance(id, AnimatedTransformation::new(start, end, 0.0, 1.0));
+        geometry.add_sphere(Sphere::new(Point3::new(-10.0, 0.0, 0.0), 1.0), None, 0, None);
+        geometry.prepare_for_rendering();
+
+        let si = geometry.intersect(&Ray::new(Point3::new(0.0, 0.0, 0.0), Vec3::new(0.0, 0.0, 1.0))).unwrap();
+        assert!((si.hit_point.z - 3.0).abs() < 1e-5);
+        let point = si.point_in(CoordinateSpace::Object, &geometry);
+        assert!((point.z + 1.0).abs() < 1e-5);
+        assert!((si.object_normal(&geometry).z + 1.0).abs() < 1e-5);
+
+        // same point of moving instance has same object space position at any time
+        let ray = Ray::new(Point3::new(10.5, 0.5, -1.0), Vec3::new(0.0, 0.0, 1.0));
+        let moved = Ray::new(Point3::new(10.75, 0.5, -1.0), Vec3::new(0.0, 0.0, 1.0)).with_time(0.25);
+        let (si, si_moved) = (geometry.intersect(&ray).unwrap(), geometry.intersect(&moved).unwrap());
+        let (point, point_moved) = (si.object_point(&geometry), si_moved.object_point(&geometry));
+        assert!((point.x - 0.5).abs() < 1e-5 && (point_moved.x - 0.5).abs() < 1e-5);
+
+        let si = geometry.intersect(&Ray::new(Point3::new(-10.0, 0.0, -5.0), Vec3::new(0.0, 0.0, 1.0))).unwrap();
+        assert!(si.object_space.is_none());
+        assert_eq!(si.point_in(CoordinateSpace::Object, &geometry).z, si.hit_point.z);
+        assert_eq!(CoordinateSpace::from_name("object"), Some(CoordinateSpace::Object));
+    }
+
+    #[test]
+    fn bilinear_patch_intersection() {
+        let patch = BilinearPatch::new(Point3::new(0.0, 0.0, 0.0), Point3::new(2.0, 0.0, 0.0),
+                                       Point3::new(0.0, 2.0, 0.0), Point3::new(2.0, 2.0, 0.0))
+            .with_uvs(Some([Point2::new(0.0, 0.0), Point2::new(4.0, 0.0), Point2::new(0.0, 1.0), Point2::new(4.0, 1.0)]));
+        let ray = Ray::new(Point3::new(0.5, 1.5, -1.0), Vec3::new(0.0, 0.0, 1.0));
+        assert_eq!(patch.intersect(&ray, 0.0), Some(1.0));
+        assert!(patch.intersect(&ray, 1.5).is_none());
+        assert!(patch.intersect(&Ray::new(Point3::new(2.5, 1.5, -1.0), Vec3::new(0.0, 0.0, 1.0)), 0.0).is_none());
+        let uv = patch.uv(Point3::new(0.5, 1.5, 0.0));
+        assert!((uv.x - 1.0).abs() < 1e-5 && (uv.y - 0.75).abs() < 1e-5);
+
+        // twisted patch is hyperbolic paraboloid z = x * y
+        let patch = BilinearPatch::new(Point3::new(0.0, 0.0, 0.0), Point3::new(1.0, 0.0, 0.0),
+                                       Point3::new(0.0, 1.0, 0.0), Point3::new(1.0, 1.0, 1.0));
+        let ray = Ray::new(Point3::new(0.5, 0.5, -1.0), Vec3::new(0.0, 0.0, 1.0));
+        let t = patch.intersect(&ray, 0.0).unwrap();
+        assert!((t - 1.25).abs() < 1e-5);
+        let normal = patch.normal(&ray, ray.point_at(t));
+        let expected = Normal::new(-0.5, -0.5, 1.0).normalize();
+        assert!((normal * expected - 1.0).abs() < 1e-5);
+        let uv = patch.uv(ray.point_at(t));
+        assert!((uv.x - 0.5).abs() < 1e-5 && (uv.y - 0.5).abs() < 1e-5);
+
+        let target = patch.point(0.2, 0.9);
+        let origin = Point3::new(0.25, 0.75, 2.0);
+        let ray = Ray::new(origin, (target - origin).normalize());
+        let t = patch.intersect(&ray, 0.0).unwrap();
+        assert!((t - target.distance(origin)).abs() < 1e-4);
+        let (u, v) = patch.parametric_coordinates(ray.point_at(t));
+        assert!((u - 0.2).abs() < 1e-4 && (v - 0.9).abs() < 1e-4);
+    }
+
+    #[test]
+    fn bilinear_mesh_geometry() {
+        let mut desc = BilinearMeshDescription {
+            vertices: Some(vec![Point3::new(0.0, 0.0, 0.0), Point3::new(1.0, 0.0, 0.0),
+                                Point3::new(0.0, 1.0, 0.0), Point3::new(1.0, 1.0, 0.0)]),
+            indices: Some(vec![0, 1, 2, 3]),
+            normals: Some(vec![Normal::new(0.0, 0.0, -1.0); 4]),
+            material: "matte".to_string(),
+            transform: Some(Transformation::translate(&Vec3::new(0.0, 0.0, 3.0)) * Transformation::scale(2.0, 1.0, 1.0)),
+            ..Default::default()
+        };
+        assert!((desc.area() - 2.0).abs() < 1e-5);
+        let mat_names = HashMap::from([("matte".to_string(), 2)]);
+        let mut descs = vec![ShapeDescription::BilinearMesh(desc)];
+        let geometry = Geometry::from_shape_descriptions(&mut descs, &mut [], &mat_names, &HashMap::new(), &HashMap::new()).unwrap();
+        let ray = Ray::new(Point3::new(1.5, 0.5, 0.0), Vec3::new(0.0, 0.0, 1.0));
+        let si = geometry.intersect(&ray).unwrap();
+        assert!((si.t - 3.0).abs() < 1e-5);
+        assert_eq!(si.material_id, 2);
+        // geometric normal follows vertex normals, so ray hits front side
+        assert!(!si.back_side && si.normal.z < -0.99 && si.shading_normal.z < -0.99);
+        assert!((si.uv.x - 0.75).abs() < 1e-5 && (si.uv.y - 0.5).abs() < 1e-5);
+
+        desc = BilinearMeshDescription { indices: Some(vec![0, 1, 2, 4]), ..Default::default() };
+        desc.vertices = Some(vec![Point3::new(0.0, 0.0, 0.0); 4]);
+        let mut descs = vec![ShapeDescription::BilinearMesh(desc)];
+        assert!(Geometry::from_shape_descriptions(&mut descs, &mut [], &mat_names, &HashMap::new(), &HashMap::new()).is_err());
+    }
+
+    #[test]
+    fn bvh_matches_linear_intersector() {
+        use crate::rng::{PCGRng, Rng};
+        let mut rng = PCGRng::new(0xabcdef, 0);
+        let mut geometry = Geometry::new();
+        for i in 0..200 {
+            let center = Point3::new(rng.rand_f32() * 10.0, rng.rand_f32() * 10.0, rng.rand_f32() * 10.0);
+            geometry.add_sphere(Sphere::new(center, 0.1 + rng.rand_f32() * 0.3), None, i, None);
+        }
+        let grid = Mesh::from((vec![Point3::new(0.0, 0.0, 5.0), Point3::new(10.0, 0.0, 5.0), Point3::new(10.0, 10.0, 5.0),
+                                    Point3::new(0.0, 10.0, 5.0)], vec![0, 1, 2, 0, 2, 3]));
+        geometry.add_mesh(grid, None, 1000, None);
+        geometry.prepare_for_rendering();
+        assert!(geometry.acceleration_memory() > 0);
+        let rays: Vec<_> = (0..500).map(|_| {
+            let origin = Point3::new(rng.rand_f32() * 10.0, rng.rand_f32() * 10.0, -1.0);
+            Ray::new(origin, Vec3::new(rng.rand_f32() - 0.5, rng.rand_f32() - 0.5, 1.0).normalize())
+        }).collect();
+        let bvh_hits: Vec<_> = rays.iter().map(|ray| geometry.intersect(ray).map(|si| (si.t, si.material_id))).collect();
+        geometry.set_accelerator_type(AcceleratorType::Linear);
+        let bounds = geometry.bounds().unwrap();
+        assert!(bounds.max().x > 9.0);
+        for (ray, bvh_hit) in rays.iter().zip(bvh_hits.iter()) {
+            assert_eq!(geometry.intersect(ray).map(|si| (si.t, si.material_id)), *bvh_hit);
+        }
+        assert!(bvh_hits.iter().filter(|hit| hit.is_some_and(|(_, id)| id < 1000)).count() > 10);
+    }
+}
@@ -0,0 +1,165 @@
+//! Analytic daylight model of Preetham, Shirley and Smits 1999
+//!
+//! Sky radiance is given by Perez distribution of luminance and chromaticity that depends on turbidity
+//! of the atmosphere and position of the sun. Sun is disk of radiance of the sun outside of atmosphere
+//! attenuated by Rayleigh and aerosol scattering along its optical path. Up direction of the model is z,
+//! radiance is in kcd/m^2 of the model.
+
+use crate::color::RGB;
+use crate::vec::Vec3;
+
+/// Default scale of radiance of sky light. Radiance of the model is in kcd/m^2 and the sun 45 degrees
+/// above horizon gives about 90 klx, so white diffuse surface lit by it has radiance about one.
+pub const DEFAULT_SKY_SCALE: f32 = 0.03;
+/// Angular radius of the sun disk seen from the earth
+pub const SUN_ANGULAR_RADIUS: f32 = 0.00465;
+/// Luminance of the sun outside of atmosphere in kcd/m^2
+const SUN_LUMINANCE: f32 = 2.0e6;
+/// Wavelengths in micrometers used for attenuation of red, green and blue channel of the sun
+const SUN_WAVELENGTHS: [f32; 3] = [0.65, 0.55, 0.45];
+
+// Coefficients A..E of Perez function, they are linear in turbidity (slope, intercept)
+const PEREZ_Y: [(f32, f32); 5] = [(0.1787, -1.4630), (-0.3554, 0.4275), (-0.0227, 5.3251), (0.1206, -2.5771), (-0.0670, 0.3703)];
+const PEREZ_X: [(f32, f32); 5] = [(-0.0193, -0.2592), (-0.0665, 0.0008), (-0.0004, 0.2125), (-0.0641, -0.8989), (-0.0033, 0.0452)];
+const PEREZ_YY: [(f32, f32); 5] = [(-0.0167, -0.2608), (-0.0950, 0.0092), (-0.0079, 0.2102), (-0.0441, -1.6537), (-0.0109, 0.0529)];
+
+// Zenith chromaticity is cubic in zenith angle of the sun, rows are multiplied by T^2, T and 1
+const ZENITH_X: [[f32; 4]; 3] = [[0.00166, -0.00375, 0.00209, 0.0],
+                                 [-0.02903, 0.06377, -0.03202, 0.00394],
+                                 [0.11693, -0.21196, 0.06052, 0.25886]];
+const ZENITH_Y: [[f32; 4]; 3] = [[0.00275, -0.00610, 0.00317, 0.0],
+                                 [-0.04214, 0.08970, -0.04153, 0.00516],
+                                 [0.15346, -0.26756, 0.06670, 0.26688]];
+
+fn perez_coefficients(table: &[(f32, f32); 5], turbidity: f32) -> [f32; 5] {
+    table.map(|(slope, intercept)| slope * turbidity + intercept)
+}
+
+fn perez(coefficients: &[f32; 5], cos_theta: f32, gamma: f32) -> f32 {
+    let [a, b, c, d, e] = *coefficients;
+    let cos_gamma = gamma.cos();
+    (1.0 + a * (b / cos_theta).exp()) * (1.0 + c * (d * gamma).exp() + e * cos_gamma * cos_gamma)
+}
+
+fn zenith_chromaticity(table: &[[f32; 4]; 3], turbidity: f32, theta_sun: f32) -> f32 {
+    let angles = [theta_sun * theta_sun * theta_sun, theta_sun * theta_sun, theta_sun, 1.0];
+    let row = |index: usize| table[index].iter().zip(angles.iter()).map(|(c, a)| c * a).sum::<f32>();
+    turbidity * turbidity * row(0) + turbidity * row(1) + row(2)
+}
+
+fn xyz_to_rgb(x: f32, y: f32, z: f32) -> RGB {
+    RGB::new((3.2406 * x - 1.5372 * y - 0.4986 * z).max(0.0),
+             (-0.9689 * x + 1.8758 * y + 0.0415 * z).max(0.0),
+             (0.0557 * x - 0.2040 * y + 1.0570 * z).max(0.0))
+}
+
+pub struct PreethamSky {
+    sun_direction: Vec3,
+    coefficients: [[f32; 5]; 3],
+    // Zenith values of luminance and chromaticity divided by Perez function at zenith
+    zenith: [f32; 3],
+    sun_radiance: RGB
+}
+
+impl PreethamSky {
+    /// Direction to the sun is normalized, turbidity is clamped to range where the model is valid.
+    /// Sky of the sun below horizon is sky of the sun at horizon and sun doesn't emit.
+    pub fn new(sun_direction: Vec3, turbidity: f32) -> Self {
+        let sun_direction = sun_direction.normalize();
+        let turbidity = turbidity.clamp(1.7, 10.0);
+        let theta_sun = sun_direction.z.clamp(-1.0, 1.0).acos().min(std::f32::consts::FRAC_PI_2 - 1e-3);
+        let coefficients = [perez_coefficients(&PEREZ_Y, turbidity), perez_coefficients(&PEREZ_X, turbidity),
+                            perez_coefficients(&PEREZ_YY, turbidity)];
+
+        let chi = (4.0 / 9.0 - turbidity / 120.0) * (std::f32::consts::PI - 2.0 * theta_sun);
+        let luminance = ((4.0453 * turbidity - 4.9710) * chi.tan() - 0.2155 * turbidity + 2.4192).max(0.0);
+        let values = [luminance, zenith_chromaticity(&ZENITH_X, turbidity, theta_sun),
+                      zenith_chromaticity(&ZENITH_Y, turbidity, theta_sun)];
+        let zenith = [0, 1, 2].map(|i| values[i] / perez(&coefficients[i], 1.0, theta_sun));
+
+        let sun_radiance = if sun_direction.z > 0.0 { Self::attenuated_sun(theta_sun, turbidity) } else { RGB::zero() };
+        Self { sun_direction, coefficients, zenith, sun_radiance }
+    }
+
+    // Transmittance of Rayleigh and aerosol (Angstrom formula with alpha 1.3) scattering along relative
+    // optical mass of the path of sun light, ozone and water vapour absorption are ignored.
+    fn attenuated_sun(theta_sun: f32, turbidity: f32) -> RGB {
+        let theta_degrees = theta_sun.to_degrees();
+        let mass = (theta_sun.cos() + 0.15 * (93.885 - theta_degrees).powf(-1.253)).recip();
+        let beta = 0.04608 * turbidity - 0.04586;
+        let [r, g, b] = SUN_WAVELENGTHS.map(|lambda| {
+            let rayleigh = (-mass * 0.008735 * lambda.powf(-4.08)).exp();
+            let aerosol = (-mass * beta * lambda.powf(-1.3)).exp();
+            SUN_LUMINANCE * rayleigh * aerosol
+        });
+        RGB::new(r, g, b)
+    }
+
+    pub fn sun_direction(&self) -> Vec3 {
+        self.sun_direction
+    }
+
+    /// Radiance of the sun disk
+    pub fn sun_radiance(&self) -> RGB {
+        self.sun_radiance
+    }
+
+    /// Radiance of the sky without the sun in normalized direction, zero below horizon.
+    pub fn radiance(&self, direction: Vec3) -> RGB {
+        if direction.z <= 0.0 {
+            return RGB::zero()
+        }
+        let gamma = (direction * self.sun_direction).clamp(-1.0, 1.0).acos();
+        let [luminance, x, y] = [0, 1, 2].map(|i| self.zenith[i] * perez(&self.coefficients[i], direction.z, gamma));
+        if y <= 0.0 {
+            return RGB::zero()
+        }
+        xyz_to_rgb(x / y * luminance, luminance, (1.0 - x - y) / y * luminance)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn daylight_sky() {
+        let sky = PreethamSky::new(Vec3::new(1.0, 0.0, 1.0), 3.0);
+        let zenith = sky.radiance(Vec3::new(0.0, 0.0, 1.0));
+        assert!(zenith.b > zenith.r && zenith.luminance() > 0.0);
+        // circumsolar region is brighter than sky opposite to the sun
+        let towards_sun = sky.radiance(Vec3::new(1.0, 0.0, 1.2).normalize());
+        let away_from_sun = sky.radiance(Vec3::new(-1.0, 0.0, 1.2).normalize());
+        assert!(towards_sun.luminance() > away_from_sun.luminance());
+        assert_eq!(sky.radiance(Vec3::new(0.0, 0.6, -0.8)).luminance(), 0.0);
+
+        // sun near horizon is dimmer and redder than high sun
+        let high = sky.sun_radiance();
+        let low = PreethamSky::new(Vec3::new(1.0, 0.0, 0.05), 3.0).sun_radiance();
+        assert!(low.luminance() < high.luminance());
+        assert!(low.r / low.b > high.r / high.b);
+        assert!(PreethamSky::new(Vec3::new(1.0, 0.0, -0.1), 3.0).sun_radiance().is_black());
+    }
+
+    #[test]
+    fn default_scale() {
+        // illuminance of horizontal surface from sky (midpoint rule over hemisphere) and the sun
+        let sky = PreethamSky::new(Vec3::new(1.0, 0.0, 1.0), 3.0);
+        let (n, dtheta, dphi) = (64, std::f32::consts::FRAC_PI_2 / 64.0, std::f32::consts::PI / 64.0);
+        let mut illuminance = 0.0;
+        for i in 0..n {
+            let theta = (i as f32 + 0.5) * dtheta;
+            for j in 0..2 * n {
+                let phi = (j as f32 + 0.5) * dphi;
+                let direction = Vec3::new(theta.sin() * phi.cos(), theta.sin() * phi.sin(), theta.cos());
+                illuminance += sky.radiance(direction).luminance() * theta.cos() * theta.sin() * dtheta * dphi;
+            }
+        }
+        let half_angle = (0.5 * SUN_ANGULAR_RADIUS).sin();
+        illuminance += sky.sun_radiance().luminance() * 4.0 * std::f32::consts::PI * half_angle * half_angle * sky.sun_direction().z;
+        assert!(illuminance > 80.0 && illuminance < 100.0, "illuminance {}", illuminance);
+        let white = illuminance * DEFAULT_SKY_SCALE * std::f32::consts::FRAC_1_PI;
+        assert!(white > 0.75 && white < 1.0, "radiance of white surface {}", white);
+    }
+}
@@ -0,0 +1,338 @@
+use std::collections::HashMap;
+use crate::color::{RGB, RGBAccumlationBuffer, AccumlationTileBuffer};
+use crate::integrators::{pdfa_to_w, visible, create_aov_buffers, add_aov_sample, save_aovs, TILE_SIZE};
+use crate::materials::ScatteringType;
+use crate::ray::{Ray, spawn_new_ray};
+use crate::rgb::RGB8uffer;
+use crate::samplers::{SamplerInterface, RandomPathSampler};
+use crate::scene::{Scene, SppmProperties};
+use crate::shapes::SurfaceInteraction;
+use crate::textures::TextureContext;
+use crate::tile::Tile;
+use crate::vec::{Point3, Vec3, Normal};
+use crate::postprocess::finish_image;
+
+const PHOTON_SEED: u64 = 0x5bd1e995;
+
+// First non specular hit of camera path, photons that land near it are gathered.
+struct VisiblePoint {
+    position: Point3,
+    normal: Normal,
+    wo: Vec3,
+    material_id: usize,
+    // Point where textures of the material are evaluated
+    ctx: TextureContext,
+    beta: RGB
+}
+
+struct SppmPixel {
+    // Emission and direct lighting found by camera paths, summed over iterations
+    ld: RGB,
+    vp: Option<VisiblePoint>,
+    // Photon flux gathered in current iteration
+    phi: RGB,
+    m: usize,
+    n: f32,
+    radius: f32,
+    tau: RGB
+}
+
+/// Uniform grid over visible points, every point is stored in all cells its gather sphere overlaps.
+struct VisiblePointGrid {
+    cell_size: f32,
+    cells: HashMap<(i32, i32, i32), Vec<usize>>
+}
+
+impl VisiblePointGrid {
+    fn new(pixels: &[SppmPixel]) -> Self {
+        let max_radius = pixels.iter().filter(|p| p.vp.is_some()).fold(0.0f32, |r, p| r.max(p.radius));
+        let cell_size = if max_radius > 0.0 { 2.0 * max_radius } else { 1.0 };
+        let mut grid = VisiblePointGrid { cell_size, cells: HashMap::new() };
+        for (index, pixel) in pixels.iter().enumerate() {
+            if let Some(vp) = &pixel.vp {
+                let r = pixel.radius;
+                let pmin = grid.cell(Point3::new(vp.position.x - r, vp.position.y - r, vp.position.z - r));
+                let pmax = grid.cell(Point3::new(vp.position.x + r, vp.position.y + r, vp.position.z + r));
+                for z in pmin.2..=pmax.2 {
+                    for y in pmin.1..=pmax.1 {
+                        for x in pmin.0..=pmax.0 {
+                            grid.cells.entry((x, y, z)).or_default().push(index);
+                        }
+                    }
+                }
+            }
+        }
+        grid
+    }
+
+    fn cell(&self, p: Point3) -> (i32, i32, i32) {
+        ((p.x / self.cell_size).floor() as i32, (p.y / self.cell_size).floor() as i32, (p.z / self.cell_size).floor() as i32)
+    }
+
+    fn lookup(&self, p: Point3) -> &[usize] {
+        match self.cells.get(&self.cell(p)) {
+            Some(indices) => indices,
+            None => &[]
+        }
+    }
+}
+
+fn direct_lighting(vp: &VisiblePoint, scene: &Scene, sampler: &mut Box<dyn SamplerInterface>) -> RGB {
+    let material = scene.material(vp.material_id, &vp.ctx);
+    let mut acum = RGB::zero();
+    for light in scene.lights.iter() {
+        let ls = match light.illuminate(vp.position, sampler) {
+            Some(ls) => ls,
+            None => continue
+        };
+        if !visible(vp.position, vp.normal, ls.position, &scene.geometry) {
+            continue
+        }
+        let mat_spectrum = match material.eval(vp.wo, vp.normal, ls.wi) {
+            Some(result) => result.color,
+            None => continue
+        };
+        let cosa = (ls.wi * vp.normal).abs();
+        let dist = vp.position.distance(ls.position);
+        let pdf = pdfa_to_w(ls.pdfa, dist, ls.cos_theta);
+        acum += (mat_spectrum * ls.intensity) * (cosa / pdf);
+    }
+    acum
+}
+
+// Follow camera path through specular bounces and store first non specular hit.
+fn trace_camera_path(ray: &Ray, isect_p: Option<SurfaceInteraction>, scene: &Scene, sampler: &mut Box<dyn SamplerInterface>,
+                     maxdepth: usize, pixel: &mut SppmPixel) {
+    let mut ray = *ray;
+    let mut beta = RGB::new(1.0, 1.0, 1.0);
+    let mut first_hit = Some(isect_p);
+    pixel.vp = None;
+    for depth in 0..=maxdepth {
+        let isect_p = match first_hit.take().unwrap_or_else(|| scene.geometry.intersect(&ray)) {
+            Some(isect_p) => isect_p,
+            None => {
+                pixel.ld += beta * scene.environment_radiance(ray.direction);
+                return
+            }
+        };
+        let material_id = isect_p.material_id as usize;
+        let material = scene.material_at(&isect_p);
+        let wo = -ray.direction;
+        pixel.ld += beta * material.emssion(wo, isect_p.normal, isect_p.back_side);
+        if material.scattering_type() != ScatteringType::Specular {
+            let vp = VisiblePoint { position: isect_p.hit_point, normal: isect_p.normal, wo, material_id,
+                                   ctx: TextureContext::new(&isect_p, &scene.geometry), beta };
+            pixel.ld += beta * direct_lighting(&vp, scene, sampler);
+            pixel.vp = Some(vp);
+            return
+        }
+        sampler.start_bounce(depth as u32);
+        let bs = match material.sample(wo, isect_p.normal, sampler) {
+            Some(bs) => bs,
+            None => return
+        };
+        beta = beta * bs.color * ((isect_p.normal * bs.wi).abs() / bs.pdfw);
+        ray = spawn_new_ray(isect_p.hit_point, isect_p.normal, bs.wi).with_time(ray.time);
+    }
+}
+
+// Trace one photon and add its flux to visible points near its non specular hits. Direct
+// illumination is already estimated by camera paths so first hit of the photon is skipped.
+fn trace_photon(scene: &Scene, sampler: &mut Box<dyn SamplerInterface>, maxdepth: usize,
+                grid: &VisiblePointGrid, pixels: &mut [SppmPixel]) {
+    let nlights = scene.lights.len();
+    let light_index = ((sampler.next_1d() * nlights as f32) as usize).min(nlights - 1);
+    let les = match scene.lights[light_index].sample_le(sampler) {
+        Some(les) => les,
+        None => return
+    };
+    let pdf = les.pdf_pos * les.pdf_dir / nlights as f32;
+    if pdf == 0.0 {
+        return
+    }
+    let cos_theta = les.normal.map_or(1.0, |normal| (normal * les.ray.direction).abs());
+    let mut beta = les.radiance * (cos_theta / pdf);
+    let mut ray = les.ray;
+    for depth in 0..maxdepth {
+        let isect_p = match scene.geometry.intersect(&ray) {
+            Some(isect_p) => isect_p,
+            None => return
+        };
+        let material = scene.material_at(&isect_p);
+        let wi = -ray.direction;
+        if depth > 0 && material.scattering_type() != ScatteringType::Specular {
+            for &index in grid.lookup(isect_p.hit_point) {
+                let pixel = &mut pixels[index];
+                let vp = match &pixel.vp {
+                    Some(vp) => vp,
+                    None => continue
+                };
+                if (vp.position - isect_p.hit_point).length_sqr() > pixel.radius * pixel.radius {
+                    continue
+                }
+                if let Some(result) = scene.material(vp.material_id, &vp.ctx).eval(vp.wo, vp.normal, wi) {
+                    pixel.phi += beta * result.color;
+                    pixel.m += 1;
+                }
+            }
+        }
+        sampler.start_bounce(depth as u32);
+        let bs = match material.sample(wi, isect_p.normal, sampler) {
+            Some(bs) => bs,
+            None => return
+        };
+        beta = beta * bs.color * ((isect_p.normal * bs.wi).abs() / bs.pdfw);
+        ray = spawn_new_ray(isect_p.hit_point, isect_p.normal, bs.wi).with_time(ray.time);
+    }
+}
+
+// Progressive radius reduction (Hachisuka and Jensen), only fraction alpha of new photons is kept.
+fn update_pixel(pixel: &mut SppmPixel, alpha: f32) {
+    if pixel.m > 0 {
+        let n_new = pixel.n + alpha * pixel.m as f32;
+        let radius_new = pixel.radius * (n_new / (pixel.n + pixel.m as f32)).sqrt();
+        let beta = pixel.vp.as_ref().map_or(RGB::zero(), |vp| vp.beta);
+        pixel.tau = (pixel.tau + beta * pixel.phi) * ((radius_new * radius_new) / (pixel.radius * pixel.radius));
+        pixel.n = n_new;
+        pixel.radius = radius_new;
+    }
+    pixel.phi = RGB::zero();
+    pixel.m = 0;
+}
+
+// Returns radiance and depth of pixels when it is accumulated with auxiliary outputs.
+fn render_sppm(scene: &Scene, settings: &SppmProperties) -> (RGBAccumlationBuffer, Option<Vec<f32>>) {
+    let resolution = scene.settings.resolution;
+    let tile = Tile::new(0, 0, resolution.width, resolution.height);
+    let mut pixels: Vec<_> = (0..resolution.width * resolution.height).map(|_| SppmPixel {
+        ld: RGB::zero(), vp: None, phi: RGB::zero(), m: 0, n: 0.0, radius: settings.initial_radius, tau: RGB::zero()
+    }).collect();
+    let tiles = tile.split(TILE_SIZE, TILE_SIZE);
+    let mut photon_sampler: Box<dyn SamplerInterface> = Box::new(RandomPathSampler::new(PHOTON_SEED));
+    let mut aovs = create_aov_buffers(scene);
+
+    // NOTE: cancelled rendering is estimated from finished iterations
+    let mut finished_iterations = 0;
+    for iteration in 0..settings.iterations {
+        if scene.cancel_token.is_cancelled() {
+            break;
+        }
+        for camera_tile in tiles.iter() {
+            let mut sampler = scene.sampler.clone_for_tile(camera_tile, iteration as u32);
+            for (x, y) in *camera_tile {
+                let (sx, sy) = sampler.sample_pixel(x, y, iteration);
+                let ray = scene.camera.generate_ray_at_time(x as f32 + sx, y as f32 + sy, sampler.sample_time());
+                let isect_p = scene.geometry.intersect(&ray);
+                add_aov_sample(scene, &mut aovs, x, y, &ray, isect_p.as_ref());
+                trace_camera_path(&ray, isect_p, scene, &mut sampler, settings.maxdepth, &mut pixels[y * resolution.width + x]);
+            }
+        }
+        if !scene.lights.is_empty() {
+            let grid = VisiblePointGrid::new(&pixels);
+            photon_sampler.initialize(&tile, iteration as u32);
+            for _ in 0..settings.photons_per_iteration {
+                trace_photon(scene, &mut photon_sampler, settings.maxdepth, &grid, &mut pixels);
+            }
+        }
+        for pixel in pixels.iter_mut() {
+            update_pixel(pixel, settings.alpha);
+        }
+        finished_iterations += 1;
+    }
+
+    save_aovs(scene, &aovs);
+    // NOTE: estimate of pixel averages its jittered visible points, so it is splatted from center of the pixel
+    // with reconstruction filter of the scene, as samples of other integrators
+    let mut film = AccumlationTileBuffer::new(tile, scene.filter_radius(), resolution.width, resolution.height);
+    let weight = |x: f32, y: f32| scene.filter_weight(x, y);
+    let nphotons = (finished_iterations * settings.photons_per_iteration) as f32;
+    let iterations = finished_iterations.max(1) as f32;
+    for (x, y) in tile {
+        let pixel = &pixels[y * resolution.width + x];
+        let mut rgb = pixel.ld * iterations.recip();
+        if nphotons > 0.0 {
+            rgb += pixel.tau * (nphotons * std::f32::consts::PI * pixel.radius * pixel.radius).recip();
+        }
+        film.add(x, y, x as f32 + 0.5, y as f32 + 0.5, &rgb, &weight);
+    }
+    let mut accum = RGBAccumlationBuffer::new(tile.size(), scene.settings.buffer_precision);
+    accum.add_accumulation_tile_buffer(&film);
+    (accum, aovs.and_then(|aovs| aovs.depth()))
+}
+
+pub fn sppm_integrator(scene: &Scene, settings: &SppmProperties) -> RGB8uffer {
+    let (accum, depth) = render_sppm(scene, settings);
+    finish_image(scene, &accum, depth)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::filter::FilterDescriptor;
+    use crate::lights::LightDescription;
+    use crate::materials::{MaterialDescription, MaterialType};
+    use crate::rgb::ImageSize;
+    use crate::scene::{SceneDescription, RenderingAlgorithm};
+    use crate::shapes::{ShapeDescription, SphereDescription};
+
+    #[test]
+    fn sppm_integrating_sphere() {
+        // Point light in the center of diffuse sphere, radiance of wall is
+        // albedo / pi * irradiance / (1 - albedo), direct part is half of it for albedo 0.5.
+        let mut desc = SceneDescription::default();
+        desc.set_resolution(ImageSize::new(8, 8));
+        desc.camera_desc.position = Point3::new(0.0, 0.0, 0.0);
+        desc.camera_desc.look_at = Point3::new(0.0, 0.0, 1.0);
+        desc.materials.push(MaterialDescription { name: "wall".to_string(), diffuse: RGB::new(0.5, 0.5, 0.5), ..Default::default() });
+        desc.shapes.push(ShapeDescription::Sphere(SphereDescription { radius: 1.0, material: "wall".to_string(), ..Default::default() }));
+        desc.lights.push(LightDescription { intensity: RGB::new(std::f32::consts::PI, std::f32::consts::PI, std::f32::consts::PI), ..Default::default() });
+        let scene = Scene::try_from(desc).unwrap();
+        let settings = SppmProperties { iterations: 4, photons_per_iteration: 20000, maxdepth: 20, initial_radius: 0.3, ..Default::default() };
+        let (accum, _) = render_sppm(&scene, &settings);
+        let mut sum = 0.0;
+        for y in 0..8 {
+            for x in 0..8 {
+                let sample = accum.get(x, y).unwrap();
+                sum += (sample.spectrum * sample.weight.recip()).r;
+            }
+        }
+        let average = sum / 64.0;
+        assert!((average - 1.0).abs() < 0.1, "average radiance {}", average);
+    }
+
+    #[test]
+    fn sppm_uses_filter_of_scene() {
+        let mut desc = SceneDescription::default();
+        desc.set_resolution(ImageSize::new(8, 8));
+        desc.filter = Some(FilterDescriptor::default());
+        let scene = Scene::try_from(desc).unwrap();
+        let settings = SppmProperties { iterations: 1, photons_per_iteration: 0, ..Default::default() };
+        let (accum, _) = render_sppm(&scene, &settings);
+        // triangle filter of radius 2 splats estimate of every pixel to its neighbours
+        assert_eq!(accum.get(4, 4).unwrap().weight, 16.0);
+        assert_eq!(accum.get(0, 0).unwrap().weight, 9.0);
+    }
+
+    #[test]
+    fn sppm_rejects_area_lights() {
+        let mut desc = SceneDescription::default();
+        desc.settings.rendering_algorithm = RenderingAlgorithm::Sppm(SppmProperties::default());
+        desc.materials.push(MaterialDescription { name: "light".to_string(), typ: MaterialType::EmissiveMatte,
+                                                  emission: RGB::new(1.0, 1.0, 1.0), ..Default::default() });
+        desc.shapes.push(ShapeDescription::Sphere(SphereDescription { radius: 1.0, material: "light".to_string(), ..Default::default() }));
+        assert!(Scene::try_from(desc).is_err());
+    }
+
+    #[test]
+    fn visible_point_grid_lookup() {
+        let position = Point3::new(0.5, 0.5, 0.5);
+        let ctx = TextureContext { uv: crate::vec::Point2::new(0.0, 0.0), point: position, object_point: position };
+        let vp = VisiblePoint { position, normal: Normal::new(0.0, 0.0, 1.0),
+            wo: Vec3::new(0.0, 0.0, 1.0), material_id: 0, ctx, beta: RGB::new(1.0, 1.0, 1.0) };
+        let pixels = vec![SppmPixel { ld: RGB::zero(), vp: Some(vp), phi: RGB::zero(), m: 0, n: 0.0, radius: 0.25, tau: RGB::zero() }];
+        let grid = VisiblePointGrid::new(&pixels);
+        assert_eq!(grid.lookup(Point3::new(0.3, 0.5, 0.5)), &[0]);
+        assert!(grid.lookup(Point3::new(5.0, 0.5, 0.5)).is_empty());
+    }
+}
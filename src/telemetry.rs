@@ -0,0 +1,164 @@
+//! Sampling efficiency of materials and lights
+//!
+//! Every sampling strategy counts its samples, samples that couldn't be generated (zero pdf) and samples
+//! that were generated but contribute nothing (occluded light samples, light samples that BSDF doesn't
+//! scatter). Distribution of MIS weights shows how often the strategy wins over the other one, strategy
+//! whose samples have mostly small weights only adds variance. Counters are atomic, so tiles rendered
+//! in parallel share them.
+
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Number of equal bins of MIS weights in range [0, 1]
+pub const MIS_BINS: usize = 10;
+
+/// Result of one sample of sampling strategy.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SampleOutcome {
+    ZeroPdf,
+    Rejected,
+    /// Sample contributes, MIS weight is None when sample isn't combined with other strategy
+    Accepted(Option<f32>),
+}
+
+#[derive(Debug, Default)]
+pub struct StrategyCounters {
+    samples: AtomicU64,
+    zero_pdf: AtomicU64,
+    rejected: AtomicU64,
+    mis_weights: [AtomicU64; MIS_BINS],
+}
+
+impl StrategyCounters {
+    pub fn record(&self, outcome: SampleOutcome) {
+        self.samples.fetch_add(1, Ordering::Relaxed);
+        match outcome {
+            SampleOutcome::ZeroPdf => { self.zero_pdf.fetch_add(1, Ordering::Relaxed); }
+            SampleOutcome::Rejected => { self.rejected.fetch_add(1, Ordering::Relaxed); }
+            SampleOutcome::Accepted(Some(weight)) => {
+                let bin = ((weight.clamp(0.0, 1.0) * MIS_BINS as f32) as usize).min(MIS_BINS - 1);
+                self.mis_weights[bin].fetch_add(1, Ordering::Relaxed);
+            }
+            SampleOutcome::Accepted(None) => {}
+        }
+    }
+
+    pub fn samples(&self) -> u64 {
+        self.samples.load(Ordering::Relaxed)
+    }
+
+    pub fn zero_pdf(&self) -> u64 {
+        self.zero_pdf.load(Ordering::Relaxed)
+    }
+
+    pub fn rejected(&self) -> u64 {
+        self.rejected.load(Ordering::Relaxed)
+    }
+
+    /// Counts of MIS weights in bins [i / MIS_BINS, (i + 1) / MIS_BINS)
+    pub fn mis_histogram(&self) -> [u64; MIS_BINS] {
+        std::array::from_fn(|bin| self.mis_weights[bin].load(Ordering::Relaxed))
+    }
+
+    /// Average MIS weight of weighted samples, bins are represented by their centers
+    pub fn mean_mis_weight(&self) -> Option<f32> {
+        let histogram = self.mis_histogram();
+        let count: u64 = histogram.iter().sum();
+        if count == 0 {
+            return None
+        }
+        let sum: f64 = histogram.iter().enumerate().map(|(bin, n)| (bin as f64 + 0.5) / MIS_BINS as f64 * *n as f64).sum();
+        Some((sum / count as f64) as f32)
+    }
+}
+
+/// Counters of BSDF sampling of every material and of sampling of every light, in order of the scene.
+pub struct SamplingTelemetry {
+    material_names: Vec<String>,
+    light_names: Vec<String>,
+    materials: Vec<StrategyCounters>,
+    lights: Vec<StrategyCounters>,
+}
+
+impl SamplingTelemetry {
+    pub fn new(material_names: Vec<String>, light_names: Vec<String>) -> Self {
+        let materials = material_names.iter().map(|_| StrategyCounters::default()).collect();
+        let lights = light_names.iter().map(|_| StrategyCounters::default()).collect();
+        Self { material_names, light_names, materials, lights }
+    }
+
+    pub fn record_material(&self, material: usize, outcome: SampleOutcome) {
+        self.materials[material].record(outcome);
+    }
+
+    pub fn record_light(&self, light: usize, outcome: SampleOutcome) {
+        self.lights[light].record(outcome);
+    }
+
+    pub fn material(&self, material: usize) -> &StrategyCounters {
+        &self.materials[material]
+    }
+
+    pub fn light(&self, light: usize) -> &StrategyCounters {
+        &self.lights[light]
+    }
+}
+
+fn write_counters(f: &mut fmt::Formatter<'_>, name: &str, counters: &StrategyCounters) -> fmt::Result {
+    let samples = counters.samples();
+    let percent = |count: u64| 100.0 * count as f64 / samples.max(1) as f64;
+    write!(f, "  {:<24} {:>12} {:>9.2}% {:>9.2}%", name, samples, percent(counters.zero_pdf()), percent(counters.rejected()))?;
+    match counters.mean_mis_weight() {
+        Some(weight) => {
+            let histogram = counters.mis_histogram();
+            let weighted: u64 = histogram.iter().sum();
+            let bins: Vec<String> = histogram.iter().map(|n| format!("{:.0}", 100.0 * *n as f64 / weighted as f64)).collect();
+            writeln!(f, " {:>9.3}  [{}]", weight, bins.join(" "))
+        }
+        None => writeln!(f, " {:>9}", "-")
+    }
+}
+
+/// Table of strategies that took any sample, MIS histogram is in percents of weighted samples.
+impl fmt::Display for SamplingTelemetry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let header = format!("  {:<24} {:>12} {:>10} {:>10} {:>9}  MIS weights %", "", "Samples", "Zero pdf", "Rejected", "Mean MIS");
+        for (title, names, counters) in [("Materials (BSDF sampling)", &self.material_names, &self.materials),
+                                         ("Lights (light sampling)", &self.light_names, &self.lights)] {
+            writeln!(f, "{}", title)?;
+            writeln!(f, "{}", header)?;
+            for (name, counters) in names.iter().zip(counters.iter()).filter(|(_, counters)| counters.samples() > 0) {
+                write_counters(f, name, counters)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn strategy_counters() {
+        let telemetry = SamplingTelemetry::new(vec!["glass".to_string(), "matte".to_string()], vec!["0 point".to_string()]);
+        telemetry.record_material(1, SampleOutcome::ZeroPdf);
+        telemetry.record_material(1, SampleOutcome::Accepted(Some(0.05)));
+        telemetry.record_material(1, SampleOutcome::Accepted(Some(1.0)));
+        telemetry.record_material(1, SampleOutcome::Accepted(None));
+        telemetry.record_light(0, SampleOutcome::Rejected);
+
+        let matte = telemetry.material(1);
+        assert_eq!((matte.samples(), matte.zero_pdf(), matte.rejected()), (4, 1, 0));
+        let histogram = matte.mis_histogram();
+        assert_eq!((histogram[0], histogram[MIS_BINS - 1], histogram.iter().sum::<u64>()), (1, 1, 2));
+        assert_eq!(matte.mean_mis_weight(), Some(0.5));
+        assert_eq!(telemetry.light(0).rejected(), 1);
+        assert_eq!(telemetry.light(0).mean_mis_weight(), None);
+
+        // strategies without samples are not reported
+        let report = telemetry.to_string();
+        assert!(report.contains("matte") && report.contains("0 point") && !report.contains("glass"));
+    }
+}
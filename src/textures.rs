@@ -0,0 +1,581 @@
+//! Textures of material parameters
+//!
+//! Textures are evaluated at hit point from its surface parameterization. Image textures are filtered
+//! bilinearly and repeat outside of [0, 1] range of uv, row 0 of image is v = 1. Images with 8 or 16 bit
+//! channels (PNG, JPEG) are decoded to linear values by encoding of the texture (sRGB for color maps,
+//! linear for data maps as roughness), float images (EXR) are always linear.
+//! Procedural textures (checkerboard, noise, marble) and combinators of other textures (scale, mix)
+//! need no images.
+
+use std::collections::HashMap;
+#[cfg(feature = "fs")]
+use std::error::Error;
+#[cfg(feature = "fs")]
+use std::path::Path;
+use std::sync::Arc;
+
+use image::DynamicImage;
+
+use crate::color::{ColorEncoding, RGB};
+use crate::hash;
+use crate::shapes::{CoordinateSpace, Geometry, SurfaceInteraction};
+use crate::transformations::Transformation;
+use crate::vec::{Point2, Point3};
+
+/// Point where texture is evaluated
+#[derive(Debug, Clone, Copy)]
+pub struct TextureContext {
+    pub uv: Point2,
+    pub point: Point3,
+    /// Point in object space of the hit primitive, it equals point for primitives in world space
+    pub object_point: Point3,
+}
+
+impl TextureContext {
+    pub fn new(isect: &SurfaceInteraction, geometry: &Geometry) -> Self {
+        Self { uv: isect.uv, point: isect.hit_point, object_point: isect.object_point(geometry) }
+    }
+
+    fn point_in(&self, space: CoordinateSpace) -> Point3 {
+        match space {
+            CoordinateSpace::World => self.point,
+            CoordinateSpace::Object => self.object_point
+        }
+    }
+}
+
+pub trait RGBTexture: Send + Sync {
+    fn evaluate(&self, ctx: &TextureContext) -> RGB;
+}
+
+pub trait FloatTexture: Send + Sync {
+    fn evaluate(&self, ctx: &TextureContext) -> f32;
+}
+
+/// Pixels of image file in linear RGB
+pub struct TextureImage {
+    width: usize,
+    height: usize,
+    pixels: Vec<RGB>,
+}
+
+impl TextureImage {
+    pub fn new(width: usize, height: usize, pixels: Vec<RGB>) -> Self {
+        Self { width, height, pixels }
+    }
+
+    fn from_image(img: DynamicImage, encoding: ColorEncoding) -> Self {
+        let encoding = match img {
+            DynamicImage::ImageRgb32F(_) | DynamicImage::ImageRgba32F(_) => ColorEncoding::Linear,
+            _ => encoding
+        };
+        let img = img.to_rgb32f();
+        let decode = |value: f32| encoding.to_linear(value);
+        let pixels = img.pixels().map(|p| RGB::new(decode(p[0]), decode(p[1]), decode(p[2]))).collect();
+        Self::new(img.width() as usize, img.height() as usize, pixels)
+    }
+
+    /// Image file fetched to memory, format is guessed from content.
+    pub fn from_memory(data: &[u8], encoding: ColorEncoding) -> Result<Self, String> {
+        match image::load_from_memory(data) {
+            Ok(img) => Ok(Self::from_image(img, encoding)),
+            Err(err) => Err(err.to_string())
+        }
+    }
+
+    #[cfg(feature = "fs")]
+    pub fn load<P: AsRef<Path>>(path: P, encoding: ColorEncoding) -> Result<Self, Box<dyn Error>> {
+        Ok(Self::from_image(image::open(path)?, encoding))
+    }
+
+    #[cfg(not(feature = "fs"))]
+    pub fn load(_path: &str, _encoding: ColorEncoding) -> Result<Self, String> {
+        Err("loading of texture image requires fs feature".to_string())
+    }
+
+    /// Float image (EXR, HDR) with linear values, 8 and 16-bit images are rejected because they
+    /// hold tone mapped values.
+    #[cfg(feature = "fs")]
+    pub fn load_linear<P: AsRef<Path>>(path: P) -> Result<Self, Box<dyn Error>> {
+        let img = image::open(path)?;
+        if !matches!(img, DynamicImage::ImageRgb32F(_) | DynamicImage::ImageRgba32F(_)) {
+            return Err("linear float image (EXR, HDR) expected, 8 and 16-bit images are tone mapped!".into())
+        }
+        Ok(Self::from_image(img, ColorEncoding::Linear))
+    }
+
+    #[cfg(not(feature = "fs"))]
+    pub fn load_linear(_path: &str) -> Result<Self, String> {
+        Err("loading of image requires fs feature".to_string())
+    }
+
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    /// Pixels in row-major order, row 0 is top of the image
+    pub fn pixels(&self) -> &[RGB] {
+        &self.pixels
+    }
+
+    pub fn memory_usage(&self) -> usize {
+        self.pixels.capacity() * std::mem::size_of::<RGB>()
+    }
+
+    fn texel(&self, x: i64, y: i64) -> RGB {
+        let x = x.rem_euclid(self.width as i64) as usize;
+        let y = y.rem_euclid(self.height as i64) as usize;
+        self.pixels[y * self.width + x]
+    }
+
+    pub fn bilinear(&self, u: f32, v: f32) -> RGB {
+        let x = u * self.width as f32 - 0.5;
+        let y = (1.0 - v) * self.height as f32 - 0.5;
+        let (x0, y0) = (x.floor(), y.floor());
+        let (dx, dy) = (x - x0, y - y0);
+        let (x0, y0) = (x0 as i64, y0 as i64);
+        self.texel(x0, y0) * ((1.0 - dx) * (1.0 - dy)) + self.texel(x0 + 1, y0) * (dx * (1.0 - dy)) +
+            self.texel(x0, y0 + 1) * ((1.0 - dx) * dy) + self.texel(x0 + 1, y0 + 1) * (dx * dy)
+    }
+}
+
+/// Image looked up by uv of the hit, value is multiplied by scale and optionally inverted (1 - value).
+pub struct ImageTexture {
+    image: Arc<TextureImage>,
+    scale: f32,
+    invert: bool,
+}
+
+impl ImageTexture {
+    pub fn new(image: Arc<TextureImage>, scale: f32, invert: bool) -> Self {
+        Self { image, scale, invert }
+    }
+}
+
+impl RGBTexture for ImageTexture {
+    fn evaluate(&self, ctx: &TextureContext) -> RGB {
+        let rgb = self.image.bilinear(ctx.uv.x, ctx.uv.y) * self.scale;
+        if self.invert {
+            return RGB::new((1.0 - rgb.r).max(0.0), (1.0 - rgb.g).max(0.0), (1.0 - rgb.b).max(0.0))
+        }
+        rgb
+    }
+}
+
+// NOTE: float value of image is average of its channels
+impl FloatTexture for ImageTexture {
+    fn evaluate(&self, ctx: &TextureContext) -> f32 {
+        let rgb = RGBTexture::evaluate(self, ctx);
+        (rgb.r + rgb.g + rgb.b) / 3.0
+    }
+}
+
+fn average(rgb: RGB) -> f32 {
+    (rgb.r + rgb.g + rgb.b) / 3.0
+}
+
+fn lerp(t: f32, a: f32, b: f32) -> f32 {
+    a + t * (b - a)
+}
+
+fn fade(t: f32) -> f32 {
+    t * t * t * (t * (t * 6.0 - 15.0) + 10.0)
+}
+
+// Gradient of lattice point is one of 12 directions to edges of cube (improved noise of Perlin 2002),
+// it is chosen by hash of the point instead of permutation table.
+fn gradient(ix: i32, iy: i32, iz: i32, dx: f32, dy: f32, dz: f32) -> f32 {
+    let h = hash!(ix, iy, iz) & 15;
+    let u = if h < 8 { dx } else { dy };
+    let v = if h < 4 { dy } else if h == 12 || h == 14 { dx } else { dz };
+    (if h & 1 == 0 { u } else { -u }) + (if h & 2 == 0 { v } else { -v })
+}
+
+/// Perlin gradient noise, it is zero at integer lattice points and roughly in range [-1, 1].
+pub fn perlin_noise(p: Point3) -> f32 {
+    let (fx, fy, fz) = (p.x.floor(), p.y.floor(), p.z.floor());
+    let (ix, iy, iz) = (fx as i32, fy as i32, fz as i32);
+    let (dx, dy, dz) = (p.x - fx, p.y - fy, p.z - fz);
+    let corner = |i: i32, j: i32, k: i32| gradient(ix + i, iy + j, iz + k, dx - i as f32, dy - j as f32, dz - k as f32);
+    let (wx, wy, wz) = (fade(dx), fade(dy), fade(dz));
+    let x00 = lerp(wx, corner(0, 0, 0), corner(1, 0, 0));
+    let x10 = lerp(wx, corner(0, 1, 0), corner(1, 1, 0));
+    let x01 = lerp(wx, corner(0, 0, 1), corner(1, 0, 1));
+    let x11 = lerp(wx, corner(0, 1, 1), corner(1, 1, 1));
+    lerp(wz, lerp(wy, x00, x10), lerp(wy, x01, x11))
+}
+
+/// Fractional Brownian motion, sum of octaves of noise, frequency of every octave is almost doubled
+/// and its amplitude is multiplied by omega.
+pub fn fbm(p: Point3, octaves: u32, omega: f32) -> f32 {
+    let (mut sum, mut lambda, mut o) = (0.0, 1.0, 1.0);
+    for _ in 0..octaves {
+        sum += o * perlin_noise(Point3::new(p.x * lambda, p.y * lambda, p.z * lambda));
+        lambda *= 1.99;
+        o *= omega;
+    }
+    sum
+}
+
+// Colors of marble layers, they are control points of cubic Bezier segments (pbrt)
+const MARBLE_COLORS: [(f32, f32, f32); 9] = [(0.58, 0.58, 0.6), (0.58, 0.58, 0.6), (0.58, 0.58, 0.6), (0.5, 0.5, 0.5),
+    (0.6, 0.59, 0.58), (0.58, 0.58, 0.6), (0.58, 0.58, 0.6), (0.2, 0.2, 0.33), (0.58, 0.58, 0.6)];
+
+fn marble_color(t: f32) -> RGB {
+    let segments = MARBLE_COLORS.len() - 3;
+    let first = ((t * segments as f32).floor() as usize).min(segments - 1);
+    let t = t * segments as f32 - first as f32;
+    let [c0, c1, c2, c3] = [0, 1, 2, 3].map(|i| {
+        let (r, g, b) = MARBLE_COLORS[first + i];
+        RGB::new(r, g, b)
+    });
+    // de Casteljau evaluation of the segment
+    let mix = |a: RGB, b: RGB| a * (1.0 - t) + b * t;
+    let (c01, c12, c23) = (mix(c0, c1), mix(c1, c2), mix(c2, c3));
+    mix(mix(c01, c12), mix(c12, c23)) * 1.5
+}
+
+/// Texture of the scene, it can be used both for color and for scalar parameters of materials.
+/// Checkerboard is given in uv, noise textures are solid textures of point in texture space, which is
+/// placed in world space or in object space of the hit primitive.
+pub enum Texture {
+    Constant(RGB),
+    Image(ImageTexture),
+    Checkerboard { tex1: Arc<Texture>, tex2: Arc<Texture>, uscale: f32, vscale: f32 },
+    Scale { tex: Arc<Texture>, scale: Arc<Texture> },
+    Mix { tex1: Arc<Texture>, tex2: Arc<Texture>, amount: Arc<Texture> },
+    Fbm { space: CoordinateSpace, to_texture: Transformation, octaves: u32, roughness: f32 },
+    Marble { space: CoordinateSpace, to_texture: Transformation, octaves: u32, roughness: f32, scale: f32, variation: f32 },
+}
+
+impl Texture {
+    fn checker<'a>(tex1: &'a Texture, tex2: &'a Texture, uscale: f32, vscale: f32, ctx: &TextureContext) -> &'a Texture {
+        let cell = (ctx.uv.x * uscale).floor() as i64 + (ctx.uv.y * vscale).floor() as i64;
+        if cell.rem_euclid(2) == 0 { tex1 } else { tex2 }
+    }
+}
+
+impl RGBTexture for Texture {
+    fn evaluate(&self, ctx: &TextureContext) -> RGB {
+        match self {
+            Texture::Constant(rgb) => *rgb,
+            Texture::Image(texture) => RGBTexture::evaluate(texture, ctx),
+            Texture::Checkerboard { tex1, tex2, uscale, vscale } => {
+                RGBTexture::evaluate(Texture::checker(tex1, tex2, *uscale, *vscale, ctx), ctx)
+            }
+            Texture::Scale { tex, scale } => RGBTexture::evaluate(tex.as_ref(), ctx) * RGBTexture::evaluate(scale.as_ref(), ctx),
+            Texture::Mix { tex1, tex2, amount } => {
+                let t = FloatTexture::evaluate(amount.as_ref(), ctx);
+                RGBTexture::evaluate(tex1.as_ref(), ctx) * (1.0 - t) + RGBTexture::evaluate(tex2.as_ref(), ctx) * t
+            }
+            Texture::Fbm { .. } => {
+                let value = FloatTexture::evaluate(self, ctx);
+                RGB::new(value, value, value)
+            }
+            Texture::Marble { space, to_texture, octaves, roughness, scale, variation } => {
+                let p = *to_texture * ctx.point_in(*space);
+                let p = Point3::new(p.x * scale, p.y * scale, p.z * scale);
+                let marble = p.y + variation * fbm(p, *octaves, *roughness);
+                marble_color((0.5 + 0.5 * marble.sin()).clamp(0.0, 1.0))
+            }
+        }
+    }
+}
+
+// NOTE: float value of color textures is average of their channels
+impl FloatTexture for Texture {
+    fn evaluate(&self, ctx: &TextureContext) -> f32 {
+        match self {
+            Texture::Constant(rgb) => average(*rgb),
+            Texture::Image(texture) => FloatTexture::evaluate(texture, ctx),
+            Texture::Checkerboard { tex1, tex2, uscale, vscale } => {
+                FloatTexture::evaluate(Texture::checker(tex1, tex2, *uscale, *vscale, ctx), ctx)
+            }
+            Texture::Scale { tex, scale } => FloatTexture::evaluate(tex.as_ref(), ctx) * FloatTexture::evaluate(scale.as_ref(), ctx),
+            Texture::Mix { tex1, tex2, amount } => {
+                let t = FloatTexture::evaluate(amount.as_ref(), ctx);
+                lerp(t, FloatTexture::evaluate(tex1.as_ref(), ctx), FloatTexture::evaluate(tex2.as_ref(), ctx))
+            }
+            Texture::Fbm { space, to_texture, octaves, roughness } => fbm(*to_texture * ctx.point_in(*space), *octaves, *roughness),
+            Texture::Marble { .. } => average(RGBTexture::evaluate(self, ctx)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TextureType {
+    Image,
+    Constant,
+    Checkerboard,
+    Scale,
+    Mix,
+    Fbm,
+    Marble,
+}
+
+/// Parameter of texture that is either constant value or other texture of the scene.
+#[derive(Debug, Clone)]
+pub enum TextureInput {
+    Value(RGB),
+    Texture(String),
+}
+
+impl TextureInput {
+    pub fn constant(value: f32) -> Self {
+        TextureInput::Value(RGB::new(value, value, value))
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct TextureDescription {
+    pub name: String,
+    pub typ: TextureType,
+    /// Image file of image texture
+    pub filename: Option<String>,
+    /// Encoding of 8 and 16-bit images, sRGB for color maps and linear for data maps
+    pub encoding: ColorEncoding,
+    /// Multiplier of image texture, frequency of marble texture
+    pub scale: f32,
+    pub invert: bool,
+    /// Value of constant texture, two textures of checkerboard and mix, texture of scale texture is tex1
+    /// and its scale is tex2
+    pub tex1: TextureInput,
+    pub tex2: TextureInput,
+    /// Weight of tex2 in mix texture
+    pub amount: TextureInput,
+    /// Number of checks per unit of u and v
+    pub uscale: f32,
+    pub vscale: f32,
+    /// Octaves of noise and multiplier of amplitude of every next octave
+    pub octaves: u32,
+    pub roughness: f32,
+    /// Amount of noise that perturbs layers of marble
+    pub variation: f32,
+    /// Transformation from texture space to space of noise textures, None is identity
+    pub transform: Option<Transformation>,
+    /// Space where texture space of noise textures is placed, object space moves with the primitive
+    pub space: CoordinateSpace,
+}
+
+impl TextureDescription {
+    /// Description with defaults of texture type (e.g. mix is half of black and white texture).
+    pub fn new(name: &str, typ: TextureType) -> Self {
+        let (tex1, tex2) = match typ {
+            TextureType::Mix => (TextureInput::constant(0.0), TextureInput::constant(1.0)),
+            TextureType::Scale => (TextureInput::constant(1.0), TextureInput::constant(1.0)),
+            _ => (TextureInput::constant(1.0), TextureInput::constant(0.0)),
+        };
+        Self { name: name.to_string(), typ, tex1, tex2, ..Default::default() }
+    }
+
+    fn input(&self, input: &TextureInput, textures: &HashMap<String, Arc<Texture>>) -> Result<Arc<Texture>, String> {
+        match input {
+            TextureInput::Value(rgb) => Ok(Arc::new(Texture::Constant(*rgb))),
+            TextureInput::Texture(name) => match textures.get(name) {
+                Some(texture) => Ok(texture.clone()),
+                None => Err(format!("Texture {}: texture {} doesn't exist!", self.name, name))
+            }
+        }
+    }
+
+    fn to_texture(&self) -> Transformation {
+        self.transform.map_or(Transformation::identity(), |transform| transform.inverse())
+    }
+
+    /// Textures that are referenced by this texture must be already created. Images are cached by file
+    /// name and encoding, so textures that share file share its pixels.
+    pub fn create(&self, images: &mut HashMap<(String, ColorEncoding), Arc<TextureImage>>,
+                  textures: &HashMap<String, Arc<Texture>>) -> Result<Texture, String> {
+        match self.typ {
+            TextureType::Image => {
+                let fname = match &self.filename {
+                    Some(fname) => fname,
+                    None => return Err(format!("Texture {}: image file is not specified!", self.name))
+                };
+                let key = (fname.clone(), self.encoding);
+                let image = match images.get(&key) {
+                    Some(image) => image.clone(),
+                    None => {
+                        let image = match TextureImage::load(fname, self.encoding) {
+                            Ok(image) => Arc::new(image),
+                            Err(err) => return Err(format!("Texture {}: {} - {}", self.name, fname, err))
+                        };
+                        images.insert(key, image.clone());
+                        image
+                    }
+                };
+                Ok(Texture::Image(ImageTexture::new(image, self.scale, self.invert)))
+            }
+            TextureType::Constant => match &self.tex1 {
+                TextureInput::Value(rgb) => Ok(Texture::Constant(*rgb)),
+                TextureInput::Texture(_) => Err(format!("Texture {}: value of constant texture expected!", self.name))
+            },
+            TextureType::Checkerboard => Ok(Texture::Checkerboard {
+                tex1: self.input(&self.tex1, textures)?,
+                tex2: self.input(&self.tex2, textures)?,
+                uscale: self.uscale,
+                vscale: self.vscale
+            }),
+            TextureType::Scale => Ok(Texture::Scale {
+                tex: self.input(&self.tex1, textures)?,
+                scale: self.input(&self.tex2, textures)?
+            }),
+            TextureType::Mix => Ok(Texture::Mix {
+                tex1: self.input(&self.tex1, textures)?,
+                tex2: self.input(&self.tex2, textures)?,
+                amount: self.input(&self.amount, textures)?
+            }),
+            TextureType::Fbm => Ok(Texture::Fbm {
+                space: self.space,
+                to_texture: self.to_texture(),
+                octaves: self.octaves,
+                roughness: self.roughness
+            }),
+            TextureType::Marble => Ok(Texture::Marble {
+                space: self.space,
+                to_texture: self.to_texture(),
+                octaves: self.octaves,
+                roughness: self.roughness,
+                scale: self.scale,
+                variation: self.variation
+            }),
+        }
+    }
+}
+
+impl Default for TextureDescription {
+    fn default() -> Self {
+        Self {
+            name: "texture".to_string(),
+            typ: TextureType::Image,
+            filename: None,
+            encoding: ColorEncoding::SRGB,
+            scale: 1.0,
+            invert: false,
+            tex1: TextureInput::constant(1.0),
+            tex2: TextureInput::constant(0.0),
+            amount: TextureInput::constant(0.5),
+            uscale: 1.0,
+            vscale: 1.0,
+            octaves: 8,
+            roughness: 0.5,
+            variation: 0.2,
+            transform: None,
+            space: CoordinateSpace::World,
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn image_lookup() {
+        // 2x2 image, top row is red and black, bottom row is green and white
+        let pixels = vec![RGB::new(1.0, 0.0, 0.0), RGB::new(0.0, 0.0, 0.0), RGB::new(0.0, 1.0, 0.0), RGB::new(1.0, 1.0, 1.0)];
+        let image = Arc::new(TextureImage::new(2, 2, pixels));
+        assert_eq!(image.bilinear(0.25, 0.75).r, 1.0);
+        assert_eq!(image.bilinear(0.25, 0.25).g, 1.0);
+        // center of the image is average of all pixels and image repeats outside of [0, 1]
+        assert!((image.bilinear(0.5, 0.5).g - 0.5).abs() < 1e-6);
+        assert_eq!(image.bilinear(1.25, -0.75).g, 1.0);
+
+        let ctx = TextureContext { uv: Point2::new(0.75, 0.25), point: Point3::new(0.0, 0.0, 0.0), object_point: Point3::new(0.0, 0.0, 0.0) };
+        let texture = Texture::Image(ImageTexture::new(image.clone(), 0.5, false));
+        assert_eq!(RGBTexture::evaluate(&texture, &ctx).b, 0.5);
+        assert_eq!(FloatTexture::evaluate(&texture, &ctx), 0.5);
+        let inverted = ImageTexture::new(image, 1.0, true);
+        assert_eq!(RGBTexture::evaluate(&inverted, &ctx).b, 0.0);
+    }
+
+    #[test]
+    fn procedural_textures() {
+        // noise is zero at lattice points and it is smooth and bounded between them
+        assert_eq!(perlin_noise(Point3::new(3.0, -2.0, 5.0)), 0.0);
+        let p = Point3::new(0.3, 0.7, 0.2);
+        assert!((perlin_noise(p) - perlin_noise(Point3::new(0.3001, 0.7, 0.2))).abs() < 1e-3);
+        let values: Vec<f32> = (0..100).map(|i| perlin_noise(Point3::new(i as f32 * 0.37, 0.5, 0.25))).collect();
+        assert!(values.iter().all(|v| v.abs() <= 1.5) && values.iter().any(|v| v.abs() > 0.1));
+
+        let mut images = HashMap::new();
+        let mut textures = HashMap::new();
+        let mut create = |desc: TextureDescription, textures: &mut HashMap<String, Arc<Texture>>| {
+            let texture = Arc::new(desc.create(&mut images, textures).unwrap());
+            textures.insert(desc.name.clone(), texture.clone());
+            texture
+        };
+        let mut checks = TextureDescription::new("checks", TextureType::Checkerboard);
+        (checks.uscale, checks.vscale) = (4.0, 4.0);
+        let checks = create(checks, &mut textures);
+        let ctx = |u: f32, v: f32| TextureContext { uv: Point2::new(u, v), point: Point3::new(u, v, 0.5), object_point: Point3::new(u, v, 0.5) };
+        assert_eq!(FloatTexture::evaluate(checks.as_ref(), &ctx(0.1, 0.1)), 1.0);
+        assert_eq!(FloatTexture::evaluate(checks.as_ref(), &ctx(0.3, 0.1)), 0.0);
+        assert_eq!(FloatTexture::evaluate(checks.as_ref(), &ctx(0.3, 0.3)), 1.0);
+
+        let mix = TextureDescription { amount: TextureInput::Texture("checks".to_string()),
+                                       tex2: TextureInput::Value(RGB::new(0.0, 0.5, 0.0)), ..TextureDescription::new("mix", TextureType::Mix) };
+        let mix = create(mix, &mut textures);
+        assert_eq!(RGBTexture::evaluate(mix.as_ref(), &ctx(0.1, 0.1)).g, 0.5);
+        assert_eq!(RGBTexture::evaluate(mix.as_ref(), &ctx(0.3, 0.1)).g, 0.0);
+        let scale = TextureDescription { tex1: TextureInput::Texture("mix".to_string()), tex2: TextureInput::constant(4.0),
+                                         ..TextureDescription::new("scale", TextureType::Scale) };
+        assert_eq!(RGBTexture::evaluate(create(scale, &mut textures).as_ref(), &ctx(0.1, 0.1)).g, 2.0);
+        let missing = TextureDescription { tex1: TextureInput::Texture("wood".to_string()), ..TextureDescription::new("scale", TextureType::Scale) };
+        assert!(missing.create(&mut HashMap::new(), &textures).is_err());
+
+        let fbm = create(TextureDescription::new("fbm", TextureType::Fbm), &mut textures);
+        assert_eq!(RGBTexture::evaluate(fbm.as_ref(), &ctx(0.3, 0.4)).g, FloatTexture::evaluate(fbm.as_ref(), &ctx(0.3, 0.4)));
+        let marble = create(TextureDescription::new("marble", TextureType::Marble), &mut textures);
+        let veins: Vec<RGB> = (0..50).map(|i| RGBTexture::evaluate(marble.as_ref(), &ctx(0.0, i as f32 * 0.13))).collect();
+        assert!(veins.iter().all(|rgb| rgb.r >= 0.0 && rgb.b <= 1.0));
+        assert!(veins.iter().any(|rgb| rgb.b > rgb.r));
+    }
+
+    #[test]
+    fn image_decoding() {
+        // 8-bit image is decoded by encoding of texture
+        let mut png = Vec::new();
+        let img = image::RgbImage::from_raw(1, 1, vec![255, 188, 0]).unwrap();
+        DynamicImage::ImageRgb8(img).write_to(&mut std::io::Cursor::new(&mut png), image::ImageOutputFormat::Png).unwrap();
+        let image = TextureImage::from_memory(&png, ColorEncoding::SRGB).unwrap();
+        let rgb = image.bilinear(0.5, 0.5);
+        assert_eq!(rgb.r, 1.0);
+        assert!((rgb.g - 0.5).abs() < 0.01);
+        let image = TextureImage::from_memory(&png, ColorEncoding::Linear).unwrap();
+        assert!((image.bilinear(0.5, 0.5).g - 188.0 / 255.0).abs() < 1e-6);
+        assert!(TextureImage::from_memory(&[1, 2, 3], ColorEncoding::SRGB).is_err());
+
+        let desc = TextureDescription { filename: Some("missing_texture.png".to_string()), ..Default::default() };
+        assert!(desc.create(&mut HashMap::new(), &HashMap::new()).is_err());
+        assert!(TextureDescription::default().create(&mut HashMap::new(), &HashMap::new()).is_err());
+    }
+
+    #[test]
+    fn object_space_noise() {
+        use crate::ray::Ray;
+        use crate::shapes::Sphere;
+        use crate::vec::Vec3;
+
+        let mut geometry = Geometry::new();
+        geometry.add_sphere(Sphere::new(Point3::new(0.0, 0.0, 0.0), 1.0), None, 0, None);
+        let to_world = Transformation::translate(&Vec3::new(10.3, 0.0, 0.0));
+        geometry.add_sphere(Sphere::new(Point3::new(0.0, 0.0, 0.0), 1.0), Some(to_world), 0, None);
+        geometry.prepare_for_rendering();
+        let hit = |x: f32| geometry.intersect(&Ray::new(Point3::new(x, 0.2, -5.0), Vec3::new(0.0, 0.0, 1.0))).unwrap();
+        let (ctx, ctx_moved) = (TextureContext::new(&hit(0.1), &geometry), TextureContext::new(&hit(10.4), &geometry));
+
+        // same point of both spheres has same value in object space, but not in world space
+        let create = |space: CoordinateSpace| TextureDescription { space, ..TextureDescription::new("noise", TextureType::Fbm) }
+            .create(&mut HashMap::new(), &HashMap::new()).unwrap();
+        let (object, world) = (create(CoordinateSpace::Object), create(CoordinateSpace::World));
+        assert!((FloatTexture::evaluate(&object, &ctx) - FloatTexture::evaluate(&object, &ctx_moved)).abs() < 1e-4);
+        assert!((FloatTexture::evaluate(&world, &ctx) - FloatTexture::evaluate(&world, &ctx_moved)).abs() > 1e-3);
+        assert_eq!(FloatTexture::evaluate(&object, &ctx), FloatTexture::evaluate(&world, &ctx));
+    }
+}
@@ -3,6 +3,11 @@ use crate::vec::{Normal, Point3, Vec3};
 use crate::matrix::Matrix4x4;
 use std::ops::Mul;
 
+// Product of matrix and its inverse must be identity within tolerance, inverse of near-singular
+// matrix is dominated by rounding errors. Error of every element is relative to magnitude of terms
+// that are summed in it (|M| |M^-1|), so large translations and small scales don't need tighter matrices.
+const INVERSE_TOLERANCE: f32 = 1e-3;
+
 #[derive(Debug, Copy, Clone, PartialEq)]
 pub struct Transformation {
     mat: Matrix4x4,
@@ -82,11 +87,15 @@ impl Transformation {
     }
 
     // Note: Returns world to camera transformation
-    pub fn look_at(pos: Point3, look: Point3, up: Vec3) -> Self {
+    pub fn look_at(pos: Point3, look: Point3, up: Vec3) -> Result<Self, String> {
+        if pos == look {
+            return Err(format!("eye and look at point ({}, {}, {}) are the same.", pos.x, pos.y, pos.z))
+        }
         let dir = (look - pos).normalize();
-        if up.normalize().cross(dir).length() == 0.0 {
-            panic!("up vector ({}, {}, {}) and viewing direction ({}, {}, {}) are parallel.",
-                   up.x, up.y, up.z, dir.x, dir.y, dir.z);
+        let side = up.normalize().cross(dir).length();
+        if side == 0.0 || side.is_nan() {
+            return Err(format!("up vector ({}, {}, {}) and viewing direction ({}, {}, {}) are parallel.",
+                               up.x, up.y, up.z, dir.x, dir.y, dir.z))
         }
         let right = up.normalize().cross(dir).normalize();
         let new_up = dir.cross(right);
@@ -96,11 +105,7 @@ impl Transformation {
             [right.z, new_up.z, dir.z, pos.z],
             [0.0, 0.0, 0.0, 1.0],
         ]);
-        let inv_mat = mat.inverse();
-        if inv_mat.is_none() {
-            panic!("Matrix {:?} is not invertible.", mat);
-        }
-        Self { mat: inv_mat.unwrap(), inv_mat: mat }
+        Transformation::try_from(mat).map(|transformation| transformation.inverse())
     }
 
     pub fn orthographic(z_near: f32, z_far: f32) -> Self {
@@ -109,14 +114,21 @@ impl Transformation {
     }
     
     pub fn perspective(fov: f32, z_near: f32, z_far: f32) -> Self {
+        let (a, b) = (z_far / (z_far - z_near), -z_near * z_far / (z_far - z_near));
         let mat = Matrix4x4::new([
             [1.0, 0.0, 0.0, 0.0],
             [0.0, 1.0, 0.0, 0.0],
-            [0.0, 0.0, z_far / (z_far - z_near), -z_near * z_far / (z_far - z_near)],
+            [0.0, 0.0, a, b],
             [0.0, 0.0, 1.0, 0.0],
         ]);
+        let inv_mat = Matrix4x4::new([
+            [1.0, 0.0, 0.0, 0.0],
+            [0.0, 1.0, 0.0, 0.0],
+            [0.0, 0.0, 0.0, 1.0],
+            [0.0, 0.0, 1.0 / b, -a / b],
+        ]);
         let inv_tan_angle = (0.5 * fov.to_radians()).tan().recip();
-        Transformation::scale(inv_tan_angle, inv_tan_angle, 1.0) * Transformation::from(mat)
+        Transformation::scale(inv_tan_angle, inv_tan_angle, 1.0) * Self { mat, inv_mat }
     }
 
     pub fn inverse(&self) -> Self {
@@ -131,6 +143,76 @@ impl Transformation {
         self.mat.is_identity()
     }
 
+    pub fn determinant(&self) -> f32 {
+        self.mat.determinant()
+    }
+
+    /// Element wise interpolation of matrices and of their inverses, s = 0 gives self and s = 1 gives other.
+    /// No matrix is inverted, so interpolated matrix is exact inverse of interpolated inverse only at
+    /// s = 0 and s = 1, and inverse can be singular (e.g. between mirrored transformations).
+    pub fn interpolate(&self, other: &Transformation, s: f32) -> Transformation {
+        Self { mat: self.mat * (1.0 - s) + other.mat * s, inv_mat: self.inv_mat * (1.0 - s) + other.inv_mat * s }
+    }
+
+}
+
+/// Transformation that changes between two key transformations during time interval. Matrices and
+/// inverses are interpolated element wise, world to object transformation changes linearly and rays
+/// are moved to object space without inverting matrix per ray. Translations and scales move points
+/// monotonically between key positions, large rotations grow objects in the middle of the interval.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct AnimatedTransformation {
+    start: Transformation,
+    end: Transformation,
+    start_time: f32,
+    end_time: f32
+}
+
+impl AnimatedTransformation {
+    pub fn new(start: Transformation, end: Transformation, start_time: f32, end_time: f32) -> Self {
+        Self { start, end, start_time, end_time }
+    }
+
+    pub fn is_animated(&self) -> bool {
+        self.start != self.end && self.end_time > self.start_time
+    }
+
+    pub fn start(&self) -> Transformation {
+        self.start
+    }
+
+    pub fn end(&self) -> Transformation {
+        self.end
+    }
+
+    /// Transformation at given time, it is constant outside of the time interval.
+    pub fn at(&self, time: f32) -> Transformation {
+        if !self.is_animated() {
+            return self.start
+        }
+        let s = ((time - self.start_time) / (self.end_time - self.start_time)).clamp(0.0, 1.0);
+        match s {
+            0.0 => self.start,
+            1.0 => self.end,
+            s => self.start.interpolate(&self.end, s)
+        }
+    }
+
+    /// Object to world transformations at n + 1 evenly spaced times of the interval, keys included.
+    /// Their matrices are exact inverses of world to object transformations, instants where
+    /// interpolated transformation is singular are skipped.
+    pub fn steps(&self, n: usize) -> impl Iterator<Item = Transformation> + '_ {
+        (0..=n).filter_map(move |i| {
+            let time = self.start_time + (self.end_time - self.start_time) * i as f32 / n as f32;
+            Transformation::try_from(self.at(time).inv_mat).ok().map(|world_to_object| world_to_object.inverse())
+        })
+    }
+}
+
+impl From<Transformation> for AnimatedTransformation {
+    fn from(transformation: Transformation) -> Self {
+        Self::new(transformation, transformation, 0.0, 1.0)
+    }
 }
 
 impl Mul for Transformation {
@@ -143,13 +225,18 @@ impl Mul for Transformation {
     }
 }
 
-impl From<Matrix4x4> for Transformation {
-    fn from(mat: Matrix4x4) -> Self {
-        let inv_mat = mat.inverse();
-        if inv_mat.is_none() {
-            panic!("Matrix {:?} is not invertible.", mat);
+/// Singular and near-singular matrices are rejected
+impl TryFrom<Matrix4x4> for Transformation {
+    type Error = String;
+
+    fn try_from(mat: Matrix4x4) -> Result<Self, String> {
+        match mat.inverse() {
+            Some(inv_mat) if mat.is_finite() && inv_mat.is_finite() &&
+                             (mat * inv_mat).max_scaled_difference(&Matrix4x4::identity(), &(mat.abs() * inv_mat.abs())) < INVERSE_TOLERANCE => {
+                Ok(Self { mat, inv_mat })
+            }
+            _ => Err(format!("Matrix {:?} is not invertible.", mat))
         }
-        Self { mat, inv_mat: inv_mat.unwrap() }
     }
 }
 
@@ -191,3 +278,42 @@ impl Default for Transformation {
         Transformation{mat: Matrix4x4::identity(), inv_mat: Matrix4x4::identity()}
     }
 }
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn singular_transformations() {
+        let singular = Matrix4x4::new([[1.0, 2.0, 0.0, 0.0], [2.0, 4.0, 0.0, 0.0], [0.0, 0.0, 1.0, 0.0], [0.0, 0.0, 0.0, 1.0]]);
+        assert!(Transformation::try_from(singular).is_err());
+        let near_singular = Matrix4x4::new([[1.0, 0.0, 0.0, 0.0], [0.0, 1e-30, 0.0, 0.0], [0.0, 0.0, 1e-30, 0.0], [0.0, 0.0, 0.0, 1.0]]);
+        assert!(Transformation::try_from(near_singular).is_err());
+        let shear = Matrix4x4::new([[1.0, 3.0, 0.0, 5.0], [0.0, 1.0, 0.0, 0.0], [0.0, 0.0, 2.0, 0.0], [0.0, 0.0, 0.0, 1.0]]);
+        assert!(Transformation::try_from(shear).is_ok());
+        // small scale with far translation is well-conditioned, rounding error of product is large only in absolute terms
+        let far = Matrix4x4::new([[0.01, 0.0, 0.0, 1e5], [0.0, 0.01, 0.0, -3e5], [0.0, 0.0, 0.01, 7e4], [0.0, 0.0, 0.0, 1.0]]);
+        assert!((far * far.inverse().unwrap()).max_difference(&Matrix4x4::identity()) > 1e-3);
+        assert!(Transformation::try_from(far).is_ok());
+
+        let up = Vec3::new(0.0, 1.0, 0.0);
+        assert!(Transformation::look_at(Point3::new(0.0, 0.0, 5.0), Point3::new(0.0, 0.0, 0.0), up).is_ok());
+        assert!(Transformation::look_at(Point3::new(0.0, 5.0, 0.0), Point3::new(0.0, 0.0, 0.0), up).is_err());
+        assert!(Transformation::look_at(Point3::new(1.0, 1.0, 1.0), Point3::new(1.0, 1.0, 1.0), up).is_err());
+
+        // inverse of interpolated translation is interpolated inverse
+        let (start, end) = (Transformation::translate(&Vec3::new(0.0, 0.0, 0.0)), Transformation::translate(&Vec3::new(4.0, 0.0, 0.0)));
+        assert_eq!(start.interpolate(&end, 0.5), Transformation::translate(&Vec3::new(2.0, 0.0, 0.0)));
+        // interpolation between mirrored transformations passes through singular matrix
+        let (start, end) = (Transformation::scale(1.0, 1.0, 1.0), Transformation::scale(-1.0, 1.0, 1.0));
+        let animated = AnimatedTransformation::new(start, end, 0.0, 1.0);
+        assert_eq!(animated.steps(4).count(), 4);
+        let quarter = animated.steps(4).nth(1).unwrap();
+        assert!((quarter.mat * quarter.inv_mat).max_difference(&Matrix4x4::identity()) < 1e-5);
+        assert_eq!(quarter, Transformation::scale(2.0, 1.0, 1.0));
+
+        let perspective = Transformation::perspective(60.0, 0.01, 1000.0);
+        assert!((perspective.mat * perspective.inv_mat).max_difference(&Matrix4x4::identity()) < 1e-4);
+    }
+}
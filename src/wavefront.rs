@@ -0,0 +1,192 @@
+//! Wavefront (queue based) rendering
+//!
+//! Instead of following one path at a time, all paths of one pixel sample pass of a tile are kept
+//! in a queue and processed one bounce at a time: generate camera rays, intersect all,
+//! shade all and compact the queue by removing terminated paths.
+
+use crate::color::RGB;
+use crate::integrators::{random_walk_direction, russian_roulette, clamp_scale, render_tiles, TileOutputs};
+use crate::integrators::{shading_normal, shading_normal_factor, compensate_direction, UNIFORM_SPHERE_PDF};
+use crate::postprocess::finish_image;
+use crate::ray::{Ray, spawn_new_ray};
+use crate::rgb::RGB8uffer;
+use crate::samplers::bounce_dimension;
+use crate::scene::{Scene, RandomWalkProperties};
+use crate::shapes::SurfaceInteraction;
+use crate::telemetry::SampleOutcome;
+
+struct PathState {
+    x: usize,
+    y: usize,
+    px: f32,
+    py: f32,
+    ray: Ray,
+    throughput: RGB,
+    radiance: RGB,
+}
+
+struct PathQueue {
+    paths: Vec<PathState>,
+    hits: Vec<Option<SurfaceInteraction>>,
+    finished: Vec<PathState>,
+}
+
+impl PathQueue {
+    fn new(capacity: usize) -> Self {
+        Self { paths: Vec::with_capacity(capacity), hits: Vec::with_capacity(capacity), finished: Vec::with_capacity(capacity) }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.paths.is_empty()
+    }
+
+    fn intersect(&mut self, scene: &Scene) {
+        self.hits.clear();
+        self.hits.extend(self.paths.iter().map(|path| scene.geometry.intersect(&path.ray)));
+    }
+
+    // Move paths that are marked as terminated into finished list and keep active ones in front.
+    fn compact(&mut self, active: &[bool]) {
+        let paths = std::mem::take(&mut self.paths);
+        for (path, keep) in paths.into_iter().zip(active.iter()) {
+            if *keep {
+                self.paths.push(path);
+            } else {
+                self.finished.push(path);
+            }
+        }
+    }
+}
+
+pub fn random_walk_wavefront_integrator(scene: &Scene, rw_settings: &RandomWalkProperties) -> RGB8uffer {
+    let maxdepth = rw_settings.maxdepth;
+
+    // Every tile has its own wavefront of paths
+    let film = render_tiles(scene, TileOutputs::default(), |buffers, sampler| {
+        let tile = buffers.tile;
+        let mut queue = PathQueue::new(tile.width() * tile.height());
+        let mut active = Vec::with_capacity(tile.width() * tile.height());
+        for i in buffers.passes() {
+            if scene.cancel_token.is_cancelled() {
+                break;
+            }
+            // Generate camera rays
+            for (x, y) in tile {
+                let (sx, sy) = sampler.sample_pixel(x, y, i);
+                buffers.add_sample_count(x, y);
+                let px = x as f32 + sx;
+                let py = y as f32 + sy;
+                let ray = scene.camera.generate_ray_at_time(px, py, sampler.sample_time());
+                queue.paths.push(PathState {
+                    x, y, px, py, ray,
+                    throughput: RGB::new(1.0, 1.0, 1.0),
+                    radiance: RGB::zero()
+                });
+            }
+
+            let mut depth = 0;
+            while !queue.is_empty() {
+                queue.intersect(scene);
+
+                // Shade all paths and generate continuation rays
+                active.clear();
+                for (path, hit) in queue.paths.iter_mut().zip(queue.hits.iter()) {
+                    let isect_p = match hit {
+                        Some(isect_p) => isect_p,
+                        None => {
+                            path.radiance += path.throughput * scene.environment_radiance(path.ray.direction);
+                            active.push(false);
+                            continue;
+                        }
+                    };
+                    let material = scene.material_at(isect_p);
+                    let wo = -path.ray.direction;
+                    path.radiance += path.throughput * material.emssion(wo, isect_p.normal, isect_p.back_side);
+
+                    if depth == maxdepth {
+                        active.push(false);
+                        continue;
+                    }
+
+                    sampler.set_pixel_sample(path.x, path.y, i, bounce_dimension(depth as u32));
+                    let settings = &scene.settings.shading_normals;
+                    let normal = shading_normal(isect_p, settings);
+                    let record = |outcome: SampleOutcome| if let Some(telemetry) = &scene.telemetry {
+                        telemetry.record_material(isect_p.material_id as usize, outcome);
+                    };
+                    match random_walk_direction(&*material, wo, normal, sampler) {
+                        Some((wi, weight)) => {
+                            let (wi, weight, _) = compensate_direction(isect_p, &*material, wo, normal, (wi, weight, UNIFORM_SPHERE_PDF),
+                                                                       settings, |_| UNIFORM_SPHERE_PDF);
+                            let weight = weight * shading_normal_factor(isect_p, wo, wi, settings);
+                            record(if weight.is_black() { SampleOutcome::Rejected } else { SampleOutcome::Accepted(None) });
+                            match russian_roulette(path.throughput * weight, depth, rw_settings.rrdepth, sampler) {
+                                Some(throughput) => {
+                                    path.throughput = throughput;
+                                    path.ray = spawn_new_ray(isect_p.hit_point, isect_p.normal, wi).with_time(path.ray.time);
+                                    active.push(true);
+                                }
+                                None => active.push(false)
+                            }
+                        }
+                        None => {
+                            record(SampleOutcome::ZeroPdf);
+                            active.push(false)
+                        }
+                    }
+                }
+                queue.compact(&active);
+                depth += 1;
+            }
+
+            for path in queue.finished.drain(..) {
+                let rgb = path.radiance * clamp_scale(&path.radiance, scene.settings.max_component);
+                buffers.add(path.x, path.y, path.px, path.py, &rgb);
+            }
+        }
+    });
+    film.save_outputs(scene);
+    finish_image(scene, &film.radiance, film.depth())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::integrators::random_walk_integrator;
+    use crate::materials::{MaterialDescription, MaterialType};
+    use crate::rgb::ImageSize;
+    use crate::scene::{SceneDescription, Sampler, StratifiedSamplerSettings};
+    use crate::shapes::{ShapeDescription, SphereDescription};
+    use crate::vec::Point3;
+
+    #[test]
+    fn wavefront_matches_recursive_random_walk() {
+        let mut desc = SceneDescription::default();
+        desc.set_resolution(ImageSize::new(16, 16));
+        desc.settings.spp = 4;
+        let settings = StratifiedSamplerSettings { xsamples: 2, ysamples: 2, jitter: false, ..Default::default() };
+        desc.sampler = Some(Sampler::Stratified(settings));
+        desc.materials.push(MaterialDescription { name: "light".to_string(), typ: MaterialType::EmissiveMatte,
+                                                  emission: RGB::new(1.0, 1.0, 1.0), ..Default::default() });
+        desc.materials.push(MaterialDescription { name: "matte".to_string(), ..Default::default() });
+        desc.shapes.push(ShapeDescription::Sphere(SphereDescription { position: Point3::new(0.0, 0.0, -3.0), radius: 1.0,
+                                                                      material: "matte".to_string(), ..Default::default() }));
+        desc.shapes.push(ShapeDescription::Sphere(SphereDescription { position: Point3::new(0.0, 3.0, -3.0), radius: 1.5,
+                                                                      material: "light".to_string(), ..Default::default() }));
+        let scene = Scene::try_from(desc).unwrap();
+        let rw_settings = RandomWalkProperties { maxdepth: 3, ..Default::default() };
+
+        let reference = random_walk_integrator(&scene, &rw_settings);
+        let image = random_walk_wavefront_integrator(&scene, &rw_settings);
+        for y in 0..16 {
+            for x in 0..16 {
+                let p1 = reference.get(x, y).unwrap();
+                let p2 = image.get(x, y).unwrap();
+                assert!((p1.red as i32 - p2.red as i32).abs() <= 1);
+                assert!((p1.green as i32 - p2.green as i32).abs() <= 1);
+                assert!((p1.blue as i32 - p2.blue as i32).abs() <= 1);
+            }
+        }
+    }
+}